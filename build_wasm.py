@@ -2,12 +2,31 @@
 """
 Pre-build script for pybox-reactor.wasm
 Builds the WASM module and copies it to python/pybox/image/
+
+With --wizer, the module is pre-initialized by Wizer (`cargo install wizer --all-features`)
+so the Python runtime state is already in the WASM file.
+
+With --stdio, builds a separate pybox_reactor_stdio.wasm next to the cargo output instead,
+driven by framed commands on stdin / stdout from any WASI runtime (see crates/pybox-reactor/src/stdio.rs).
+
+With --tracing, the reactor writes the duration of interpreter creation, compilation, execution
+and ioctl calls to the guest stderr (see crates/pybox-reactor/src/trace.rs).
+
+With --snapshot, snapshots the reactor right after start-up instead, reactors built with
+PyBoxReactorBuilder().prewarm() restore python/pybox/image/pybox_reactor.snapshot to skip
+the interpreter start-up.
+Needs the pybox package installed, reinstall it afterwards to bundle the snapshot.
 """
 import subprocess
 import shutil
+import sys
 from pathlib import Path
 
 
+# environment created before the pre-initialized snapshot is taken
+PREWARM_ENV = "default"
+
+
 def check_and_install_target():
     """Check if wasm32-wasip1 target is installed, install if not"""
     print("Checking for wasm32-wasip1 target...")
@@ -34,22 +53,71 @@ def check_and_install_target():
     return True
 
 
+def wizer(src: Path, dst: Path):
+    """Run the wizer.initialize export of src and write the initialized module to dst"""
+    if shutil.which("wizer") is None:
+        print("wizer not found, install it with `cargo install wizer --all-features`")
+        return 1
+
+    print(f"Pre-initializing {src.name} with wizer...")
+    result = subprocess.run(
+        [
+            "wizer", "--allow-wasi",
+            "--wasm-bulk-memory", "true",
+            "--init-func", "wizer.initialize",
+            "-o", str(dst), str(src)
+        ],
+        check=False
+    )
+    if result.returncode != 0:
+        print("\nFailed to pre-initialize pybox_reactor.wasm")
+    return result.returncode
+
+
+def build_snapshot(wasm: Path):
+    """Snapshot the reactor after start-up and the creation of the default environment"""
+    from pybox.builder import PyBoxReactorBuilder
+    from pybox.pyboxcore import PyBoxReactorSnapshot
+
+    print(f"Snapshotting {wasm.name} after start-up...")
+    reactor = PyBoxReactorBuilder().wasm(str(wasm)).prewarm(False).build()
+    if not reactor.init_local(PREWARM_ENV):
+        print(f"Failed to create environment '{PREWARM_ENV}'")
+        return 1
+
+    # 不压缩，reactor 可以直接映射，wheel 本身会压缩
+    dst = wasm.with_suffix(".snapshot")
+    PyBoxReactorSnapshot(reactor).save(str(dst), compress=False)
+    print(f"Successfully wrote {dst.name}")
+    return 0
+
+
 def main():
     workspace_root = Path(__file__).parent
 
+    if "--snapshot" in sys.argv[1:]:
+        return build_snapshot(workspace_root / "python/pybox/image/pybox_reactor.wasm")
+
     # 检查并安装 target
     if not check_and_install_target():
         return 1
 
     print("\nBuilding pybox-reactor.wasm for wasm32-wasip1...")
 
+    use_wizer = "--wizer" in sys.argv[1:]
+    use_stdio = "--stdio" in sys.argv[1:]
+    use_tracing = "--tracing" in sys.argv[1:]
+    features = [
+        name for name, used in (("wizer", use_wizer), ("stdio", use_stdio), ("tracing", use_tracing)) if used
+    ]
+
     # 构建 wasm，显示完整输出
     result = subprocess.run(
         [
             "cargo", "build", "--release",
             "--target", "wasm32-wasip1",
             "--package", "pybox-reactor"
-        ],
+        ] + (["--features", ",".join(features)] if features else []),
         cwd=workspace_root,
         check=False
     )
@@ -61,12 +129,20 @@ def main():
     # 源文件和目标路径
     src = workspace_root / "target/wasm32-wasip1/release/pybox_reactor.wasm"
     dst = workspace_root / "python/pybox/image/pybox_reactor.wasm"
+    # stdio 版本不导入 host 函数，不能替换 python 包使用的 wasm
+    if use_stdio:
+        dst = src.with_name("pybox_reactor_stdio.wasm")
 
     # 确保目标目录存在
     dst.parent.mkdir(parents=True, exist_ok=True)
 
-    # 复制文件
-    shutil.copy(src, dst)
+    # 复制文件，旧的快照属于之前的构建
+    if use_wizer:
+        if wizer(src, dst) != 0:
+            return 1
+    else:
+        shutil.copy(src, dst)
+    dst.with_suffix(".snapshot").unlink(missing_ok=True)
     print(f"Successfully copied pybox_reactor.wasm to {dst.relative_to(workspace_root)}")
 
     return 0
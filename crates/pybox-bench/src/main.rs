@@ -0,0 +1,361 @@
+//! pybox-bench 用 criterion 测量 pybox-host 的关键路径
+//!
+//! ```text
+//! pybox-bench --wasm pybox_reactor.wasm --output bench.json
+//! pybox-bench --runtime wasmtime --runtime pulley --quick
+//! pybox-bench --baseline main.json --threshold 10
+//! ```
+//!
+//! 测量冷启动、热 exec、ioctl 往返、assign 吞吐和快照恢复；指定多个运行时时对比各运行时，
+//! 指定 baseline 时与之前的 JSON 结果对比，任何一项变慢超过阈值时以 1 退出
+
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use clap::Parser;
+use criterion::{Criterion, Throughput};
+use pybox_host::{Reactor, ReactorOptions, Runtime};
+use serde_json::{Value, json};
+
+/// ioctl 往返测量中每次 exec 调用 handler 的次数
+const IOCTL_CALLS: u64 = 100;
+/// assign 测量中列表的长度
+const ASSIGN_ITEMS: usize = 1000;
+const ENV: &str = "bench";
+
+#[derive(Parser)]
+#[command(version, about = "Benchmark the pybox reactor")]
+struct Args {
+    /// Reactor WASM file
+    #[arg(long, env = "PYBOX_WASM")]
+    wasm: PathBuf,
+
+    /// Runtime to benchmark, repeat it to compare runtimes
+    #[arg(long, value_parser = parse_runtime, default_value = "wasmtime")]
+    runtime: Vec<Runtime>,
+
+    /// Only run the benchmarks whose id (`runtime/benchmark`) matches this regex
+    #[arg(long)]
+    filter: Option<String>,
+
+    /// Take fewer and shorter samples
+    #[arg(long)]
+    quick: bool,
+
+    /// Write the results as JSON to this file instead of stdout
+    #[arg(long, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Compare with the JSON results of an earlier run
+    #[arg(long, value_name = "FILE")]
+    baseline: Option<PathBuf>,
+
+    /// Percentage a benchmark may be slower than the baseline before it fails
+    #[arg(long, default_value_t = 10.0)]
+    threshold: f64,
+
+    /// Directory for the criterion data
+    #[arg(long, default_value = "target/pybox-bench")]
+    criterion_dir: PathBuf,
+}
+
+fn parse_runtime(name: &str) -> Result<Runtime, String> {
+    match name {
+        "wasmtime" => Ok(Runtime::Wasmtime),
+        #[cfg(feature = "pulley")]
+        "pulley" => Ok(Runtime::Pulley),
+        #[cfg(feature = "wasmer")]
+        "wasmer" => Ok(Runtime::Wasmer),
+        _ => Err(format!(
+            "unknown runtime '{}', pulley and wasmer need the features of the same name",
+            name
+        )),
+    }
+}
+
+fn runtime_name(runtime: Runtime) -> String {
+    format!("{:?}", runtime).to_lowercase()
+}
+
+/// 一项测量：名称和每次迭代处理的数量
+struct Bench {
+    name: &'static str,
+    throughput: Option<Throughput>,
+}
+
+/// 在一个运行时上运行全部测量
+fn bench_runtime(c: &mut Criterion, wasm: &Path, runtime: Runtime) -> anyhow::Result<Vec<Bench>> {
+    let options = ReactorOptions {
+        runtime,
+        ..ReactorOptions::default()
+    };
+    let mut benches = Vec::new();
+    let mut group = c.benchmark_group(runtime_name(runtime));
+
+    // 模块在第一次创建 reactor 时编译并缓存，冷启动只包括实例化和解释器启动
+    let mut reactor = Reactor::new(wasm, &options)?;
+    group.bench_function("cold_start", |b| {
+        b.iter(|| {
+            let mut reactor = Reactor::new(wasm, &options).unwrap();
+            reactor.init_local(ENV).unwrap();
+        })
+    });
+    benches.push(Bench {
+        name: "cold_start",
+        throughput: None,
+    });
+
+    reactor.init_local(ENV)?;
+    group.bench_function("warm_exec", |b| {
+        b.iter(|| reactor.exec(ENV, "x = 1", None).unwrap())
+    });
+    benches.push(Bench {
+        name: "warm_exec",
+        throughput: None,
+    });
+
+    // 空响应不需要 host 重入 guest 分配内存，所有运行时都支持
+    reactor.register_handler(1, |_: &[u8]| Some(Vec::new()), None)?;
+    let code = format!(
+        "import pybox\nfor _ in range({}):\n    pybox.pybox_ioctl_host(1, b'ping')",
+        IOCTL_CALLS
+    );
+    let throughput = Throughput::Elements(IOCTL_CALLS);
+    group.throughput(throughput.clone());
+    group.bench_function("ioctl_round_trip", |b| {
+        b.iter(|| reactor.exec(ENV, &code, None).unwrap())
+    });
+    benches.push(Bench {
+        name: "ioctl_round_trip",
+        throughput: Some(throughput),
+    });
+
+    let value = json!((0..ASSIGN_ITEMS).collect::<Vec<_>>());
+    let throughput = Throughput::Bytes(value.to_string().len() as u64);
+    group.throughput(throughput.clone());
+    group.bench_function("assign", |b| {
+        b.iter(|| reactor.assign(ENV, "data", &value).unwrap())
+    });
+    benches.push(Bench {
+        name: "assign",
+        throughput: Some(throughput),
+    });
+
+    let snapshot = reactor.snapshot()?;
+    let throughput = Throughput::Bytes(snapshot.size() as u64);
+    group.throughput(throughput.clone());
+    group.bench_function("snapshot_restore", |b| {
+        b.iter(|| reactor.restore(&snapshot).unwrap())
+    });
+    benches.push(Bench {
+        name: "snapshot_restore",
+        throughput: Some(throughput),
+    });
+
+    group.finish();
+    Ok(benches)
+}
+
+/// 读取 criterion 保存的估计值；被 filter 跳过的测量没有结果，或者只有 started 之前的旧结果
+fn read_result(
+    dir: &Path,
+    runtime: &str,
+    bench: &Bench,
+    started: SystemTime,
+) -> anyhow::Result<Option<Value>> {
+    let path = dir
+        .join(runtime)
+        .join(bench.name)
+        .join("new/estimates.json");
+    match std::fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+        Ok(modified) if modified >= started => {}
+        _ => return Ok(None),
+    }
+    let estimates: Value = serde_json::from_slice(&std::fs::read(&path)?)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    let mut result = json!({
+        "benchmark": bench.name,
+        "runtime": runtime,
+        "mean_ns": estimates["mean"]["point_estimate"],
+        "median_ns": estimates["median"]["point_estimate"],
+        "std_dev_ns": estimates["std_dev"]["point_estimate"],
+    });
+    match bench.throughput {
+        Some(Throughput::Elements(elements)) => result["elements"] = elements.into(),
+        Some(Throughput::Bytes(bytes)) => result["bytes"] = bytes.into(),
+        _ => {}
+    }
+    Ok(Some(result))
+}
+
+fn mean(result: &Value) -> Option<f64> {
+    result["mean_ns"].as_f64()
+}
+
+/// 与 baseline 中同名测量的变化，返回 (测量, 变化的百分比)
+fn changes(results: &[Value], baseline: &Value) -> Vec<(String, f64)> {
+    let Some(baseline) = baseline["results"].as_array() else {
+        return Vec::new();
+    };
+    results
+        .iter()
+        .filter_map(|result| {
+            let before = baseline.iter().find(|before| {
+                before["benchmark"] == result["benchmark"] && before["runtime"] == result["runtime"]
+            })?;
+            let (now, before) = (mean(result)?, mean(before)?);
+            let id = format!(
+                "{}/{}",
+                result["runtime"].as_str()?,
+                result["benchmark"].as_str()?
+            );
+            Some((id, (now - before) / before * 100.0))
+        })
+        .collect()
+}
+
+/// 各运行时相对第一个运行时的耗时倍数，返回 (测量, 运行时, 倍数)
+fn ratios(results: &[Value], first: &str) -> Vec<(String, String, f64)> {
+    results
+        .iter()
+        .filter(|result| result["runtime"] != first)
+        .filter_map(|result| {
+            let base = results.iter().find(|base| {
+                base["runtime"] == first && base["benchmark"] == result["benchmark"]
+            })?;
+            Some((
+                result["benchmark"].as_str()?.to_string(),
+                result["runtime"].as_str()?.to_string(),
+                mean(result)? / mean(base)?,
+            ))
+        })
+        .collect()
+}
+
+fn run(args: &Args) -> anyhow::Result<bool> {
+    let mut c = Criterion::default()
+        .output_directory(&args.criterion_dir)
+        .without_plots();
+    if args.quick {
+        c = c
+            .sample_size(10)
+            .warm_up_time(Duration::from_millis(500))
+            .measurement_time(Duration::from_secs(1));
+    }
+    if let Some(filter) = &args.filter {
+        c = c.with_filter(filter);
+    }
+
+    let started = SystemTime::now();
+    let mut results = Vec::new();
+    for &runtime in &args.runtime {
+        let benches = bench_runtime(&mut c, &args.wasm, runtime)?;
+        for bench in &benches {
+            let runtime = runtime_name(runtime);
+            results.extend(read_result(&args.criterion_dir, &runtime, bench, started)?);
+        }
+    }
+    c.final_summary();
+
+    let report = json!({
+        "wasm": args.wasm.display().to_string(),
+        "results": results,
+    });
+    match &args.output {
+        Some(output) => std::fs::write(output, serde_json::to_string_pretty(&report)?)
+            .with_context(|| format!("Failed to write {}", output.display()))?,
+        None => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+
+    if let [first, _, ..] = args.runtime.as_slice() {
+        let first = runtime_name(*first);
+        for (bench, runtime, ratio) in ratios(&results, &first) {
+            eprintln!(
+                "{}: {} takes {:.2}x the time of {}",
+                bench, runtime, ratio, first
+            );
+        }
+    }
+
+    let Some(baseline) = &args.baseline else {
+        return Ok(true);
+    };
+    let baseline: Value = serde_json::from_slice(
+        &std::fs::read(baseline)
+            .with_context(|| format!("Failed to read {}", baseline.display()))?,
+    )?;
+    let mut passed = true;
+    for (id, change) in changes(&results, &baseline) {
+        let regressed = change > args.threshold;
+        passed &= !regressed;
+        eprintln!(
+            "{}: {:+.1}%{}",
+            id,
+            change,
+            if regressed { " REGRESSED" } else { "" }
+        );
+    }
+    Ok(passed)
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    match run(&args) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("pybox-bench: {:#}", err);
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn result(benchmark: &str, runtime: &str, mean_ns: f64) -> Value {
+        json!({ "benchmark": benchmark, "runtime": runtime, "mean_ns": mean_ns })
+    }
+
+    #[test]
+    fn test_changes() {
+        let baseline = json!({ "results": [
+            result("warm_exec", "wasmtime", 100.0),
+            result("assign", "wasmtime", 200.0),
+        ]});
+        let results = [
+            result("warm_exec", "wasmtime", 150.0),
+            result("assign", "wasmtime", 100.0),
+            result("cold_start", "wasmtime", 1.0),
+        ];
+        assert_eq!(
+            changes(&results, &baseline),
+            [
+                ("wasmtime/warm_exec".to_string(), 50.0),
+                ("wasmtime/assign".to_string(), -50.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ratios() {
+        let results = [
+            result("warm_exec", "wasmtime", 100.0),
+            result("warm_exec", "pulley", 5000.0),
+            result("assign", "pulley", 1.0),
+        ];
+        assert_eq!(
+            ratios(&results, "wasmtime"),
+            [("warm_exec".to_string(), "pulley".to_string(), 50.0)]
+        );
+    }
+
+    #[test]
+    fn test_parse_runtime() {
+        assert_eq!(parse_runtime("wasmtime"), Ok(Runtime::Wasmtime));
+        assert!(parse_runtime("v8").is_err());
+    }
+}
@@ -0,0 +1,218 @@
+//! pybox-builder 把纯 python 包打包进定制的 reactor wasm
+//!
+//! ```text
+//! pybox-builder -r requirements.txt -o tools_reactor.wasm
+//! pybox-builder attrs ./libs/internal_pkg --source ./src -o tools_reactor.wasm --wizer
+//! ```
+//!
+//! 包由 `python3 -m pip install --target` 安装到暂存目录，`--source` 的目录原样复制；
+//! 暂存目录中不能有扩展模块。随后以 `PYBOX_BUNDLE=<暂存目录>` 构建 pybox-reactor，
+//! 模块源码随标准库一起编进 wasm，guest 代码直接 import 即可
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode};
+
+use anyhow::{Context, bail};
+use clap::Parser;
+
+/// 扩展模块的后缀，wasm 中无法加载
+const EXTENSION_SUFFIXES: [&str; 3] = ["so", "pyd", "dylib"];
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Bake pure-Python packages into a custom pybox reactor"
+)]
+struct Args {
+    /// Packages to install with pip: names, version specifiers or local paths
+    packages: Vec<String>,
+
+    /// Install the packages listed in a requirements file
+    #[arg(short = 'r', long, value_name = "FILE")]
+    requirements: Vec<PathBuf>,
+
+    /// Copy the modules and packages of a directory into the bundle as they are
+    #[arg(long, value_name = "DIR")]
+    source: Vec<PathBuf>,
+
+    /// Do not install the dependencies of the packages
+    #[arg(long)]
+    no_deps: bool,
+
+    /// Pre-initialize the reactor with wizer (`cargo install wizer --all-features`)
+    #[arg(long)]
+    wizer: bool,
+
+    /// Output WASM file
+    #[arg(short = 'o', long, default_value = "pybox_reactor.wasm")]
+    output: PathBuf,
+
+    /// Root of the pybox workspace
+    #[arg(long, default_value = concat!(env!("CARGO_MANIFEST_DIR"), "/../.."))]
+    workspace: PathBuf,
+}
+
+/// 运行命令，失败时以命令名报错
+fn run_command(command: &mut Command) -> anyhow::Result<()> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let status = command
+        .status()
+        .with_context(|| format!("Failed to run {}", program))?;
+    if !status.success() {
+        bail!("{} exited with {}", program, status);
+    }
+    Ok(())
+}
+
+/// 把包安装并复制到暂存目录
+fn stage(args: &Args, bundle: &Path) -> anyhow::Result<()> {
+    if bundle.exists() {
+        std::fs::remove_dir_all(bundle)?;
+    }
+    std::fs::create_dir_all(bundle)?;
+
+    if !args.packages.is_empty() || !args.requirements.is_empty() {
+        let mut pip = Command::new("python3");
+        pip.args(["-m", "pip", "install", "--no-compile", "--target"])
+            .arg(bundle);
+        if args.no_deps {
+            pip.arg("--no-deps");
+        }
+        for requirements in &args.requirements {
+            pip.arg("-r").arg(requirements);
+        }
+        pip.args(&args.packages);
+        run_command(&mut pip)?;
+    }
+    for source in &args.source {
+        copy_dir(source, bundle).with_context(|| format!("Failed to copy {}", source.display()))?;
+    }
+
+    let extensions = extension_modules(bundle)?;
+    if !extensions.is_empty() {
+        let names: Vec<String> = extensions
+            .iter()
+            .map(|path| {
+                path.strip_prefix(bundle)
+                    .unwrap_or(path)
+                    .display()
+                    .to_string()
+            })
+            .collect();
+        bail!(
+            "Extension modules cannot run in the sandbox, use pure-Python packages: {}",
+            names.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(from)? {
+        let path = entry?.path();
+        let target = to.join(path.file_name().unwrap());
+        if path.is_dir() {
+            std::fs::create_dir_all(&target)?;
+            copy_dir(&path, &target)?;
+        } else {
+            std::fs::copy(&path, &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// 目录中的扩展模块
+fn extension_modules(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut extensions = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            extensions.extend(extension_modules(&path)?);
+        } else if path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| EXTENSION_SUFFIXES.contains(&ext))
+        {
+            extensions.push(path);
+        }
+    }
+    extensions.sort();
+    Ok(extensions)
+}
+
+/// 以暂存目录构建 pybox-reactor，使用单独的 target 目录，不影响默认构建的 wasm
+fn build(args: &Args, target_dir: &Path, bundle: &Path) -> anyhow::Result<PathBuf> {
+    let mut cargo = Command::new("cargo");
+    cargo
+        .args(["build", "--release", "--target", "wasm32-wasip1"])
+        .args(["--package", "pybox-reactor", "--target-dir"])
+        .arg(target_dir)
+        .env("PYBOX_BUNDLE", bundle)
+        .current_dir(&args.workspace);
+    if args.wizer {
+        cargo.args(["--features", "wizer"]);
+    }
+    run_command(&mut cargo)?;
+    Ok(target_dir.join("wasm32-wasip1/release/pybox_reactor.wasm"))
+}
+
+fn run(args: &Args) -> anyhow::Result<()> {
+    let workspace = args
+        .workspace
+        .canonicalize()
+        .with_context(|| format!("{} is not the pybox workspace", args.workspace.display()))?;
+    let target_dir = workspace.join("target/pybox-builder");
+    let bundle = target_dir.join("bundle");
+    stage(args, &bundle)?;
+    let wasm = build(args, &target_dir, &bundle)?;
+
+    if args.wizer {
+        run_command(
+            Command::new("wizer")
+                .args(["--allow-wasi", "--wasm-bulk-memory", "true"])
+                .args(["--init-func", "wizer.initialize", "-o"])
+                .arg(&args.output)
+                .arg(&wasm),
+        )?;
+    } else {
+        std::fs::copy(&wasm, &args.output)
+            .with_context(|| format!("Failed to write {}", args.output.display()))?;
+    }
+    println!("Wrote {}", args.output.display());
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("pybox-builder: {:#}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_extension_modules() {
+        let dir = std::env::temp_dir().join(format!("pybox-builder-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("pkg/sub")).unwrap();
+        for file in [
+            "pkg/__init__.py",
+            "pkg/sub/fast.cpython-312-x86_64-linux-gnu.so",
+            "mod.py",
+        ] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+        let extensions = extension_modules(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            extensions,
+            [dir.join("pkg/sub/fast.cpython-312-x86_64-linux-gnu.so")]
+        );
+    }
+}
@@ -0,0 +1,192 @@
+//! pybox-cli 在命令行中运行沙箱内的 python 代码
+//!
+//! ```text
+//! pybox-cli --wasm pybox_reactor.wasm script.py
+//! echo "print(1 + 1)" | pybox-cli -
+//! pybox-cli -c "import sys; sys.exit(3)"; echo $?
+//! pybox-cli
+//! ```
+//!
+//! 进程以 guest 的状态退出：正常结束为 0，`sys.exit(n)` 为 n，未捕获的异常为 1，
+//! 超时为 124，KeyboardInterrupt 为 130，host 错误为 2；
+//! 没有指定代码且 stdin 是终端时进入交互式解释器
+
+mod repl;
+mod runner;
+
+use std::io::{IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use clap::Parser;
+
+use runner::{Runner, RunnerOptions};
+
+#[derive(Parser)]
+#[command(version, about = "Run Python code inside the pybox sandbox")]
+struct Args {
+    /// Script to run, `-` reads it from stdin. Without a script or `-c` an
+    /// interactive session starts when stdin is a terminal
+    #[arg(conflicts_with = "command")]
+    script: Option<PathBuf>,
+
+    /// Program passed in as a string
+    #[arg(short = 'c', value_name = "CODE")]
+    command: Option<String>,
+
+    /// Reactor WASM file
+    #[arg(long, env = "PYBOX_WASM")]
+    wasm: PathBuf,
+
+    /// Stop the execution after this many seconds
+    #[arg(long, value_name = "SECONDS")]
+    timeout: Option<f64>,
+
+    /// Maximum guest memory in bytes, with an optional K, M or G suffix
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_memory: Option<usize>,
+
+    /// Map a host directory into the sandbox, `GUEST=HOST` or a path used for both
+    #[arg(long, value_name = "DIR", value_parser = parse_preopen)]
+    preopen: Vec<(String, PathBuf)>,
+
+    /// Print the structured result as JSON instead of the output
+    #[arg(long)]
+    json_output: bool,
+}
+
+/// 解析带 K / M / G 后缀的字节数
+fn parse_size(size: &str) -> Result<usize, String> {
+    let (digits, unit) = match size.char_indices().last() {
+        Some((index, suffix)) if suffix.is_ascii_alphabetic() => {
+            let unit = match suffix.to_ascii_uppercase() {
+                'K' => 1 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                _ => return Err(format!("unknown size suffix '{}'", suffix)),
+            };
+            (&size[..index], unit)
+        }
+        _ => (size, 1),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|size| size.checked_mul(unit))
+        .ok_or_else(|| format!("invalid size '{}'", size))
+}
+
+/// 解析 `GUEST=HOST`，没有 `=` 时 guest 和 host 使用相同的路径
+fn parse_preopen(preopen: &str) -> Result<(String, PathBuf), String> {
+    let (guest, host) = preopen.split_once('=').unwrap_or((preopen, preopen));
+    if guest.is_empty() || host.is_empty() {
+        return Err(format!("invalid directory mapping '{}'", preopen));
+    }
+    Ok((guest.to_string(), PathBuf::from(host)))
+}
+
+/// 是否进入交互式解释器
+fn interactive(args: &Args) -> bool {
+    args.command.is_none() && args.script.is_none() && std::io::stdin().is_terminal()
+}
+
+/// 执行期间的 Ctrl-C 交给 guest 处理，guest 没有响应时再按一次退出
+pub(crate) fn install_interrupt_handler(runner: &Runner) -> anyhow::Result<()> {
+    let interrupt = runner.interrupt_handle();
+    ctrlc::set_handler(move || {
+        if interrupt.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+    })?;
+    Ok(())
+}
+
+fn read_code(args: &Args) -> anyhow::Result<String> {
+    if let Some(command) = &args.command {
+        return Ok(command.clone());
+    }
+    let script = args.script.as_deref().unwrap_or("-".as_ref());
+    if script.as_os_str() == "-" {
+        let mut code = String::new();
+        std::io::stdin().read_to_string(&mut code)?;
+        return Ok(code);
+    }
+    std::fs::read_to_string(script).map_err(|e| anyhow::anyhow!("{}: {}", script.display(), e))
+}
+
+fn run(args: &Args) -> anyhow::Result<i32> {
+    let timeout = args
+        .timeout
+        .map(Duration::try_from_secs_f64)
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("invalid timeout: {}", e))?;
+    let mut runner = Runner::new(&RunnerOptions {
+        wasm: args.wasm.clone(),
+        timeout,
+        max_memory: args.max_memory,
+        preopens: args.preopen.clone(),
+    })?;
+
+    if interactive(args) {
+        return repl::run(&mut runner);
+    }
+    install_interrupt_handler(&runner)?;
+    let code = read_code(args)?;
+    let outcome = runner.exec(&code)?;
+
+    if args.json_output {
+        println!("{}", outcome.to_json());
+    } else {
+        print!("{}", outcome.stdout);
+        std::io::stdout().flush()?;
+        eprint!("{}", outcome.stderr);
+        if outcome.timed_out {
+            eprintln!("pybox-cli: execution timed out");
+        }
+        if outcome.interrupted {
+            eprintln!("pybox-cli: execution interrupted");
+        }
+    }
+    Ok(outcome.exit_code())
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    match run(&args) {
+        Ok(code) => ExitCode::from(code as u8),
+        Err(err) => {
+            eprintln!("pybox-cli: {:#}", err);
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("64k"), Ok(64 << 10));
+        assert_eq!(parse_size("256M"), Ok(256 << 20));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("M").is_err());
+    }
+
+    #[test]
+    fn test_parse_preopen() {
+        assert_eq!(
+            parse_preopen("/data=./data"),
+            Ok(("/data".to_string(), PathBuf::from("./data")))
+        );
+        assert_eq!(
+            parse_preopen("/tmp"),
+            Ok(("/tmp".to_string(), PathBuf::from("/tmp")))
+        );
+        assert!(parse_preopen("=/tmp").is_err());
+    }
+}
@@ -0,0 +1,170 @@
+//! repl.rs 交互式解释器
+//!
+//! 所有输入在同一个局部环境中执行，变量在各次输入之间保留；guest 以 BlockExpr 模式编译代码，
+//! 以表达式结尾的输入回显表达式的 repr，与 python 的 `single` 模式相同
+//! 执行期间的 Ctrl-C 在 python 代码中抛出 KeyboardInterrupt，输入时的 Ctrl-C 清空当前输入
+
+use std::io::Write;
+use std::path::PathBuf;
+
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+
+use crate::runner::Runner;
+
+/// 运行交互式解释器直到 EOF 或 SystemExit，返回进程的退出码
+pub fn run(runner: &mut Runner) -> anyhow::Result<i32> {
+    let mut editor = DefaultEditor::new()?;
+    // rustyline 创建终端时会替换 SIGINT 的处理函数，需要在之后安装
+    crate::install_interrupt_handler(runner)?;
+    let history = history_path();
+    if let Some(history) = &history {
+        let _ = editor.load_history(history);
+    }
+    println!(
+        "pybox {} (sandboxed RustPython), Ctrl-D to exit",
+        env!("CARGO_PKG_VERSION")
+    );
+
+    let mut buffer = String::new();
+    let code = loop {
+        let prompt = if buffer.is_empty() { ">>> " } else { "... " };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                if !buffer.is_empty() {
+                    buffer.push('\n');
+                }
+                buffer.push_str(&line);
+                if needs_more(&buffer, &line) {
+                    continue;
+                }
+            }
+            Err(ReadlineError::Interrupted) => {
+                println!("KeyboardInterrupt");
+                buffer.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break 0,
+            Err(err) => return Err(err.into()),
+        }
+
+        let source = std::mem::take(&mut buffer);
+        if source.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(source.as_str());
+
+        let outcome = match runner.exec(&source) {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                eprintln!("pybox-cli: {:#}", err);
+                restart(runner)?;
+                continue;
+            }
+        };
+        print!("{}", outcome.stdout);
+        std::io::stdout().flush()?;
+        eprint!("{}", outcome.stderr);
+        if let Some(result_repr) = &outcome.result_repr {
+            println!("{}", result_repr);
+        }
+        if outcome.timed_out || outcome.interrupted {
+            eprintln!(
+                "pybox-cli: execution {}",
+                if outcome.timed_out {
+                    "timed out"
+                } else {
+                    "interrupted"
+                }
+            );
+            restart(runner)?;
+            continue;
+        }
+        if outcome
+            .exception
+            .as_ref()
+            .is_some_and(|exception| exception["type"] == "SystemExit")
+        {
+            break outcome.exit_code();
+        }
+    };
+
+    if let Some(history) = &history {
+        let _ = editor.save_history(history);
+    }
+    Ok(code)
+}
+
+/// 被终止的 guest 状态不可信，重新开始一个空的环境
+fn restart(runner: &mut Runner) -> anyhow::Result<()> {
+    runner.restart()?;
+    eprintln!("pybox-cli: sandbox restarted, previous state lost");
+    Ok(())
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".pybox_history"))
+}
+
+/// 输入是否还没有结束：括号或字符串没有闭合、以 `\` 续行，
+/// 或者是以 `:` 开始的代码块且最后一行不是空行
+fn needs_more(buffer: &str, line: &str) -> bool {
+    if line.trim_end().ends_with('\\') {
+        return true;
+    }
+    let mut depth = 0i32;
+    let mut quote = None;
+    let mut chars = buffer.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(_), '\\') => {
+                chars.next();
+            }
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '#') => {
+                // 注释到行尾
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            (None, '(' | '[' | '{') => depth += 1,
+            (None, ')' | ']' | '}') => depth -= 1,
+            (None, _) => {}
+        }
+    }
+    // 三引号字符串按连续的单引号字符串计算，未闭合时 quote 仍然有值
+    if depth > 0 || quote.is_some() {
+        return true;
+    }
+    let block = buffer
+        .lines()
+        .next()
+        .is_some_and(|first| first.trim_end().ends_with(':'));
+    block && !line.trim().is_empty()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_needs_more() {
+        assert!(!needs_more("x = 1", "x = 1"));
+        assert!(needs_more("x = (1,", "x = (1,"));
+        assert!(!needs_more("x = (1,\n2)", "2)"));
+        assert!(!needs_more("x = ')'", "x = ')'"));
+        assert!(!needs_more("x = 1 # (", "x = 1 # ("));
+        assert!(needs_more("x = 1 + \\", "x = 1 + \\"));
+
+        assert!(needs_more("if x:", "if x:"));
+        assert!(needs_more("if x:\n    y = 1", "    y = 1"));
+        assert!(!needs_more("if x:\n    y = 1\n", ""));
+
+        assert!(needs_more("s = \"\"\"a", "s = \"\"\"a"));
+        assert!(!needs_more("s = \"\"\"a\nb\"\"\"", "b\"\"\""));
+    }
+}
@@ -0,0 +1,132 @@
+//! runner.rs 用 pybox-host 加载 reactor wasm，在一个局部环境中执行代码
+//!
+//! CLI 不注册 handler，guest 发起的 ioctl 请求除名称解析外全部返回失败；
+//! 超时、Ctrl-C 中断和内存上限由 pybox-host 的 reactor 实现
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+use pybox_host::{Reactor, ReactorOptions, Snapshot};
+use serde_json::{Value, json};
+
+/// 执行代码的局部环境
+const ENV_ID: &str = "__main__";
+
+#[derive(Clone)]
+pub struct RunnerOptions {
+    pub wasm: PathBuf,
+    /// 单次 exec 的超时时间
+    pub timeout: Option<Duration>,
+    /// guest 线性内存的上限（字节）
+    pub max_memory: Option<usize>,
+    /// (guest 路径, host 路径)
+    pub preopens: Vec<(String, PathBuf)>,
+}
+
+/// exec 的结果，字段与 `pybox_exec_report` 的 json 结果对应
+pub struct ExecOutcome {
+    pub stdout: String,
+    pub stderr: String,
+    /// `{"type", "message", "traceback"}`
+    pub exception: Option<Value>,
+    pub result_repr: Option<String>,
+    pub timed_out: bool,
+    /// Ctrl-C 终止了 guest 执行
+    pub interrupted: bool,
+}
+
+impl From<pybox_host::ExecOutcome> for ExecOutcome {
+    fn from(outcome: pybox_host::ExecOutcome) -> Self {
+        Self {
+            stdout: outcome.stdout,
+            stderr: outcome.stderr,
+            exception: outcome.exception,
+            result_repr: outcome.result_repr,
+            timed_out: outcome.timed_out,
+            interrupted: outcome.interrupted,
+        }
+    }
+}
+
+impl ExecOutcome {
+    /// 进程的退出码：SystemExit 的退出码，未捕获的异常为 1，超时为 124，
+    /// 与 python 一样 KeyboardInterrupt 为 130
+    pub fn exit_code(&self) -> i32 {
+        if self.timed_out {
+            return 124;
+        }
+        if self.interrupted {
+            return 130;
+        }
+        let Some(exception) = &self.exception else {
+            return 0;
+        };
+        if exception["type"] == "KeyboardInterrupt" {
+            return 130;
+        }
+        if exception["type"] != "SystemExit" {
+            return 1;
+        }
+        match exception["message"].as_str().unwrap_or_default() {
+            "" | "None" => 0,
+            message => message.parse().unwrap_or(1),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "stdout": self.stdout,
+            "stderr": self.stderr,
+            "exception": self.exception,
+            "result_repr": self.result_repr,
+            "timed_out": self.timed_out,
+            "interrupted": self.interrupted,
+            "exit_code": self.exit_code(),
+        })
+    }
+}
+
+pub struct Runner {
+    reactor: Reactor,
+    /// 刚创建好空环境时的快照，restart 恢复到这里
+    initial: Snapshot,
+}
+
+impl Runner {
+    /// 加载 wasm 并创建执行代码的局部环境
+    pub fn new(options: &RunnerOptions) -> anyhow::Result<Self> {
+        let mut reactor = Reactor::new(
+            &options.wasm,
+            &ReactorOptions {
+                timeout: options.timeout,
+                max_memory: options.max_memory,
+                preopens: options.preopens.clone(),
+                // guest 可以读取管道输入
+                inherit_stdin: true,
+                ..ReactorOptions::default()
+            },
+        )?;
+        reactor.init_local(ENV_ID)?;
+        let initial = reactor.snapshot()?;
+        Ok(Self { reactor, initial })
+    }
+
+    /// 恢复到刚创建环境时的状态，之前环境中的状态全部丢失
+    /// guest 执行被终止后内部状态不可信，需要重新开始；中断句柄保持不变
+    pub fn restart(&mut self) -> anyhow::Result<()> {
+        self.reactor.restore(&self.initial)
+    }
+
+    /// 请求中断正在执行的代码，可以在其他线程或信号处理函数中设置
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        self.reactor.interrupt_handle()
+    }
+
+    /// 执行代码，guest 代码抛出的异常记录在结果中，host 错误以 Err 返回
+    pub fn exec(&mut self, code: &str) -> anyhow::Result<ExecOutcome> {
+        let outcome = self.reactor.exec(ENV_ID, code, None)?;
+        Ok(outcome.into())
+    }
+}
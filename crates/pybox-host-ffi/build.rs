@@ -0,0 +1,13 @@
+//! 从导出的 extern "C" 函数生成 include/pybox_host.h
+
+fn main() {
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    cbindgen::Builder::new()
+        .with_crate(&crate_dir)
+        .with_config(cbindgen::Config::from_file(format!("{}/cbindgen.toml", crate_dir)).unwrap())
+        .generate()
+        .expect("Failed to generate pybox_host.h")
+        .write_to_file(format!("{}/include/pybox_host.h", crate_dir));
+}
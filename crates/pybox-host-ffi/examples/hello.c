@@ -0,0 +1,83 @@
+/*
+ * Embed pybox from C
+ *
+ *   cargo build --release -p pybox-host-ffi
+ *   cc crates/pybox-host-ffi/examples/hello.c -Icrates/pybox-host-ffi/include \
+ *       -Ltarget/release -lpybox_host -o hello
+ *   LD_LIBRARY_PATH=target/release ./hello python/pybox/image/pybox_reactor.wasm
+ */
+#include <stdio.h>
+#include <string.h>
+
+#include "pybox_host.h"
+
+/* Echo the request back in upper case */
+static int32_t upper(void *user_data, const uint8_t *request, size_t request_len,
+                     PyboxResponse *response) {
+    (void)user_data;
+    for (size_t i = 0; i < request_len; i++) {
+        uint8_t c = request[i];
+        if (c >= 'a' && c <= 'z') {
+            c -= 'a' - 'A';
+        }
+        pybox_response_write(response, &c, 1);
+    }
+    return 0;
+}
+
+static int fail(const char *what) {
+    fprintf(stderr, "%s: %s\n", what, pybox_last_error());
+    return 1;
+}
+
+int main(int argc, char **argv) {
+    if (argc < 2) {
+        fprintf(stderr, "usage: %s <pybox_reactor.wasm>\n", argv[0]);
+        return 2;
+    }
+
+    PyboxOptions options = {.max_memory = 256 << 20, .timeout_ms = 5000};
+    PyboxReactor *reactor = pybox_reactor_new(argv[1], &options);
+    if (reactor == NULL) {
+        return fail("pybox_reactor_new");
+    }
+    if (pybox_register_handler(reactor, 1, upper, NULL, "upper") != 0) {
+        return fail("pybox_register_handler");
+    }
+    if (pybox_init_local(reactor, "env", NULL) != 0) {
+        return fail("pybox_init_local");
+    }
+    if (pybox_assign(reactor, "env", "name", "\"pybox\"") != 0) {
+        return fail("pybox_assign");
+    }
+
+    PyboxSnapshot *snapshot = pybox_snapshot(reactor);
+    PyboxExecResult *result = pybox_exec(
+        reactor, "env",
+        "import pybox\n"
+        "ok, data = pybox.pybox_ioctl_host(pybox.resolve('upper'), name.encode())\n"
+        "print(data.decode())\n"
+        "count = 1\n",
+        0);
+    if (result == NULL) {
+        return fail("pybox_exec");
+    }
+    printf("stdout: %s", pybox_exec_result_stdout(result, NULL));
+    const char *exception = pybox_exec_result_exception(result, NULL);
+    printf("exception: %s\n", exception ? exception : "none");
+    pybox_exec_result_free(result);
+
+    char *count = pybox_retrieve(reactor, "env", "count");
+    printf("count: %s\n", count ? count : pybox_last_error());
+    pybox_string_free(count);
+
+    /* the variables created after the snapshot are gone */
+    pybox_restore(reactor, snapshot);
+    result = pybox_exec(reactor, "env", "print('count' in globals())\n", 0);
+    printf("after restore: %s", pybox_exec_result_stdout(result, NULL));
+    pybox_exec_result_free(result);
+
+    pybox_snapshot_free(snapshot);
+    pybox_reactor_free(reactor);
+    return 0;
+}
@@ -0,0 +1,166 @@
+#ifndef PYBOX_HOST_H
+#define PYBOX_HOST_H
+
+/* Generated by cbindgen from crates/pybox-host-ffi, do not edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/*
+ exec 的结果，字符串字段以 NUL 结尾保存，供 getter 直接返回指针
+ */
+typedef struct PyboxExecResult PyboxExecResult;
+
+/*
+ reactor 句柄
+ */
+typedef struct PyboxReactor PyboxReactor;
+
+/*
+ handler 的响应，由 pybox_response_write 追加数据
+ */
+typedef struct PyboxResponse PyboxResponse;
+
+/*
+ reactor 内存和全局变量的快照
+ */
+typedef struct PyboxSnapshot PyboxSnapshot;
+
+/*
+ 创建 reactor 的选项，0 表示不限制
+ */
+typedef struct PyboxOptions {
+  /*
+   guest 线性内存的上限（字节）
+   */
+  uint64_t max_memory;
+  /*
+   exec 的默认超时（毫秒）
+   */
+  uint64_t timeout_ms;
+} PyboxOptions;
+
+/*
+ C handler：成功返回 0 并通过 pybox_response_write 写入响应，非 0 表示失败
+ */
+typedef int32_t (*PyboxHandlerFn)(void *user_data, const uint8_t *request, size_t request_len, struct PyboxResponse *response);
+
+/*
+ 实时输出回调：stream 为 "stdout" 或 "stderr"
+ */
+typedef void (*PyboxOutputFn)(void *user_data, const char *stream, const char *text, size_t len);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ 最近一次失败的错误信息，没有时返回 NULL；指针在本线程下一次调用 pybox 函数前有效
+ */
+const char *pybox_last_error(void);
+
+/*
+ 加载 wasm 文件创建 reactor，options 可以为 NULL；同一个文件只编译一次
+ */
+struct PyboxReactor *pybox_reactor_new(const char *wasm_path, const struct PyboxOptions *options);
+
+void pybox_reactor_free(struct PyboxReactor *reactor);
+
+/*
+ 注册 handler，guest 以 pybox_ioctl_host(handle, data) 调用；
+ name 不为 NULL 时 guest 可以用 pybox.resolve(name) 查询 handle。
+ user_data 原样传给 func，需要在 reactor 释放或 handler 注销前保持有效
+ */
+int32_t pybox_register_handler(struct PyboxReactor *reactor, uint32_t handle, PyboxHandlerFn func, void *user_data, const char *name);
+
+/*
+ 注销 handler，返回 1 表示已注销，0 表示没有该 handler
+ */
+int32_t pybox_unregister_handler(struct PyboxReactor *reactor, uint32_t handle);
+
+/*
+ 在 handler 中追加响应数据
+ */
+int32_t pybox_response_write(struct PyboxResponse *response, const uint8_t *data, size_t len);
+
+/*
+ 设置 exec 实时输出的回调，func 为 NULL 时取消；旧版 guest 不支持实时输出
+ */
+int32_t pybox_set_output_callback(struct PyboxReactor *reactor, PyboxOutputFn func, void *user_data);
+
+/*
+ 创建空的局部环境，from_env_id 不为 NULL 时浅拷贝该环境
+ */
+int32_t pybox_init_local(struct PyboxReactor *reactor, const char *env_id, const char *from_env_id);
+
+int32_t pybox_del_local(struct PyboxReactor *reactor, const char *env_id);
+
+/*
+ 在局部环境中执行代码，timeout_ms 为 0 时使用创建时的超时。
+ 代码抛出的异常记录在结果中，超时也返回结果（timed_out 为 1），
+ 之后 reactor 的状态不可信，应恢复快照或重新创建
+ */
+struct PyboxExecResult *pybox_exec(struct PyboxReactor *reactor, const char *env_id, const char *code, uint64_t timeout_ms);
+
+const char *pybox_exec_result_stdout(const struct PyboxExecResult *result, size_t *len);
+
+const char *pybox_exec_result_stderr(const struct PyboxExecResult *result, size_t *len);
+
+/*
+ 末尾表达式的 repr，没有时返回 NULL
+ */
+const char *pybox_exec_result_repr(const struct PyboxExecResult *result, size_t *len);
+
+/*
+ 未捕获的异常 `{"type", "message", "traceback"}` 的 json，没有时返回 NULL
+ */
+const char *pybox_exec_result_exception(const struct PyboxExecResult *result, size_t *len);
+
+/*
+ 完整结果的 json，包括 artifacts
+ */
+const char *pybox_exec_result_json(const struct PyboxExecResult *result, size_t *len);
+
+int32_t pybox_exec_result_timed_out(const struct PyboxExecResult *result);
+
+int32_t pybox_exec_result_truncated(const struct PyboxExecResult *result);
+
+void pybox_exec_result_free(struct PyboxExecResult *result);
+
+/*
+ 以 json 值创建变量
+ */
+int32_t pybox_assign(struct PyboxReactor *reactor, const char *env_id, const char *name, const char *value_json);
+
+/*
+ 读取变量的 json 值，由 pybox_string_free 释放
+ */
+char *pybox_retrieve(struct PyboxReactor *reactor, const char *env_id, const char *name);
+
+void pybox_string_free(char *string);
+
+/*
+ 保存 reactor 当前的内存和全局变量
+ */
+struct PyboxSnapshot *pybox_snapshot(struct PyboxReactor *reactor);
+
+/*
+ 恢复到快照时的状态，快照必须来自同一个 wasm 文件的 reactor
+ */
+int32_t pybox_restore(struct PyboxReactor *reactor, const struct PyboxSnapshot *snapshot);
+
+/*
+ 快照的内存大小（字节）
+ */
+size_t pybox_snapshot_size(const struct PyboxSnapshot *snapshot);
+
+void pybox_snapshot_free(struct PyboxSnapshot *snapshot);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* PYBOX_HOST_H */
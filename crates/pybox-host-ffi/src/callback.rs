@@ -0,0 +1,67 @@
+//! callback.rs 把 C 回调适配为 pybox-host 的 Handler 和 OutputSink
+
+use std::ffi::{c_char, c_void};
+
+use pybox_host::{Handler, OutputSink};
+
+use crate::PyboxResponse;
+
+/// C handler：成功返回 0 并通过 pybox_response_write 写入响应，非 0 表示失败
+pub type PyboxHandlerFn = unsafe extern "C" fn(
+    user_data: *mut c_void,
+    request: *const u8,
+    request_len: usize,
+    response: *mut PyboxResponse,
+) -> i32;
+
+/// 实时输出回调：stream 为 "stdout" 或 "stderr"
+pub type PyboxOutputFn = unsafe extern "C" fn(
+    user_data: *mut c_void,
+    stream: *const c_char,
+    text: *const c_char,
+    len: usize,
+);
+
+pub struct CHandler {
+    pub func: PyboxHandlerFn,
+    pub user_data: *mut c_void,
+}
+
+pub struct COutput {
+    pub func: PyboxOutputFn,
+    pub user_data: *mut c_void,
+}
+
+// Handler 和 OutputSink 要求可以跨线程移动；回调在使用 reactor 的线程上调用，
+// user_data 能否在线程间使用由调用方保证
+unsafe impl Send for CHandler {}
+unsafe impl Send for COutput {}
+
+impl Handler for CHandler {
+    fn call(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        let mut response = PyboxResponse { data: Vec::new() };
+        let result = unsafe {
+            (self.func)(
+                self.user_data,
+                request.as_ptr(),
+                request.len(),
+                &mut response,
+            )
+        };
+        (result == 0).then_some(response.data)
+    }
+}
+
+impl OutputSink for COutput {
+    fn write(&mut self, stream: &str, text: &str) {
+        let stream = format!("{}\0", stream);
+        unsafe {
+            (self.func)(
+                self.user_data,
+                stream.as_ptr().cast(),
+                text.as_ptr().cast(),
+                text.len(),
+            )
+        };
+    }
+}
@@ -0,0 +1,500 @@
+//! libpybox_host 以 C ABI 导出 pybox 沙箱，头文件 include/pybox_host.h 由 build.rs 通过 cbindgen 生成
+//!
+//! 约定：
+//! - 返回 int32_t 的函数成功返回 0，失败返回 -1，错误信息由 pybox_last_error() 读取（线程局部）
+//! - 返回指针的函数失败返回 NULL
+//! - 字符串参数为 NUL 结尾的 utf-8；库返回的对象由对应的 *_free 释放
+//! - 一个 reactor 同时只能在一个线程中使用，handler 中不能再调用同一个 reactor
+
+// 指针参数的约定见上面的说明，不再为每个函数重复 # Safety
+#![allow(clippy::missing_safety_doc)]
+
+mod callback;
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char, c_void};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use pybox_host::{OutputSink, Reactor, ReactorOptions, Snapshot};
+
+use callback::{CHandler, COutput};
+
+pub use callback::{PyboxHandlerFn, PyboxOutputFn};
+
+/// reactor 句柄
+pub struct PyboxReactor {
+    reactor: Reactor,
+    /// 正在调用 guest，handler 中重入同一个 reactor 时报错
+    busy: bool,
+}
+
+/// reactor 内存和全局变量的快照
+pub struct PyboxSnapshot {
+    snapshot: Snapshot,
+}
+
+/// handler 的响应，由 pybox_response_write 追加数据
+pub struct PyboxResponse {
+    data: Vec<u8>,
+}
+
+/// exec 的结果，字符串字段以 NUL 结尾保存，供 getter 直接返回指针
+pub struct PyboxExecResult {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    result_repr: Option<Vec<u8>>,
+    exception: Option<Vec<u8>>,
+    json: Vec<u8>,
+    truncated: bool,
+    timed_out: bool,
+}
+
+/// 创建 reactor 的选项，0 表示不限制
+#[repr(C)]
+pub struct PyboxOptions {
+    /// guest 线性内存的上限（字节）
+    pub max_memory: u64,
+    /// exec 的默认超时（毫秒）
+    pub timeout_ms: u64,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with_borrow_mut(|error| *error = Some(message));
+}
+
+/// 调用 f，错误和 panic 记录为 last error 并返回 fallback
+fn ffi_call<T>(fallback: T, f: impl FnOnce() -> anyhow::Result<T>) -> T {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(err)) => {
+            set_error(format!("{:#}", err));
+            fallback
+        }
+        Err(_) => {
+            set_error("pybox panicked".to_string());
+            fallback
+        }
+    }
+}
+
+fn status(f: impl FnOnce() -> anyhow::Result<()>) -> i32 {
+    ffi_call(-1, || f().map(|_| 0))
+}
+
+/// 读取 NUL 结尾的 utf-8 字符串参数
+unsafe fn str_arg<'a>(ptr: *const c_char, name: &str) -> anyhow::Result<&'a str> {
+    if ptr.is_null() {
+        return Err(anyhow!("'{}' is NULL", name));
+    }
+    unsafe { CStr::from_ptr(ptr) }
+        .to_str()
+        .map_err(|_| anyhow!("'{}' is not valid utf-8", name))
+}
+
+unsafe fn reactor_arg<'a>(reactor: *mut PyboxReactor) -> anyhow::Result<&'a mut PyboxReactor> {
+    let reactor = unsafe { reactor.as_mut() }.ok_or_else(|| anyhow!("'reactor' is NULL"))?;
+    if reactor.busy {
+        return Err(anyhow!(
+            "The reactor is busy, it can not be used from its own handlers"
+        ));
+    }
+    Ok(reactor)
+}
+
+/// 标记 reactor 正在调用 guest
+fn with_busy<T>(
+    reactor: &mut PyboxReactor,
+    f: impl FnOnce(&mut Reactor) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    reactor.busy = true;
+    let result = catch_unwind(AssertUnwindSafe(|| f(&mut reactor.reactor)));
+    reactor.busy = false;
+    result.unwrap_or_else(|_| Err(anyhow!("pybox panicked")))
+}
+
+fn nul_terminated(text: &str) -> Vec<u8> {
+    let mut data = text.as_bytes().to_vec();
+    data.push(0);
+    data
+}
+
+/// 最近一次失败的错误信息，没有时返回 NULL；指针在本线程下一次调用 pybox 函数前有效
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_last_error() -> *const c_char {
+    LAST_ERROR.with_borrow(|error| error.as_ref().map_or(std::ptr::null(), |e| e.as_ptr()))
+}
+
+/// 加载 wasm 文件创建 reactor，options 可以为 NULL；同一个文件只编译一次
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_reactor_new(
+    wasm_path: *const c_char,
+    options: *const PyboxOptions,
+) -> *mut PyboxReactor {
+    ffi_call(std::ptr::null_mut(), || {
+        let wasm_path = unsafe { str_arg(wasm_path, "wasm_path") }?;
+        let (max_memory, timeout_ms) = unsafe { options.as_ref() }
+            .map_or((0, 0), |options| (options.max_memory, options.timeout_ms));
+        let options = ReactorOptions {
+            max_memory: Some(max_memory as usize).filter(|size| *size > 0),
+            timeout: Some(Duration::from_millis(timeout_ms)).filter(|timeout| !timeout.is_zero()),
+            ..ReactorOptions::default()
+        };
+        let reactor = Reactor::new(wasm_path, &options)?;
+        Ok(Box::into_raw(Box::new(PyboxReactor {
+            reactor,
+            busy: false,
+        })))
+    })
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_reactor_free(reactor: *mut PyboxReactor) {
+    if !reactor.is_null() {
+        drop(unsafe { Box::from_raw(reactor) });
+    }
+}
+
+/// 注册 handler，guest 以 pybox_ioctl_host(handle, data) 调用；
+/// name 不为 NULL 时 guest 可以用 pybox.resolve(name) 查询 handle。
+/// user_data 原样传给 func，需要在 reactor 释放或 handler 注销前保持有效
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_register_handler(
+    reactor: *mut PyboxReactor,
+    handle: u32,
+    func: Option<PyboxHandlerFn>,
+    user_data: *mut c_void,
+    name: *const c_char,
+) -> i32 {
+    status(|| {
+        let reactor = unsafe { reactor_arg(reactor) }?;
+        let func = func.ok_or_else(|| anyhow!("'func' is NULL"))?;
+        let name = match name.is_null() {
+            true => None,
+            false => Some(unsafe { str_arg(name, "name") }?),
+        };
+        reactor
+            .reactor
+            .register_handler(handle, CHandler { func, user_data }, name)
+    })
+}
+
+/// 注销 handler，返回 1 表示已注销，0 表示没有该 handler
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_unregister_handler(reactor: *mut PyboxReactor, handle: u32) -> i32 {
+    ffi_call(-1, || {
+        let reactor = unsafe { reactor_arg(reactor) }?;
+        Ok(reactor.reactor.unregister_handler(handle) as i32)
+    })
+}
+
+/// 在 handler 中追加响应数据
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_response_write(
+    response: *mut PyboxResponse,
+    data: *const u8,
+    len: usize,
+) -> i32 {
+    status(|| {
+        let response = unsafe { response.as_mut() }.ok_or_else(|| anyhow!("'response' is NULL"))?;
+        if len > 0 {
+            if data.is_null() {
+                return Err(anyhow!("'data' is NULL"));
+            }
+            response
+                .data
+                .extend_from_slice(unsafe { std::slice::from_raw_parts(data, len) });
+        }
+        Ok(())
+    })
+}
+
+/// 设置 exec 实时输出的回调，func 为 NULL 时取消；旧版 guest 不支持实时输出
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_set_output_callback(
+    reactor: *mut PyboxReactor,
+    func: Option<PyboxOutputFn>,
+    user_data: *mut c_void,
+) -> i32 {
+    status(|| {
+        let reactor = unsafe { reactor_arg(reactor) }?;
+        reactor.reactor.set_output(
+            func.map(|func| Box::new(COutput { func, user_data }) as Box<dyn OutputSink>),
+        );
+        Ok(())
+    })
+}
+
+/// 创建空的局部环境，from_env_id 不为 NULL 时浅拷贝该环境
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_init_local(
+    reactor: *mut PyboxReactor,
+    env_id: *const c_char,
+    from_env_id: *const c_char,
+) -> i32 {
+    status(|| {
+        let reactor = unsafe { reactor_arg(reactor) }?;
+        let env_id = unsafe { str_arg(env_id, "env_id") }?;
+        let from = match from_env_id.is_null() {
+            true => None,
+            false => Some(unsafe { str_arg(from_env_id, "from_env_id") }?),
+        };
+        with_busy(reactor, |reactor| match from {
+            Some(from) => reactor.init_local_from(env_id, from).map(drop),
+            None => reactor.init_local(env_id).map(drop),
+        })
+    })
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_del_local(reactor: *mut PyboxReactor, env_id: *const c_char) -> i32 {
+    status(|| {
+        let reactor = unsafe { reactor_arg(reactor) }?;
+        let env_id = unsafe { str_arg(env_id, "env_id") }?;
+        if !with_busy(reactor, |reactor| reactor.del_local(env_id))? {
+            return Err(anyhow!("Environment '{}' not found", env_id));
+        }
+        Ok(())
+    })
+}
+
+/// 在局部环境中执行代码，timeout_ms 为 0 时使用创建时的超时。
+/// 代码抛出的异常记录在结果中，超时也返回结果（timed_out 为 1），
+/// 之后 reactor 的状态不可信，应恢复快照或重新创建
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_exec(
+    reactor: *mut PyboxReactor,
+    env_id: *const c_char,
+    code: *const c_char,
+    timeout_ms: u64,
+) -> *mut PyboxExecResult {
+    ffi_call(std::ptr::null_mut(), || {
+        let reactor = unsafe { reactor_arg(reactor) }?;
+        let env_id = unsafe { str_arg(env_id, "env_id") }?;
+        let code = unsafe { str_arg(code, "code") }?;
+        let timeout = Some(Duration::from_millis(timeout_ms)).filter(|t| !t.is_zero());
+        let outcome = with_busy(reactor, |reactor| reactor.exec(env_id, code, timeout))?;
+        let exception = outcome
+            .exception
+            .as_ref()
+            .map(|exception| nul_terminated(&exception.to_string()));
+        Ok(Box::into_raw(Box::new(PyboxExecResult {
+            stdout: nul_terminated(&outcome.stdout),
+            stderr: nul_terminated(&outcome.stderr),
+            result_repr: outcome.result_repr.as_deref().map(nul_terminated),
+            exception,
+            json: nul_terminated(&outcome.to_json().to_string()),
+            truncated: outcome.truncated,
+            timed_out: outcome.timed_out,
+        })))
+    })
+}
+
+/// 返回 NUL 结尾的字段，len 不为 NULL 时写入不含 NUL 的长度
+unsafe fn result_field(field: Option<&[u8]>, len: *mut usize) -> *const c_char {
+    let Some(field) = field else {
+        return std::ptr::null();
+    };
+    if let Some(len) = unsafe { len.as_mut() } {
+        *len = field.len() - 1;
+    }
+    field.as_ptr() as *const c_char
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_exec_result_stdout(
+    result: *const PyboxExecResult,
+    len: *mut usize,
+) -> *const c_char {
+    let result = unsafe { result.as_ref() };
+    unsafe { result_field(result.map(|r| r.stdout.as_slice()), len) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_exec_result_stderr(
+    result: *const PyboxExecResult,
+    len: *mut usize,
+) -> *const c_char {
+    let result = unsafe { result.as_ref() };
+    unsafe { result_field(result.map(|r| r.stderr.as_slice()), len) }
+}
+
+/// 末尾表达式的 repr，没有时返回 NULL
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_exec_result_repr(
+    result: *const PyboxExecResult,
+    len: *mut usize,
+) -> *const c_char {
+    let result = unsafe { result.as_ref() };
+    unsafe { result_field(result.and_then(|r| r.result_repr.as_deref()), len) }
+}
+
+/// 未捕获的异常 `{"type", "message", "traceback"}` 的 json，没有时返回 NULL
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_exec_result_exception(
+    result: *const PyboxExecResult,
+    len: *mut usize,
+) -> *const c_char {
+    let result = unsafe { result.as_ref() };
+    unsafe { result_field(result.and_then(|r| r.exception.as_deref()), len) }
+}
+
+/// 完整结果的 json，包括 artifacts
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_exec_result_json(
+    result: *const PyboxExecResult,
+    len: *mut usize,
+) -> *const c_char {
+    let result = unsafe { result.as_ref() };
+    unsafe { result_field(result.map(|r| r.json.as_slice()), len) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_exec_result_timed_out(result: *const PyboxExecResult) -> i32 {
+    unsafe { result.as_ref() }.is_some_and(|r| r.timed_out) as i32
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_exec_result_truncated(result: *const PyboxExecResult) -> i32 {
+    unsafe { result.as_ref() }.is_some_and(|r| r.truncated) as i32
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_exec_result_free(result: *mut PyboxExecResult) {
+    if !result.is_null() {
+        drop(unsafe { Box::from_raw(result) });
+    }
+}
+
+/// 以 json 值创建变量
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_assign(
+    reactor: *mut PyboxReactor,
+    env_id: *const c_char,
+    name: *const c_char,
+    value_json: *const c_char,
+) -> i32 {
+    status(|| {
+        let reactor = unsafe { reactor_arg(reactor) }?;
+        let env_id = unsafe { str_arg(env_id, "env_id") }?;
+        let name = unsafe { str_arg(name, "name") }?;
+        let value = serde_json::from_str(unsafe { str_arg(value_json, "value_json") }?)
+            .map_err(|e| anyhow!("Invalid value_json: {}", e))?;
+        with_busy(reactor, |reactor| reactor.assign(env_id, name, &value))
+    })
+}
+
+/// 读取变量的 json 值，由 pybox_string_free 释放
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_retrieve(
+    reactor: *mut PyboxReactor,
+    env_id: *const c_char,
+    name: *const c_char,
+) -> *mut c_char {
+    ffi_call(std::ptr::null_mut(), || {
+        let reactor = unsafe { reactor_arg(reactor) }?;
+        let env_id = unsafe { str_arg(env_id, "env_id") }?;
+        let name = unsafe { str_arg(name, "name") }?;
+        let value = with_busy(reactor, |reactor| reactor.retrieve(env_id, name))?;
+        Ok(CString::new(value.to_string())?.into_raw())
+    })
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(unsafe { CString::from_raw(string) });
+    }
+}
+
+/// 保存 reactor 当前的内存和全局变量
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_snapshot(reactor: *mut PyboxReactor) -> *mut PyboxSnapshot {
+    ffi_call(std::ptr::null_mut(), || {
+        let reactor = unsafe { reactor_arg(reactor) }?;
+        let snapshot = reactor.reactor.snapshot();
+        Ok(Box::into_raw(Box::new(PyboxSnapshot { snapshot })))
+    })
+}
+
+/// 恢复到快照时的状态，快照必须来自同一个 wasm 文件的 reactor
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_restore(
+    reactor: *mut PyboxReactor,
+    snapshot: *const PyboxSnapshot,
+) -> i32 {
+    status(|| {
+        let reactor = unsafe { reactor_arg(reactor) }?;
+        let snapshot = unsafe { snapshot.as_ref() }.ok_or_else(|| anyhow!("'snapshot' is NULL"))?;
+        reactor.reactor.restore(&snapshot.snapshot)
+    })
+}
+
+/// 快照的内存大小（字节）
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_snapshot_size(snapshot: *const PyboxSnapshot) -> usize {
+    unsafe { snapshot.as_ref() }.map_or(0, |snapshot| snapshot.snapshot.size())
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pybox_snapshot_free(snapshot: *mut PyboxSnapshot) {
+    if !snapshot.is_null() {
+        drop(unsafe { Box::from_raw(snapshot) });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn last_error() -> String {
+        let error = pybox_last_error();
+        assert!(!error.is_null());
+        unsafe { CStr::from_ptr(error) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_errors() {
+        let reactor = unsafe { pybox_reactor_new(std::ptr::null(), std::ptr::null()) };
+        assert!(reactor.is_null());
+        assert_eq!(last_error(), "'wasm_path' is NULL");
+
+        let path = CString::new("/nonexistent/pybox.wasm").unwrap();
+        let reactor = unsafe { pybox_reactor_new(path.as_ptr(), std::ptr::null()) };
+        assert!(reactor.is_null());
+        assert!(last_error().starts_with("Failed to load /nonexistent/pybox.wasm"));
+
+        let env_id = CString::new("env").unwrap();
+        let result =
+            unsafe { pybox_init_local(std::ptr::null_mut(), env_id.as_ptr(), std::ptr::null()) };
+        assert_eq!(result, -1);
+        assert_eq!(last_error(), "'reactor' is NULL");
+    }
+
+    #[test]
+    fn test_response_write() {
+        let mut response = PyboxResponse { data: Vec::new() };
+        let data = b"hello";
+        assert_eq!(
+            unsafe { pybox_response_write(&mut response, data.as_ptr(), 5) },
+            0
+        );
+        assert_eq!(
+            unsafe { pybox_response_write(&mut response, std::ptr::null(), 0) },
+            0
+        );
+        assert_eq!(
+            unsafe { pybox_response_write(&mut response, std::ptr::null(), 1) },
+            -1
+        );
+        assert_eq!(response.data, b"hello");
+    }
+}
@@ -0,0 +1,130 @@
+//! backend 执行 reactor wasm 的运行时
+//!
+//! Reactor 只通过 [`Backend`] 调用导出、读写 guest 内存和保存快照，
+//! guest 的 ioctl 请求由各运行时的导入转交给 [`Host`]
+
+#[cfg(feature = "wasmer")]
+mod wasmer;
+#[cfg(feature = "wasmtime")]
+mod wasmtime;
+
+#[cfg(not(any(feature = "wasmtime", feature = "wasmer")))]
+compile_error!("pybox-host needs the wasmtime or the wasmer feature");
+
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Instant;
+
+use crate::ReactorOptions;
+use crate::handler::Host;
+
+/// 执行 reactor 的运行时，在创建 reactor 时选择
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Runtime {
+    /// 支持超时、中断、内存上限和目录挂载
+    #[cfg(feature = "wasmtime")]
+    Wasmtime,
+    /// wasmtime 的 Pulley 解释器，用于禁止生成可执行内存（JIT）的平台，执行速度慢几十倍；
+    /// 解释器不能在 host 调用中重入 guest：handler 的响应暂存在 host 由 guest 拉取，中断直接终止执行
+    #[cfg(feature = "pulley")]
+    Pulley,
+    /// 用于无法使用 wasmtime 的平台；不支持超时、中断、内存上限和目录挂载
+    #[cfg(feature = "wasmer")]
+    Wasmer,
+}
+
+impl Default for Runtime {
+    #[cfg(feature = "wasmtime")]
+    fn default() -> Self {
+        Runtime::Wasmtime
+    }
+
+    #[cfg(not(feature = "wasmtime"))]
+    fn default() -> Self {
+        Runtime::Wasmer
+    }
+}
+
+/// 超时时 guest 执行以该错误终止
+#[derive(Debug)]
+pub(crate) struct TimedOut;
+
+impl std::fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "execution timed out")
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// 中断请求无法交给 guest 处理时 guest 执行以该错误终止
+#[derive(Debug)]
+pub(crate) struct Interrupted;
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "execution interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// 快照中可变全局变量的值，浮点数保存位模式
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Global {
+    I32(i32),
+    I64(i64),
+    F32(u32),
+    F64(u64),
+}
+
+/// 线性内存和导出的可变全局变量
+pub(crate) type State = (Vec<u8>, Vec<(String, Global)>);
+
+/// 一个实例化的 reactor
+pub(crate) trait Backend: Send {
+    fn host(&mut self) -> &mut Host;
+
+    fn has_export(&mut self, name: &str) -> bool;
+
+    /// 调用参数和返回值都是 i32 的导出，返回第一个返回值
+    fn call(&mut self, name: &str, args: &[i32]) -> anyhow::Result<Option<i32>>;
+
+    fn read(&mut self, ptr: u32, data: &mut [u8]) -> anyhow::Result<()>;
+
+    fn write(&mut self, ptr: u32, data: &[u8]) -> anyhow::Result<()>;
+
+    /// 设置之后调用的截止时间，到期时调用以 TimedOut 终止；同时开始新一次执行的中断处理
+    fn set_deadline(&mut self, deadline: Option<Instant>) -> anyhow::Result<()>;
+
+    fn save(&mut self) -> anyhow::Result<State>;
+
+    /// 恢复 save 的结果，线性内存多出的部分清零
+    fn restore(&mut self, memory: &[u8], globals: &[(String, Global)]) -> anyhow::Result<()>;
+}
+
+/// 用选择的运行时加载 wasm 并实例化，interrupt 被设置时中断正在执行的代码
+pub(crate) fn instantiate(
+    wasm_path: &Path,
+    options: &ReactorOptions,
+    interrupt: &Arc<AtomicBool>,
+) -> anyhow::Result<Box<dyn Backend>> {
+    match options.runtime {
+        #[cfg(feature = "wasmtime")]
+        Runtime::Wasmtime => Ok(Box::new(self::wasmtime::WasmtimeBackend::new(
+            wasm_path, options, interrupt,
+        )?)),
+        #[cfg(feature = "pulley")]
+        Runtime::Pulley => Ok(Box::new(self::wasmtime::WasmtimeBackend::new(
+            wasm_path, options, interrupt,
+        )?)),
+        #[cfg(feature = "wasmer")]
+        Runtime::Wasmer => {
+            let _ = interrupt;
+            Ok(Box::new(self::wasmer::WasmerBackend::new(
+                wasm_path, options,
+            )?))
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! wasmer.rs 启用 wasmer feature 时可选的运行时
+//!
+//! 用于无法使用 wasmtime 的平台，WASI 由 wasmer-wasix 提供；
+//! 没有等价于 epoch 中断的机制，超时、中断、内存上限和目录挂载都不支持
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::Instant;
+
+use anyhow::{anyhow, bail};
+use wasmer::{
+    Engine, Extern, Function, FunctionEnv, FunctionEnvMut, Instance, Memory, Module, Mutability,
+    Pages, Store, TypedFunction, Value,
+};
+use wasmer_wasix::{WasiEnv, WasiFunctionEnv};
+
+use super::{Backend, Global, State};
+use crate::ReactorOptions;
+use crate::handler::Host;
+
+type WasmPtr = u32;
+type WasmSize = u32;
+
+static ENGINE: LazyLock<Engine> = LazyLock::new(Engine::default);
+
+/// 按路径缓存的编译结果，同一个 wasm 文件的 reactor 共享模块
+static MODULES: LazyLock<Mutex<HashMap<PathBuf, Module>>> = LazyLock::new(Mutex::default);
+
+fn load_module(path: &Path) -> anyhow::Result<Module> {
+    let mut modules = MODULES.lock().unwrap();
+    if let Some(module) = modules.get(path) {
+        return Ok(module.clone());
+    }
+    let module = Module::from_file(&*ENGINE, path)
+        .map_err(|e| anyhow!("Failed to load {}: {}", path.display(), e))?;
+    modules.insert(path.to_path_buf(), module.clone());
+    Ok(module)
+}
+
+/// ioctl 导入的环境，memory 和 alloc_mem 在实例化之后设置
+struct IoctlEnv {
+    memory: Option<Memory>,
+    alloc_mem: Option<TypedFunction<WasmSize, WasmPtr>>,
+    host: Host,
+}
+
+pub(crate) struct WasmerBackend {
+    store: Store,
+    instance: Instance,
+    memory: Memory,
+    env: FunctionEnv<IoctlEnv>,
+    /// wasmer-wasix 的系统调用需要 tokio 运行时，与 store 同生命周期
+    runtime: tokio::runtime::Runtime,
+    _wasi: WasiFunctionEnv,
+}
+
+impl WasmerBackend {
+    pub fn new(wasm_path: &Path, options: &ReactorOptions) -> anyhow::Result<Self> {
+        if options.max_memory.is_some() || !options.preopens.is_empty() || options.inherit_stdin {
+            bail!(
+                "The wasmer runtime does not support memory limits, preopened directories or stdin"
+            );
+        }
+        let module = load_module(wasm_path)?;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let _guard = runtime.enter();
+
+        let mut store = Store::new(ENGINE.clone());
+        let mut wasi = WasiEnv::builder("pybox").finalize(&mut store)?;
+        let mut imports = wasi.import_object(&mut store, &module)?;
+        let env = FunctionEnv::new(
+            &mut store,
+            IoctlEnv {
+                memory: None,
+                alloc_mem: None,
+                host: Host::default(),
+            },
+        );
+        imports.define(
+            "env",
+            "pybox_ioctl_host_req_impl",
+            Function::new_typed_with_env(&mut store, &env, ioctl),
+        );
+        let instance = Instance::new(&mut store, &module, &imports)?;
+        wasi.initialize(&mut store, instance.clone())?;
+        if let Ok(initialize) = instance.exports.get_function("_initialize") {
+            initialize.call(&mut store, &[])?;
+        }
+        let memory = instance
+            .exports
+            .get_memory("memory")
+            .map_err(|_| anyhow!("The reactor does not export its memory"))?
+            .clone();
+        let alloc_mem = instance
+            .exports
+            .get_typed_function(&store, "pybox_alloc_mem")
+            .ok();
+        let state = env.as_mut(&mut store);
+        state.memory = Some(memory.clone());
+        state.alloc_mem = alloc_mem;
+        drop(_guard);
+        Ok(Self {
+            store,
+            instance,
+            memory,
+            env,
+            runtime,
+            _wasi: wasi,
+        })
+    }
+}
+
+impl Backend for WasmerBackend {
+    fn host(&mut self) -> &mut Host {
+        &mut self.env.as_mut(&mut self.store).host
+    }
+
+    fn has_export(&mut self, name: &str) -> bool {
+        self.instance.exports.get_function(name).is_ok()
+    }
+
+    fn call(&mut self, name: &str, args: &[i32]) -> anyhow::Result<Option<i32>> {
+        let func = self
+            .instance
+            .exports
+            .get_function(name)
+            .map_err(|_| anyhow!("The reactor does not export {}", name))?;
+        let args: Vec<Value> = args.iter().map(|arg| Value::I32(*arg)).collect();
+        let _guard = self.runtime.enter();
+        let results = func.call(&mut self.store, &args)?;
+        Ok(results.first().and_then(Value::i32))
+    }
+
+    fn read(&mut self, ptr: u32, data: &mut [u8]) -> anyhow::Result<()> {
+        Ok(self.memory.view(&self.store).read(ptr as u64, data)?)
+    }
+
+    fn write(&mut self, ptr: u32, data: &[u8]) -> anyhow::Result<()> {
+        Ok(self.memory.view(&self.store).write(ptr as u64, data)?)
+    }
+
+    fn set_deadline(&mut self, deadline: Option<Instant>) -> anyhow::Result<()> {
+        if deadline.is_some() {
+            bail!("The wasmer runtime does not support timeouts");
+        }
+        Ok(())
+    }
+
+    fn save(&mut self) -> anyhow::Result<State> {
+        let mut globals = Vec::new();
+        for (name, export) in self.instance.exports.iter() {
+            let Extern::Global(global) = export else {
+                continue;
+            };
+            if global.ty(&self.store).mutability != Mutability::Var {
+                continue;
+            }
+            let value = match global.get(&mut self.store) {
+                Value::I32(value) => Global::I32(value),
+                Value::I64(value) => Global::I64(value),
+                Value::F32(value) => Global::F32(value.to_bits()),
+                Value::F64(value) => Global::F64(value.to_bits()),
+                _ => continue,
+            };
+            globals.push((name.clone(), value));
+        }
+        let memory = self.memory.view(&self.store).copy_to_vec()?;
+        Ok((memory, globals))
+    }
+
+    fn restore(&mut self, memory: &[u8], globals: &[(String, Global)]) -> anyhow::Result<()> {
+        // 内存只能增长：先增长到快照的大小，快照之后增长的部分清零
+        let size = self.memory.view(&self.store).data_size() as usize;
+        let missing = memory.len().saturating_sub(size);
+        if missing > 0 {
+            let pages = missing.div_ceil(wasmer::WASM_PAGE_SIZE) as u32;
+            self.memory.grow(&mut self.store, Pages(pages))?;
+        }
+        let view = self.memory.view(&self.store);
+        view.write(0, memory)?;
+        let rest = view.data_size() as usize - memory.len();
+        view.write(memory.len() as u64, &vec![0; rest])?;
+        for (name, value) in globals {
+            let global = self
+                .instance
+                .exports
+                .get_global(name)
+                .map_err(|_| anyhow!("Global '{}' not found", name))?;
+            let value = match *value {
+                Global::I32(value) => Value::I32(value),
+                Global::I64(value) => Value::I64(value),
+                Global::F32(bits) => Value::F32(f32::from_bits(bits)),
+                Global::F64(bits) => Value::F64(f64::from_bits(bits)),
+            };
+            global.set(&mut self.store, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// guest 的 ioctl 请求交给 Host 处理，请求和响应的 packet 与 wasmtime 运行时相同
+fn ioctl(
+    mut env: FunctionEnvMut<IoctlEnv>,
+    handle: u32,
+    req_ptr: WasmPtr,
+    resp_ptr: WasmPtr,
+) -> i32 {
+    let Some(request) = read_request(&env, req_ptr) else {
+        return -1;
+    };
+    let Some(response) = env.data_mut().host.ioctl(handle, &request) else {
+        return -1;
+    };
+    write_response(&mut env, resp_ptr, &response).unwrap_or(-1)
+}
+
+/// 读取 guest 的 ioctl 请求 packet `{buf, buf_len}` 指向的数据
+fn read_request(env: &FunctionEnvMut<IoctlEnv>, req_ptr: WasmPtr) -> Option<Vec<u8>> {
+    let view = env.data().memory.as_ref()?.view(env);
+    let mut packet = [0; 8];
+    view.read(req_ptr as u64, &mut packet).ok()?;
+    let buf = u32::from_le_bytes(packet[..4].try_into().ok()?);
+    let buf_len = u32::from_le_bytes(packet[4..].try_into().ok()?);
+    let mut data = vec![0; buf_len as usize];
+    view.read(buf as u64, &mut data).ok()?;
+    Some(data)
+}
+
+/// 在 guest 内存中分配响应数据，并把 packet `{buf, buf_len}` 写到 resp_ptr
+fn write_response(
+    env: &mut FunctionEnvMut<IoctlEnv>,
+    resp_ptr: WasmPtr,
+    data: &[u8],
+) -> Option<i32> {
+    let (state, mut store) = env.data_and_store_mut();
+    let memory = state.memory.clone()?;
+    let alloc_mem = state.alloc_mem.clone()?;
+    let buf = alloc_mem.call(&mut store, data.len() as WasmSize).ok()?;
+    if buf == 0 {
+        return Some(-1);
+    }
+    let view = memory.view(&store);
+    view.write(buf as u64, data).ok()?;
+    let mut packet = buf.to_le_bytes().to_vec();
+    packet.extend_from_slice(&(data.len() as WasmSize).to_le_bytes());
+    view.write(resp_ptr as u64, &packet).ok()?;
+    Some(0)
+}
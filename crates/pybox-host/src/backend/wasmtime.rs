@@ -0,0 +1,416 @@
+//! wasmtime.rs 默认的运行时
+//!
+//! 超时和中断由 epoch 中断实现，内存上限由 store 的 limiter 实现；
+//! 启用 pulley feature 时同一个 backend 也以 Pulley 解释器执行
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, anyhow};
+use wasmtime::{
+    Caller, Engine, Func, Instance, Memory, Module, Store, StoreContextMut, StoreLimits,
+    StoreLimitsBuilder, TypedFunc, UpdateDeadline, Val,
+};
+use wasmtime_wasi::WasiCtxBuilder;
+use wasmtime_wasi::preview1::WasiP1Ctx;
+
+use super::{Backend, Global, Interrupted, Runtime, State, TimedOut};
+use crate::ReactorOptions;
+use crate::handler::Host;
+use crate::protocol::RESPONSE_HANDLE;
+
+type WasmPtr = u32;
+type WasmSize = u32;
+
+/// epoch 的间隔，guest 执行期间每隔这段时间检查一次是否超时或中断
+const EPOCH_TICK: Duration = Duration::from_millis(50);
+
+/// 所有 reactor 共享的引擎，epoch 计时线程随第一次使用启动
+static ENGINE: LazyLock<Engine> = LazyLock::new(|| new_engine(None));
+
+/// 以 Pulley 解释器执行的引擎，不生成可执行内存；Pulley 的指针宽度必须与 host 相同
+#[cfg(feature = "pulley")]
+static PULLEY_ENGINE: LazyLock<Engine> = LazyLock::new(|| {
+    let target = match cfg!(target_pointer_width = "64") {
+        true => "pulley64",
+        false => "pulley32",
+    };
+    new_engine(Some(target))
+});
+
+fn new_engine(target: Option<&str>) -> Engine {
+    let mut config = wasmtime::Config::new();
+    // 编译缓存不可用时直接编译
+    let _ = config.cache_config_load_default();
+    config.epoch_interruption(true);
+    if let Some(target) = target {
+        config
+            .target(target)
+            .expect("wasmtime does not support the target");
+    }
+    let engine = Engine::new(&config).expect("Failed to create the wasmtime engine");
+    let ticker = engine.clone();
+    std::thread::Builder::new()
+        .name("pybox-epoch".to_string())
+        .spawn(move || {
+            loop {
+                std::thread::sleep(EPOCH_TICK);
+                ticker.increment_epoch();
+            }
+        })
+        .expect("Failed to start the epoch thread");
+    engine
+}
+
+/// 运行时使用的引擎
+fn engine(runtime: Runtime) -> &'static Engine {
+    match runtime {
+        #[cfg(feature = "pulley")]
+        Runtime::Pulley => &PULLEY_ENGINE,
+        _ => &ENGINE,
+    }
+}
+
+fn is_pulley(runtime: Runtime) -> bool {
+    match runtime {
+        #[cfg(feature = "pulley")]
+        Runtime::Pulley => true,
+        _ => false,
+    }
+}
+
+/// 按运行时和路径缓存的编译结果，同一个 wasm 文件的 reactor 共享模块
+static MODULES: LazyLock<Mutex<HashMap<(Runtime, PathBuf), Module>>> =
+    LazyLock::new(Mutex::default);
+
+fn load_module(runtime: Runtime, path: &Path) -> anyhow::Result<Module> {
+    let mut modules = MODULES.lock().unwrap();
+    let key = (runtime, path.to_path_buf());
+    if let Some(module) = modules.get(&key) {
+        return Ok(module.clone());
+    }
+    let module = Module::from_file(engine(runtime), path)
+        .map_err(|e| anyhow!("Failed to load {}: {}", path.display(), e))?;
+    modules.insert(key, module.clone());
+    Ok(module)
+}
+
+struct HostState {
+    wasi: WasiP1Ctx,
+    limits: StoreLimits,
+    /// 正在执行的 exec 的截止时间
+    deadline: Option<Instant>,
+    interrupt: Arc<AtomicBool>,
+    /// guest 导出的 pybox_interrupt，在 python 代码中抛出 KeyboardInterrupt
+    guest_interrupt: Option<TypedFunc<(), i32>>,
+    /// 本次执行已经交给 guest 中断过
+    interrupted: bool,
+    /// host 调用中能否再调用 guest；Pulley 解释器不支持重入
+    reentrant: bool,
+    /// 不能重入时暂存的非空响应，guest 分配好缓冲区后通过 RESPONSE_HANDLE 拉取
+    pending: Option<Vec<u8>>,
+    host: Host,
+}
+
+pub(crate) struct WasmtimeBackend {
+    store: Store<HostState>,
+    instance: Instance,
+    memory: Memory,
+    /// 按名称缓存的导出函数
+    funcs: HashMap<String, Option<Func>>,
+}
+
+impl WasmtimeBackend {
+    pub fn new(
+        wasm_path: &Path,
+        options: &ReactorOptions,
+        interrupt: &Arc<AtomicBool>,
+    ) -> anyhow::Result<Self> {
+        let engine = engine(options.runtime);
+        let module = load_module(options.runtime, wasm_path)?;
+
+        let mut builder = WasiCtxBuilder::new();
+        builder.inherit_stderr();
+        if options.inherit_stdin {
+            builder.inherit_stdin();
+        }
+        for (guest_path, host_path) in &options.preopens {
+            builder
+                .preopened_dir(
+                    host_path,
+                    guest_path,
+                    wasmtime_wasi::DirPerms::all(),
+                    wasmtime_wasi::FilePerms::all(),
+                )
+                .with_context(|| format!("Failed to preopen {}", host_path.display()))?;
+        }
+        let mut limits = StoreLimitsBuilder::new();
+        if let Some(max_memory) = options.max_memory {
+            limits = limits.memory_size(max_memory);
+        }
+        let mut store = Store::new(
+            engine,
+            HostState {
+                wasi: builder.build_p1(),
+                limits: limits.build(),
+                deadline: None,
+                interrupt: Arc::clone(interrupt),
+                guest_interrupt: None,
+                interrupted: false,
+                reentrant: !is_pulley(options.runtime),
+                pending: None,
+                host: Host::default(),
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        store.set_epoch_deadline(1);
+        store.epoch_deadline_callback(on_epoch);
+
+        let mut linker = wasmtime::Linker::new(engine);
+        wasmtime_wasi::preview1::add_to_linker_sync(&mut linker, |state: &mut HostState| {
+            &mut state.wasi
+        })?;
+        linker.func_wrap("env", "pybox_ioctl_host_req_impl", ioctl)?;
+        let instance = linker.instantiate(&mut store, &module)?;
+        if let Ok(initialize) = instance.get_typed_func::<(), ()>(&mut store, "_initialize") {
+            initialize.call(&mut store, ())?;
+        }
+        store.data_mut().guest_interrupt =
+            instance.get_typed_func(&mut store, "pybox_interrupt").ok();
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow!("The reactor does not export its memory"))?;
+        Ok(Self {
+            store,
+            instance,
+            memory,
+            funcs: HashMap::new(),
+        })
+    }
+
+    fn func(&mut self, name: &str) -> Option<Func> {
+        if let Some(func) = self.funcs.get(name) {
+            return *func;
+        }
+        let func = self.instance.get_func(&mut self.store, name);
+        self.funcs.insert(name.to_string(), func);
+        func
+    }
+}
+
+impl Backend for WasmtimeBackend {
+    fn host(&mut self) -> &mut Host {
+        &mut self.store.data_mut().host
+    }
+
+    fn has_export(&mut self, name: &str) -> bool {
+        self.func(name).is_some()
+    }
+
+    fn call(&mut self, name: &str, args: &[i32]) -> anyhow::Result<Option<i32>> {
+        let func = self
+            .func(name)
+            .ok_or_else(|| anyhow!("The reactor does not export {}", name))?;
+        let args: Vec<Val> = args.iter().map(|arg| Val::I32(*arg)).collect();
+        let mut results = vec![Val::I32(0); func.ty(&self.store).results().len()];
+        func.call(&mut self.store, &args, &mut results)?;
+        Ok(results.first().and_then(Val::i32))
+    }
+
+    fn read(&mut self, ptr: u32, data: &mut [u8]) -> anyhow::Result<()> {
+        Ok(self.memory.read(&self.store, ptr as usize, data)?)
+    }
+
+    fn write(&mut self, ptr: u32, data: &[u8]) -> anyhow::Result<()> {
+        Ok(self.memory.write(&mut self.store, ptr as usize, data)?)
+    }
+
+    fn set_deadline(&mut self, deadline: Option<Instant>) -> anyhow::Result<()> {
+        let state = self.store.data_mut();
+        state.deadline = deadline;
+        state.interrupted = false;
+        Ok(())
+    }
+
+    fn save(&mut self) -> anyhow::Result<State> {
+        let exports: Vec<(String, wasmtime::Global)> = self
+            .instance
+            .exports(&mut self.store)
+            .filter_map(|export| {
+                let name = export.name().to_string();
+                export.into_global().map(|global| (name, global))
+            })
+            .collect();
+        let mut globals = Vec::new();
+        for (name, global) in exports {
+            if global.ty(&self.store).mutability() != wasmtime::Mutability::Var {
+                continue;
+            }
+            let value = match global.get(&mut self.store) {
+                Val::I32(value) => Global::I32(value),
+                Val::I64(value) => Global::I64(value),
+                Val::F32(bits) => Global::F32(bits),
+                Val::F64(bits) => Global::F64(bits),
+                _ => continue,
+            };
+            globals.push((name, value));
+        }
+        Ok((self.memory.data(&self.store).to_vec(), globals))
+    }
+
+    fn restore(&mut self, memory: &[u8], globals: &[(String, Global)]) -> anyhow::Result<()> {
+        let Self {
+            store,
+            instance,
+            memory: guest_memory,
+            ..
+        } = self;
+        // 内存只能增长：先增长到快照的大小，快照之后增长的部分清零
+        let missing = memory.len().saturating_sub(guest_memory.data_size(&*store));
+        if missing > 0 {
+            let page_size = guest_memory.page_size(&*store) as usize;
+            guest_memory.grow(&mut *store, missing.div_ceil(page_size) as u64)?;
+        }
+        let data = guest_memory.data_mut(&mut *store);
+        let (restored, rest) = data.split_at_mut(memory.len());
+        restored.copy_from_slice(memory);
+        rest.fill(0);
+        for (name, value) in globals {
+            let global = instance
+                .get_global(&mut *store, name)
+                .ok_or_else(|| anyhow!("Global '{}' not found", name))?;
+            let value = match *value {
+                Global::I32(value) => Val::I32(value),
+                Global::I64(value) => Val::I64(value),
+                Global::F32(bits) => Val::F32(bits),
+                Global::F64(bits) => Val::F64(bits),
+            };
+            global.set(&mut *store, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// guest 的 ioctl 请求交给 Host 处理
+fn ioctl(
+    mut caller: Caller<'_, HostState>,
+    handle: u32,
+    req_ptr: WasmPtr,
+    resp_ptr: WasmPtr,
+) -> anyhow::Result<i32> {
+    if handle == RESPONSE_HANDLE {
+        return take_response(&mut caller, req_ptr);
+    }
+    // guest 每次请求前都会拉取上一个暂存的响应，剩下的来自中途失败的请求
+    caller.data_mut().pending = None;
+    let Some(request) = read_request(&mut caller, req_ptr) else {
+        return Ok(-1);
+    };
+    let Some(response) = caller.data_mut().host.ioctl(handle, &request) else {
+        return Ok(-1);
+    };
+    write_response(&mut caller, resp_ptr, &response)
+}
+
+/// 读取 guest 的 ioctl 请求 packet `{buf, buf_len}` 指向的数据
+fn read_request(caller: &mut Caller<'_, HostState>, req_ptr: WasmPtr) -> Option<Vec<u8>> {
+    let memory = caller.get_export("memory")?.into_memory()?;
+    let mut packet = [0; 8];
+    memory.read(&*caller, req_ptr as usize, &mut packet).ok()?;
+    let buf = u32::from_le_bytes(packet[..4].try_into().ok()?);
+    let buf_len = u32::from_le_bytes(packet[4..].try_into().ok()?);
+    let mut data = vec![0; buf_len as usize];
+    memory.read(&*caller, buf as usize, &mut data).ok()?;
+    Some(data)
+}
+
+/// 在 guest 内存中分配响应数据，并把 packet `{buf, buf_len}` 写到 resp_ptr
+///
+/// 空响应保持 guest 初始化的 `{NULL, 0}`，不需要分配；
+/// 不能重入 guest 时不调用 pybox_alloc_mem，响应暂存在 host，resp_ptr 中写入 `{NULL, len}`，
+/// 返回值仍表示请求是否成功
+fn write_response(
+    caller: &mut Caller<'_, HostState>,
+    resp_ptr: WasmPtr,
+    data: &[u8],
+) -> anyhow::Result<i32> {
+    if data.is_empty() {
+        return Ok(0);
+    }
+    if !caller.data().reentrant {
+        let Some(memory) = caller.get_export("memory").and_then(|e| e.into_memory()) else {
+            return Ok(-1);
+        };
+        let mut packet = 0u32.to_le_bytes().to_vec();
+        packet.extend_from_slice(&(data.len() as WasmSize).to_le_bytes());
+        memory.write(&mut *caller, resp_ptr as usize, &packet)?;
+        caller.data_mut().pending = Some(data.to_vec());
+        return Ok(0);
+    }
+    let (Some(memory), Some(alloc_mem)) = (
+        caller.get_export("memory").and_then(|e| e.into_memory()),
+        caller
+            .get_export("pybox_alloc_mem")
+            .and_then(|e| e.into_func()),
+    ) else {
+        return Ok(-1);
+    };
+    let alloc_mem = alloc_mem.typed::<WasmSize, WasmPtr>(&*caller)?;
+    let buf = alloc_mem.call(&mut *caller, data.len() as WasmSize)?;
+    if buf == 0 {
+        return Ok(-1);
+    }
+    memory.write(&mut *caller, buf as usize, data)?;
+    let mut packet = buf.to_le_bytes().to_vec();
+    packet.extend_from_slice(&(data.len() as WasmSize).to_le_bytes());
+    memory.write(&mut *caller, resp_ptr as usize, &packet)?;
+    Ok(0)
+}
+
+/// 把暂存的响应写入 guest 分配的缓冲区，请求 packet `{buf, buf_len}` 指向该缓冲区
+fn take_response(caller: &mut Caller<'_, HostState>, req_ptr: WasmPtr) -> anyhow::Result<i32> {
+    let Some(memory) = caller.get_export("memory").and_then(|e| e.into_memory()) else {
+        return Ok(-1);
+    };
+    let mut packet = [0; 8];
+    memory.read(&*caller, req_ptr as usize, &mut packet)?;
+    let buf = u32::from_le_bytes(packet[..4].try_into()?);
+    let buf_len = u32::from_le_bytes(packet[4..].try_into()?);
+    let Some(data) = caller.data_mut().pending.take() else {
+        return Ok(-1);
+    };
+    if data.len() != buf_len as usize {
+        return Ok(-1);
+    }
+    memory.write(&mut *caller, buf as usize, &data)?;
+    Ok(0)
+}
+
+/// epoch 到期时检查超时和中断请求
+///
+/// guest 支持中断时在 python 代码中抛出 KeyboardInterrupt，exec 正常返回；
+/// guest 不支持中断或本次执行已经中断过时终止执行
+fn on_epoch(mut ctx: StoreContextMut<'_, HostState>) -> anyhow::Result<UpdateDeadline> {
+    let state = ctx.data_mut();
+    if state
+        .deadline
+        .is_some_and(|deadline| Instant::now() >= deadline)
+    {
+        return Err(TimedOut.into());
+    }
+    if !state.interrupt.swap(false, Ordering::SeqCst) {
+        return Ok(UpdateDeadline::Continue(1));
+    }
+    let first = !std::mem::replace(&mut state.interrupted, true);
+    if first
+        && state.reentrant
+        && let Some(guest_interrupt) = state.guest_interrupt.clone()
+        && let Ok(0) = guest_interrupt.call(&mut ctx, ())
+    {
+        return Ok(UpdateDeadline::Continue(1));
+    }
+    Err(Interrupted.into())
+}
@@ -0,0 +1,73 @@
+//! handler.rs guest 调用 host 的扩展点：ioctl handler 和实时输出
+//!
+//! 两者都在调用 guest 的线程上同步执行，期间不能再使用同一个 reactor
+
+use std::collections::HashMap;
+
+use crate::protocol::{OUTPUT_HANDLE, RESOLVE_HANDLE};
+
+/// guest 以 `pybox.pybox_ioctl_host(handle, data)` 调用的 handler
+///
+/// 闭包 `FnMut(&[u8]) -> Option<Vec<u8>>` 自动实现该 trait
+pub trait Handler: Send {
+    /// 处理一次请求，返回 None 时 guest 的调用失败
+    fn call(&mut self, request: &[u8]) -> Option<Vec<u8>>;
+}
+
+impl<F> Handler for F
+where
+    F: FnMut(&[u8]) -> Option<Vec<u8>> + Send,
+{
+    fn call(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        self(request)
+    }
+}
+
+/// exec 的实时输出，stream 为 "stdout" 或 "stderr"
+///
+/// 闭包 `FnMut(&str, &str)` 自动实现该 trait；旧版 guest 不支持实时输出
+pub trait OutputSink: Send {
+    fn write(&mut self, stream: &str, text: &str);
+}
+
+impl<F> OutputSink for F
+where
+    F: FnMut(&str, &str) + Send,
+{
+    fn write(&mut self, stream: &str, text: &str) {
+        self(stream, text)
+    }
+}
+
+/// host 一侧与运行时无关的状态：handler、名称和实时输出
+#[derive(Default)]
+pub(crate) struct Host {
+    pub handlers: HashMap<u32, Box<dyn Handler>>,
+    /// handler 名称 -> handle，供 guest 的 pybox.resolve(name) 使用
+    pub names: HashMap<String, u32>,
+    pub output: Option<Box<dyn OutputSink>>,
+}
+
+impl Host {
+    /// guest 的 ioctl 请求：用户 handle 交给注册的 handler，系统 handle 中只支持名称解析和实时输出；
+    /// 返回 None 时 guest 的调用失败
+    pub fn ioctl(&mut self, handle: u32, request: &[u8]) -> Option<Vec<u8>> {
+        match handle {
+            RESOLVE_HANDLE => {
+                let name = String::from_utf8_lossy(request);
+                let handle = self.names.get(name.as_ref())?;
+                Some(handle.to_string().into_bytes())
+            }
+            OUTPUT_HANDLE => {
+                let request = String::from_utf8_lossy(request);
+                if let (Some(output), Some((stream, text))) =
+                    (&mut self.output, request.split_once(':'))
+                {
+                    output.write(stream, text);
+                }
+                Some(Vec::new())
+            }
+            handle => self.handlers.get_mut(&handle)?.call(request),
+        }
+    }
+}
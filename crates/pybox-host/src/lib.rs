@@ -0,0 +1,38 @@
+//! pybox-host 在 wasmtime 中运行 pybox reactor，不依赖 pyo3，供 rust 程序直接使用沙箱
+//!
+//! 启用 wasmer feature 后可以用 [`ReactorOptions::runtime`] 选择 wasmer 运行时
+//!
+//! pybox-cli、pybox-server、pybox-node 和 pybox-host-ffi 都基于这里的 [`Reactor`]。
+//! pybox-python 还没有迁移：它目前只共用 [`protocol`] 中的 ioctl 约定，exec、快照和 handler 仍由它自己的
+//! wasmtime host 实现；迁移前这里需要先支持 fuel 预算、映射的快照文件、脏页追踪和多个 reactor 共享的引擎
+//!
+//! - [`Reactor`]：一个 reactor 实例，负责局部环境、exec、变量和快照
+//! - [`Env`]：reactor 中的一个局部环境
+//! - [`Handler`]：guest 以 `pybox.pybox_ioctl_host(handle, data)` 调用的 host 扩展
+//! - [`protocol`]：guest 与 host 之间的系统 handle 约定
+//!
+//! ```no_run
+//! use pybox_host::{Reactor, ReactorOptions};
+//!
+//! let mut reactor = Reactor::new("pybox_reactor.wasm", &ReactorOptions::default())?;
+//! reactor.register_handler(
+//!     1,
+//!     |request: &[u8]| Some(request.to_ascii_uppercase()),
+//!     Some("upper"),
+//! )?;
+//! let mut env = reactor.init_local("env")?;
+//! env.assign("name", &serde_json::json!("pybox"))?;
+//! let outcome = env.exec("import pybox\nprint(pybox.pybox_ioctl_host(pybox.resolve('upper'), name.encode()))")?;
+//! print!("{}", outcome.stdout);
+//! # anyhow::Ok(())
+//! ```
+
+mod backend;
+mod handler;
+pub mod protocol;
+mod reactor;
+
+pub use backend::Runtime;
+pub use handler::{Handler, OutputSink};
+pub use protocol::RESERVED_HANDLE_BASE;
+pub use reactor::{COPY_ENV_CODE, Env, ExecOutcome, GuestError, Reactor, ReactorOptions, Snapshot};
@@ -0,0 +1,31 @@
+//! protocol.rs guest 与 host 之间的 ioctl 约定：系统 handle 的编号和用户 handle 的范围
+//!
+//! pybox-host 和 pybox-python 的 host 共用这些定义，guest 一侧见 pybox-reactor 的 codec.rs 和 ioctl.rs；
+//! 每个 host 只实现其中的一部分系统 handle，未实现的 handle 返回失败
+
+/// ioctl 约定的版本，guest 以 pybox_protocol_version 导出，host 加载时检查两者一致
+///
+/// 2：不能重入 guest 的 host 在响应 packet 中写入 `{NULL, len}`，guest 通过 RESPONSE_HANDLE 拉取
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// handle >= SYSTEM_HANDLE_BASE 为 pybox 保留的系统 handle，由 host 原生处理
+/// (guest 端 handle 为 isize，wasm32 下需要保持在 i32 范围内)
+pub const SYSTEM_HANDLE_BASE: u32 = 0x7FFF_0000;
+/// 系统 handle：按名称解析 handler，请求为 utf-8 名称，响应为十进制 handle id
+pub const RESOLVE_HANDLE: u32 = SYSTEM_HANDLE_BASE;
+/// 系统 handle：查询 host 能力，响应为 `key=value` 行
+pub const CAPS_HANDLE: u32 = SYSTEM_HANDLE_BASE + 1;
+/// 系统 handle：拉取流式响应，请求为 `next:<id>` 或 `close:<id>`，空响应表示结束
+pub const STREAM_HANDLE: u32 = SYSTEM_HANDLE_BASE + 2;
+/// 系统 handle：按名称查询环形缓冲区，请求为 utf-8 名称，响应为十进制地址
+pub const RING_HANDLE: u32 = SYSTEM_HANDLE_BASE + 3;
+/// 系统 handle：exec 的实时输出，请求为 `stdout:<text>` 或 `stderr:<text>`，响应为空
+pub const OUTPUT_HANDLE: u32 = SYSTEM_HANDLE_BASE + 4;
+/// 系统 handle：最外层 exec / call 剩余的 fuel 预算，响应为十进制数，不限制时为空
+pub const BUDGET_HANDLE: u32 = SYSTEM_HANDLE_BASE + 5;
+/// 系统 handle：guest 线性内存的上限，响应为十进制字节数，不限制时为空
+pub const MEMORY_HANDLE: u32 = SYSTEM_HANDLE_BASE + 6;
+/// 系统 handle：拉取暂存在 host 的响应，见 `backend::wasmtime` 中不能重入 guest 时的响应处理
+pub const RESPONSE_HANDLE: u32 = SYSTEM_HANDLE_BASE + 7;
+/// 分帧压缩的标记位，用户 handler 只能使用更小的 handle
+pub const RESERVED_HANDLE_BASE: u32 = 0x4000_0000;
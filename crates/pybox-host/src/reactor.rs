@@ -0,0 +1,591 @@
+//! reactor.rs 一个 reactor 实例：局部环境、exec、变量和快照
+//!
+//! guest 的 ioctl 请求在调用 guest 的线程上同步交给注册的 handler；
+//! wasm 由 backend 中选择的运行时执行
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+use serde_json::{Value, json};
+
+use crate::backend::{self, Backend, Global, Interrupted, Runtime, TimedOut};
+use crate::handler::{Handler, OutputSink};
+use crate::protocol::{PROTOCOL_VERSION, RESERVED_HANDLE_BASE};
+
+type WasmPtr = u32;
+
+/// 在新环境中深拷贝从源环境浅拷贝来的变量：模块按引用共享，
+/// 源环境中定义的函数重新绑定到新环境，之后修改任何一个环境都不影响另一个
+pub const COPY_ENV_CODE: &str = r#"
+def __pybox_copy_env(env):
+    import copy, types
+    names = [name for name in env if not (name.startswith("__") and name.endswith("__"))]
+    memo = {}
+    for name in names:
+        value = env[name]
+        if isinstance(value, types.ModuleType):
+            memo[id(value)] = value
+        elif isinstance(value, types.FunctionType) and value.__globals__ is not env:
+            function = types.FunctionType(value.__code__, env, value.__name__, None, value.__closure__)
+            memo[id(value)] = function
+            function.__defaults__ = copy.deepcopy(value.__defaults__, memo)
+            function.__kwdefaults__ = copy.deepcopy(value.__kwdefaults__, memo)
+            function.__qualname__ = value.__qualname__
+    values = copy.deepcopy([env[name] for name in names], memo)
+    for name, value in zip(names, values):
+        env[name] = value
+__pybox_copy_env(globals())
+del __pybox_copy_env
+"#;
+
+/// 创建 reactor 的选项
+#[derive(Clone, Default)]
+pub struct ReactorOptions {
+    /// 执行 wasm 的运行时
+    pub runtime: Runtime,
+    /// guest 线性内存的上限（字节）
+    pub max_memory: Option<usize>,
+    /// exec 的默认超时
+    pub timeout: Option<Duration>,
+    /// 挂载到 guest 的目录 (guest 路径, host 路径)
+    pub preopens: Vec<(String, PathBuf)>,
+    /// guest 可以读取 host 进程的标准输入，wasmer 运行时不支持
+    pub inherit_stdin: bool,
+}
+
+/// guest 拒绝的请求（环境或变量不存在、无法序列化等），与 guest trap 等 host 错误区分
+#[derive(Debug)]
+pub struct GuestError(pub String);
+
+impl std::fmt::Display for GuestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for GuestError {}
+
+/// exec 的结果，字段与 `pybox_exec_report` 的 json 结果对应
+#[derive(Debug, Clone, Default)]
+pub struct ExecOutcome {
+    pub stdout: String,
+    pub stderr: String,
+    /// `{"type", "message", "traceback"}`
+    pub exception: Option<Value>,
+    pub result_repr: Option<String>,
+    /// `pybox.artifact` 附加的数据 `{"name", "mime", "binary", "data"}`
+    pub artifacts: Vec<Value>,
+    /// 输出或 artifact 超过限制而被截断
+    pub truncated: bool,
+    /// 超时后 guest 停在任意位置，之后应恢复快照或重新创建 reactor
+    pub timed_out: bool,
+    /// 中断请求终止了 guest 执行，reactor 的状态同样不可信
+    pub interrupted: bool,
+    /// 输出已经通过 OutputSink 实时发送，旧版 guest 不支持实时输出
+    pub streamed: bool,
+}
+
+impl ExecOutcome {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "stdout": self.stdout,
+            "stderr": self.stderr,
+            "exception": self.exception,
+            "result_repr": self.result_repr,
+            "artifacts": self.artifacts,
+            "truncated": self.truncated,
+            "timed_out": self.timed_out,
+            "interrupted": self.interrupted,
+        })
+    }
+}
+
+/// 内存和可变全局变量的快照，只能恢复到同一个 wasm 文件、同一种运行时的 reactor
+#[derive(Clone)]
+pub struct Snapshot {
+    wasm_path: PathBuf,
+    runtime: Runtime,
+    memory: Vec<u8>,
+    globals: Vec<(String, Global)>,
+}
+
+impl Snapshot {
+    /// 快照的内存大小（字节）
+    pub fn size(&self) -> usize {
+        self.memory.len()
+    }
+}
+
+/// 一个沙箱化的 python 解释器
+///
+/// 同一时间只能在一个线程中使用；handler 和输出回调中不能再调用同一个 reactor
+pub struct Reactor {
+    backend: Box<dyn Backend>,
+    wasm_path: PathBuf,
+    runtime: Runtime,
+    /// exec 的默认超时
+    timeout: Option<Duration>,
+    interrupt: Arc<AtomicBool>,
+}
+
+impl Reactor {
+    /// 加载 wasm 文件创建 reactor，同一个文件只编译一次
+    pub fn new(wasm_path: impl AsRef<Path>, options: &ReactorOptions) -> anyhow::Result<Self> {
+        let wasm_path = wasm_path.as_ref();
+        let interrupt = Arc::new(AtomicBool::new(false));
+        let backend = backend::instantiate(wasm_path, options, &interrupt)?;
+        let mut reactor = Self {
+            backend,
+            wasm_path: wasm_path.to_path_buf(),
+            runtime: options.runtime,
+            timeout: options.timeout,
+            interrupt,
+        };
+        reactor.check_protocol()?;
+        Ok(reactor)
+    }
+
+    /// 执行 wasm 的运行时
+    pub fn runtime(&self) -> Runtime {
+        self.runtime
+    }
+
+    /// 注册 handler，name 不为空时 guest 可以用 pybox.resolve(name) 查询 handle
+    pub fn register_handler(
+        &mut self,
+        handle: u32,
+        handler: impl Handler + 'static,
+        name: Option<&str>,
+    ) -> anyhow::Result<()> {
+        if handle >= RESERVED_HANDLE_BASE {
+            bail!("Handler ID {} is reserved by pybox", handle);
+        }
+        let host = self.backend.host();
+        host.handlers.insert(handle, Box::new(handler));
+        host.names.retain(|_, named| *named != handle);
+        if let Some(name) = name {
+            host.names.insert(name.to_string(), handle);
+        }
+        Ok(())
+    }
+
+    /// 注销 handler，返回是否注册过
+    pub fn unregister_handler(&mut self, handle: u32) -> bool {
+        let host = self.backend.host();
+        host.names.retain(|_, named| *named != handle);
+        host.handlers.remove(&handle).is_some()
+    }
+
+    /// 设置 exec 实时输出的回调，None 时取消
+    pub fn set_output(&mut self, output: Option<Box<dyn OutputSink>>) {
+        self.backend.host().output = output;
+    }
+
+    /// 请求中断正在执行的代码，可以在其他线程或信号处理函数中设置
+    ///
+    /// guest 支持中断时代码中抛出 KeyboardInterrupt，否则 exec 以 interrupted 结束；
+    /// wasmer 运行时不支持中断
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.interrupt)
+    }
+
+    /// 已经存在的局部环境
+    pub fn env(&mut self, id: &str) -> Env<'_> {
+        Env {
+            reactor: self,
+            id: id.to_string(),
+        }
+    }
+
+    /// 创建空的局部环境
+    pub fn init_local(&mut self, id: &str) -> anyhow::Result<Env<'_>> {
+        let id_ptr = self.new_bytes(id.as_bytes())?;
+        let result = self.call_i32("pybox_init_local", &[id_ptr]);
+        self.free(id_ptr)?;
+        if result? != 0 {
+            return Err(GuestError(format!("Failed to create the environment '{}'", id)).into());
+        }
+        Ok(self.env(id))
+    }
+
+    /// 创建局部环境并浅拷贝 from 环境中的变量
+    pub fn init_local_from(&mut self, id: &str, from: &str) -> anyhow::Result<Env<'_>> {
+        let id_ptr = self.new_bytes(id.as_bytes())?;
+        let from_ptr = self.new_bytes(from.as_bytes())?;
+        let result = self.call_i32("pybox_init_local_from", &[id_ptr, from_ptr]);
+        for ptr in [id_ptr, from_ptr] {
+            self.free(ptr)?;
+        }
+        if result? != 0 {
+            return Err(GuestError(format!(
+                "Failed to create the environment '{}' from '{}'",
+                id, from
+            ))
+            .into());
+        }
+        Ok(self.env(id))
+    }
+
+    /// 删除局部环境，返回环境是否存在
+    pub fn del_local(&mut self, id: &str) -> anyhow::Result<bool> {
+        let id_ptr = self.new_bytes(id.as_bytes())?;
+        let result = self.call_i32("pybox_del_local", &[id_ptr]);
+        self.free(id_ptr)?;
+        Ok(result? == 0)
+    }
+
+    /// 执行代码，timeout 为 None 时使用创建时的超时
+    ///
+    /// guest 代码抛出的异常记录在结果中，超时和中断也返回结果，host 错误以 Err 返回；
+    /// 设置了输出回调时实时发送输出
+    pub fn exec(
+        &mut self,
+        id: &str,
+        code: &str,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<ExecOutcome> {
+        let timeout = timeout.or(self.timeout);
+        let id_ptr = self.new_bytes(id.as_bytes())?;
+        let code_ptr = self.new_bytes(code.as_bytes())?;
+        let output_ptr = self.new_ptr()?;
+        let error_ptr = self.new_ptr()?;
+
+        let streamed = self.backend.host().output.is_some()
+            && self.backend.has_export("pybox_set_output_stream");
+        if streamed {
+            self.backend.call("pybox_set_output_stream", &[1])?;
+        }
+        // 执行前的中断请求不作用于本次执行
+        self.interrupt.store(false, Ordering::SeqCst);
+        self.backend
+            .set_deadline(timeout.map(|timeout| Instant::now() + timeout))?;
+        let result = self.call_i32("pybox_exec", &[id_ptr, code_ptr, output_ptr, error_ptr]);
+        self.backend.set_deadline(None)?;
+
+        let result = match result {
+            Err(err) if err.is::<TimedOut>() || err.is::<Interrupted>() => {
+                return Ok(ExecOutcome {
+                    timed_out: err.is::<TimedOut>(),
+                    interrupted: err.is::<Interrupted>(),
+                    ..ExecOutcome::default()
+                });
+            }
+            // guest 内部的 panic、内存分配失败等，wasm 调用栈对用户没有意义
+            result => result.map_err(|err| anyhow!("guest trapped: {}", err.root_cause()))?,
+        };
+        let output = self.take_ptr(output_ptr)?;
+        let error = self.take_ptr(error_ptr)?;
+        for ptr in [id_ptr, code_ptr] {
+            self.free(ptr)?;
+        }
+        if streamed {
+            self.backend.call("pybox_set_output_stream", &[0])?;
+        }
+        if result != 0 {
+            return Err(GuestError(String::from_utf8_lossy(&error).into_owned()).into());
+        }
+
+        let report: Value = match self.read_report()? {
+            Some(report) => serde_json::from_slice(&report)?,
+            // 旧版 guest 合并的输出全部记为 stdout，异常从输出末尾的 traceback 中解析
+            None => {
+                let output = String::from_utf8_lossy(&output);
+                json!({ "stdout": output, "exception": trailing_exception(&output) })
+            }
+        };
+        let text = |key: &str| report[key].as_str().map(str::to_string);
+        Ok(ExecOutcome {
+            stdout: text("stdout").unwrap_or_default(),
+            stderr: text("stderr").unwrap_or_default(),
+            exception: Some(report["exception"].clone()).filter(Value::is_object),
+            result_repr: text("result_repr"),
+            artifacts: report["artifacts"].as_array().cloned().unwrap_or_default(),
+            truncated: report["truncated"].as_bool().unwrap_or(false),
+            timed_out: false,
+            interrupted: false,
+            streamed,
+        })
+    }
+
+    /// 以 json 值创建变量
+    pub fn assign(&mut self, id: &str, name: &str, value: &Value) -> anyhow::Result<()> {
+        let args = [id.as_bytes(), name.as_bytes(), value.to_string().as_bytes()]
+            .into_iter()
+            .map(|data| self.new_bytes(data))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let error_ptr = self.new_ptr()?;
+        let result = self.call_i32("pybox_assign", &[args[0], args[1], args[2], error_ptr]);
+        let error = self.take_ptr(error_ptr)?;
+        for ptr in args {
+            self.free(ptr)?;
+        }
+        if result? != 0 {
+            return Err(GuestError(String::from_utf8_lossy(&error).into_owned()).into());
+        }
+        Ok(())
+    }
+
+    /// 读取变量的 json 值
+    pub fn retrieve(&mut self, id: &str, name: &str) -> anyhow::Result<Value> {
+        // 旧版 guest 没有导出 pybox_retrieve
+        if !self.backend.has_export("pybox_retrieve") {
+            bail!("The reactor does not export pybox_retrieve");
+        }
+        let id_ptr = self.new_bytes(id.as_bytes())?;
+        let name_ptr = self.new_bytes(name.as_bytes())?;
+        let object_ptr = self.new_ptr()?;
+        let error_ptr = self.new_ptr()?;
+        let result = self.call_i32("pybox_retrieve", &[id_ptr, name_ptr, object_ptr, error_ptr]);
+        let object = self.take_ptr(object_ptr)?;
+        let error = self.take_ptr(error_ptr)?;
+        for ptr in [id_ptr, name_ptr] {
+            self.free(ptr)?;
+        }
+        if result? != 0 {
+            return Err(GuestError(String::from_utf8_lossy(&error).into_owned()).into());
+        }
+        Ok(serde_json::from_slice(&object)?)
+    }
+
+    /// 保护变量，guest 代码不能重新赋值或删除
+    pub fn protect(&mut self, id: &str, name: &str) -> anyhow::Result<()> {
+        let id_ptr = self.new_bytes(id.as_bytes())?;
+        let name_ptr = self.new_bytes(name.as_bytes())?;
+        let result = self.call_i32("pybox_local_protect", &[id_ptr, name_ptr]);
+        for ptr in [id_ptr, name_ptr] {
+            self.free(ptr)?;
+        }
+        if result? != 0 {
+            return Err(GuestError(format!(
+                "Failed to protect '{}' in the environment '{}'",
+                name, id
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// 保存 reactor 当前的内存和全局变量
+    pub fn snapshot(&mut self) -> anyhow::Result<Snapshot> {
+        let (memory, globals) = self.backend.save()?;
+        Ok(Snapshot {
+            wasm_path: self.wasm_path.clone(),
+            runtime: self.runtime,
+            memory,
+            globals,
+        })
+    }
+
+    /// 恢复到快照时的状态，快照之后创建的环境和变量全部丢失
+    pub fn restore(&mut self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        if self.wasm_path != snapshot.wasm_path || self.runtime != snapshot.runtime {
+            bail!("The snapshot was taken from another wasm file or runtime");
+        }
+        self.backend.restore(&snapshot.memory, &snapshot.globals)
+    }
+
+    /// 检查 guest 的 ioctl 协议版本与 host 一致
+    ///
+    /// 旧版 guest 没有导出 pybox_protocol_version，只有 Pulley 依赖新协议暂存响应，此时拒绝加载
+    fn check_protocol(&mut self) -> anyhow::Result<()> {
+        if !self.backend.has_export("pybox_protocol_version") {
+            #[cfg(feature = "pulley")]
+            if self.runtime == Runtime::Pulley {
+                bail!(
+                    "The reactor predates ioctl protocol {}, rebuild it to run on Pulley",
+                    PROTOCOL_VERSION
+                );
+            }
+            return Ok(());
+        }
+        let version = self.call_i32("pybox_protocol_version", &[])? as u32;
+        if version != PROTOCOL_VERSION {
+            bail!(
+                "The reactor speaks ioctl protocol {} but the host speaks {}, rebuild the reactor",
+                version,
+                PROTOCOL_VERSION
+            );
+        }
+        Ok(())
+    }
+
+    /// 调用返回 i32 的导出，pybox 的导出以 0 表示成功
+    fn call_i32(&mut self, name: &str, args: &[WasmPtr]) -> anyhow::Result<i32> {
+        let args: Vec<i32> = args.iter().map(|arg| *arg as i32).collect();
+        self.backend
+            .call(name, &args)?
+            .ok_or_else(|| anyhow!("{} returned nothing", name))
+    }
+
+    fn read_report(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        // 旧版 guest 没有导出 pybox_exec_report
+        if !self.backend.has_export("pybox_exec_report") {
+            return Ok(None);
+        }
+        let report_ptr = self.new_ptr()?;
+        let result = self.call_i32("pybox_exec_report", &[report_ptr])?;
+        let report = self.take_ptr(report_ptr)?;
+        Ok(Some(report).filter(|report| result == 0 && !report.is_empty()))
+    }
+
+    fn alloc(&mut self, size: usize) -> anyhow::Result<WasmPtr> {
+        Ok(self.call_i32("pybox_alloc_mem", &[size as WasmPtr])? as WasmPtr)
+    }
+
+    /// 在 guest 内存中创建 pybox_bytes：4 字节长度 + 数据
+    fn new_bytes(&mut self, data: &[u8]) -> anyhow::Result<WasmPtr> {
+        let ptr = self.alloc(4 + data.len())?;
+        let mut bytes = (data.len() as u32).to_le_bytes().to_vec();
+        bytes.extend_from_slice(data);
+        self.backend.write(ptr, &bytes)?;
+        Ok(ptr)
+    }
+
+    /// 分配一个初始化为 NULL 的 *mut pybox_bytes
+    fn new_ptr(&mut self) -> anyhow::Result<WasmPtr> {
+        let ptr = self.alloc(4)?;
+        self.backend.write(ptr, &[0; 4])?;
+        Ok(ptr)
+    }
+
+    /// 读取 *mut pybox_bytes 指向的数据，释放数据和指针本身
+    fn take_ptr(&mut self, ptr_ptr: WasmPtr) -> anyhow::Result<Vec<u8>> {
+        let ptr = self.read_u32(ptr_ptr)?;
+        let mut data = Vec::new();
+        if ptr != 0 {
+            let len = self.read_u32(ptr)?;
+            data = vec![0; len as usize];
+            self.backend.read(ptr + 4, &mut data)?;
+            self.free(ptr)?;
+        }
+        self.free(ptr_ptr)?;
+        Ok(data)
+    }
+
+    fn read_u32(&mut self, ptr: WasmPtr) -> anyhow::Result<u32> {
+        let mut data = [0; 4];
+        self.backend.read(ptr, &mut data)?;
+        Ok(u32::from_le_bytes(data))
+    }
+
+    fn free(&mut self, ptr: WasmPtr) -> anyhow::Result<()> {
+        self.backend.call("pybox_free_mem", &[ptr as i32])?;
+        Ok(())
+    }
+}
+
+/// reactor 中的一个局部环境，方法与 Reactor 上以环境 id 为参数的方法相同
+pub struct Env<'a> {
+    reactor: &'a mut Reactor,
+    id: String,
+}
+
+impl Env<'_> {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// 以创建 reactor 时的超时执行代码
+    pub fn exec(&mut self, code: &str) -> anyhow::Result<ExecOutcome> {
+        self.reactor.exec(&self.id, code, None)
+    }
+
+    pub fn exec_with_timeout(
+        &mut self,
+        code: &str,
+        timeout: Duration,
+    ) -> anyhow::Result<ExecOutcome> {
+        self.reactor.exec(&self.id, code, Some(timeout))
+    }
+
+    pub fn assign(&mut self, name: &str, value: &Value) -> anyhow::Result<()> {
+        self.reactor.assign(&self.id, name, value)
+    }
+
+    pub fn retrieve(&mut self, name: &str) -> anyhow::Result<Value> {
+        self.reactor.retrieve(&self.id, name)
+    }
+
+    pub fn protect(&mut self, name: &str) -> anyhow::Result<()> {
+        self.reactor.protect(&self.id, name)
+    }
+
+    /// 删除该环境
+    pub fn delete(self) -> anyhow::Result<bool> {
+        self.reactor.del_local(&self.id)
+    }
+}
+
+/// 输出以未捕获异常的 traceback 结尾时解析出异常的类型和消息
+fn trailing_exception(output: &str) -> Option<Value> {
+    let start = output.rfind("Traceback (most recent call last):\n")?;
+    let traceback = &output[start..];
+    let last = traceback.trim_end().lines().last()?;
+    let (type_name, message) = last.split_once(": ").unwrap_or((last, ""));
+    let is_name = |name: &str| {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+    };
+    if last.starts_with(' ') || !is_name(type_name) {
+        return None;
+    }
+    Some(json!({ "type": type_name, "message": message, "traceback": traceback }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_trailing_exception() {
+        let output = "hi\nTraceback (most recent call last):\n  File \"<embedded>\", line 1, in <module>\nZeroDivisionError: division by zero\n";
+        let exception = trailing_exception(output).unwrap();
+        assert_eq!(exception["type"], "ZeroDivisionError");
+        assert_eq!(exception["message"], "division by zero");
+        assert!(trailing_exception("hi\n").is_none());
+    }
+
+    /// Pulley 不能重入 guest，handler 的响应暂存在 host 由 guest 拉取
+    #[cfg(feature = "pulley")]
+    #[test]
+    fn test_pulley_handler_response() {
+        let wasm = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../../python/pybox/image/pybox_reactor.wasm");
+        let options = ReactorOptions {
+            runtime: Runtime::Pulley,
+            ..ReactorOptions::default()
+        };
+        let mut reactor = Reactor::new(&wasm, &options).unwrap();
+        reactor
+            .register_handler(
+                1,
+                |request: &[u8]| Some(request.to_ascii_uppercase()),
+                Some("upper"),
+            )
+            .unwrap();
+        let mut env = reactor.init_local("env").unwrap();
+        env.assign("name", &json!("pybox")).unwrap();
+        let outcome = env
+            .exec("import pybox\nok, data = pybox.pybox_ioctl_host(pybox.resolve('upper'), name.encode())\nprint(ok, data)")
+            .unwrap();
+        assert!(outcome.exception.is_none(), "{:?}", outcome.exception);
+        assert_eq!(outcome.stdout, "True b'PYBOX'\n");
+    }
+
+    #[test]
+    fn test_load_error() {
+        let error = Reactor::new("/nonexistent/pybox.wasm", &ReactorOptions::default())
+            .err()
+            .unwrap();
+        assert!(
+            error
+                .to_string()
+                .starts_with("Failed to load /nonexistent/pybox.wasm")
+        );
+    }
+}
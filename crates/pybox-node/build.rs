@@ -0,0 +1,5 @@
+//! 设置 Node addon 的链接参数
+
+fn main() {
+    napi_build::setup();
+}
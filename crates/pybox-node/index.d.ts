@@ -0,0 +1,72 @@
+/// <reference types="node" />
+
+export interface ReactorOptions {
+  /** Maximum guest memory in bytes */
+  maxMemory?: number
+  /** Default execution time limit in milliseconds */
+  timeoutMs?: number
+}
+
+export interface ExecOptions {
+  /** Receives the output while the code runs */
+  onOutput?: (stream: 'stdout' | 'stderr', text: string) => void
+  /** Execution time limit in milliseconds, overrides the reactor default */
+  timeoutMs?: number
+}
+
+export interface ExecException {
+  type: string
+  message: string
+  traceback: string
+}
+
+export interface Artifact {
+  name: string
+  mime: string | null
+  binary: boolean
+  /** Text, or hex encoded data when binary is true */
+  data: string
+}
+
+export interface ExecResult {
+  stdout: string
+  stderr: string
+  /** Uncaught exception of the code */
+  exception: ExecException | null
+  /** repr of a trailing expression */
+  resultRepr: string | null
+  artifacts: Artifact[]
+  /** Output or artifacts were cut at the guest limits */
+  truncated: boolean
+  /** The output was already delivered through onOutput */
+  streamed: boolean
+}
+
+export type Handler = (request: Buffer) => Buffer | string | null
+
+export type JsonValue = null | boolean | number | string | JsonValue[] | { [key: string]: JsonValue }
+
+export class ReactorSnapshot {
+  /** Memory size of the snapshot in bytes */
+  readonly size: number
+}
+
+export class Reactor {
+  constructor (wasmPath: string, options?: ReactorOptions)
+  static fromSnapshot (snapshot: ReactorSnapshot, wasmPath: string, options?: ReactorOptions): Reactor
+
+  registerHandler (handle: number, func: Handler, name?: string): void
+  unregisterHandler (handle: number): boolean
+
+  initLocal (envId: string): boolean
+  initLocalFrom (envId: string, fromEnvId: string): boolean
+  delLocal (envId: string): boolean
+
+  exec (code: string, envId: string, options?: ExecOptions): ExecResult
+  assign (name: string, value: JsonValue, envId: string): void
+  retrieve (name: string, envId: string): JsonValue
+  protect (name: string, envId: string): void
+
+  snapshot (): ReactorSnapshot
+  restore (snapshot: ReactorSnapshot): void
+}
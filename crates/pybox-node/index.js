@@ -0,0 +1,144 @@
+'use strict'
+
+// binding.js is generated by `napi build` and loads the addon for the current platform
+const { NativeReactor, ReactorSnapshot } = require('./binding.js')
+
+// Handles at or above this value are reserved by pybox
+const RESERVED_HANDLE_BASE = 0x40000000
+
+/**
+ * A sandboxed Python interpreter, the JavaScript counterpart of pybox.PyBoxReactor.
+ *
+ * Code runs synchronously on the calling thread. Handlers are plain JavaScript
+ * functions that guest code calls through pybox.pybox_ioctl_host / pybox.resolve.
+ */
+class Reactor {
+  /**
+   * @param {string} wasmPath Path to the reactor WASM file
+   * @param {{maxMemory?: number, timeoutMs?: number}} [options]
+   */
+  constructor (wasmPath, options = {}) {
+    this._native = new NativeReactor(wasmPath, options)
+    this._handlers = new Map()
+    this._busy = false
+    this._dispatch = (handle, request) => {
+      const handler = this._handlers.get(handle)
+      return handler === undefined ? null : handler(request)
+    }
+  }
+
+  /**
+   * Register a handler for ioctl requests
+   *
+   * @param {number} handle Handler ID
+   * @param {(request: Buffer) => Buffer | string | null} func Returns the response,
+   *     null makes the guest call fail
+   * @param {string} [name] Guest code can resolve it with pybox.resolve(name)
+   */
+  registerHandler (handle, func, name) {
+    if (!Number.isInteger(handle) || handle < 0 || handle >= RESERVED_HANDLE_BASE) {
+      throw new RangeError(`Handler ID ${handle} is reserved by pybox`)
+    }
+    if (typeof func !== 'function') {
+      throw new TypeError('A handler must be a function')
+    }
+    this._native.registerHandler(handle, name)
+    this._handlers.set(handle, func)
+  }
+
+  /**
+   * Unregister a handler
+   *
+   * @returns {boolean} Whether the handler was registered
+   */
+  unregisterHandler (handle) {
+    this._native.unregisterHandler(handle)
+    return this._handlers.delete(handle)
+  }
+
+  initLocal (envId) {
+    return this._call(() => this._native.initLocal(envId))
+  }
+
+  initLocalFrom (envId, fromEnvId) {
+    return this._call(() => this._native.initLocalFrom(envId, fromEnvId))
+  }
+
+  delLocal (envId) {
+    return this._call(() => this._native.delLocal(envId))
+  }
+
+  /**
+   * Execute code in an environment
+   *
+   * Exceptions raised by the code are reported in the result, exceptions thrown
+   * by handlers or onOutput interrupt the execution and are rethrown when it ends.
+   *
+   * @param {string} code
+   * @param {string} envId Local environment created with initLocal
+   * @param {{onOutput?: (stream: string, text: string) => void, timeoutMs?: number}} [options]
+   * @returns {ExecResult}
+   */
+  exec (code, envId, options = {}) {
+    return this._call(() =>
+      this._native.exec(code, envId, this._dispatch, options.onOutput, options.timeoutMs)
+    )
+  }
+
+  /** Create a variable from a JSON-compatible value */
+  assign (name, value, envId) {
+    return this._call(() => this._native.assign(envId, name, value))
+  }
+
+  /** Read a variable as a JSON-compatible value */
+  retrieve (name, envId) {
+    return this._call(() => this._native.retrieve(envId, name))
+  }
+
+  /** Protect a variable from being reassigned or deleted by guest code */
+  protect (name, envId) {
+    return this._call(() => this._native.protect(envId, name))
+  }
+
+  /**
+   * Save the memory and globals of the reactor
+   *
+   * @returns {ReactorSnapshot}
+   */
+  snapshot () {
+    return this._call(() => this._native.snapshot())
+  }
+
+  /**
+   * Restore a snapshot taken from a reactor of the same WASM file
+   *
+   * Use it after an execution timed out, the reactor state is not reliable then.
+   */
+  restore (snapshot) {
+    return this._call(() => this._native.restore(snapshot))
+  }
+
+  /**
+   * Create a reactor and restore a snapshot into it
+   */
+  static fromSnapshot (snapshot, wasmPath, options = {}) {
+    const reactor = new Reactor(wasmPath, options)
+    reactor.restore(snapshot)
+    return reactor
+  }
+
+  // The reactor can not be reentered from a handler
+  _call (f) {
+    if (this._busy) {
+      throw new Error('The reactor is busy, it can not be used from its own handlers')
+    }
+    this._busy = true
+    try {
+      return f()
+    } finally {
+      this._busy = false
+    }
+  }
+}
+
+module.exports = { Reactor, ReactorSnapshot }
@@ -0,0 +1,137 @@
+//! guest.rs JS 回调与 pybox-host 的 handler、实时输出之间的转接
+//!
+//! JS handler 不保存在 Rust 中：每次调用 guest 时由 JS 传入一个分发函数
+//! `(handle, request: Buffer) => Buffer | string | null`，只在调用期间登记在 JS 线程的 ACTIVE 中。
+//! napi 的 JsFunction 只能在 JS 线程上使用，不能放进要求 Send 的 handler；
+//! 注册到 reactor 的 handler 只记录 handle，guest 在 JS 线程上同步执行，回调时从 ACTIVE 取出分发函数
+
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use napi::{Env, JsBuffer, JsFunction, JsString, JsUnknown, ValueType};
+use pybox_host::{Handler, OutputSink};
+
+/// 调用 guest 期间的 JS 回调
+#[derive(Clone, Copy)]
+pub struct Callbacks {
+    pub env: Env,
+    pub dispatch: JsFunction,
+    pub on_output: Option<JsFunction>,
+}
+
+/// 一次正在进行的调用
+struct Active {
+    callbacks: Callbacks,
+    /// reactor 的中断请求，回调抛出异常后用它尽快结束 guest 执行
+    interrupt: Arc<AtomicBool>,
+    /// 回调抛出的 JS 异常，调用结束后重新抛出
+    error: Option<napi::Error>,
+}
+
+thread_local! {
+    /// JS 线程上正在进行的调用，handler 中调用其他 reactor 时嵌套
+    static ACTIVE: RefCell<Vec<Active>> = const { RefCell::new(Vec::new()) };
+}
+
+/// 在 JS 线程上登记回调后执行 f，返回 f 的结果和回调抛出的 JS 异常
+pub fn with_active<T>(
+    callbacks: Callbacks,
+    interrupt: Arc<AtomicBool>,
+    f: impl FnOnce() -> T,
+) -> (T, Option<napi::Error>) {
+    ACTIVE.with_borrow_mut(|active| {
+        active.push(Active {
+            callbacks,
+            interrupt,
+            error: None,
+        })
+    });
+    let result = f();
+    let error = ACTIVE
+        .with_borrow_mut(Vec::pop)
+        .and_then(|active| active.error);
+    (result, error)
+}
+
+/// 当前调用的回调；不在调用期间或已经有回调抛出异常时为 None
+fn active_callbacks() -> Option<Callbacks> {
+    ACTIVE.with_borrow(|active| {
+        active
+            .last()
+            .filter(|active| active.error.is_none())
+            .map(|active| active.callbacks)
+    })
+}
+
+/// 注册到 reactor 的 handler，把请求交给当前调用的 JS 分发函数
+pub fn dispatcher(handle: u32) -> impl Handler {
+    move |request: &[u8]| call_dispatch(handle, request)
+}
+
+/// exec 的实时输出交给当前调用的 onOutput
+pub fn output_sink() -> Box<dyn OutputSink> {
+    Box::new(|stream: &str, text: &str| call_on_output(stream, text))
+}
+
+/// 调用 JS 分发函数，返回 None 表示没有该 handler 或 handler 返回了 null
+fn call_dispatch(handle: u32, request: &[u8]) -> Option<Vec<u8>> {
+    let Callbacks { env, dispatch, .. } = active_callbacks()?;
+    let result = (|| -> napi::Result<Option<Vec<u8>>> {
+        let args = [
+            env.create_uint32(handle)?.into_unknown(),
+            env.create_buffer_with_data(request.to_vec())?
+                .into_raw()
+                .into_unknown(),
+        ];
+        js_bytes(dispatch.call(None, &args)?)
+    })();
+    result.unwrap_or_else(throw)
+}
+
+fn call_on_output(stream: &str, text: &str) {
+    let Some(Callbacks {
+        env,
+        on_output: Some(on_output),
+        ..
+    }) = active_callbacks()
+    else {
+        return;
+    };
+    let result = (|| -> napi::Result<()> {
+        let args = [env.create_string(stream)?, env.create_string(text)?];
+        on_output.call(None, &args)?;
+        Ok(())
+    })();
+    result.unwrap_or_else(throw)
+}
+
+/// 记录 JS 异常并请求中断 guest 执行，本次调用中之后的回调不再执行
+fn throw<T: Default>(err: napi::Error) -> T {
+    ACTIVE.with_borrow_mut(|active| {
+        if let Some(active) = active.last_mut() {
+            active.error = Some(err);
+            active.interrupt.store(true, Ordering::SeqCst);
+        }
+    });
+    T::default()
+}
+
+/// handler 的返回值：Buffer 原样返回，字符串按 utf-8 编码，null / undefined 表示失败
+fn js_bytes(value: JsUnknown) -> napi::Result<Option<Vec<u8>>> {
+    match value.get_type()? {
+        ValueType::Null | ValueType::Undefined => Ok(None),
+        ValueType::String => {
+            let value = unsafe { value.cast::<JsString>() };
+            Ok(Some(value.into_utf8()?.as_slice().to_vec()))
+        }
+        _ if value.is_buffer()? => {
+            let value = JsBuffer::try_from(value)?;
+            Ok(Some(value.into_value()?.to_vec()))
+        }
+        _ => Err(napi::Error::new(
+            napi::Status::InvalidArg,
+            "A handler must return a Buffer, a string or null",
+        )),
+    }
+}
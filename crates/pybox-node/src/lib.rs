@@ -0,0 +1,214 @@
+//! pybox-node 以 napi-rs 导出的 Node addon，JS 接口见 index.js / index.d.ts
+//!
+//! NativeReactor 对应 pyo3 的 PyBoxReactor：局部环境、exec、assign / retrieve、保护变量和快照；
+//! reactor 由 pybox-host 实现，handler 由 index.js 中的 Reactor 管理，exec 时通过分发函数调用。
+//! Node 在单线程中执行 JS，exec 同步阻塞调用线程
+
+mod guest;
+
+use std::time::Duration;
+
+use napi::{Env, JsFunction};
+use napi_derive::napi;
+use pybox_host::{GuestError, Reactor, Snapshot};
+use serde_json::Value;
+
+use guest::{Callbacks, with_active};
+
+#[napi(object)]
+pub struct ReactorOptions {
+    /// guest 线性内存的上限（字节）
+    pub max_memory: Option<u32>,
+    /// exec 的默认超时（毫秒）
+    pub timeout_ms: Option<u32>,
+}
+
+/// exec 的结果，字段与 `pybox_exec_report` 的 json 结果对应
+#[napi(object)]
+pub struct ExecResult {
+    pub stdout: String,
+    pub stderr: String,
+    /// `{type, message, traceback}`
+    pub exception: Option<Value>,
+    pub result_repr: Option<String>,
+    /// `pybox.artifact` 附加的数据 `{name, mime, binary, data}`
+    pub artifacts: Vec<Value>,
+    /// 输出或 artifact 超过限制而被截断
+    pub truncated: bool,
+    /// 输出已经通过 onOutput 实时发送，旧版 guest 不支持实时输出
+    pub streamed: bool,
+}
+
+/// 内存和可变全局变量的快照，只能恢复到同一个 wasm 文件的 reactor
+#[napi]
+pub struct ReactorSnapshot {
+    snapshot: Snapshot,
+}
+
+#[napi]
+impl ReactorSnapshot {
+    /// 快照的内存大小（字节）
+    #[napi(getter)]
+    pub fn size(&self) -> u32 {
+        self.snapshot.size() as u32
+    }
+}
+
+#[napi]
+pub struct NativeReactor {
+    reactor: Reactor,
+}
+
+fn to_napi(err: anyhow::Error) -> napi::Error {
+    napi::Error::from_reason(format!("{:#}", err))
+}
+
+/// guest 拒绝的请求返回 false，其他错误抛出
+fn guest_ok<T>(result: anyhow::Result<T>) -> napi::Result<bool> {
+    match result {
+        Ok(_) => Ok(true),
+        Err(err) if err.is::<GuestError>() => Ok(false),
+        Err(err) => Err(to_napi(err)),
+    }
+}
+
+#[napi]
+impl NativeReactor {
+    #[napi(constructor)]
+    pub fn new(wasm_path: String, options: Option<ReactorOptions>) -> napi::Result<Self> {
+        let options = options.unwrap_or(ReactorOptions {
+            max_memory: None,
+            timeout_ms: None,
+        });
+        let reactor = Reactor::new(
+            &wasm_path,
+            &pybox_host::ReactorOptions {
+                max_memory: options.max_memory.map(|max_memory| max_memory as usize),
+                timeout: options
+                    .timeout_ms
+                    .map(|timeout| Duration::from_millis(timeout as u64)),
+                ..pybox_host::ReactorOptions::default()
+            },
+        )
+        .map_err(to_napi)?;
+        Ok(Self { reactor })
+    }
+
+    /// 注册 handle，guest 的请求交给 exec 时传入的分发函数；name 不为空时 guest 可以按名称解析
+    #[napi]
+    pub fn register_handler(&mut self, handle: u32, name: Option<String>) -> napi::Result<()> {
+        self.reactor
+            .register_handler(handle, guest::dispatcher(handle), name.as_deref())
+            .map_err(to_napi)
+    }
+
+    /// 注销 handle，返回是否注册过
+    #[napi]
+    pub fn unregister_handler(&mut self, handle: u32) -> bool {
+        self.reactor.unregister_handler(handle)
+    }
+
+    /// 创建空的局部环境
+    #[napi]
+    pub fn init_local(&mut self, env_id: String) -> napi::Result<bool> {
+        guest_ok(self.reactor.init_local(&env_id).map(drop))
+    }
+
+    /// 浅拷贝已有的局部环境
+    #[napi]
+    pub fn init_local_from(&mut self, env_id: String, from_env_id: String) -> napi::Result<bool> {
+        guest_ok(
+            self.reactor
+                .init_local_from(&env_id, &from_env_id)
+                .map(drop),
+        )
+    }
+
+    #[napi]
+    pub fn del_local(&mut self, env_id: String) -> napi::Result<bool> {
+        self.reactor.del_local(&env_id).map_err(to_napi)
+    }
+
+    /// 执行代码，guest 代码抛出的异常记录在结果中；
+    /// handler 和 onOutput 抛出的 JS 异常中断执行并在 exec 返回时原样抛出
+    #[napi]
+    pub fn exec(
+        &mut self,
+        env: Env,
+        code: String,
+        env_id: String,
+        dispatch: JsFunction,
+        on_output: Option<JsFunction>,
+        timeout_ms: Option<u32>,
+    ) -> napi::Result<ExecResult> {
+        let timeout = timeout_ms.map(|timeout| Duration::from_millis(timeout as u64));
+        self.reactor
+            .set_output(on_output.is_some().then(guest::output_sink));
+        let callbacks = Callbacks {
+            env,
+            dispatch,
+            on_output,
+        };
+        let interrupt = self.reactor.interrupt_handle();
+        let (result, error) = with_active(callbacks, interrupt, || {
+            self.reactor.exec(&env_id, &code, timeout)
+        });
+        self.reactor.set_output(None);
+        if let Some(error) = error {
+            return Err(error);
+        }
+        let outcome = result.map_err(to_napi)?;
+        if outcome.timed_out {
+            return Err(napi::Error::new(
+                napi::Status::Cancelled,
+                "execution timed out",
+            ));
+        }
+        if outcome.interrupted {
+            return Err(napi::Error::new(
+                napi::Status::Cancelled,
+                "execution interrupted",
+            ));
+        }
+        Ok(ExecResult {
+            stdout: outcome.stdout,
+            stderr: outcome.stderr,
+            exception: outcome.exception,
+            result_repr: outcome.result_repr,
+            artifacts: outcome.artifacts,
+            truncated: outcome.truncated,
+            streamed: outcome.streamed,
+        })
+    }
+
+    /// 以 json 值创建变量
+    #[napi]
+    pub fn assign(&mut self, env_id: String, name: String, value: Value) -> napi::Result<()> {
+        self.reactor.assign(&env_id, &name, &value).map_err(to_napi)
+    }
+
+    /// 读取变量的 json 值
+    #[napi]
+    pub fn retrieve(&mut self, env_id: String, name: String) -> napi::Result<Value> {
+        self.reactor.retrieve(&env_id, &name).map_err(to_napi)
+    }
+
+    /// 保护变量，guest 代码不能重新赋值或删除
+    #[napi]
+    pub fn protect(&mut self, env_id: String, name: String) -> napi::Result<()> {
+        self.reactor.protect(&env_id, &name).map_err(to_napi)
+    }
+
+    /// 保存 reactor 当前的内存和全局变量
+    #[napi]
+    pub fn snapshot(&mut self) -> napi::Result<ReactorSnapshot> {
+        let snapshot = self.reactor.snapshot().map_err(to_napi)?;
+        Ok(ReactorSnapshot { snapshot })
+    }
+
+    /// 恢复到快照时的状态，快照之后创建的环境和变量全部丢失
+    #[napi]
+    pub fn restore(&mut self, snapshot: &ReactorSnapshot) -> napi::Result<()> {
+        self.reactor.restore(&snapshot.snapshot).map_err(to_napi)
+    }
+}
@@ -0,0 +1,78 @@
+//! acl.rs 按环境限制可以调用的 handler
+//!
+//! 没有 ACL 的环境可以调用所有 handler；设置后只能调用列出的 handler，按名称或 handle 匹配。
+//! 名称在调用时查询，ACL 可以在 handler 注册之前设置。拒绝的调用不会到达 handler，
+//! guest 收到与 JSON-RPC handler 异常响应相同格式的 PermissionError
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+/// 一个环境允许调用的 handler
+struct Acl {
+    names: HashSet<String>,
+    handles: HashSet<u32>,
+}
+
+/// env id -> ACL，全局环境记为空字符串
+#[derive(Default)]
+pub struct HandlerAcls {
+    envs: Mutex<HashMap<String, Acl>>,
+}
+
+impl HandlerAcls {
+    /// 设置环境允许调用的 handler，替换之前的 ACL
+    pub fn set(&self, env_id: &str, names: Vec<String>, handles: Vec<u32>) {
+        let acl = Acl {
+            names: names.into_iter().collect(),
+            handles: handles.into_iter().collect(),
+        };
+        self.envs.lock().unwrap().insert(env_id.to_string(), acl);
+    }
+
+    /// 取消环境的 ACL
+    pub fn clear(&self, env_id: &str) -> bool {
+        self.envs.lock().unwrap().remove(env_id).is_some()
+    }
+
+    /// 环境允许调用的 handler (名称, handle)，没有 ACL 时返回 None
+    pub fn get(&self, env_id: &str) -> Option<(Vec<String>, Vec<u32>)> {
+        let envs = self.envs.lock().unwrap();
+        let acl = envs.get(env_id)?;
+        let mut names: Vec<String> = acl.names.iter().cloned().collect();
+        let mut handles: Vec<u32> = acl.handles.iter().copied().collect();
+        names.sort();
+        handles.sort();
+        Some((names, handles))
+    }
+
+    /// 环境是否可以调用 handler
+    /// * `name` handler 注册时的名称
+    pub fn allowed(&self, env_id: &str, handle: u32, name: Option<&str>) -> bool {
+        let envs = self.envs.lock().unwrap();
+        let Some(acl) = envs.get(env_id) else {
+            return true;
+        };
+        acl.handles.contains(&handle) || name.is_some_and(|name| acl.names.contains(name))
+    }
+}
+
+/// 拒绝调用时返回给 guest 的响应
+pub fn denied_response(env_id: &str, handle: u32, name: Option<&str>) -> Vec<u8> {
+    let target = match name {
+        Some(name) => format!("'{}'", name),
+        None => handle.to_string(),
+    };
+    serde_json::json!({
+        "exception": format!(
+            "PermissionError: Environment '{}' is not allowed to call handler {}",
+            env_id, target
+        ),
+        "denied": {
+            "env": env_id,
+            "handle": handle,
+            "handler": name,
+        },
+    })
+    .to_string()
+    .into_bytes()
+}
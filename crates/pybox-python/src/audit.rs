@@ -0,0 +1,122 @@
+//! audit.rs host 端的审计日志
+//!
+//! 每次 exec / call / assign / protect 以及 guest 调用 handler 都会生成一条 JSON 记录，
+//! 交给 Python 可调用对象或按行追加到文件，记录中的字符串在写出前替换已注册的密钥
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use pyo3::prelude::*;
+use serde_json::{Map, Value};
+
+use crate::redact::SharedRedactor;
+
+/// 审计记录的接收端
+enum AuditSink {
+    /// Python 可调用对象，接收 dict
+    Callable(Py<PyAny>),
+    /// JSON Lines 文件
+    File(std::fs::File),
+}
+
+#[derive(Default)]
+pub struct AuditLog {
+    sink: Mutex<Option<AuditSink>>,
+    redactor: SharedRedactor,
+}
+
+impl AuditLog {
+    pub fn new(redactor: SharedRedactor) -> Self {
+        Self {
+            sink: Mutex::default(),
+            redactor,
+        }
+    }
+
+    /// 设置接收端为 Python 可调用对象
+    pub fn set_callable(&self, callable: Py<PyAny>) {
+        *self.sink.lock().unwrap() = Some(AuditSink::Callable(callable));
+    }
+
+    /// 设置接收端为文件，记录追加到文件末尾
+    pub fn set_file(&self, path: PathBuf) -> Result<(), String> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        *self.sink.lock().unwrap() = Some(AuditSink::File(file));
+        Ok(())
+    }
+
+    /// 关闭审计日志，返回之前是否已启用
+    pub fn clear(&self) -> bool {
+        self.sink.lock().unwrap().take().is_some()
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.sink.lock().unwrap().is_some()
+    }
+
+    /// 记录一次操作，未启用时不做任何事
+    /// fields 只在启用时调用，用于填充操作相关的字段
+    pub fn record(
+        &self,
+        py: Python<'_>,
+        op: &str,
+        env_id: &str,
+        started: Instant,
+        error: Option<&PyErr>,
+        fields: impl FnOnce(&mut Map<String, Value>),
+    ) {
+        if !self.enabled() {
+            return;
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |time| time.as_secs_f64());
+        let mut record = Map::new();
+        record.insert("time".into(), time.into());
+        record.insert("op".into(), op.into());
+        record.insert("env_id".into(), env_id.into());
+        record.insert("duration".into(), started.elapsed().as_secs_f64().into());
+        record.insert(
+            "outcome".into(),
+            if error.is_some() { "error" } else { "ok" }.into(),
+        );
+        if let Some(error) = error {
+            record.insert("error".into(), error.to_string().into());
+        }
+        fields(&mut record);
+        let mut record = Value::Object(record);
+        let redactor = self.redactor.lock().unwrap().clone_ref(py);
+        if let Err(e) = redactor.redact_json(py, &mut record) {
+            e.write_unraisable(py, None);
+            return;
+        }
+        self.write(py, record);
+    }
+
+    fn write(&self, py: Python<'_>, record: Value) {
+        let callable = match &mut *self.sink.lock().unwrap() {
+            Some(AuditSink::File(file)) => {
+                if let Err(e) = writeln!(file, "{}", record) {
+                    eprintln!("Failed to write audit record: {}", e);
+                }
+                return;
+            }
+            Some(AuditSink::Callable(callable)) => callable.clone_ref(py),
+            None => return,
+        };
+        // 回调期间不持有锁，回调内部可以重新配置审计日志
+        let result = py
+            .import("json")
+            .and_then(|json| json.getattr("loads")?.call1((record.to_string(),)))
+            .and_then(|record| callable.call1(py, (record,)));
+        if let Err(e) = result {
+            e.write_unraisable(py, Some(callable.bind(py)));
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! budget.rs 按环境累计 fuel 消耗并执行预算限制
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Default)]
+struct EnvBudget {
+    /// 剩余的 fuel，None 表示不限制
+    remaining: Option<u64>,
+    /// 累计消耗的 fuel
+    used: u64,
+}
+
+/// env id -> fuel 预算，全局环境记为空字符串
+#[derive(Default)]
+pub struct FuelBudgets {
+    envs: Mutex<HashMap<String, EnvBudget>>,
+}
+
+impl FuelBudgets {
+    /// 设置剩余预算，None 取消限制
+    pub fn set(&self, env_id: &str, fuel: Option<u64>) {
+        let mut envs = self.envs.lock().unwrap();
+        envs.entry(env_id.to_string()).or_default().remaining = fuel;
+    }
+
+    /// 增加预算，没有限制的环境保持不限制
+    pub fn top_up(&self, env_id: &str, fuel: u64) -> Option<u64> {
+        let mut envs = self.envs.lock().unwrap();
+        let budget = envs.entry(env_id.to_string()).or_default();
+        if let Some(remaining) = budget.remaining.as_mut() {
+            *remaining = remaining.saturating_add(fuel);
+        }
+        budget.remaining
+    }
+
+    pub fn remaining(&self, env_id: &str) -> Option<u64> {
+        self.envs.lock().unwrap().get(env_id)?.remaining
+    }
+
+    pub fn used(&self, env_id: &str) -> u64 {
+        self.envs
+            .lock()
+            .unwrap()
+            .get(env_id)
+            .map_or(0, |budget| budget.used)
+    }
+
+    /// 开始执行前检查预算，返回本次可用的 fuel
+    pub fn limit(&self, env_id: &str) -> Result<u64, String> {
+        match self.remaining(env_id) {
+            Some(0) => Err(format!(
+                "Fuel budget of environment '{}' is exhausted",
+                env_id
+            )),
+            Some(remaining) => Ok(remaining),
+            None => Ok(u64::MAX),
+        }
+    }
+
+    /// 记入一次执行消耗的 fuel
+    pub fn charge(&self, env_id: &str, fuel: u64) {
+        let mut envs = self.envs.lock().unwrap();
+        let budget = envs.entry(env_id.to_string()).or_default();
+        budget.used = budget.used.saturating_add(fuel);
+        if let Some(remaining) = budget.remaining.as_mut() {
+            *remaining = remaining.saturating_sub(fuel);
+        }
+    }
+}
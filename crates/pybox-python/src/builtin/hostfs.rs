@@ -0,0 +1,280 @@
+//! hostfs.rs 内置的 host 文件 handler，只能访问配置的目录
+//!
+//! guest 路径形如 `/<mount>/<relative path>`，mount 为配置的目录名，
+//! guest 端通过 `pybox.hostfs` 访问：read / write / list / stat
+
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use pyo3::prelude::*;
+use serde_json::{Value, json};
+
+use super::{NativeHandler, RpcRequest, hex_decode, hex_encode, rpc_exception, rpc_result};
+
+pub struct HostFs {
+    /// mount 名称 -> host 目录（已规范化）
+    mounts: HashMap<String, PathBuf>,
+    /// 读写单个文件的最大字节数
+    max_file_size: Option<u64>,
+    /// 允许访问的文件扩展名（不含 `.`），None 表示不限制
+    extensions: Option<Vec<String>>,
+    read_only: bool,
+}
+
+impl HostFs {
+    pub fn new(
+        mounts: HashMap<String, PathBuf>,
+        max_file_size: Option<u64>,
+        extensions: Option<Vec<String>>,
+        read_only: bool,
+    ) -> Result<Self, String> {
+        let mut canonical = HashMap::new();
+        for (name, dir) in mounts {
+            if name.is_empty() || name.contains(['/', '\\']) {
+                return Err(format!("Invalid mount name '{}'", name));
+            }
+            let dir = dir
+                .canonicalize()
+                .map_err(|e| format!("{}: {}", dir.display(), e))?;
+            if !dir.is_dir() {
+                return Err(format!("{} is not a directory", dir.display()));
+            }
+            canonical.insert(name, dir);
+        }
+        let extensions = extensions.map(|extensions| {
+            extensions
+                .into_iter()
+                .map(|ext| ext.trim_start_matches('.').to_lowercase())
+                .collect()
+        });
+        Ok(Self {
+            mounts: canonical,
+            max_file_size,
+            extensions,
+            read_only,
+        })
+    }
+
+    /// 将 guest 路径解析为 host 路径
+    /// 不允许 `..` 以及经由符号链接逃逸出 mount 目录
+    fn resolve(&self, path: &str) -> Result<PathBuf, String> {
+        let mut components = Path::new(path).components().filter_map(|c| match c {
+            Component::RootDir | Component::CurDir => None,
+            Component::Normal(name) => Some(Ok(name)),
+            _ => Some(Err(format!("PermissionError: invalid path '{}'", path))),
+        });
+
+        let mount = match components.next() {
+            Some(mount) => mount?,
+            None => return Err(format!("PermissionError: invalid path '{}'", path)),
+        };
+        let root = mount
+            .to_str()
+            .and_then(|mount| self.mounts.get(mount))
+            .ok_or_else(|| format!("FileNotFoundError: no mount for '{}'", path))?;
+
+        let mut host_path = root.clone();
+        for name in components {
+            host_path.push(name?);
+        }
+
+        // 已存在的路径检查真实位置，新文件检查父目录（悬空的符号链接直接拒绝）
+        let real_path = match host_path.canonicalize() {
+            Ok(real_path) => real_path,
+            Err(_) if host_path.symlink_metadata().is_ok() => {
+                return Err(format!("PermissionError: '{}' escapes its mount", path));
+            }
+            Err(_) => host_path
+                .parent()
+                .and_then(|parent| parent.canonicalize().ok())
+                .ok_or_else(|| format!("FileNotFoundError: '{}'", path))?,
+        };
+        if !real_path.starts_with(root) {
+            return Err(format!("PermissionError: '{}' escapes its mount", path));
+        }
+        Ok(host_path)
+    }
+
+    /// 检查文件扩展名策略
+    fn check_extension(&self, path: &str, host_path: &Path) -> Result<(), String> {
+        let Some(extensions) = &self.extensions else {
+            return Ok(());
+        };
+        let ext = host_path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase())
+            .unwrap_or_default();
+        if !extensions.contains(&ext) {
+            return Err(format!(
+                "PermissionError: file type of '{}' is not allowed",
+                path
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_size(&self, path: &str, size: u64) -> Result<(), String> {
+        match self.max_file_size {
+            Some(max_file_size) if size > max_file_size => Err(format!(
+                "ValueError: '{}' exceeds the size limit of {} bytes",
+                path, max_file_size
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn read(&self, path: &str) -> Result<Vec<u8>, String> {
+        let host_path = self.resolve(path)?;
+        self.check_extension(path, &host_path)?;
+        let metadata = std::fs::metadata(&host_path)
+            .map_err(|e| format!("FileNotFoundError: '{}': {}", path, e))?;
+        if !metadata.is_file() {
+            return Err(format!("IsADirectoryError: '{}'", path));
+        }
+        self.check_size(path, metadata.len())?;
+        std::fs::read(&host_path).map_err(|e| format!("OSError: '{}': {}", path, e))
+    }
+
+    pub fn write(&self, path: &str, data: &[u8]) -> Result<(), String> {
+        if self.read_only {
+            return Err(format!("PermissionError: '{}' is read-only", path));
+        }
+        let host_path = self.resolve(path)?;
+        self.check_extension(path, &host_path)?;
+        self.check_size(path, data.len() as u64)?;
+        std::fs::write(&host_path, data).map_err(|e| format!("OSError: '{}': {}", path, e))
+    }
+
+    pub fn list(&self, path: &str) -> Result<Vec<String>, String> {
+        // 根目录列出所有 mount
+        if Path::new(path)
+            .components()
+            .all(|c| matches!(c, Component::RootDir | Component::CurDir))
+        {
+            let mut names: Vec<String> = self.mounts.keys().cloned().collect();
+            names.sort();
+            return Ok(names);
+        }
+
+        let host_path = self.resolve(path)?;
+        let entries = std::fs::read_dir(&host_path)
+            .map_err(|e| format!("FileNotFoundError: '{}': {}", path, e))?;
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("OSError: '{}': {}", path, e))?;
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn stat(&self, path: &str) -> Result<Value, String> {
+        let host_path = self.resolve(path)?;
+        let metadata = std::fs::metadata(&host_path)
+            .map_err(|e| format!("FileNotFoundError: '{}': {}", path, e))?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|time| time.as_secs_f64());
+        Ok(json!({
+            "size": metadata.len(),
+            "is_dir": metadata.is_dir(),
+            "modified": modified,
+        }))
+    }
+
+    fn dispatch(&self, request: &[u8]) -> Result<Value, String> {
+        let request = RpcRequest::parse(request)?;
+        let path = request.str_arg(1, "path")?;
+
+        match request.str_arg(0, "op")? {
+            // 文本以 str 传输，二进制以 hex 传输（guest 端没有 base64 可用）
+            "read" => {
+                let data = self.read(path)?;
+                match request.arg(2, "binary") {
+                    Some(Value::Bool(true)) => Ok(hex_encode(&data).into()),
+                    _ => String::from_utf8(data)
+                        .map(Value::String)
+                        .map_err(|_| format!("UnicodeDecodeError: '{}' is not utf-8", path)),
+                }
+            }
+            "write" => {
+                let data = request.str_arg(2, "data")?;
+                let data = match request.arg(3, "binary") {
+                    Some(Value::Bool(true)) => hex_decode(data)?,
+                    _ => data.as_bytes().to_vec(),
+                };
+                self.write(path, &data)?;
+                Ok(Value::Null)
+            }
+            "list" => Ok(self.list(path)?.into()),
+            "stat" => self.stat(path),
+            op => Err(format!("ValueError: unknown hostfs operation '{}'", op)),
+        }
+    }
+}
+
+impl NativeHandler for HostFs {
+    fn call(&self, _env_id: &str, request: &[u8]) -> Vec<u8> {
+        match self.dispatch(request) {
+            Ok(result) => rpc_result(&result),
+            Err(e) => rpc_exception(&e),
+        }
+    }
+}
+
+/// 内置 host 文件 handler，注册后 guest 通过 `pybox.hostfs` 访问
+///
+/// 用法：
+///   fs = PyBoxHostFS({"data": "/srv/data"}, max_file_size=1 << 20, extensions=["csv"])
+///   reactor.register_handler(handle, fs, "pybox.hostfs")
+#[pyclass]
+pub struct PyBoxHostFS {
+    fs: Arc<HostFs>,
+}
+
+impl PyBoxHostFS {
+    pub fn handler(&self) -> Arc<dyn NativeHandler> {
+        self.fs.clone()
+    }
+}
+
+#[pymethods]
+impl PyBoxHostFS {
+    /// Create a host filesystem handler
+    ///
+    /// Args:
+    ///     mounts: Dict mapping mount names to host directories,
+    ///         the guest sees them as `/<name>/...`
+    ///     max_file_size: Optional limit of a single read or write in bytes
+    ///     extensions: Optional list of allowed file extensions
+    ///     read_only: Reject every write if True
+    #[new]
+    #[pyo3(signature = (mounts, max_file_size=None, extensions=None, read_only=false))]
+    fn new(
+        mounts: HashMap<String, PathBuf>,
+        max_file_size: Option<u64>,
+        extensions: Option<Vec<String>>,
+        read_only: bool,
+    ) -> PyResult<Self> {
+        let fs = HostFs::new(mounts, max_file_size, extensions, read_only)
+            .map_err(pyo3::exceptions::PyValueError::new_err)?;
+        Ok(Self { fs: Arc::new(fs) })
+    }
+
+    /// Resolve a guest path to its host path, raises if the path is not allowed
+    fn resolve(&self, path: &str) -> PyResult<PathBuf> {
+        self.fs
+            .resolve(path)
+            .map_err(pyo3::exceptions::PyPermissionError::new_err)
+    }
+
+    /// Names of the mounts
+    fn mounts(&self) -> Vec<String> {
+        self.fs.list("/").unwrap_or_default()
+    }
+}
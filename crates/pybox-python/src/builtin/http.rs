@@ -0,0 +1,243 @@
+//! http.rs 内置的 HTTP 请求 handler，只能访问允许的域名
+//!
+//! guest 端通过 `pybox.http.request(method, url, ...)` 访问
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use pyo3::prelude::*;
+use serde_json::{Map, Value, json};
+
+use super::{NativeHandler, RpcRequest, rpc_exception, rpc_result};
+
+/// 默认允许的请求方法
+const DEFAULT_METHODS: &[&str] = &["GET", "HEAD"];
+/// 默认的最大响应大小
+const DEFAULT_MAX_RESPONSE_BYTES: u64 = 1 << 20;
+/// 默认的请求超时（秒）
+const DEFAULT_TIMEOUT: f64 = 30.0;
+
+pub struct HttpFetch {
+    client: reqwest::blocking::Client,
+    /// 允许的域名，`*.example.com` 匹配所有子域名
+    allowed_hosts: Vec<String>,
+    /// 允许的请求方法（大写）
+    methods: Vec<String>,
+    max_response_bytes: u64,
+    /// 每次 exec 最多发起的请求数
+    max_requests: Option<u32>,
+    /// env id -> 本次 exec 已发起的请求数
+    counts: Mutex<HashMap<String, u32>>,
+}
+
+impl HttpFetch {
+    pub fn new(
+        allowed_hosts: Vec<String>,
+        methods: Option<Vec<String>>,
+        max_response_bytes: Option<u64>,
+        max_requests: Option<u32>,
+        timeout: Option<f64>,
+    ) -> Result<Self, String> {
+        let timeout = Duration::try_from_secs_f64(timeout.unwrap_or(DEFAULT_TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        let client = reqwest::blocking::Client::builder()
+            .timeout(timeout)
+            // 重定向可能跳出允许的域名，交给 guest 自己处理
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|e| e.to_string())?;
+        let methods = methods
+            .unwrap_or_else(|| DEFAULT_METHODS.iter().map(|m| m.to_string()).collect())
+            .into_iter()
+            .map(|method| method.to_uppercase())
+            .collect();
+        Ok(Self {
+            client,
+            allowed_hosts: allowed_hosts
+                .into_iter()
+                .map(|host| host.to_lowercase())
+                .collect(),
+            methods,
+            max_response_bytes: max_response_bytes.unwrap_or(DEFAULT_MAX_RESPONSE_BYTES),
+            max_requests,
+            counts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn is_allowed_host(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        self.allowed_hosts
+            .iter()
+            .any(|allowed| match allowed.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|sub| sub.ends_with('.')),
+                None => *allowed == host,
+            })
+    }
+
+    /// 计入一次请求，超过 max_requests 时返回错误
+    fn count_request(&self, env_id: &str) -> Result<(), String> {
+        let Some(max_requests) = self.max_requests else {
+            return Ok(());
+        };
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(env_id.to_string()).or_default();
+        if *count >= max_requests {
+            return Err(format!(
+                "QuotaExceeded: at most {} http requests per exec",
+                max_requests
+            ));
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    fn request(&self, env_id: &str, request: &RpcRequest) -> Result<Value, String> {
+        let method = request.str_arg(0, "method")?.to_uppercase();
+        let url = request.str_arg(1, "url")?;
+
+        if !self.methods.contains(&method) {
+            return Err(format!("PermissionError: method {} is not allowed", method));
+        }
+        let url = reqwest::Url::parse(url).map_err(|e| format!("ValueError: {}", e))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(format!(
+                "PermissionError: scheme {} is not allowed",
+                url.scheme()
+            ));
+        }
+        if !url
+            .host_str()
+            .is_some_and(|host| self.is_allowed_host(host))
+        {
+            return Err(format!("PermissionError: host of {} is not allowed", url));
+        }
+        self.count_request(env_id)?;
+
+        let method = reqwest::Method::from_bytes(method.as_bytes())
+            .map_err(|e| format!("ValueError: {}", e))?;
+        let mut builder = self.client.request(method, url);
+        if let Some(Value::Object(headers)) = request.arg(2, "headers") {
+            for (name, value) in headers {
+                let value = match value {
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+                builder = builder.header(name, value);
+            }
+        }
+        match request.arg(3, "body") {
+            Some(Value::String(body)) => builder = builder.body(body.clone()),
+            Some(Value::Null) | None => {}
+            Some(body) => {
+                builder = builder
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(body.to_string())
+            }
+        }
+
+        let response = builder
+            .send()
+            .map_err(|e| format!("ConnectionError: {}", e))?;
+
+        let status = response.status().as_u16();
+        let final_url = response.url().to_string();
+        let mut headers = Map::new();
+        for (name, value) in response.headers() {
+            headers.insert(
+                name.to_string(),
+                String::from_utf8_lossy(value.as_bytes()).into(),
+            );
+        }
+
+        // 多读一个字节以判断是否超出限制
+        let mut body = Vec::new();
+        response
+            .take(self.max_response_bytes + 1)
+            .read_to_end(&mut body)
+            .map_err(|e| format!("ConnectionError: {}", e))?;
+        if body.len() as u64 > self.max_response_bytes {
+            return Err(format!(
+                "ValueError: response exceeds the size limit of {} bytes",
+                self.max_response_bytes
+            ));
+        }
+
+        Ok(json!({
+            "status": status,
+            "url": final_url,
+            "headers": headers,
+            "body": String::from_utf8_lossy(&body),
+        }))
+    }
+}
+
+impl NativeHandler for HttpFetch {
+    fn call(&self, env_id: &str, request: &[u8]) -> Vec<u8> {
+        match RpcRequest::parse(request).and_then(|request| self.request(env_id, &request)) {
+            Ok(result) => rpc_result(&result),
+            Err(e) => rpc_exception(&e),
+        }
+    }
+
+    fn begin_exec(&self, env_id: &str) {
+        self.counts.lock().unwrap().remove(env_id);
+    }
+}
+
+/// 内置 HTTP 请求 handler，注册后 guest 通过 `pybox.http` 访问
+///
+/// 用法：
+///   http = PyBoxHttp(["api.example.com", "*.example.org"], max_requests=10)
+///   reactor.register_handler(handle, http, "pybox.http")
+#[pyclass]
+pub struct PyBoxHttp {
+    fetch: Arc<HttpFetch>,
+}
+
+impl PyBoxHttp {
+    pub fn handler(&self) -> Arc<dyn NativeHandler> {
+        self.fetch.clone()
+    }
+}
+
+#[pymethods]
+impl PyBoxHttp {
+    /// Create an HTTP fetch handler
+    ///
+    /// Args:
+    ///     allowed_hosts: Hosts the guest may reach, `*.example.com` matches subdomains
+    ///     methods: Allowed methods, GET and HEAD by default
+    ///     max_response_bytes: Limit of a response body, 1 MiB by default
+    ///     max_requests: Optional limit of requests per exec
+    ///     timeout: Request timeout in seconds, 30 by default
+    #[new]
+    #[pyo3(signature = (allowed_hosts, methods=None, max_response_bytes=None, max_requests=None, timeout=None))]
+    fn new(
+        allowed_hosts: Vec<String>,
+        methods: Option<Vec<String>>,
+        max_response_bytes: Option<u64>,
+        max_requests: Option<u32>,
+        timeout: Option<f64>,
+    ) -> PyResult<Self> {
+        let fetch = HttpFetch::new(
+            allowed_hosts,
+            methods,
+            max_response_bytes,
+            max_requests,
+            timeout,
+        )
+        .map_err(pyo3::exceptions::PyValueError::new_err)?;
+        Ok(Self {
+            fetch: Arc::new(fetch),
+        })
+    }
+
+    /// Whether the guest may reach a host
+    fn is_allowed_host(&self, host: &str) -> bool {
+        self.fetch.is_allowed_host(host)
+    }
+}
@@ -0,0 +1,240 @@
+//! kv.rs 内置的 key/value 存储 handler，按调用环境划分命名空间
+//!
+//! guest 端通过 `pybox.kv` 访问：get / set / delete / keys
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use pyo3::prelude::*;
+use serde_json::Value;
+
+use super::{NativeHandler, RpcRequest, rpc_exception, rpc_result};
+
+/// env id -> (key -> json 序列化的 value)
+type KvSpaces = HashMap<String, BTreeMap<String, String>>;
+
+pub struct KvStore {
+    spaces: Mutex<KvSpaces>,
+    /// 每个环境可使用的最大字节数（key + json value）
+    max_bytes: Option<usize>,
+    /// 持久化文件，每次修改后整体写回
+    path: Option<PathBuf>,
+}
+
+impl KvStore {
+    /// 创建存储，path 存在时从文件加载
+    pub fn open(max_bytes: Option<usize>, path: Option<PathBuf>) -> Result<Self, String> {
+        let mut spaces = KvSpaces::new();
+        if let Some(path) = path.as_ref().filter(|path| path.exists()) {
+            let data = std::fs::read(path).map_err(|e| e.to_string())?;
+            let saved: HashMap<String, BTreeMap<String, Value>> =
+                serde_json::from_slice(&data).map_err(|e| e.to_string())?;
+            for (env_id, space) in saved {
+                let space = space
+                    .into_iter()
+                    .map(|(key, value)| (key, value.to_string()))
+                    .collect();
+                spaces.insert(env_id, space);
+            }
+        }
+        Ok(Self {
+            spaces: Mutex::new(spaces),
+            max_bytes,
+            path,
+        })
+    }
+
+    pub fn get(&self, env_id: &str, key: &str) -> Option<String> {
+        let spaces = self.spaces.lock().unwrap();
+        spaces.get(env_id)?.get(key).cloned()
+    }
+
+    pub fn set(&self, env_id: &str, key: &str, value: String) -> Result<(), String> {
+        let mut spaces = self.spaces.lock().unwrap();
+        let space = spaces.entry(env_id.to_string()).or_default();
+
+        if let Some(max_bytes) = self.max_bytes {
+            let used: usize = space
+                .iter()
+                .filter(|(k, _)| k.as_str() != key)
+                .map(|(k, v)| k.len() + v.len())
+                .sum();
+            if used + key.len() + value.len() > max_bytes {
+                return Err(format!(
+                    "QuotaExceeded: kv store of env '{}' is limited to {} bytes",
+                    env_id, max_bytes
+                ));
+            }
+        }
+
+        space.insert(key.to_string(), value);
+        self.save(&spaces)
+    }
+
+    pub fn delete(&self, env_id: &str, key: &str) -> Result<bool, String> {
+        let mut spaces = self.spaces.lock().unwrap();
+        let removed = spaces
+            .get_mut(env_id)
+            .is_some_and(|space| space.remove(key).is_some());
+        if removed {
+            self.save(&spaces)?;
+        }
+        Ok(removed)
+    }
+
+    pub fn keys(&self, env_id: &str, prefix: &str) -> Vec<String> {
+        let spaces = self.spaces.lock().unwrap();
+        spaces
+            .get(env_id)
+            .map(|space| {
+                space
+                    .keys()
+                    .filter(|key| key.starts_with(prefix))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// 清空指定环境，env_id 为 None 时清空所有环境
+    pub fn clear(&self, env_id: Option<&str>) -> Result<(), String> {
+        let mut spaces = self.spaces.lock().unwrap();
+        match env_id {
+            Some(env_id) => {
+                spaces.remove(env_id);
+            }
+            None => spaces.clear(),
+        }
+        self.save(&spaces)
+    }
+
+    fn save(&self, spaces: &KvSpaces) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut saved = serde_json::Map::new();
+        for (env_id, space) in spaces {
+            let mut values = serde_json::Map::new();
+            for (key, value) in space {
+                let value = serde_json::from_str(value).map_err(|e| e.to_string())?;
+                values.insert(key.clone(), value);
+            }
+            saved.insert(env_id.clone(), Value::Object(values));
+        }
+        // 先写临时文件再替换，避免写到一半的文件
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, Value::Object(saved).to_string()).map_err(|e| e.to_string())?;
+        std::fs::rename(&tmp_path, path).map_err(|e| e.to_string())
+    }
+
+    fn dispatch(&self, env_id: &str, request: &[u8]) -> Result<Value, String> {
+        let request = RpcRequest::parse(request)?;
+        let parse = |value: String| serde_json::from_str(&value).map_err(|e| e.to_string());
+
+        match request.str_arg(0, "op")? {
+            "get" => match self.get(env_id, request.str_arg(1, "key")?) {
+                Some(value) => parse(value),
+                None => Ok(request.arg(2, "default").cloned().unwrap_or(Value::Null)),
+            },
+            "set" => {
+                let key = request.str_arg(1, "key")?;
+                let value = request.arg(2, "value").cloned().unwrap_or(Value::Null);
+                self.set(env_id, key, value.to_string())?;
+                Ok(Value::Null)
+            }
+            "delete" => Ok(Value::Bool(
+                self.delete(env_id, request.str_arg(1, "key")?)?,
+            )),
+            "keys" => {
+                let prefix = request.str_arg(1, "prefix").unwrap_or("");
+                Ok(self.keys(env_id, prefix).into())
+            }
+            op => Err(format!("ValueError: unknown kv operation '{}'", op)),
+        }
+    }
+}
+
+impl NativeHandler for KvStore {
+    fn call(&self, env_id: &str, request: &[u8]) -> Vec<u8> {
+        match self.dispatch(env_id, request) {
+            Ok(result) => rpc_result(&result),
+            Err(e) => rpc_exception(&e),
+        }
+    }
+}
+
+/// 内置 key/value 存储，注册为 handler 后 guest 通过 `pybox.kv` 访问
+///
+/// 用法：
+///   store = PyBoxKVStore(max_bytes=1 << 20, path="kv.json")
+///   reactor.register_handler(handle, store, "pybox.kv")
+#[pyclass]
+pub struct PyBoxKVStore {
+    store: Arc<KvStore>,
+}
+
+impl PyBoxKVStore {
+    pub fn handler(&self) -> Arc<dyn NativeHandler> {
+        self.store.clone()
+    }
+}
+
+#[pymethods]
+impl PyBoxKVStore {
+    /// Create a key/value store
+    ///
+    /// Args:
+    ///     max_bytes: Optional per-environment quota (keys + JSON values)
+    ///     path: Optional JSON file used to persist the store
+    #[new]
+    #[pyo3(signature = (max_bytes=None, path=None))]
+    fn new(max_bytes: Option<usize>, path: Option<PathBuf>) -> PyResult<Self> {
+        let store =
+            KvStore::open(max_bytes, path).map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+        Ok(Self {
+            store: Arc::new(store),
+        })
+    }
+
+    /// Get a value of an environment, None if missing
+    fn get(&self, py: Python, env_id: &str, key: &str) -> PyResult<Option<Py<PyAny>>> {
+        let Some(value) = self.store.get(env_id, key) else {
+            return Ok(None);
+        };
+        let json_module = py.import("json")?;
+        Ok(Some(
+            json_module.getattr("loads")?.call1((value,))?.unbind(),
+        ))
+    }
+
+    /// Set a JSON-serializable value of an environment
+    fn set(&self, py: Python, env_id: &str, key: &str, value: &Bound<'_, PyAny>) -> PyResult<()> {
+        let json_module = py.import("json")?;
+        let value: String = json_module.getattr("dumps")?.call1((value,))?.extract()?;
+        self.store
+            .set(env_id, key, value)
+            .map_err(pyo3::exceptions::PyValueError::new_err)
+    }
+
+    /// Delete a key of an environment, returns whether it existed
+    fn delete(&self, env_id: &str, key: &str) -> PyResult<bool> {
+        self.store
+            .delete(env_id, key)
+            .map_err(pyo3::exceptions::PyRuntimeError::new_err)
+    }
+
+    /// List the keys of an environment
+    #[pyo3(signature = (env_id, prefix=""))]
+    fn keys(&self, env_id: &str, prefix: &str) -> Vec<String> {
+        self.store.keys(env_id, prefix)
+    }
+
+    /// Clear an environment, or every environment if env_id is None
+    #[pyo3(signature = (env_id=None))]
+    fn clear(&self, env_id: Option<&str>) -> PyResult<()> {
+        self.store
+            .clear(env_id)
+            .map_err(pyo3::exceptions::PyRuntimeError::new_err)
+    }
+}
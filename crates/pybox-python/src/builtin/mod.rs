@@ -0,0 +1,115 @@
+//! builtin 提供 Rust 实现的内置 handler，guest 调用时不经过 Python 回调
+//!
+//! 内置 handler 与 Python handler 使用同一套 JSON-RPC 协议：
+//! 请求 `{"args": [...], "kwargs": {...}}`，响应 `{"result": ...}` 或 `{"exception": "..."}`
+//! 新版 guest 的请求还带有调用方环境 `env` 和 exec 的关联 id `correlation_id`，
+//! 内置 handler 使用 host 记录的当前环境，忽略这两个字段
+
+pub mod hostfs;
+pub mod http;
+pub mod kv;
+pub mod sql;
+
+use std::sync::Arc;
+
+use pyo3::prelude::*;
+use serde_json::{Map, Value};
+
+/// Rust 实现的 handler
+pub trait NativeHandler: Send + Sync {
+    /// 处理一次 JSON-RPC 请求，错误以 exception 响应返回
+    /// * `env_id` 发起调用的环境
+    /// * `request` 请求数据
+    fn call(&self, env_id: &str, request: &[u8]) -> Vec<u8>;
+
+    /// 环境开始一次 exec / call，用于重置按次计算的配额
+    fn begin_exec(&self, _env_id: &str) {}
+}
+
+/// 如果 obj 是内置 handler 对象，返回它的 NativeHandler
+pub fn native_handler(obj: &Bound<'_, PyAny>) -> Option<Arc<dyn NativeHandler>> {
+    if let Ok(kv) = obj.cast::<kv::PyBoxKVStore>() {
+        return Some(kv.borrow().handler());
+    }
+    if let Ok(fs) = obj.cast::<hostfs::PyBoxHostFS>() {
+        return Some(fs.borrow().handler());
+    }
+    if let Ok(http) = obj.cast::<http::PyBoxHttp>() {
+        return Some(http.borrow().handler());
+    }
+    if let Ok(sql) = obj.cast::<sql::PyBoxSQLite>() {
+        return Some(sql.borrow().handler());
+    }
+    None
+}
+
+/// JSON-RPC 请求
+pub struct RpcRequest {
+    pub args: Vec<Value>,
+    pub kwargs: Map<String, Value>,
+}
+
+impl RpcRequest {
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let mut request: Map<String, Value> =
+            serde_json::from_slice(data).map_err(|e| format!("Invalid request: {}", e))?;
+        let args = match request.remove("args") {
+            Some(Value::Array(args)) => args,
+            None => Vec::new(),
+            Some(_) => return Err("Invalid request: 'args' must be a list".to_string()),
+        };
+        let kwargs = match request.remove("kwargs") {
+            Some(Value::Object(kwargs)) => kwargs,
+            None => Map::new(),
+            Some(_) => return Err("Invalid request: 'kwargs' must be a dict".to_string()),
+        };
+        Ok(Self { args, kwargs })
+    }
+
+    /// 按位置或关键字获取参数
+    pub fn arg(&self, index: usize, name: &str) -> Option<&Value> {
+        self.args.get(index).or_else(|| self.kwargs.get(name))
+    }
+
+    /// 获取字符串参数
+    pub fn str_arg(&self, index: usize, name: &str) -> Result<&str, String> {
+        match self.arg(index, name) {
+            Some(Value::String(value)) => Ok(value),
+            Some(_) => Err(format!("TypeError: '{}' must be a str", name)),
+            None => Err(format!("TypeError: missing required argument '{}'", name)),
+        }
+    }
+}
+
+/// 构造成功响应
+pub fn rpc_result(result: &Value) -> Vec<u8> {
+    serde_json::json!({ "result": result })
+        .to_string()
+        .into_bytes()
+}
+
+/// 构造异常响应，guest 端会抛出 `JSON-RPC Error: {exception}`
+pub fn rpc_exception(exception: &str) -> Vec<u8> {
+    serde_json::json!({ "exception": exception })
+        .to_string()
+        .into_bytes()
+}
+
+/// 二进制数据以 hex 传输，guest 端用 bytes.hex / bytes.fromhex 转换
+pub fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn hex_decode(data: &str) -> Result<Vec<u8>, String> {
+    if data.len() % 2 != 0 {
+        return Err("ValueError: odd-length hex data".to_string());
+    }
+    (0..data.len())
+        .step_by(2)
+        .map(|i| {
+            data.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| "ValueError: invalid hex data".to_string())
+        })
+        .collect()
+}
@@ -0,0 +1,343 @@
+//! sql.rs 内置的 SQLite handler，每个环境使用独立的数据库
+//!
+//! guest 端通过 `pybox.sql` 访问：execute / query / executescript
+//!
+//! 连接不允许附加其他数据库 (ATTACH / VACUUM INTO)，authorizer 拒绝 load_extension
+//! 和白名单以外的 PRAGMA，guest 的 SQL 只能访问环境自己的数据库
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use pyo3::prelude::*;
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization};
+use rusqlite::limits::Limit;
+use rusqlite::types::{ToSqlOutput, Value as SqlValue, ValueRef};
+use serde_json::{Value, json};
+
+use super::{NativeHandler, RpcRequest, hex_encode, rpc_exception, rpc_result};
+
+/// 默认单次查询返回的最大行数
+const DEFAULT_MAX_ROWS: usize = 1000;
+
+/// 只读取信息或只影响当前连接的 PRAGMA，其他 PRAGMA 可能修改文件、路径或 schema
+const ALLOWED_PRAGMAS: &[&str] = &[
+    "application_id",
+    "defer_foreign_keys",
+    "foreign_key_check",
+    "foreign_key_list",
+    "foreign_keys",
+    "index_info",
+    "index_list",
+    "index_xinfo",
+    "integrity_check",
+    "quick_check",
+    "recursive_triggers",
+    "table_info",
+    "table_list",
+    "table_xinfo",
+    "user_version",
+];
+
+/// guest SQL 的授权：拒绝附加数据库、加载扩展和白名单以外的 PRAGMA
+fn authorize(context: AuthContext<'_>) -> Authorization {
+    match context.action {
+        AuthAction::Attach { .. } | AuthAction::Detach { .. } => Authorization::Deny,
+        AuthAction::Function { function_name }
+            if function_name.eq_ignore_ascii_case("load_extension") =>
+        {
+            Authorization::Deny
+        }
+        AuthAction::Pragma { pragma_name, .. }
+            if !ALLOWED_PRAGMAS
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(pragma_name)) =>
+        {
+            Authorization::Deny
+        }
+        _ => Authorization::Allow,
+    }
+}
+
+/// 打开环境的数据库并限制 guest SQL 能访问的范围
+fn open_connection(path: Option<PathBuf>) -> rusqlite::Result<rusqlite::Connection> {
+    let connection = match path {
+        Some(path) => rusqlite::Connection::open(path)?,
+        None => rusqlite::Connection::open_in_memory()?,
+    };
+    // VACUUM INTO 同样通过附加数据库写入目标文件
+    connection.set_limit(Limit::SQLITE_LIMIT_ATTACHED, 0)?;
+    connection.authorizer(Some(authorize));
+    Ok(connection)
+}
+
+pub struct SqlStore {
+    /// 数据库文件目录，None 时每个环境使用内存数据库
+    directory: Option<PathBuf>,
+    max_rows: usize,
+    /// env id -> 数据库连接
+    connections: Mutex<HashMap<String, rusqlite::Connection>>,
+}
+
+/// JSON 参数转换为 SQLite 值，list / dict 以 JSON 文本保存
+fn to_sql(value: &Value) -> SqlValue {
+    match value {
+        Value::Null => SqlValue::Null,
+        Value::Bool(value) => SqlValue::Integer(*value as i64),
+        Value::Number(number) => match number.as_i64() {
+            Some(value) => SqlValue::Integer(value),
+            None => SqlValue::Real(number.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(value) => SqlValue::Text(value.clone()),
+        value => SqlValue::Text(value.to_string()),
+    }
+}
+
+/// SQLite 值转换为 JSON，blob 以 hex 文本返回
+fn to_json(value: ValueRef<'_>) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(value) => value.into(),
+        ValueRef::Real(value) => value.into(),
+        ValueRef::Text(value) => String::from_utf8_lossy(value).into(),
+        ValueRef::Blob(value) => hex_encode(value).into(),
+    }
+}
+
+/// 语句参数，支持位置参数 (list) 和命名参数 (dict)
+fn bind_params(
+    statement: &mut rusqlite::Statement<'_>,
+    params: Option<&Value>,
+) -> Result<(), String> {
+    match params {
+        None | Some(Value::Null) => Ok(()),
+        Some(Value::Array(params)) => {
+            for (index, param) in params.iter().enumerate() {
+                statement
+                    .raw_bind_parameter(index + 1, ToSqlOutput::Owned(to_sql(param)))
+                    .map_err(|e| format!("ProgrammingError: {}", e))?;
+            }
+            Ok(())
+        }
+        Some(Value::Object(params)) => {
+            for (name, param) in params {
+                let name = format!(":{}", name.trim_start_matches([':', '@', '$']));
+                let Some(index) = statement
+                    .parameter_index(&name)
+                    .map_err(|e| format!("ProgrammingError: {}", e))?
+                else {
+                    return Err(format!("ProgrammingError: no parameter named {}", name));
+                };
+                statement
+                    .raw_bind_parameter(index, ToSqlOutput::Owned(to_sql(param)))
+                    .map_err(|e| format!("ProgrammingError: {}", e))?;
+            }
+            Ok(())
+        }
+        Some(_) => Err("TypeError: params must be a list or a dict".to_string()),
+    }
+}
+
+impl SqlStore {
+    pub fn new(directory: Option<PathBuf>, max_rows: Option<usize>) -> Result<Self, String> {
+        if let Some(directory) = &directory {
+            std::fs::create_dir_all(directory).map_err(|e| e.to_string())?;
+        }
+        Ok(Self {
+            directory,
+            max_rows: max_rows.unwrap_or(DEFAULT_MAX_ROWS),
+            connections: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// 环境的数据库文件路径，文件名为环境名的 hex 编码，不同的环境不会共用文件
+    pub fn database_path(&self, env_id: &str) -> Option<PathBuf> {
+        let name = if env_id.is_empty() {
+            "_global".to_string()
+        } else {
+            hex_encode(env_id.as_bytes())
+        };
+        Some(self.directory.as_ref()?.join(format!("{}.sqlite3", name)))
+    }
+
+    /// 获取环境的数据库连接并执行 f
+    fn with_connection<R>(
+        &self,
+        env_id: &str,
+        f: impl FnOnce(&rusqlite::Connection) -> Result<R, String>,
+    ) -> Result<R, String> {
+        let mut connections = self.connections.lock().unwrap();
+        if !connections.contains_key(env_id) {
+            let connection = open_connection(self.database_path(env_id))
+                .map_err(|e| format!("OperationalError: {}", e))?;
+            connections.insert(env_id.to_string(), connection);
+        }
+        f(&connections[env_id])
+    }
+
+    pub fn execute(
+        &self,
+        env_id: &str,
+        sql: &str,
+        params: Option<&Value>,
+    ) -> Result<Value, String> {
+        self.with_connection(env_id, |connection| {
+            let mut statement = connection
+                .prepare(sql)
+                .map_err(|e| format!("OperationalError: {}", e))?;
+            bind_params(&mut statement, params)?;
+            let rowcount = statement
+                .raw_execute()
+                .map_err(|e| format!("OperationalError: {}", e))?;
+            Ok(json!({
+                "rowcount": rowcount,
+                "lastrowid": connection.last_insert_rowid(),
+            }))
+        })
+    }
+
+    pub fn query(&self, env_id: &str, sql: &str, params: Option<&Value>) -> Result<Value, String> {
+        self.with_connection(env_id, |connection| {
+            let mut statement = connection
+                .prepare(sql)
+                .map_err(|e| format!("OperationalError: {}", e))?;
+            bind_params(&mut statement, params)?;
+            let columns: Vec<String> = statement
+                .column_names()
+                .into_iter()
+                .map(str::to_string)
+                .collect();
+
+            let mut rows = statement.raw_query();
+            let mut result = Vec::new();
+            while let Some(row) = rows
+                .next()
+                .map_err(|e| format!("OperationalError: {}", e))?
+            {
+                if result.len() >= self.max_rows {
+                    return Err(format!(
+                        "ValueError: query returns more than {} rows",
+                        self.max_rows
+                    ));
+                }
+                let values = (0..columns.len())
+                    .map(|index| row.get_ref(index).map(to_json))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| format!("OperationalError: {}", e))?;
+                result.push(Value::Array(values));
+            }
+            Ok(json!({ "columns": columns, "rows": result }))
+        })
+    }
+
+    pub fn executescript(&self, env_id: &str, sql: &str) -> Result<(), String> {
+        self.with_connection(env_id, |connection| {
+            connection
+                .execute_batch(sql)
+                .map_err(|e| format!("OperationalError: {}", e))
+        })
+    }
+
+    /// 关闭环境的数据库连接，内存数据库的数据会被丢弃
+    pub fn close(&self, env_id: &str) -> bool {
+        self.connections.lock().unwrap().remove(env_id).is_some()
+    }
+
+    fn dispatch(&self, env_id: &str, request: &[u8]) -> Result<Value, String> {
+        let request = RpcRequest::parse(request)?;
+        let sql = request.str_arg(1, "sql")?;
+        match request.str_arg(0, "op")? {
+            "execute" => self.execute(env_id, sql, request.arg(2, "params")),
+            "query" => self.query(env_id, sql, request.arg(2, "params")),
+            "executescript" => self.executescript(env_id, sql).map(|_| Value::Null),
+            op => Err(format!("ValueError: unknown sql operation '{}'", op)),
+        }
+    }
+}
+
+impl NativeHandler for SqlStore {
+    fn call(&self, env_id: &str, request: &[u8]) -> Vec<u8> {
+        match self.dispatch(env_id, request) {
+            Ok(result) => rpc_result(&result),
+            Err(e) => rpc_exception(&e),
+        }
+    }
+}
+
+/// 内置 SQLite handler，注册后 guest 通过 `pybox.sql` 访问
+///
+/// 用法：
+///   sql = PyBoxSQLite(directory="dbs", max_rows=500)
+///   reactor.register_handler(handle, sql, "pybox.sql")
+#[pyclass]
+pub struct PyBoxSQLite {
+    store: Arc<SqlStore>,
+}
+
+impl PyBoxSQLite {
+    pub fn handler(&self) -> Arc<dyn NativeHandler> {
+        self.store.clone()
+    }
+}
+
+#[pymethods]
+impl PyBoxSQLite {
+    /// Create a SQLite handler
+    ///
+    /// Args:
+    ///     directory: Optional directory holding one database file per environment,
+    ///         in-memory databases are used if None
+    ///     max_rows: Limit of rows returned by a query, 1000 by default
+    #[new]
+    #[pyo3(signature = (directory=None, max_rows=None))]
+    fn new(directory: Option<PathBuf>, max_rows: Option<usize>) -> PyResult<Self> {
+        let store = SqlStore::new(directory, max_rows)
+            .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+        Ok(Self {
+            store: Arc::new(store),
+        })
+    }
+
+    /// Database file of an environment, None for in-memory databases
+    fn database_path(&self, env_id: &str) -> Option<PathBuf> {
+        self.store.database_path(env_id)
+    }
+
+    /// Run a query against the database of an environment
+    ///
+    /// Returns:
+    ///     list[list]: Rows of the result
+    #[pyo3(signature = (env_id, sql, params=None))]
+    fn query(
+        &self,
+        py: Python,
+        env_id: &str,
+        sql: &str,
+        params: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<Py<PyAny>> {
+        let json_module = py.import("json")?;
+        let params: Option<Value> = match params {
+            Some(params) => {
+                let params: String = json_module.getattr("dumps")?.call1((params,))?.extract()?;
+                Some(
+                    serde_json::from_str(&params)
+                        .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))?,
+                )
+            }
+            None => None,
+        };
+        let result = self
+            .store
+            .query(env_id, sql, params.as_ref())
+            .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+        let rows = json_module
+            .getattr("loads")?
+            .call1((result["rows"].to_string(),))?;
+        Ok(rows.unbind())
+    }
+
+    /// Close the database of an environment, in-memory data is dropped
+    fn close(&self, env_id: &str) -> bool {
+        self.store.close(env_id)
+    }
+}
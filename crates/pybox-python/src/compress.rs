@@ -0,0 +1,92 @@
+//! compress.rs ioctl 负载的分帧压缩，与 guest 端 codec.rs 对应
+//!
+//! guest 以 `handle | COMPRESSED_HANDLE_FLAG` 发送的请求和对应的响应都是分帧数据：
+//! 首字节为编码 (FRAME_RAW / FRAME_ZSTD)，其后为数据；
+//! 流式响应为 FRAME_STREAM + u32 stream id，guest 通过 STREAM_HANDLE 逐块拉取
+
+use std::borrow::Cow;
+use std::io::Read;
+
+/// 带有该标记的 handle 使用分帧的请求和响应
+pub const COMPRESSED_HANDLE_FLAG: u32 = pybox_host::protocol::RESERVED_HANDLE_BASE;
+/// 默认的压缩阈值
+pub const DEFAULT_COMPRESS_THRESHOLD: usize = 64 * 1024;
+
+const FRAME_RAW: u8 = 0;
+const FRAME_ZSTD: u8 = 1;
+const FRAME_STREAM: u8 = 2;
+/// zstd 压缩等级，负载通常是 JSON，低等级已经足够
+const ZSTD_LEVEL: i32 = 1;
+
+/// 分帧编码，数据不小于 threshold 且压缩有收益时使用 zstd
+pub fn encode(data: &[u8], threshold: usize) -> Vec<u8> {
+    if data.len() >= threshold {
+        if let Ok(compressed) = zstd::bulk::compress(data, ZSTD_LEVEL) {
+            if compressed.len() < data.len() {
+                let mut frame = Vec::with_capacity(compressed.len() + 1);
+                frame.push(FRAME_ZSTD);
+                frame.extend_from_slice(&compressed);
+                return frame;
+            }
+        }
+    }
+    let mut frame = Vec::with_capacity(data.len() + 1);
+    frame.push(FRAME_RAW);
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// 流式响应的分帧
+pub fn stream_frame(stream_id: u32) -> Vec<u8> {
+    let mut frame = vec![FRAME_STREAM];
+    frame.extend_from_slice(&stream_id.to_le_bytes());
+    frame
+}
+
+/// 分帧解码失败的原因
+#[derive(Debug)]
+pub enum DecodeError {
+    /// 解压后的数据超过了限制的字节数
+    TooLarge(usize),
+    /// 分帧数据无效
+    Invalid(String),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooLarge(limit) => write!(
+                f,
+                "decompressed request exceeds the limit of {} bytes",
+                limit
+            ),
+            Self::Invalid(reason) => f.write_str(reason),
+        }
+    }
+}
+
+/// 分帧解码，未压缩的数据不拷贝；解压最多产生 limit 字节，超出时返回 TooLarge
+pub fn decode(frame: &[u8], limit: usize) -> Result<Cow<'_, [u8]>, DecodeError> {
+    match frame.split_first() {
+        Some((&FRAME_RAW, data)) => Ok(Cow::Borrowed(data)),
+        Some((&FRAME_ZSTD, data)) => {
+            let mut decoded = Vec::new();
+            zstd::stream::read::Decoder::new(data)
+                .and_then(|decoder| {
+                    decoder
+                        .take((limit as u64).saturating_add(1))
+                        .read_to_end(&mut decoded)
+                })
+                .map_err(|e| DecodeError::Invalid(e.to_string()))?;
+            if decoded.len() > limit {
+                return Err(DecodeError::TooLarge(limit));
+            }
+            Ok(Cow::Owned(decoded))
+        }
+        Some((codec, _)) => Err(DecodeError::Invalid(format!(
+            "unknown frame codec {}",
+            codec
+        ))),
+        None => Err(DecodeError::Invalid("empty frame".to_string())),
+    }
+}
@@ -0,0 +1,237 @@
+//! dirty_pages.rs 跟踪 guest 线性内存中写入过的页，快照 update 时只复制这些页
+//!
+//! 使用 Linux 的 soft-dirty 位：向 /proc/self/clear_refs 写入 "4" 清除进程所有页的 soft-dirty 位，
+//! 之后写入的页在 /proc/self/pagemap 中置位。清除对整个进程生效，所以清除前先把所有跟踪中的
+//! 内存的脏页收集到各自的集合中；其他线程上正在访问的 reactor 可能在收集和清除之间写入，
+//! 它的跟踪记为失效，下一次 update 退回到完整复制。其他平台或内核不支持 soft-dirty 时不跟踪
+//!
+//! 每次清除都要遍历整个进程的页表并写保护所有页，开销随进程的常驻内存增长，
+//! 也会重置进程中其他依赖 soft-dirty 的组件的状态，见 enable_dirty_tracking 的说明
+
+use std::ops::Range;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// pagemap 条目中的 soft-dirty 位
+const SOFT_DIRTY_BIT: u64 = 1 << 55;
+
+/// 一段被跟踪的内存
+struct TrackedMemory {
+    id: u64,
+    base: usize,
+    len: usize,
+    /// 本段跟踪开始以来写入过的系统页
+    dirty: Vec<bool>,
+    /// 本段跟踪的编号，全局唯一
+    generation: u64,
+    /// 收集失败或访问中被清除时为 false
+    valid: bool,
+    /// reactor 是否正在被访问
+    busy: bool,
+}
+
+/// 所有跟踪中的内存，收集和清除在锁内进行
+static TRACKED: Mutex<Vec<TrackedMemory>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// 一个 reactor 的脏页跟踪，drop 时停止跟踪
+pub struct DirtyTracker {
+    id: u64,
+}
+
+impl DirtyTracker {
+    /// 内核支持 soft-dirty 时开始跟踪，否则返回 None
+    pub fn new() -> Option<Self> {
+        if !supported() {
+            return None;
+        }
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        TRACKED.lock().unwrap().push(TrackedMemory {
+            id,
+            base: 0,
+            len: 0,
+            dirty: Vec::new(),
+            generation: 0,
+            valid: false,
+            busy: false,
+        });
+        Some(Self { id })
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut TrackedMemory) -> R) -> Option<R> {
+        let mut tracked = TRACKED.lock().unwrap();
+        tracked
+            .iter_mut()
+            .find(|memory| memory.id == self.id)
+            .map(f)
+    }
+
+    /// reactor 开始被访问，期间其他 reactor 的清除使本段跟踪失效
+    pub fn enter(&self) {
+        self.with(|memory| memory.busy = true);
+    }
+
+    pub fn exit(&self) {
+        self.with(|memory| memory.busy = false);
+    }
+
+    /// 内存被替换（重新实例化），之前的跟踪不再可信
+    pub fn invalidate(&self) {
+        self.with(|memory| memory.valid = false);
+    }
+
+    /// 结束当前一段跟踪并开始新的一段，返回新一段的编号和
+    /// 编号为 since 的一段以来写入过的字节范围（按系统页对齐）；
+    /// since 不是当前一段、跟踪失效或内存被移动时范围为 None
+    pub fn checkpoint(
+        &self,
+        base: usize,
+        len: usize,
+        since: Option<u64>,
+    ) -> (Option<u64>, Option<Vec<Range<usize>>>) {
+        let page_size = page_size();
+        let mut tracked = TRACKED.lock().unwrap();
+        for memory in tracked.iter_mut() {
+            if memory.busy && memory.id != self.id {
+                memory.valid = false;
+            } else if memory.valid && !harvest(memory, page_size) {
+                memory.valid = false;
+            }
+        }
+        let Some(index) = tracked.iter().position(|memory| memory.id == self.id) else {
+            return (None, None);
+        };
+        let dirty = {
+            let memory = &tracked[index];
+            (memory.valid && since == Some(memory.generation) && memory.base == base)
+                .then(|| dirty_ranges(memory, len, page_size))
+        };
+        let cleared = std::fs::write("/proc/self/clear_refs", "4").is_ok();
+        if !cleared {
+            // 其他内存的 soft-dirty 位状态未知
+            tracked.iter_mut().for_each(|memory| memory.valid = false);
+            return (None, None);
+        }
+        let memory = &mut tracked[index];
+        memory.base = base;
+        memory.len = len;
+        memory.dirty = vec![false; len.div_ceil(page_size)];
+        memory.generation = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        memory.valid = true;
+        (Some(memory.generation), dirty)
+    }
+}
+
+impl Drop for DirtyTracker {
+    fn drop(&mut self) {
+        TRACKED
+            .lock()
+            .unwrap()
+            .retain(|memory| memory.id != self.id);
+    }
+}
+
+/// 写入过的页合并为连续的字节范围，跟踪开始后增长的内存全部视为写入过
+fn dirty_ranges(memory: &TrackedMemory, len: usize, page_size: usize) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    let mut push = |range: Range<usize>| match ranges.last_mut() {
+        Some(last) if last.end == range.start => last.end = range.end,
+        _ => ranges.push(range),
+    };
+    for (page, _) in memory.dirty.iter().enumerate().filter(|(_, dirty)| **dirty) {
+        let start = page * page_size;
+        push(start..(start + page_size).min(len));
+    }
+    if len > memory.len {
+        push(memory.len..len);
+    }
+    ranges.retain(|range| range.start < range.end);
+    ranges
+}
+
+#[cfg(unix)]
+fn page_size() -> usize {
+    usize::try_from(unsafe { libc::sysconf(libc::_SC_PAGESIZE) }).unwrap_or(4096)
+}
+
+#[cfg(not(unix))]
+fn page_size() -> usize {
+    4096
+}
+
+/// 将 memory 中 soft-dirty 的页记入 dirty 集合
+#[cfg(target_os = "linux")]
+fn harvest(memory: &mut TrackedMemory, page_size: usize) -> bool {
+    use std::os::unix::fs::FileExt;
+
+    let pages = memory.dirty.len();
+    if pages == 0 {
+        return true;
+    }
+    let Ok(pagemap) = std::fs::File::open("/proc/self/pagemap") else {
+        return false;
+    };
+    let mut entries = vec![0u8; pages * 8];
+    let offset = (memory.base / page_size) as u64 * 8;
+    if pagemap.read_exact_at(&mut entries, offset).is_err() {
+        return false;
+    }
+    for (dirty, entry) in memory.dirty.iter_mut().zip(entries.chunks_exact(8)) {
+        let entry = u64::from_ne_bytes(entry.try_into().unwrap());
+        *dirty |= entry & SOFT_DIRTY_BIT != 0;
+    }
+    true
+}
+
+#[cfg(not(target_os = "linux"))]
+fn harvest(_memory: &mut TrackedMemory, _page_size: usize) -> bool {
+    false
+}
+
+/// 新映射的页带有 soft-dirty 位，内核不支持时该位始终为 0
+#[cfg(target_os = "linux")]
+fn supported() -> bool {
+    static SUPPORTED: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+    *SUPPORTED.get_or_init(|| {
+        if std::fs::OpenOptions::new()
+            .write(true)
+            .open("/proc/self/clear_refs")
+            .is_err()
+        {
+            return false;
+        }
+        let page_size = page_size();
+        // SAFETY: 匿名私有映射，只在本函数内使用并释放
+        unsafe {
+            let ptr = libc::mmap(
+                std::ptr::null_mut(),
+                page_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if ptr == libc::MAP_FAILED {
+                return false;
+            }
+            ptr.cast::<u8>().write_volatile(1);
+            let mut probe = TrackedMemory {
+                id: 0,
+                base: ptr as usize,
+                len: page_size,
+                dirty: vec![false],
+                generation: 0,
+                valid: true,
+                busy: false,
+            };
+            let supported = harvest(&mut probe, page_size) && probe.dirty[0];
+            libc::munmap(ptr, page_size);
+            supported
+        }
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn supported() -> bool {
+    false
+}
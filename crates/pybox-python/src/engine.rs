@@ -0,0 +1,174 @@
+//! engine.rs 可以在多个 reactor 之间共享的 wasmtime Engine
+//!
+//! 编译好的模块按 Engine 缓存，使用同一个 PyBoxEngine 的 reactor 共享模块缓存和引擎的配置；
+//! 没有指定引擎的 reactor 使用 DEFAULT_ENGINE。fuel 计量会拖慢 guest 的执行，
+//! 只有创建时打开 fuel 的引擎（如 DEFAULT_FUEL_ENGINE）支持 fuel 预算
+
+use std::sync::Arc;
+
+use pyo3::prelude::*;
+
+use crate::reactor::PyBoxReactor;
+
+pub static DEFAULT_ENGINE: std::sync::LazyLock<Arc<wasmtime::Engine>> =
+    std::sync::LazyLock::new(|| default_engine(false));
+
+/// 打开 fuel 计量的默认引擎，PyBoxEngine.default(fuel=True) 返回它
+pub static DEFAULT_FUEL_ENGINE: std::sync::LazyLock<Arc<wasmtime::Engine>> =
+    std::sync::LazyLock::new(|| default_engine(true));
+
+fn default_engine(fuel: bool) -> Arc<wasmtime::Engine> {
+    let mut config = wasmtime::Config::new();
+    // 启用编译缓存
+    config.cache_config_load_default().unwrap();
+    new_engine(config, fuel).unwrap()
+}
+
+/// epoch 的间隔，guest 执行期间每隔这段时间检查一次 host 的信号
+const EPOCH_TICK: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// 创建 reactor 使用的 Engine：打开 epoch 中断并启动递增 epoch 的线程，fuel 为 true 时打开 fuel 计量
+pub fn new_engine(
+    mut config: wasmtime::Config,
+    fuel: bool,
+) -> wasmtime::Result<Arc<wasmtime::Engine>> {
+    // 按 fuel 统计 guest 执行的指令数，预算和 fuel 统计需要
+    config.consume_fuel(fuel);
+    // guest 执行期间定期检查 host 的信号，见 PyBoxReactorCore::on_epoch
+    config.epoch_interruption(true);
+    let engine = Arc::new(wasmtime::Engine::new(&config)?);
+    // 线程只持有弱引用，Engine 释放后线程退出
+    let ticker = Arc::downgrade(&engine);
+    std::thread::Builder::new()
+        .name("pybox-epoch".to_string())
+        .spawn(move || {
+            loop {
+                std::thread::sleep(EPOCH_TICK);
+                let Some(engine) = ticker.upgrade() else {
+                    break;
+                };
+                engine.increment_epoch();
+            }
+        })?;
+    Ok(engine)
+}
+
+/// A wasmtime engine shared by many reactors
+///
+/// Reactors created with the same engine share its compiled module cache and
+/// its configuration, so a fleet compiles the WASM file once and runs under
+/// the same limits. Reactors created without an engine use
+/// `PyBoxEngine.default()`.
+///
+///     engine = PyBoxEngine(max_wasm_stack=1024 * 1024, memory_limit=256 * 1024 * 1024)
+///     engine.load(wasm_file)
+///     builder = PyBoxReactorBuilder().engine(engine)
+///     boxes = [builder.build() for _ in range(8)]
+#[pyclass(module = "pybox.pyboxcore")]
+pub struct PyBoxEngine {
+    engine: Arc<wasmtime::Engine>,
+    memory_limit: Option<usize>,
+    /// 引擎是否打开了 fuel 计量
+    fuel: bool,
+}
+
+impl PyBoxEngine {
+    pub fn engine(&self) -> &Arc<wasmtime::Engine> {
+        &self.engine
+    }
+
+    /// 使用该引擎的 reactor 默认的内存上限
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.memory_limit
+    }
+
+    /// 引擎是否打开了 fuel 计量
+    pub fn fuel(&self) -> bool {
+        self.fuel
+    }
+}
+
+#[pymethods]
+impl PyBoxEngine {
+    /// Create an engine
+    ///
+    /// Args:
+    ///     cache: Cache compiled modules on disk with the default wasmtime cache configuration
+    ///     opt_level: Cranelift optimization level, "none", "speed" or "speed_and_size"
+    ///     max_wasm_stack: Maximum stack size of guest code in bytes, deeper
+    ///         recursion traps. None keeps the wasmtime default
+    ///     memory_reservation: Virtual address space reserved for each linear
+    ///         memory in bytes, None keeps the wasmtime default
+    ///     memory_limit: Default memory limit of the reactors using this engine,
+    ///         see PyBoxReactor.set_memory_limit
+    ///     fuel: Count the instructions guest code runs as fuel, required by
+    ///         fuel budgets (set_budget, calibrate). Slows guest code down, so
+    ///         it is off by default and the fuel in stats and heartbeats is 0
+    #[new]
+    #[pyo3(signature = (cache=true, opt_level="speed", max_wasm_stack=None, memory_reservation=None, memory_limit=None, fuel=false))]
+    fn new(
+        cache: bool,
+        opt_level: &str,
+        max_wasm_stack: Option<usize>,
+        memory_reservation: Option<u64>,
+        memory_limit: Option<usize>,
+        fuel: bool,
+    ) -> PyResult<Self> {
+        let mut config = wasmtime::Config::new();
+        if cache {
+            config
+                .cache_config_load_default()
+                .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+        }
+        config.cranelift_opt_level(match opt_level {
+            "none" => wasmtime::OptLevel::None,
+            "speed" => wasmtime::OptLevel::Speed,
+            "speed_and_size" => wasmtime::OptLevel::SpeedAndSize,
+            _ => {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "Unknown opt_level '{}', expected none, speed or speed_and_size",
+                    opt_level
+                )));
+            }
+        });
+        if let Some(size) = max_wasm_stack {
+            config.max_wasm_stack(size);
+        }
+        if let Some(bytes) = memory_reservation {
+            config.memory_reservation(bytes);
+        }
+        let engine = new_engine(config, fuel)
+            .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))?;
+        Ok(Self {
+            engine,
+            memory_limit,
+            fuel,
+        })
+    }
+
+    /// The engine of reactors created without one
+    ///
+    /// Args:
+    ///     fuel: Return the shared default engine that counts fuel instead
+    #[staticmethod]
+    #[pyo3(signature = (fuel=false))]
+    fn default(fuel: bool) -> Self {
+        let engine = match fuel {
+            true => &DEFAULT_FUEL_ENGINE,
+            false => &DEFAULT_ENGINE,
+        };
+        Self {
+            engine: Arc::clone(engine),
+            memory_limit: None,
+            fuel,
+        }
+    }
+
+    /// Compile a WASM file into the module cache of this engine ahead of time
+    ///
+    /// Reactors created afterwards with this engine and the same path skip compilation.
+    fn load(&self, py: Python<'_>, wasmfile: &str) -> PyResult<()> {
+        PyBoxReactor::load_module(py, &self.engine, wasmfile)?;
+        Ok(())
+    }
+}
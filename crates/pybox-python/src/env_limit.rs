@@ -0,0 +1,40 @@
+//! env_limit.rs 环境大小的上限
+//!
+//! 变量数量的上限交给 guest，ProtectedLocals 在新增变量时检查并抛出 pybox.EnvLimitError；
+//! 单个值的大小按 JSON 序列化后的字节数在 host 端检查，作用于 assign 和 retrieve
+
+use crate::error::PyBoxEnvLimitError;
+
+/// 一个环境的大小上限，None 表示不限制
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct EnvLimits {
+    /// 环境中变量数量的上限
+    pub max_variables: Option<usize>,
+    /// assign / retrieve 的单个值序列化后的字节数上限
+    pub max_value_size: Option<usize>,
+}
+
+impl EnvLimits {
+    /// 检查 assign / retrieve 的值序列化后的大小
+    pub fn check_value_size(&self, env_id: &str, name: &str, size: usize) -> pyo3::PyResult<()> {
+        match self.max_value_size {
+            Some(max_value_size) if size > max_value_size => {
+                Err(PyBoxEnvLimitError::new_err(format!(
+                    "Value of '{}' in environment '{}' is {} bytes, the limit is {} bytes",
+                    name, env_id, size, max_value_size
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// guest 返回的 traceback 是否以 pybox.EnvLimitError 结束
+pub fn is_limit_error(traceback: &str) -> bool {
+    traceback
+        .lines()
+        .rev()
+        .find(|line| !line.trim().is_empty())
+        .map(|line| line.strip_prefix("pybox.").unwrap_or(line))
+        .is_some_and(|line| line.starts_with("EnvLimitError"))
+}
@@ -0,0 +1,54 @@
+//! error.rs pyboxcore 抛出的异常类型
+//!
+//! 模块名使用完整路径 pybox.pyboxcore，异常可以被 pickle，在进程之间传递
+
+use pyo3::create_exception;
+
+create_exception!(
+    pybox.pyboxcore,
+    PyBoxQuotaError,
+    pyo3::exceptions::PyRuntimeError,
+    "An environment exhausted one of its quotas"
+);
+
+create_exception!(
+    pybox.pyboxcore,
+    PyBoxRateLimitError,
+    PyBoxQuotaError,
+    "An environment was called more often or deeper than its rate limit allows"
+);
+
+create_exception!(
+    pybox.pyboxcore,
+    PyBoxEnvLimitError,
+    PyBoxQuotaError,
+    "An environment exceeded its variable count or value size limit"
+);
+
+create_exception!(
+    pybox.pyboxcore,
+    PyBoxGuestError,
+    pyo3::exceptions::PyException,
+    "An exception raised by guest code without a matching builtin exception type"
+);
+
+create_exception!(
+    pybox.pyboxcore,
+    PyBoxTimeoutError,
+    pyo3::exceptions::PyTimeoutError,
+    "An execution ran past the watchdog timeout and was stopped"
+);
+
+create_exception!(
+    pybox.pyboxcore,
+    PyBoxReplayError,
+    pyo3::exceptions::PyRuntimeError,
+    "A replayed execution made a handler request that does not match the recording"
+);
+
+create_exception!(
+    pybox.pyboxcore,
+    PyBoxLintError,
+    pyo3::exceptions::PyException,
+    "Code rejected by a lint rule before it ran"
+);
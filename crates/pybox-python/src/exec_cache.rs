@@ -0,0 +1,152 @@
+//! exec_cache.rs 缓存 exec 的结果
+//!
+//! 以 `exec(..., cache=True)` 执行的代码由调用方保证结果确定且不改变环境，
+//! 结果以 (环境, 代码) 和环境的状态版本为键保存；普通 exec、assign、call、protect 等
+//! 可能改变环境的操作更新环境的版本，之前缓存的结果随之失效，按最近使用淘汰
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use dashmap::DashMap;
+use pyo3::prelude::*;
+
+/// 默认缓存的结果数量
+pub const DEFAULT_CAPACITY: usize = 128;
+
+struct Entry {
+    env_id: String,
+    code: String,
+    /// 是否同时返回 PyBoxExecStats，决定结果的形状
+    stats: bool,
+    version: u64,
+    result: Py<PyAny>,
+}
+
+pub struct ExecCache {
+    /// 环境 ID -> 状态版本，版本从全局递增的序号中分配，删除后重建的环境不会复用旧版本
+    versions: DashMap<String, u64>,
+    next_version: AtomicU64,
+    /// 最近使用的排在最后
+    entries: Mutex<VecDeque<Entry>>,
+    capacity: AtomicUsize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Default for ExecCache {
+    fn default() -> Self {
+        Self {
+            versions: DashMap::new(),
+            next_version: AtomicU64::new(1),
+            entries: Mutex::new(VecDeque::new()),
+            capacity: AtomicUsize::new(DEFAULT_CAPACITY),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+}
+
+impl ExecCache {
+    /// 环境当前的状态版本
+    pub fn version(&self, env_id: &str) -> u64 {
+        self.versions.get(env_id).map_or(0, |version| *version)
+    }
+
+    /// 环境的状态可能已经改变，之前缓存的结果失效
+    pub fn touch(&self, env_id: &str) {
+        let version = self.next_version.fetch_add(1, Ordering::Relaxed);
+        self.versions.insert(env_id.to_string(), version);
+    }
+
+    /// 删除环境的版本和缓存的结果
+    pub fn forget(&self, env_id: &str) {
+        self.versions.remove(env_id);
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|entry| entry.env_id != env_id);
+    }
+
+    /// 内存被整体恢复，所有环境的状态都可能改变
+    pub fn touch_all(&self) {
+        self.versions.clear();
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// 查找环境在 version 时执行 code 的结果
+    pub fn get(
+        &self,
+        py: Python<'_>,
+        env_id: &str,
+        code: &str,
+        stats: bool,
+        version: u64,
+    ) -> Option<Py<PyAny>> {
+        let mut entries = self.entries.lock().unwrap();
+        let index = entries.iter().position(|entry| {
+            entry.version == version
+                && entry.stats == stats
+                && entry.env_id == env_id
+                && entry.code == code
+        });
+        let Some(index) = index else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        let entry = entries.remove(index)?;
+        let result = entry.result.clone_ref(py);
+        entries.push_back(entry);
+        Some(result)
+    }
+
+    pub fn insert(&self, env_id: &str, code: &str, stats: bool, version: u64, result: Py<PyAny>) {
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        if capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        // 同一段代码的旧版本结果不会再命中
+        entries.retain(|entry| {
+            !(entry.env_id == env_id && entry.code == code && entry.stats == stats)
+        });
+        while entries.len() >= capacity {
+            entries.pop_front();
+        }
+        entries.push_back(Entry {
+            env_id: env_id.to_string(),
+            code: code.to_string(),
+            stats,
+            version,
+            result,
+        });
+    }
+
+    /// 设置缓存的结果数量，0 表示不缓存
+    pub fn set_capacity(&self, capacity: usize) {
+        self.capacity.store(capacity, Ordering::Relaxed);
+        let mut entries = self.entries.lock().unwrap();
+        while entries.len() > capacity {
+            entries.pop_front();
+        }
+    }
+
+    /// 清空缓存的结果，返回清除的数量
+    pub fn clear(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.len();
+        entries.clear();
+        count
+    }
+
+    /// (命中次数, 未命中次数, 缓存的结果数量, 容量)
+    pub fn info(&self) -> (u64, u64, usize, usize) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+            self.entries.lock().unwrap().len(),
+            self.capacity.load(Ordering::Relaxed),
+        )
+    }
+}
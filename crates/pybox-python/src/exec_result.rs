@@ -0,0 +1,423 @@
+//! exec_result.rs exec 的结构化结果
+//!
+//! guest 通过 pybox_exec_report 导出 json 格式的结果，旧版 guest 没有该导出时
+//! 合并的输出全部记为 stdout；`str(result)` 与之前返回的合并输出相同。
+//! guest 端的输出和 artifact 限制由沙箱中的 python 代码执行，可以被绕过，host 生成结果时按 ExecLimits 再截断一次
+
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyList, PyString, PyType};
+use serde_json::Value;
+
+use crate::error::{PyBoxEnvLimitError, PyBoxGuestError, PyBoxLintError};
+use crate::redact::Redactor;
+use crate::stats::PyBoxExecStats;
+
+/// exec 的输出和 artifact 限制
+#[derive(Clone, Copy, Default)]
+pub struct ExecLimits {
+    /// stdout 和 stderr 合计的最大字节数
+    pub max_output: Option<usize>,
+    pub max_artifacts: Option<usize>,
+    /// artifact 数据的最大总字节数
+    pub max_artifact_bytes: Option<usize>,
+}
+
+/// text 中不超过 max 字节的最长前缀（按字符边界）
+pub fn text_prefix(text: &str, max: usize) -> &str {
+    let end = (0..=max.min(text.len()))
+        .rev()
+        .find(|end| text.is_char_boundary(*end))
+        .unwrap_or(0);
+    &text[..end]
+}
+
+/// 将文本截断到 max 字节以内，返回是否截断
+fn truncate_text(text: &mut String, max: Option<usize>) -> bool {
+    let Some(max) = max.filter(|max| text.len() > *max) else {
+        return false;
+    };
+    let end = text_prefix(text, max).len();
+    text.truncate(end);
+    true
+}
+
+/// artifact 数据的字节数，二进制数据以 hex 传输
+fn artifact_size(info: &Value) -> usize {
+    let data = info["data"].as_str().unwrap_or_default();
+    if info["binary"].as_bool().unwrap_or(false) {
+        data.len() / 2
+    } else {
+        data.len()
+    }
+}
+
+/// exec 的结果，除了下列属性外，也可以像 str 一样使用合并的输出
+#[pyclass(get_all, module = "pybox.pyboxcore")]
+pub struct PyBoxExecResult {
+    /// stdout 和 stderr 按写入顺序合并的输出，包括未捕获异常的 traceback
+    pub output: String,
+    pub stdout: String,
+    pub stderr: String,
+    /// guest 代码抛出的未捕获异常
+    pub exception: Option<Py<PyAny>>,
+    /// 代码以表达式结尾时表达式的 repr
+    pub result_repr: Option<String>,
+    /// guest 通过 `pybox.artifact(name, data, mime)` 附加的数据
+    pub artifacts: Py<PyList>,
+    pub stats: Option<Py<PyBoxExecStats>>,
+    /// 输出或 artifact 超过 exec 的限制而被截断
+    pub truncated: bool,
+    /// 执行超过 watchdog 的超时时间被终止，reactor 已从快照恢复
+    pub timed_out: bool,
+    /// host 和沙箱中的 lint 规则报告的诊断
+    pub diagnostics: Py<PyList>,
+}
+
+/// 将 guest 异常转换为同名的内置异常，没有同名内置异常时使用 PyBoxGuestError
+/// guest 的异常类型和 traceback 记录在 guest_type / guest_traceback 属性中，
+/// pybox.LintError 转换为 PyBoxLintError，pybox.EnvLimitError 转换为 PyBoxEnvLimitError
+fn guest_exception(py: Python<'_>, info: &Value, diagnostics: &Py<PyList>) -> PyResult<Py<PyAny>> {
+    let type_name = info["type"].as_str().unwrap_or("Exception");
+    let message = info["message"].as_str().unwrap_or_default();
+    let traceback = info["traceback"].as_str().unwrap_or_default();
+
+    if type_name == "LintError" {
+        let exception = lint_error(py, message, diagnostics)?;
+        exception.setattr(py, "guest_type", type_name)?;
+        exception.setattr(py, "guest_traceback", traceback)?;
+        return Ok(exception);
+    }
+
+    if type_name == "EnvLimitError" {
+        let exception = PyBoxEnvLimitError::new_err(message.to_string()).into_value(py);
+        exception.setattr(py, "guest_type", type_name)?;
+        exception.setattr(py, "guest_traceback", traceback)?;
+        return Ok(exception.into_any());
+    }
+
+    let builtin = py
+        .import("builtins")?
+        .getattr(type_name)
+        .ok()
+        .filter(|exc_type| {
+            exc_type.cast::<PyType>().is_ok_and(|exc_type| {
+                exc_type
+                    .is_subclass_of::<pyo3::exceptions::PyBaseException>()
+                    .unwrap_or(false)
+            })
+        })
+        // 构造参数不是单个消息的异常类型会失败
+        .and_then(|exc_type| exc_type.call1((message,)).ok());
+    let exception = match builtin {
+        Some(exception) => exception,
+        None => PyBoxGuestError::new_err(format!("{}: {}", type_name, message))
+            .into_value(py)
+            .into_bound(py)
+            .into_any(),
+    };
+    exception.setattr("guest_type", type_name)?;
+    exception.setattr("guest_traceback", traceback)?;
+    Ok(exception.unbind())
+}
+
+/// 带有 diagnostics 属性的 PyBoxLintError
+pub fn lint_error(py: Python<'_>, message: &str, diagnostics: &Py<PyList>) -> PyResult<Py<PyAny>> {
+    let exception = PyBoxLintError::new_err(message.to_string()).into_value(py);
+    exception.setattr(py, "diagnostics", diagnostics.clone_ref(py))?;
+    Ok(exception.into_any())
+}
+
+/// json 描述的 artifact 转换为 dict，二进制数据还原为 bytes
+fn artifact<'py>(py: Python<'py>, info: &Value) -> PyResult<Bound<'py, PyDict>> {
+    let artifact = PyDict::new(py);
+    artifact.set_item("name", info["name"].as_str().unwrap_or_default())?;
+    artifact.set_item("mime", info["mime"].as_str())?;
+    let data = info["data"].as_str().unwrap_or_default();
+    if info["binary"].as_bool().unwrap_or(false) {
+        let data =
+            crate::builtin::hex_decode(data).map_err(pyo3::exceptions::PyValueError::new_err)?;
+        artifact.set_item("data", PyBytes::new(py, &data))?;
+    } else {
+        artifact.set_item("data", data)?;
+    }
+    Ok(artifact)
+}
+
+/// pickle 时的字段，顺序与结构体相同
+type ResultFields = (
+    String,
+    String,
+    String,
+    Option<Py<PyAny>>,
+    Option<String>,
+    Py<PyList>,
+    Option<Py<PyBoxExecStats>>,
+    bool,
+    bool,
+    Py<PyList>,
+);
+
+impl PyBoxExecResult {
+    /// 由合并的输出和 guest 导出的 json 结果创建，超过 limits 的输出和 artifact 被丢弃
+    pub fn new(
+        py: Python<'_>,
+        mut output: String,
+        report: Option<&[u8]>,
+        stats: Option<Py<PyBoxExecStats>>,
+        limits: ExecLimits,
+    ) -> PyResult<Self> {
+        let report: Value = report
+            .and_then(|report| serde_json::from_slice(report).ok())
+            .unwrap_or_default();
+        let mut truncated = truncate_text(&mut output, limits.max_output);
+        let Value::Object(report) = report else {
+            return Ok(Self {
+                stdout: output.clone(),
+                output,
+                stderr: String::new(),
+                exception: None,
+                result_repr: None,
+                artifacts: PyList::empty(py).unbind(),
+                stats,
+                truncated,
+                timed_out: false,
+                diagnostics: PyList::empty(py).unbind(),
+            });
+        };
+
+        let diagnostics = match report.get("diagnostics") {
+            Some(diagnostics @ Value::Array(_)) => py
+                .import("json")?
+                .getattr("loads")?
+                .call1((diagnostics.to_string(),))?
+                .cast_into::<PyList>()?
+                .unbind(),
+            _ => PyList::empty(py).unbind(),
+        };
+        let exception = match report.get("exception") {
+            Some(info @ Value::Object(_)) => Some(guest_exception(py, info, &diagnostics)?),
+            _ => None,
+        };
+        let artifacts = PyList::empty(py);
+        if let Some(Value::Array(infos)) = report.get("artifacts") {
+            let mut remaining_bytes = limits.max_artifact_bytes;
+            for info in infos {
+                let size = artifact_size(info);
+                if limits
+                    .max_artifacts
+                    .is_some_and(|max| artifacts.len() >= max)
+                    || remaining_bytes.is_some_and(|remaining| size > remaining)
+                {
+                    truncated = true;
+                    continue;
+                }
+                if let Some(remaining) = &mut remaining_bytes {
+                    *remaining -= size;
+                }
+                artifacts.append(artifact(py, info)?)?;
+            }
+        }
+        let text = |key: &str| report.get(key).and_then(Value::as_str).map(str::to_string);
+        let mut stdout = text("stdout").unwrap_or_default();
+        let mut stderr = text("stderr").unwrap_or_default();
+        truncated |= truncate_text(&mut stdout, limits.max_output);
+        let stderr_limit = limits
+            .max_output
+            .map(|max| max.saturating_sub(stdout.len()));
+        truncated |= truncate_text(&mut stderr, stderr_limit);
+        truncated |= report
+            .get("truncated")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        Ok(Self {
+            stdout,
+            stderr,
+            result_repr: text("result_repr"),
+            output,
+            exception,
+            artifacts: artifacts.unbind(),
+            stats,
+            truncated,
+            timed_out: false,
+            diagnostics,
+        })
+    }
+}
+
+impl PyBoxExecResult {
+    /// 替换返回给调用方的文本中的密钥：输出、result_repr、artifact 以及异常的消息和 traceback
+    pub fn redact(&mut self, py: Python<'_>, redactor: &Redactor) -> PyResult<()> {
+        if redactor.is_empty() {
+            return Ok(());
+        }
+        redactor.redact_in_place(py, &mut self.output)?;
+        redactor.redact_in_place(py, &mut self.stdout)?;
+        redactor.redact_in_place(py, &mut self.stderr)?;
+        if let Some(result_repr) = &mut self.result_repr {
+            redactor.redact_in_place(py, result_repr)?;
+        }
+        redactor.redact_artifacts(py, self.artifacts.bind(py))?;
+        if let Some(exception) = &self.exception {
+            redactor.redact_exception(py, exception.bind(py))?;
+        }
+        Ok(())
+    }
+
+    /// host 的 lint 规则报告错误、代码没有运行时的结果
+    pub fn lint_blocked(py: Python<'_>, diagnostics: Bound<'_, PyList>) -> PyResult<Self> {
+        let mut errors = Vec::new();
+        for diagnostic in diagnostics.iter() {
+            if !diagnostic.get_item("severity")?.eq("error")? {
+                continue;
+            }
+            let message = diagnostic.get_item("message")?.str()?.to_string();
+            let line = diagnostic.get_item("line")?;
+            errors.push(if line.is_none() {
+                message
+            } else {
+                format!("line {}: {}", line, message)
+            });
+        }
+        let message = errors.join("; ");
+        let output = format!("LintError: {}\n", message);
+        let diagnostics = diagnostics.unbind();
+        Ok(Self {
+            stdout: String::new(),
+            stderr: output.clone(),
+            output,
+            exception: Some(lint_error(py, &message, &diagnostics)?),
+            result_repr: None,
+            artifacts: PyList::empty(py).unbind(),
+            stats: None,
+            truncated: false,
+            timed_out: false,
+            diagnostics,
+        })
+    }
+}
+
+#[pymethods]
+impl PyBoxExecResult {
+    fn __str__(&self) -> &str {
+        &self.output
+    }
+
+    /// Pickle support, so results can be sent to other processes
+    fn __reduce__<'py>(slf: &Bound<'py, Self>) -> PyResult<(Bound<'py, PyAny>, (ResultFields,))> {
+        let py = slf.py();
+        let result = slf.borrow();
+        Ok((
+            slf.get_type().getattr("_from_pickle")?,
+            ((
+                result.output.clone(),
+                result.stdout.clone(),
+                result.stderr.clone(),
+                result
+                    .exception
+                    .as_ref()
+                    .map(|exception| exception.clone_ref(py)),
+                result.result_repr.clone(),
+                result.artifacts.clone_ref(py),
+                result.stats.as_ref().map(|stats| stats.clone_ref(py)),
+                result.truncated,
+                result.timed_out,
+                result.diagnostics.clone_ref(py),
+            ),),
+        ))
+    }
+
+    #[classmethod]
+    fn _from_pickle(_cls: &Bound<'_, PyType>, fields: ResultFields) -> Self {
+        let (
+            output,
+            stdout,
+            stderr,
+            exception,
+            result_repr,
+            artifacts,
+            stats,
+            truncated,
+            timed_out,
+            diagnostics,
+        ) = fields;
+        Self {
+            output,
+            stdout,
+            stderr,
+            exception,
+            result_repr,
+            artifacts,
+            stats,
+            truncated,
+            timed_out,
+            diagnostics,
+        }
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "PyBoxExecResult(output={:?}, exception={}, result_repr={:?})",
+            self.output,
+            if self.exception.is_some() {
+                "..."
+            } else {
+                "None"
+            },
+            self.result_repr,
+        )
+    }
+
+    fn __len__(&self) -> usize {
+        self.output.chars().count()
+    }
+
+    fn __contains__(&self, item: &str) -> bool {
+        self.output.contains(item)
+    }
+
+    fn __getitem__<'py>(
+        &self,
+        py: Python<'py>,
+        key: &Bound<'py, PyAny>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        PyString::new(py, &self.output).get_item(key)
+    }
+
+    fn __iter__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        Ok(PyString::new(py, &self.output).try_iter()?.into_any())
+    }
+
+    fn __add__<'py>(
+        &self,
+        py: Python<'py>,
+        other: &Bound<'py, PyAny>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        PyString::new(py, &self.output).add(other)
+    }
+
+    fn __radd__<'py>(
+        &self,
+        py: Python<'py>,
+        other: &Bound<'py, PyAny>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        other.add(PyString::new(py, &self.output))
+    }
+
+    fn __eq__(&self, other: &Bound<'_, PyAny>) -> bool {
+        if let Ok(other) = other.cast::<PyBoxExecResult>() {
+            return other.borrow().output == self.output;
+        }
+        other
+            .extract::<&str>()
+            .is_ok_and(|other| other == self.output)
+    }
+
+    fn __hash__(&self, py: Python<'_>) -> PyResult<isize> {
+        PyString::new(py, &self.output).hash()
+    }
+
+    /// 其余属性按 str 处理，兼容之前返回 str 的用法，如 `result.strip()`
+    fn __getattr__<'py>(&self, py: Python<'py>, name: &str) -> PyResult<Bound<'py, PyAny>> {
+        PyString::new(py, &self.output).getattr(name)
+    }
+}
@@ -0,0 +1,102 @@
+//! handler_context.rs 传给 Python handler 的调用上下文
+//!
+//! 参数中有 `ctx` 的 handler 以 `handler(data, ctx=ctx)` 调用，只接受 bytes 的 handler 调用方式不变
+
+use std::sync::Mutex;
+
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::json::CodecOptions;
+
+/// 调用 handler 的上下文
+///
+/// `emit` 发送的部分响应排在 handler 返回值之前，guest 支持流式响应时以
+/// pybox.Stream 逐块拉取，否则与返回值拼接为一个响应
+#[pyclass(frozen, module = "pybox.pyboxcore")]
+pub struct PyBoxHandlerContext {
+    /// 发起调用的环境
+    #[pyo3(get)]
+    env_id: String,
+    #[pyo3(get)]
+    handle: u32,
+    /// handler 注册时使用的名称
+    #[pyo3(get)]
+    handler_name: Option<String>,
+    /// 本次调用在 reactor 所有 host 调用中的序号，从 1 开始
+    #[pyo3(get)]
+    seq: u64,
+    /// 响应的 JSON 编码选项，见 PyBoxReactor.set_codec
+    codec: CodecOptions,
+    chunks: Mutex<Vec<Py<PyBytes>>>,
+}
+
+impl PyBoxHandlerContext {
+    pub fn new(
+        env_id: String,
+        handle: u32,
+        handler_name: Option<String>,
+        seq: u64,
+        codec: CodecOptions,
+    ) -> Self {
+        Self {
+            env_id,
+            handle,
+            handler_name,
+            seq,
+            codec,
+            chunks: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// 取出 emit 发送的部分响应
+    pub fn take_chunks(&self) -> Vec<Py<PyBytes>> {
+        std::mem::take(&mut *self.chunks.lock().unwrap())
+    }
+
+    /// 参数中是否有 ctx，无法获取签名的可调用对象（部分内置函数）视为没有
+    pub fn accepted_by(func: &Bound<'_, PyAny>) -> bool {
+        let parameters = func
+            .py()
+            .import("inspect")
+            .and_then(|inspect| inspect.getattr("signature")?.call1((func,)))
+            .and_then(|signature| signature.getattr("parameters"));
+        parameters.is_ok_and(|parameters| parameters.contains("ctx").unwrap_or(false))
+    }
+}
+
+#[pymethods]
+impl PyBoxHandlerContext {
+    /// Send part of the response ahead of the return value of the handler
+    ///
+    /// Sandboxed code pulls the chunks one by one from the pybox.Stream it receives
+    /// when it supports streamed responses, otherwise all chunks and the return
+    /// value are joined into one response. Strings are encoded as UTF-8.
+    fn emit(&self, py: Python<'_>, chunk: &Bound<'_, PyAny>) -> PyResult<()> {
+        let chunk = match chunk.cast::<PyBytes>() {
+            Ok(bytes) => bytes.clone().unbind(),
+            Err(_) => PyBytes::new(py, chunk.extract::<String>()?.as_bytes()).unbind(),
+        };
+        self.chunks.lock().unwrap().push(chunk);
+        Ok(())
+    }
+
+    /// How JSON responses encode NaN and Infinity, see PyBoxReactor.set_codec
+    #[getter]
+    fn non_finite(&self) -> &'static str {
+        self.codec.non_finite.name()
+    }
+
+    /// Whether JSON responses tag ints beyond 64 bits, see PyBoxReactor.set_codec
+    #[getter]
+    fn big_ints(&self) -> bool {
+        self.codec.big_ints
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "PyBoxHandlerContext(env_id={:?}, handle={}, handler_name={:?}, seq={})",
+            self.env_id, self.handle, self.handler_name, self.seq
+        )
+    }
+}
@@ -0,0 +1,98 @@
+//! handler_quota.rs handler 的请求 / 响应大小和每次 exec 的调用次数限制
+//!
+//! 在 register_handler 时设置。请求大小和调用次数在调用 handler 之前检查，响应大小在 handler
+//! 返回后、写回 guest 之前检查，超出限制时 guest 收到 pybox.QuotaError；
+//! 调用次数在最外层的 exec / call 开始时清零
+
+/// 一个 handler 的限制，None 表示不限制
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct HandlerQuota {
+    pub max_request_bytes: Option<usize>,
+    pub max_response_bytes: Option<usize>,
+    pub max_calls_per_exec: Option<usize>,
+}
+
+/// handle -> 限制和本次 exec 中的调用次数
+#[derive(Default)]
+pub struct HandlerQuotas {
+    quotas: dashmap::DashMap<u32, HandlerQuota>,
+    calls: dashmap::DashMap<u32, usize>,
+}
+
+impl HandlerQuotas {
+    /// 设置 handler 的限制，全部为 None 时移除
+    pub fn set(&self, handle: u32, quota: HandlerQuota) {
+        self.calls.remove(&handle);
+        if quota == HandlerQuota::default() {
+            self.quotas.remove(&handle);
+        } else {
+            self.quotas.insert(handle, quota);
+        }
+    }
+
+    /// handler 的限制
+    pub fn get(&self, handle: u32) -> HandlerQuota {
+        self.quotas
+            .get(&handle)
+            .map(|quota| *quota)
+            .unwrap_or_default()
+    }
+
+    /// 新的 exec 开始，调用次数清零
+    pub fn reset_calls(&self) {
+        self.calls.clear();
+    }
+
+    /// 检查请求大小并记入一次调用，超出限制时返回错误，被拒绝的调用不计数
+    pub fn acquire(&self, handle: u32, request_bytes: usize) -> Result<(), String> {
+        let quota = self.get(handle);
+        if let Some(max_request_bytes) = quota.max_request_bytes
+            && request_bytes > max_request_bytes
+        {
+            return Err(format!(
+                "request of {} bytes exceeds the limit of {} bytes",
+                request_bytes, max_request_bytes
+            ));
+        }
+        let Some(max_calls) = quota.max_calls_per_exec else {
+            return Ok(());
+        };
+        let mut calls = self.calls.entry(handle).or_default();
+        if *calls >= max_calls {
+            return Err(format!(
+                "called more than {} times in one execution",
+                max_calls
+            ));
+        }
+        *calls += 1;
+        Ok(())
+    }
+
+    /// 检查响应大小
+    pub fn check_response(&self, handle: u32, response_bytes: usize) -> Result<(), String> {
+        match self.get(handle).max_response_bytes {
+            Some(max_response_bytes) if response_bytes > max_response_bytes => Err(format!(
+                "response of {} bytes exceeds the limit of {} bytes",
+                response_bytes, max_response_bytes
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// 拒绝调用时返回给 guest 的响应，与 JSON-RPC handler 的异常响应格式相同
+pub fn rejected_response(handle: u32, name: Option<&str>, message: &str) -> Vec<u8> {
+    let target = match name {
+        Some(name) => format!("'{}'", name),
+        None => handle.to_string(),
+    };
+    serde_json::json!({
+        "exception": format!("QuotaError: Handler {}: {}", target, message),
+        "quota": {
+            "handle": handle,
+            "handler": name,
+        },
+    })
+    .to_string()
+    .into_bytes()
+}
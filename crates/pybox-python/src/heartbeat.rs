@@ -0,0 +1,103 @@
+//! heartbeat.rs exec 执行期间由 epoch 驱动的心跳回调
+//!
+//! 每隔 every 个 epoch 调用一次 `callback(env_id, elapsed, fuel_consumed)`，
+//! 回调抛出的异常会中止正在执行的 exec，可以用来实现自适应的超时策略；
+//! 被中止的 guest 状态不可信，exec 结束后由 reactor 恢复
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use pyo3::prelude::*;
+
+struct HeartbeatCallback {
+    callback: Py<PyAny>,
+    every: u64,
+}
+
+/// 正在执行的最外层 exec
+struct RunningExec {
+    env_id: Option<String>,
+    started: Instant,
+    fuel_before: u64,
+    ticks: u64,
+}
+
+#[derive(Default)]
+pub struct Heartbeat {
+    callback: Mutex<Option<HeartbeatCallback>>,
+    running: Mutex<Option<RunningExec>>,
+    /// 正在调用回调，回调期间不能重入 reactor
+    in_callback: AtomicBool,
+    /// 回调抛出异常中止了正在执行的 exec
+    aborted: AtomicBool,
+}
+
+impl Heartbeat {
+    /// 替换回调，返回之前是否已设置
+    pub fn set(&self, callback: Option<Py<PyAny>>, every: u64) -> bool {
+        let callback = callback.map(|callback| HeartbeatCallback {
+            callback,
+            every: every.max(1),
+        });
+        std::mem::replace(&mut *self.callback.lock().unwrap(), callback).is_some()
+    }
+
+    /// 最外层 exec 开始执行，fuel_before 为开始时剩余的 fuel
+    pub fn start(&self, env_id: Option<&str>, fuel_before: u64) {
+        self.aborted.store(false, Ordering::SeqCst);
+        *self.running.lock().unwrap() = Some(RunningExec {
+            env_id: env_id.map(str::to_string),
+            started: Instant::now(),
+            fuel_before,
+            ticks: 0,
+        });
+    }
+
+    /// 最外层 exec 结束，返回执行是否被回调中止
+    pub fn finish(&self) -> bool {
+        self.running.lock().unwrap().take();
+        self.aborted.swap(false, Ordering::SeqCst)
+    }
+
+    /// 是否正在调用回调
+    pub fn in_callback(&self) -> bool {
+        self.in_callback.load(Ordering::SeqCst)
+    }
+
+    /// 一个 epoch 到期，fuel 为当前剩余的 fuel，到达间隔时调用回调
+    pub fn tick(&self, py: Python<'_>, fuel: u64) -> PyResult<()> {
+        let Some((callback, every)) = self
+            .callback
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|callback| (callback.callback.clone_ref(py), callback.every))
+        else {
+            return Ok(());
+        };
+        let args = {
+            let mut running = self.running.lock().unwrap();
+            let Some(running) = running.as_mut() else {
+                return Ok(());
+            };
+            running.ticks += 1;
+            if !running.ticks.is_multiple_of(every) {
+                return Ok(());
+            }
+            (
+                running.env_id.clone(),
+                running.started.elapsed().as_secs_f64(),
+                running.fuel_before.saturating_sub(fuel),
+            )
+        };
+
+        self.in_callback.store(true, Ordering::SeqCst);
+        let result = callback.call1(py, args);
+        self.in_callback.store(false, Ordering::SeqCst);
+        if result.is_err() {
+            self.aborted.store(true, Ordering::SeqCst);
+        }
+        result.map(|_| ())
+    }
+}
@@ -0,0 +1,95 @@
+//! history.rs 按环境记录的 exec / assign / protect 操作
+//!
+//! 只记录最外层成功执行的操作（handler 内部嵌套的调用会在重放外层 exec 时再次发生），
+//! 在新的 reactor 上按顺序重放可以重建环境的状态，比内存快照更轻量，
+//! 但依赖执行结果是确定的
+//!
+//! 默认不记录，set_history 开启后每个环境最多保留 limit 条，超出时丢弃最早的操作
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use dashmap::DashMap;
+
+#[derive(Clone)]
+pub enum HistoryOp {
+    /// 传入 exec 的代码，重放时会再次经过 before 回调
+    Exec(String),
+    /// 变量名和 JSON 序列化后的值
+    Assign(String, String),
+    Protect(String),
+    /// 一次 unprotect 取消保护的变量名
+    Unprotect(Vec<String>),
+}
+
+#[derive(Default)]
+pub struct History {
+    /// 环境 ID -> 按顺序记录的操作，全局环境记为空字符串
+    envs: DashMap<String, VecDeque<HistoryOp>>,
+    /// 每个环境保留的操作数，0 表示不记录
+    limit: AtomicUsize,
+}
+
+impl History {
+    /// 设置每个环境保留的操作数，None 时停止记录并清空，返回之前是否在记录
+    pub fn set_limit(&self, limit: Option<usize>) -> bool {
+        let limit = limit.unwrap_or(0);
+        let previous = self.limit.swap(limit, Ordering::Relaxed);
+        if limit == 0 {
+            self.envs.clear();
+        } else {
+            for mut ops in self.envs.iter_mut() {
+                let excess = ops.len().saturating_sub(limit);
+                ops.drain(..excess);
+            }
+        }
+        previous != 0
+    }
+
+    /// 是否在记录，不记录时调用方可以跳过准备操作的开销
+    pub fn enabled(&self) -> bool {
+        self.limit.load(Ordering::Relaxed) != 0
+    }
+
+    pub fn push(&self, env_id: &str, op: HistoryOp) {
+        let limit = self.limit.load(Ordering::Relaxed);
+        if limit == 0 {
+            return;
+        }
+        let mut ops = self.envs.entry(env_id.to_string()).or_default();
+        if ops.len() >= limit {
+            ops.pop_front();
+        }
+        ops.push_back(op);
+    }
+
+    pub fn get(&self, env_id: &str) -> Vec<HistoryOp> {
+        self.envs
+            .get(env_id)
+            .map(|ops| ops.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// 清空环境的历史，返回之前是否有记录
+    pub fn clear(&self, env_id: &str) -> bool {
+        self.envs.remove(env_id).is_some()
+    }
+
+    /// 清空所有环境的历史
+    pub fn clear_all(&self) {
+        self.envs.clear();
+    }
+
+    /// 从其他环境复制的环境继承其历史
+    pub fn copy(&self, env_id: &str, from_env_id: &str) {
+        let ops = self.envs.get(from_env_id).map(|ops| ops.clone());
+        match ops {
+            Some(ops) if !ops.is_empty() => {
+                self.envs.insert(env_id.to_string(), ops);
+            }
+            _ => {
+                self.clear(env_id);
+            }
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! instance_state.rs 线性内存之外的实例状态：导出的可变全局变量和表
+//!
+//! guest 导出 shadow stack 指针 (__stack_pointer) 和函数表，只恢复内存时栈指针与内存不一致，
+//! 中途被终止的执行会留下错误的栈指针；旧版 guest 没有这些导出时状态为空，只能恢复内存
+
+use std::io::{Read, Write};
+
+use wasmtime::AsContextMut;
+
+/// 读取文件中长度字段给出的 len 字节，缓冲区随读到的数据增长，
+/// 损坏的长度字段超出剩余数据时返回 UnexpectedEof 而不是预先分配
+pub fn read_bytes(reader: impl Read, len: u64) -> std::io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.take(len).read_to_end(&mut buf)?;
+    if (buf.len() as u64) < len {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf)
+}
+
+/// 全局变量的值，只保存数值类型，浮点数按位保存
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum GlobalValue {
+    I32(i32),
+    I64(i64),
+    F32(u32),
+    F64(u64),
+}
+
+impl GlobalValue {
+    fn from_val(val: wasmtime::Val) -> Option<Self> {
+        match val {
+            wasmtime::Val::I32(value) => Some(Self::I32(value)),
+            wasmtime::Val::I64(value) => Some(Self::I64(value)),
+            wasmtime::Val::F32(bits) => Some(Self::F32(bits)),
+            wasmtime::Val::F64(bits) => Some(Self::F64(bits)),
+            _ => None,
+        }
+    }
+
+    fn to_val(self) -> wasmtime::Val {
+        match self {
+            Self::I32(value) => wasmtime::Val::I32(value),
+            Self::I64(value) => wasmtime::Val::I64(value),
+            Self::F32(bits) => wasmtime::Val::F32(bits),
+            Self::F64(bits) => wasmtime::Val::F64(bits),
+        }
+    }
+
+    /// (类型标记, 按位表示)
+    fn encode(self) -> (u8, u64) {
+        match self {
+            Self::I32(value) => (0, value as u32 as u64),
+            Self::I64(value) => (1, value as u64),
+            Self::F32(bits) => (2, bits as u64),
+            Self::F64(bits) => (3, bits),
+        }
+    }
+
+    fn decode(tag: u8, bits: u64) -> Option<Self> {
+        match tag {
+            0 => Some(Self::I32(bits as u32 as i32)),
+            1 => Some(Self::I64(bits as i64)),
+            2 => Some(Self::F32(bits as u32)),
+            3 => Some(Self::F64(bits)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct InstanceState {
+    /// 导出的可变全局变量
+    globals: Vec<(String, GlobalValue)>,
+    /// 导出的表的元素，元素只能恢复到创建它的 Store
+    tables: Vec<(String, Vec<wasmtime::Ref>)>,
+    /// 保存表的 Store 的编号，从文件读取时为 None
+    store_id: Option<u64>,
+}
+
+impl InstanceState {
+    /// 保存实例导出的可变全局变量和表，store_id 标识表的元素所属的 Store
+    pub fn capture(
+        instance: &wasmtime::Instance,
+        store_id: u64,
+        mut store: impl AsContextMut,
+    ) -> Self {
+        let exports: Vec<(String, wasmtime::Extern)> = instance
+            .exports(&mut store)
+            .map(|export| (export.name().to_string(), export.into_extern()))
+            .collect();
+        let mut state = Self {
+            store_id: Some(store_id),
+            ..Self::default()
+        };
+        for (name, export) in exports {
+            match export {
+                wasmtime::Extern::Global(global)
+                    if global.ty(&store).mutability() == wasmtime::Mutability::Var =>
+                {
+                    if let Some(value) = GlobalValue::from_val(global.get(&mut store)) {
+                        state.globals.push((name, value));
+                    }
+                }
+                wasmtime::Extern::Table(table) => {
+                    let elements = (0..table.size(&store))
+                        .filter_map(|index| table.get(&mut store, index))
+                        .collect();
+                    state.tables.push((name, elements));
+                }
+                _ => {}
+            }
+        }
+        state
+    }
+
+    /// 恢复全局变量和表
+    ///
+    /// 表的元素属于保存时的 Store，恢复到其他 Store 时跳过表；
+    /// Rust 编译的 guest 在实例化之后不会修改函数表，同一模块的实例的表相同。
+    /// 同一 Store 中增长或写入表失败时返回错误，不留下只恢复了一部分的表
+    pub fn restore(
+        &self,
+        instance: &wasmtime::Instance,
+        store_id: u64,
+        mut store: impl AsContextMut,
+    ) -> Result<(), String> {
+        for (name, value) in &self.globals {
+            let global = instance
+                .get_global(&mut store, name)
+                .ok_or_else(|| format!("Global '{}' not found", name))?;
+            global
+                .set(&mut store, value.to_val())
+                .map_err(|e| e.to_string())?;
+        }
+        if self.store_id != Some(store_id) {
+            return Ok(());
+        }
+        for (name, elements) in &self.tables {
+            let table = instance
+                .get_table(&mut store, name)
+                .ok_or_else(|| format!("Table '{}' not found", name))?;
+            let size = table.size(&store);
+            let len = elements.len() as u64;
+            if size < len {
+                let init = elements
+                    .last()
+                    .cloned()
+                    .unwrap_or(wasmtime::Ref::Func(None));
+                table
+                    .grow(&mut store, len - size, init)
+                    .map_err(|e| format!("Failed to grow table '{}': {}", name, e))?;
+            }
+            for (index, element) in elements.iter().enumerate() {
+                table
+                    .set(&mut store, index as u64, element.clone())
+                    .map_err(|e| format!("Failed to set table '{}' [{}]: {}", name, index, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 写入全局变量：u32 数量 | 每项 u32 名称长度、名称、u8 类型、u64 值，表不写入
+    pub fn write_globals(&self, mut writer: impl Write) -> std::io::Result<()> {
+        writer.write_all(&(self.globals.len() as u32).to_le_bytes())?;
+        for (name, value) in &self.globals {
+            let (tag, bits) = value.encode();
+            writer.write_all(&(name.len() as u32).to_le_bytes())?;
+            writer.write_all(name.as_bytes())?;
+            writer.write_all(&[tag])?;
+            writer.write_all(&bits.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// 读取 write_globals 写入的全局变量
+    pub fn read_globals(mut reader: impl Read) -> Result<Self, String> {
+        let read_u32 = |reader: &mut dyn Read| -> Result<u32, String> {
+            let mut buf = [0u8; 4];
+            reader.read_exact(&mut buf).map_err(|e| e.to_string())?;
+            Ok(u32::from_le_bytes(buf))
+        };
+        let count = read_u32(&mut reader)?;
+        let mut globals = Vec::new();
+        for _ in 0..count {
+            let len = read_u32(&mut reader)?;
+            let name = read_bytes(&mut reader, len as u64).map_err(|e| e.to_string())?;
+            let name = String::from_utf8(name).map_err(|e| e.to_string())?;
+            let mut tag = [0u8; 1];
+            reader.read_exact(&mut tag).map_err(|e| e.to_string())?;
+            let mut bits = [0u8; 8];
+            reader.read_exact(&mut bits).map_err(|e| e.to_string())?;
+            let value = GlobalValue::decode(tag[0], u64::from_le_bytes(bits))
+                .ok_or_else(|| format!("unknown global type {}", tag[0]))?;
+            globals.push((name, value));
+        }
+        Ok(Self {
+            globals,
+            tables: Vec::new(),
+            store_id: None,
+        })
+    }
+
+    /// 全局变量的数量
+    pub fn globals_len(&self) -> usize {
+        self.globals.len()
+    }
+}
@@ -0,0 +1,310 @@
+//! json.rs assign / retrieve / call 和 JSON-RPC 使用的 JSON 编解码，与 guest 端 json.rs 对应
+//!
+//! 标准 JSON 没有 NaN 和 Infinity，非有限浮点数按 `NonFinite` 处理，
+//! Tag 时编码为 `{"__pybox_float__": "nan" | "inf" | "-inf"}`，两端解码时还原为 float；
+//! 启用 big_ints 时超出 64 位的整数编码为 `{"__pybox_int__": "<十进制>"}`，两端解码时还原为 int；
+//! assign 将注册的自定义类型编码为 `{"__pybox_type__": "<类型名>", "value": ...}`，由 guest 端的解码器还原
+
+use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyDict, PyFloat, PyInt, PyList, PyString, PyTuple, PyType};
+
+/// 非有限浮点数的标记键，与 guest 端一致
+pub const FLOAT_TAG: &str = "__pybox_float__";
+/// 超出 64 位的整数的标记键，与 guest 端一致
+pub const INT_TAG: &str = "__pybox_int__";
+
+/// 自定义类型的标记键，与 guest 端一致
+pub const TYPE_TAG: &str = "__pybox_type__";
+
+/// assign 支持的自定义类型，见 PyBoxReactor.register_type
+pub struct TypeCodec {
+    pub cls: Py<PyType>,
+    /// 标记中的类型名
+    pub name: String,
+    /// host 端将值转换为可序列化的数据
+    pub encode: Py<PyAny>,
+    /// guest 端定义 `decode(data)` 的源码
+    pub decoder: String,
+}
+
+impl TypeCodec {
+    pub fn clone_ref(&self, py: Python<'_>) -> Self {
+        Self {
+            cls: self.cls.clone_ref(py),
+            name: self.name.clone(),
+            encode: self.encode.clone_ref(py),
+            decoder: self.decoder.clone(),
+        }
+    }
+}
+
+/// 递归地将 dict / list / tuple 中注册类型的值替换为带标记的对象
+pub fn tag_types<'py>(
+    value: &Bound<'py, PyAny>,
+    types: &[TypeCodec],
+) -> PyResult<Bound<'py, PyAny>> {
+    let py = value.py();
+    for codec in types {
+        if value.is_instance(codec.cls.bind(py))? {
+            let data = codec.encode.bind(py).call1((value,))?;
+            let tagged = PyDict::new(py);
+            tagged.set_item(TYPE_TAG, &codec.name)?;
+            tagged.set_item("value", tag_types(&data, types)?)?;
+            return Ok(tagged.into_any());
+        }
+    }
+    if let Ok(dict) = value.cast::<PyDict>() {
+        let replaced = PyDict::new(py);
+        for (key, item) in dict.iter() {
+            replaced.set_item(key, tag_types(&item, types)?)?;
+        }
+        return Ok(replaced.into_any());
+    }
+    if value.is_instance_of::<PyList>() || value.is_instance_of::<PyTuple>() {
+        let items = value
+            .try_iter()?
+            .map(|item| tag_types(&item?, types))
+            .collect::<PyResult<Vec<_>>>()?;
+        return Ok(PyList::new(py, items)?.into_any());
+    }
+    Ok(value.clone())
+}
+
+/// 非有限浮点数的处理方式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NonFinite {
+    /// 输出 NaN / Infinity，与 Python json 模块的默认行为相同
+    #[default]
+    Allow,
+    /// 抛出 ValueError
+    Error,
+    /// 输出 null
+    Null,
+    /// 输出带标记的对象
+    Tag,
+}
+
+impl NonFinite {
+    pub fn parse(name: &str) -> PyResult<Self> {
+        match name {
+            "allow" => Ok(Self::Allow),
+            "error" => Ok(Self::Error),
+            "null" => Ok(Self::Null),
+            "tag" => Ok(Self::Tag),
+            _ => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "non_finite must be 'allow', 'error', 'null' or 'tag', got {:?}",
+                name
+            ))),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Allow => "allow",
+            Self::Error => "error",
+            Self::Null => "null",
+            Self::Tag => "tag",
+        }
+    }
+}
+
+/// 编解码选项，guest 端通过 pybox_set_codec 设置相同的选项
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CodecOptions {
+    pub non_finite: NonFinite,
+    /// 标记超出 64 位的整数
+    pub big_ints: bool,
+}
+
+impl CodecOptions {
+    /// pybox_set_codec 的参数：低 2 位为 NonFinite，第 3 位为 big_ints
+    pub fn flags(&self) -> i32 {
+        let non_finite = match self.non_finite {
+            NonFinite::Allow => 0,
+            NonFinite::Error => 1,
+            NonFinite::Null => 2,
+            NonFinite::Tag => 3,
+        };
+        non_finite | if self.big_ints { 0b100 } else { 0 }
+    }
+
+    /// 编码前是否需要替换 value 中的数字
+    fn rewrites(&self) -> bool {
+        self.big_ints || self.rewrites_non_finite()
+    }
+
+    fn rewrites_non_finite(&self) -> bool {
+        matches!(self.non_finite, NonFinite::Null | NonFinite::Tag)
+    }
+}
+
+/// 按 options 将 value 编码为 JSON，default 与 json.dumps 的同名参数相同
+pub fn dumps(
+    py: Python<'_>,
+    value: &Bound<'_, PyAny>,
+    options: CodecOptions,
+    default: Option<&Bound<'_, PyAny>>,
+) -> PyResult<String> {
+    let kwargs = PyDict::new(py);
+    if let Some(default) = default {
+        kwargs.set_item("default", default)?;
+    }
+    if options.non_finite != NonFinite::Allow {
+        kwargs.set_item("allow_nan", false)?;
+    }
+    let value = if options.rewrites() {
+        replace_numbers(value, options)?
+    } else {
+        value.clone()
+    };
+    py.import("json")?
+        .getattr("dumps")?
+        .call((value,), Some(&kwargs))?
+        .extract()
+}
+
+/// 解码 JSON，还原带标记的非有限浮点数和大整数
+pub fn loads<'py>(py: Python<'py>, data: &[u8]) -> PyResult<Bound<'py, PyAny>> {
+    let text = std::str::from_utf8(data)
+        .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))?;
+    let value = py.import("json")?.getattr("loads")?.call1((text,))?;
+    if text.contains(FLOAT_TAG) || text.contains(INT_TAG) {
+        restore_tagged(&value)
+    } else {
+        Ok(value)
+    }
+}
+
+/// 递归替换 dict / list / tuple 中的非有限浮点数和大整数
+fn replace_numbers<'py>(
+    value: &Bound<'py, PyAny>,
+    options: CodecOptions,
+) -> PyResult<Bound<'py, PyAny>> {
+    let py = value.py();
+    if options.big_ints
+        && value.is_instance_of::<PyInt>()
+        && !value.is_instance_of::<PyBool>()
+        && value.extract::<i64>().is_err()
+    {
+        let tagged = PyDict::new(py);
+        tagged.set_item(INT_TAG, value.str()?)?;
+        return Ok(tagged.into_any());
+    }
+    if let Ok(float) = value.cast::<PyFloat>() {
+        let float = float.value();
+        if float.is_finite() || !options.rewrites_non_finite() {
+            return Ok(value.clone());
+        }
+        if options.non_finite == NonFinite::Null {
+            return Ok(py.None().into_bound(py));
+        }
+        let name = if float.is_nan() {
+            "nan"
+        } else if float > 0.0 {
+            "inf"
+        } else {
+            "-inf"
+        };
+        let tagged = PyDict::new(py);
+        tagged.set_item(FLOAT_TAG, name)?;
+        return Ok(tagged.into_any());
+    }
+    if let Ok(dict) = value.cast::<PyDict>() {
+        let replaced = PyDict::new(py);
+        for (key, item) in dict.iter() {
+            replaced.set_item(key, replace_numbers(&item, options)?)?;
+        }
+        return Ok(replaced.into_any());
+    }
+    if value.is_instance_of::<PyList>() || value.is_instance_of::<PyTuple>() {
+        let items = value
+            .try_iter()?
+            .map(|item| replace_numbers(&item?, options))
+            .collect::<PyResult<Vec<_>>>()?;
+        return Ok(PyList::new(py, items)?.into_any());
+    }
+    Ok(value.clone())
+}
+
+/// 递归将带标记的对象还原为 float / int
+fn restore_tagged<'py>(value: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyAny>> {
+    let py = value.py();
+    if let Ok(dict) = value.cast::<PyDict>() {
+        if dict.len() == 1
+            && let Some(digits) = dict.get_item(INT_TAG)?
+            && digits.is_instance_of::<PyString>()
+        {
+            return py.get_type::<PyInt>().call1((digits,));
+        }
+        if dict.len() == 1
+            && let Some(tag) = dict.get_item(FLOAT_TAG)?
+            && let Ok(tag) = tag.cast::<PyString>()
+        {
+            let float = match tag.to_str()? {
+                "nan" => f64::NAN,
+                "inf" => f64::INFINITY,
+                "-inf" => f64::NEG_INFINITY,
+                _ => return Ok(value.clone()),
+            };
+            return Ok(PyFloat::new(py, float).into_any());
+        }
+        for (key, item) in dict.iter() {
+            dict.set_item(key, restore_tagged(&item)?)?;
+        }
+        return Ok(value.clone());
+    }
+    if let Ok(list) = value.cast::<PyList>() {
+        for (index, item) in list.iter().enumerate() {
+            list.set_item(index, restore_tagged(&item)?)?;
+        }
+    }
+    Ok(value.clone())
+}
+
+/// Encode a value as JSON with the pybox codec
+///
+/// Non-finite floats are emitted as NaN / Infinity ("allow"), rejected with
+/// ValueError ("error"), emitted as null ("null") or emitted as
+/// `{"__pybox_float__": "nan"}` style objects that `decode_json` and the
+/// sandbox turn back into floats ("tag"). With big_ints, ints that do not fit
+/// in 64 bits are emitted as `{"__pybox_int__": "<digits>"}` so parsers that
+/// read numbers as doubles keep them exact.
+///
+/// Args:
+///     value: Value to encode
+///     non_finite: How to encode NaN and Infinity
+///     default: Called for objects that are not serializable, like json.dumps
+///     big_ints: Tag ints beyond 64 bits
+///
+/// Returns:
+///     str: The JSON text
+#[pyfunction]
+#[pyo3(signature = (value, non_finite="allow", default=None, big_ints=false))]
+pub fn encode_json(
+    py: Python<'_>,
+    value: &Bound<'_, PyAny>,
+    non_finite: &str,
+    default: Option<&Bound<'_, PyAny>>,
+    big_ints: bool,
+) -> PyResult<String> {
+    let options = CodecOptions {
+        non_finite: NonFinite::parse(non_finite)?,
+        big_ints,
+    };
+    dumps(py, value, options, default)
+}
+
+/// Decode JSON produced by the pybox codec, restoring tagged floats and ints
+///
+/// Args:
+///     data: JSON text as str or bytes
+///
+/// Returns:
+///     The decoded value
+#[pyfunction]
+pub fn decode_json<'py>(py: Python<'py>, data: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyAny>> {
+    match data.cast::<pyo3::types::PyBytes>() {
+        Ok(bytes) => loads(py, bytes.as_bytes()),
+        Err(_) => loads(py, data.extract::<String>()?.as_bytes()),
+    }
+}
@@ -1,13 +1,82 @@
+mod acl;
+mod audit;
+mod budget;
+mod builtin;
+mod compress;
+mod dirty_pages;
+mod engine;
+mod env_limit;
+mod error;
+mod exec_cache;
+mod exec_result;
+mod handler_context;
+mod handler_quota;
+mod heartbeat;
+mod history;
+mod instance_state;
+mod json;
+mod mapped_memory;
+mod memory_limit;
+mod middleware;
+mod output_filter;
+mod ratelimit;
 mod reactor;
 mod reactor_snapshot;
+mod reactor_view;
+mod recorder;
+mod redact;
+mod ring;
+mod sandbox_tool;
+mod stats;
+mod telemetry;
+mod watchdog;
 
 use pyo3::prelude::*;
 
 /// A Python module implemented in Rust.
 #[pymodule]
 fn pyboxcore(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<engine::PyBoxEngine>()?;
     m.add_class::<reactor::PyBoxReactor>()?;
     m.add_class::<reactor::PyBoxReactorCore>()?;
     m.add_class::<reactor_snapshot::PyBoxReactorSnapshot>()?;
+    m.add_class::<reactor_snapshot::PyBoxSnapshotGuard>()?;
+    m.add_class::<reactor_view::PyBoxView>()?;
+    m.add_class::<stats::PyBoxExecStats>()?;
+    m.add_class::<exec_result::PyBoxExecResult>()?;
+    m.add_class::<handler_context::PyBoxHandlerContext>()?;
+    m.add_class::<sandbox_tool::PyBoxSandboxTool>()?;
+    m.add_function(wrap_pyfunction!(json::encode_json, m)?)?;
+    m.add_function(wrap_pyfunction!(json::decode_json, m)?)?;
+    m.add("SandboxTool", m.getattr("PyBoxSandboxTool")?)?;
+    m.add(
+        "PyBoxQuotaError",
+        m.py().get_type::<error::PyBoxQuotaError>(),
+    )?;
+    m.add(
+        "PyBoxRateLimitError",
+        m.py().get_type::<error::PyBoxRateLimitError>(),
+    )?;
+    m.add(
+        "PyBoxEnvLimitError",
+        m.py().get_type::<error::PyBoxEnvLimitError>(),
+    )?;
+    m.add(
+        "PyBoxGuestError",
+        m.py().get_type::<error::PyBoxGuestError>(),
+    )?;
+    m.add(
+        "PyBoxTimeoutError",
+        m.py().get_type::<error::PyBoxTimeoutError>(),
+    )?;
+    m.add(
+        "PyBoxReplayError",
+        m.py().get_type::<error::PyBoxReplayError>(),
+    )?;
+    m.add("PyBoxLintError", m.py().get_type::<error::PyBoxLintError>())?;
+    m.add_class::<builtin::kv::PyBoxKVStore>()?;
+    m.add_class::<builtin::hostfs::PyBoxHostFS>()?;
+    m.add_class::<builtin::http::PyBoxHttp>()?;
+    m.add_class::<builtin::sql::PyBoxSQLite>()?;
     Ok(())
 }
@@ -0,0 +1,40 @@
+//! mapped_memory.rs 以 mmap 映射快照文件中未压缩的内存
+//!
+//! 多个 reactor 从同一个快照文件恢复时共享 page cache，文件只在恢复时被读取；
+//! 恢复将映射的内容拷贝到 guest 的线性内存，线性内存始终由 wasmtime 管理，恢复后不再依赖文件
+//!
+//! 映射在快照对象的生命周期内保留，文件不能被原地截断或改写（读取被截断的部分会 SIGBUS）；
+//! PyBoxReactorSnapshot.save 总是写入临时文件后 rename，被替换的旧文件在映射释放前仍然有效
+
+use std::fs::File;
+
+/// 快照文件中未压缩内存的对齐，不小于各平台的页大小
+pub const MAPPED_ALIGN: u64 = 64 * 1024;
+
+pub struct MappedMemory {
+    map: memmap2::Mmap,
+}
+
+impl MappedMemory {
+    /// 只读映射 file 中 offset 开始的 len 字节
+    pub fn new(file: File, offset: u64, len: usize) -> std::io::Result<Self> {
+        // SAFETY: 映射期间原地修改快照文件属于使用错误（save 以 rename 替换文件），只读的私有映射不会写回文件
+        let map = unsafe {
+            memmap2::MmapOptions::new()
+                .offset(offset)
+                .len(len)
+                .map(&file)?
+        };
+        Ok(Self { map })
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.map
+    }
+
+    /// 将快照拷贝到 memory，超出 memory 的部分被忽略
+    pub fn restore(&self, memory: &mut [u8]) {
+        let len = self.map.len().min(memory.len());
+        memory[..len].copy_from_slice(&self.map[..len]);
+    }
+}
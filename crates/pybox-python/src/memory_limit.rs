@@ -0,0 +1,57 @@
+//! memory_limit.rs guest 线性内存的上限
+//!
+//! wasmtime 在内存增长前通过 Store::limiter 询问上限，超过上限时以 PyBoxQuotaError 终止 guest 执行；
+//! 视图导出的 buffer 直接指向线性内存，存在期间增长失败 (memory.grow 返回 -1)，内存不会被移动
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::error::PyBoxQuotaError;
+
+/// 内存上限的字节数，0 表示不限制，由 reactor 和 limiter 共享
+pub type MemoryLimit = Arc<AtomicUsize>;
+
+/// 已导出、尚未释放的视图 buffer 数量，由 reactor 和 limiter 共享
+pub type ViewExports = Arc<AtomicUsize>;
+
+/// 保存在 Store 的数据中，Store::limiter 的回调返回它的可变引用
+pub struct MemoryLimiter {
+    limit: MemoryLimit,
+    views: ViewExports,
+}
+
+impl MemoryLimiter {
+    pub fn new(limit: MemoryLimit, views: ViewExports) -> Self {
+        Self { limit, views }
+    }
+}
+
+impl wasmtime::ResourceLimiter for MemoryLimiter {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> wasmtime::Result<bool> {
+        if self.views.load(Ordering::SeqCst) > 0 {
+            return Ok(false);
+        }
+        let limit = self.limit.load(Ordering::Relaxed);
+        if limit != 0 && desired > limit {
+            return Err(wasmtime::Error::from(PyBoxQuotaError::new_err(format!(
+                "Guest memory limit of {} bytes exceeded",
+                limit
+            ))));
+        }
+        Ok(maximum.is_none_or(|maximum| desired <= maximum))
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> wasmtime::Result<bool> {
+        Ok(maximum.is_none_or(|maximum| desired <= maximum))
+    }
+}
@@ -0,0 +1,219 @@
+//! middleware.rs exec 前后调用的 host 回调
+//!
+//! before 回调 `(env_id, code) -> code` 可以改写代码或抛出异常阻止执行，
+//! after 回调 `(env_id, result) -> result` 可以处理执行结果，返回 None 时保持不变；
+//! lint 规则 `(env_id, code, ast) -> diagnostics` 在运行前检查代码，报告错误时不运行；
+//! output 处理函数 `(stream, text) -> text` 改写返回给 host 的输出；
+//! prelude / teardown 是宿主配置的代码，在每次 exec 前后或环境创建、删除时执行
+
+use std::sync::Mutex;
+
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList, PyString};
+
+/// 宿主配置的代码
+struct HookCode {
+    code: String,
+    /// true 时在每次 exec 前后执行，否则在环境创建 / 删除时执行
+    per_exec: bool,
+}
+
+#[derive(Default)]
+pub struct ExecMiddleware {
+    before: Mutex<Vec<Py<PyAny>>>,
+    after: Mutex<Vec<Py<PyAny>>>,
+    lint: Mutex<Vec<Py<PyAny>>>,
+    output: Mutex<Vec<Py<PyAny>>>,
+    prelude: Mutex<Option<HookCode>>,
+    teardown: Mutex<Option<HookCode>>,
+}
+
+impl ExecMiddleware {
+    pub fn add_before(&self, callback: Py<PyAny>) {
+        self.before.lock().unwrap().push(callback);
+    }
+
+    pub fn add_after(&self, callback: Py<PyAny>) {
+        self.after.lock().unwrap().push(callback);
+    }
+
+    pub fn add_lint(&self, rule: Py<PyAny>) {
+        self.lint.lock().unwrap().push(rule);
+    }
+
+    pub fn has_lint(&self) -> bool {
+        !self.lint.lock().unwrap().is_empty()
+    }
+
+    pub fn add_output(&self, processor: Py<PyAny>) {
+        self.output.lock().unwrap().push(processor);
+    }
+
+    pub fn has_output(&self) -> bool {
+        !self.output.lock().unwrap().is_empty()
+    }
+
+    pub fn set_prelude(&self, code: Option<String>, per_exec: bool) {
+        *self.prelude.lock().unwrap() = code.map(|code| HookCode { code, per_exec });
+    }
+
+    pub fn set_teardown(&self, code: Option<String>, per_exec: bool) {
+        *self.teardown.lock().unwrap() = code.map(|code| HookCode { code, per_exec });
+    }
+
+    /// 指定时机要执行的 prelude
+    pub fn prelude(&self, per_exec: bool) -> Option<String> {
+        Self::hook_code(&self.prelude, per_exec)
+    }
+
+    /// 指定时机要执行的 teardown
+    pub fn teardown(&self, per_exec: bool) -> Option<String> {
+        Self::hook_code(&self.teardown, per_exec)
+    }
+
+    fn hook_code(hook: &Mutex<Option<HookCode>>, per_exec: bool) -> Option<String> {
+        hook.lock()
+            .unwrap()
+            .as_ref()
+            .filter(|hook| hook.per_exec == per_exec)
+            .map(|hook| hook.code.clone())
+    }
+
+    /// 移除回调，返回是否存在
+    pub fn remove(&self, py: Python<'_>, callback: &Bound<'_, PyAny>) -> bool {
+        let mut removed = false;
+        for callbacks in [&self.before, &self.after, &self.lint, &self.output] {
+            callbacks.lock().unwrap().retain(|cb| {
+                let found = cb.bind(py).is(callback);
+                removed |= found;
+                !found
+            });
+        }
+        removed
+    }
+
+    /// 复制回调列表，回调期间不持有锁，回调内部可以注册或移除回调
+    fn callbacks(py: Python<'_>, callbacks: &Mutex<Vec<Py<PyAny>>>) -> Vec<Py<PyAny>> {
+        callbacks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|cb| cb.clone_ref(py))
+            .collect()
+    }
+
+    /// 按注册顺序调用 before 回调，返回最终执行的代码
+    pub fn before(&self, py: Python<'_>, env_id: Option<&str>, code: &str) -> PyResult<String> {
+        let mut code = code.to_string();
+        for callback in Self::callbacks(py, &self.before) {
+            let ret = callback.call1(py, (env_id, &code))?;
+            if !ret.is_none(py) {
+                code = ret.extract(py)?;
+            }
+        }
+        Ok(code)
+    }
+
+    /// 按注册顺序调用 lint 规则，返回统一格式的诊断
+    /// `{"message", "line", "severity": "warning" | "error", "rule"}`，规则抛出异常时记为错误
+    pub fn lint<'py>(
+        &self,
+        py: Python<'py>,
+        env_id: Option<&str>,
+        code: &str,
+        tree: &Bound<'py, PyAny>,
+    ) -> PyResult<Bound<'py, PyList>> {
+        let diagnostics = PyList::empty(py);
+        for rule in Self::callbacks(py, &self.lint) {
+            let rule = rule.bind(py);
+            let name: Option<String> = rule.getattr("__name__").ok().and_then(|n| n.extract().ok());
+            // 返回 None 表示没有诊断
+            let items = rule.call1((env_id, code, tree)).and_then(|items| {
+                if items.is_none() {
+                    Ok(Vec::new())
+                } else {
+                    items.try_iter()?.collect::<PyResult<Vec<_>>>()
+                }
+            });
+            match items {
+                Ok(items) => {
+                    for item in items {
+                        diagnostics.append(Self::diagnostic(&item, name.as_deref())?)?;
+                    }
+                }
+                // 出错的规则不能放行代码
+                Err(err) => {
+                    let diagnostic = PyDict::new(py);
+                    diagnostic.set_item("message", format!("lint rule failed: {}", err))?;
+                    diagnostic.set_item("line", py.None())?;
+                    diagnostic.set_item("severity", "error")?;
+                    diagnostic.set_item("rule", name.as_deref())?;
+                    diagnostics.append(diagnostic)?;
+                }
+            }
+        }
+        Ok(diagnostics)
+    }
+
+    /// 规则返回的 str 或 dict 转换为统一格式
+    fn diagnostic<'py>(
+        item: &Bound<'py, PyAny>,
+        rule: Option<&str>,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let py = item.py();
+        let diagnostic = PyDict::new(py);
+        if item.is_instance_of::<PyString>() {
+            diagnostic.set_item("message", item)?;
+            diagnostic.set_item("line", py.None())?;
+            diagnostic.set_item("severity", "warning")?;
+            diagnostic.set_item("rule", rule)?;
+            return Ok(diagnostic);
+        }
+        let item = item.cast::<PyDict>()?;
+        let message = match item.get_item("message")? {
+            Some(message) => message.str()?.to_string(),
+            None => String::new(),
+        };
+        let severity = item
+            .get_item("severity")?
+            .is_some_and(|severity| severity.eq("error").unwrap_or(false));
+        diagnostic.set_item("message", message)?;
+        diagnostic.set_item("line", item.get_item("line")?)?;
+        diagnostic.set_item("severity", if severity { "error" } else { "warning" })?;
+        match item.get_item("rule")? {
+            Some(rule) => diagnostic.set_item("rule", rule)?,
+            None => diagnostic.set_item("rule", rule)?,
+        }
+        Ok(diagnostic)
+    }
+
+    /// 按注册顺序调用 after 回调，返回最终的执行结果
+    pub fn after(
+        &self,
+        py: Python<'_>,
+        env_id: Option<&str>,
+        mut result: Py<PyAny>,
+    ) -> PyResult<Py<PyAny>> {
+        for callback in Self::callbacks(py, &self.after) {
+            let ret = callback.call1(py, (env_id, result.clone_ref(py)))?;
+            if !ret.is_none(py) {
+                result = ret;
+            }
+        }
+        Ok(result)
+    }
+
+    /// 按注册顺序调用 output 处理函数改写一段输出，空输出不调用
+    pub fn output(&self, py: Python<'_>, stream: &str, text: &mut String) -> PyResult<()> {
+        if text.is_empty() {
+            return Ok(());
+        }
+        for processor in Self::callbacks(py, &self.output) {
+            let ret = processor.call1(py, (stream, text.as_str()))?;
+            *text = ret.extract(py).map_err(|_| {
+                pyo3::exceptions::PyTypeError::new_err("output processor must return str")
+            })?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+//! output_filter.rs 过滤 exec 捕获的输出
+//!
+//! guest 输出的终端转义序列会破坏 host 的日志查看器，`\r` 等控制字符可以伪造日志行；
+//! 过滤在 host 端进行，作用于 exec 结果的 output / stdout / stderr 和 on_output 收到的实时输出
+
+use std::borrow::Cow;
+
+const ESC: char = '\x1b';
+const BEL: char = '\x07';
+/// 8 位的 CSI，等同于 `ESC [`
+const CSI: char = '\u{9b}';
+
+/// reactor 的输出过滤选项
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutputFilter {
+    /// 删除 ANSI 转义序列 (CSI、OSC 和两字节的 ESC 序列)
+    pub strip_ansi: bool,
+    /// 将换行和制表符以外的控制字符转义为 `\xNN`
+    pub escape_control: bool,
+}
+
+impl OutputFilter {
+    /// 是否需要改写输出
+    pub fn enabled(&self) -> bool {
+        self.strip_ansi || self.escape_control
+    }
+
+    /// 过滤一段输出，未启用或没有需要处理的字符时不复制
+    pub fn apply<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if !self.enabled() || !text.chars().any(is_control) {
+            return Cow::Borrowed(text);
+        }
+        let mut output = String::with_capacity(text.len());
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            if self.strip_ansi && (c == ESC || c == CSI) {
+                skip_escape(c, &mut chars);
+                continue;
+            }
+            if self.escape_control && is_control(c) {
+                output.push_str(&format!("\\x{:02x}", c as u32));
+                continue;
+            }
+            output.push(c);
+        }
+        Cow::Owned(output)
+    }
+
+    /// 就地过滤
+    pub fn apply_in_place(&self, text: &mut String) {
+        if let Cow::Owned(filtered) = self.apply(text) {
+            *text = filtered;
+        }
+    }
+}
+
+/// 换行和制表符以外的 C0 / C1 控制字符
+fn is_control(c: char) -> bool {
+    c.is_control() && c != '\n' && c != '\t'
+}
+
+/// 跳过以 start 开始的转义序列，未结束的序列一直跳到输出末尾
+fn skip_escape(start: char, chars: &mut std::iter::Peekable<std::str::Chars<'_>>) {
+    let kind = match start {
+        CSI => '[',
+        _ => match chars.next() {
+            Some(kind) => kind,
+            None => return,
+        },
+    };
+    match kind {
+        // CSI: 参数和中间字节之后以 0x40..=0x7e 结束
+        '[' => {
+            for c in chars.by_ref() {
+                if ('\x40'..='\x7e').contains(&c) {
+                    break;
+                }
+            }
+        }
+        // OSC / DCS / APC / PM / SOS: 以 BEL 或 `ESC \` 结束
+        ']' | 'P' | '_' | '^' | 'X' => {
+            while let Some(c) = chars.next() {
+                if c == BEL {
+                    break;
+                }
+                if c == ESC && chars.peek() == Some(&'\\') {
+                    chars.next();
+                    break;
+                }
+            }
+        }
+        // 字符集选择等带一个中间字节的序列，如 `ESC ( B`
+        ' '..='/' => {
+            chars.next();
+        }
+        // 其余为两字节的序列，如 `ESC c`
+        _ => {}
+    }
+}
@@ -0,0 +1,92 @@
+//! ratelimit.rs 按环境限制 exec / call / assign 的调用频率和嵌套深度
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// 令牌桶，每次调用消耗一个令牌
+struct Bucket {
+    /// 每秒补充的令牌数，None 表示不限制频率
+    calls_per_second: Option<f64>,
+    /// 桶容量，允许的突发调用数
+    burst: f64,
+    /// 同一环境在调用栈中同时存在的最大层数
+    max_depth: Option<usize>,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        if let Some(rate) = self.calls_per_second {
+            let elapsed = now.duration_since(self.last).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate).min(self.burst);
+        }
+        self.last = now;
+    }
+}
+
+/// env id -> 限制，全局环境记为空字符串
+#[derive(Default)]
+pub struct RateLimits {
+    envs: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimits {
+    /// 设置环境的限制，burst 默认为每秒的调用数（至少为 1）
+    pub fn set(
+        &self,
+        env_id: &str,
+        calls_per_second: Option<f64>,
+        burst: Option<u32>,
+        max_depth: Option<usize>,
+    ) -> Result<(), String> {
+        if calls_per_second.is_some_and(|rate| !(rate > 0.0)) {
+            return Err("calls_per_second must be positive".to_string());
+        }
+        let burst = match burst {
+            Some(burst) => burst as f64,
+            None => calls_per_second.map_or(1.0, |rate| rate.ceil().max(1.0)),
+        };
+        let bucket = Bucket {
+            calls_per_second,
+            burst,
+            max_depth,
+            tokens: burst,
+            last: Instant::now(),
+        };
+        self.envs.lock().unwrap().insert(env_id.to_string(), bucket);
+        Ok(())
+    }
+
+    /// 取消环境的限制
+    pub fn clear(&self, env_id: &str) -> bool {
+        self.envs.lock().unwrap().remove(env_id).is_some()
+    }
+
+    /// 记入一次调用，超出限制时返回错误
+    /// * `depth` 环境当前在调用栈中的层数
+    pub fn acquire(&self, env_id: &str, depth: usize) -> Result<(), String> {
+        let mut envs = self.envs.lock().unwrap();
+        let Some(bucket) = envs.get_mut(env_id) else {
+            return Ok(());
+        };
+        if let Some(max_depth) = bucket.max_depth
+            && depth >= max_depth
+        {
+            return Err(format!(
+                "Environment '{}' exceeded the nested call depth of {}",
+                env_id, max_depth
+            ));
+        }
+        if bucket.calls_per_second.is_some() {
+            bucket.refill();
+            if bucket.tokens < 1.0 {
+                return Err(format!("Environment '{}' exceeded its rate limit", env_id));
+            }
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
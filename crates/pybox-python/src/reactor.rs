@@ -14,6 +14,28 @@ type WasmSize = u32;
 /// WASM ioctl handle ID
 type HandleId = u32;
 
+/// Store 的数据：WASI 上下文和内存上限，Store::limiter 的回调从这里返回 limiter
+pub struct StoreData {
+    wasi: WasiP1Ctx,
+    limiter: MemoryLimiter,
+    /// 进程内唯一的 Store 编号，快照中的表元素只能恢复到编号相同的 Store
+    id: u64,
+}
+
+/// 分配 StoreData::id
+static STORE_IDS: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+// 系统 handle 与 pybox-host 共用同一份定义；
+// 其余 handle 中 COMPRESSED_HANDLE_FLAG 位标记分帧压缩的请求，handler 只能使用更小的 handle
+use pybox_host::protocol::{
+    BUDGET_HANDLE, CAPS_HANDLE, MEMORY_HANDLE, OUTPUT_HANDLE, RESOLVE_HANDLE, RING_HANDLE,
+    STREAM_HANDLE, SYSTEM_HANDLE_BASE,
+};
+/// 环形缓冲区数据区的最小大小
+const MIN_RING_CAPACITY: WasmSize = 16;
+/// 模板环境 id 的前缀，模板环境只用于复制，不直接执行代码
+const TEMPLATE_ENV_PREFIX: &str = "__pybox_template__:";
+
 /// WASM 端的 pybox_bytes 结构（仅用于文档）
 #[allow(dead_code)]
 #[repr(C, packed)]
@@ -34,7 +56,7 @@ impl IoctlPacket {
     /// 从 WASM 内存中读取 IoctlPacket
     fn read_from_memory(
         memory: &wasmtime::Memory,
-        caller: &wasmtime::Caller<'_, WasiP1Ctx>,
+        caller: &wasmtime::Caller<'_, StoreData>,
         ptr: WasmPtr,
     ) -> Result<Self, String> {
         let memory_data = memory.data(caller);
@@ -67,7 +89,7 @@ impl IoctlPacket {
     fn write_to_memory(
         &self,
         memory: &wasmtime::Memory,
-        caller: &mut wasmtime::Caller<'_, WasiP1Ctx>,
+        caller: &mut wasmtime::Caller<'_, StoreData>,
         ptr: WasmPtr,
     ) -> Result<(), String> {
         let memory_data = memory.data_mut(caller);
@@ -122,21 +144,201 @@ impl ModuleCacheKey {
 static MODULE_CACHES: std::sync::LazyLock<dashmap::DashMap<ModuleCacheKey, Arc<wasmtime::Module>>> =
     std::sync::LazyLock::new(|| dashmap::DashMap::new());
 
-static DEFAULT_ENGINE: std::sync::LazyLock<Arc<wasmtime::Engine>> =
-    std::sync::LazyLock::new(|| {
-        let mut config = wasmtime::Config::new();
-        // 启用编译缓存
-        config.cache_config_load_default().unwrap();
-        Arc::new(wasmtime::Engine::new(&config).unwrap())
-    });
-
+/// 模块文件内容的 sha256，快照据此判断是否来自同一个 wasm
+static MODULE_HASHES: std::sync::LazyLock<dashmap::DashMap<ModuleCacheKey, String>> =
+    std::sync::LazyLock::new(dashmap::DashMap::new);
+
+/// wasm 文件旁预先初始化的快照，按快照路径缓存，同一文件的 reactor 共享映射的内存；
+/// 同时记录文件的修改时间和大小，文件被替换后重新读取
+static PREWARM_SNAPSHOTS: std::sync::LazyLock<
+    dashmap::DashMap<std::path::PathBuf, (PrewarmStamp, Py<PyBoxReactorSnapshot>)>,
+> = std::sync::LazyLock::new(dashmap::DashMap::new);
+
+/// 预初始化快照文件的 (修改时间, 大小)
+type PrewarmStamp = (Option<std::time::SystemTime>, u64);
+
+/// 以 json 输出环境中的变量，模块按名称记录，json 往返后不相等的变量列在 lost 中
+const EXPORT_ENV_CODE: &str = r#"
+def __pybox_export_env():
+    import json, types
+    state, modules, lost = {}, {}, []
+    for name, value in list(globals().items()):
+        if name.startswith('__'):
+            continue
+        if isinstance(value, types.ModuleType):
+            modules[name] = value.__name__
+            continue
+        try:
+            if json.loads(json.dumps(value)) != value:
+                raise ValueError(name)
+        except Exception:
+            lost.append(name)
+            continue
+        state[name] = value
+    return json.dumps({'state': state, 'modules': modules, 'lost': sorted(lost)})
+print(__pybox_export_env())
+del __pybox_export_env
+"#;
+
+/// 将 EXPORT_ENV_CODE 导出的变量写回环境，重新导入记录的模块
+const IMPORT_ENV_CODE: &str = r#"
+def __pybox_import_env(data):
+    import importlib, json
+    data = json.loads(data)
+    globals().update(data['state'])
+    for name, module in data['modules'].items():
+        globals()[name] = importlib.import_module(module)
+"#;
+
+/// Store 的 epoch 回调持有的 core 引用数，实例化后 ioctl 函数再持有一个
+const STORE_CORE_REFS: usize = 1;
+
+/// calibrate 运行标准负载的临时环境，与已有环境同名时加数字后缀
+const CALIBRATE_ENV: &str = "__pybox_calibrate__";
+/// calibrate 的标准负载：循环、算术、字符串、list / dict 操作和函数调用
+const CALIBRATE_CODE: &str = r#"
+def __pybox_calibrate():
+    def step(i):
+        return (i * 7 + 3) % 101
+    total = 0
+    items = []
+    table = {}
+    for i in range(2000):
+        total += step(i)
+        items.append(str(i))
+        table[i % 97] = total
+    ",".join(items).split(",")
+    sorted(table.values(), reverse=True)
+    return total
+__pybox_calibrate()
+"#;
+/// calibrate 测量时运行标准负载的默认次数，第一次作为预热不计入
+const CALIBRATE_ROUNDS: usize = 5;
+
+use pybox_host::COPY_ENV_CODE;
 use pyo3::prelude::*;
-use pyo3::types::{PyBytes, PyBytesMethods};
+use pyo3::types::{PyBytes, PyBytesMethods, PyDict, PyList, PyString, PyType};
+use wasmtime::AsContextMut;
+
+use crate::acl::{self, HandlerAcls};
+use crate::audit::AuditLog;
+use crate::budget::FuelBudgets;
+use crate::builtin::{self, NativeHandler};
+use crate::compress::{self, COMPRESSED_HANDLE_FLAG};
+use crate::dirty_pages::DirtyTracker;
+use crate::engine::{DEFAULT_ENGINE, PyBoxEngine};
+use crate::env_limit::{self, EnvLimits};
+use crate::error::{
+    PyBoxEnvLimitError, PyBoxQuotaError, PyBoxRateLimitError, PyBoxReplayError, PyBoxTimeoutError,
+};
+use crate::exec_cache::{self, ExecCache};
+use crate::exec_result::{ExecLimits, PyBoxExecResult, text_prefix};
+use crate::handler_context::PyBoxHandlerContext;
+use crate::handler_quota::{self, HandlerQuota, HandlerQuotas};
+use crate::heartbeat::Heartbeat;
+use crate::history::{History, HistoryOp};
+use crate::instance_state::InstanceState;
+use crate::json::{self, CodecOptions, NonFinite};
+use crate::memory_limit::{MemoryLimit, MemoryLimiter, ViewExports};
+use crate::middleware::ExecMiddleware;
+use crate::output_filter::OutputFilter;
+use crate::ratelimit::RateLimits;
+use crate::reactor_snapshot::PyBoxReactorSnapshot;
+use crate::reactor_view::PyBoxView;
+use crate::recorder::IoctlRecorder;
+use crate::redact::SharedRedactor;
+use crate::ring::{RING_HEADER_SIZE, Ring};
+use crate::stats::{PyBoxExecStats, ReactorCounters};
+use crate::telemetry::{self, Span, Tracer};
+use crate::watchdog::Watchdog;
+
+/// 已注册的 handler
+enum Handler {
+    /// Python 可调用对象，接受 bytes 返回 bytes
+    Python(Py<PyAny>),
+    /// 参数中有 ctx 的 Python 可调用对象，以 `func(data, ctx=PyBoxHandlerContext)` 调用
+    PythonWithContext(Py<PyAny>),
+    /// Rust 实现的内置 handler，不经过 Python 回调
+    Native(Arc<dyn NativeHandler>),
+}
+
+impl Handler {
+    fn clone_ref(&self, py: Python<'_>) -> Self {
+        match self {
+            Handler::Python(func) => Handler::Python(func.clone_ref(py)),
+            Handler::PythonWithContext(func) => Handler::PythonWithContext(func.clone_ref(py)),
+            Handler::Native(native) => Handler::Native(Arc::clone(native)),
+        }
+    }
+}
+
+/// 正在执行 handler 的 wasmtime Caller
+/// handler 内部重入 guest 时必须复用这个 Caller 的上下文, 不能再取外层 Store 的可变引用
+struct ActiveCaller(*mut wasmtime::Caller<'static, StoreData>);
+
+/// Caller 只会在持有它的线程上使用 (safe_access 保证)
+unsafe impl Send for ActiveCaller {}
+
+/// handler 返回时弹出 ActiveCaller
+struct ActiveCallerGuard<'a> {
+    core: &'a PyBoxReactorCore,
+}
+
+impl Drop for ActiveCallerGuard<'_> {
+    fn drop(&mut self) {
+        self.core.callers.lock().unwrap().pop();
+    }
+}
+
+/// exec 的调用参数，guest 在开始执行代码时读取
+#[derive(Default)]
+struct ExecOptions {
+    /// 实时输出回调
+    on_output: Option<Py<PyAny>>,
+    /// 输出的最大字节数
+    max_output: Option<usize>,
+    /// 最多附加的 artifact 数量
+    max_artifacts: Option<usize>,
+    /// artifact 数据的最大总字节数
+    max_artifact_bytes: Option<usize>,
+    /// 随 guest 的 JSON-RPC 请求发送的关联 id
+    correlation_id: Option<String>,
+    /// 代码抛出异常时回滚环境
+    transactional: bool,
+    /// 已经交给实时输出回调的字节数，host 按 max_output 截断，不依赖 guest 端的限制
+    output_sent: usize,
+}
+
+/// 一次 exec 在 guest 端的结果
+#[derive(Default)]
+struct GuestExec {
+    /// 合并的输出
+    output: String,
+    /// pybox_exec_report 导出的 json 结果
+    report: Option<Vec<u8>>,
+    compile_ns: Option<u64>,
+    run_ns: Option<u64>,
+    /// 代码是否抛出了异常
+    raised: bool,
+}
+
+/// exec / call 返回时弹出当前环境
+struct ActiveEnvGuard<'a> {
+    core: &'a PyBoxReactorCore,
+}
+
+impl Drop for ActiveEnvGuard<'_> {
+    fn drop(&mut self) {
+        self.core.envs.lock().unwrap().pop();
+    }
+}
 
 #[pyclass]
 #[derive(Default)]
 pub struct PyBoxReactorCore {
-    handlers: dashmap::DashMap<HandleId, Py<PyAny>>,
+    handlers: dashmap::DashMap<HandleId, Handler>,
+    /// handler 名称 -> handle，guest 通过 RESOLVE_HANDLE 查询
+    names: dashmap::DashMap<String, HandleId>,
     alloc_mem: std::sync::OnceLock<wasmtime::TypedFunc<WasmSize, WasmPtr>>,
     free_mem: std::sync::OnceLock<wasmtime::TypedFunc<WasmPtr, ()>>,
     init_local: std::sync::OnceLock<wasmtime::TypedFunc<WasmPtr, i32>>,
@@ -145,28 +347,309 @@ pub struct PyBoxReactorCore {
     assign: std::sync::OnceLock<wasmtime::TypedFunc<(WasmPtr, WasmPtr, WasmPtr, WasmPtr), i32>>,
     protect: std::sync::OnceLock<wasmtime::TypedFunc<(WasmPtr, WasmPtr), i32>>,
     exec: std::sync::OnceLock<wasmtime::TypedFunc<(WasmPtr, WasmPtr, WasmPtr, WasmPtr), i32>>,
+    retrieve: std::sync::OnceLock<wasmtime::TypedFunc<(WasmPtr, WasmPtr, WasmPtr, WasmPtr), i32>>,
+    call: std::sync::OnceLock<
+        wasmtime::TypedFunc<(WasmPtr, WasmPtr, WasmPtr, WasmPtr, WasmPtr), i32>,
+    >,
+    buffer_alloc: std::sync::OnceLock<wasmtime::TypedFunc<WasmSize, WasmPtr>>,
+    assign_buffer: std::sync::OnceLock<
+        wasmtime::TypedFunc<(WasmPtr, WasmPtr, WasmPtr, WasmSize, i32, WasmPtr), i32>,
+    >,
+    /// 释放 guest 中没有交付的 buffer_alloc 缓冲区，旧版 guest 没有
+    buffer_release: std::sync::OnceLock<wasmtime::TypedFunc<(), ()>>,
+    exec_compile_ns: std::sync::OnceLock<wasmtime::TypedFunc<(), u64>>,
+    exec_run_ns: std::sync::OnceLock<wasmtime::TypedFunc<(), u64>>,
+    exec_raised: std::sync::OnceLock<wasmtime::TypedFunc<(), i32>>,
+    exec_report: std::sync::OnceLock<wasmtime::TypedFunc<WasmPtr, i32>>,
+    interrupt: std::sync::OnceLock<wasmtime::TypedFunc<(), i32>>,
+    set_output_stream: std::sync::OnceLock<wasmtime::TypedFunc<i32, ()>>,
+    set_exec_limits: std::sync::OnceLock<wasmtime::TypedFunc<(i64, i64, i64), ()>>,
+    set_exec_flags: std::sync::OnceLock<wasmtime::TypedFunc<i32, ()>>,
+    set_correlation_id: std::sync::OnceLock<wasmtime::TypedFunc<WasmPtr, ()>>,
+    set_codec: std::sync::OnceLock<wasmtime::TypedFunc<i32, ()>>,
+    view_acquire:
+        std::sync::OnceLock<wasmtime::TypedFunc<(WasmPtr, WasmPtr, WasmPtr, WasmPtr), i32>>,
+    view_release: std::sync::OnceLock<wasmtime::TypedFunc<u32, i32>>,
+    check: std::sync::OnceLock<wasmtime::TypedFunc<(WasmPtr, WasmPtr), i32>>,
+    protection_stats: std::sync::OnceLock<wasmtime::TypedFunc<(WasmPtr, WasmPtr), i32>>,
+    sanitizer_report: std::sync::OnceLock<wasmtime::TypedFunc<WasmPtr, i32>>,
+    protect_all: std::sync::OnceLock<wasmtime::TypedFunc<(WasmPtr, WasmPtr), i32>>,
+    unprotect: std::sync::OnceLock<wasmtime::TypedFunc<(WasmPtr, WasmPtr), i32>>,
+    protected_keys: std::sync::OnceLock<wasmtime::TypedFunc<(WasmPtr, WasmPtr), i32>>,
+    set_local_encoding:
+        std::sync::OnceLock<wasmtime::TypedFunc<(WasmPtr, WasmPtr, WasmPtr, WasmPtr), i32>>,
+    ast: std::sync::OnceLock<wasmtime::TypedFunc<(WasmPtr, WasmPtr, WasmPtr), i32>>,
+    gc: std::sync::OnceLock<wasmtime::TypedFunc<WasmPtr, i32>>,
+    set_max_variables: std::sync::OnceLock<wasmtime::TypedFunc<(WasmPtr, i64), i32>>,
     memory: std::sync::OnceLock<wasmtime::Memory>,
     instance: std::sync::OnceLock<wasmtime::Instance>,
+    /// 正在执行的 handler 栈, 栈深即重入深度
+    callers: std::sync::Mutex<Vec<ActiveCaller>>,
+    /// 正在执行代码的环境栈, 内置 handler 按栈顶环境划分数据
+    envs: std::sync::Mutex<Vec<String>>,
+    /// 超过该大小的负载使用 zstd 压缩，None 表示不支持压缩
+    compress_threshold: Option<usize>,
+    /// 未读完的流式响应：(环境, stream id) -> Python 迭代器，只有创建它的环境可以拉取
+    streams: dashmap::DashMap<(String, u32), Py<PyAny>>,
+    next_stream_id: std::sync::atomic::AtomicU32,
+    /// 按环境累计的 fuel 消耗和预算
+    budgets: FuelBudgets,
+    /// 引擎是否打开了 fuel 计量，关闭时不能设置预算，统计中的 fuel 为 0
+    fuel: bool,
+    /// 按环境限制调用频率和嵌套深度
+    rate_limits: RateLimits,
+    /// 按环境限制可以调用的 handler
+    acls: HandlerAcls,
+    /// handler 的请求 / 响应大小和每次 exec 的调用次数限制
+    handler_quotas: HandlerQuotas,
+    /// guest 发起的 ioctl 调用总数
+    ioctl_calls: std::sync::atomic::AtomicU64,
+    /// 创建以来累计的 exec 和 handler 计数
+    counters: ReactorCounters,
+    /// 环形缓冲区：名称 -> (guest 地址, 数据区大小)，guest 通过 RING_HANDLE 查询
+    rings: dashmap::DashMap<String, (WasmPtr, WasmSize)>,
+    /// 可选的 OpenTelemetry tracer
+    tracer: Tracer,
+    /// 可选的审计日志
+    audit: AuditLog,
+    /// 模板名称 -> 需要在副本中重新保护的变量
+    templates: dashmap::DashMap<String, Vec<String>>,
+    /// exec 前后调用的回调
+    middleware: ExecMiddleware,
+    /// 已在 guest 中抛出 KeyboardInterrupt，本次调用结束前再次中断时直接终止执行
+    interrupted: std::sync::atomic::AtomicBool,
+    /// 其他线程调用 cancel() 请求停止正在执行的代码，在下一个 epoch 按 Ctrl-C 处理
+    cancel_requested: std::sync::atomic::AtomicBool,
+    /// 正在执行的 exec 的参数，嵌套的 exec 在栈顶
+    exec_options: std::sync::Mutex<Vec<ExecOptions>>,
+    /// exec 执行期间的心跳回调
+    heartbeat: Heartbeat,
+    /// exec 超时后从快照恢复
+    watchdog: Watchdog,
+    /// 已创建的局部环境，trim 时按此重建
+    local_envs: dashmap::DashSet<String>,
+    /// 局部环境 -> 已保护的变量，trim 后重新保护
+    protected: dashmap::DashMap<String, Vec<String>>,
+    /// 局部环境 -> 创建时设置的 (编码, 错误处理)，trim 后重新设置
+    encodings: dashmap::DashMap<String, (String, String)>,
+    /// 局部环境 -> (每隔多少次 exec 回收一次, 上次回收后的 exec 次数)
+    gc_policies: dashmap::DashMap<String, (u32, u32)>,
+    /// 局部环境 -> 变量数量和单个值大小的上限
+    env_limits: dashmap::DashMap<String, EnvLimits>,
+    /// 已导出、尚未释放的视图 buffer 数量，与 limiter 共享，期间 guest 内存不能增长
+    view_exports: ViewExports,
+    /// guest 线性内存的上限
+    memory_limit: MemoryLimit,
+    /// 记录或回放 handler 的请求 / 响应
+    recorder: IoctlRecorder,
+    /// 按环境记录的 exec / assign / protect 操作
+    history: History,
+    /// assign / retrieve / call 和 JSON-RPC 的 JSON 编解码选项
+    codec: std::sync::Mutex<CodecOptions>,
+    /// assign 支持的自定义类型，按注册顺序匹配
+    types: std::sync::Mutex<Vec<json::TypeCodec>>,
+    /// exec 输出返回给 host 前的过滤选项
+    output_filter: std::sync::Mutex<OutputFilter>,
+    /// 标记为 cache 的 exec 的结果
+    exec_cache: ExecCache,
+    /// 快照 update 使用的脏页跟踪，enable_dirty_tracking 开启
+    dirty_tracker: std::sync::OnceLock<DirtyTracker>,
+    /// 输出中需要替换为 [REDACTED] 的密钥
+    redactor: SharedRedactor,
 }
 
 impl PyBoxReactorCore {
-    /// 注册一个 Python handler
+    /// 注册一个 handler
     /// handle: handler 的 ID
-    /// func: Python 可调用对象，接受 bytes 参数，返回 bytes
-    fn register_handler(&self, handle: HandleId, func: Py<PyAny>) {
+    /// func: Python handler 或内置 handler
+    /// name: 可选的 handler 名称
+    fn register_handler(&self, handle: HandleId, func: Handler, name: Option<String>) {
         self.handlers.insert(handle, func);
+        if let Some(name) = name {
+            self.names.insert(name, handle);
+        }
     }
 
-    /// 取消注册一个 handler
+    /// 取消注册一个 handler（同时移除它的名称）
     fn unregister_handler(&self, handle: HandleId) -> bool {
+        self.names.retain(|_, h| *h != handle);
+        self.handler_quotas.set(handle, HandlerQuota::default());
         self.handlers.remove(&handle).is_some()
     }
+
+    /// handle 注册时使用的名称
+    fn handler_name(&self, handle: HandleId) -> Option<String> {
+        self.names
+            .iter()
+            .find(|entry| *entry.value() == handle)
+            .map(|entry| entry.key().clone())
+    }
+
+    /// 处理系统 handle 的请求，返回 None 表示失败
+    fn handle_system_request(&self, handle: HandleId, req: &[u8]) -> Option<Vec<u8>> {
+        match handle {
+            RESOLVE_HANDLE => {
+                let name = std::str::from_utf8(req).ok()?;
+                let handle = *self.names.get(name)?;
+                Some(handle.to_string().into_bytes())
+            }
+            CAPS_HANDLE => {
+                let threshold = self.compress_threshold?;
+                Some(format!("compress=zstd\ncompress_threshold={}", threshold).into_bytes())
+            }
+            RING_HANDLE => {
+                let name = std::str::from_utf8(req).ok()?;
+                let (ptr, _) = *self.rings.get(name)?;
+                Some(ptr.to_string().into_bytes())
+            }
+            MEMORY_HANDLE => {
+                let limit = self.memory_limit.load(std::sync::atomic::Ordering::Relaxed);
+                Some(match limit {
+                    0 => Vec::new(),
+                    limit => limit.to_string().into_bytes(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// 进入 handler, 记录当前 Caller 供 handler 内部重入使用
+    fn enter_handler(&self, caller: &mut wasmtime::Caller<'_, StoreData>) -> ActiveCallerGuard<'_> {
+        let ptr = caller as *mut wasmtime::Caller<'_, StoreData>
+            as *mut wasmtime::Caller<'static, StoreData>;
+        self.callers.lock().unwrap().push(ActiveCaller(ptr));
+        ActiveCallerGuard { core: self }
+    }
+
+    /// 最内层正在执行的 handler 的 Caller
+    fn active_caller(&self) -> Option<*mut wasmtime::Caller<'static, StoreData>> {
+        self.callers.lock().unwrap().last().map(|caller| caller.0)
+    }
+
+    /// 当前 handler 重入深度
+    fn call_depth(&self) -> usize {
+        self.callers.lock().unwrap().len()
+    }
+
+    /// 进入环境执行代码，全局环境记为空字符串
+    fn enter_env(&self, env_id: Option<&str>) -> ActiveEnvGuard<'_> {
+        let env_id = env_id.unwrap_or_default();
+        for handler in self.handlers.iter() {
+            if let Handler::Native(native) = handler.value() {
+                native.begin_exec(env_id);
+            }
+        }
+        self.envs.lock().unwrap().push(env_id.to_string());
+        ActiveEnvGuard { core: self }
+    }
+
+    /// 当前正在执行代码的环境
+    fn active_env(&self) -> String {
+        self.envs
+            .lock()
+            .unwrap()
+            .last()
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// 检查环境的调用频率和嵌套深度，超出限制时返回 PyBoxRateLimitError
+    fn check_rate_limit(&self, env_id: &str) -> PyResult<()> {
+        let depth = self
+            .envs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|env| *env == env_id)
+            .count();
+        self.rate_limits
+            .acquire(env_id, depth)
+            .map_err(PyBoxRateLimitError::new_err)
+    }
+
+    /// 最外层的 exec / call 进入 guest 前开始计量 fuel，预算耗尽时返回错误
+    /// 嵌套调用消耗的 fuel 计入最外层的环境，返回 None
+    fn start_metering(
+        &self,
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+        env_id: &str,
+    ) -> PyResult<Option<u64>> {
+        if self.call_depth() > 0 || !self.fuel {
+            return Ok(None);
+        }
+        let limit = self
+            .budgets
+            .limit(env_id)
+            .map_err(PyBoxQuotaError::new_err)?;
+        ctx.as_context_mut()
+            .set_fuel(limit)
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+        Ok(Some(limit))
+    }
+
+    /// guest 被强制终止后将内存、全局变量和表恢复到 watchdog 的快照，
+    /// 返回是否恢复，未启用 watchdog 时返回 false
+    fn restore_watchdog(
+        &self,
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+    ) -> PyResult<bool> {
+        let Some(memory) = self.get_memory() else {
+            return Ok(false);
+        };
+        let Some(state) = self.watchdog.restore(memory.data_mut(&mut ctx)) else {
+            return Ok(false);
+        };
+        self.restore_state(&mut ctx, &state)
+            .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+        Ok(true)
+    }
+
+    /// 引擎没有打开 fuel 计量时，需要 fuel 的操作返回错误
+    fn require_fuel(&self, operation: &str) -> PyResult<()> {
+        match self.fuel {
+            true => Ok(()),
+            false => Err(pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "{} needs an engine that counts fuel, create the reactor with PyBoxEngine(fuel=True)",
+                operation
+            ))),
+        }
+    }
+
+    /// 正在计量的最外层环境剩余的 fuel，环境没有预算时返回 None
+    fn remaining_budget(&self, ctx: impl wasmtime::AsContext) -> Option<u64> {
+        let env_id = self.envs.lock().unwrap().first().cloned()?;
+        self.budgets.remaining(&env_id)?;
+        ctx.as_context().get_fuel().ok()
+    }
+
+    /// 结束计量，记入消耗的 fuel 并恢复为不限制
+    fn stop_metering(
+        &self,
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+        env_id: &str,
+        limit: Option<u64>,
+    ) {
+        let Some(limit) = limit else {
+            return;
+        };
+        let mut ctx = ctx.as_context_mut();
+        let remaining = ctx.get_fuel().unwrap_or(0);
+        self.budgets.charge(env_id, limit - remaining);
+        let _ = ctx.set_fuel(u64::MAX);
+    }
 }
 
 impl PyBoxReactorCore {
-    fn new() -> Self {
+    fn new(compress_threshold: Option<usize>, fuel: bool) -> Self {
+        // 审计日志和 ioctl 记录写出前使用同一份密钥表
+        let redactor = SharedRedactor::default();
         Self {
             handlers: dashmap::DashMap::new(),
+            compress_threshold,
+            fuel,
+            audit: AuditLog::new(redactor.clone()),
+            recorder: IoctlRecorder::new(redactor.clone()),
+            redactor,
             ..Default::default()
         }
     }
@@ -174,8 +657,8 @@ impl PyBoxReactorCore {
     // 统一初始化方法
     fn init(
         &self,
-        linker: &wasmtime::Linker<WasiP1Ctx>,
-        store: &mut wasmtime::Store<WasiP1Ctx>,
+        linker: &wasmtime::Linker<StoreData>,
+        store: &mut wasmtime::Store<StoreData>,
         module: &wasmtime::Module,
     ) -> Result<(), String> {
         // 创建 instance
@@ -232,6 +715,141 @@ impl PyBoxReactorCore {
             let _ = self.exec.set(exec);
         }
 
+        // 可选导出 (旧版本的 wasm 可能没有)
+        if let Ok(retrieve) = instance.get_typed_func::<(WasmPtr, WasmPtr, WasmPtr, WasmPtr), i32>(
+            &mut *store,
+            "pybox_retrieve",
+        ) {
+            let _ = self.retrieve.set(retrieve);
+        }
+        if let Ok(call) = instance
+            .get_typed_func::<(WasmPtr, WasmPtr, WasmPtr, WasmPtr, WasmPtr), i32>(
+                &mut *store,
+                "pybox_call",
+            )
+        {
+            let _ = self.call.set(call);
+        }
+        if let (Ok(exec_compile_ns), Ok(exec_run_ns)) = (
+            instance.get_typed_func::<(), u64>(&mut *store, "pybox_exec_compile_ns"),
+            instance.get_typed_func::<(), u64>(&mut *store, "pybox_exec_run_ns"),
+        ) {
+            let _ = self.exec_compile_ns.set(exec_compile_ns);
+            let _ = self.exec_run_ns.set(exec_run_ns);
+        }
+        if let Ok(exec_raised) =
+            instance.get_typed_func::<(), i32>(&mut *store, "pybox_exec_raised")
+        {
+            let _ = self.exec_raised.set(exec_raised);
+        }
+        if let Ok(exec_report) =
+            instance.get_typed_func::<WasmPtr, i32>(&mut *store, "pybox_exec_report")
+        {
+            let _ = self.exec_report.set(exec_report);
+        }
+        if let Ok(interrupt) = instance.get_typed_func::<(), i32>(&mut *store, "pybox_interrupt") {
+            let _ = self.interrupt.set(interrupt);
+        }
+        if let Ok(set_output_stream) =
+            instance.get_typed_func::<i32, ()>(&mut *store, "pybox_set_output_stream")
+        {
+            let _ = self.set_output_stream.set(set_output_stream);
+        }
+        if let Ok(set_exec_limits) =
+            instance.get_typed_func::<(i64, i64, i64), ()>(&mut *store, "pybox_set_exec_limits")
+        {
+            let _ = self.set_exec_limits.set(set_exec_limits);
+        }
+        if let Ok(set_exec_flags) =
+            instance.get_typed_func::<i32, ()>(&mut *store, "pybox_set_exec_flags")
+        {
+            let _ = self.set_exec_flags.set(set_exec_flags);
+        }
+        if let Ok(set_correlation_id) =
+            instance.get_typed_func::<WasmPtr, ()>(&mut *store, "pybox_set_correlation_id")
+        {
+            let _ = self.set_correlation_id.set(set_correlation_id);
+        }
+        if let Ok(set_codec) = instance.get_typed_func::<i32, ()>(&mut *store, "pybox_set_codec") {
+            let _ = self.set_codec.set(set_codec);
+        }
+        if let Ok(check) =
+            instance.get_typed_func::<(WasmPtr, WasmPtr), i32>(&mut *store, "pybox_check")
+        {
+            let _ = self.check.set(check);
+        }
+        if let Ok(protection_stats) = instance
+            .get_typed_func::<(WasmPtr, WasmPtr), i32>(&mut *store, "pybox_protection_stats")
+        {
+            let _ = self.protection_stats.set(protection_stats);
+        }
+        if let Ok(sanitizer_report) =
+            instance.get_typed_func::<WasmPtr, i32>(&mut *store, "pybox_sanitizer_report")
+        {
+            let _ = self.sanitizer_report.set(sanitizer_report);
+        }
+        if let Ok(protect_all) = instance
+            .get_typed_func::<(WasmPtr, WasmPtr), i32>(&mut *store, "pybox_local_protect_all")
+        {
+            let _ = self.protect_all.set(protect_all);
+        }
+        if let Ok(unprotect) =
+            instance.get_typed_func::<(WasmPtr, WasmPtr), i32>(&mut *store, "pybox_local_unprotect")
+        {
+            let _ = self.unprotect.set(unprotect);
+        }
+        if let Ok(protected_keys) = instance
+            .get_typed_func::<(WasmPtr, WasmPtr), i32>(&mut *store, "pybox_local_protected_keys")
+        {
+            let _ = self.protected_keys.set(protected_keys);
+        }
+        if let Ok(set_local_encoding) = instance
+            .get_typed_func::<(WasmPtr, WasmPtr, WasmPtr, WasmPtr), i32>(
+                &mut *store,
+                "pybox_local_set_encoding",
+            )
+        {
+            let _ = self.set_local_encoding.set(set_local_encoding);
+        }
+        if let Ok(ast) =
+            instance.get_typed_func::<(WasmPtr, WasmPtr, WasmPtr), i32>(&mut *store, "pybox_ast")
+        {
+            let _ = self.ast.set(ast);
+        }
+        if let Ok(gc) = instance.get_typed_func::<WasmPtr, i32>(&mut *store, "pybox_gc") {
+            let _ = self.gc.set(gc);
+        }
+        if let Ok(set_max_variables) = instance
+            .get_typed_func::<(WasmPtr, i64), i32>(&mut *store, "pybox_local_set_max_variables")
+        {
+            let _ = self.set_max_variables.set(set_max_variables);
+        }
+        if let (Ok(buffer_alloc), Ok(assign_buffer)) = (
+            instance.get_typed_func::<WasmSize, WasmPtr>(&mut *store, "pybox_buffer_alloc"),
+            instance.get_typed_func::<(WasmPtr, WasmPtr, WasmPtr, WasmSize, i32, WasmPtr), i32>(
+                &mut *store,
+                "pybox_assign_buffer",
+            ),
+        ) {
+            let _ = self.buffer_alloc.set(buffer_alloc);
+            let _ = self.assign_buffer.set(assign_buffer);
+        }
+        if let Ok(buffer_release) =
+            instance.get_typed_func::<(), ()>(&mut *store, "pybox_buffer_release")
+        {
+            let _ = self.buffer_release.set(buffer_release);
+        }
+        if let (Ok(view_acquire), Ok(view_release)) = (
+            instance.get_typed_func::<(WasmPtr, WasmPtr, WasmPtr, WasmPtr), i32>(
+                &mut *store,
+                "pybox_view_acquire",
+            ),
+            instance.get_typed_func::<u32, i32>(&mut *store, "pybox_view_release"),
+        ) {
+            let _ = self.view_acquire.set(view_acquire);
+            let _ = self.view_release.set(view_release);
+        }
+
         // 存储 instance
         self.instance
             .set(instance)
@@ -240,6 +858,122 @@ impl PyBoxReactorCore {
         Ok(())
     }
 
+    /// 丢弃与旧 instance 绑定的导出函数、内存和环形缓冲区，之后可以重新 init
+    /// 返回需要重新创建的环形缓冲区 (名称, 数据区大小)
+    fn reset_instance(&mut self) -> Vec<(String, WasmSize)> {
+        self.exec_cache.touch_all();
+        if let Some(tracker) = self.dirty_tracker.get() {
+            tracker.invalidate();
+        }
+        self.alloc_mem.take();
+        self.free_mem.take();
+        self.init_local.take();
+        self.init_local_from.take();
+        self.del_local.take();
+        self.assign.take();
+        self.protect.take();
+        self.exec.take();
+        self.retrieve.take();
+        self.call.take();
+        self.buffer_alloc.take();
+        self.assign_buffer.take();
+        self.buffer_release.take();
+        self.exec_compile_ns.take();
+        self.exec_run_ns.take();
+        self.exec_raised.take();
+        self.exec_report.take();
+        self.interrupt.take();
+        self.set_output_stream.take();
+        self.set_exec_limits.take();
+        self.set_exec_flags.take();
+        self.set_correlation_id.take();
+        self.set_codec.take();
+        self.view_acquire.take();
+        self.view_release.take();
+        self.check.take();
+        self.protection_stats.take();
+        self.sanitizer_report.take();
+        self.set_local_encoding.take();
+        self.protect_all.take();
+        self.unprotect.take();
+        self.protected_keys.take();
+        self.ast.take();
+        self.gc.take();
+        self.set_max_variables.take();
+        self.memory.take();
+        self.instance.take();
+        self.streams.clear();
+        self.take_rings()
+    }
+
+    /// 清空环形缓冲区的记录，返回需要重新创建的 (名称, 数据区大小)
+    fn take_rings(&self) -> Vec<(String, WasmSize)> {
+        let rings = self
+            .rings
+            .iter()
+            .map(|ring| (ring.key().clone(), ring.value().1))
+            .collect();
+        self.rings.clear();
+        rings
+    }
+
+    /// 导出环境中的变量，返回 json 字符串和无法保留的变量名
+    fn export_env(
+        &self,
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+        env_id: &str,
+    ) -> PyResult<(String, Vec<String>)> {
+        let output = self
+            .run_code(&mut ctx, Some(env_id), EXPORT_ENV_CODE)?
+            .and_then(|output| {
+                if self.last_exec_raised(&mut ctx) {
+                    Err(output)
+                } else {
+                    Ok(output)
+                }
+            })
+            .map_err(|error| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "PyBox failed to export environment '{}': {}",
+                    env_id, error
+                ))
+            })?;
+        let state = output.trim_end().to_string();
+        let lost = serde_json::from_str::<serde_json::Value>(&state)
+            .ok()
+            .and_then(|value| value.get("lost").cloned())
+            .and_then(|lost| serde_json::from_value::<Vec<String>>(lost).ok())
+            .ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "PyBox failed to export environment '{}': unexpected output",
+                    env_id
+                ))
+            })?;
+        Ok((state, lost))
+    }
+
+    /// 将 export_env 导出的变量写回环境
+    fn import_env(
+        &self,
+        ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+        env_id: &str,
+        state: &str,
+    ) -> PyResult<()> {
+        // json 字符串字面量同时也是合法的 python 字符串字面量
+        let literal = serde_json::to_string(state)
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+        let code = format!(
+            "{}__pybox_import_env({})\ndel __pybox_import_env",
+            IMPORT_ENV_CODE, literal
+        );
+        self.run_hook(ctx, Some(env_id), &code)?.map_err(|error| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "PyBox failed to import environment '{}': {}",
+                env_id, error
+            ))
+        })
+    }
+
     fn get_alloc_mem(&self) -> Option<&wasmtime::TypedFunc<WasmSize, WasmPtr>> {
         self.alloc_mem.get()
     }
@@ -256,10 +990,124 @@ impl PyBoxReactorCore {
         self.instance.get()
     }
 
+    /// 保存导出的可变全局变量和表，实例未初始化时为空
+    pub fn capture_state(
+        &self,
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+    ) -> InstanceState {
+        let store_id = ctx.as_context().data().id;
+        self.get_instance()
+            .map(|instance| InstanceState::capture(instance, store_id, ctx))
+            .unwrap_or_default()
+    }
+
+    /// 开始新一段脏页跟踪，返回本段的编号和 since 一段以来写入过的字节范围，
+    /// 未开启跟踪或无法确定写入过的页时范围为 None
+    pub fn dirty_checkpoint(
+        &self,
+        ctx: impl wasmtime::AsContext,
+        since: Option<u64>,
+    ) -> (Option<u64>, Option<Vec<std::ops::Range<usize>>>) {
+        let (Some(tracker), Some(memory)) = (self.dirty_tracker.get(), self.get_memory()) else {
+            return (None, None);
+        };
+        tracker.checkpoint(
+            memory.data_ptr(&ctx) as usize,
+            memory.data_size(&ctx),
+            since,
+        )
+    }
+
+    /// 影响 guest 内存布局的引擎特性，只有特性相同的快照可以恢复
+    pub fn engine_features(&self, ctx: impl wasmtime::AsContext) -> String {
+        let Some(memory) = self.get_memory() else {
+            return String::new();
+        };
+        let ty = memory.ty(ctx);
+        format!(
+            "{},{},page={}",
+            if ty.is_64() { "memory64" } else { "memory32" },
+            if ty.is_shared() { "shared" } else { "unshared" },
+            ty.page_size()
+        )
+    }
+
+    /// 已创建的局部环境及其保护的变量，快照据此恢复 host 端的记录
+    pub fn env_records(&self) -> Vec<(String, Vec<String>)> {
+        let mut records: Vec<(String, Vec<String>)> = self
+            .local_envs
+            .iter()
+            .map(|env_id| {
+                let protected = self
+                    .protected
+                    .get(env_id.as_str())
+                    .map(|protected| protected.clone())
+                    .unwrap_or_default();
+                (env_id.clone(), protected)
+            })
+            .collect();
+        records.sort();
+        records
+    }
+
+    /// 将 env_records 的结果加入局部环境的记录，已有环境的记录和设置保持不变
+    pub fn merge_env_records(&self, records: &[(String, Vec<String>)]) {
+        for (env_id, protected) in records {
+            if self.local_envs.insert(env_id.clone()) && !protected.is_empty() {
+                self.protected.insert(env_id.clone(), protected.clone());
+            }
+        }
+    }
+
+    /// 以 env_records 的结果替换局部环境的记录
+    pub fn set_env_records(&self, records: &[(String, Vec<String>)]) {
+        self.local_envs.clear();
+        self.protected.clear();
+        self.encodings
+            .retain(|env_id, _| records.iter().any(|(record, _)| record == env_id));
+        self.gc_policies
+            .retain(|env_id, _| records.iter().any(|(record, _)| record == env_id));
+        self.env_limits
+            .retain(|env_id, _| records.iter().any(|(record, _)| record == env_id));
+        for (env_id, protected) in records {
+            self.local_envs.insert(env_id.clone());
+            if !protected.is_empty() {
+                self.protected.insert(env_id.clone(), protected.clone());
+            }
+        }
+    }
+
+    /// 仍有导出的视图 buffer 时拒绝会替换、移动或改写 guest 内存的操作
+    pub fn check_views(&self, operation: &str) -> PyResult<()> {
+        if self.view_exports.load(Ordering::SeqCst) > 0 {
+            return Err(pyo3::exceptions::PyBufferError::new_err(format!(
+                "cannot {} PyBoxReactor: exported memoryviews exist",
+                operation
+            )));
+        }
+        Ok(())
+    }
+
+    /// 恢复 capture_state 保存的全局变量和表，仍有导出的视图 buffer 时返回错误
+    pub fn restore_state(
+        &self,
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+        state: &InstanceState,
+    ) -> Result<(), String> {
+        if self.view_exports.load(Ordering::SeqCst) > 0 {
+            return Err("cannot restore PyBoxReactor: exported memoryviews exist".to_string());
+        }
+        let instance = self.get_instance().ok_or("Instance not available")?;
+        // 内存被整体恢复，缓存的结果可能不再对应环境的状态
+        self.exec_cache.touch_all();
+        let store_id = ctx.as_context().data().id;
+        state.restore(instance, store_id, ctx)
+    }
+
     // 从 WASM 内存读取字节 (泛型版本，支持 AsContext)
     fn read_memory_bytes(
         &self,
-        ctx: impl wasmtime::AsContext<Data = WasiP1Ctx>,
+        ctx: impl wasmtime::AsContext<Data = StoreData>,
         ptr: WasmPtr,
         len: WasmSize,
     ) -> Result<Vec<u8>, String> {
@@ -274,7 +1122,7 @@ impl PyBoxReactorCore {
     // 写入字节到 WASM 内存 (泛型版本，支持 AsContextMut)
     fn write_memory_bytes(
         &self,
-        mut ctx: impl wasmtime::AsContextMut<Data = WasiP1Ctx>,
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
         ptr: WasmPtr,
         data: &[u8],
     ) -> Result<(), String> {
@@ -287,7 +1135,7 @@ impl PyBoxReactorCore {
     // 在 WASM 内存中分配缓冲区 (泛型版本，支持 AsContextMut)
     fn allocate_buffer(
         &self,
-        mut ctx: impl wasmtime::AsContextMut<Data = WasiP1Ctx>,
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
         size: WasmSize,
     ) -> Result<WasmPtr, String> {
         let alloc_func = self
@@ -299,7 +1147,7 @@ impl PyBoxReactorCore {
     // 创建一个 pybox_bytes 结构（包含长度和数据）
     fn create_pybox_bytes(
         &self,
-        mut ctx: impl wasmtime::AsContextMut<Data = WasiP1Ctx>,
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
         data: &[u8],
     ) -> Result<WasmPtr, String> {
         // pybox_bytes 的布局: { length: u32, data: [u8; 0] }
@@ -321,7 +1169,7 @@ impl PyBoxReactorCore {
     // 从 WASM 内存中读取一个 *mut pybox_bytes 指针指向的数据
     fn read_pybox_bytes_ptr(
         &self,
-        ctx: impl wasmtime::AsContext<Data = WasiP1Ctx>,
+        ctx: impl wasmtime::AsContext<Data = StoreData>,
         ptr_ptr: WasmPtr,
     ) -> Result<Option<Vec<u8>>, String> {
         // 读取指针值（4 字节）
@@ -350,10 +1198,25 @@ impl PyBoxReactorCore {
         Ok(Some(data))
     }
 
+    // 读取 *mut pybox_bytes 指向的数据并释放 WASM 端分配的缓冲区
+    fn take_pybox_bytes_ptr(
+        &self,
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+        ptr_ptr: WasmPtr,
+    ) -> Result<Vec<u8>, String> {
+        let ptr = self.read_u32(&ctx.as_context(), ptr_ptr)?;
+        if ptr == 0 {
+            return Ok(Vec::new());
+        }
+        let data = self.read_pybox_bytes_data(&ctx.as_context(), ptr)?.to_vec();
+        self.free_buffer(&mut ctx, ptr)?;
+        Ok(data)
+    }
+
     // 释放 WASM 内存中的缓冲区 (泛型版本，支持 AsContextMut)
     fn free_buffer(
         &self,
-        mut ctx: impl wasmtime::AsContextMut<Data = WasiP1Ctx>,
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
         ptr: WasmPtr,
     ) -> Result<(), String> {
         let free_func = self
@@ -362,91 +1225,624 @@ impl PyBoxReactorCore {
         free_func.call(&mut ctx, ptr).map_err(|e| e.to_string())
     }
 
-    // ==================== 零拷贝优化方法 ====================
-
-    /// 零拷贝读取内存切片（直接返回引用）
-    /// 注意：返回的引用生命周期绑定到传入的 context 引用
-    fn read_memory_slice<'a>(
+    /// 在环境中执行代码，返回输出或 guest 报告的错误信息，guest trap 以 PyErr 返回
+    fn run_code(
         &self,
-        ctx: &'a impl wasmtime::AsContext<Data = WasiP1Ctx>,
-        ptr: WasmPtr,
-        len: WasmSize,
-    ) -> Result<&'a [u8], String> {
-        let memory = self.get_memory().ok_or("Memory not available")?;
-        let memory_data = memory.data(ctx);
-        let start = ptr as usize;
-        let end = start + len as usize;
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+        env_id: Option<&str>,
+        code: &str,
+    ) -> PyResult<Result<String, String>> {
+        let pybox_exec_func = self
+            .exec
+            .get()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Failed to get pybox_exec"))?;
+
+        // ========== 优化：批量分配所有参数 ==========
+        // 准备输入数据切片
+        let mut input_slices = Vec::with_capacity(4);
+        let env_id_index = if let Some(env_id) = env_id {
+            input_slices.push(env_id.as_bytes());
+            Some(input_slices.len() - 1)
+        } else {
+            None
+        };
+        input_slices.push(code.as_bytes()); // code
+        input_slices.push(&[0u8; 4]); // output_ptr_ptr (初始化为 NULL)
+        input_slices.push(&[0u8; 4]); // error_ptr_ptr (初始化为 NULL)
+
+        // 一次性分配所有内存！
+        let (base_ptr, ptrs) = self
+            .allocate_pybox_bytes_batch(&mut ctx, &input_slices)
+            .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+        // 解析各个指针
+        let (env_id_ptr, code_ptr, output_ptr_ptr, error_ptr_ptr) = if let Some(idx) = env_id_index
+        {
+            (ptrs[idx], ptrs[idx + 1], ptrs[idx + 2], ptrs[idx + 3])
+        } else {
+            (0, ptrs[0], ptrs[1], ptrs[2])
+        };
 
-        if end > memory_data.len() {
-            return Err(format!(
-                "Memory access out of bounds: {}..{} > {}",
-                start,
-                end,
-                memory_data.len()
-            ));
+        let result = pybox_exec_func
+            .call(
+                &mut ctx,
+                (env_id_ptr, code_ptr, output_ptr_ptr, error_ptr_ptr),
+            )
+            .map_err(guest_error)?;
+
+        // 读取输出和错误并释放 WASM 端分配的缓冲区
+        let output = self
+            .take_pybox_bytes_ptr(&mut ctx, output_ptr_ptr)
+            .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+        let error = self
+            .take_pybox_bytes_ptr(&mut ctx, error_ptr_ptr)
+            .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+        // ========== 优化：批量释放参数（一次调用）==========
+        self.free_buffer(&mut ctx, base_ptr)
+            .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+        if result != 0 {
+            if error.is_empty() {
+                return Ok(Err("Unknown error".to_string()));
+            }
+            return Ok(Err(String::from_utf8_lossy(&error).to_string()));
         }
-
-        Ok(&memory_data[start..end])
+        Ok(Ok(String::from_utf8_lossy(&output).to_string()))
     }
 
-    /// 零拷贝读取 u32
-    fn read_u32(
+    /// 执行宿主配置的代码，代码抛出异常时以异常信息作为错误返回，正常输出被丢弃
+    /// 旧版 guest 没有导出 pybox_exec_raised 时无法检测代码中的异常
+    fn run_hook(
         &self,
-        ctx: &impl wasmtime::AsContext<Data = WasiP1Ctx>,
-        ptr: WasmPtr,
-    ) -> Result<u32, String> {
-        let slice = self.read_memory_slice(ctx, ptr, 4)?;
-        Ok(u32::from_le_bytes([slice[0], slice[1], slice[2], slice[3]]))
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+        env_id: Option<&str>,
+        code: &str,
+    ) -> PyResult<Result<(), String>> {
+        let output = match self.run_code(&mut ctx, env_id, code)? {
+            Ok(output) => output,
+            Err(error) => return Ok(Err(error)),
+        };
+        Ok(if self.last_exec_raised(ctx) {
+            Err(output)
+        } else {
+            Ok(())
+        })
     }
 
-    /// 零拷贝读取 pybox_bytes 的数据部分（不包含 length 字段）
-    fn read_pybox_bytes_data<'a>(
+    /// 设置局部环境的编码和错误处理方式，Err 为 guest 返回的错误信息
+    fn set_env_encoding(
         &self,
-        ctx: &'a impl wasmtime::AsContext<Data = WasiP1Ctx>,
-        ptr: WasmPtr,
-    ) -> Result<&'a [u8], String> {
-        if ptr == 0 {
-            return Ok(&[]);
-        }
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+        env_id: &str,
+        encoding: &str,
+        errors: &str,
+    ) -> PyResult<Result<(), String>> {
+        let set_local_encoding = self.set_local_encoding.get().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err(
+                "Environment encodings are not supported by this pybox module",
+            )
+        })?;
+
+        let (base_ptr, ptrs) = self
+            .allocate_pybox_bytes_batch(
+                &mut ctx,
+                &[
+                    env_id.as_bytes(),
+                    encoding.as_bytes(),
+                    errors.as_bytes(),
+                    &[0u8; 4], // error_ptr_ptr (初始化为 NULL)
+                ],
+            )
+            .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
 
-        let length = self.read_u32(ctx, ptr)?;
-        if length == 0 {
-            return Ok(&[]);
-        }
+        let result = set_local_encoding
+            .call(&mut ctx, (ptrs[0], ptrs[1], ptrs[2], ptrs[3]))
+            .map_err(guest_error)?;
+        let error = self
+            .take_pybox_bytes_ptr(&mut ctx, ptrs[3])
+            .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
 
-        self.read_memory_slice(ctx, ptr + 4, length)
+        self.free_buffer(&mut ctx, base_ptr)
+            .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+        Ok(match result {
+            0 => Ok(()),
+            _ => Err(String::from_utf8_lossy(&error).into_owned()),
+        })
     }
 
-    /// 零拷贝读取 *mut pybox_bytes 指向的数据
-    fn read_pybox_bytes_ptr_data<'a>(
+    /// 以环境 id 调用 pybox_init_local / pybox_del_local 这样的导出，返回导出的返回值
+    /// host 不记录该环境，用于 host 自己使用的临时环境
+    fn call_env_export(
         &self,
-        ctx: &'a impl wasmtime::AsContext<Data = WasiP1Ctx>,
-        ptr_ptr: WasmPtr,
-    ) -> Result<&'a [u8], String> {
-        let ptr = self.read_u32(ctx, ptr_ptr)?;
-        self.read_pybox_bytes_data(ctx, ptr)
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+        func: Option<&wasmtime::TypedFunc<WasmPtr, i32>>,
+        env_id: &str,
+    ) -> PyResult<i32> {
+        let func = func.ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("Environment exports not available")
+        })?;
+        let (base_ptr, ptrs) = self
+            .allocate_pybox_bytes_batch(&mut ctx, &[env_id.as_bytes()])
+            .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+        let result = func.call(&mut ctx, ptrs[0]).map_err(guest_error)?;
+        self.free_buffer(&mut ctx, base_ptr)
+            .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+        Ok(result)
     }
 
-    // ==================== 批量分配优化方法 ====================
+    /// 环境的大小上限
+    fn env_limits(&self, env_id: &str) -> EnvLimits {
+        self.env_limits
+            .get(env_id)
+            .map(|limits| *limits)
+            .unwrap_or_default()
+    }
 
-    /// 批量分配多个 pybox_bytes 结构，一次性分配连续内存
-    ///
-    /// 参数：
-    /// - data_slices: 要分配的数据切片数组
-    ///
-    /// 返回：
-    /// - Ok((base_ptr, vec![ptr1, ptr2, ...])): 基础指针和各个 pybox_bytes 的指针
-    fn allocate_pybox_bytes_batch(
+    /// 将环境的变量数量上限设置到 guest，新建的环境有自己的 ProtectedLocals，需要重新设置
+    fn apply_env_limits(
         &self,
-        mut ctx: impl wasmtime::AsContextMut<Data = WasiP1Ctx>,
-        data_slices: &[&[u8]],
-    ) -> Result<(WasmPtr, Vec<WasmPtr>), String> {
-        if data_slices.is_empty() {
-            return Ok((0, Vec::new()));
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+        env_id: &str,
+    ) -> PyResult<()> {
+        let set_max_variables = self.set_max_variables.get().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err(
+                "Environment limits are not supported by this PyBox image",
+            )
+        })?;
+        let max_variables = self
+            .env_limits(env_id)
+            .max_variables
+            .map_or(-1, |max_variables| max_variables as i64);
+
+        let (base_ptr, ptrs) = self
+            .allocate_pybox_bytes_batch(&mut ctx, &[env_id.as_bytes()])
+            .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+        let result = set_max_variables
+            .call(&mut ctx, (ptrs[0], max_variables))
+            .map_err(guest_error)?;
+        self.free_buffer(&mut ctx, base_ptr)
+            .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+        if result != 0 {
+            return Err(pyo3::exceptions::PyRuntimeError::new_err(format!(
+                "Local context '{}' not found",
+                env_id
+            )));
         }
+        Ok(())
+    }
 
-        // 1. 计算总大小（每个 pybox_bytes = 4 字节 length + 数据长度）
-        let total_size: u32 = data_slices.iter().map(|d| 4 + d.len() as u32).sum();
+    /// exec 结束后按环境的回收策略运行 pybox_gc
+    fn collect_after_exec(
+        &self,
+        ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+        env_id: &str,
+    ) -> PyResult<()> {
+        let due = match self.gc_policies.get_mut(env_id) {
+            Some(mut policy) => {
+                policy.1 += 1;
+                let due = policy.1 >= policy.0;
+                if due {
+                    policy.1 = 0;
+                }
+                due
+            }
+            None => false,
+        };
+        if due {
+            self.call_env_export(ctx, self.gc.get(), env_id)?;
+        }
+        Ok(())
+    }
+
+    /// 最近一次 exec 的代码是否抛出了异常，旧版 guest 总是返回 false
+    fn last_exec_raised(&self, mut ctx: impl wasmtime::AsContextMut<Data = StoreData>) -> bool {
+        self.exec_raised
+            .get()
+            .and_then(|func| func.call(&mut ctx, ()).ok())
+            .is_some_and(|raised| raised != 0)
+    }
+
+    /// 执行代码，前后运行每次 exec 都执行的 prelude / teardown
+    /// prelude 失败时不执行代码，代码失败时仍会执行 teardown
+    fn run_with_hooks(
+        &self,
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+        env_id: Option<&str>,
+        code: &str,
+        options: ExecOptions,
+    ) -> PyResult<GuestExec> {
+        if let Some(prelude) = self.middleware.prelude(true) {
+            self.run_hook(&mut ctx, env_id, &prelude)?
+                .map_err(|error| {
+                    pyo3::exceptions::PyRuntimeError::new_err(format!(
+                        "PyBox prelude failed: {}",
+                        error
+                    ))
+                })?;
+        }
+        let result =
+            self.with_exec_options(&mut ctx, options, |ctx| self.run_code(ctx, env_id, code))?;
+        // 结构化结果和计时在 teardown 覆盖之前读取
+        let mut guest_time = |func: Option<&wasmtime::TypedFunc<(), u64>>| {
+            func.and_then(|func| func.call(&mut ctx, ()).ok())
+        };
+        let compile_ns = guest_time(self.exec_compile_ns.get());
+        let run_ns = guest_time(self.exec_run_ns.get());
+        let report = self.read_exec_report(&mut ctx);
+        let raised = self.last_exec_raised(&mut ctx);
+        if let Some(teardown) = self.middleware.teardown(true) {
+            self.run_hook(&mut ctx, env_id, &teardown)?
+                .map_err(|error| {
+                    pyo3::exceptions::PyRuntimeError::new_err(format!(
+                        "PyBox teardown failed: {}",
+                        error
+                    ))
+                })?;
+        }
+        let output = result.map_err(|error| {
+            pyo3::exceptions::PyRuntimeError::new_err(format!("PyBox exec failed: {}", error))
+        })?;
+        Ok(GuestExec {
+            output,
+            report,
+            compile_ns,
+            run_ns,
+            raised,
+        })
+    }
+
+    /// 执行 f 期间开始的 exec 使用 options，结束后恢复外层 exec 的设置
+    /// 旧版 guest 没有导出对应的设置函数时不支持实时输出和限制
+    fn with_exec_options<C, R>(
+        &self,
+        mut ctx: C,
+        options: ExecOptions,
+        f: impl FnOnce(&mut C) -> R,
+    ) -> R
+    where
+        C: wasmtime::AsContextMut<Data = StoreData>,
+    {
+        self.apply_exec_options(&mut ctx, &options);
+        self.exec_options.lock().unwrap().push(options);
+
+        let result = f(&mut ctx);
+
+        let mut exec_options = self.exec_options.lock().unwrap();
+        exec_options.pop();
+        let outer = exec_options.last();
+        self.apply_exec_options(&mut ctx, outer.unwrap_or(&ExecOptions::default()));
+        result
+    }
+
+    /// 将 exec 的参数设置到 guest
+    fn apply_exec_options(
+        &self,
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+        options: &ExecOptions,
+    ) {
+        if let Some(set_output_stream) = self.set_output_stream.get() {
+            let _ = set_output_stream.call(&mut ctx, options.on_output.is_some() as i32);
+        }
+        if let Some(set_exec_limits) = self.set_exec_limits.get() {
+            // 负数表示不限制
+            let limit = |limit: Option<usize>| limit.map_or(-1, |limit| limit as i64);
+            let limits = (
+                limit(options.max_output),
+                limit(options.max_artifacts),
+                limit(options.max_artifact_bytes),
+            );
+            let _ = set_exec_limits.call(&mut ctx, limits);
+        }
+        if let Some(set_exec_flags) = self.set_exec_flags.get() {
+            // 与 guest 端 exec.rs 的 PYBOX_EXEC_TRANSACTIONAL 一致
+            let flags = if options.transactional { 2 } else { 0 };
+            let _ = set_exec_flags.call(&mut ctx, flags);
+        }
+        if let Some(set_correlation_id) = self.set_correlation_id.get() {
+            // 0 表示清除
+            match &options.correlation_id {
+                Some(id) => {
+                    if let Ok((base_ptr, ptrs)) =
+                        self.allocate_pybox_bytes_batch(&mut ctx, &[id.as_bytes()])
+                    {
+                        let _ = set_correlation_id.call(&mut ctx, ptrs[0]);
+                        let _ = self.free_buffer(&mut ctx, base_ptr);
+                    }
+                }
+                None => {
+                    let _ = set_correlation_id.call(&mut ctx, 0);
+                }
+            }
+        }
+        self.apply_codec(&mut ctx);
+    }
+
+    /// 将 JSON 编解码选项设置到 guest，旧版 guest 没有导出 pybox_set_codec 时忽略
+    fn apply_codec(&self, mut ctx: impl wasmtime::AsContextMut<Data = StoreData>) {
+        if let Some(set_codec) = self.set_codec.get() {
+            let _ = set_codec.call(&mut ctx, self.codec_options().flags());
+        }
+    }
+
+    fn codec_options(&self) -> CodecOptions {
+        *self.codec.lock().unwrap()
+    }
+
+    fn type_codecs(&self, py: Python<'_>) -> Vec<json::TypeCodec> {
+        let types = self.types.lock().unwrap();
+        types.iter().map(|codec| codec.clone_ref(py)).collect()
+    }
+
+    /// 在环境中定义 JSON 中出现的注册类型的解码器，guest 端跳过源码未变的解码器
+    fn install_decoders(
+        &self,
+        py: Python<'_>,
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+        env_id: &str,
+        types: &[json::TypeCodec],
+        json: &str,
+    ) -> PyResult<()> {
+        for codec in types {
+            if !json.contains(&format!("\"{}\"", codec.name)) {
+                continue;
+            }
+            let code = format!(
+                "import pybox\npybox._register_decoder({}, {})\n",
+                PyString::new(py, &codec.name).repr()?,
+                PyString::new(py, &codec.decoder).repr()?
+            );
+            self.run_hook(&mut ctx, Some(env_id), &code)?
+                .map_err(|error| {
+                    pyo3::exceptions::PyRuntimeError::new_err(format!(
+                        "PyBox decoder of type '{}' failed: {}",
+                        codec.name, error
+                    ))
+                })?;
+        }
+        Ok(())
+    }
+
+    /// 将 guest 的实时输出交给最内层 exec 的回调，回调抛出的异常会中止 exec
+    fn handle_output_request(
+        &self,
+        py: Python<'_>,
+        caller: &mut wasmtime::Caller<'_, StoreData>,
+        req: &[u8],
+        resp_ptr: WasmPtr,
+    ) -> PyResult<i32> {
+        let req = String::from_utf8_lossy(req);
+        let Some((stream, text)) = req.split_once(':') else {
+            return Ok(-1);
+        };
+        let (callback, text) = match self.exec_options.lock().unwrap().last_mut() {
+            Some(options) => {
+                // 超过 max_output 的部分不交给回调
+                let text = match options.max_output {
+                    Some(max_output) => {
+                        text_prefix(text, max_output.saturating_sub(options.output_sent))
+                    }
+                    None => text,
+                };
+                options.output_sent += text.len();
+                let callback = options.on_output.as_ref();
+                (callback.map(|callback| callback.clone_ref(py)), text)
+            }
+            None => (None, text),
+        };
+        if let Some(callback) = callback
+            && !text.is_empty()
+        {
+            let mut text = self.output_filter.lock().unwrap().apply(text).into_owned();
+            let redactor = self.redactor.lock().unwrap().clone_ref(py);
+            redactor.redact_in_place(py, &mut text)?;
+            self.middleware.output(py, stream, &mut text)?;
+            // 回调内部可以重入 guest
+            let _guard = self.enter_handler(caller);
+            callback.call1(py, (stream, text))?;
+        }
+        Ok(self.write_ioctl_response(caller, resp_ptr, b"", false))
+    }
+
+    /// 读取最近一次 exec 的 json 结果，旧版 guest 没有导出 pybox_exec_report 时返回 None
+    fn read_exec_report(
+        &self,
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+    ) -> Option<Vec<u8>> {
+        let exec_report = self.exec_report.get()?;
+        let (base_ptr, ptrs) = self
+            .allocate_pybox_bytes_batch(&mut ctx, &[&[0u8; 4]])
+            .ok()?;
+        let result = exec_report.call(&mut ctx, ptrs[0]);
+        let report = self.take_pybox_bytes_ptr(&mut ctx, ptrs[0]).ok();
+        let _ = self.free_buffer(&mut ctx, base_ptr);
+        match result {
+            Ok(0) => report.filter(|report| !report.is_empty()),
+            _ => None,
+        }
+    }
+
+    /// epoch 到期时检查 watchdog 超时，调用心跳回调并运行 host 的信号处理函数
+    ///
+    /// watchdog 超时时以 PyBoxTimeoutError 终止 guest 执行；
+    /// Ctrl-C 或 cancel() 产生的 KeyboardInterrupt 交给 guest 在 python 代码中抛出，exec 正常返回已有的输出；
+    /// guest 不支持中断、本次调用已经中断过，或信号处理函数、心跳回调抛出其他异常时终止 guest 执行，
+    /// 异常由 exec / call 抛出
+    fn on_epoch(
+        &self,
+        mut ctx: wasmtime::StoreContextMut<'_, StoreData>,
+    ) -> wasmtime::Result<wasmtime::UpdateDeadline> {
+        if let Some(timeout) = self.watchdog.expired() {
+            return Err(wasmtime::Error::from(PyBoxTimeoutError::new_err(format!(
+                "Execution exceeded the watchdog timeout of {:.3}s",
+                timeout.as_secs_f64()
+            ))));
+        }
+        let fuel = ctx.get_fuel().unwrap_or(0);
+        Python::attach(|py| self.heartbeat.tick(py, fuel))?;
+        // 短暂释放 GIL，让等待的线程（例如 exec_async 的事件循环）可以运行
+        Python::attach(|py| py.detach(|| ()));
+        let signals = match self
+            .cancel_requested
+            .swap(false, std::sync::atomic::Ordering::SeqCst)
+        {
+            true => Err(pyo3::exceptions::PyKeyboardInterrupt::new_err(
+                "Execution cancelled",
+            )),
+            false => Python::attach(|py| py.check_signals()),
+        };
+        let Err(err) = signals else {
+            return Ok(wasmtime::UpdateDeadline::Continue(1));
+        };
+        let keyboard_interrupt =
+            Python::attach(|py| err.is_instance_of::<pyo3::exceptions::PyKeyboardInterrupt>(py));
+        if keyboard_interrupt
+            && !self
+                .interrupted
+                .swap(true, std::sync::atomic::Ordering::SeqCst)
+            && let Some(interrupt) = self.interrupt.get()
+            && let Ok(0) = interrupt.call(&mut ctx, ())
+        {
+            return Ok(wasmtime::UpdateDeadline::Continue(1));
+        }
+        Err(wasmtime::Error::from(err))
+    }
+
+    /// 在 guest 内存中创建环形缓冲区，缓冲区在 reactor 的生命周期内一直有效
+    fn create_ring(
+        &self,
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+        name: &str,
+        capacity: WasmSize,
+    ) -> Result<(), String> {
+        if self.rings.contains_key(name) {
+            return Err(format!("Ring buffer '{}' already exists", name));
+        }
+        let size = capacity
+            .checked_add(RING_HEADER_SIZE as WasmSize)
+            .ok_or("Ring buffer too large")?;
+        let ptr = self.allocate_buffer(&mut ctx, size)?;
+        let memory = self.get_memory().ok_or("Memory not available")?;
+        let mem = memory
+            .data_mut(&mut ctx)
+            .get_mut(ptr as usize..)
+            .ok_or("Ring buffer out of bounds")?;
+        Ring::init(mem, capacity)?;
+        self.rings.insert(name.to_string(), (ptr, capacity));
+        Ok(())
+    }
+
+    /// 直接访问 guest 内存中的环形缓冲区，不进入 guest
+    fn with_ring<R>(
+        &self,
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+        name: &str,
+        f: impl FnOnce(&mut Ring<'_>) -> Result<R, String>,
+    ) -> Result<R, String> {
+        let (ptr, capacity) = *self
+            .rings
+            .get(name)
+            .ok_or_else(|| format!("No ring buffer named '{}'", name))?;
+        let memory = self.get_memory().ok_or("Memory not available")?;
+        let mem = memory
+            .data_mut(&mut ctx)
+            .get_mut(ptr as usize..)
+            .ok_or("Ring buffer out of bounds")?;
+        let mut ring = Ring::new(mem)?;
+        // 头部位于 guest 内存中，guest 代码可能改写它
+        if ring.capacity() != capacity {
+            return Err(format!("Ring buffer '{}' corrupted", name));
+        }
+        f(&mut ring)
+    }
+
+    // ==================== 零拷贝优化方法 ====================
+
+    /// 零拷贝读取内存切片（直接返回引用）
+    /// 注意：返回的引用生命周期绑定到传入的 context 引用
+    fn read_memory_slice<'a>(
+        &self,
+        ctx: &'a impl wasmtime::AsContext<Data = StoreData>,
+        ptr: WasmPtr,
+        len: WasmSize,
+    ) -> Result<&'a [u8], String> {
+        let memory = self.get_memory().ok_or("Memory not available")?;
+        let memory_data = memory.data(ctx);
+        let start = ptr as usize;
+        let end = start + len as usize;
+
+        if end > memory_data.len() {
+            return Err(format!(
+                "Memory access out of bounds: {}..{} > {}",
+                start,
+                end,
+                memory_data.len()
+            ));
+        }
+
+        Ok(&memory_data[start..end])
+    }
+
+    /// 零拷贝读取 u32
+    fn read_u32(
+        &self,
+        ctx: &impl wasmtime::AsContext<Data = StoreData>,
+        ptr: WasmPtr,
+    ) -> Result<u32, String> {
+        let slice = self.read_memory_slice(ctx, ptr, 4)?;
+        Ok(u32::from_le_bytes([slice[0], slice[1], slice[2], slice[3]]))
+    }
+
+    /// 零拷贝读取 pybox_bytes 的数据部分（不包含 length 字段）
+    fn read_pybox_bytes_data<'a>(
+        &self,
+        ctx: &'a impl wasmtime::AsContext<Data = StoreData>,
+        ptr: WasmPtr,
+    ) -> Result<&'a [u8], String> {
+        if ptr == 0 {
+            return Ok(&[]);
+        }
+
+        let length = self.read_u32(ctx, ptr)?;
+        if length == 0 {
+            return Ok(&[]);
+        }
+
+        self.read_memory_slice(ctx, ptr + 4, length)
+    }
+
+    /// 零拷贝读取 *mut pybox_bytes 指向的数据
+    fn read_pybox_bytes_ptr_data<'a>(
+        &self,
+        ctx: &'a impl wasmtime::AsContext<Data = StoreData>,
+        ptr_ptr: WasmPtr,
+    ) -> Result<&'a [u8], String> {
+        let ptr = self.read_u32(ctx, ptr_ptr)?;
+        self.read_pybox_bytes_data(ctx, ptr)
+    }
+
+    // ==================== 批量分配优化方法 ====================
+
+    /// 批量分配多个 pybox_bytes 结构，一次性分配连续内存
+    ///
+    /// 参数：
+    /// - data_slices: 要分配的数据切片数组
+    ///
+    /// 返回：
+    /// - Ok((base_ptr, vec![ptr1, ptr2, ...])): 基础指针和各个 pybox_bytes 的指针
+    fn allocate_pybox_bytes_batch(
+        &self,
+        mut ctx: impl wasmtime::AsContextMut<Data = StoreData>,
+        data_slices: &[&[u8]],
+    ) -> Result<(WasmPtr, Vec<WasmPtr>), String> {
+        if data_slices.is_empty() {
+            return Ok((0, Vec::new()));
+        }
+
+        // 1. 计算总大小（每个 pybox_bytes = 4 字节 length + 数据长度）
+        let total_size: u32 = data_slices.iter().map(|d| 4 + d.len() as u32).sum();
 
         // 2. 一次性分配整块内存
         let base_ptr = self.allocate_buffer(&mut ctx, total_size)?;
@@ -481,11 +1877,15 @@ impl PyBoxReactorCore {
     // 处理 WASM 的 ioctl 请求
     fn handle_ioctl_request(
         &self,
-        mut caller: wasmtime::Caller<'_, WasiP1Ctx>,
+        mut caller: wasmtime::Caller<'_, StoreData>,
         handle: HandleId,
         req_ptr: WasmPtr,
         resp_ptr: WasmPtr,
     ) -> Result<i32, PyErr> {
+        let seq = self
+            .ioctl_calls
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+            + 1;
         pyo3::Python::attach(|py| -> Result<i32, PyErr> {
             // 1. 读取请求包结构
             let memory = match self.get_memory() {
@@ -514,76 +1914,428 @@ impl PyBoxReactorCore {
                 }
             };
 
-            // 3. 查找 Python handler
+            // 2. 系统 handle 由 host 原生处理
+            if handle == STREAM_HANDLE {
+                if self.recorder.replaying() {
+                    let req = req_data.to_vec();
+                    return self.replay_ioctl(&mut caller, handle, &req, resp_ptr);
+                }
+                let req = String::from_utf8_lossy(req_data).into_owned();
+                return self.handle_stream_request(py, &mut caller, &req, resp_ptr);
+            }
+            if handle == OUTPUT_HANDLE {
+                let req = req_data.to_vec();
+                return self.handle_output_request(py, &mut caller, &req, resp_ptr);
+            }
+            if handle == BUDGET_HANDLE {
+                let remaining = self.remaining_budget(&caller);
+                let resp_data = remaining.map(|fuel| fuel.to_string()).unwrap_or_default();
+                return Ok(self.write_ioctl_response(
+                    &mut caller,
+                    resp_ptr,
+                    resp_data.as_bytes(),
+                    false,
+                ));
+            }
+            if handle >= SYSTEM_HANDLE_BASE {
+                let Some(resp_data) = self.handle_system_request(handle, req_data) else {
+                    return Ok(-1);
+                };
+                return Ok(self.write_ioctl_response(&mut caller, resp_ptr, &resp_data, false));
+            }
+
+            // 带压缩标记的请求是分帧数据，响应同样需要分帧
+            let framed = handle & COMPRESSED_HANDLE_FLAG != 0;
+            let handle = handle & !COMPRESSED_HANDLE_FLAG;
+            self.counters.handler_call(handle);
+            let req_data = if framed {
+                // 压缩前的请求在 guest 内存中，不会超过内存的大小；handler 限制了请求大小时以它为准
+                let max_request_bytes = self.handler_quotas.get(handle).max_request_bytes;
+                let limit = memory
+                    .data_size(&caller)
+                    .min(max_request_bytes.unwrap_or(usize::MAX));
+                match compress::decode(req_data, limit) {
+                    Ok(data) => data,
+                    Err(e @ compress::DecodeError::TooLarge(_))
+                        if max_request_bytes == Some(limit) =>
+                    {
+                        let resp_data = handler_quota::rejected_response(
+                            handle,
+                            self.handler_name(handle).as_deref(),
+                            &e.to_string(),
+                        );
+                        self.write_ioctl_response(&mut caller, resp_ptr, &resp_data, false);
+                        return Ok(-1);
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to decode request frame: {}", e);
+                        return Ok(-1);
+                    }
+                }
+            } else {
+                std::borrow::Cow::Borrowed(req_data)
+            };
+
+            // 不在环境 ACL 中的 handler 拒绝调用，响应不分帧
+            let env_id = self.active_env();
+            let handler_name = self.handler_name(handle);
+            if !self.acls.allowed(&env_id, handle, handler_name.as_deref()) {
+                let resp_data = acl::denied_response(&env_id, handle, handler_name.as_deref());
+                self.write_ioctl_response(&mut caller, resp_ptr, &resp_data, false);
+                return Ok(-1);
+            }
+
+            // 回放模式下按记录返回响应，不调用 handler
+            if self.recorder.replaying() {
+                let req = req_data.into_owned();
+                return self.replay_ioctl(&mut caller, handle, &req, resp_ptr);
+            }
+
+            // 3. 查找 handler
             let handler = match self.handlers.get(&handle) {
                 Some(h) => h.clone_ref(py),
                 None => return Ok(-1), // Handler 不存在
             };
+            if let Err(message) = self.handler_quotas.acquire(handle, req_data.len()) {
+                let resp_data =
+                    handler_quota::rejected_response(handle, handler_name.as_deref(), &message);
+                self.write_ioctl_response(&mut caller, resp_ptr, &resp_data, false);
+                return Ok(-1);
+            }
+            let started = std::time::Instant::now();
+            let native = matches!(handler, Handler::Native(_));
+            let request_bytes = req_data.len();
+            let audit_fields = |record: &mut serde_json::Map<String, serde_json::Value>| {
+                record.insert("handle".into(), handle.into());
+                record.insert("handler_name".into(), self.handler_name(handle).into());
+                record.insert("native".into(), native.into());
+                record.insert("request_bytes".into(), request_bytes.into());
+            };
+            let span = self.tracer.start(py, "pybox.handler", |attrs| {
+                attrs.set_item("pybox.env_id", self.active_env())?;
+                attrs.set_item("pybox.handle", handle)?;
+                if let Some(name) = self.handler_name(handle) {
+                    attrs.set_item("pybox.handler_name", name)?;
+                }
+                attrs.set_item("pybox.native", native)?;
+                attrs.set_item("pybox.request_bytes", request_bytes)
+            });
+            let (handler, context) = match handler {
+                Handler::Python(func) => (func, None),
+                Handler::PythonWithContext(func) => {
+                    let context = PyBoxHandlerContext::new(
+                        self.active_env(),
+                        handle,
+                        self.handler_name(handle),
+                        seq,
+                        self.codec_options(),
+                    );
+                    (func, Some(Py::new(py, context)?))
+                }
+                Handler::Native(native) => {
+                    // 内置 handler 直接处理，不经过 Python，处理期间释放 GIL
+                    let env_id = self.active_env();
+                    let resp_data = py.detach(|| native.call(&env_id, &req_data));
+                    self.audit
+                        .record(py, "handler", &env_id, started, None, audit_fields);
+                    if let Err(message) =
+                        self.handler_quotas.check_response(handle, resp_data.len())
+                    {
+                        let resp_data = handler_quota::rejected_response(
+                            handle,
+                            handler_name.as_deref(),
+                            &message,
+                        );
+                        self.write_ioctl_response(&mut caller, resp_ptr, &resp_data, false);
+                        return Ok(-1);
+                    }
+                    if let Some(span) = span {
+                        span.set_attribute(py, "pybox.response_bytes", resp_data.len());
+                        span.end(py, None);
+                    }
+                    self.recorder.ioctl(handle, &req_data, &resp_data, framed);
+                    return Ok(self.write_ioctl_response(
+                        &mut caller,
+                        resp_ptr,
+                        &resp_data,
+                        framed,
+                    ));
+                }
+            };
 
             // 4. 调用 Python handler（PyBytes::new 内部会拷贝数据，但我们避免了中间 Vec 的分配）
-            let req_pybytes = PyBytes::new(py, req_data);
-            let resp_result = match handler.call1(py, (req_pybytes,)) {
+            // handler 执行期间记录 Caller，handler 内部可以安全地重入 guest
+            let req_pybytes = PyBytes::new(py, &req_data);
+            let resp_result = {
+                let _guard = self.enter_handler(&mut caller);
+                match &context {
+                    Some(context) => {
+                        let kwargs = PyDict::new(py);
+                        kwargs.set_item("ctx", context)?;
+                        handler.call(py, (&req_pybytes,), Some(&kwargs))
+                    }
+                    None => handler.call1(py, (&req_pybytes,)),
+                }
+            };
+            self.audit.record(
+                py,
+                "handler",
+                &self.active_env(),
+                started,
+                resp_result.as_ref().err(),
+                audit_fields,
+            );
+            if let Some(span) = span {
+                span.end(py, resp_result.as_ref().err());
+            }
+            let resp_result = match resp_result {
                 Ok(result) => result,
                 Err(e) => {
                     // python 异常, 需要传递
+                    self.recorder
+                        .ioctl_error(handle, req_pybytes.as_bytes(), &e.to_string());
                     return Err(e);
                 }
             };
 
+            // ctx.emit 发送的部分响应排在返回值之前：分帧模式下作为流逐块响应，否则拼接
+            let chunks = context.map_or_else(Vec::new, |context| context.get().take_chunks());
+            let resp_result = if chunks.is_empty() {
+                resp_result
+            } else {
+                let resp_bound = resp_result.bind(py);
+                match resp_bound.cast_exact::<PyBytes>() {
+                    Ok(bytes) if !framed => {
+                        let mut joined = Vec::new();
+                        for chunk in chunks.iter().map(|chunk| chunk.bind(py)).chain([bytes]) {
+                            joined.extend_from_slice(chunk.as_bytes());
+                        }
+                        PyBytes::new(py, &joined).into_any().unbind()
+                    }
+                    resp => {
+                        let rest = match resp {
+                            Ok(bytes) => PyList::new(py, [bytes])?.into_any(),
+                            Err(_) => resp_bound.clone(),
+                        };
+                        py.import("itertools")?
+                            .getattr("chain")?
+                            .call1((PyList::new(py, chunks)?, rest))?
+                            .unbind()
+                    }
+                }
+            };
+
             // 5. 提取响应数据（已经是零拷贝：as_bytes 返回引用）
             let resp_bound = resp_result.bind(py);
             let resp_bytes: &pyo3::Bound<'_, PyBytes> = match resp_bound.cast_exact() {
                 Ok(bytes) => bytes,
+                // 分帧模式下，返回迭代器的 handler 以流的形式逐块响应
+                Err(_) if framed => match resp_bound.try_iter() {
+                    Ok(iter) => {
+                        let stream_id = self.next_stream_id.fetch_add(1, Ordering::SeqCst);
+                        self.streams
+                            .insert((self.active_env(), stream_id), iter.into_any().unbind());
+                        let frame = compress::stream_frame(stream_id);
+                        self.recorder
+                            .ioctl(handle, req_pybytes.as_bytes(), &frame, false);
+                        return Ok(self.write_ioctl_response(&mut caller, resp_ptr, &frame, false));
+                    }
+                    Err(e) => {
+                        eprintln!("Response is neither bytes nor iterable: {:?}", e);
+                        return Ok(-1);
+                    }
+                },
                 Err(e) => {
                     eprintln!("Response is not bytes type: {:?}", e);
                     return Ok(-1);
                 }
             };
             let resp_data: &[u8] = resp_bytes.as_bytes();
+            if let Err(message) = self.handler_quotas.check_response(handle, resp_data.len()) {
+                let resp_data =
+                    handler_quota::rejected_response(handle, handler_name.as_deref(), &message);
+                self.write_ioctl_response(&mut caller, resp_ptr, &resp_data, false);
+                return Ok(-1);
+            }
+
+            // 6. 写回响应
+            self.recorder
+                .ioctl(handle, req_pybytes.as_bytes(), resp_data, framed);
+            Ok(self.write_ioctl_response(&mut caller, resp_ptr, resp_data, framed))
+        })
+    }
+
+    // 处理流式响应的拉取请求
+    fn handle_stream_request(
+        &self,
+        py: Python<'_>,
+        caller: &mut wasmtime::Caller<'_, StoreData>,
+        req: &str,
+        resp_ptr: WasmPtr,
+    ) -> Result<i32, PyErr> {
+        let Some((op, stream_id)) = req.split_once(':') else {
+            return Ok(-1);
+        };
+        let Ok(stream_id) = stream_id.parse::<u32>() else {
+            return Ok(-1);
+        };
+        let key = (self.active_env(), stream_id);
+
+        if op == "close" {
+            self.streams.remove(&key);
+            self.recorder
+                .ioctl(STREAM_HANDLE, req.as_bytes(), &[], false);
+            return Ok(self.write_ioctl_response(caller, resp_ptr, &[], false));
+        }
+        if op != "next" {
+            return Ok(-1);
+        }
+
+        let Some(iter) = self.streams.get(&key).map(|iter| iter.clone_ref(py)) else {
+            return Ok(-1);
+        };
 
-            // 6. 在 WASM 内存中分配响应缓冲区
-            let resp_buf_ptr = match self.allocate_buffer(&mut caller, resp_data.len() as u32) {
-                Ok(ptr) => ptr,
+        // 跳过空块，迭代结束时返回空响应
+        let chunk = loop {
+            // 迭代期间记录 Caller，迭代器内部可以安全地重入 guest
+            let next = {
+                let _guard = self.enter_handler(caller);
+                iter.bind(py).call_method0("__next__")
+            };
+            let chunk = match next {
+                Ok(chunk) => chunk,
+                Err(e) if e.is_instance_of::<pyo3::exceptions::PyStopIteration>(py) => {
+                    self.streams.remove(&key);
+                    break Vec::new();
+                }
                 Err(e) => {
-                    eprintln!("Failed to allocate buffer: {}", e);
-                    return Ok(-1);
+                    self.streams.remove(&key);
+                    self.recorder
+                        .ioctl_error(STREAM_HANDLE, req.as_bytes(), &e.to_string());
+                    return Err(e);
                 }
             };
-
-            // 7. 写入响应数据
-            if let Err(e) = self.write_memory_bytes(&mut caller, resp_buf_ptr, resp_data) {
-                eprintln!("Failed to write response data: {}", e);
-                let _ = self.free_buffer(&mut caller, resp_buf_ptr);
-                return Ok(-1);
-            }
-
-            // 8. 写入响应包结构
-            let resp_packet = IoctlPacket {
-                buf: resp_buf_ptr,
-                buf_len: resp_data.len() as WasmSize,
+            let chunk = match chunk.cast::<PyBytes>() {
+                Ok(bytes) => bytes.as_bytes().to_vec(),
+                Err(_) => chunk.extract::<String>()?.into_bytes(),
             };
-
-            if let Err(e) = resp_packet.write_to_memory(memory, &mut caller, resp_ptr) {
-                eprintln!("Failed to write response packet: {}", e);
-                let _ = self.free_buffer(&mut caller, resp_buf_ptr);
-                return Ok(-1);
+            if !chunk.is_empty() {
+                break chunk;
             }
+        };
 
-            // 9. 返回成功（0 表示成功，非 0 表示失败）
-            Ok(0)
-        })
+        self.recorder
+            .ioctl(STREAM_HANDLE, req.as_bytes(), &chunk, false);
+        Ok(self.write_ioctl_response(caller, resp_ptr, &chunk, false))
     }
-}
 
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::thread;
+    // 回放模式下写回下一条记录的响应，记录的 handler 异常重新抛出
+    fn replay_ioctl(
+        &self,
+        caller: &mut wasmtime::Caller<'_, StoreData>,
+        handle: HandleId,
+        req_data: &[u8],
+        resp_ptr: WasmPtr,
+    ) -> Result<i32, PyErr> {
+        let (resp_data, framed) = self
+            .recorder
+            .next(handle, req_data)
+            .map_err(PyBoxReplayError::new_err)?
+            .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+        Ok(self.write_ioctl_response(caller, resp_ptr, &resp_data, framed))
+    }
 
-#[pyclass(subclass)]
-pub struct PyBoxReactor {
-    pub core: Option<Arc<PyBoxReactorCore>>,
-    pub store: Option<std::cell::UnsafeCell<wasmtime::Store<WasiP1Ctx>>>,
-    owner_thread_raw: AtomicU64,
-}
+    // 在 WASM 内存中分配响应缓冲区并写入响应包，返回 0 表示成功
+    // framed 为 true 时响应按分帧格式写入，超过阈值的响应会被压缩
+    fn write_ioctl_response(
+        &self,
+        caller: &mut wasmtime::Caller<'_, StoreData>,
+        resp_ptr: WasmPtr,
+        resp_data: &[u8],
+        framed: bool,
+    ) -> i32 {
+        let Some(memory) = self.get_memory() else {
+            eprintln!("Memory not available");
+            return -1;
+        };
+
+        let frame;
+        let resp_data = if framed {
+            frame = compress::encode(resp_data, self.compress_threshold.unwrap_or(usize::MAX));
+            frame.as_slice()
+        } else {
+            resp_data
+        };
+
+        // 1. 在 WASM 内存中分配响应缓冲区
+        let resp_buf_ptr = match self.allocate_buffer(&mut *caller, resp_data.len() as u32) {
+            Ok(ptr) => ptr,
+            Err(e) => {
+                eprintln!("Failed to allocate buffer: {}", e);
+                return -1;
+            }
+        };
+
+        // 2. 写入响应数据
+        if let Err(e) = self.write_memory_bytes(&mut *caller, resp_buf_ptr, resp_data) {
+            eprintln!("Failed to write response data: {}", e);
+            let _ = self.free_buffer(&mut *caller, resp_buf_ptr);
+            return -1;
+        }
+
+        // 3. 写入响应包结构
+        let resp_packet = IoctlPacket {
+            buf: resp_buf_ptr,
+            buf_len: resp_data.len() as WasmSize,
+        };
+
+        if let Err(e) = resp_packet.write_to_memory(memory, caller, resp_ptr) {
+            eprintln!("Failed to write response packet: {}", e);
+            let _ = self.free_buffer(&mut *caller, resp_buf_ptr);
+            return -1;
+        }
+
+        // 0 表示成功，非 0 表示失败
+        0
+    }
+}
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+
+/// guest 调用失败时的异常：handler 抛出的 Python 异常原样传递，fuel 耗尽时为 PyBoxQuotaError
+fn guest_error(err: wasmtime::Error) -> PyErr {
+    if let Some(wasmtime::Trap::OutOfFuel) = err.downcast_ref::<wasmtime::Trap>() {
+        return PyBoxQuotaError::new_err("Fuel budget exhausted during execution");
+    }
+    match err.downcast::<PyErr>() {
+        Ok(err) => err,
+        Err(err) => {
+            pyo3::exceptions::PyRuntimeError::new_err(format!("Wasmtime runtime error: {}", err))
+        }
+    }
+}
+
+#[pyclass(subclass)]
+pub struct PyBoxReactor {
+    pub core: Option<Arc<PyBoxReactorCore>>,
+    pub store: Option<std::cell::UnsafeCell<wasmtime::Store<StoreData>>>,
+    owner_thread_raw: AtomicU64,
+    /// handler 内部重入 guest 的最大深度
+    max_call_depth: usize,
+    /// 实例化使用的模块和目录映射，trim 时重新实例化
+    module: Option<Arc<wasmtime::Module>>,
+    preopen_dirs: HashMap<String, String>,
+    /// wasm 文件内容的 sha256
+    module_hash: String,
+    /// 创建时恢复的快照，recycle 时原地恢复到它
+    baseline: std::sync::OnceLock<Py<PyBoxReactorSnapshot>>,
+    /// 延迟实例化的 reactor 在实例化后恢复的预热快照
+    deferred_prewarm: Option<Py<PyBoxReactorSnapshot>>,
+}
+
+/// 默认的 handler 重入深度限制
+const DEFAULT_MAX_CALL_DEPTH: usize = 16;
 
 /// 支持多线程存储
 unsafe impl Sync for PyBoxReactor {}
@@ -611,76 +2363,279 @@ impl PyBoxReactor {
                 "PyboxReactor using by another thread!",
             ));
         }
+        // 清除上一次调用结束后才到达的 cancel 请求
+        if is_initial && let Some(core) = &self.core {
+            core.cancel_requested.store(false, Ordering::SeqCst);
+            if let Some(tracker) = core.dirty_tracker.get() {
+                tracker.enter();
+            }
+        }
 
         let result = f();
 
         // 3. 只有第一层调用负责清零
         if is_initial {
+            if let Some(core) = &self.core {
+                core.interrupted.store(false, Ordering::SeqCst);
+                core.cancel_requested.store(false, Ordering::SeqCst);
+                if let Some(tracker) = core.dirty_tracker.get() {
+                    tracker.exit();
+                }
+            }
             self.owner_thread_raw.store(0, Ordering::SeqCst);
         }
 
         result
     }
-}
 
-#[pymethods]
-impl PyBoxReactor {
-    #[new]
-    #[pyo3(signature = (*_args, **_kwargs))]
-    fn new(
-        _args: &Bound<'_, pyo3::types::PyTuple>,
-        _kwargs: Option<&Bound<'_, pyo3::types::PyDict>>,
-    ) -> Self {
-        Self {
-            core: None,
-            store: None,
-            owner_thread_raw: AtomicU64::new(0),
+    /// 获取进入 guest 使用的 store 上下文
+    ///
+    /// 在 handler 内部重入时复用最内层 handler 的 Caller 上下文，
+    /// 避免与正在执行的外层调用同时持有 Store 的可变引用；
+    /// 重入深度达到 max_call_depth 时抛出 RecursionError
+    pub fn store_context(&self) -> pyo3::PyResult<wasmtime::StoreContextMut<'_, StoreData>> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+
+        if core.active_caller().is_some() && core.call_depth() >= self.max_call_depth {
+            return Err(pyo3::exceptions::PyRecursionError::new_err(format!(
+                "PyBoxReactor maximum call depth ({}) exceeded",
+                self.max_call_depth
+            )));
         }
+        self.memory_context()
     }
 
-    /// Initialize the PyBoxReactor instance
-    ///
-    /// Args:
-    ///     wasmfile: Path to the WASM file
-    ///     preopen_dirs: Optional dict mapping guest paths to host paths
-    #[pyo3(signature = (wasmfile, preopen_dirs=None))]
-    fn __init__(
-        &mut self,
-        wasmfile: &str,
-        preopen_dirs: Option<HashMap<String, String>>,
+    /// 释放 guest 端导出的视图
+    pub fn release_view(&self, view_id: u32) -> pyo3::PyResult<()> {
+        self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+            let mut store = self.store_context()?;
+            let pybox_view_release = core.view_release.get().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("Failed to get pybox_view_release")
+            })?;
+            pybox_view_release
+                .call(&mut store, view_id)
+                .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+            Ok(())
+        })
+    }
+
+    /// wasm 文件内容的 sha256 十六进制
+    pub fn module_hash(&self) -> &str {
+        &self.module_hash
+    }
+
+    /// 记录视图导出或释放了一个 buffer，仍有导出的 buffer 时 trim、recycle 和快照恢复
+    /// 不能替换内存，guest 的 memory.grow 失败
+    pub fn view_exported(&self, exported: bool) {
+        if let Some(core) = &self.core {
+            if exported {
+                core.view_exports.fetch_add(1, Ordering::SeqCst);
+            } else {
+                core.view_exports.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// 获取只访问 guest 内存、不进入 guest 的 store 上下文，不受重入深度限制
+    pub fn memory_context(&self) -> pyo3::PyResult<wasmtime::StoreContextMut<'_, StoreData>> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+
+        // 心跳回调在 guest 执行中途调用，没有可以复用的 Caller
+        if core.heartbeat.in_callback() {
+            return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                "PyBoxReactor cannot be used from a heartbeat callback",
+            ));
+        }
+        if let Some(caller) = core.active_caller() {
+            // SAFETY: Caller 在 handler 返回前一直有效，且只在当前线程使用
+            return Ok(unsafe { &mut *caller }.as_context_mut());
+        }
+
+        // 从 UnsafeCell 获取可变指针
+        let store_ptr = self
+            .store
+            .as_ref()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Store not initialized"))?
+            .get();
+        // 延迟实例化的 reactor 在第一次访问 guest 时实例化
+        if core.instance.get().is_none() {
+            self.instantiate_deferred(unsafe { &mut *store_ptr })?;
+        }
+        Ok(unsafe { &mut *store_ptr }.as_context_mut())
+    }
+
+    /// 实例化 lazy 模式下推迟的模块，并恢复推迟的预热快照
+    fn instantiate_deferred(&self, store: &mut wasmtime::Store<StoreData>) -> pyo3::PyResult<()> {
+        let (Some(core), Some(module)) = (self.core.as_ref(), self.module.as_ref()) else {
+            return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                "PyBoxReactor not initialized",
+            ));
+        };
+        Self::instantiate_module(core, module, store)?;
+        Python::attach(|py| {
+            if let Some(snapshot) = &self.deferred_prewarm {
+                let snapshot = snapshot.clone_ref(py);
+                if snapshot.borrow(py).check_compatible(self).is_ok() {
+                    snapshot.borrow(py).restore_with(py, self, false)?;
+                    let _ = self.baseline.set(snapshot);
+                    return Ok(());
+                }
+            }
+            let snapshot = PyBoxReactorSnapshot::capture(self, core, store)?;
+            let _ = self.baseline.set(Py::new(py, snapshot)?);
+            Ok(())
+        })
+    }
+
+    /// 保存当前状态作为 recycle 原地恢复的基线
+    fn capture_baseline(&self, py: pyo3::Python) -> pyo3::PyResult<()> {
+        let snapshot = self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+            PyBoxReactorSnapshot::capture(self, core, self.store_context()?)
+        })?;
+        let _ = self.baseline.set(Py::new(py, snapshot)?);
+        Ok(())
+    }
+
+    /// 清除所有环境、历史和流，内存恢复到 baseline，重建空的环形缓冲区
+    fn reset_to_baseline(
+        &self,
+        py: pyo3::Python,
+        baseline: Option<&Py<PyBoxReactorSnapshot>>,
+        rings: Vec<(String, WasmSize)>,
     ) -> pyo3::PyResult<()> {
+        let core = self.core.clone().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        core.set_env_records(&[]);
+        core.history.clear_all();
+        core.streams.clear();
+
+        if let Some(baseline) = baseline {
+            let baseline = baseline.borrow(py);
+            baseline.restore(py, self)?;
+            // 快照之外增长的内存可能留有之前的数据
+            self.safe_access(|| {
+                let mut store = self.store_context()?;
+                if let Some(memory) = core.get_memory()
+                    && let Some(tail) = memory.data_mut(&mut store).get_mut(baseline.len()..)
+                {
+                    tail.fill(0);
+                }
+                Ok(())
+            })?;
+        }
+
+        self.safe_access(|| {
+            let mut store = self.store_context()?;
+            for (name, capacity) in rings {
+                core.create_ring(&mut store, &name, capacity)
+                    .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+            }
+            // watchdog 的快照属于回收之前的状态
+            if let Some(memory) = core.get_memory() {
+                let state = core.capture_state(&mut store);
+                core.watchdog.rebase(memory.data(&store), state);
+            }
+            Ok(())
+        })
+    }
+
+    /// 创建 Store 并实例化模块，由 core 完成初始化
+    fn instantiate_store(
+        core: &Arc<PyBoxReactorCore>,
+        module: &wasmtime::Module,
+        preopen_dirs: &HashMap<String, String>,
+    ) -> pyo3::PyResult<wasmtime::Store<StoreData>> {
+        let mut store = Self::create_store(core, module.engine(), preopen_dirs)?;
+        Self::instantiate_module(core, module, &mut store)?;
+        Ok(store)
+    }
+
+    /// 创建配置好 WASI、epoch 回调和内存上限的 Store，还没有实例化模块
+    fn create_store(
+        core: &Arc<PyBoxReactorCore>,
+        engine: &wasmtime::Engine,
+        preopen_dirs: &HashMap<String, String>,
+    ) -> pyo3::PyResult<wasmtime::Store<StoreData>> {
+        let mut store = Self::create_bare_store(core, engine, preopen_dirs)?;
+        Self::watch_epoch(core, &mut store);
+        Ok(store)
+    }
+
+    /// 创建配置好 WASI 和内存上限的 Store，不持有 core 的引用
+    fn create_bare_store(
+        core: &PyBoxReactorCore,
+        engine: &wasmtime::Engine,
+        preopen_dirs: &HashMap<String, String>,
+    ) -> pyo3::PyResult<wasmtime::Store<StoreData>> {
         // 创建 WASI 上下文构建器
         let mut builder = WasiCtxBuilder::new();
 
         // 配置 preopen_dirs (虚拟文件系统映射)
-        if let Some(dirs) = preopen_dirs {
-            for (guest_path, host_path) in dirs {
-                builder
-                    .preopened_dir(
-                        &host_path,
-                        &guest_path,
-                        wasmtime_wasi::DirPerms::all(),
-                        wasmtime_wasi::FilePerms::all(),
-                    )
-                    .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
-            }
+        for (guest_path, host_path) in preopen_dirs {
+            builder
+                .preopened_dir(
+                    host_path,
+                    guest_path,
+                    wasmtime_wasi::DirPerms::all(),
+                    wasmtime_wasi::FilePerms::all(),
+                )
+                .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
         }
 
-        // 构建 WASI Preview 1 上下文
-        let wasi_ctx = builder.build_p1();
+        // 构建 WASI Preview 1 上下文，内存增长前检查上限
+        let data = StoreData {
+            wasi: builder.build_p1(),
+            limiter: MemoryLimiter::new(
+                Arc::clone(&core.memory_limit),
+                Arc::clone(&core.view_exports),
+            ),
+            id: STORE_IDS.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+        };
 
         // 创建 Store
-        let mut store = wasmtime::Store::new(&**DEFAULT_ENGINE, wasi_ctx);
+        let mut store = wasmtime::Store::new(engine, data);
+        // 预算之外不限制执行，start_metering 按环境的预算设置 fuel
+        if core.fuel {
+            store
+                .set_fuel(u64::MAX)
+                .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+        }
+        store.limiter(|data| &mut data.limiter);
+        Ok(store)
+    }
+
+    /// 每个 epoch 检查一次 host 的信号，回调持有 core 的引用
+    fn watch_epoch(core: &Arc<PyBoxReactorCore>, store: &mut wasmtime::Store<StoreData>) {
+        store.set_epoch_deadline(1);
+        let core_epoch = Arc::clone(core);
+        store.epoch_deadline_callback(move |ctx| core_epoch.on_epoch(ctx));
+    }
 
+    /// 在 store 中实例化模块，运行 _initialize 并解析导出函数
+    fn instantiate_module(
+        core: &Arc<PyBoxReactorCore>,
+        module: &wasmtime::Module,
+        store: &mut wasmtime::Store<StoreData>,
+    ) -> pyo3::PyResult<()> {
         // 创建 Linker
-        let mut linker = wasmtime::Linker::new(&**DEFAULT_ENGINE);
+        let mut linker = wasmtime::Linker::new(module.engine());
 
         // 将 WASI Preview 1 添加到 linker
-        wasmtime_wasi::preview1::add_to_linker_sync(&mut linker, |s| s)
+        wasmtime_wasi::preview1::add_to_linker_sync(&mut linker, |s: &mut StoreData| &mut s.wasi)
             .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
 
-        let core = Arc::new(PyBoxReactorCore::new());
-        let core_clone = Arc::clone(&core);
+        let core_clone = Arc::clone(core);
 
         // 添加自定义的符号到 linker
         // (handle: HandleId, req_ptr: WasmPtr, resp_ptr: WasmPtr) -> i32
@@ -689,7 +2644,7 @@ impl PyBoxReactor {
             .func_wrap(
                 "env",
                 "pybox_ioctl_host_req_impl",
-                move |caller: wasmtime::Caller<'_, WasiP1Ctx>,
+                move |caller: wasmtime::Caller<'_, StoreData>,
                       handle: HandleId,
                       req_ptr: WasmPtr,
                       resp_ptr: WasmPtr|
@@ -701,44 +2656,386 @@ impl PyBoxReactor {
             )
             .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
 
-        // 从缓存加载或编译 WASM 模块
-        let cache_key = ModuleCacheKey::new(Arc::clone(&DEFAULT_ENGINE), wasmfile.to_string());
+        // 使用 core.init 一次性完成所有初始化
+        core.init(&linker, store, module)
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
+
+        Ok(())
+    }
+
+    /// 以新的 instance 替换当前的 instance，返回需要重新创建的环形缓冲区
+    ///
+    /// 新 Store 先创建好，创建失败时旧的 instance 不受影响；替换后实例化失败时
+    /// 保留新 Store，reactor 回到 lazy 状态，下次使用时重新实例化
+    fn reinstantiate(&mut self) -> pyo3::PyResult<Vec<(String, WasmSize)>> {
+        let module = self.module.clone().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        if core.call_depth() > 0 || core.heartbeat.in_callback() {
+            return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                "PyBoxReactor cannot be re-instantiated from a handler",
+            ));
+        }
+        // 旧 Store 中的回调持有 core 的引用，Store 释放后 core 必须只剩 reactor 持有，
+        // 否则重置会失败，先检查再改动任何状态
+        let store_refs = match self.store {
+            Some(_) => STORE_CORE_REFS + usize::from(core.instance.get().is_some()),
+            None => 0,
+        };
+        if Arc::strong_count(core) != 1 + store_refs || Arc::weak_count(core) != 0 {
+            return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                "PyBoxReactor is in use",
+            ));
+        }
+
+        // epoch 回调在重置之后再挂上，新 Store 暂不持有 core
+        let mut store = Self::create_bare_store(core, module.engine(), &self.preopen_dirs)?;
+        self.store = None;
+        let rings = self
+            .core
+            .as_mut()
+            .and_then(Arc::get_mut)
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor is in use"))?
+            .reset_instance();
+        let core = self.core.clone().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        Self::watch_epoch(&core, &mut store);
+        let result = Self::instantiate_module(&core, &module, &mut store);
+        self.store = Some(std::cell::UnsafeCell::new(store));
+        result.map(|_| rings)
+    }
 
-        let module = if let Some(cached) = MODULE_CACHES.get(&cache_key) {
+    /// 从缓存加载或编译 WASM 模块，返回模块和文件内容的 sha256
+    pub fn load_module(
+        py: pyo3::Python<'_>,
+        engine: &Arc<wasmtime::Engine>,
+        wasmfile: &str,
+    ) -> pyo3::PyResult<(Arc<wasmtime::Module>, String)> {
+        let cache_key = ModuleCacheKey::new(Arc::clone(engine), wasmfile.to_string());
+        if let (Some(cached), Some(hash)) =
+            (MODULE_CACHES.get(&cache_key), MODULE_HASHES.get(&cache_key))
+        {
             // 缓存命中，直接使用
-            Arc::clone(&cached)
-        } else {
-            // 缓存未命中，加载并缓存
-            let module = Arc::new(
-                wasmtime::Module::from_file(&**DEFAULT_ENGINE, wasmfile)
-                    .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?,
-            );
-            MODULE_CACHES.insert(cache_key.clone(), Arc::clone(&module));
-            module
+            return Ok((Arc::clone(&cached), hash.clone()));
+        }
+        // 缓存未命中，加载并缓存
+        let wasm = std::fs::read(wasmfile)
+            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+        let module = Arc::new(
+            wasmtime::Module::new(engine, &wasm)
+                .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?,
+        );
+        let hash: String = py
+            .import("hashlib")?
+            .call_method1("sha256", (PyBytes::new(py, &wasm),))?
+            .call_method0("hexdigest")?
+            .extract()?;
+        MODULE_CACHES.insert(cache_key.clone(), Arc::clone(&module));
+        MODULE_HASHES.insert(cache_key, hash.clone());
+        Ok((module, hash))
+    }
+
+    /// 读取并缓存预先初始化的快照，文件不存在时返回 None
+    fn prewarm_snapshot(
+        py: pyo3::Python<'_>,
+        path: &std::path::Path,
+    ) -> pyo3::PyResult<Option<Py<PyBoxReactorSnapshot>>> {
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => {
+                PREWARM_SNAPSHOTS.remove(path);
+                return Ok(None);
+            }
         };
+        let stamp = (metadata.modified().ok(), metadata.len());
+        if let Some(cached) = PREWARM_SNAPSHOTS.get(path)
+            && cached.0 == stamp
+        {
+            return Ok(Some(cached.1.clone_ref(py)));
+        }
+        let snapshot = Py::new(py, PyBoxReactorSnapshot::open(path)?)?;
+        PREWARM_SNAPSHOTS.insert(path.to_path_buf(), (stamp, snapshot.clone_ref(py)));
+        Ok(Some(snapshot))
+    }
 
-        // 使用 core.init 一次性完成所有初始化
-        core.init(&linker, &mut store, &module)
-            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
+    /// 启用追踪时开始一个 span，attributes 填充初始属性
+    fn start_span(
+        &self,
+        py: pyo3::Python<'_>,
+        name: &str,
+        env_id: Option<&str>,
+        attributes: impl FnOnce(&Bound<'_, pyo3::types::PyDict>) -> pyo3::PyResult<()>,
+    ) -> Option<Span> {
+        self.core.as_ref()?.tracer.start(py, name, |attrs| {
+            attrs.set_item("pybox.env_id", env_id.unwrap_or_default())?;
+            attributes(attrs)
+        })
+    }
+
+    /// 记录一条审计日志，fields 填充操作相关的字段
+    fn audit<R>(
+        &self,
+        py: pyo3::Python<'_>,
+        op: &str,
+        env_id: &str,
+        started: std::time::Instant,
+        result: &pyo3::PyResult<R>,
+        fields: impl FnOnce(&mut serde_json::Map<String, serde_json::Value>),
+    ) {
+        if let Some(core) = self.core.as_ref() {
+            core.audit
+                .record(py, op, env_id, started, result.as_ref().err(), fields);
+        }
+    }
+
+    /// 结束 span 并原样返回结果
+    fn end_span<R>(
+        py: pyo3::Python<'_>,
+        span: Option<Span>,
+        result: pyo3::PyResult<R>,
+    ) -> pyo3::PyResult<R> {
+        if let Some(span) = span {
+            span.end(py, result.as_ref().err());
+        }
+        result
+    }
+}
+
+#[pymethods]
+impl PyBoxReactor {
+    #[new]
+    #[pyo3(signature = (*_args, **_kwargs))]
+    fn new(
+        _args: &Bound<'_, pyo3::types::PyTuple>,
+        _kwargs: Option<&Bound<'_, pyo3::types::PyDict>>,
+    ) -> Self {
+        Self {
+            core: None,
+            store: None,
+            owner_thread_raw: AtomicU64::new(0),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            module: None,
+            preopen_dirs: HashMap::new(),
+            module_hash: String::new(),
+            baseline: std::sync::OnceLock::new(),
+            deferred_prewarm: None,
+        }
+    }
+
+    /// Initialize the PyBoxReactor instance
+    ///
+    /// Further options (prewarm, lazy, engine, snapshot) are set with
+    /// PyBoxReactorBuilder
+    ///
+    /// Args:
+    ///     wasmfile: Path to the WASM file
+    ///     preopen_dirs: Optional dict mapping guest paths to host paths
+    ///     max_call_depth: Maximum depth of guest calls nested inside handlers
+    ///     compress_threshold: Handler payloads at least this large are zstd-compressed
+    ///         across the WASM boundary, None disables compression
+    #[pyo3(signature = (wasmfile, preopen_dirs=None, max_call_depth=DEFAULT_MAX_CALL_DEPTH, compress_threshold=Some(compress::DEFAULT_COMPRESS_THRESHOLD)))]
+    fn __init__(
+        &mut self,
+        py: pyo3::Python,
+        wasmfile: &str,
+        preopen_dirs: Option<HashMap<String, String>>,
+        max_call_depth: usize,
+        compress_threshold: Option<usize>,
+    ) -> pyo3::PyResult<()> {
+        self.start(
+            py,
+            wasmfile,
+            preopen_dirs,
+            max_call_depth,
+            compress_threshold,
+            false,
+            false,
+            None,
+        )
+    }
+
+    /// Start the reactor with every option, used by PyBoxReactorBuilder in place of __init__
+    ///
+    /// Args:
+    ///     wasmfile, preopen_dirs, max_call_depth, compress_threshold: As for __init__
+    ///     prewarm: Restore the pre-initialized snapshot shipped next to the WASM
+    ///         file (same name with a `.snapshot` extension) when there is one
+    ///         for this build, skipping the interpreter start-up. Ignored when
+    ///         preopen_dirs is not empty, the snapshot is taken without any
+    ///     lazy: Defer instantiating the module and starting the interpreter
+    ///         until the guest is first used (init_local, exec, assign, ...),
+    ///         so reactors created ahead of time and discarded cost little.
+    ///         Errors of the start-up are then raised by that first call
+    ///     engine: PyBoxEngine to run on, reactors sharing an engine share its
+    ///         compiled modules and configuration. None for PyBoxEngine.default()
+    #[pyo3(name = "_start", signature = (wasmfile, preopen_dirs, max_call_depth, compress_threshold, prewarm, lazy, engine))]
+    #[allow(clippy::too_many_arguments)]
+    fn start(
+        &mut self,
+        py: pyo3::Python,
+        wasmfile: &str,
+        preopen_dirs: Option<HashMap<String, String>>,
+        max_call_depth: usize,
+        compress_threshold: Option<usize>,
+        prewarm: bool,
+        lazy: bool,
+        engine: Option<PyRef<'_, PyBoxEngine>>,
+    ) -> pyo3::PyResult<()> {
+        let preopen_dirs = preopen_dirs.unwrap_or_default();
+        let fuel = engine.as_ref().is_some_and(|engine| engine.fuel());
+        let core = Arc::new(PyBoxReactorCore::new(compress_threshold, fuel));
+
+        let engine = match &engine {
+            Some(engine) => {
+                if let Some(limit) = engine.memory_limit() {
+                    core.memory_limit.store(limit, Ordering::Relaxed);
+                }
+                Arc::clone(engine.engine())
+            }
+            None => Arc::clone(&DEFAULT_ENGINE),
+        };
+        let (module, module_hash) = Self::load_module(py, &engine, wasmfile)?;
+
+        let store = if lazy {
+            Self::create_store(&core, &engine, &preopen_dirs)?
+        } else {
+            Self::instantiate_store(&core, &module, &preopen_dirs)?
+        };
 
         // 设置实例的字段
         self.core = Some(core);
         self.store = Some(std::cell::UnsafeCell::new(store));
+        self.max_call_depth = max_call_depth;
+        self.module = Some(module);
+        self.preopen_dirs = preopen_dirs;
+        self.module_hash = module_hash;
+
+        // 预初始化快照在没有 preopen 目录时生成，guest 启动时记录的 preopen 也在快照中，
+        // 有 preopen 目录的 reactor 按正常方式启动
+        if prewarm && self.preopen_dirs.is_empty() {
+            let path = std::path::Path::new(wasmfile).with_extension("snapshot");
+            if let Some(snapshot) = Self::prewarm_snapshot(py, &path)? {
+                if lazy {
+                    self.deferred_prewarm = Some(snapshot);
+                } else if snapshot.borrow(py).check_compatible(self).is_ok() {
+                    // 其他 wasm 构建的快照被忽略，按正常方式启动
+                    snapshot.borrow(py).restore_with(py, self, false)?;
+                    let _ = self.baseline.set(snapshot);
+                }
+            }
+        }
+        // 没有快照时保存启动后的状态，recycle 原地恢复到它
+        if !lazy && self.baseline.get().is_none() {
+            self.capture_baseline(py)?;
+        }
+
+        Ok(())
+    }
+
+    /// Create a reactor from a snapshot
+    ///
+    /// The reactor is constructed with the given arguments, which must load the
+    /// wasm file the snapshot was taken from, and the snapshot is restored into
+    /// it, so one warmed-up snapshot can start many independent reactors.
+    /// Handlers, rings, limits and other host-side settings are not part of a
+    /// snapshot and are set up on the new reactor as usual.
+    ///
+    /// Args:
+    ///     snapshot: PyBoxReactorSnapshot to start from
+    ///     *args, **kwargs: Passed to the constructor
+    ///
+    /// Returns:
+    ///     A new instance of the class it is called on
+    #[classmethod]
+    #[pyo3(signature = (snapshot, *args, **kwargs))]
+    fn from_snapshot<'py>(
+        cls: &Bound<'py, pyo3::types::PyType>,
+        snapshot: PyRef<'py, PyBoxReactorSnapshot>,
+        args: &Bound<'py, pyo3::types::PyTuple>,
+        kwargs: Option<&Bound<'py, pyo3::types::PyDict>>,
+    ) -> pyo3::PyResult<Bound<'py, PyAny>> {
+        let reactor = cls.call(args, kwargs)?;
+        reactor
+            .cast::<PyBoxReactor>()?
+            .borrow_mut()
+            .restore_baseline(cls.py(), snapshot)?;
+        Ok(reactor)
+    }
 
+    /// Restore a snapshot and keep it as the state recycle() returns to,
+    /// used by from_snapshot and PyBoxReactorBuilder.snapshot
+    ///
+    /// Args:
+    ///     snapshot: PyBoxReactorSnapshot to start from
+    #[pyo3(name = "_restore_baseline")]
+    fn restore_baseline(
+        &mut self,
+        py: pyo3::Python,
+        snapshot: PyRef<'_, PyBoxReactorSnapshot>,
+    ) -> pyo3::PyResult<()> {
+        snapshot.restore(py, self)?;
+        self.baseline = std::sync::OnceLock::from(Py::from(snapshot));
         Ok(())
     }
 
-    /// Register a Python handler for ioctl requests
+    /// Register a handler for ioctl requests
+    ///
+    /// The quotas protect the service behind a handler from sandboxed code
+    /// abusing it. A request that is too large, or a call beyond
+    /// max_calls_per_exec, is rejected before the handler runs; a response that
+    /// is too large is dropped. The sandboxed call then raises
+    /// `pybox.QuotaError`. Calls are counted from the start of each outermost
+    /// exec or call, streamed responses are not limited.
     ///
     /// Args:
     ///     handle: Handler ID
-    ///     func: Python callable that accepts bytes and returns bytes
-    fn register_handler(&self, handle: HandleId, func: Py<PyAny>) -> pyo3::PyResult<()> {
+    ///     func: Python callable that accepts bytes and returns bytes,
+    ///         or a built-in handler such as PyBoxKVStore
+    ///     name: Optional name, guest code can resolve it with pybox.resolve(name)
+    ///     max_request_bytes: Maximum bytes of a request
+    ///     max_response_bytes: Maximum bytes of a response
+    ///     max_calls_per_exec: Maximum calls during one execution
+    #[pyo3(signature = (handle, func, name=None, max_request_bytes=None, max_response_bytes=None, max_calls_per_exec=None))]
+    fn register_handler(
+        &self,
+        py: pyo3::Python,
+        handle: HandleId,
+        func: Py<PyAny>,
+        name: Option<String>,
+        max_request_bytes: Option<usize>,
+        max_response_bytes: Option<usize>,
+        max_calls_per_exec: Option<usize>,
+    ) -> pyo3::PyResult<()> {
         self.safe_access(|| {
             let core = self.core.as_ref().ok_or_else(|| {
                 pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
             })?;
-            core.register_handler(handle, func);
+            if handle >= COMPRESSED_HANDLE_FLAG {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "Handler ID {} is reserved by pybox",
+                    handle
+                )));
+            }
+            let handler = match builtin::native_handler(func.bind(py)) {
+                Some(native) => Handler::Native(native),
+                None if PyBoxHandlerContext::accepted_by(func.bind(py)) => {
+                    Handler::PythonWithContext(func)
+                }
+                None => Handler::Python(func),
+            };
+            core.register_handler(handle, handler, name);
+            core.handler_quotas.set(
+                handle,
+                HandlerQuota {
+                    max_request_bytes,
+                    max_response_bytes,
+                    max_calls_per_exec,
+                },
+            );
             Ok(())
         })
     }
@@ -761,24 +3058,37 @@ impl PyBoxReactor {
 
     /// Initialize a new local environment
     ///
+    /// The encoding is used by the environment's captured stdout / stderr
+    /// (`sys.stdout.encoding`, `sys.stdout.buffer`) and reported by
+    /// `locale.getpreferredencoding()`; text that does not fit it raises
+    /// UnicodeEncodeError with errors="strict" or is replaced with "replace".
+    /// The setting survives trim and is copied by init_local_from.
+    ///
     /// Args:
     ///     env_id: Environment ID
+    ///     encoding: Preferred encoding, "utf-8" when only errors is given
+    ///     errors: Codec error handler, "strict" when only encoding is given
     ///
     /// Returns:
     ///     bool: True if successful, False otherwise
-    fn init_local(&self, env_id: &str) -> pyo3::PyResult<bool> {
+    ///
+    /// Raises:
+    ///     ValueError: If the encoding or error handler is unknown
+    #[pyo3(signature = (env_id, encoding=None, errors=None))]
+    fn init_local(
+        &self,
+        env_id: &str,
+        encoding: Option<&str>,
+        errors: Option<&str>,
+    ) -> pyo3::PyResult<bool> {
         self.safe_access(|| {
             let core = self.core.as_ref().ok_or_else(|| {
                 pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
             })?;
+            core.exec_cache.touch(env_id);
 
-            // 从 UnsafeCell 获取可变指针
-            let store_ptr = self
-                .store
-                .as_ref()
-                .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Store not initialized"))?
-                .get();
-            let store = unsafe { &mut *store_ptr };
+            // handler 内部重入时使用 Caller 的上下文
+            let mut store = self.store_context()?;
 
             let pybox_init_local_func = core.init_local.get().ok_or_else(|| {
                 pyo3::exceptions::PyRuntimeError::new_err("Failed to get pybox_init_local")
@@ -786,14 +3096,14 @@ impl PyBoxReactor {
 
             // ========== 优化：批量分配（虽然只有一个参数，但保持一致性）==========
             let (base_ptr, ptrs) = core
-                .allocate_pybox_bytes_batch(&mut *store, &[env_id.as_bytes()])
+                .allocate_pybox_bytes_batch(&mut store, &[env_id.as_bytes()])
                 .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
 
             let env_id_ptr = ptrs[0];
 
             // 调用 WASM 函数
             let result = pybox_init_local_func
-                .call(&mut *store, env_id_ptr)
+                .call(&mut store, env_id_ptr)
                 .map_err(|e| {
                     pyo3::exceptions::PyRuntimeError::new_err(format!(
                         "pybox_init_local failed: {}",
@@ -802,15 +3112,63 @@ impl PyBoxReactor {
                 })?;
 
             // 清理
-            core.free_buffer(&mut *store, base_ptr)
+            core.free_buffer(&mut store, base_ptr)
                 .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
 
-            Ok(result == 0)
-        })
-    }
+            if result == 0 {
+                core.local_envs.insert(env_id.to_string());
+                core.history.clear(env_id);
+                core.encodings.remove(env_id);
+            }
 
-    /// Initialize a new local environment from an existing one
-    ///
+            // 编码在 prelude 之前设置，失败时删除环境
+            if result == 0 && (encoding.is_some() || errors.is_some()) {
+                let setting = (
+                    encoding.unwrap_or("utf-8").to_string(),
+                    errors.unwrap_or("strict").to_string(),
+                );
+                let applied = core
+                    .set_env_encoding(&mut store, env_id, &setting.0, &setting.1)
+                    .and_then(|applied| {
+                        applied.map_err(|error| {
+                            pyo3::exceptions::PyValueError::new_err(format!(
+                                "Invalid encoding for environment '{}': {}",
+                                env_id, error
+                            ))
+                        })
+                    });
+                if let Err(error) = applied {
+                    drop(store);
+                    self.del_local(env_id)?;
+                    return Err(error);
+                }
+                core.encodings.insert(env_id.to_string(), setting);
+            }
+
+            // 新环境执行 prelude，失败时删除环境
+            if result == 0
+                && let Some(prelude) = core.middleware.prelude(false)
+                && let Err(error) = core.run_hook(&mut store, Some(env_id), &prelude)?
+            {
+                drop(store);
+                self.del_local(env_id)?;
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "PyBox prelude failed: {}",
+                    error
+                )));
+            }
+
+            // 重新创建的环境保留之前设置的上限
+            if result == 0 && core.env_limits.contains_key(env_id) {
+                core.apply_env_limits(&mut store, env_id)?;
+            }
+
+            Ok(result == 0)
+        })
+    }
+
+    /// Initialize a new local environment from an existing one
+    ///
     /// Args:
     ///     env_id: New environment ID
     ///     from_env_id: Source environment ID to copy from
@@ -822,14 +3180,10 @@ impl PyBoxReactor {
             let core = self.core.as_ref().ok_or_else(|| {
                 pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
             })?;
+            core.exec_cache.touch(env_id);
 
-            // 从 UnsafeCell 获取可变指针
-            let store_ptr = self
-                .store
-                .as_ref()
-                .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Store not initialized"))?
-                .get();
-            let store = unsafe { &mut *store_ptr };
+            // handler 内部重入时使用 Caller 的上下文
+            let mut store = self.store_context()?;
 
             let pybox_init_local_from_func = core.init_local_from.get().ok_or_else(|| {
                 pyo3::exceptions::PyRuntimeError::new_err("Failed to get pybox_init_local_from")
@@ -838,7 +3192,7 @@ impl PyBoxReactor {
             // ========== 优化：批量分配两个参数 ==========
             let (base_ptr, ptrs) = core
                 .allocate_pybox_bytes_batch(
-                    &mut *store,
+                    &mut store,
                     &[env_id.as_bytes(), from_env_id.as_bytes()],
                 )
                 .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
@@ -847,7 +3201,7 @@ impl PyBoxReactor {
 
             // 调用 WASM 函数
             let result = pybox_init_local_from_func
-                .call(&mut *store, (env_id_ptr, from_env_id_ptr))
+                .call(&mut store, (env_id_ptr, from_env_id_ptr))
                 .map_err(|e| {
                     pyo3::exceptions::PyRuntimeError::new_err(format!(
                         "pybox_init_local_from failed: {}",
@@ -856,9 +3210,32 @@ impl PyBoxReactor {
                 })?;
 
             // ========== 优化：批量释放（一次调用）==========
-            core.free_buffer(&mut *store, base_ptr)
+            core.free_buffer(&mut store, base_ptr)
                 .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
 
+            if result == 0 {
+                core.local_envs.insert(env_id.to_string());
+                core.history.copy(env_id, from_env_id);
+                core.encodings.remove(env_id);
+            }
+
+            // 新环境有自己的解释器，编码需要重新设置
+            let setting = core
+                .encodings
+                .get(from_env_id)
+                .map(|setting| setting.clone());
+            if result == 0
+                && let Some(setting) = setting
+            {
+                core.set_env_encoding(&mut store, env_id, &setting.0, &setting.1)?
+                    .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+                core.encodings.insert(env_id.to_string(), setting);
+            }
+
+            if result == 0 && core.env_limits.contains_key(env_id) {
+                core.apply_env_limits(&mut store, env_id)?;
+            }
+
             Ok(result == 0)
         })
     }
@@ -875,29 +3252,32 @@ impl PyBoxReactor {
             let core = self.core.as_ref().ok_or_else(|| {
                 pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
             })?;
+            core.exec_cache.touch(env_id);
 
-            // 从 UnsafeCell 获取可变指针
-            let store_ptr = self
-                .store
-                .as_ref()
-                .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Store not initialized"))?
-                .get();
-            let store = unsafe { &mut *store_ptr };
+            // handler 内部重入时使用 Caller 的上下文
+            let mut store = self.store_context()?;
 
             let pybox_del_local_func = core.del_local.get().ok_or_else(|| {
                 pyo3::exceptions::PyRuntimeError::new_err("Failed to get pybox_del_local")
             })?;
 
+            // 删除前执行 teardown，失败时仍然删除环境
+            if let Some(teardown) = core.middleware.teardown(false)
+                && let Err(error) = core.run_hook(&mut store, Some(env_id), &teardown)?
+            {
+                eprintln!("PyBox teardown of '{}' failed: {}", env_id, error);
+            }
+
             // ========== 优化：批量分配（虽然只有一个参数，但保持一致性）==========
             let (base_ptr, ptrs) = core
-                .allocate_pybox_bytes_batch(&mut *store, &[env_id.as_bytes()])
+                .allocate_pybox_bytes_batch(&mut store, &[env_id.as_bytes()])
                 .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
 
             let env_id_ptr = ptrs[0];
 
             // 调用 WASM 函数
             let result = pybox_del_local_func
-                .call(&mut *store, env_id_ptr)
+                .call(&mut store, env_id_ptr)
                 .map_err(|e| {
                     pyo3::exceptions::PyRuntimeError::new_err(format!(
                         "pybox_del_local failed: {}",
@@ -906,9 +3286,21 @@ impl PyBoxReactor {
                 })?;
 
             // 清理
-            core.free_buffer(&mut *store, base_ptr)
+            core.free_buffer(&mut store, base_ptr)
                 .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
 
+            if result == 0 {
+                core.local_envs.remove(env_id);
+                core.protected.remove(env_id);
+                core.encodings.remove(env_id);
+                core.gc_policies.remove(env_id);
+                core.env_limits.remove(env_id);
+                core.history.clear(env_id);
+                core.exec_cache.forget(env_id);
+                core.streams
+                    .retain(|(stream_env, _), _| stream_env != env_id);
+            }
+
             Ok(result == 0)
         })
     }
@@ -926,31 +3318,42 @@ impl PyBoxReactor {
         name: &str,
         value: &Bound<'_, PyAny>,
     ) -> pyo3::PyResult<()> {
-        self.safe_access(|| {
+        let started = std::time::Instant::now();
+        let span = self.start_span(py, "pybox.assign", Some(env_id), |attrs| {
+            attrs.set_item("pybox.name", name)
+        });
+        let result = self.safe_access(|| {
             let core = self.core.as_ref().ok_or_else(|| {
                 pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
             })?;
+            core.exec_cache.touch(env_id);
 
-            // 从 UnsafeCell 获取可变指针
-            let store_ptr = self
-                .store
-                .as_ref()
-                .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Store not initialized"))?
-                .get();
-            let store = unsafe { &mut *store_ptr };
+            core.check_rate_limit(env_id)?;
+            // handler 内部重入时使用 Caller 的上下文
+            let mut store = self.store_context()?;
 
             let pybox_assign_func = core.assign.get().ok_or_else(|| {
                 pyo3::exceptions::PyRuntimeError::new_err("Failed to get pybox_assign")
             })?;
 
-            // 将 value 序列化为 JSON
-            let json_module = py.import("json")?;
-            let json_str: String = json_module.getattr("dumps")?.call1((value,))?.extract()?;
+            // 将 value 序列化为 JSON，注册类型的值替换为带标记的对象
+            let types = core.type_codecs(py);
+            let value = match types.is_empty() {
+                true => value.clone(),
+                false => json::tag_types(value, &types)?,
+            };
+            let json_str = json::dumps(py, &value, core.codec_options(), None)?;
+            core.env_limits(env_id)
+                .check_value_size(env_id, name, json_str.len())?;
+            if json_str.contains(json::TYPE_TAG) {
+                core.install_decoders(py, &mut store, env_id, &types, &json_str)?;
+            }
+            core.apply_codec(&mut store);
 
             // ========== 优化：批量分配所有参数 ==========
             let (base_ptr, ptrs) = core
                 .allocate_pybox_bytes_batch(
-                    &mut *store,
+                    &mut store,
                     &[
                         env_id.as_bytes(),
                         name.as_bytes(),
@@ -965,7 +3368,7 @@ impl PyBoxReactor {
 
             // 调用 WASM 函数
             let result = pybox_assign_func
-                .call(&mut *store, (env_id_ptr, name_ptr, json_ptr, error_ptr_ptr))
+                .call(&mut store, (env_id_ptr, name_ptr, json_ptr, error_ptr_ptr))
                 .map_err(|e| {
                     pyo3::exceptions::PyRuntimeError::new_err(format!("pybox_assign failed: {}", e))
                 })?;
@@ -973,17 +3376,17 @@ impl PyBoxReactor {
             // ========== 优化：零拷贝读取错误信息 ==========
             let error_msg = {
                 let error_data = core
-                    .read_pybox_bytes_ptr_data(&*store, error_ptr_ptr)
+                    .read_pybox_bytes_ptr_data(&store, error_ptr_ptr)
                     .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
 
                 let error_str = String::from_utf8_lossy(error_data).to_string();
 
                 // 释放 WASM 端分配的错误缓冲区
                 let error_ptr = core
-                    .read_u32(&*store, error_ptr_ptr)
+                    .read_u32(&store, error_ptr_ptr)
                     .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
                 if error_ptr != 0 {
-                    core.free_buffer(&mut *store, error_ptr)
+                    core.free_buffer(&mut store, error_ptr)
                         .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
                 }
 
@@ -991,10 +3394,16 @@ impl PyBoxReactor {
             };
 
             // ========== 优化：批量释放参数（一次调用）==========
-            core.free_buffer(&mut *store, base_ptr)
+            core.free_buffer(&mut store, base_ptr)
                 .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
 
             // 检查结果
+            if result != 0 && env_limit::is_limit_error(&error_msg) {
+                return Err(PyBoxEnvLimitError::new_err(format!(
+                    "PyBox assign failed: {}",
+                    error_msg
+                )));
+            }
             if result != 0 {
                 return Err(pyo3::exceptions::PyRuntimeError::new_err(format!(
                     "PyBox assign failed: {}",
@@ -1006,172 +3415,1682 @@ impl PyBoxReactor {
                 )));
             }
 
+            if core.call_depth() == 0 {
+                core.history
+                    .push(env_id, HistoryOp::Assign(name.to_string(), json_str));
+            }
+
             Ok(())
-        })
+        });
+        self.audit(py, "assign", env_id, started, &result, |record| {
+            record.insert("name".into(), name.into());
+        });
+        Self::end_span(py, span, result)
     }
 
     /// Execute Python code in a sandboxed environment
     ///
+    /// A KeyboardInterrupt (Ctrl-C) received while the code runs is raised inside
+    /// the sandbox, exec then returns the partial output with the KeyboardInterrupt
+    /// as `result.exception`. A second interrupt, or a guest that cannot be
+    /// interrupted, stops the execution and raises KeyboardInterrupt from exec.
+    ///
     /// Args:
     ///     code: Python code to execute
     ///     env_id: Optional environment ID. If None, uses global environment
+    ///     stats: Also return the PyBoxExecStats of the execution, kept for
+    ///         compatibility as it is also available as `result.stats`
+    ///     on_output: Optional callable receiving `(stream, text)` for every write to
+    ///         stdout / stderr while the code runs, stream is "stdout" or "stderr".
+    ///         An exception raised by it aborts the execution.
+    ///     max_output: Maximum bytes of captured output, the rest is dropped
+    ///     max_artifacts: Maximum number of artifacts, later ones are dropped
+    ///     max_artifact_bytes: Maximum total bytes of artifact data, artifacts
+    ///         that do not fit are dropped. `result.truncated` tells whether any
+    ///         of the limits was hit.
+    ///     auto_create: Create the environment when it does not exist instead of
+    ///         failing with "Local context not found", as init_local would
+    ///     correlation_id: Optional id sent along with every JSON-RPC request the
+    ///         code makes, next to the calling env id, so handlers can tie the
+    ///         calls of one execution together
+    ///     transactional: Restore the environment's variables and protected
+    ///         names to their state before the exec when the code raises, so a
+    ///         failed step leaves nothing half done. Objects changed in place,
+    ///         like a list appended to, are not restored.
+    ///     cache: Return the result of an earlier cached exec of the same code
+    ///         in this environment when nothing changed the environment since,
+    ///         instead of running it again. The code must be deterministic and
+    ///         must not change the environment, a cached exec does not count as
+    ///         a change. A cached result is returned as is: on_output is not
+    ///         called and the other options are not part of the key. Timed out
+    ///         executions are not cached. A cache hit is still subject to the
+    ///         rate limit and fuel budget of the environment and is audited with
+    ///         `cache_hit` set. See set_exec_cache.
     ///
     /// Returns:
-    ///     str: Output from the execution (stdout + stderr)
-    #[pyo3(signature = (code, env_id=None))]
-    fn exec(&self, code: &str, env_id: Option<&str>) -> pyo3::PyResult<String> {
-        self.safe_access(|| {
+    ///     PyBoxExecResult: stdout, stderr, uncaught exception, repr of a trailing
+    ///         expression and artifacts of the execution. It behaves as the merged
+    ///         output (stdout + stderr) string, which exec returned before.
+    ///         (result, PyBoxExecStats) if stats is True
+    #[pyo3(signature = (code, env_id=None, stats=false, on_output=None, max_output=None, max_artifacts=None, max_artifact_bytes=None, auto_create=false, correlation_id=None, transactional=false, cache=false))]
+    fn exec(
+        &self,
+        py: pyo3::Python,
+        code: &str,
+        env_id: Option<&str>,
+        stats: bool,
+        on_output: Option<Py<PyAny>>,
+        max_output: Option<usize>,
+        max_artifacts: Option<usize>,
+        max_artifact_bytes: Option<usize>,
+        auto_create: bool,
+        correlation_id: Option<String>,
+        transactional: bool,
+        cache: bool,
+    ) -> pyo3::PyResult<Py<PyAny>> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        // 旧版 guest 无法回滚，不能静默地当作普通 exec
+        if transactional && core.set_exec_flags.get().is_none() {
+            return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                "transactional exec is not supported by this PyBox image",
+            ));
+        }
+        // 通过 init_local 创建，host 记录环境并运行环境的 prelude
+        if auto_create
+            && let Some(env_id) = env_id
+            && !core.local_envs.contains(env_id)
+        {
+            self.init_local(env_id, None, None)?;
+        }
+        // before 回调可以改写代码或阻止执行，历史中记录改写前的代码
+        let source = code;
+        let code = core.middleware.before(py, env_id, source)?;
+        let code = code.as_str();
+        // host 的 lint 规则在运行前检查代码，无法解析的代码交给 guest 报告语法错误
+        let lint = match core.middleware.has_lint() {
+            true => match self.ast(py, code) {
+                Ok(tree) => Some(core.middleware.lint(py, env_id, code, tree.bind(py))?),
+                Err(_) => None,
+            },
+            false => None,
+        };
+        if let Some(diagnostics) = &lint
+            && diagnostics.iter().any(|diagnostic| {
+                diagnostic
+                    .get_item("severity")
+                    .and_then(|severity| severity.eq("error"))
+                    .unwrap_or(false)
+            })
+        {
+            let result = PyBoxExecResult::lint_blocked(py, diagnostics.clone())?;
+            let result = match stats {
+                true => (result, py.None()).into_pyobject(py)?.into_any().unbind(),
+                false => result.into_pyobject(py)?.into_any().unbind(),
+            };
+            return core.middleware.after(py, env_id, result);
+        }
+        // 环境的状态版本在执行前读取
+        let cache_env = env_id.unwrap_or_default();
+        let cache_version = core.exec_cache.version(cache_env);
+
+        let started = std::time::Instant::now();
+        let span = self.start_span(py, "pybox.exec", env_id, |attrs| {
+            attrs.set_item("pybox.code_hash", telemetry::code_hash(py, code)?)?;
+            attrs.set_item("pybox.code_length", code.len())
+        });
+        let mut timed_out = false;
+        let mut cache_hit = false;
+        let mut reset = false;
+        let result = self.safe_access(|| {
             let core = self.core.as_ref().ok_or_else(|| {
                 pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
             })?;
-            // 从 UnsafeCell 获取可变指针
-            let store_ptr = self
-                .store
-                .as_ref()
-                .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Store not initialized"))?
-                .get();
-
-            // 通过 unsafe 创建可变引用
-            let store = unsafe { &mut *store_ptr };
+            core.check_rate_limit(env_id.unwrap_or_default())?;
+            // 命中时不运行代码，但与实际执行一样受频率和预算限制，并写入审计日志
+            if cache {
+                if core.call_depth() == 0 {
+                    core.budgets
+                        .limit(cache_env)
+                        .map_err(PyBoxQuotaError::new_err)?;
+                }
+                if let Some(result) = core
+                    .exec_cache
+                    .get(py, cache_env, code, stats, cache_version)
+                {
+                    cache_hit = true;
+                    return Ok(result);
+                }
+            }
+            // handler 内部嵌套的 exec 在回放时会随 handler 一起跳过，只记录最外层的 exec
+            // 回放时 before 回调会再次运行，因此记录改写前的代码
+            if core.call_depth() == 0 {
+                core.recorder.exec(env_id, source);
+            }
+            // handler 内部重入时使用 Caller 的上下文
+            let mut store = self.store_context()?;
+            let _env = core.enter_env(env_id);
 
-            let pybox_exec_func = core.exec.get().ok_or_else(|| {
-                pyo3::exceptions::PyRuntimeError::new_err("Failed to get pybox_exec")
-            })?;
+            let start = std::time::Instant::now();
+            let pages_before = core.get_memory().map_or(0, |memory| memory.size(&store));
+            let ioctl_calls_before = core.ioctl_calls.load(std::sync::atomic::Ordering::Relaxed);
 
-            // ========== 优化：批量分配所有参数 ==========
-            // 准备输入数据切片
-            let mut input_slices = Vec::with_capacity(4);
-            let env_id_index = if let Some(env_id) = env_id {
-                input_slices.push(env_id.as_bytes());
-                Some(input_slices.len() - 1)
-            } else {
-                None
+            // ========== 调用 WASM 函数 ==========
+            // prelude / teardown 与代码一起计量
+            let metering_env = env_id.unwrap_or_default();
+            let metering = core.start_metering(&mut store, metering_env)?;
+            let fuel_before = store.get_fuel().unwrap_or(0);
+            let limits = ExecLimits {
+                max_output,
+                max_artifacts,
+                max_artifact_bytes,
+            };
+            let options = ExecOptions {
+                on_output,
+                max_output,
+                max_artifacts,
+                max_artifact_bytes,
+                correlation_id,
+                transactional,
+                output_sent: 0,
+            };
+            // 心跳和 watchdog 只跟踪最外层的 exec
+            let outermost = core.call_depth() == 0;
+            if outermost {
+                core.heartbeat.start(env_id, fuel_before);
+                core.watchdog.start();
+                core.handler_quotas.reset_calls();
+            }
+            let result = core.run_with_hooks(&mut store, env_id, code, options);
+            let mut aborted = false;
+            if outermost {
+                aborted = core.heartbeat.finish();
+                core.watchdog.finish();
+            }
+            let fuel_consumed = fuel_before.saturating_sub(store.get_fuel().unwrap_or(0));
+            let counters = &core.counters;
+            counters.execs.fetch_add(1, Ordering::Relaxed);
+            counters
+                .guest_time_ns
+                .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+            counters
+                .fuel_consumed
+                .fetch_add(fuel_consumed, Ordering::Relaxed);
+            if let Some(span) = &span {
+                span.set_attribute(py, "pybox.fuel_consumed", fuel_consumed);
+            }
+            core.stop_metering(&mut store, metering_env, metering);
+            let (guest, timeout) = match result {
+                // 超时被终止的 guest 状态不可信，恢复到最近一次正常状态后返回超时结果
+                Err(err) if outermost && err.is_instance_of::<PyBoxTimeoutError>(py) => {
+                    core.restore_watchdog(&mut store)?;
+                    counters.timeouts.fetch_add(1, Ordering::Relaxed);
+                    (GuestExec::default(), Some(err))
+                }
+                // 心跳回调中止的 guest 状态同样不可信，没有 watchdog 的快照时回到启动时的状态
+                Err(err) if aborted => {
+                    counters.traps.fetch_add(1, Ordering::Relaxed);
+                    reset = !core.restore_watchdog(&mut store)?;
+                    return Err(err);
+                }
+                result => {
+                    let guest = result.inspect_err(|_| {
+                        counters.traps.fetch_add(1, Ordering::Relaxed);
+                    })?;
+                    // 回滚的 exec 没有改变环境，回放时不需要重新执行
+                    if outermost && !(transactional && guest.raised) && core.history.enabled() {
+                        core.history
+                            .push(metering_env, HistoryOp::Exec(source.to_string()));
+                    }
+                    // 回收在计量结束后进行，不计入环境的预算
+                    if outermost && let Some(env_id) = env_id {
+                        core.collect_after_exec(&mut store, env_id)?;
+                    }
+                    if outermost
+                        && core.watchdog.checkpointing()
+                        && let Some(memory) = core.get_memory()
+                    {
+                        let state = core.capture_state(&mut store);
+                        core.watchdog.checkpoint(memory.data(&store), state);
+                    }
+                    (guest, None)
+                }
             };
-            input_slices.push(code.as_bytes()); // code
-            input_slices.push(&[0u8; 4]); // output_ptr_ptr (初始化为 NULL)
-            input_slices.push(&[0u8; 4]); // error_ptr_ptr (初始化为 NULL)
 
-            // 一次性分配所有内存！
-            let (base_ptr, ptrs) = core
-                .allocate_pybox_bytes_batch(&mut *store, &input_slices)
-                .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
+            let memory_pages = core.get_memory().map_or(0, |memory| memory.size(&store));
+            let memory_bytes = core
+                .get_memory()
+                .map_or(0, |memory| memory.data_size(&store));
+            counters
+                .memory_bytes
+                .store(memory_bytes as u64, Ordering::Relaxed);
+            let exec_stats = Py::new(
+                py,
+                PyBoxExecStats {
+                    wall_time: start.elapsed().as_secs_f64(),
+                    compile_time: guest.compile_ns.map(|ns| ns as f64 / 1e9),
+                    run_time: guest.run_ns.map(|ns| ns as f64 / 1e9),
+                    fuel_consumed,
+                    memory_pages_grown: memory_pages - pages_before,
+                    memory_pages,
+                    ioctl_calls: core.ioctl_calls.load(std::sync::atomic::Ordering::Relaxed)
+                        - ioctl_calls_before,
+                },
+            )?;
+            let mut result = PyBoxExecResult::new(
+                py,
+                guest.output,
+                guest.report.as_deref(),
+                Some(exec_stats.clone_ref(py)),
+                limits,
+            )?;
+            let filter = *core.output_filter.lock().unwrap();
+            if filter.enabled() {
+                filter.apply_in_place(&mut result.output);
+                filter.apply_in_place(&mut result.stdout);
+                filter.apply_in_place(&mut result.stderr);
+            }
+            let redactor = core.redactor.lock().unwrap().clone_ref(py);
+            result.redact(py, &redactor)?;
+            if core.middleware.has_output() {
+                core.middleware.output(py, "output", &mut result.output)?;
+                core.middleware.output(py, "stdout", &mut result.stdout)?;
+                core.middleware.output(py, "stderr", &mut result.stderr)?;
+            }
+            // host 规则的诊断排在沙箱中 linter 的诊断之前
+            if let Some(diagnostics) = &lint {
+                for (index, diagnostic) in diagnostics.iter().enumerate() {
+                    result.diagnostics.bind(py).insert(index, diagnostic)?;
+                }
+            }
+            if let Some(err) = timeout {
+                result.timed_out = true;
+                result.exception = Some(err.into_value(py).into_any());
+            }
+            timed_out = result.timed_out;
+            if result.exception.is_some() {
+                counters.failures.fetch_add(1, Ordering::Relaxed);
+            }
+            if !stats {
+                return Ok(result.into_pyobject(py)?.into_any().unbind());
+            }
+            Ok((result, exec_stats).into_pyobject(py)?.into_any().unbind())
+        });
+        if reset && let Some(baseline) = self.baseline.get().map(|base| base.clone_ref(py)) {
+            let outcome = self.reset_to_baseline(py, Some(&baseline), core.take_rings());
+            if let (Err(reset_err), Err(err)) = (&outcome, &result) {
+                reset_err.set_cause(py, Some(err.clone_ref(py)));
+            }
+            outcome?;
+        }
+        let env = env_id.unwrap_or_default();
+        self.audit(py, "exec", env, started, &result, |record| {
+            record.insert(
+                "code_hash".into(),
+                telemetry::code_hash(py, code).ok().into(),
+            );
+            record.insert("code_length".into(), code.len().into());
+            record.insert("cache_hit".into(), cache_hit.into());
+        });
+        // 失败的 exec 也可能已经改变了环境
+        if !cache {
+            core.exec_cache.touch(cache_env);
+        }
+        if let Some(span) = &span {
+            span.set_attribute(py, "pybox.cache_hit", cache_hit);
+        }
+        let result = Self::end_span(py, span, result)?;
+        // 执行期间环境被其他操作改变时不缓存
+        if cache && !cache_hit && !timed_out && core.exec_cache.version(cache_env) == cache_version
+        {
+            let cached = result.clone_ref(py);
+            core.exec_cache
+                .insert(cache_env, code, stats, cache_version, cached);
+        }
+        core.middleware.after(py, env_id, result)
+    }
 
-            // 解析各个指针
-            let (env_id_ptr, code_ptr, output_ptr_ptr, error_ptr_ptr) =
-                if let Some(idx) = env_id_index {
-                    (ptrs[idx], ptrs[idx + 1], ptrs[idx + 2], ptrs[idx + 3])
-                } else {
-                    (0, ptrs[0], ptrs[1], ptrs[2])
-                };
+    /// Stop the running execution from another thread, as Ctrl-C would
+    ///
+    /// The first cancel raises KeyboardInterrupt inside the sandbox within one
+    /// epoch tick (50ms), so the guest unwinds and exec returns the partial output
+    /// with the KeyboardInterrupt as `result.exception`. Cancelling again, or a
+    /// guest that cannot be interrupted, stops the execution and exec raises
+    /// KeyboardInterrupt. exec_async calls it when its task is cancelled.
+    ///
+    /// Returns:
+    ///     bool: Whether an execution was running
+    fn cancel(&self) -> bool {
+        let Some(core) = &self.core else {
+            return false;
+        };
+        if self.owner_thread_raw.load(Ordering::SeqCst) == 0 {
+            return false;
+        }
+        core.cancel_requested.store(true, Ordering::SeqCst);
+        true
+    }
 
-            // ========== 调用 WASM 函数 ==========
-            let result = pybox_exec_func
-                .call(
-                    &mut *store,
-                    (env_id_ptr, code_ptr, output_ptr_ptr, error_ptr_ptr),
-                )
-                .map_err(|e| match e.downcast::<PyErr>() {
-                    Ok(err) => err,
-                    Err(err) => pyo3::exceptions::PyRuntimeError::new_err(format!(
-                        "Wasmtime runtime error: {}",
-                        err
-                    )),
-                })?;
+    /// Set the fuel budget of an environment
+    ///
+    /// Fuel consumed by exec and call is charged to the environment, once the
+    /// budget is exhausted they raise PyBoxQuotaError until it is topped up.
+    /// Guest calls nested inside handlers are charged to the outermost environment.
+    /// Sandboxed code reads what is left with `pybox.remaining_budget()`, so it
+    /// can save its progress and stop before running out. Budgets need an
+    /// engine that counts fuel, see `PyBoxEngine(fuel=True)`.
+    ///
+    /// Args:
+    ///     env_id: Environment ID, None for the global environment
+    ///     fuel: Remaining fuel, None removes the limit
+    #[pyo3(signature = (env_id, fuel))]
+    fn set_budget(&self, env_id: Option<&str>, fuel: Option<u64>) -> pyo3::PyResult<()> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        if fuel.is_some() {
+            core.require_fuel("set_budget")?;
+        }
+        core.budgets.set(env_id.unwrap_or_default(), fuel);
+        Ok(())
+    }
 
-            // ========== 优化：零拷贝读取输出 ==========
-            let output = {
-                let output_data = core
-                    .read_pybox_bytes_ptr_data(&*store, output_ptr_ptr)
-                    .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
+    /// Add fuel to the budget of an environment
+    ///
+    /// Returns:
+    ///     int | None: Remaining fuel, None if the environment is unlimited
+    #[pyo3(signature = (env_id, fuel))]
+    fn top_up_budget(&self, env_id: Option<&str>, fuel: u64) -> pyo3::PyResult<Option<u64>> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        core.require_fuel("top_up_budget")?;
+        Ok(core.budgets.top_up(env_id.unwrap_or_default(), fuel))
+    }
 
-                // 从切片引用直接转 String（这里的拷贝是必需的）
-                let output_str = String::from_utf8_lossy(output_data).to_string();
+    /// Remaining fuel budget of an environment, None if unlimited
+    #[pyo3(signature = (env_id=None))]
+    fn budget(&self, env_id: Option<&str>) -> pyo3::PyResult<Option<u64>> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        Ok(core.budgets.remaining(env_id.unwrap_or_default()))
+    }
 
-                // 释放 WASM 端分配的输出缓冲区
-                let output_ptr = core
-                    .read_u32(&*store, output_ptr_ptr)
-                    .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
-                if output_ptr != 0 {
-                    core.free_buffer(&mut *store, output_ptr)
-                        .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
-                }
+    /// Total fuel consumed by an environment since the reactor was created
+    #[pyo3(signature = (env_id=None))]
+    fn fuel_used(&self, env_id: Option<&str>) -> pyo3::PyResult<u64> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        Ok(core.budgets.used(env_id.unwrap_or_default()))
+    }
 
-                output_str
+    /// Estimate the fuel budget that roughly matches a wall-clock limit
+    ///
+    /// Runs a standard workload (loops, arithmetic, strings, lists and dicts)
+    /// in a temporary environment, measures the fuel it consumes per millisecond
+    /// on this machine and returns the fuel of `ms` milliseconds. The budget
+    /// stays deterministic once chosen, unlike a timeout, but code much slower
+    /// or faster per instruction than the workload, such as big-number math or
+    /// waiting on handlers, does not match the time. Calibrate once at
+    /// start-up and reuse the value with set_budget.
+    ///
+    /// Example:
+    ///     box.set_budget(env_id, box.calibrate(2000))
+    ///
+    /// Args:
+    ///     ms: Wall-clock limit in milliseconds
+    ///     rounds: How many times the workload runs, the first run warms up
+    ///         and is not measured
+    ///
+    /// Returns:
+    ///     int: Fuel consumed in about `ms` milliseconds
+    #[pyo3(signature = (ms, rounds=CALIBRATE_ROUNDS))]
+    fn calibrate(&self, ms: f64, rounds: usize) -> pyo3::PyResult<u64> {
+        if !ms.is_finite() || ms < 0.0 {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "ms must be a non-negative number",
+            ));
+        }
+        if rounds < 2 {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "rounds must be at least 2",
+            ));
+        }
+        self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+            core.require_fuel("calibrate")?;
+            // 嵌套在 handler 中时 fuel 计入外层的预算，测量结果也不可信
+            if core.call_depth() > 0 {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                    "calibrate cannot run inside a handler",
+                ));
+            }
+            // init_local 会替换同名环境，不能占用调用方的环境
+            let env_id = (0u64..)
+                .map(|n| match n {
+                    0 => CALIBRATE_ENV.to_string(),
+                    n => format!("{}{}", CALIBRATE_ENV, n),
+                })
+                .find(|env_id| !core.local_envs.contains(env_id))
+                .unwrap_or_default();
+            let mut store = self.store_context()?;
+            if core.call_env_export(&mut store, core.init_local.get(), &env_id)? != 0 {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                    "Failed to create the calibration environment",
+                ));
+            }
+            let mut fuel = 0u64;
+            let mut elapsed = std::time::Duration::ZERO;
+            let mut measure = || -> PyResult<()> {
+                for round in 0..rounds {
+                    let fuel_before = store.get_fuel().unwrap_or(0);
+                    let start = std::time::Instant::now();
+                    core.run_hook(&mut store, Some(&env_id), CALIBRATE_CODE)?
+                        .map_err(|error| {
+                            pyo3::exceptions::PyRuntimeError::new_err(format!(
+                                "PyBox calibration workload failed: {}",
+                                error
+                            ))
+                        })?;
+                    if round > 0 {
+                        elapsed += start.elapsed();
+                        fuel += fuel_before.saturating_sub(store.get_fuel().unwrap_or(0));
+                    }
+                }
+                Ok(())
             };
+            let measured = measure();
+            core.call_env_export(&mut store, core.del_local.get(), &env_id)?;
+            measured?;
+            let fuel_per_ms = fuel as f64 / (elapsed.as_secs_f64() * 1000.0).max(f64::EPSILON);
+            Ok((fuel_per_ms * ms) as u64)
+        })
+    }
 
-            // ========== 优化：零拷贝读取错误 ==========
-            let error = {
-                let error_data = core
-                    .read_pybox_bytes_ptr_data(&*store, error_ptr_ptr)
-                    .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
+    /// Limit how often exec, call and assign may run in an environment
+    ///
+    /// Calls beyond the limit raise PyBoxRateLimitError instead of running.
+    ///
+    /// Args:
+    ///     env_id: Environment ID, None for the global environment
+    ///     calls_per_second: Sustained call rate, None for no rate limit
+    ///     burst: Calls allowed at once, defaults to calls_per_second
+    ///     max_depth: How many times the environment may be entered at once
+    ///         through handlers calling back into the guest
+    #[pyo3(signature = (env_id, calls_per_second=None, burst=None, max_depth=None))]
+    fn set_rate_limit(
+        &self,
+        env_id: Option<&str>,
+        calls_per_second: Option<f64>,
+        burst: Option<u32>,
+        max_depth: Option<usize>,
+    ) -> pyo3::PyResult<()> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        core.rate_limits
+            .set(
+                env_id.unwrap_or_default(),
+                calls_per_second,
+                burst,
+                max_depth,
+            )
+            .map_err(pyo3::exceptions::PyValueError::new_err)
+    }
 
-                let error_str = String::from_utf8_lossy(error_data).to_string();
+    /// Restrict which handlers an environment may call
+    ///
+    /// Without an ACL an environment may call every handler. With one, calls to
+    /// handlers not listed are rejected before the handler runs: a JSON-RPC
+    /// call raises PermissionError in the sandbox, and a raw
+    /// `pybox_ioctl_host` call fails with the JSON error
+    /// `{"exception": "PermissionError: ...", "denied": {"env", "handle", "handler"}}`.
+    /// Handlers are matched by the name they were registered with or by their
+    /// handle, names may be listed before the handler is registered. The
+    /// environment making the call is the one whose code runs, also inside
+    /// nested calls.
+    ///
+    /// Args:
+    ///     env_id: Environment ID, None for the global environment
+    ///     handlers: Names (str) or handles (int) of the allowed handlers,
+    ///         None removes the ACL
+    #[pyo3(signature = (env_id, handlers))]
+    fn set_handler_acl(
+        &self,
+        env_id: Option<&str>,
+        handlers: Option<Vec<Bound<'_, PyAny>>>,
+    ) -> pyo3::PyResult<()> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        let env_id = env_id.unwrap_or_default();
+        let Some(handlers) = handlers else {
+            core.acls.clear(env_id);
+            return Ok(());
+        };
+        let mut names = Vec::new();
+        let mut handles = Vec::new();
+        for handler in handlers {
+            if let Ok(name) = handler.extract::<String>() {
+                names.push(name);
+            } else if let Ok(handle) = handler.extract::<HandleId>() {
+                handles.push(handle);
+            } else {
+                return Err(pyo3::exceptions::PyTypeError::new_err(
+                    "handlers must be handler names (str) or handles (int)",
+                ));
+            }
+        }
+        core.acls.set(env_id, names, handles);
+        Ok(())
+    }
 
-                // 释放 WASM 端分配的错误缓冲区
-                let error_ptr = core
-                    .read_u32(&*store, error_ptr_ptr)
-                    .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
-                if error_ptr != 0 {
-                    core.free_buffer(&mut *store, error_ptr)
-                        .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
-                }
+    /// Handlers an environment may call
+    ///
+    /// Returns:
+    ///     tuple[list[str], list[int]] | None: Allowed names and handles, None
+    ///         if the environment has no ACL
+    #[pyo3(signature = (env_id=None))]
+    fn handler_acl(&self, env_id: Option<&str>) -> pyo3::PyResult<Option<(Vec<String>, Vec<u32>)>> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        Ok(core.acls.get(env_id.unwrap_or_default()))
+    }
 
-                error_str
-            };
+    /// Remove the rate limit of an environment
+    ///
+    /// Returns:
+    ///     bool: True if the environment had a rate limit
+    #[pyo3(signature = (env_id=None))]
+    fn clear_rate_limit(&self, env_id: Option<&str>) -> pyo3::PyResult<bool> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        Ok(core.rate_limits.clear(env_id.unwrap_or_default()))
+    }
 
-            // ========== 优化：批量释放参数（一次调用）==========
-            core.free_buffer(&mut *store, base_ptr)
-                .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
+    /// Enable OpenTelemetry tracing
+    ///
+    /// exec, call and assign each create a span under the caller's current span,
+    /// with a child span for every handler the guest calls. Spans carry the
+    /// environment ID; exec spans also carry a hash of the code.
+    ///
+    /// Args:
+    ///     tracer: Tracer creating the spans,
+    ///         `opentelemetry.trace.get_tracer("pybox")` if None
+    #[pyo3(signature = (tracer=None))]
+    fn enable_tracing(&self, py: pyo3::Python, tracer: Option<Py<PyAny>>) -> pyo3::PyResult<()> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        let tracer = match tracer {
+            Some(tracer) => tracer,
+            None => py
+                .import("opentelemetry.trace")?
+                .call_method1("get_tracer", ("pybox",))?
+                .unbind(),
+        };
+        core.tracer.set(Some(tracer));
+        Ok(())
+    }
 
-            // 检查结果
-            if result != 0 {
-                return Err(pyo3::exceptions::PyRuntimeError::new_err(format!(
-                    "PyBox exec failed: {}",
-                    if !error.is_empty() {
-                        error
-                    } else {
-                        "Unknown error".to_string()
-                    }
-                )));
-            }
+    /// Disable tracing, returns whether it was enabled
+    fn disable_tracing(&self) -> pyo3::PyResult<bool> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        Ok(core.tracer.set(None))
+    }
 
-            Ok(output)
-        })
+    /// Register a callback run before every exec
+    ///
+    /// The callback is called as `callback(env_id, code)` in registration order.
+    /// It may return rewritten code (None keeps the code) or raise to reject it.
+    ///
+    /// Returns:
+    ///     The callback, so this can be used as a decorator
+    fn on_before_exec(&self, callback: &Bound<'_, PyAny>) -> pyo3::PyResult<Py<PyAny>> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        core.middleware.add_before(callback.clone().unbind());
+        Ok(callback.clone().unbind())
     }
 
-    /// Protect a variable in an environment (make it read-only from Python code)
+    /// Register a callback run after every successful exec
+    ///
+    /// The callback is called as `callback(env_id, result)` in registration order,
+    /// where result is what exec returns. A value other than None replaces it.
+    ///
+    /// Returns:
+    ///     The callback, so this can be used as a decorator
+    fn on_after_exec(&self, callback: &Bound<'_, PyAny>) -> pyo3::PyResult<Py<PyAny>> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        core.middleware.add_after(callback.clone().unbind());
+        Ok(callback.clone().unbind())
+    }
+
+    /// Register a lint rule run on the code of every exec before it runs
+    ///
+    /// The rule is called as `rule(env_id, code, tree)` in registration order,
+    /// where tree is what `ast(code)` returns. It returns an iterable of
+    /// diagnostics, strings or dicts with `message`, `line`, `severity`
+    /// ("warning" or "error") and `rule`. Any error, or a rule that raises,
+    /// blocks the execution: exec returns without running the code and
+    /// `result.exception` is a PyBoxLintError. All findings are returned as
+    /// `result.diagnostics`, followed by those of linters registered inside the
+    /// sandbox with `pybox.lint`.
+    ///
+    /// Returns:
+    ///     The rule, so this can be used as a decorator
+    fn add_lint(&self, rule: &Bound<'_, PyAny>) -> pyo3::PyResult<Py<PyAny>> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        core.middleware.add_lint(rule.clone().unbind());
+        Ok(rule.clone().unbind())
+    }
+
+    /// Register an output processor applied to the output of every exec
+    ///
+    /// The processor is called as `processor(stream, text)` in registration
+    /// order and returns the new text as str, each one receiving what the
+    /// previous returned. It runs after the output filter and secret redaction
+    /// on the output, stdout and stderr of exec results (stream "output",
+    /// "stdout" or "stderr") and on the text passed to on_output, so PII
+    /// scrubbing or markdown normalization is configured once instead of at
+    /// every call site. An exception raised by a processor is raised from exec.
+    ///
+    /// Returns:
+    ///     The processor, so this can be used as a decorator
+    fn add_output_processor(&self, processor: &Bound<'_, PyAny>) -> pyo3::PyResult<Py<PyAny>> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        core.middleware.add_output(processor.clone().unbind());
+        Ok(processor.clone().unbind())
+    }
+
+    /// Remove an exec callback, lint rule or output processor, returns whether it was registered
+    fn remove_middleware(&self, callback: &Bound<'_, PyAny>) -> pyo3::PyResult<bool> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        Ok(core.middleware.remove(callback.py(), callback))
+    }
+
+    /// Set code run in every new environment, or before every exec
+    ///
+    /// By default the prelude runs once when `init_local` creates an environment,
+    /// environments copied by `init_local_from` inherit it from their source.
+    /// Its output is discarded and a failing prelude deletes the new environment.
     ///
     /// Args:
-    ///     env_id: Environment ID
-    ///     name: Variable name to protect
-    fn protect(&self, env_id: &str, name: &str) -> pyo3::PyResult<()> {
+    ///     code: Prelude code, None removes it
+    ///     per_exec: Run the prelude before every exec instead
+    #[pyo3(signature = (code, per_exec=false))]
+    fn set_prelude(&self, code: Option<String>, per_exec: bool) -> pyo3::PyResult<()> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        core.middleware.set_prelude(code, per_exec);
+        Ok(())
+    }
+
+    /// Set code run before an environment is deleted, or after every exec
+    ///
+    /// By default the teardown runs when `del_local` deletes an environment.
+    /// Per-exec teardowns also run when the code fails. Their output is discarded.
+    ///
+    /// Args:
+    ///     code: Teardown code, None removes it
+    ///     per_exec: Run the teardown after every exec instead
+    #[pyo3(signature = (code, per_exec=false))]
+    fn set_teardown(&self, code: Option<String>, per_exec: bool) -> pyo3::PyResult<()> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        core.middleware.set_teardown(code, per_exec);
+        Ok(())
+    }
+
+    /// Call a heartbeat callback periodically while an exec is running
+    ///
+    /// The callback receives `(env_id, elapsed, fuel_consumed)` of the running
+    /// exec, elapsed in seconds and fuel_consumed 0 unless the engine counts
+    /// fuel. An exception raised by it aborts the exec and is raised from exec,
+    /// which allows custom timeout policies. The aborted guest may be left
+    /// inconsistent, so it is restored from the watchdog snapshot when
+    /// set_watchdog is enabled, or else reset to its start-up state like
+    /// recycle(), dropping every environment. The callback runs in the middle
+    /// of the execution and cannot use the reactor.
+    ///
+    /// Args:
+    ///     callback: Callable, None removes the heartbeat
+    ///     every: Call it every `every` epoch ticks (50ms each)
+    ///
+    /// Returns:
+    ///     bool: Whether a heartbeat callback was set before
+    #[pyo3(signature = (callback=None, every=1))]
+    fn set_heartbeat(&self, callback: Option<Py<PyAny>>, every: u64) -> pyo3::PyResult<bool> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        Ok(core.heartbeat.set(callback, every))
+    }
+
+    /// Stop executions that run past a deadline and restore the reactor
+    ///
+    /// An exec running longer than `timeout` seconds is stopped, the guest memory
+    /// is restored from the last known-good snapshot and exec returns a result
+    /// with `timed_out` set and a PyBoxTimeoutError as `result.exception`, so the
+    /// reactor stays usable. A call is stopped and restored the same way and
+    /// raises the PyBoxTimeoutError. Nested execs and calls inside handlers
+    /// share the deadline of the outermost one.
+    ///
+    /// Args:
+    ///     timeout: Deadline in seconds, None disables the watchdog
+    ///     snapshot: PyBoxReactorSnapshot to restore from, defaults to a snapshot
+    ///         of the current state
+    ///     checkpoint: Refresh the snapshot after every successful exec, which
+    ///         copies the whole guest memory each time
+    ///
+    /// Returns:
+    ///     bool: Whether the watchdog was enabled before
+    #[pyo3(signature = (timeout=None, snapshot=None, checkpoint=false))]
+    fn set_watchdog(
+        &self,
+        py: pyo3::Python,
+        timeout: Option<f64>,
+        snapshot: Option<PyRef<'_, PyBoxReactorSnapshot>>,
+        checkpoint: bool,
+    ) -> pyo3::PyResult<bool> {
         self.safe_access(|| {
             let core = self.core.as_ref().ok_or_else(|| {
                 pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
             })?;
-
-            // 从 UnsafeCell 获取可变指针
-            let store_ptr = self
-                .store
+            let timeout = timeout
+                .map(std::time::Duration::try_from_secs_f64)
+                .transpose()
+                .map_err(|err| pyo3::exceptions::PyValueError::new_err(err.to_string()))?;
+            if let Some(snapshot) = &snapshot {
+                snapshot.check_compatible(self)?;
+            }
+            let (memory, state) = match snapshot
                 .as_ref()
-                .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Store not initialized"))?
-                .get();
-            let store = unsafe { &mut *store_ptr };
+                .map(|snapshot| Ok::<_, PyErr>((snapshot.memory(py)?, snapshot.state())))
+                .transpose()?
+            {
+                Some((Some(data), state)) => (data.into_owned(), state.clone()),
+                Some((None, _)) => {
+                    return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                        "Snapshot not available",
+                    ));
+                }
+                None if timeout.is_some() => {
+                    let mut store = self.store_context()?;
+                    let memory = core.get_memory().ok_or_else(|| {
+                        pyo3::exceptions::PyRuntimeError::new_err("Memory not available")
+                    })?;
+                    let state = core.capture_state(&mut store);
+                    (memory.data(&store).to_vec(), state)
+                }
+                None => (Vec::new(), InstanceState::default()),
+            };
+            Ok(core.watchdog.set(timeout, checkpoint, memory, state))
+        })
+    }
 
-            let pybox_local_protect_func = core.protect.get().ok_or_else(|| {
-                pyo3::exceptions::PyRuntimeError::new_err("Failed to get pybox_protect")
-            })?;
+    /// Limit the size of the guest linear memory
+    ///
+    /// An execution that grows the memory past the limit is stopped and raises
+    /// PyBoxQuotaError. WASM memory never shrinks, use trim to release memory
+    /// that is already above a new limit. Sandboxed code sees the limit next to
+    /// its heap usage in `pybox.mem_info()`.
+    ///
+    /// Args:
+    ///     limit: Maximum memory size in bytes, None removes the limit
+    #[pyo3(signature = (limit=None))]
+    fn set_memory_limit(&self, limit: Option<usize>) -> pyo3::PyResult<()> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        core.memory_limit
+            .store(limit.unwrap_or(0), std::sync::atomic::Ordering::Relaxed);
+        Ok(())
+    }
 
-            // ========== 优化：批量分配两个参数 ==========
+    /// Choose how NaN and Infinity cross the sandbox boundary
+    ///
+    /// Applies to assign, retrieve, call and JSON-RPC calls made with
+    /// pybox_json_rpc, on both the host and the sandbox side. "allow" emits
+    /// NaN / Infinity like the json module does, which strict JSON parsers
+    /// reject; "error" raises ValueError; "null" replaces them with None;
+    /// "tag" encodes them as `{"__pybox_float__": "nan"}` style objects that are
+    /// turned back into floats on the other side.
+    ///
+    /// Python ints of any size survive the round trip between two Python ends,
+    /// but parsers that read JSON numbers as doubles lose the digits of ints
+    /// beyond 64 bits. big_ints encodes those as `{"__pybox_int__": "<digits>"}`
+    /// strings that both sides turn back into ints.
+    ///
+    /// Args:
+    ///     non_finite: "allow", "error", "null" or "tag"
+    ///     big_ints: Tag ints that do not fit in 64 bits
+    #[pyo3(signature = (non_finite="allow", big_ints=false))]
+    fn set_codec(&self, non_finite: &str, big_ints: bool) -> pyo3::PyResult<()> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        *core.codec.lock().unwrap() = CodecOptions {
+            non_finite: NonFinite::parse(non_finite)?,
+            big_ints,
+        };
+        Ok(())
+    }
+
+    /// Sanitize the output of exec before it reaches the host
+    ///
+    /// Applies to the output, stdout and stderr of exec results and to the
+    /// text passed to on_output. strip_ansi removes terminal escape sequences
+    /// (colors, cursor movement, OSC titles and hyperlinks); escape_control
+    /// replaces the remaining control characters, other than newline and tab,
+    /// with `\xNN` escapes, so guest output cannot rewrite or forge lines in
+    /// host logs. Both are off by default.
+    ///
+    /// Args:
+    ///     strip_ansi: Remove ANSI escape sequences
+    ///     escape_control: Escape control characters
+    #[pyo3(signature = (strip_ansi=false, escape_control=false))]
+    fn set_output_filter(&self, strip_ansi: bool, escape_control: bool) -> pyo3::PyResult<()> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        *core.output_filter.lock().unwrap() = OutputFilter {
+            strip_ansi,
+            escape_control,
+        };
+        Ok(())
+    }
+
+    /// Limit the size of an environment
+    ///
+    /// max_variables caps the number of variables the environment holds, a new
+    /// variable beyond it raises `pybox.EnvLimitError` (a MemoryError) inside
+    /// the sandbox, which exec reports as a PyBoxEnvLimitError in
+    /// `result.exception`, and assign raises PyBoxEnvLimitError. Existing
+    /// variables can still be changed. max_value_size caps the JSON size in
+    /// bytes of a single value passed by assign or retrieve, both raise
+    /// PyBoxEnvLimitError instead of transferring a larger one. The limits are
+    /// kept when the environment is recreated by trim and removed with it by
+    /// del_local.
+    ///
+    /// Args:
+    ///     env_id: Environment ID
+    ///     max_variables: Maximum number of variables, None for no limit
+    ///     max_value_size: Maximum bytes of an assigned or retrieved value,
+    ///         None for no limit
+    #[pyo3(signature = (env_id, max_variables=None, max_value_size=None))]
+    fn set_env_limits(
+        &self,
+        env_id: &str,
+        max_variables: Option<usize>,
+        max_value_size: Option<usize>,
+    ) -> pyo3::PyResult<()> {
+        self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+            let limits = EnvLimits {
+                max_variables,
+                max_value_size,
+            };
+            let previous = core.env_limits.insert(env_id.to_string(), limits);
+            let applied = self
+                .store_context()
+                .and_then(|store| core.apply_env_limits(store, env_id));
+            if let Err(error) = applied {
+                match previous {
+                    Some(previous) => core.env_limits.insert(env_id.to_string(), previous),
+                    None => core.env_limits.remove(env_id).map(|(_, limits)| limits),
+                };
+                return Err(error);
+            }
+            if limits == EnvLimits::default() {
+                core.env_limits.remove(env_id);
+            }
+            Ok(())
+        })
+    }
+
+    /// Collect garbage in an environment now
+    ///
+    /// Runs the interpreter's garbage collector and clears its caches (type
+    /// cache, compiled regular expressions, linecache and the last uncaught
+    /// exception kept in `sys.last_*`) so memory held by earlier code can be
+    /// reused. Variables of the environment are kept.
+    ///
+    /// Args:
+    ///     env_id: Environment ID
+    ///
+    /// Returns:
+    ///     int: Number of unreachable objects found
+    fn gc(&self, env_id: &str) -> pyo3::PyResult<u32> {
+        self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+            if core.gc.get().is_none() {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                    "gc is not supported by this PyBox image",
+                ));
+            }
+            let mut store = self.store_context()?;
+            let collected = core.call_env_export(&mut store, core.gc.get(), env_id)?;
+            u32::try_from(collected).map_err(|_| {
+                pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to collect garbage in environment '{}'",
+                    env_id
+                ))
+            })
+        })
+    }
+
+    /// Collect garbage in an environment automatically after its executions
+    ///
+    /// Long-lived sessions otherwise keep cycles, caches and the last
+    /// traceback around between executions and their memory creeps upward.
+    /// The collection, the same as gc, runs after every `every`-th exec of the
+    /// environment and is not charged to its fuel budget. Executions that time
+    /// out are not counted. Deleting the environment removes the setting.
+    ///
+    /// Args:
+    ///     env_id: Environment ID
+    ///     every: Collect after this many executions, None turns automatic
+    ///         collection off
+    #[pyo3(signature = (env_id, every=Some(1)))]
+    fn set_gc(&self, env_id: &str, every: Option<u32>) -> pyo3::PyResult<()> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        match every {
+            Some(0) => Err(pyo3::exceptions::PyValueError::new_err(
+                "every must be at least 1",
+            )),
+            Some(every) => {
+                if core.gc.get().is_none() {
+                    return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                        "gc is not supported by this PyBox image",
+                    ));
+                }
+                core.gc_policies.insert(env_id.to_string(), (every, 0));
+                Ok(())
+            }
+            None => {
+                core.gc_policies.remove(env_id);
+                Ok(())
+            }
+        }
+    }
+
+    /// Register a secret to redact from the output of exec
+    ///
+    /// Occurrences of the secret in the output, stdout, stderr and result_repr
+    /// of exec results, in the message and guest_traceback of their exception,
+    /// in text passed to on_output and in artifacts are replaced with
+    /// `[REDACTED]` before they leave the reactor, so code echoing a credential
+    /// it was given does not leak it to logs or users. The same applies to the
+    /// error of a failed call, audit records, files written by `record` and the
+    /// operations returned by `history`, so replaying them runs the redacted
+    /// text. Binary artifacts and recorded handler payloads only have literal
+    /// secrets replaced. A secret split across two writes streamed to on_output
+    /// is not caught there, the final result is still redacted.
+    ///
+    /// Args:
+    ///     secret: Text to redact, or a regular expression if regex is True
+    ///     regex: Treat secret as a pattern for Python's re module
+    #[pyo3(signature = (secret, regex=false))]
+    fn add_secret(&self, py: pyo3::Python, secret: &str, regex: bool) -> pyo3::PyResult<()> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        if secret.is_empty() {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "secret must not be empty",
+            ));
+        }
+        if regex {
+            let pattern = py.import("re")?.call_method1("compile", (secret,))?;
+            core.redactor.lock().unwrap().add_pattern(pattern.unbind());
+        } else {
+            core.redactor.lock().unwrap().add_secret(secret.to_string());
+        }
+        Ok(())
+    }
+
+    /// Forget every secret registered with add_secret
+    fn clear_secrets(&self) -> pyo3::PyResult<()> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        core.redactor.lock().unwrap().clear();
+        Ok(())
+    }
+
+    /// Track the memory pages written by the sandbox between snapshots
+    ///
+    /// With tracking on, `PyBoxReactorSnapshot.update` copies only the pages
+    /// written since the snapshot was last taken or updated instead of the
+    /// whole memory, which makes checkpointing mostly idle sessions cheap.
+    /// Only the most recently taken snapshot of a reactor benefits, updating
+    /// an older one copies everything as before. Tracking uses the soft-dirty
+    /// bits of Linux; other systems, or kernels built without them, keep
+    /// copying everything.
+    ///
+    /// The kernel can only clear soft-dirty bits for the whole process, so
+    /// taking, updating or restoring a snapshot of a tracked reactor walks the
+    /// page tables of the entire host process, not just the sandbox memory.
+    /// Its cost grows with the resident size of the process and it briefly
+    /// write-protects all of its pages. It also resets the bits for anything else in the process
+    /// that relies on them, such as a CRIU-style checkpointer, and a tracked
+    /// reactor that is running on another thread at that moment falls back to
+    /// a full copy on its next update. Enable it in processes dedicated to
+    /// sandboxes, typically with large, mostly idle guest memories.
+    ///
+    /// Returns:
+    ///     bool: Whether pages are tracked
+    fn enable_dirty_tracking(&self) -> pyo3::PyResult<bool> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        if core.dirty_tracker.get().is_none()
+            && let Some(tracker) = DirtyTracker::new()
+        {
+            let _ = core.dirty_tracker.set(tracker);
+        }
+        Ok(core.dirty_tracker.get().is_some())
+    }
+
+    /// Counters accumulated since the reactor was created
+    ///
+    /// Cheap enough to poll and safe to call from another thread while the
+    /// reactor is running, so operators can watch its health without wrapping
+    /// every call. Executions blocked by lint rules or served from the exec
+    /// cache do not reach the guest and are not counted.
+    ///
+    /// Returns:
+    ///     dict: uptime (seconds), execs, failures (code raised or timed out),
+    ///         timeouts, traps (exec raised instead of returning a result, like
+    ///         a guest trap or an exhausted fuel budget), guest_time (seconds
+    ///         spent in exec), fuel_consumed, ioctl_calls (all guest requests),
+    ///         handler_calls (calls per registered handler name) and
+    ///         memory_bytes (memory size after the last exec)
+    fn stats(&self, py: pyo3::Python) -> pyo3::PyResult<Py<PyAny>> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        let ioctl_calls = core.ioctl_calls.load(Ordering::Relaxed);
+        let stats = core
+            .counters
+            .to_dict(py, ioctl_calls, |handle| core.handler_name(handle))?;
+        Ok(stats.into_any().unbind())
+    }
+
+    /// Set how many results of `exec(..., cache=True)` are kept
+    ///
+    /// Results are keyed by environment, code and the environment's state
+    /// version. exec without cache, assign, call, protect and the other
+    /// operations that may change an environment advance its version, so a
+    /// cached result is only returned while the environment is as it was when
+    /// the result was produced; restoring a snapshot drops every cached result.
+    /// The least recently used results are dropped first.
+    ///
+    /// Args:
+    ///     max_entries: Number of results to keep, 0 disables the cache
+    #[pyo3(signature = (max_entries=exec_cache::DEFAULT_CAPACITY))]
+    fn set_exec_cache(&self, max_entries: usize) -> pyo3::PyResult<()> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        core.exec_cache.set_capacity(max_entries);
+        Ok(())
+    }
+
+    /// Drop every cached exec result, returns how many were dropped
+    fn clear_exec_cache(&self) -> pyo3::PyResult<usize> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        Ok(core.exec_cache.clear())
+    }
+
+    /// Statistics of the exec cache
+    ///
+    /// Returns:
+    ///     dict: hits, misses, entries and max_entries
+    fn exec_cache_info(&self, py: pyo3::Python) -> pyo3::PyResult<Py<PyAny>> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        let (hits, misses, entries, max_entries) = core.exec_cache.info();
+        let info = PyDict::new(py);
+        info.set_item("hits", hits)?;
+        info.set_item("misses", misses)?;
+        info.set_item("entries", entries)?;
+        info.set_item("max_entries", max_entries)?;
+        Ok(info.into_any().unbind())
+    }
+
+    /// Teach assign to send values of a type JSON cannot represent
+    ///
+    /// encode runs on the host and turns a value into JSON-serializable data.
+    /// decoder is Python source that defines `decode(data)`, it runs in the
+    /// sandbox and rebuilds the value from that data. assign sends instances of
+    /// cls, also inside dicts, lists and tuples, as
+    /// `{"__pybox_type__": name, "value": data}` and defines the decoder in the
+    /// target environment the first time it is needed. Types are matched in
+    /// registration order, registering a name again replaces it.
+    ///
+    /// Example:
+    ///     box.register_type(decimal.Decimal, str,
+    ///         "import decimal\ndef decode(data):\n    return decimal.Decimal(data)")
+    ///
+    /// Args:
+    ///     cls: Type whose instances are encoded, subclasses included
+    ///     encode: Called with the value, returns JSON-serializable data
+    ///     decoder: Source of the sandbox-side `decode(data)` function
+    ///     name: Name in the tag, defaults to `module.qualname` of cls
+    #[pyo3(signature = (cls, encode, decoder, name=None))]
+    fn register_type(
+        &self,
+        cls: &Bound<'_, PyType>,
+        encode: &Bound<'_, PyAny>,
+        decoder: String,
+        name: Option<String>,
+    ) -> pyo3::PyResult<()> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        let name = match name {
+            Some(name) => name,
+            None => format!("{}.{}", cls.module()?, cls.qualname()?),
+        };
+        let mut types = core.types.lock().unwrap();
+        types.retain(|codec| codec.name != name);
+        types.push(json::TypeCodec {
+            cls: cls.clone().unbind(),
+            name,
+            encode: encode.clone().unbind(),
+            decoder,
+        });
+        Ok(())
+    }
+
+    /// Release guest memory by re-instantiating the module
+    ///
+    /// WASM memory never shrinks, so a single large workload keeps the reactor
+    /// inflated. trim exports the variables of every local environment, replaces
+    /// the instance with a fresh one and restores them, as assign would; modules
+    /// are imported again by name. Environments are recreated with the
+    /// environment prelude and protected variables are protected again; handlers,
+    /// budgets and other host configuration are kept.
+    ///
+    /// Only values that survive a JSON round trip unchanged and modules can be
+    /// carried over. When an environment holds anything else (functions, class
+    /// instances, tuples...) trim raises ValueError naming those variables and
+    /// changes nothing, unless lossy=True drops them. If re-instantiating or
+    /// restoring fails the reactor is rolled back to its state before trim.
+    ///
+    /// Ring buffers are recreated empty, and views and snapshots taken before
+    /// trim must not be used afterwards. trim cannot run inside a handler.
+    ///
+    /// Args:
+    ///     lossy: Drop variables that cannot be carried over instead of raising
+    ///
+    /// Returns:
+    ///     int: Bytes of guest memory released
+    #[pyo3(signature = (lossy=false))]
+    fn trim(&mut self, py: pyo3::Python, lossy: bool) -> pyo3::PyResult<usize> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        core.check_views("trim")?;
+
+        // 导出所有局部环境的变量，并保存当前状态用于失败时回滚
+        let env_ids: Vec<String> = core.local_envs.iter().map(|env| env.clone()).collect();
+        let (states, size_before, snapshot) = self.safe_access(|| {
+            let mut store = self.store_context()?;
+            let mut states = Vec::with_capacity(env_ids.len());
+            let mut lost = Vec::new();
+            for env_id in env_ids {
+                let (state, names) = core.export_env(&mut store, &env_id)?;
+                lost.extend(names.into_iter().map(|name| format!("{}.{}", env_id, name)));
+                states.push((env_id, state));
+            }
+            if !lost.is_empty() && !lossy {
+                lost.sort();
+                return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "trim cannot carry over {}; pass lossy=True to drop them",
+                    lost.join(", ")
+                )));
+            }
+            let size = core
+                .get_memory()
+                .map_or(0, |memory| memory.data_size(&store));
+            let snapshot = PyBoxReactorSnapshot::capture(self, core, &mut store)?;
+            Ok((states, size, snapshot))
+        })?;
+        let rings: Vec<(String, (WasmPtr, WasmSize))> = core
+            .rings
+            .iter()
+            .map(|ring| (ring.key().clone(), *ring.value()))
+            .collect();
+
+        match self.trim_into_new_instance(py, &states) {
+            Ok(size_after) => Ok(size_before.saturating_sub(size_after)),
+            Err(error) => {
+                // 回到 trim 之前的内存、环境和环形缓冲区，lazy 状态下恢复时重新实例化
+                let rollback = snapshot.restore(py, self).and_then(|_| {
+                    let core = self.core.as_ref().ok_or_else(|| {
+                        pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+                    })?;
+                    core.rings.clear();
+                    for (name, ring) in rings {
+                        core.rings.insert(name, ring);
+                    }
+                    self.rebase_watchdog()
+                });
+                match rollback {
+                    Ok(()) => Err(error),
+                    Err(rollback) => {
+                        rollback.set_cause(py, Some(error));
+                        Err(rollback)
+                    }
+                }
+            }
+        }
+    }
+
+    /// 当前状态作为 watchdog 的基线，旧的快照属于之前的 instance 或状态
+    fn rebase_watchdog(&self) -> pyo3::PyResult<()> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        self.safe_access(|| {
+            let mut store = self.store_context()?;
+            if let Some(memory) = core.get_memory() {
+                let state = core.capture_state(&mut store);
+                core.watchdog.rebase(memory.data(&store), state);
+            }
+            Ok(())
+        })
+    }
+
+    /// 以新的 instance 替换当前的 instance 并按导出的变量重建环境，返回新的内存大小
+    fn trim_into_new_instance(
+        &mut self,
+        py: pyo3::Python,
+        states: &[(String, String)],
+    ) -> pyo3::PyResult<usize> {
+        let rings = self.reinstantiate()?;
+        let core = self.core.clone().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+
+        let size_after = self.safe_access(|| {
+            let mut store = self.store_context()?;
+            for (name, capacity) in rings {
+                core.create_ring(&mut store, &name, capacity)
+                    .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+            }
+            Ok(core
+                .get_memory()
+                .map_or(0, |memory| memory.data_size(&store)))
+        })?;
+
+        // 按导出的变量重建环境
+        for (env_id, state) in states {
+            core.local_envs.remove(env_id);
+            let encoding = core
+                .encodings
+                .get(env_id.as_str())
+                .map(|setting| setting.clone());
+            let (encoding, errors) = match &encoding {
+                Some((encoding, errors)) => (Some(encoding.as_str()), Some(errors.as_str())),
+                None => (None, None),
+            };
+            if !self.init_local(env_id, encoding, errors)? {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to recreate environment '{}'",
+                    env_id
+                )));
+            }
+            self.safe_access(|| core.import_env(self.store_context()?, env_id, state))?;
+            let protected = core
+                .protected
+                .get(env_id.as_str())
+                .map(|protected| protected.clone())
+                .unwrap_or_default();
+            for name in protected {
+                self.protect(py, env_id, &name)?;
+            }
+        }
+
+        self.rebase_watchdog()?;
+        Ok(size_after)
+    }
+
+    /// Whether the module is instantiated, False until a lazy reactor is first used
+    fn instantiated(&self) -> bool {
+        self.core
+            .as_ref()
+            .is_some_and(|core| core.instance.get().is_some())
+    }
+
+    /// Reset the reactor to the state it was created in, reusing it
+    ///
+    /// Every environment, guest variable, recorded history and pending stream
+    /// is dropped; handlers, limits, budgets, middleware and other host-side
+    /// settings are kept and ring buffers are recreated empty. Memory and
+    /// globals are reset in place from the image the reactor started from,
+    /// reusing the store and instance, which is much cheaper than creating a
+    /// new reactor. That image is the prewarm snapshot or the from_snapshot
+    /// snapshot; a reactor started without one keeps a copy of its memory taken
+    /// right after start-up, which costs that much host memory per reactor.
+    /// With shrink=True the module is instantiated again first, which also
+    /// releases the memory grown since, like trim.
+    ///
+    /// Views and snapshots taken before recycle must not be used afterwards.
+    /// recycle cannot run inside a handler.
+    ///
+    /// Args:
+    ///     shrink: Re-instantiate even when the memory could be reset in place
+    #[pyo3(signature = (shrink=false))]
+    fn recycle(&mut self, py: pyo3::Python, shrink: bool) -> pyo3::PyResult<()> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        core.check_views("recycle")?;
+        let baseline = self.baseline.get().map(|baseline| baseline.clone_ref(py));
+
+        let rings = if shrink || baseline.is_none() {
+            self.reinstantiate()?
+        } else {
+            core.take_rings()
+        };
+        // 从未实例化的 lazy reactor 在这里实例化，之后的 recycle 原地恢复
+        if baseline.is_none() {
+            self.capture_baseline(py)?;
+        }
+        self.reset_to_baseline(py, baseline.as_ref(), rings)
+    }
+
+    /// Send an audit record of every sandbox operation to a sink
+    ///
+    /// Records are JSON objects with `time`, `op` (exec, call, assign,
+    /// assign_buffer, retrieve, protect or handler), `env_id`, `duration` and
+    /// `outcome` ("ok" or "error", with the message in `error`). exec records
+    /// carry a `code_hash` instead of the code.
+    ///
+    /// Args:
+    ///     sink: Callable receiving each record as a dict, or a path the records
+    ///         are appended to as JSON Lines. None disables the audit log.
+    ///
+    /// Returns:
+    ///     bool: Whether an audit sink was set before
+    #[pyo3(signature = (sink=None))]
+    fn set_audit_sink(&self, sink: Option<&Bound<'_, PyAny>>) -> pyo3::PyResult<bool> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        let enabled = core.audit.clear();
+        match sink {
+            None => {}
+            Some(sink) if sink.is_callable() => core.audit.set_callable(sink.clone().unbind()),
+            Some(sink) => core
+                .audit
+                .set_file(sink.extract()?)
+                .map_err(pyo3::exceptions::PyOSError::new_err)?,
+        }
+        Ok(enabled)
+    }
+
+    /// Record handler traffic to a file for deterministic re-execution
+    ///
+    /// The code of every top-level exec, every handler request with the response
+    /// written back to the guest (or the message of the exception it raised) and
+    /// every chunk pulled from a streamed response are appended to the file as
+    /// JSON Lines, until `stop_recording` is called.
+    ///
+    /// Args:
+    ///     path: File the records are appended to
+    #[pyo3(signature = (path))]
+    fn record(&self, path: std::path::PathBuf) -> pyo3::PyResult<()> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        core.recorder
+            .record(&path)
+            .map_err(pyo3::exceptions::PyOSError::new_err)
+    }
+
+    /// Replay a recording made by `record`
+    ///
+    /// Until `stop_recording` is called, handler requests are answered with the
+    /// recorded responses in order instead of calling the live handlers, and a
+    /// recorded handler exception is raised again as RuntimeError. A request that
+    /// does not match the next record raises PyBoxReplayError.
+    ///
+    /// Args:
+    ///     path: File written by `record`
+    ///
+    /// Returns:
+    ///     list[tuple[str | None, str]]: `(env_id, code)` of the recorded execs in
+    ///         order, to be executed again on this reactor
+    #[pyo3(signature = (path))]
+    fn replay_recording(
+        &self,
+        path: std::path::PathBuf,
+    ) -> pyo3::PyResult<Vec<(Option<String>, String)>> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        core.recorder
+            .replay(&path)
+            .map_err(pyo3::exceptions::PyOSError::new_err)
+    }
+
+    /// Stop recording or replaying handler traffic
+    ///
+    /// Returns:
+    ///     bool: Whether a recording or replay was active
+    fn stop_recording(&self) -> pyo3::PyResult<bool> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        Ok(core.recorder.clear())
+    }
+
+    /// Record the operations of every environment for `history` and `replay`
+    ///
+    /// Recording is off by default. Each environment keeps its most recent
+    /// max_ops operations, older ones are dropped; a history that lost
+    /// operations no longer rebuilds the environment from scratch.
+    ///
+    /// Args:
+    ///     max_ops: Operations kept per environment, None stops recording and
+    ///         forgets every history
+    ///
+    /// Returns:
+    ///     bool: Whether recording was enabled before
+    #[pyo3(signature = (max_ops=None))]
+    fn set_history(&self, max_ops: Option<usize>) -> pyo3::PyResult<bool> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        if max_ops == Some(0) {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "max_ops must be positive, use None to stop recording",
+            ));
+        }
+        Ok(core.history.set_limit(max_ops))
+    }
+
+    /// Ordered exec / assign / protect operations of an environment
+    ///
+    /// Empty unless recording was enabled with `set_history`. Only top-level
+    /// operations that completed are recorded, a timed out exec that was
+    /// rolled back is left out. The history starts over when the environment
+    /// is created or deleted, `init_local_from` copies the history of the
+    /// source environment.
+    ///
+    /// Args:
+    ///     env_id: Environment ID, None for the global environment
+    ///
+    /// Returns:
+    ///     list[tuple]: `("exec", code)`, `("assign", name, value)`,
+    ///         `("protect", name)` or `("unprotect", names)` in the order they
+    ///         ran. protect_all is recorded as a protect of every name.
+    #[pyo3(signature = (env_id=None))]
+    fn history<'py>(
+        &self,
+        py: pyo3::Python<'py>,
+        env_id: Option<&str>,
+    ) -> pyo3::PyResult<Vec<Bound<'py, pyo3::types::PyTuple>>> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        let redactor = core.redactor.lock().unwrap().clone_ref(py);
+        core.history
+            .get(env_id.unwrap_or_default())
+            .into_iter()
+            .map(|op| match op {
+                HistoryOp::Exec(code) => ("exec", redactor.redact(py, &code)?).into_pyobject(py),
+                HistoryOp::Assign(name, value) => {
+                    let value = redactor.redact_object(py, json::loads(py, value.as_bytes())?)?;
+                    ("assign", name, value).into_pyobject(py)
+                }
+                HistoryOp::Protect(name) => ("protect", name).into_pyobject(py),
+                HistoryOp::Unprotect(names) => ("unprotect", names).into_pyobject(py),
+            })
+            .collect()
+    }
+
+    /// Forget the recorded history of an environment
+    ///
+    /// Args:
+    ///     env_id: Environment ID, None for the global environment
+    ///
+    /// Returns:
+    ///     bool: Whether the environment had a history
+    #[pyo3(signature = (env_id=None))]
+    fn clear_history(&self, env_id: Option<&str>) -> pyo3::PyResult<bool> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        Ok(core.history.clear(env_id.unwrap_or_default()))
+    }
+
+    /// Run the operations of a history on an environment of this reactor
+    ///
+    /// A lightweight alternative to snapshots for reconstructing a session,
+    /// typically on a fresh reactor: `new.replay(old.history(env_id), env_id)`.
+    /// It rebuilds the same state as long as the recorded code is deterministic.
+    ///
+    /// Args:
+    ///     history: Operations returned by `history`
+    ///     target_env: Environment the operations run in, it must already exist.
+    ///         None for the global environment, which accepts only exec.
+    ///
+    /// Returns:
+    ///     list[PyBoxExecResult]: Results of the replayed execs in order
+    #[pyo3(signature = (history, target_env))]
+    fn replay(
+        &self,
+        py: pyo3::Python,
+        history: &Bound<'_, PyAny>,
+        target_env: Option<&str>,
+    ) -> pyo3::PyResult<Vec<Py<PyAny>>> {
+        let mut results = Vec::new();
+        for op in history.try_iter()? {
+            let op = op?;
+            let kind: String = op.get_item(0)?.extract()?;
+            let env_id = || {
+                target_env.ok_or_else(|| {
+                    pyo3::exceptions::PyValueError::new_err(format!(
+                        "Cannot replay {} on the global environment",
+                        kind
+                    ))
+                })
+            };
+            match kind.as_str() {
+                "exec" => {
+                    let code: String = op.get_item(1)?.extract()?;
+                    results.push(self.exec(
+                        py, &code, target_env, false, None, None, None, None, false, None, false,
+                    )?);
+                }
+                "assign" => {
+                    let name: String = op.get_item(1)?.extract()?;
+                    self.assign(py, env_id()?, &name, &op.get_item(2)?)?;
+                }
+                "protect" => {
+                    let name: String = op.get_item(1)?.extract()?;
+                    self.protect(py, env_id()?, &name)?;
+                }
+                "unprotect" => {
+                    let names: Vec<String> = op.get_item(1)?.extract()?;
+                    self.unprotect(py, env_id()?, names)?;
+                }
+                _ => {
+                    return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                        "Unknown history operation '{}'",
+                        kind
+                    )));
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    /// Protect a variable in an environment (make it read-only from Python code)
+    ///
+    /// Args:
+    ///     env_id: Environment ID
+    ///     name: Variable name to protect
+    fn protect(&self, py: pyo3::Python, env_id: &str, name: &str) -> pyo3::PyResult<()> {
+        let started = std::time::Instant::now();
+        let result = self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+            core.exec_cache.touch(env_id);
+
+            // handler 内部重入时使用 Caller 的上下文
+            let mut store = self.store_context()?;
+
+            let pybox_local_protect_func = core.protect.get().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("Failed to get pybox_protect")
+            })?;
+
+            // ========== 优化：批量分配两个参数 ==========
             let (base_ptr, ptrs) = core
-                .allocate_pybox_bytes_batch(&mut *store, &[env_id.as_bytes(), name.as_bytes()])
+                .allocate_pybox_bytes_batch(&mut store, &[env_id.as_bytes(), name.as_bytes()])
                 .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
 
             let (env_id_ptr, name_ptr) = (ptrs[0], ptrs[1]);
 
             // 调用 WASM 函数
             let result = pybox_local_protect_func
-                .call(&mut *store, (env_id_ptr, name_ptr))
+                .call(&mut store, (env_id_ptr, name_ptr))
                 .map_err(|e| {
                     pyo3::exceptions::PyRuntimeError::new_err(format!(
                         "pybox_local_protect failed: {}",
@@ -1180,7 +5099,7 @@ impl PyBoxReactor {
                 })?;
 
             // ========== 优化：批量释放（一次调用）==========
-            core.free_buffer(&mut *store, base_ptr)
+            core.free_buffer(&mut store, base_ptr)
                 .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))?;
 
             // 检查结果
@@ -1191,7 +5110,1067 @@ impl PyBoxReactor {
                 )));
             }
 
+            let mut protected = core.protected.entry(env_id.to_string()).or_default();
+            if !protected.iter().any(|protected| protected == name) {
+                protected.push(name.to_string());
+            }
+            if core.call_depth() == 0 {
+                core.history
+                    .push(env_id, HistoryOp::Protect(name.to_string()));
+            }
+
             Ok(())
-        })
+        });
+        self.audit(py, "protect", env_id, started, &result, |record| {
+            record.insert("name".into(), name.into());
+        });
+        result
+    }
+
+    /// Protect every variable currently defined in an environment
+    ///
+    /// Use it to freeze the setup (tools, configuration) before running
+    /// untrusted code. Variables defined later are not protected.
+    ///
+    /// Args:
+    ///     env_id: Environment ID
+    ///
+    /// Returns:
+    ///     list[str]: The protected names, sorted
+    fn protect_all(&self, py: pyo3::Python, env_id: &str) -> pyo3::PyResult<Vec<String>> {
+        let started = std::time::Instant::now();
+        let result = self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+            core.exec_cache.touch(env_id);
+
+            let mut store = self.store_context()?;
+
+            let pybox_protect_all_func = core.protect_all.get().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("Failed to get pybox_local_protect_all")
+            })?;
+
+            let (base_ptr, ptrs) = core
+                .allocate_pybox_bytes_batch(
+                    &mut store,
+                    &[
+                        env_id.as_bytes(),
+                        &[0u8; 4], // result_ptr_ptr (初始化为 NULL)
+                    ],
+                )
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            let result = pybox_protect_all_func
+                .call(&mut store, (ptrs[0], ptrs[1]))
+                .map_err(guest_error)?;
+            let names = core
+                .take_pybox_bytes_ptr(&mut store, ptrs[1])
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            core.free_buffer(&mut store, base_ptr)
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            if result != 0 {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to protect variables in environment '{}'",
+                    env_id
+                )));
+            }
+            let names: Vec<String> = serde_json::from_slice(&names)
+                .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+
+            let mut protected = core.protected.entry(env_id.to_string()).or_default();
+            for name in &names {
+                if !protected.contains(name) {
+                    protected.push(name.clone());
+                }
+                if core.call_depth() == 0 {
+                    core.history.push(env_id, HistoryOp::Protect(name.clone()));
+                }
+            }
+            Ok(names)
+        });
+        self.audit(py, "protect_all", env_id, started, &result, |record| {
+            if let Ok(names) = &result {
+                record.insert("names".into(), names.clone().into());
+            }
+        });
+        result
+    }
+
+    /// Stop protecting variables in an environment
+    ///
+    /// Names that are not protected are ignored.
+    ///
+    /// Args:
+    ///     env_id: Environment ID
+    ///     names: Variable names to unprotect
+    fn unprotect(&self, py: pyo3::Python, env_id: &str, names: Vec<String>) -> pyo3::PyResult<()> {
+        let started = std::time::Instant::now();
+        let result = self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+            core.exec_cache.touch(env_id);
+
+            let mut store = self.store_context()?;
+
+            let pybox_unprotect_func = core.unprotect.get().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("Failed to get pybox_local_unprotect")
+            })?;
+
+            let names_json = serde_json::to_vec(&names)
+                .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+            let (base_ptr, ptrs) = core
+                .allocate_pybox_bytes_batch(&mut store, &[env_id.as_bytes(), &names_json])
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            let result = pybox_unprotect_func
+                .call(&mut store, (ptrs[0], ptrs[1]))
+                .map_err(guest_error)?;
+
+            core.free_buffer(&mut store, base_ptr)
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            if result != 0 {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to unprotect variables in environment '{}'",
+                    env_id
+                )));
+            }
+
+            if let Some(mut protected) = core.protected.get_mut(env_id) {
+                protected.retain(|name| !names.contains(name));
+            }
+            if core.call_depth() == 0 {
+                core.history
+                    .push(env_id, HistoryOp::Unprotect(names.clone()));
+            }
+            Ok(())
+        });
+        self.audit(py, "unprotect", env_id, started, &result, |record| {
+            record.insert("names".into(), names.clone().into());
+        });
+        result
+    }
+
+    /// List the protected variables of an environment
+    ///
+    /// Read from the sandbox itself, so it reflects exactly what code running in
+    /// the environment cannot modify. Names protected before they were defined
+    /// are included.
+    ///
+    /// Args:
+    ///     env_id: Environment ID
+    ///
+    /// Returns:
+    ///     list[str]: The protected names, sorted
+    fn protected_keys(&self, env_id: &str) -> pyo3::PyResult<Vec<String>> {
+        self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+
+            let mut store = self.store_context()?;
+
+            let pybox_protected_keys_func = core.protected_keys.get().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err(
+                    "Failed to get pybox_local_protected_keys",
+                )
+            })?;
+
+            let (base_ptr, ptrs) = core
+                .allocate_pybox_bytes_batch(
+                    &mut store,
+                    &[
+                        env_id.as_bytes(),
+                        &[0u8; 4], // result_ptr_ptr (初始化为 NULL)
+                    ],
+                )
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            let result = pybox_protected_keys_func
+                .call(&mut store, (ptrs[0], ptrs[1]))
+                .map_err(guest_error)?;
+            let keys = core
+                .take_pybox_bytes_ptr(&mut store, ptrs[1])
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            core.free_buffer(&mut store, base_ptr)
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            if result != 0 {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to get protected variables of environment '{}'",
+                    env_id
+                )));
+            }
+            serde_json::from_slice(&keys)
+                .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+        })
+    }
+
+    /// How many times code in an environment tried to modify or delete each
+    /// protected variable
+    ///
+    /// Every blocked assignment or deletion counts, whether or not the code
+    /// caught the error, so a rising count is a cheap sign of code probing the
+    /// protected setup.
+    ///
+    /// Args:
+    ///     env_id: Environment ID
+    ///
+    /// Returns:
+    ///     dict[str, int]: Blocked attempts per protected name, names never
+    ///         targeted are left out
+    fn protection_stats(&self, py: pyo3::Python, env_id: &str) -> pyo3::PyResult<Py<PyAny>> {
+        self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+
+            let mut store = self.store_context()?;
+
+            let pybox_protection_stats_func = core.protection_stats.get().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("Failed to get pybox_protection_stats")
+            })?;
+
+            let (base_ptr, ptrs) = core
+                .allocate_pybox_bytes_batch(
+                    &mut store,
+                    &[
+                        env_id.as_bytes(),
+                        &[0u8; 4], // result_ptr_ptr (初始化为 NULL)
+                    ],
+                )
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            let result = pybox_protection_stats_func
+                .call(&mut store, (ptrs[0], ptrs[1]))
+                .map_err(guest_error)?;
+            let stats = core
+                .take_pybox_bytes_ptr(&mut store, ptrs[1])
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            core.free_buffer(&mut store, base_ptr)
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            if result != 0 {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to get protection stats of environment '{}'",
+                    env_id
+                )));
+            }
+            Ok(json::loads(py, &stats)?.unbind())
+        })
+    }
+
+    /// What the guest changed in the builtins of its interpreters
+    ///
+    /// Lets a host verify that the sandbox policy in effect matches its
+    /// expectations, for example that `exit` is gone, instead of trusting the
+    /// build of the reactor. Every interpreter of a reactor is created the
+    /// same way, so the report covers all environments.
+    ///
+    /// Returns:
+    ///     list[dict]: Changes in the order they were made, each with `kind`
+    ///         ("builtin" or "module"), `name` and `action` ("added",
+    ///         "removed", or "missing" when a name to remove did not exist)
+    fn sanitizer_report(&self, py: pyo3::Python) -> pyo3::PyResult<Py<PyAny>> {
+        self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+
+            let mut store = self.store_context()?;
+
+            let pybox_sanitizer_report_func = core.sanitizer_report.get().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err(
+                    "Sanitizer reports are not supported by this PyBox image",
+                )
+            })?;
+
+            let (base_ptr, ptrs) = core
+                .allocate_pybox_bytes_batch(&mut store, &[&[0u8; 4]])
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            let result = pybox_sanitizer_report_func
+                .call(&mut store, ptrs[0])
+                .map_err(guest_error)?;
+            let report = core
+                .take_pybox_bytes_ptr(&mut store, ptrs[0])
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            core.free_buffer(&mut store, base_ptr)
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            if result != 0 {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                    "Failed to get the sanitizer report",
+                ));
+            }
+            Ok(json::loads(py, &report)?.unbind())
+        })
+    }
+
+    /// Syntax-check code without running it
+    ///
+    /// The code is compiled by the same compiler exec uses, in a separate
+    /// interpreter, so no environment is touched. Safe to call from inside a
+    /// handler while the guest is running.
+    ///
+    /// Args:
+    ///     code: Python code to check
+    ///
+    /// Returns:
+    ///     list[dict]: Diagnostics with `message`, `line`, `column` (1-based),
+    ///         `offset` (0-based character offset), `end_line` and `end_column`,
+    ///         positions are None when unknown. Empty when the code compiles.
+    fn check(&self, py: pyo3::Python, code: &str) -> pyo3::PyResult<Py<PyAny>> {
+        self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+
+            let mut store = self.store_context()?;
+
+            let pybox_check_func = core.check.get().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("Failed to get pybox_check")
+            })?;
+
+            let (base_ptr, ptrs) = core
+                .allocate_pybox_bytes_batch(
+                    &mut store,
+                    &[
+                        code.as_bytes(),
+                        &[0u8; 4], // result_ptr_ptr (初始化为 NULL)
+                    ],
+                )
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            let result = pybox_check_func
+                .call(&mut store, (ptrs[0], ptrs[1]))
+                .map_err(guest_error)?;
+            let diagnostics = core
+                .take_pybox_bytes_ptr(&mut store, ptrs[1])
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            core.free_buffer(&mut store, base_ptr)
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            if result != 0 {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                    "PyBox check failed",
+                ));
+            }
+            Ok(json::loads(py, &diagnostics)?.unbind())
+        })
+    }
+
+    /// Parse code with the sandbox's parser and return its AST, without running it
+    ///
+    /// Use it for host-side static checks (file access, network use, loop
+    /// patterns) that see exactly what exec would compile. Safe to call from
+    /// inside a handler while the guest is running.
+    ///
+    /// Args:
+    ///     code: Python code to parse
+    ///
+    /// Returns:
+    ///     dict: `tree` is the AST as nested dicts whose `_type` is the node type,
+    ///         `names` the sorted names used, `imports` a list of
+    ///         `{"module", "names", "line"}` and `calls` a list of
+    ///         `{"target", "line"}` where target is the dotted callee, or None
+    ///         when it is not a plain name or attribute chain
+    ///
+    /// Raises:
+    ///     RuntimeError: The code does not parse
+    fn ast(&self, py: pyo3::Python, code: &str) -> pyo3::PyResult<Py<PyAny>> {
+        self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+
+            let mut store = self.store_context()?;
+
+            let pybox_ast_func = core.ast.get().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("Failed to get pybox_ast")
+            })?;
+
+            let (base_ptr, ptrs) = core
+                .allocate_pybox_bytes_batch(
+                    &mut store,
+                    &[
+                        code.as_bytes(),
+                        &[0u8; 4], // result_ptr_ptr (初始化为 NULL)
+                        &[0u8; 4], // error_ptr_ptr (初始化为 NULL)
+                    ],
+                )
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            let result = pybox_ast_func
+                .call(&mut store, (ptrs[0], ptrs[1], ptrs[2]))
+                .map_err(guest_error)?;
+            let tree = core
+                .take_pybox_bytes_ptr(&mut store, ptrs[1])
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+            let error = core
+                .take_pybox_bytes_ptr(&mut store, ptrs[2])
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            core.free_buffer(&mut store, base_ptr)
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            if result != 0 {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "PyBox ast failed: {}",
+                    String::from_utf8_lossy(&error)
+                )));
+            }
+            Ok(json::loads(py, &tree)?.unbind())
+        })
+    }
+
+    /// Retrieve a variable from an environment
+    ///
+    /// Safe to call from inside a handler while the guest is running.
+    ///
+    /// Args:
+    ///     env_id: Environment ID
+    ///     name: Variable name
+    ///
+    /// Returns:
+    ///     The variable value (JSON round-tripped)
+    fn retrieve(&self, py: pyo3::Python, env_id: &str, name: &str) -> pyo3::PyResult<Py<PyAny>> {
+        let started = std::time::Instant::now();
+        let result = self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+
+            // handler 内部重入时使用 Caller 的上下文
+            let mut store = self.store_context()?;
+
+            let pybox_retrieve_func = core.retrieve.get().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("Failed to get pybox_retrieve")
+            })?;
+            core.apply_codec(&mut store);
+
+            let (base_ptr, ptrs) = core
+                .allocate_pybox_bytes_batch(
+                    &mut store,
+                    &[
+                        env_id.as_bytes(),
+                        name.as_bytes(),
+                        &[0u8; 4], // object_ptr_ptr (初始化为 NULL)
+                        &[0u8; 4], // error_ptr_ptr (初始化为 NULL)
+                    ],
+                )
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            let (env_id_ptr, name_ptr, object_ptr_ptr, error_ptr_ptr) =
+                (ptrs[0], ptrs[1], ptrs[2], ptrs[3]);
+
+            let result = pybox_retrieve_func
+                .call(
+                    &mut store,
+                    (env_id_ptr, name_ptr, object_ptr_ptr, error_ptr_ptr),
+                )
+                .map_err(|e| {
+                    pyo3::exceptions::PyRuntimeError::new_err(format!(
+                        "pybox_retrieve failed: {}",
+                        e
+                    ))
+                })?;
+
+            let object = core
+                .take_pybox_bytes_ptr(&mut store, object_ptr_ptr)
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+            let error = core
+                .take_pybox_bytes_ptr(&mut store, error_ptr_ptr)
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            core.free_buffer(&mut store, base_ptr)
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            if result != 0 {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "PyBox retrieve failed: {}",
+                    String::from_utf8_lossy(&error)
+                )));
+            }
+            core.env_limits(env_id)
+                .check_value_size(env_id, name, object.len())?;
+
+            Ok(json::loads(py, &object)?.unbind())
+        });
+        self.audit(py, "retrieve", env_id, started, &result, |record| {
+            record.insert("name".into(), name.into());
+        });
+        result
+    }
+
+    /// Call a callable stored in an environment
+    ///
+    /// Arguments and the return value are JSON round-tripped. Safe to call from
+    /// inside a handler while the guest is running. A call running past the
+    /// watchdog timeout raises PyBoxTimeoutError, see set_watchdog.
+    ///
+    /// Args:
+    ///     env_id: Environment ID
+    ///     name: Name of the callable variable
+    ///     *args, **kwargs: Arguments passed to the callable
+    ///
+    /// Returns:
+    ///     The return value of the callable
+    #[pyo3(signature = (env_id, name, *args, **kwargs))]
+    fn call(
+        &self,
+        py: pyo3::Python,
+        env_id: &str,
+        name: &str,
+        args: &Bound<'_, pyo3::types::PyTuple>,
+        kwargs: Option<&Bound<'_, pyo3::types::PyDict>>,
+    ) -> pyo3::PyResult<Py<PyAny>> {
+        let started = std::time::Instant::now();
+        let span = self.start_span(py, "pybox.call", Some(env_id), |attrs| {
+            attrs.set_item("pybox.name", name)
+        });
+        let result = self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+            core.exec_cache.touch(env_id);
+
+            core.check_rate_limit(env_id)?;
+            // handler 内部重入时使用 Caller 的上下文
+            let mut store = self.store_context()?;
+
+            let pybox_call_func = core.call.get().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("Failed to get pybox_call")
+            })?;
+            let _env = core.enter_env(Some(env_id));
+
+            // 参数序列化为 {"args": [...], "kwargs": {...}}
+            let request = pyo3::types::PyDict::new(py);
+            request.set_item("args", args)?;
+            match kwargs {
+                Some(kwargs) => request.set_item("kwargs", kwargs)?,
+                None => request.set_item("kwargs", pyo3::types::PyDict::new(py))?,
+            }
+            let args_json = json::dumps(py, &request, core.codec_options(), None)?;
+            core.apply_codec(&mut store);
+
+            let (base_ptr, ptrs) = core
+                .allocate_pybox_bytes_batch(
+                    &mut store,
+                    &[
+                        env_id.as_bytes(),
+                        name.as_bytes(),
+                        args_json.as_bytes(),
+                        &[0u8; 4], // result_ptr_ptr (初始化为 NULL)
+                        &[0u8; 4], // error_ptr_ptr (初始化为 NULL)
+                    ],
+                )
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            let (env_id_ptr, name_ptr, args_ptr, result_ptr_ptr, error_ptr_ptr) =
+                (ptrs[0], ptrs[1], ptrs[2], ptrs[3], ptrs[4]);
+
+            // handler 的调用次数按最外层的 exec / call 计算
+            if core.call_depth() == 0 {
+                core.handler_quotas.reset_calls();
+            }
+            let metering = core.start_metering(&mut store, env_id)?;
+            // 与 exec 一样，最外层的 call 受 watchdog 的截止时间限制
+            let outermost = core.call_depth() == 0;
+            if outermost {
+                core.watchdog.start();
+            }
+            let result = pybox_call_func.call(
+                &mut store,
+                (
+                    env_id_ptr,
+                    name_ptr,
+                    args_ptr,
+                    result_ptr_ptr,
+                    error_ptr_ptr,
+                ),
+            );
+            if outermost {
+                core.watchdog.finish();
+            }
+            core.stop_metering(&mut store, env_id, metering);
+            let result = match result.map_err(guest_error) {
+                // 超时被终止的 guest 状态不可信，恢复到最近一次正常状态后抛出超时错误
+                Err(err) if outermost && err.is_instance_of::<PyBoxTimeoutError>(py) => {
+                    core.restore_watchdog(&mut store)?;
+                    core.counters.timeouts.fetch_add(1, Ordering::Relaxed);
+                    return Err(err);
+                }
+                result => result?,
+            };
+
+            let ret = core
+                .take_pybox_bytes_ptr(&mut store, result_ptr_ptr)
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+            let error = core
+                .take_pybox_bytes_ptr(&mut store, error_ptr_ptr)
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            core.free_buffer(&mut store, base_ptr)
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            if result != 0 {
+                // traceback 包含源码行，可能带有密钥
+                let redactor = core.redactor.lock().unwrap().clone_ref(py);
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "PyBox call failed: {}",
+                    redactor.redact(py, &String::from_utf8_lossy(&error))?
+                )));
+            }
+
+            Ok(json::loads(py, &ret)?.unbind())
+        });
+        self.audit(py, "call", env_id, started, &result, |record| {
+            record.insert("name".into(), name.into());
+        });
+        Self::end_span(py, span, result)
+    }
+
+    /// Assign the raw bytes of a buffer object to a variable, bypassing JSON
+    ///
+    /// The data is copied once straight into guest memory, suited to large
+    /// arrays such as numpy arrays. Protection is bypassed like `assign`.
+    ///
+    /// Args:
+    ///     env_id: Environment ID
+    ///     name: Variable name
+    ///     obj: Any C-contiguous object supporting the buffer protocol
+    ///     memoryview: Bind a memoryview of the bytes instead of the bytes
+    #[pyo3(signature = (env_id, name, obj, memoryview=false))]
+    fn assign_buffer(
+        &self,
+        py: pyo3::Python,
+        env_id: &str,
+        name: &str,
+        obj: &Bound<'_, PyAny>,
+        memoryview: bool,
+    ) -> pyo3::PyResult<()> {
+        // 任意格式的连续 buffer 统一转换为字节视图
+        let view = py
+            .import("builtins")?
+            .getattr("memoryview")?
+            .call1((obj,))?;
+        if !view.getattr("c_contiguous")?.extract::<bool>()? {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "assign_buffer requires a C-contiguous buffer",
+            ));
+        }
+        let view = view.call_method1("cast", ("B",))?;
+        let buffer = pyo3::buffer::PyBuffer::<u8>::get(&view)?;
+        // SAFETY: buffer 为 C 连续的字节 buffer，持有期间数据有效
+        let data = unsafe {
+            std::slice::from_raw_parts(buffer.buf_ptr() as *const u8, buffer.len_bytes())
+        };
+        let len = WasmSize::try_from(data.len()).map_err(|_| {
+            pyo3::exceptions::PyValueError::new_err("Buffer too large for guest memory")
+        })?;
+
+        let started = std::time::Instant::now();
+        let result = self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+            core.exec_cache.touch(env_id);
+
+            core.check_rate_limit(env_id)?;
+            let mut store = self.store_context()?;
+
+            let (Some(pybox_buffer_alloc), Some(pybox_assign_buffer)) =
+                (core.buffer_alloc.get(), core.assign_buffer.get())
+            else {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                    "Failed to get pybox_assign_buffer",
+                ));
+            };
+
+            // guest 分配的缓冲区直接成为 bytes 的存储，交给 pybox_assign_buffer 后由 guest 回收
+            let buffer_ptr = pybox_buffer_alloc
+                .call(&mut store, len)
+                .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+            let assigned = (|| {
+                core.write_memory_bytes(&mut store, buffer_ptr, data)
+                    .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+                let (base_ptr, ptrs) = core
+                    .allocate_pybox_bytes_batch(
+                        &mut store,
+                        &[
+                            env_id.as_bytes(),
+                            name.as_bytes(),
+                            &[0u8; 4], // error_ptr_ptr (初始化为 NULL)
+                        ],
+                    )
+                    .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+                let (env_id_ptr, name_ptr, error_ptr_ptr) = (ptrs[0], ptrs[1], ptrs[2]);
+
+                let result = pybox_assign_buffer
+                    .call(
+                        &mut store,
+                        (
+                            env_id_ptr,
+                            name_ptr,
+                            buffer_ptr,
+                            len,
+                            memoryview as i32,
+                            error_ptr_ptr,
+                        ),
+                    )
+                    .map_err(|e| {
+                        pyo3::exceptions::PyRuntimeError::new_err(format!(
+                            "Wasmtime runtime error: {}",
+                            e
+                        ))
+                    })?;
+                Ok::<_, PyErr>((base_ptr, error_ptr_ptr, result))
+            })();
+            // 缓冲区没有交给 guest 时不会被取走，释放它以免留在 guest 中
+            let (base_ptr, error_ptr_ptr, result) = assigned.inspect_err(|_| {
+                if let Some(release) = core.buffer_release.get() {
+                    let _ = release.call(&mut store, ());
+                }
+            })?;
+
+            let error = core
+                .take_pybox_bytes_ptr(&mut store, error_ptr_ptr)
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            core.free_buffer(&mut store, base_ptr)
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            if result != 0 {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "PyBox assign_buffer failed: {}",
+                    String::from_utf8_lossy(&error)
+                )));
+            }
+            Ok(())
+        });
+        self.audit(py, "assign_buffer", env_id, started, &result, |record| {
+            record.insert("name".into(), name.into());
+            record.insert("size".into(), data.len().into());
+        });
+        result
+    }
+
+    /// Read-only view of a bytes or bytearray variable without copying it
+    ///
+    /// The view points straight into guest memory, `memoryview(view)` exposes it
+    /// as a buffer. The guest keeps the variable alive and a bytearray cannot be
+    /// resized until the view is released.
+    ///
+    /// Args:
+    ///     env_id: Environment ID
+    ///     name: Name of the variable
+    ///
+    /// Returns:
+    ///     PyBoxView: Release it with `release()` or use it as a context manager
+    fn view(slf: &Bound<'_, Self>, env_id: &str, name: &str) -> pyo3::PyResult<PyBoxView> {
+        let this = slf.borrow();
+        let (view_id, ptr, len) = this.safe_access(|| {
+            let core = this.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+            core.exec_cache.touch(env_id);
+
+            let mut store = this.store_context()?;
+
+            let pybox_view_acquire = core.view_acquire.get().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("Failed to get pybox_view_acquire")
+            })?;
+
+            let (base_ptr, ptrs) = core
+                .allocate_pybox_bytes_batch(
+                    &mut store,
+                    &[
+                        env_id.as_bytes(),
+                        name.as_bytes(),
+                        &[0u8; 12], // info: view id, 数据地址, 数据长度
+                        &[0u8; 4],  // error_ptr_ptr (初始化为 NULL)
+                    ],
+                )
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            let (env_id_ptr, name_ptr, info_ptr, error_ptr_ptr) =
+                (ptrs[0], ptrs[1], ptrs[2], ptrs[3]);
+
+            let result = pybox_view_acquire
+                .call(&mut store, (env_id_ptr, name_ptr, info_ptr, error_ptr_ptr))
+                .map_err(|e| {
+                    pyo3::exceptions::PyRuntimeError::new_err(format!(
+                        "Wasmtime runtime error: {}",
+                        e
+                    ))
+                })?;
+
+            let info = core
+                .read_memory_bytes(&store, info_ptr, 12)
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+            let error = core
+                .take_pybox_bytes_ptr(&mut store, error_ptr_ptr)
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            core.free_buffer(&mut store, base_ptr)
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+
+            if result != 0 {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "PyBox view failed: {}",
+                    String::from_utf8_lossy(&error)
+                )));
+            }
+
+            let field = |i: usize| u32::from_le_bytes(info[i * 4..i * 4 + 4].try_into().unwrap());
+            Ok((field(0), field(1), field(2)))
+        })?;
+        Ok(PyBoxView::new(slf.clone().unbind(), view_id, ptr, len))
+    }
+
+    /// Create a ring buffer shared with the guest
+    ///
+    /// The buffer lives in guest memory for the lifetime of the reactor, both sides
+    /// read and write messages directly without an ioctl call per message.
+    /// Each direction supports a single writer and a single reader, guest code
+    /// reaches it with `pybox.ring(name)`.
+    ///
+    /// Args:
+    ///     name: Name of the ring buffer
+    ///     capacity: Size of the data area in bytes, each message takes 4 extra bytes
+    fn ring_create(&self, name: &str, capacity: WasmSize) -> pyo3::PyResult<()> {
+        self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+            if capacity < MIN_RING_CAPACITY {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "Ring buffer capacity must be at least {} bytes",
+                    MIN_RING_CAPACITY
+                )));
+            }
+            let mut store = self.store_context()?;
+            core.create_ring(&mut store, name, capacity)
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)
+        })
+    }
+
+    /// Write a message into a ring buffer
+    ///
+    /// Returns:
+    ///     bool: False if the ring buffer is full
+    fn ring_write(&self, name: &str, data: &[u8]) -> pyo3::PyResult<bool> {
+        self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+            let mut store = self.memory_context()?;
+            core.with_ring(&mut store, name, |ring| Ok(ring.write(data)))
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)
+        })
+    }
+
+    /// Read a message from a ring buffer
+    ///
+    /// Returns:
+    ///     bytes | None: None if the ring buffer is empty
+    fn ring_read(&self, py: pyo3::Python, name: &str) -> pyo3::PyResult<Option<Py<PyBytes>>> {
+        self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+            let mut store = self.memory_context()?;
+            let message = core
+                .with_ring(&mut store, name, |ring| ring.read())
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+            Ok(message.map(|message| PyBytes::new(py, &message).unbind()))
+        })
+    }
+
+    /// Read every pending message from a ring buffer
+    ///
+    /// Returns:
+    ///     list[bytes]: Messages in the order they were written
+    fn ring_drain(&self, py: pyo3::Python, name: &str) -> pyo3::PyResult<Vec<Py<PyBytes>>> {
+        self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+            let mut store = self.memory_context()?;
+            let messages = core
+                .with_ring(&mut store, name, |ring| {
+                    let mut messages = Vec::new();
+                    while let Some(message) = ring.read()? {
+                        messages.push(message);
+                    }
+                    Ok(messages)
+                })
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+            Ok(messages
+                .iter()
+                .map(|message| PyBytes::new(py, message).unbind())
+                .collect())
+        })
+    }
+
+    /// Create a template environment instantiated cheaply by `instantiate`
+    ///
+    /// The setup code runs once in a hidden environment. Instances get a deep
+    /// copy of its variables, so changing mutable values in one instance does not
+    /// affect the template or other instances; modules are shared and functions
+    /// are rebound to the instance.
+    ///
+    /// Args:
+    ///     name: Template name
+    ///     setup_code: Code preparing the template state
+    ///     protected: Variables protected in every instance
+    ///
+    /// Returns:
+    ///     str: Output of the setup code
+    #[pyo3(signature = (name, setup_code, protected=Vec::new()))]
+    fn create_template(
+        &self,
+        py: pyo3::Python,
+        name: &str,
+        setup_code: &str,
+        protected: Vec<String>,
+    ) -> pyo3::PyResult<Py<PyAny>> {
+        self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+            if core.templates.contains_key(name) {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "Template '{}' already exists",
+                    name
+                )));
+            }
+
+            let template_env = format!("{}{}", TEMPLATE_ENV_PREFIX, name);
+            if !self.init_local(&template_env, None, None)? {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to create template '{}'",
+                    name
+                )));
+            }
+            // setup 代码失败时删除模板环境
+            let output = self
+                .exec(
+                    py,
+                    setup_code,
+                    Some(&template_env),
+                    false,
+                    None,
+                    None,
+                    None,
+                    None,
+                    false,
+                    None,
+                    false,
+                )
+                .and_then(|output| {
+                    if core.last_exec_raised(self.memory_context()?) {
+                        return Err(pyo3::exceptions::PyRuntimeError::new_err(format!(
+                            "PyBox template setup failed: {}",
+                            output
+                        )));
+                    }
+                    Ok(output)
+                });
+            let output = match output {
+                Ok(output) => output,
+                Err(e) => {
+                    self.del_local(&template_env)?;
+                    return Err(e);
+                }
+            };
+            core.templates.insert(name.to_string(), protected);
+            Ok(output)
+        })
+    }
+
+    /// Create an environment from a template
+    ///
+    /// Raises RuntimeError and creates nothing when a template variable cannot be
+    /// deep-copied.
+    ///
+    /// Args:
+    ///     template: Template name
+    ///     env_id: New environment ID
+    ///
+    /// Returns:
+    ///     bool: True if successful, False if the environment already exists
+    fn instantiate(&self, py: pyo3::Python, template: &str, env_id: &str) -> pyo3::PyResult<bool> {
+        self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+            let protected = core
+                .templates
+                .get(template)
+                .map(|protected| protected.clone())
+                .ok_or_else(|| {
+                    pyo3::exceptions::PyKeyError::new_err(format!(
+                        "Template '{}' not found",
+                        template
+                    ))
+                })?;
+
+            let template_env = format!("{}{}", TEMPLATE_ENV_PREFIX, template);
+            if !self.init_local_from(env_id, &template_env)? {
+                return Ok(false);
+            }
+            // 深拷贝浅拷贝来的变量，实例之间以及与模板之间不共享可变状态
+            if let Err(error) = core.run_hook(self.store_context()?, Some(env_id), COPY_ENV_CODE)? {
+                self.del_local(env_id)?;
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(format!(
+                    "Failed to instantiate template '{}': {}",
+                    template, error
+                )));
+            }
+            // 复制环境不会保留保护状态，需要重新保护
+            for name in &protected {
+                self.protect(py, env_id, name)?;
+            }
+            Ok(true)
+        })
+    }
+
+    /// Delete a template, existing instances are not affected
+    fn del_template(&self, name: &str) -> pyo3::PyResult<bool> {
+        self.safe_access(|| {
+            let core = self.core.as_ref().ok_or_else(|| {
+                pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+            })?;
+            if core.templates.remove(name).is_none() {
+                return Ok(false);
+            }
+            self.del_local(&format!("{}{}", TEMPLATE_ENV_PREFIX, name))
+        })
+    }
+
+    /// Names of the templates
+    fn templates(&self) -> pyo3::PyResult<Vec<String>> {
+        let core = self.core.as_ref().ok_or_else(|| {
+            pyo3::exceptions::PyRuntimeError::new_err("PyBoxReactor not initialized")
+        })?;
+        let mut names: Vec<String> = core
+            .templates
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        names.sort();
+        Ok(names)
     }
 }
@@ -1,18 +1,612 @@
 #![allow(dead_code)]
 
-use crate::reactor::PyBoxReactor;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::instance_state::{InstanceState, read_bytes};
+use crate::mapped_memory::{MAPPED_ALIGN, MappedMemory};
+use crate::reactor::{PyBoxReactor, PyBoxReactorCore, StoreData};
 use pyo3::prelude::*;
+use pyo3::types::PyType;
+
+/// 快照文件的魔数
+const SNAPSHOT_MAGIC: &[u8; 8] = b"PYBOXSNP";
+/// 快照文件格式的版本，格式改变时增加
+const SNAPSHOT_VERSION: u32 = 2;
+/// 保存快照时的 zstd 压缩等级，guest 内存大部分为空页，压缩收益很大
+const SNAPSHOT_ZSTD_LEVEL: i32 = 3;
+/// 增量快照比较和记录的页大小，wasm 内存大小总是它的整数倍
+const DELTA_PAGE_SIZE: usize = 4096;
+/// 区分同一进程中并发 save 的临时文件
+static SAVE_COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
 
 /// 简单的内存快照
 /// 用法：
 ///   snapshot = PyBoxReactorSnapshot(reactor)  # 保存当前状态
 ///   # ... 执行一些操作
 ///   snapshot.restore(reactor)  # 恢复到快照时刻
+///   snapshot.save(path)  # 保存到文件，PyBoxReactorSnapshot.load(path) 读回
+///   data = snapshot.to_bytes()  # 同样的格式，from_bytes(data) 读回，也支持 pickle
+///   snapshot.save(path, compress=False)  # 不压缩，PyBoxReactorSnapshot.load(path, mmap=True) 映射文件
+///   delta = PyBoxReactorSnapshot(reactor, base=snapshot)  # 只记录与 base 不同的页
+///   snapshot.diff(other)  # 不同的页数、范围和字节数
+///   with snapshot.guard(reactor):  # 离开代码块时自动恢复
+///       reactor.exec(code, env_id)
+///
+/// 除线性内存外还保存导出的可变全局变量（shadow stack 指针）和表，
+/// 表的元素只能恢复到快照所在的 reactor，文件中只保存全局变量
+///
+/// 快照文件格式，字符串为 u32 长度 | utf-8：
+///   魔数 "PYBOXSNP" | u32 格式版本 | u8 是否压缩 | 模块 hash | 引擎特性 | 全局变量 | 局部环境
+///   | u64 内存大小 | zstd 压缩的内存，或补 0 到 MAPPED_ALIGN 对齐后未压缩的内存
+///
+/// 恢复前检查模块 hash、引擎特性和内存大小，来自其他 wasm 的快照会破坏 instance
 #[pyclass(subclass)]
 pub struct PyBoxReactorSnapshot {
     /// 保存的内存快照
-    snapshot: Option<Vec<u8>>,
+    snapshot: Option<SnapshotMemory>,
+    /// 快照来源 wasm 文件的 sha256
+    module_hash: Option<String>,
+    /// 快照时影响内存布局的引擎特性
+    engine_features: Option<String>,
+    /// 增量快照，此时 snapshot 为 None
+    delta: Option<Delta>,
+    /// 快照时的全局变量和表，增量快照也保存完整的状态
+    state: InstanceState,
+    /// 快照时的局部环境及其保护的变量，恢复时替换 reactor 的记录
+    envs: Option<Vec<(String, Vec<String>)>>,
+    /// 快照时开始的脏页跟踪的编号，reactor 未开启跟踪时为 None
+    generation: Option<u64>,
+    /// 以此快照为 base 的增量快照各持有一份，计数大于 1 时不能再 update
+    dependents: Arc<()>,
+}
+
+fn invalid(reason: &str) -> PyErr {
+    pyo3::exceptions::PyValueError::new_err(format!("Invalid PyBox snapshot: {}", reason))
+}
+
+fn write_str(mut writer: impl Write, value: &str) -> std::io::Result<()> {
+    writer.write_all(&(value.len() as u32).to_le_bytes())?;
+    writer.write_all(value.as_bytes())
+}
+
+fn read_u32(mut reader: impl Read) -> std::io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_str(mut reader: impl Read) -> std::io::Result<String> {
+    let len = read_u32(&mut reader)?;
+    let buf = read_bytes(&mut reader, len as u64)?;
+    String::from_utf8(buf).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// 局部环境：u32 数量 | 每项环境 id、u32 保护的变量数量、变量名，字符串为 u32 长度 | utf-8
+fn write_envs(mut writer: impl Write, envs: &[(String, Vec<String>)]) -> std::io::Result<()> {
+    writer.write_all(&(envs.len() as u32).to_le_bytes())?;
+    for (env_id, protected) in envs {
+        write_str(&mut writer, env_id)?;
+        writer.write_all(&(protected.len() as u32).to_le_bytes())?;
+        for name in protected {
+            write_str(&mut writer, name)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_envs(mut reader: impl Read) -> std::io::Result<Vec<(String, Vec<String>)>> {
+    let mut envs = Vec::new();
+    for _ in 0..read_u32(&mut reader)? {
+        let env_id = read_str(&mut reader)?;
+        let mut protected = Vec::new();
+        for _ in 0..read_u32(&mut reader)? {
+            protected.push(read_str(&mut reader)?);
+        }
+        envs.push((env_id, protected));
+    }
+    Ok(envs)
+}
+
+/// 完整快照的内存
+enum SnapshotMemory {
+    Owned(Vec<u8>),
+    /// 映射的快照文件，恢复时以写时复制映射到 guest 内存
+    Mapped(MappedMemory),
+}
+
+impl std::ops::Deref for SnapshotMemory {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Owned(data) => data,
+            Self::Mapped(mapped) => mapped.data(),
+        }
+    }
+}
+
+/// 快照文件的头部
+struct Header {
+    compressed: bool,
+    module_hash: String,
+    engine_features: String,
+    state: InstanceState,
+    envs: Vec<(String, Vec<String>)>,
+    /// 内存大小
+    size: usize,
+}
+
+/// 相对 base 快照的增量
+struct Delta {
+    base: Py<PyBoxReactorSnapshot>,
+    /// base 的 dependents，增量快照释放或改为其他 base 时减少计数
+    _dependent: Arc<()>,
+    /// 快照时的内存大小
+    len: usize,
+    /// 与 base 不同的页的序号，数据按顺序存放在 data 中
+    pages: Vec<usize>,
+    data: Vec<u8>,
+}
+
+impl Delta {
+    /// 比较 memory 与 base 的内存，记录不同的页，超出 base 的部分与全 0 比较
+    fn new(
+        base: Py<PyBoxReactorSnapshot>,
+        dependent: Arc<()>,
+        base_memory: &[u8],
+        memory: &[u8],
+    ) -> Self {
+        let mut pages = Vec::new();
+        let mut data = Vec::new();
+        for (index, page) in memory.chunks(DELTA_PAGE_SIZE).enumerate() {
+            if page_changed(base_memory, index * DELTA_PAGE_SIZE, page) {
+                pages.push(index);
+                data.extend_from_slice(page);
+            }
+        }
+        Self {
+            base,
+            _dependent: dependent,
+            len: memory.len(),
+            pages,
+            data,
+        }
+    }
+
+    /// 只重新比较 dirty 范围内的页，其余的页保持原来的记录
+    fn update(&mut self, base_memory: &[u8], memory: &[u8], dirty: &[Range<usize>]) {
+        let mut pages: BTreeMap<usize, Vec<u8>> = self
+            .pages
+            .iter()
+            .copied()
+            .zip(self.data.chunks(DELTA_PAGE_SIZE).map(<[u8]>::to_vec))
+            .collect();
+        for range in dirty {
+            for index in range.start / DELTA_PAGE_SIZE..range.end.div_ceil(DELTA_PAGE_SIZE) {
+                let start = index * DELTA_PAGE_SIZE;
+                let Some(page) = memory.get(start..(start + DELTA_PAGE_SIZE).min(memory.len()))
+                else {
+                    continue;
+                };
+                if page_changed(base_memory, start, page) {
+                    pages.insert(index, page.to_vec());
+                } else {
+                    pages.remove(&index);
+                }
+            }
+        }
+        self.len = memory.len();
+        self.pages = pages.keys().copied().collect();
+        self.data = pages.into_values().flatten().collect();
+    }
+}
+
+/// memory 中 start 开始的页是否与 base 不同，超出 base 的部分与全 0 比较
+fn page_changed(base_memory: &[u8], start: usize, page: &[u8]) -> bool {
+    match base_memory.get(start..start + page.len()) {
+        Some(base_page) => base_page != page,
+        None => page.iter().any(|&byte| byte != 0),
+    }
 }
+
+/// 按页比较两段内存，较短的一段超出的部分视为 0
+/// 返回不同的页数、合并相邻页后的字节范围 [start, end) 和不同的字节数
+fn diff_pages(a: &[u8], b: &[u8], page_size: usize) -> (usize, Vec<(usize, usize)>, usize) {
+    let len = a.len().max(b.len());
+    let mut pages = 0;
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    let mut changed_bytes = 0;
+    for start in (0..len).step_by(page_size) {
+        let end = (start + page_size).min(len);
+        let page_a = &a[start.min(a.len())..end.min(a.len())];
+        let page_b = &b[start.min(b.len())..end.min(b.len())];
+        if page_a == page_b && page_a.len() == end - start {
+            continue;
+        }
+        let byte = |page: &[u8], index: usize| page.get(index).copied().unwrap_or(0);
+        let changed = (0..end - start)
+            .filter(|&index| byte(page_a, index) != byte(page_b, index))
+            .count();
+        if changed == 0 {
+            continue;
+        }
+        pages += 1;
+        changed_bytes += changed;
+        match ranges.last_mut() {
+            Some(range) if range.1 == start => range.1 = end,
+            _ => ranges.push((start, end)),
+        }
+    }
+    (pages, ranges, changed_bytes)
+}
+
+impl PyBoxReactorSnapshot {
+    /// 以 store 的当前状态创建完整快照，供已经持有 store 的调用方使用
+    pub fn capture(
+        reactor: &PyBoxReactor,
+        core: &PyBoxReactorCore,
+        mut store: impl wasmtime::AsContextMut<Data = StoreData>,
+    ) -> PyResult<Self> {
+        let Some(memory) = core.get_memory() else {
+            return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                "Can not get PyBoxReactor Memory!",
+            ));
+        };
+        let state = core.capture_state(&mut store);
+        let generation = core.dirty_checkpoint(&store, None).0;
+        Ok(Self {
+            snapshot: Some(SnapshotMemory::Owned(memory.data(&store).to_vec())),
+            module_hash: Some(reactor.module_hash().to_string()),
+            engine_features: Some(core.engine_features(&store)),
+            delta: None,
+            state,
+            envs: Some(core.env_records()),
+            generation,
+            dependents: Arc::default(),
+        })
+    }
+
+    /// 快照时的内存大小
+    pub fn len(&self) -> usize {
+        match (&self.snapshot, &self.delta) {
+            (Some(snapshot), _) => snapshot.len(),
+            (None, Some(delta)) => delta.len,
+            (None, None) => 0,
+        }
+    }
+
+    /// 检查快照能否恢复到 reactor，模块、引擎特性不同或内存超过上限时返回错误
+    pub fn check_compatible(&self, reactor: &PyBoxReactor) -> PyResult<()> {
+        let incompatible = |reason: String| {
+            pyo3::exceptions::PyValueError::new_err(format!(
+                "Snapshot is not compatible with this reactor: {}",
+                reason
+            ))
+        };
+        if let Some(hash) = &self.module_hash
+            && hash != reactor.module_hash()
+        {
+            return Err(incompatible(format!(
+                "taken from wasm module {}, the reactor loaded {}",
+                hash,
+                reactor.module_hash()
+            )));
+        }
+        reactor.safe_access(|| {
+            let Some(core) = reactor.core.as_ref() else {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                    "Can not fetch PyBoxReactorCore!",
+                ));
+            };
+            let store = reactor.store_context()?;
+            let features = core.engine_features(&store);
+            if let Some(expected) = &self.engine_features
+                && *expected != features
+            {
+                return Err(incompatible(format!(
+                    "taken with engine features {}, the reactor has {}",
+                    expected, features
+                )));
+            }
+            let maximum = core.get_memory().and_then(|memory| {
+                let ty = memory.ty(&store);
+                ty.maximum()
+                    .map(|pages| pages.saturating_mul(ty.page_size()))
+            });
+            if let Some(maximum) = maximum
+                && self.len() as u64 > maximum
+            {
+                return Err(incompatible(format!(
+                    "{} bytes of memory, the reactor allows at most {}",
+                    self.len(),
+                    maximum
+                )));
+            }
+            Ok(())
+        })
+    }
+
+    /// 只复制上次快照以来写入过的页，无法确定写入过哪些页时返回 false
+    fn update_dirty(&mut self, py: Python<'_>, reactor: &PyBoxReactor) -> PyResult<bool> {
+        let Some(since) = self.generation else {
+            return Ok(false);
+        };
+        // 映射的快照不能原地修改
+        if matches!(self.snapshot, Some(SnapshotMemory::Mapped(_))) {
+            return Ok(false);
+        }
+        reactor.safe_access(|| {
+            let Some(core) = reactor.core.as_ref() else {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                    "Can not fetch PyBoxReactorCore!",
+                ));
+            };
+
+            let mut store = reactor.store_context()?;
+
+            let Some(memory) = core.get_memory() else {
+                return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                    "Can not get PyBoxReactor Memory!",
+                ));
+            };
+
+            let (generation, dirty) = core.dirty_checkpoint(&store, Some(since));
+            self.generation = generation;
+            let Some(dirty) = dirty else {
+                return Ok(false);
+            };
+            let state = core.capture_state(&mut store);
+            let data = memory.data(&store);
+            match (&mut self.snapshot, &mut self.delta) {
+                (Some(SnapshotMemory::Owned(snapshot)), _) => {
+                    snapshot.resize(data.len(), 0);
+                    for range in dirty {
+                        snapshot[range.clone()].copy_from_slice(&data[range]);
+                    }
+                }
+                (None, Some(delta)) => {
+                    let base = delta.base.clone_ref(py);
+                    let base_snapshot = base.borrow(py);
+                    let base_memory = base_snapshot.memory(py)?.ok_or_else(|| {
+                        pyo3::exceptions::PyRuntimeError::new_err("Base snapshot is empty!")
+                    })?;
+                    delta.update(&base_memory, data, &dirty);
+                }
+                _ => return Ok(false),
+            }
+            self.state = state;
+            self.envs = Some(core.env_records());
+            Ok(true)
+        })
+    }
+
+    /// 已是其他增量快照的 base 时不能修改，否则它们会按新的内存还原
+    fn check_no_dependents(&self) -> PyResult<()> {
+        match Arc::strong_count(&self.dependents) - 1 {
+            0 => Ok(()),
+            count => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Snapshot is the base of {} delta snapshot(s) and can not be updated",
+                count
+            ))),
+        }
+    }
+
+    /// 快照时的全局变量和表
+    pub fn state(&self) -> &InstanceState {
+        &self.state
+    }
+
+    /// 快照的完整内存数据，增量快照需要与 base 合并
+    pub fn memory(&self, py: Python<'_>) -> PyResult<Option<Cow<'_, [u8]>>> {
+        if let Some(snapshot) = &self.snapshot {
+            return Ok(Some(Cow::Borrowed(&**snapshot)));
+        }
+        if self.delta.is_none() {
+            return Ok(None);
+        }
+        let mut memory = vec![0u8; self.len()];
+        self.apply(py, &mut memory)?;
+        Ok(Some(Cow::Owned(memory)))
+    }
+
+    /// 将快照写入 memory，超出 memory 的部分被忽略
+    fn apply(&self, py: Python<'_>, memory: &mut [u8]) -> PyResult<()> {
+        if let Some(snapshot) = &self.snapshot {
+            match snapshot {
+                SnapshotMemory::Owned(snapshot) => {
+                    let copy_len = std::cmp::min(memory.len(), snapshot.len());
+                    memory[..copy_len].copy_from_slice(&snapshot[..copy_len]);
+                }
+                SnapshotMemory::Mapped(mapped) => mapped.restore(memory),
+            }
+            return Ok(());
+        }
+        let Some(delta) = &self.delta else {
+            return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                "No snapshot available! Call __init__ first.",
+            ));
+        };
+        let base = delta.base.borrow(py);
+        base.apply(py, memory)?;
+        // base 之后增长的内存在快照时除记录的页外都是 0
+        let base_len = base.len().min(memory.len());
+        let len = delta.len.min(memory.len());
+        if base_len < len {
+            memory[base_len..len].fill(0);
+        }
+        for (page, data) in delta.pages.iter().zip(delta.data.chunks(DELTA_PAGE_SIZE)) {
+            let start = page * DELTA_PAGE_SIZE;
+            if let Some(target) = memory.get_mut(start..start + data.len()) {
+                target.copy_from_slice(data);
+            }
+        }
+        Ok(())
+    }
+
+    /// 以快照文件格式写入 writer，增量快照与 base 合并后写入
+    fn write_to(&self, py: Python<'_>, mut writer: impl Write, compress: bool) -> PyResult<()> {
+        let Some(snapshot) = self.memory(py)? else {
+            return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                "No snapshot available! Call __init__ first.",
+            ));
+        };
+        let mut header = Vec::new();
+        header.write_all(SNAPSHOT_MAGIC)?;
+        header.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        header.write_all(&[compress as u8])?;
+        write_str(&mut header, self.module_hash.as_deref().unwrap_or_default())?;
+        write_str(
+            &mut header,
+            self.engine_features.as_deref().unwrap_or_default(),
+        )?;
+        self.state.write_globals(&mut header)?;
+        write_envs(&mut header, self.envs.as_deref().unwrap_or_default())?;
+        header.write_all(&(snapshot.len() as u64).to_le_bytes())?;
+        writer.write_all(&header)?;
+        if compress {
+            zstd::stream::copy_encode(&*snapshot, &mut writer, SNAPSHOT_ZSTD_LEVEL)?;
+        } else {
+            // 对齐后才能直接映射
+            let padding =
+                (header.len() as u64).next_multiple_of(MAPPED_ALIGN) - header.len() as u64;
+            std::io::copy(&mut std::io::repeat(0).take(padding), &mut writer)?;
+            writer.write_all(&snapshot)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// 读取 write_to 写入的头部
+    fn read_header(mut reader: impl Read) -> PyResult<Header> {
+        let mut magic = [0u8; 8];
+        reader
+            .read_exact(&mut magic)
+            .map_err(|_| invalid("truncated header"))?;
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        let version = read_u32(&mut reader).map_err(|_| invalid("truncated header"))?;
+        if version != SNAPSHOT_VERSION {
+            return Err(invalid(&format!(
+                "format version {} is not supported, expected {}",
+                version, SNAPSHOT_VERSION
+            )));
+        }
+        let mut compressed = [0u8; 1];
+        reader
+            .read_exact(&mut compressed)
+            .map_err(|_| invalid("truncated header"))?;
+        let module_hash = read_str(&mut reader).map_err(|_| invalid("bad module hash"))?;
+        let engine_features = read_str(&mut reader).map_err(|_| invalid("bad engine features"))?;
+        let state = InstanceState::read_globals(&mut reader).map_err(|e| invalid(&e))?;
+        let envs = read_envs(&mut reader).map_err(|_| invalid("truncated header"))?;
+        let mut size = [0u8; 8];
+        reader
+            .read_exact(&mut size)
+            .map_err(|_| invalid("truncated header"))?;
+        let size = u64::from_le_bytes(size);
+        // 32 位内存最多 4 GiB
+        if !engine_features.starts_with("memory64") && size > 1 << 32 {
+            return Err(invalid("memory size exceeds the 32-bit address space"));
+        }
+        Ok(Header {
+            compressed: compressed[0] != 0,
+            module_hash,
+            engine_features,
+            state,
+            envs,
+            size: usize::try_from(size).map_err(|_| invalid("memory size too large"))?,
+        })
+    }
+
+    /// 读取头部之后的内存，解压的数据不超过头部记录的大小
+    fn read_memory(header: &Header, mut reader: impl Read + Seek) -> PyResult<Vec<u8>> {
+        let limit = header.size as u64;
+        let memory = if header.compressed {
+            let mut memory = Vec::new();
+            zstd::stream::read::Decoder::new(reader)
+                .map_err(|e| invalid(&e.to_string()))?
+                .take(limit + 1)
+                .read_to_end(&mut memory)
+                .map_err(|e| invalid(&e.to_string()))?;
+            memory
+        } else {
+            let offset = reader.stream_position()?.next_multiple_of(MAPPED_ALIGN);
+            // 先与剩余的数据比较，损坏的大小字段不会导致巨大的分配
+            if reader.seek(SeekFrom::End(0))? < offset + limit {
+                return Err(invalid("truncated memory"));
+            }
+            reader.seek(SeekFrom::Start(offset))?;
+            read_bytes(&mut reader, limit).map_err(|_| invalid("truncated memory"))?
+        };
+        if memory.len() != header.size {
+            return Err(invalid("memory size mismatch"));
+        }
+        Ok(memory)
+    }
+
+    /// 不经过 __init__ 创建 cls 的实例，子类同样适用
+    fn from_parts<'py>(
+        cls: &Bound<'py, PyType>,
+        header: Header,
+        memory: SnapshotMemory,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let instance = cls.call_method1("__new__", (cls,))?;
+        *instance.cast::<PyBoxReactorSnapshot>()?.borrow_mut() = Self::with_memory(header, memory);
+        Ok(instance)
+    }
+
+    fn with_memory(header: Header, memory: SnapshotMemory) -> Self {
+        Self {
+            snapshot: Some(memory),
+            module_hash: (!header.module_hash.is_empty()).then_some(header.module_hash),
+            engine_features: (!header.engine_features.is_empty()).then_some(header.engine_features),
+            delta: None,
+            state: header.state,
+            envs: Some(header.envs),
+            generation: None,
+            dependents: Arc::default(),
+        }
+    }
+
+    /// 读取快照文件，mmap 为 true 时映射未压缩的内存
+    fn read_file(path: &std::path::Path, mmap: bool) -> PyResult<(Header, SnapshotMemory)> {
+        let file = std::fs::File::open(path)?;
+        let mut reader = std::io::BufReader::new(file);
+        let header = Self::read_header(&mut reader)?;
+        let memory = if mmap {
+            if header.compressed {
+                return Err(pyo3::exceptions::PyValueError::new_err(
+                    "Only snapshots saved with compress=False can be memory-mapped",
+                ));
+            }
+            let offset = reader.stream_position()?.next_multiple_of(MAPPED_ALIGN);
+            let file = reader.into_inner();
+            // 映射超出文件末尾的部分在访问时会触发 SIGBUS
+            if file.metadata()?.len() < offset + header.size as u64 {
+                return Err(invalid("truncated memory"));
+            }
+            SnapshotMemory::Mapped(MappedMemory::new(file, offset, header.size)?)
+        } else {
+            SnapshotMemory::Owned(Self::read_memory(&header, reader)?)
+        };
+        Ok((header, memory))
+    }
+
+    /// 读取快照文件，未压缩的快照映射内存，压缩的快照读入内存
+    pub fn open(path: &std::path::Path) -> PyResult<Self> {
+        let (header, memory) = match Self::read_file(path, true) {
+            Ok(snapshot) => snapshot,
+            Err(_) => Self::read_file(path, false)?,
+        };
+        Ok(Self::with_memory(header, memory))
+    }
+}
+
 /// 不适用 COW 等方式的情况，很难避免全量扫描，不如直接拷贝存储
 #[pymethods]
 impl PyBoxReactorSnapshot {
@@ -22,11 +616,29 @@ impl PyBoxReactorSnapshot {
         _args: &Bound<'_, pyo3::types::PyTuple>,
         _kwargs: Option<&Bound<'_, pyo3::types::PyDict>>,
     ) -> Self {
-        Self { snapshot: None }
+        Self {
+            snapshot: None,
+            module_hash: None,
+            engine_features: None,
+            delta: None,
+            state: InstanceState::default(),
+            envs: None,
+            generation: None,
+            dependents: Arc::default(),
+        }
     }
 
     /// 初始化快照，保存当前内存状态
-    fn __init__(&mut self, reactor: &PyBoxReactor) -> pyo3::PyResult<()> {
+    /// 指定 base 时为增量快照，只保存与 base 不同的页，base 之后不能再 update
+    /// base 不能是快照自身，已是其他增量快照的 base 时不能重新初始化
+    #[pyo3(signature = (reactor, base=None))]
+    fn __init__(
+        &mut self,
+        py: Python<'_>,
+        reactor: &PyBoxReactor,
+        base: Option<Py<PyBoxReactorSnapshot>>,
+    ) -> pyo3::PyResult<()> {
+        self.check_no_dependents()?;
         reactor.safe_access(|| {
             let Some(core) = reactor.core.as_ref() else {
                 return Err(pyo3::exceptions::PyRuntimeError::new_err(
@@ -34,12 +646,7 @@ impl PyBoxReactorSnapshot {
                 ));
             };
 
-            let store_ptr = reactor
-                .store
-                .as_ref()
-                .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Store not initialized"))?
-                .get();
-            let store = unsafe { &*store_ptr };
+            let mut store = reactor.store_context()?;
 
             let Some(memory) = core.get_memory() else {
                 return Err(pyo3::exceptions::PyRuntimeError::new_err(
@@ -47,15 +654,60 @@ impl PyBoxReactorSnapshot {
                 ));
             };
 
-            // 保存完整内存快照
-            let data = memory.data(store);
-            self.snapshot = Some(data.to_vec());
+            // 先完成所有可能失败的步骤，出错时快照保持不变
+            let state = core.capture_state(&mut store);
+            let generation = core.dirty_checkpoint(&store, None).0;
+            let data = memory.data(&store);
+            let (snapshot, delta) = match base {
+                Some(base) => {
+                    let delta = {
+                        // self 已被可变借用，base 为 self 时借用失败
+                        let base_snapshot = base.try_borrow(py).map_err(|_| {
+                            pyo3::exceptions::PyValueError::new_err(
+                                "A snapshot can not be its own base",
+                            )
+                        })?;
+                        let base_memory = base_snapshot.memory(py)?.ok_or_else(|| {
+                            pyo3::exceptions::PyRuntimeError::new_err("Base snapshot is empty!")
+                        })?;
+                        Delta::new(
+                            base.clone_ref(py),
+                            base_snapshot.dependents.clone(),
+                            &base_memory,
+                            data,
+                        )
+                    };
+                    (None, Some(delta))
+                }
+                // 保存完整内存快照
+                None => (Some(SnapshotMemory::Owned(data.to_vec())), None),
+            };
+            self.snapshot = snapshot;
+            self.delta = delta;
+            self.state = state;
+            self.envs = Some(core.env_records());
+            self.generation = generation;
+            self.module_hash = Some(reactor.module_hash().to_string());
+            self.engine_features = Some(core.engine_features(&store));
             Ok(())
         })
     }
 
     /// 恢复到快照时的内存状态
-    fn restore(&self, reactor: &PyBoxReactor) -> pyo3::PyResult<()> {
+    /// 快照来自其他 wasm 模块或引擎特性不同时抛出 ValueError
+    pub fn restore(&self, py: Python<'_>, reactor: &PyBoxReactor) -> pyo3::PyResult<()> {
+        self.restore_with(py, reactor, true)
+    }
+
+    /// 恢复快照，replace_envs 为 false 时快照中的环境加入已有的记录，
+    /// 不清除 host 端为其他环境保存的设置
+    pub fn restore_with(
+        &self,
+        py: Python<'_>,
+        reactor: &PyBoxReactor,
+        replace_envs: bool,
+    ) -> pyo3::PyResult<()> {
+        self.check_compatible(reactor)?;
         reactor.safe_access(|| {
             let Some(core) = reactor.core.as_ref() else {
                 return Err(pyo3::exceptions::PyRuntimeError::new_err(
@@ -63,12 +715,9 @@ impl PyBoxReactorSnapshot {
                 ));
             };
 
-            let store_ptr = reactor
-                .store
-                .as_ref()
-                .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("Store not initialized"))?
-                .get();
-            let store = unsafe { &mut *store_ptr };
+            // 导出的 memoryview 直接指向 guest 内存，恢复会改写或移动它
+            core.check_views("restore")?;
+            let mut store = reactor.store_context()?;
 
             let Some(memory) = core.get_memory() else {
                 return Err(pyo3::exceptions::PyRuntimeError::new_err(
@@ -76,28 +725,300 @@ impl PyBoxReactorSnapshot {
                 ));
             };
 
-            let Some(snapshot) = &self.snapshot else {
-                return Err(pyo3::exceptions::PyRuntimeError::new_err(
-                    "No snapshot available! Call __init__ first.",
-                ));
-            };
+            // 新创建的 reactor 的内存可能小于快照，先增长到快照的大小
+            let missing = self.len().saturating_sub(memory.data_size(&store));
+            if missing > 0 {
+                let page_size = memory.page_size(&store) as usize;
+                memory
+                    .grow(&mut store, missing.div_ceil(page_size) as u64)
+                    .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))?;
+            }
 
             // 恢复内存
-            let memory_data = memory.data_mut(store);
-            let copy_len = std::cmp::min(memory_data.len(), snapshot.len());
-            memory_data[..copy_len].copy_from_slice(&snapshot[..copy_len]);
-
+            let memory_data = memory.data_mut(&mut store);
+            self.apply(py, memory_data)?;
+            // 恢复全局变量和表
+            core.restore_state(&mut store, &self.state)
+                .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+            match &self.envs {
+                Some(envs) if replace_envs => core.set_env_records(envs),
+                Some(envs) => core.merge_env_records(envs),
+                None => {}
+            }
             Ok(())
         })
     }
 
-    /// 更新快照为当前状态（可选功能）
-    fn update(&mut self, reactor: &PyBoxReactor) -> pyo3::PyResult<()> {
-        self.__init__(reactor)
+    /// 更新快照为当前状态（可选功能），增量快照仍相对原来的 base
+    /// reactor 开启脏页跟踪时只复制上次快照以来写入过的页
+    /// 已是其他增量快照的 base 时抛出 ValueError
+    fn update(&mut self, py: Python<'_>, reactor: &PyBoxReactor) -> pyo3::PyResult<()> {
+        self.check_no_dependents()?;
+        if self.update_dirty(py, reactor)? {
+            return Ok(());
+        }
+        let base = self.delta.as_ref().map(|delta| delta.base.clone_ref(py));
+        self.__init__(py, reactor, base)
     }
 
-    /// 获取快照大小（字节数）
+    /// 获取快照大小（字节数），增量快照为记录的页的大小
     fn size(&self) -> usize {
-        self.snapshot.as_ref().map(|s| s.len()).unwrap_or(0)
+        match (&self.snapshot, &self.delta) {
+            (Some(snapshot), _) => snapshot.len(),
+            (None, Some(delta)) => delta.data.len(),
+            (None, None) => 0,
+        }
+    }
+
+    /// Restore the reactor to this snapshot when a with block exits
+    ///
+    ///     with snapshot.guard(reactor):
+    ///         reactor.exec(risky_code, env_id)
+    ///
+    /// Exceptions raised in the block are not suppressed.
+    ///
+    /// Args:
+    ///     reactor: Reactor to restore
+    ///     on_error: Only restore when the block raises, keeping the changes of
+    ///         a block that completes
+    ///
+    /// Returns:
+    ///     PyBoxSnapshotGuard: Context manager entering as the reactor
+    #[pyo3(signature = (reactor, on_error=false))]
+    fn guard(
+        slf: &Bound<'_, Self>,
+        reactor: Py<PyBoxReactor>,
+        on_error: bool,
+    ) -> PyBoxSnapshotGuard {
+        PyBoxSnapshotGuard {
+            snapshot: slf.clone().unbind(),
+            reactor,
+            on_error,
+            restored: false,
+        }
+    }
+
+    /// Compare the memory of this snapshot with another one
+    ///
+    /// Useful to see how much a session grows between two points, to tune
+    /// delta snapshots or to find out whether code changed state it should
+    /// not have. Memory beyond the end of the smaller snapshot counts as zeros.
+    ///
+    /// Args:
+    ///     other: Snapshot to compare with, of the same module
+    ///     page_size: Granularity of the comparison in bytes, 4096 is the page
+    ///         size of delta snapshots
+    ///
+    /// Returns:
+    ///     dict: `pages` (number of differing pages), `ranges` (list of
+    ///         `(start, end)` byte offsets of runs of differing pages),
+    ///         `changed_bytes` (number of differing bytes) and `sizes`
+    ///         (memory sizes of both snapshots)
+    #[pyo3(signature = (other, page_size=DELTA_PAGE_SIZE))]
+    fn diff<'py>(
+        &self,
+        py: Python<'py>,
+        other: PyRef<'_, PyBoxReactorSnapshot>,
+        page_size: usize,
+    ) -> PyResult<Bound<'py, pyo3::types::PyDict>> {
+        if page_size == 0 {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "page_size must be positive",
+            ));
+        }
+        if let (Some(hash), Some(other_hash)) = (&self.module_hash, &other.module_hash)
+            && hash != other_hash
+        {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "Snapshots were taken from different wasm modules: {} and {}",
+                hash, other_hash
+            )));
+        }
+        let missing = || {
+            pyo3::exceptions::PyRuntimeError::new_err("No snapshot available! Call __init__ first.")
+        };
+        let memory = self.memory(py)?.ok_or_else(missing)?;
+        let other_memory = other.memory(py)?.ok_or_else(missing)?;
+        let (pages, ranges, changed_bytes) = diff_pages(&memory, &other_memory, page_size);
+
+        let diff = pyo3::types::PyDict::new(py);
+        diff.set_item("pages", pages)?;
+        diff.set_item("ranges", ranges)?;
+        diff.set_item("changed_bytes", changed_bytes)?;
+        diff.set_item("sizes", (memory.len(), other_memory.len()))?;
+        Ok(diff)
+    }
+
+    /// Base snapshot of a delta snapshot, None for a full snapshot
+    #[getter]
+    fn base(&self, py: Python<'_>) -> Option<Py<PyBoxReactorSnapshot>> {
+        self.delta.as_ref().map(|delta| delta.base.clone_ref(py))
+    }
+
+    /// Snapshot file format version written by `save`
+    #[classattr]
+    const FORMAT_VERSION: u32 = SNAPSHOT_VERSION;
+
+    /// Engine features affecting the guest memory layout when the snapshot was taken
+    #[getter]
+    fn engine_features(&self) -> Option<&str> {
+        self.engine_features.as_deref()
+    }
+
+    /// sha256 of the wasm file the snapshot was taken from
+    #[getter]
+    pub fn module_hash(&self) -> Option<&str> {
+        self.module_hash.as_deref()
+    }
+
+    /// Save the snapshot to a file
+    ///
+    /// The memory is zstd-compressed behind a small versioned header recording
+    /// the module hash, the engine features and the memory size, load it back
+    /// with `PyBoxReactorSnapshot.load`.
+    /// A delta snapshot is merged with its base and saved as a full snapshot.
+    ///
+    /// Args:
+    ///     path: File to write, replaced if it exists
+    ///     compress: Store the memory uncompressed when False, such a file is
+    ///         larger but can be memory-mapped by `load(path, mmap=True)`
+    #[pyo3(signature = (path, compress=true))]
+    fn save(&self, py: Python<'_>, path: std::path::PathBuf, compress: bool) -> pyo3::PyResult<()> {
+        // 写入同目录的临时文件后 rename 替换，原文件不会被原地截断：
+        // 以 mmap 加载它的快照仍映射着旧的 inode，截断会使其 SIGBUS 或读到被改写的页
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(
+            ".{}.{}.tmp",
+            std::process::id(),
+            SAVE_COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        ));
+        let temp = path.with_file_name(name);
+        let result = (|| -> PyResult<()> {
+            let file = std::fs::File::create(&temp)?;
+            let mut writer = std::io::BufWriter::new(file);
+            self.write_to(py, &mut writer, compress)?;
+            writer
+                .into_inner()
+                .map_err(|err| err.into_error())?
+                .sync_all()?;
+            std::fs::rename(&temp, &path)?;
+            Ok(())
+        })();
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp);
+        }
+        result
+    }
+
+    /// Load a snapshot saved by `save`
+    ///
+    /// Raises ValueError for files that are not snapshots or were written in
+    /// another format version. Compatibility with a reactor is checked when the
+    /// snapshot is restored.
+    ///
+    /// With `mmap=True` the memory of an uncompressed snapshot is mapped instead
+    /// of read, so loading is cheap and snapshots loaded from the same file
+    /// share its pages in the page cache. Restoring copies the mapped pages into
+    /// the guest memory, a restored reactor does not depend on the file. The
+    /// file must not be modified in place while the snapshot is alive; `save`
+    /// replaces files by renaming, so re-saving to the same path is safe.
+    ///
+    /// Args:
+    ///     path: Snapshot file
+    ///     mmap: Map the memory of a snapshot saved with `compress=False`
+    ///
+    /// Returns:
+    ///     An instance of the class it is called on, ready to restore
+    #[classmethod]
+    #[pyo3(signature = (path, mmap=false))]
+    fn load<'py>(
+        cls: &Bound<'py, PyType>,
+        path: std::path::PathBuf,
+        mmap: bool,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let (header, memory) = Self::read_file(&path, mmap)?;
+        Self::from_parts(cls, header, memory)
+    }
+
+    /// Whether the memory is mapped from a snapshot file
+    #[getter]
+    fn mapped(&self) -> bool {
+        matches!(self.snapshot, Some(SnapshotMemory::Mapped(_)))
+    }
+
+    /// Serialize the snapshot in the same format as `save`
+    ///
+    /// Useful to keep snapshots in a key/value store or send them through a job
+    /// queue, read them back with `PyBoxReactorSnapshot.from_bytes`.
+    ///
+    /// Returns:
+    ///     bytes: The serialized snapshot
+    fn to_bytes<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, pyo3::types::PyBytes>> {
+        let mut data = Vec::new();
+        self.write_to(py, &mut data, true)?;
+        Ok(pyo3::types::PyBytes::new(py, &data))
+    }
+
+    /// Deserialize a snapshot produced by `to_bytes` or `save`
+    ///
+    /// Args:
+    ///     data: Serialized snapshot
+    ///
+    /// Returns:
+    ///     An instance of the class it is called on, ready to restore
+    #[classmethod]
+    fn from_bytes<'py>(cls: &Bound<'py, PyType>, data: &[u8]) -> PyResult<Bound<'py, PyAny>> {
+        let mut reader = std::io::Cursor::new(data);
+        let header = Self::read_header(&mut reader)?;
+        let memory = Self::read_memory(&header, reader)?;
+        Self::from_parts(cls, header, SnapshotMemory::Owned(memory))
+    }
+
+    /// Pickle support, a snapshot is pickled as its serialized bytes
+    fn __reduce__<'py>(
+        slf: &Bound<'py, Self>,
+    ) -> PyResult<(Bound<'py, PyAny>, (Bound<'py, pyo3::types::PyBytes>,))> {
+        let py = slf.py();
+        let from_bytes = slf.get_type().getattr("from_bytes")?;
+        Ok((from_bytes, (slf.borrow().to_bytes(py)?,)))
+    }
+}
+
+/// snapshot.guard(reactor) 返回的上下文管理器，离开代码块时恢复 reactor
+#[pyclass]
+pub struct PyBoxSnapshotGuard {
+    snapshot: Py<PyBoxReactorSnapshot>,
+    reactor: Py<PyBoxReactor>,
+    /// 只在代码块抛出异常时恢复
+    on_error: bool,
+    restored: bool,
+}
+
+#[pymethods]
+impl PyBoxSnapshotGuard {
+    /// Whether the reactor was restored when the block exited
+    #[getter]
+    fn restored(&self) -> bool {
+        self.restored
+    }
+
+    fn __enter__(&self, py: Python<'_>) -> Py<PyBoxReactor> {
+        self.reactor.clone_ref(py)
+    }
+
+    fn __exit__(
+        &mut self,
+        py: Python<'_>,
+        exc_type: Option<&Bound<'_, PyAny>>,
+        _exc_value: Option<&Bound<'_, PyAny>>,
+        _traceback: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<bool> {
+        if !self.on_error || exc_type.is_some() {
+            self.snapshot
+                .borrow(py)
+                .restore(py, &self.reactor.borrow(py))?;
+            self.restored = true;
+        }
+        Ok(false)
     }
 }
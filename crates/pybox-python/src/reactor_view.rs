@@ -0,0 +1,153 @@
+use std::os::raw::c_int;
+
+use crate::reactor::PyBoxReactor;
+use pyo3::prelude::*;
+
+/// guest 中 bytes / bytearray 变量的只读视图，直接指向 guest 线性内存
+/// 用法：
+///   with reactor.view(env_id, "data") as view:
+///       header = bytes(memoryview(view)[:16])
+///
+/// 视图存在期间 guest 保持变量的引用，bytearray 无法改变大小；
+/// 从视图导出的 memoryview 不能超过视图的生命周期，也不能跨越快照恢复
+#[pyclass]
+pub struct PyBoxView {
+    reactor: Py<PyBoxReactor>,
+    view_id: u32,
+    /// 数据在 guest 内存中的地址和长度
+    ptr: u32,
+    len: u32,
+    /// 尚未释放的 Python buffer 数量
+    exports: usize,
+    released: bool,
+}
+
+impl PyBoxView {
+    pub fn new(reactor: Py<PyBoxReactor>, view_id: u32, ptr: u32, len: u32) -> Self {
+        Self {
+            reactor,
+            view_id,
+            ptr,
+            len,
+            exports: 0,
+            released: false,
+        }
+    }
+
+    fn check_released(&self) -> PyResult<()> {
+        if self.released {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "operation forbidden on released PyBoxView",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[pymethods]
+impl PyBoxView {
+    fn __len__(&self) -> PyResult<usize> {
+        self.check_released()?;
+        Ok(self.len as usize)
+    }
+
+    /// 导出只读 buffer，每次导出时重新计算 host 地址
+    unsafe fn __getbuffer__(
+        mut slf: PyRefMut<'_, Self>,
+        view: *mut pyo3::ffi::Py_buffer,
+        flags: c_int,
+    ) -> PyResult<()> {
+        slf.check_released()?;
+        let py = slf.py();
+        let base = {
+            let reactor = slf.reactor.borrow(py);
+            reactor.safe_access(|| {
+                let store = reactor.memory_context()?;
+                let memory = reactor
+                    .core
+                    .as_ref()
+                    .and_then(|core| core.get_memory())
+                    .ok_or_else(|| {
+                        pyo3::exceptions::PyRuntimeError::new_err("Memory not available")
+                    })?;
+                if slf.ptr as usize + slf.len as usize > memory.data_size(&store) {
+                    return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                        "PyBoxView out of bounds",
+                    ));
+                }
+                Ok(memory.data_ptr(&store))
+            })?
+        };
+        let buf = unsafe { base.add(slf.ptr as usize) };
+        let result = unsafe {
+            pyo3::ffi::PyBuffer_FillInfo(
+                view,
+                slf.as_ptr(),
+                buf as *mut std::os::raw::c_void,
+                slf.len as pyo3::ffi::Py_ssize_t,
+                1,
+                flags,
+            )
+        };
+        if result == -1 {
+            return Err(PyErr::fetch(py));
+        }
+        slf.exports += 1;
+        slf.reactor.borrow(py).view_exported(true);
+        Ok(())
+    }
+
+    unsafe fn __releasebuffer__(mut slf: PyRefMut<'_, Self>, _view: *mut pyo3::ffi::Py_buffer) {
+        slf.exports -= 1;
+        if let Ok(reactor) = slf.reactor.try_borrow(slf.py()) {
+            reactor.view_exported(false);
+        }
+    }
+
+    /// 释放视图，guest 不再持有变量的引用
+    /// 仍有导出的 memoryview 时抛出 BufferError
+    fn release(&mut self, py: Python) -> PyResult<()> {
+        if self.released {
+            return Ok(());
+        }
+        if self.exports > 0 {
+            return Err(pyo3::exceptions::PyBufferError::new_err(
+                "cannot release PyBoxView: exported memoryviews exist",
+            ));
+        }
+        let reactor = self.reactor.borrow(py);
+        reactor.release_view(self.view_id)?;
+        self.released = true;
+        Ok(())
+    }
+
+    #[getter]
+    fn released(&self) -> bool {
+        self.released
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __exit__(
+        &mut self,
+        py: Python,
+        _exc_type: Option<&Bound<'_, PyAny>>,
+        _exc_value: Option<&Bound<'_, PyAny>>,
+        _traceback: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<()> {
+        self.release(py)
+    }
+}
+
+impl Drop for PyBoxView {
+    fn drop(&mut self) {
+        if !self.released {
+            // 其他线程正在使用 reactor 时无法通知 guest，变量会一直被 guest 引用
+            Python::attach(|py| {
+                let _ = self.reactor.borrow(py).release_view(self.view_id);
+            });
+        }
+    }
+}
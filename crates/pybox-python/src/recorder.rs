@@ -0,0 +1,189 @@
+//! recorder.rs 记录和回放 handler 的请求 / 响应
+//!
+//! 记录模式下每次最外层 exec 的代码以及 guest 发起的 handler 请求、流式响应的拉取请求
+//! 和对应的响应按顺序以 JSON Lines 追加到文件；回放模式下按记录的顺序返回响应，
+//! 不调用实际的 handler，请求与记录不一致时抛出 PyBoxReplayError
+//!
+//! 写入前替换已注册的密钥：代码和异常消息按文本替换，请求和响应只替换字面密钥，
+//! 回放时实时的请求同样替换后再与记录比较
+
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use pyo3::Python;
+use serde_json::{Value, json};
+
+use crate::builtin::{hex_decode, hex_encode};
+use crate::redact::SharedRedactor;
+
+/// 记录的 exec 输入：(环境 ID, 代码)
+pub type RecordedExec = (Option<String>, String);
+
+/// 记录的响应和是否分帧，handler 抛出异常时为异常消息
+pub type RecordedResponse = Result<(Vec<u8>, bool), String>;
+
+/// 记录的一次 handler 请求
+struct RecordedIoctl {
+    handle: u32,
+    request: Vec<u8>,
+    response: RecordedResponse,
+}
+
+enum RecorderMode {
+    Record(std::fs::File),
+    Replay(VecDeque<RecordedIoctl>),
+}
+
+#[derive(Default)]
+pub struct IoctlRecorder {
+    mode: Mutex<Option<RecorderMode>>,
+    redactor: SharedRedactor,
+}
+
+impl IoctlRecorder {
+    pub fn new(redactor: SharedRedactor) -> Self {
+        Self {
+            mode: Mutex::default(),
+            redactor,
+        }
+    }
+
+    /// 开始记录，记录追加到文件末尾
+    pub fn record(&self, path: &Path) -> Result<(), String> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        *self.mode.lock().unwrap() = Some(RecorderMode::Record(file));
+        Ok(())
+    }
+
+    /// 读取记录文件开始回放，返回按顺序记录的 exec 输入
+    pub fn replay(&self, path: &Path) -> Result<Vec<RecordedExec>, String> {
+        let error = |e: &dyn std::fmt::Display| format!("{}: {}", path.display(), e);
+        let file = std::fs::File::open(path).map_err(|e| error(&e))?;
+        let mut execs = Vec::new();
+        let mut ioctls = VecDeque::new();
+        for line in std::io::BufReader::new(file).lines() {
+            let line = line.map_err(|e| error(&e))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: Value = serde_json::from_str(&line).map_err(|e| error(&e))?;
+            let text = |key: &str| record[key].as_str().map(str::to_string);
+            match record["op"].as_str() {
+                Some("exec") => execs.push((text("env_id"), text("code").unwrap_or_default())),
+                Some("ioctl") => {
+                    let bytes = |key: &str| hex_decode(record[key].as_str().unwrap_or_default());
+                    let response = match text("error") {
+                        Some(message) => Err(message),
+                        None => Ok((
+                            bytes("response").map_err(|e| error(&e))?,
+                            record["framed"].as_bool().unwrap_or(false),
+                        )),
+                    };
+                    ioctls.push_back(RecordedIoctl {
+                        handle: record["handle"].as_u64().unwrap_or_default() as u32,
+                        request: bytes("request").map_err(|e| error(&e))?,
+                        response,
+                    });
+                }
+                _ => return Err(error(&format!("unknown record {}", line))),
+            }
+        }
+        *self.mode.lock().unwrap() = Some(RecorderMode::Replay(ioctls));
+        Ok(execs)
+    }
+
+    /// 停止记录或回放，返回之前是否已启用
+    pub fn clear(&self) -> bool {
+        self.mode.lock().unwrap().take().is_some()
+    }
+
+    pub fn replaying(&self) -> bool {
+        matches!(*self.mode.lock().unwrap(), Some(RecorderMode::Replay(_)))
+    }
+
+    /// 记录一次 exec 的输入
+    pub fn exec(&self, env_id: Option<&str>, code: &str) {
+        self.write(|| json!({ "op": "exec", "env_id": env_id, "code": code }));
+    }
+
+    /// 记录一次 handler 请求和写回的响应
+    pub fn ioctl(&self, handle: u32, request: &[u8], response: &[u8], framed: bool) {
+        self.write(|| {
+            let redactor = self.redactor.lock().unwrap();
+            json!({
+                "op": "ioctl",
+                "handle": handle,
+                "request": hex_encode(&redactor.redact_bytes(request)),
+                "response": hex_encode(&redactor.redact_bytes(response)),
+                "framed": framed,
+            })
+        });
+    }
+
+    /// 记录一次抛出异常的 handler 请求
+    pub fn ioctl_error(&self, handle: u32, request: &[u8], error: &str) {
+        self.write(|| {
+            let redactor = self.redactor.lock().unwrap();
+            json!({
+                "op": "ioctl",
+                "handle": handle,
+                "request": hex_encode(&redactor.redact_bytes(request)),
+                "error": error,
+            })
+        });
+    }
+
+    /// 取出下一条记录的响应，请求与记录不一致时返回 Err
+    pub fn next(&self, handle: u32, request: &[u8]) -> Result<RecordedResponse, String> {
+        let mut mode = self.mode.lock().unwrap();
+        let Some(RecorderMode::Replay(ioctls)) = &mut *mode else {
+            return Err("not replaying".to_string());
+        };
+        let Some(recorded) = ioctls.pop_front() else {
+            return Err(format!(
+                "Replay diverged: request to handle {} after the end of the recording",
+                handle
+            ));
+        };
+        let request = self
+            .redactor
+            .lock()
+            .unwrap()
+            .redact_bytes(request)
+            .into_owned();
+        if recorded.handle != handle || recorded.request != request {
+            return Err(format!(
+                "Replay diverged: request to handle {} does not match the recorded request to handle {}",
+                handle, recorded.handle
+            ));
+        }
+        Ok(recorded.response)
+    }
+
+    fn write(&self, record: impl FnOnce() -> Value) {
+        if !matches!(*self.mode.lock().unwrap(), Some(RecorderMode::Record(_))) {
+            return;
+        }
+        // 正则替换调用 Python，不持有记录的锁；替换失败时不写入，避免泄露密钥
+        let mut record = record();
+        let redacted = Python::attach(|py| {
+            let redactor = self.redactor.lock().unwrap().clone_ref(py);
+            redactor.redact_json(py, &mut record)
+        });
+        if let Err(e) = redacted {
+            eprintln!("Failed to redact replay record: {}", e);
+            return;
+        }
+        if let Some(RecorderMode::Record(file)) = &mut *self.mode.lock().unwrap()
+            && let Err(e) = writeln!(file, "{}", record)
+        {
+            eprintln!("Failed to write replay record: {}", e);
+        }
+    }
+}
@@ -0,0 +1,195 @@
+//! redact.rs 将输出中的密钥替换为 [REDACTED]
+//!
+//! host 注入给 guest 的凭据可能被代码打印出来，替换在 host 端进行，作用于 exec 结果的
+//! output / stdout / stderr / result_repr、异常的消息和 traceback、artifact、on_output 收到的实时输出，
+//! 以及审计日志、ioctl 记录和 history() 返回的操作。正则使用 host 的 Python re 模块
+
+use std::sync::{Arc, Mutex};
+
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyList, PyString, PyTuple};
+use serde_json::Value;
+
+pub const REDACTED: &str = "[REDACTED]";
+
+/// reactor、审计日志和 ioctl 记录共享的密钥表
+pub type SharedRedactor = Arc<Mutex<Redactor>>;
+
+/// 已注册的密钥和正则
+#[derive(Default)]
+pub struct Redactor {
+    /// 按长度从长到短排列，包含关系的密钥整体替换
+    secrets: Vec<String>,
+    /// 编译后的 re.Pattern
+    patterns: Vec<Py<PyAny>>,
+}
+
+impl Redactor {
+    pub fn is_empty(&self) -> bool {
+        self.secrets.is_empty() && self.patterns.is_empty()
+    }
+
+    pub fn add_secret(&mut self, secret: String) {
+        if !self.secrets.contains(&secret) {
+            self.secrets.push(secret);
+            self.secrets
+                .sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+        }
+    }
+
+    pub fn add_pattern(&mut self, pattern: Py<PyAny>) {
+        self.patterns.push(pattern);
+    }
+
+    pub fn clear(&mut self) {
+        self.secrets.clear();
+        self.patterns.clear();
+    }
+
+    /// 复制一份用于替换，替换时调用 Python，不持有 reactor 的锁
+    pub fn clone_ref(&self, py: Python<'_>) -> Self {
+        Self {
+            secrets: self.secrets.clone(),
+            patterns: self
+                .patterns
+                .iter()
+                .map(|pattern| pattern.clone_ref(py))
+                .collect(),
+        }
+    }
+
+    /// 替换一段文本中的密钥
+    pub fn redact(&self, py: Python<'_>, text: &str) -> PyResult<String> {
+        let mut text = text.to_string();
+        for secret in &self.secrets {
+            if text.contains(secret.as_str()) {
+                text = text.replace(secret.as_str(), REDACTED);
+            }
+        }
+        for pattern in &self.patterns {
+            text = pattern
+                .call_method1(py, "sub", (REDACTED, text))?
+                .extract(py)?;
+        }
+        Ok(text)
+    }
+
+    /// 就地替换
+    pub fn redact_in_place(&self, py: Python<'_>, text: &mut String) -> PyResult<()> {
+        if !self.is_empty() {
+            *text = self.redact(py, text)?;
+        }
+        Ok(())
+    }
+
+    /// 替换 JSON 中所有的字符串，用于写出的审计和 ioctl 记录
+    pub fn redact_json(&self, py: Python<'_>, value: &mut Value) -> PyResult<()> {
+        match value {
+            Value::String(text) => self.redact_in_place(py, text),
+            Value::Array(items) => items
+                .iter_mut()
+                .try_for_each(|item| self.redact_json(py, item)),
+            Value::Object(entries) => entries
+                .values_mut()
+                .try_for_each(|entry| self.redact_json(py, entry)),
+            _ => Ok(()),
+        }
+    }
+
+    /// 替换 Python 对象中的字符串，list / tuple / dict 逐项替换，其他对象原样返回
+    pub fn redact_object<'py>(
+        &self,
+        py: Python<'py>,
+        object: Bound<'py, PyAny>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        if self.is_empty() {
+            return Ok(object);
+        }
+        if let Ok(text) = object.cast::<PyString>() {
+            return Ok(PyString::new(py, &self.redact(py, &text.to_cow()?)?).into_any());
+        }
+        if let Ok(items) = object.cast::<PyList>() {
+            let redacted = PyList::empty(py);
+            for item in items.iter() {
+                redacted.append(self.redact_object(py, item)?)?;
+            }
+            return Ok(redacted.into_any());
+        }
+        if let Ok(items) = object.cast::<PyTuple>() {
+            let redacted = items
+                .iter()
+                .map(|item| self.redact_object(py, item))
+                .collect::<PyResult<Vec<_>>>()?;
+            return Ok(PyTuple::new(py, redacted)?.into_any());
+        }
+        if let Ok(entries) = object.cast::<PyDict>() {
+            let redacted = PyDict::new(py);
+            for (key, value) in entries.iter() {
+                redacted.set_item(self.redact_object(py, key)?, self.redact_object(py, value)?)?;
+            }
+            return Ok(redacted.into_any());
+        }
+        Ok(object)
+    }
+
+    /// 替换异常的消息 (args 中的字符串) 和 guest_traceback
+    pub fn redact_exception(&self, py: Python<'_>, exception: &Bound<'_, PyAny>) -> PyResult<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let args = exception.getattr("args")?;
+        exception.setattr("args", self.redact_object(py, args)?)?;
+        if let Ok(traceback) = exception.getattr("guest_traceback") {
+            exception.setattr("guest_traceback", self.redact_object(py, traceback)?)?;
+        }
+        Ok(())
+    }
+
+    /// 替换二进制数据中的字面密钥，没有密钥出现时借用原数据
+    pub fn redact_bytes<'a>(&self, data: &'a [u8]) -> std::borrow::Cow<'a, [u8]> {
+        match self.replace_bytes(data) {
+            Some(redacted) => std::borrow::Cow::Owned(redacted),
+            None => std::borrow::Cow::Borrowed(data),
+        }
+    }
+
+    /// 替换二进制数据中的密钥，正则只作用于文本
+    fn replace_bytes(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let mut data = data.to_vec();
+        let mut changed = false;
+        for secret in self.secrets.iter().map(String::as_bytes) {
+            let mut redacted = Vec::with_capacity(data.len());
+            let mut rest = data.as_slice();
+            while let Some(index) = rest.windows(secret.len()).position(|w| w == secret) {
+                redacted.extend_from_slice(&rest[..index]);
+                redacted.extend_from_slice(REDACTED.as_bytes());
+                rest = &rest[index + secret.len()..];
+                changed = true;
+            }
+            redacted.extend_from_slice(rest);
+            data = redacted;
+        }
+        changed.then_some(data)
+    }
+
+    /// 替换 artifact 的数据，文本数据按文本处理，bytes 只替换密钥
+    pub fn redact_artifacts(&self, py: Python<'_>, artifacts: &Bound<'_, PyList>) -> PyResult<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        for artifact in artifacts.iter() {
+            let artifact = artifact.cast::<PyDict>()?;
+            let Some(data) = artifact.get_item("data")? else {
+                continue;
+            };
+            if let Ok(text) = data.cast::<PyString>() {
+                artifact.set_item("data", self.redact(py, &text.to_cow()?)?)?;
+            } else if let Ok(bytes) = data.cast::<PyBytes>()
+                && let Some(redacted) = self.replace_bytes(bytes.as_bytes())
+            {
+                artifact.set_item("data", PyBytes::new(py, &redacted))?;
+            }
+        }
+        Ok(())
+    }
+}
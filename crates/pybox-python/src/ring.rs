@@ -0,0 +1,108 @@
+//! ring.rs shared-memory ring buffer between host and guest
+//!
+//! 与 guest 端 pybox-reactor/src/ring.rs 使用相同的内存布局（小端 u32）：
+//! `capacity | head | tail | data[capacity]`，head / tail 为累计写入 / 读取的字节数，
+//! 每条消息为 `u32 长度 + 数据`。每个方向只允许一个写者和一个读者
+
+/// 头部大小：capacity + head + tail
+pub const RING_HEADER_SIZE: usize = 12;
+
+pub struct Ring<'a> {
+    mem: &'a mut [u8],
+}
+
+impl<'a> Ring<'a> {
+    /// mem 从环形缓冲区头部开始，长度至少为头部 + 数据区
+    pub fn new(mem: &'a mut [u8]) -> Result<Self, String> {
+        if mem.len() < RING_HEADER_SIZE {
+            return Err("Ring buffer out of bounds".to_string());
+        }
+        let capacity = u32::from_le_bytes(mem[..4].try_into().unwrap()) as usize;
+        let mem = mem
+            .get_mut(..RING_HEADER_SIZE + capacity)
+            .ok_or("Ring buffer out of bounds")?;
+        Ok(Self { mem })
+    }
+
+    /// 初始化一个空的环形缓冲区
+    pub fn init(mem: &'a mut [u8], capacity: u32) -> Result<Self, String> {
+        let header = mem
+            .get_mut(..RING_HEADER_SIZE)
+            .ok_or("Ring buffer out of bounds")?;
+        header.fill(0);
+        header[..4].copy_from_slice(&capacity.to_le_bytes());
+        Self::new(mem)
+    }
+
+    fn load(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.mem[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn store(&mut self, offset: usize, value: u32) {
+        self.mem[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.load(0)
+    }
+
+    /// 已写入未读取的字节数
+    pub fn used(&self) -> u32 {
+        self.load(4).wrapping_sub(self.load(8))
+    }
+
+    fn copy_in(&mut self, pos: u32, data: &[u8]) {
+        let capacity = self.capacity() as usize;
+        let start = pos as usize % capacity;
+        let first = data.len().min(capacity - start);
+        let base = RING_HEADER_SIZE;
+        self.mem[base + start..base + start + first].copy_from_slice(&data[..first]);
+        self.mem[base..base + data.len() - first].copy_from_slice(&data[first..]);
+    }
+
+    fn copy_out(&self, pos: u32, data: &mut [u8]) {
+        let capacity = self.capacity() as usize;
+        let start = pos as usize % capacity;
+        let first = data.len().min(capacity - start);
+        let base = RING_HEADER_SIZE;
+        let len = data.len();
+        data[..first].copy_from_slice(&self.mem[base + start..base + start + first]);
+        data[first..].copy_from_slice(&self.mem[base..base + len - first]);
+    }
+
+    /// 写入一条消息，空间不足时返回 false
+    pub fn write(&mut self, data: &[u8]) -> bool {
+        let needed = 4 + data.len() as u64;
+        if needed > self.capacity().saturating_sub(self.used()) as u64 {
+            return false;
+        }
+        let head = self.load(4);
+        self.copy_in(head, &(data.len() as u32).to_le_bytes());
+        self.copy_in(head.wrapping_add(4), data);
+        self.store(4, head.wrapping_add(needed as u32));
+        true
+    }
+
+    /// 读取一条消息，没有消息时返回 None
+    /// guest 可以改写头部，长度不合法时返回错误而不是越界读取
+    pub fn read(&mut self) -> Result<Option<Vec<u8>>, String> {
+        let used = self.used();
+        if used > self.capacity() {
+            return Err("Ring buffer corrupted".to_string());
+        }
+        if used < 4 {
+            return Ok(None);
+        }
+        let tail = self.load(8);
+        let mut len = [0u8; 4];
+        self.copy_out(tail, &mut len);
+        let len = u32::from_le_bytes(len);
+        if len as u64 + 4 > used as u64 {
+            return Err("Ring buffer corrupted".to_string());
+        }
+        let mut data = vec![0u8; len as usize];
+        self.copy_out(tail.wrapping_add(4), &mut data);
+        self.store(8, tail.wrapping_add(4 + len));
+        Ok(Some(data))
+    }
+}
@@ -0,0 +1,281 @@
+//! sandbox_tool.rs 把 reactor 的一个环境包装为 LLM 框架的工具
+//!
+//! 模型给出代码，工具执行后返回 JSON 格式的观察结果 `{ok, stdout, stderr, result, error, ...}`；
+//! policy 回调 `(code) -> code` 在执行前检查或改写代码，抛出异常时拒绝执行
+
+use std::sync::Mutex;
+
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList, PyString};
+
+use crate::exec_result::PyBoxExecResult;
+use crate::reactor::PyBoxReactor;
+
+const DEFAULT_DESCRIPTION: &str = "Run Python code in a persistent sandbox and return its output. \
+Variables, functions and imports are kept between calls. Use print() to see values; \
+the repr of a trailing expression is returned as `result`.";
+
+/// OpenAI / LangChain 风格的代码执行工具
+#[pyclass]
+pub struct PyBoxSandboxTool {
+    reactor: Py<PyBoxReactor>,
+    env_id: String,
+    name: String,
+    description: String,
+    max_output: Option<usize>,
+    max_artifacts: Option<usize>,
+    policies: Mutex<Vec<Py<PyAny>>>,
+}
+
+impl PyBoxSandboxTool {
+    /// 依次调用 policy，返回最终执行的代码
+    fn apply_policies(&self, py: Python<'_>, code: &str) -> PyResult<String> {
+        let policies: Vec<Py<PyAny>> = self
+            .policies
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|policy| policy.clone_ref(py))
+            .collect();
+        let mut code = code.to_string();
+        for policy in policies {
+            let ret = policy.call1(py, (&code,))?;
+            if !ret.is_none(py) {
+                code = ret.extract(py)?;
+            }
+        }
+        Ok(code)
+    }
+
+    /// 异常的类型、消息和 guest 的 traceback
+    fn error<'py>(py: Python<'py>, exception: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyDict>> {
+        let type_name = match exception.getattr("guest_type") {
+            Ok(guest_type) => guest_type.extract::<String>()?,
+            Err(_) => exception.get_type().name()?.to_string(),
+        };
+        let error = PyDict::new(py);
+        error.set_item("type", type_name)?;
+        error.set_item("message", exception.str()?)?;
+        error.set_item("traceback", exception.getattr("guest_traceback").ok())?;
+        Ok(error)
+    }
+
+    /// 没有执行结果时的观察结果
+    fn empty_observation<'py>(
+        py: Python<'py>,
+        stdout: Bound<'py, PyAny>,
+        error: Option<Bound<'py, PyDict>>,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let observation = PyDict::new(py);
+        observation.set_item("ok", error.is_none())?;
+        observation.set_item("stdout", stdout)?;
+        observation.set_item("stderr", "")?;
+        observation.set_item("result", py.None())?;
+        observation.set_item("error", error)?;
+        observation.set_item("artifacts", PyList::empty(py))?;
+        observation.set_item("truncated", false)?;
+        observation.set_item("timed_out", false)?;
+        Ok(observation)
+    }
+
+    fn observation<'py>(
+        py: Python<'py>,
+        result: &Bound<'py, PyAny>,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        // after 中间件可以把结果替换为其他对象，按输出处理
+        let Ok(result) = result.cast::<PyBoxExecResult>() else {
+            return Self::empty_observation(py, result.str()?.into_any(), None);
+        };
+        let result = result.borrow();
+        let error = match &result.exception {
+            Some(exception) => Some(Self::error(py, exception.bind(py))?),
+            None => None,
+        };
+        let artifacts = PyList::empty(py);
+        for artifact in result.artifacts.bind(py).iter() {
+            let summary = PyDict::new(py);
+            summary.set_item("name", artifact.get_item("name")?)?;
+            summary.set_item("mime", artifact.get_item("mime")?)?;
+            artifacts.append(summary)?;
+        }
+        let observation = PyDict::new(py);
+        observation.set_item("ok", error.is_none() && !result.timed_out)?;
+        observation.set_item("stdout", &result.stdout)?;
+        observation.set_item("stderr", &result.stderr)?;
+        observation.set_item("result", &result.result_repr)?;
+        observation.set_item("error", error)?;
+        observation.set_item("artifacts", artifacts)?;
+        observation.set_item("truncated", result.truncated)?;
+        observation.set_item("timed_out", result.timed_out)?;
+        Ok(observation)
+    }
+}
+
+#[pymethods]
+impl PyBoxSandboxTool {
+    /// Wrap an environment of a reactor as a code execution tool
+    ///
+    /// Args:
+    ///     reactor: PyBoxReactor (or PyBox) running the code
+    ///     env_id: Environment the code runs in, created when it does not exist
+    ///     name: Tool name shown to the model
+    ///     description: Tool description shown to the model
+    ///     timeout: Enables the reactor watchdog with this deadline in seconds,
+    ///         a timed out call restores the reactor to its state at this point
+    ///     max_output: Maximum bytes of captured output per call
+    ///     max_artifacts: Maximum number of artifacts per call
+    ///     policies: Callables `(code) -> str | None` checked before every call,
+    ///         see add_policy
+    #[new]
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (reactor, env_id, name="python".to_string(), description=None, timeout=None, max_output=None, max_artifacts=None, policies=None))]
+    fn new(
+        py: Python<'_>,
+        reactor: Py<PyBoxReactor>,
+        env_id: String,
+        name: String,
+        description: Option<String>,
+        timeout: Option<f64>,
+        max_output: Option<usize>,
+        max_artifacts: Option<usize>,
+        policies: Option<Vec<Py<PyAny>>>,
+    ) -> PyResult<Self> {
+        let bound = reactor.bind(py);
+        // 环境已存在时 init_local 返回 False
+        bound.call_method1("init_local", (&env_id,))?;
+        if timeout.is_some() {
+            bound.call_method1("set_watchdog", (timeout,))?;
+        }
+        Ok(Self {
+            reactor,
+            env_id,
+            name,
+            description: description.unwrap_or_else(|| DEFAULT_DESCRIPTION.to_string()),
+            max_output,
+            max_artifacts,
+            policies: Mutex::new(policies.unwrap_or_default()),
+        })
+    }
+
+    #[getter]
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[getter]
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    #[getter]
+    fn env_id(&self) -> &str {
+        &self.env_id
+    }
+
+    /// Add a policy checked before every call
+    ///
+    /// The policy is called with the code and returns None to keep it or a str
+    /// to run instead. An exception raised by it rejects the call, the model
+    /// sees its type and message as the error of the observation.
+    fn add_policy(&self, policy: Py<PyAny>) {
+        self.policies.lock().unwrap().push(policy);
+    }
+
+    /// Remove a policy
+    ///
+    /// Returns:
+    ///     bool: Whether the policy was added before
+    fn remove_policy(&self, py: Python<'_>, policy: &Bound<'_, PyAny>) -> bool {
+        let mut policies = self.policies.lock().unwrap();
+        let count = policies.len();
+        policies.retain(|added| !added.bind(py).is(policy));
+        policies.len() != count
+    }
+
+    /// Run code and return the observation
+    ///
+    /// Returns:
+    ///     dict: ok, stdout, stderr, result (repr of a trailing expression),
+    ///         error ({type, message, traceback} or None), artifacts (name and
+    ///         mime of each), truncated and timed_out
+    fn run<'py>(&self, py: Python<'py>, code: &str) -> PyResult<Bound<'py, PyDict>> {
+        let code = match self.apply_policies(py, code) {
+            Ok(code) => code,
+            Err(err) => {
+                let error = Self::error(py, err.value(py).as_any())?;
+                let stdout = PyString::new(py, "").into_any();
+                return Self::empty_observation(py, stdout, Some(error));
+            }
+        };
+        let kwargs = PyDict::new(py);
+        kwargs.set_item("max_output", self.max_output)?;
+        kwargs.set_item("max_artifacts", self.max_artifacts)?;
+        let result =
+            self.reactor
+                .bind(py)
+                .call_method("exec", (code, &self.env_id), Some(&kwargs))?;
+        Self::observation(py, &result)
+    }
+
+    fn __call__<'py>(&self, py: Python<'py>, code: &str) -> PyResult<Bound<'py, PyDict>> {
+        self.run(py, code)
+    }
+
+    /// Run a tool call and return the observation as a JSON string
+    ///
+    /// Args:
+    ///     arguments: The arguments of an OpenAI tool call, a dict or its JSON
+    ///         string with a "code" key, or the code itself as LangChain passes it
+    fn invoke(&self, py: Python<'_>, arguments: &Bound<'_, PyAny>) -> PyResult<String> {
+        let json = py.import("json")?;
+        let mut arguments = arguments.clone();
+        if let Ok(text) = arguments.extract::<String>()
+            && let Ok(parsed) = json.getattr("loads")?.call1((&text,))
+            && parsed
+                .cast::<PyDict>()
+                .is_ok_and(|parsed| parsed.contains("code").unwrap_or(false))
+        {
+            arguments = parsed;
+        }
+        let code: String = match arguments.cast::<PyDict>() {
+            Ok(arguments) => arguments
+                .get_item("code")?
+                .ok_or_else(|| pyo3::exceptions::PyKeyError::new_err("code"))?
+                .extract()?,
+            Err(_) => arguments.extract()?,
+        };
+        let observation = self.run(py, &code)?;
+        json.getattr("dumps")?.call1((observation,))?.extract()
+    }
+
+    /// The tool definition in the OpenAI function calling format
+    ///
+    /// Returns:
+    ///     dict: {"type": "function", "function": {name, description, parameters}}
+    fn schema<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let code = PyDict::new(py);
+        code.set_item("type", "string")?;
+        code.set_item("description", "Python code to run")?;
+        let properties = PyDict::new(py);
+        properties.set_item("code", code)?;
+        let parameters = PyDict::new(py);
+        parameters.set_item("type", "object")?;
+        parameters.set_item("properties", properties)?;
+        parameters.set_item("required", vec!["code"])?;
+        let function = PyDict::new(py);
+        function.set_item("name", &self.name)?;
+        function.set_item("description", &self.description)?;
+        function.set_item("parameters", parameters)?;
+        let schema = PyDict::new(py);
+        schema.set_item("type", "function")?;
+        schema.set_item("function", function)?;
+        Ok(schema)
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "PyBoxSandboxTool(name={:?}, env_id={:?})",
+            self.name, self.env_id
+        )
+    }
+}
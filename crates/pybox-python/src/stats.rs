@@ -0,0 +1,158 @@
+//! stats.rs 执行统计
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use pyo3::prelude::*;
+
+/// 一次 exec 的统计，即 `exec(...).stats`
+#[pyclass(get_all, module = "pybox.pyboxcore")]
+#[derive(Default)]
+pub struct PyBoxExecStats {
+    /// host 端耗时（秒）
+    pub wall_time: f64,
+    /// guest 端编译耗时（秒），wasm 不支持时为 None
+    pub compile_time: Option<f64>,
+    /// guest 端运行耗时（秒），wasm 不支持时为 None
+    pub run_time: Option<f64>,
+    /// 消耗的 fuel，引擎没有打开 fuel 计量时为 0
+    pub fuel_consumed: u64,
+    /// 增长的内存页数（64 KiB/页）
+    pub memory_pages_grown: u64,
+    /// 结束时的内存页数，wasm 内存不会收缩，即内存的高水位
+    pub memory_pages: u64,
+    /// guest 发起的 ioctl 调用次数（包括嵌套调用）
+    pub ioctl_calls: u64,
+}
+
+/// pickle 时的字段，顺序与 `_from_pickle` 的参数相同
+type StatsFields = (f64, Option<f64>, Option<f64>, u64, u64, u64, u64);
+
+#[pymethods]
+impl PyBoxExecStats {
+    /// Pickle support, so the stats can be sent to other processes
+    fn __reduce__<'py>(slf: &Bound<'py, Self>) -> PyResult<(Bound<'py, PyAny>, (StatsFields,))> {
+        let stats = slf.borrow();
+        Ok((
+            slf.get_type().getattr("_from_pickle")?,
+            ((
+                stats.wall_time,
+                stats.compile_time,
+                stats.run_time,
+                stats.fuel_consumed,
+                stats.memory_pages_grown,
+                stats.memory_pages,
+                stats.ioctl_calls,
+            ),),
+        ))
+    }
+
+    #[classmethod]
+    fn _from_pickle(_cls: &Bound<'_, pyo3::types::PyType>, fields: StatsFields) -> Self {
+        let (
+            wall_time,
+            compile_time,
+            run_time,
+            fuel_consumed,
+            memory_pages_grown,
+            memory_pages,
+            ioctl_calls,
+        ) = fields;
+        Self {
+            wall_time,
+            compile_time,
+            run_time,
+            fuel_consumed,
+            memory_pages_grown,
+            memory_pages,
+            ioctl_calls,
+        }
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "PyBoxExecStats(wall_time={:.6}, compile_time={}, run_time={}, fuel_consumed={}, \
+             memory_pages_grown={}, memory_pages={}, ioctl_calls={})",
+            self.wall_time,
+            self.compile_time
+                .map_or("None".to_string(), |t| format!("{:.6}", t)),
+            self.run_time
+                .map_or("None".to_string(), |t| format!("{:.6}", t)),
+            self.fuel_consumed,
+            self.memory_pages_grown,
+            self.memory_pages,
+            self.ioctl_calls,
+        )
+    }
+}
+
+/// reactor 创建以来累计的计数，即 `reactor.stats()`
+///
+/// 计数只使用原子操作和 DashMap，其他线程读取时不需要获得 reactor 的访问权
+pub struct ReactorCounters {
+    started: std::time::Instant,
+    /// 进入 guest 的 exec 次数，不包括被 lint 阻止或命中缓存的 exec
+    pub execs: AtomicU64,
+    /// 代码抛出未捕获异常或超时的 exec
+    pub failures: AtomicU64,
+    /// 超时被终止的 exec
+    pub timeouts: AtomicU64,
+    /// 没有返回结果、以错误结束的 exec，如 guest trap、fuel 耗尽
+    pub traps: AtomicU64,
+    /// exec 在 guest 中的累计耗时（纳秒）
+    pub guest_time_ns: AtomicU64,
+    /// exec 累计消耗的 fuel
+    pub fuel_consumed: AtomicU64,
+    /// 最近一次 exec 结束时的内存大小（字节）
+    pub memory_bytes: AtomicU64,
+    /// handle -> 调用次数，只统计注册的 handler
+    handler_calls: dashmap::DashMap<u32, u64>,
+}
+
+impl Default for ReactorCounters {
+    fn default() -> Self {
+        Self {
+            started: std::time::Instant::now(),
+            execs: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+            traps: AtomicU64::new(0),
+            guest_time_ns: AtomicU64::new(0),
+            fuel_consumed: AtomicU64::new(0),
+            memory_bytes: AtomicU64::new(0),
+            handler_calls: dashmap::DashMap::new(),
+        }
+    }
+}
+
+impl ReactorCounters {
+    pub fn handler_call(&self, handle: u32) {
+        *self.handler_calls.entry(handle).or_default() += 1;
+    }
+
+    /// 转换为 dict，handler 的调用次数以 name(handle) 为键
+    pub fn to_dict<'py>(
+        &self,
+        py: Python<'py>,
+        ioctl_calls: u64,
+        name: impl Fn(u32) -> Option<String>,
+    ) -> PyResult<Bound<'py, pyo3::types::PyDict>> {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let stats = pyo3::types::PyDict::new(py);
+        stats.set_item("uptime", self.started.elapsed().as_secs_f64())?;
+        stats.set_item("execs", load(&self.execs))?;
+        stats.set_item("failures", load(&self.failures))?;
+        stats.set_item("timeouts", load(&self.timeouts))?;
+        stats.set_item("traps", load(&self.traps))?;
+        stats.set_item("guest_time", load(&self.guest_time_ns) as f64 / 1e9)?;
+        stats.set_item("fuel_consumed", load(&self.fuel_consumed))?;
+        stats.set_item("ioctl_calls", ioctl_calls)?;
+        let handler_calls = pyo3::types::PyDict::new(py);
+        for entry in self.handler_calls.iter() {
+            let key = name(*entry.key()).unwrap_or_else(|| entry.key().to_string());
+            handler_calls.set_item(key, *entry.value())?;
+        }
+        stats.set_item("handler_calls", handler_calls)?;
+        stats.set_item("memory_bytes", load(&self.memory_bytes))?;
+        Ok(stats)
+    }
+}
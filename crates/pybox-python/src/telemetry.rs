@@ -0,0 +1,86 @@
+//! telemetry.rs 可选的 OpenTelemetry 集成
+//!
+//! span 通过 Python 端的 opentelemetry tracer 创建，会挂在调用方当前的 trace 上，
+//! 未启用时不会导入 opentelemetry，也没有额外开销
+
+use std::sync::Mutex;
+
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+/// 当前使用的 tracer，None 表示未启用
+#[derive(Default)]
+pub struct Tracer {
+    tracer: Mutex<Option<Py<PyAny>>>,
+}
+
+/// 一个进行中的 span，对应 `tracer.start_as_current_span(...)` 的上下文
+pub struct Span {
+    context: Py<PyAny>,
+    span: Py<PyAny>,
+}
+
+impl Tracer {
+    /// 替换 tracer，返回之前是否已启用
+    pub fn set(&self, tracer: Option<Py<PyAny>>) -> bool {
+        std::mem::replace(&mut *self.tracer.lock().unwrap(), tracer).is_some()
+    }
+
+    /// 开始一个 span 并设为当前 span，未启用或 tracer 出错时返回 None
+    /// attributes 只在启用时调用，用于填充 span 的初始属性
+    pub fn start(
+        &self,
+        py: Python<'_>,
+        name: &str,
+        attributes: impl FnOnce(&Bound<'_, PyDict>) -> PyResult<()>,
+    ) -> Option<Span> {
+        let tracer = self.tracer.lock().unwrap().as_ref()?.clone_ref(py);
+        let start = || -> PyResult<Span> {
+            let attrs = PyDict::new(py);
+            attributes(&attrs)?;
+            let kwargs = PyDict::new(py);
+            kwargs.set_item("attributes", attrs)?;
+            let context =
+                tracer
+                    .bind(py)
+                    .call_method("start_as_current_span", (name,), Some(&kwargs))?;
+            let span = context.call_method0("__enter__")?;
+            Ok(Span {
+                context: context.unbind(),
+                span: span.unbind(),
+            })
+        };
+        // 追踪失败不应影响执行
+        start().ok()
+    }
+}
+
+impl Span {
+    pub fn set_attribute<'py>(&self, py: Python<'py>, key: &str, value: impl IntoPyObject<'py>) {
+        let _ = self
+            .span
+            .bind(py)
+            .call_method1("set_attribute", (key, value));
+    }
+
+    /// 结束 span，出错时异常交给上下文记录并将 span 标记为错误
+    pub fn end(self, py: Python<'_>, error: Option<&PyErr>) {
+        let context = self.context.bind(py);
+        let _ = match error {
+            Some(error) => context.call_method1(
+                "__exit__",
+                (error.get_type(py), error.value(py), error.traceback(py)),
+            ),
+            None => context.call_method1("__exit__", (py.None(), py.None(), py.None())),
+        };
+    }
+}
+
+/// 代码的 sha256 前 16 位十六进制，用于在 trace 中区分代码而不记录代码本身
+pub fn code_hash(py: Python<'_>, code: &str) -> PyResult<String> {
+    py.import("hashlib")?
+        .call_method1("sha256", (code.as_bytes(),))?
+        .call_method0("hexdigest")?
+        .extract::<String>()
+        .map(|digest| digest[..16].to_string())
+}
@@ -0,0 +1,111 @@
+//! watchdog.rs 超时的 exec 被强制终止，并从最近一次正常状态的内存快照恢复
+//!
+//! 超时由 epoch 中断检测，终止时 guest 的状态（例如正在使用的 RefCell）可能已经损坏，
+//! 恢复内存和导出的全局变量（shadow stack 指针）、表后 reactor 回到快照时的状态
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::instance_state::InstanceState;
+
+struct WatchdogConfig {
+    timeout: Duration,
+    /// true 时每次 exec 成功后更新快照
+    checkpoint: bool,
+    /// 最近一次正常状态的内存
+    snapshot: Vec<u8>,
+    /// 最近一次正常状态的全局变量和表
+    state: InstanceState,
+}
+
+#[derive(Default)]
+pub struct Watchdog {
+    config: Mutex<Option<WatchdogConfig>>,
+    /// 正在执行的最外层 exec 的截止时间
+    deadline: Mutex<Option<Instant>>,
+}
+
+impl Watchdog {
+    /// 启用或关闭 watchdog，返回之前是否已启用
+    pub fn set(
+        &self,
+        timeout: Option<Duration>,
+        checkpoint: bool,
+        snapshot: Vec<u8>,
+        state: InstanceState,
+    ) -> bool {
+        let config = timeout.map(|timeout| WatchdogConfig {
+            timeout,
+            checkpoint,
+            snapshot,
+            state,
+        });
+        std::mem::replace(&mut *self.config.lock().unwrap(), config).is_some()
+    }
+
+    /// 最外层 exec 开始执行
+    pub fn start(&self) {
+        let timeout = self
+            .config
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|config| config.timeout);
+        *self.deadline.lock().unwrap() = timeout.map(|timeout| Instant::now() + timeout);
+    }
+
+    pub fn finish(&self) {
+        self.deadline.lock().unwrap().take();
+    }
+
+    /// 正在执行的 exec 已经超时时返回超时时间
+    pub fn expired(&self) -> Option<Duration> {
+        let deadline = (*self.deadline.lock().unwrap())?;
+        if Instant::now() < deadline {
+            return None;
+        }
+        self.config
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|config| config.timeout)
+    }
+
+    /// 是否在每次 exec 成功后更新快照
+    pub fn checkpointing(&self) -> bool {
+        self.config
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|config| config.checkpoint)
+    }
+
+    /// exec 正常结束，需要时将当前状态记为最近一次正常状态
+    pub fn checkpoint(&self, memory: &[u8], state: InstanceState) {
+        if let Some(config) = self.config.lock().unwrap().as_mut()
+            && config.checkpoint
+        {
+            config.snapshot.clear();
+            config.snapshot.extend_from_slice(memory);
+            config.state = state;
+        }
+    }
+
+    /// 重新实例化后旧的快照不再有效，以新的状态作为快照
+    pub fn rebase(&self, memory: &[u8], state: InstanceState) {
+        if let Some(config) = self.config.lock().unwrap().as_mut() {
+            config.snapshot.clear();
+            config.snapshot.extend_from_slice(memory);
+            config.state = state;
+        }
+    }
+
+    /// 将内存恢复到快照，返回需要由调用者恢复的全局变量和表，未启用时返回 None
+    pub fn restore(&self, memory: &mut [u8]) -> Option<InstanceState> {
+        let config = self.config.lock().unwrap();
+        let config = config.as_ref()?;
+        let len = memory.len().min(config.snapshot.len());
+        memory[..len].copy_from_slice(&config.snapshot[..len]);
+        Some(config.state.clone())
+    }
+}
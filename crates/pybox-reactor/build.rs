@@ -0,0 +1,75 @@
+//! 导出 shadow stack 指针和函数表，host 的快照除了线性内存还需要保存和恢复它们；
+//! 设置 PYBOX_BUNDLE 时把该目录中的 python 模块打包进 wasm，见 src/bundle.rs
+
+use std::path::{Path, PathBuf};
+
+fn main() {
+    if std::env::var("CARGO_CFG_TARGET_ARCH").as_deref() == Ok("wasm32") {
+        println!("cargo:rustc-link-arg=--export=__stack_pointer");
+        println!("cargo:rustc-link-arg=--export-table");
+    }
+    bundle();
+}
+
+/// 生成 $OUT_DIR/bundle.rs：按模块名排序的 (模块名, 源码, 是否为包)
+fn bundle() {
+    println!("cargo:rerun-if-env-changed=PYBOX_BUNDLE");
+    let mut modules = Vec::new();
+    if let Some(dir) = std::env::var_os("PYBOX_BUNDLE") {
+        let dir = Path::new(&dir)
+            .canonicalize()
+            .expect("PYBOX_BUNDLE is not a directory");
+        println!("cargo:rerun-if-changed={}", dir.display());
+        collect(&dir, "", &mut modules);
+    }
+    modules.sort();
+
+    let mut code = String::from("pub static BUNDLE: &[(&str, &str, bool)] = &[\n");
+    for (name, path, package) in modules {
+        code += &format!(
+            "    ({:?}, include_str!({:?}), {}),\n",
+            name,
+            path.display().to_string(),
+            package
+        );
+    }
+    code += "];\n";
+    let out = PathBuf::from(std::env::var_os("OUT_DIR").unwrap()).join("bundle.rs");
+    std::fs::write(out, code).expect("Failed to write bundle.rs");
+}
+
+/// 收集 dir 中的模块，只进入含 `__init__.py` 的子目录
+fn collect(dir: &Path, prefix: &str, modules: &mut Vec<(String, PathBuf, bool)>) {
+    let entries = std::fs::read_dir(dir).expect("Failed to read the bundle directory");
+    for entry in entries {
+        let path = entry.expect("Failed to read the bundle directory").path();
+        let is_dir = path.is_dir();
+        // 目录按完整名称，文件去掉扩展名
+        let stem = match is_dir {
+            true => path.file_name(),
+            false => path.file_stem(),
+        };
+        let Some(stem) = stem.and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let name = format!("{}{}", prefix, stem);
+        if is_dir {
+            let init = path.join("__init__.py");
+            if is_identifier(stem) && init.is_file() {
+                modules.push((name.clone(), init, true));
+                collect(&path, &format!("{}.", name), modules);
+            }
+        } else if path.extension().is_some_and(|ext| ext == "py")
+            && is_identifier(stem)
+            && stem != "__init__"
+        {
+            modules.push((name, path, false));
+        }
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
@@ -0,0 +1,68 @@
+"""static analysis helpers run by the analysis interpreter, see analysis.rs
+
+nothing here executes the analysed code, it is only parsed with the sandbox's own parser
+"""
+
+import ast
+
+_POSITIONS = ("lineno", "col_offset", "end_lineno", "end_col_offset")
+
+
+def _node(value):
+    """convert an AST node to json-compatible dicts, `_type` holds the node class"""
+    if isinstance(value, ast.AST):
+        result = {"_type": type(value).__name__}
+        for field, item in ast.iter_fields(value):
+            result[field] = _node(item)
+        for position in _POSITIONS:
+            if hasattr(value, position):
+                result[position] = getattr(value, position)
+        return result
+    if isinstance(value, list):
+        return [_node(item) for item in value]
+    if value is None or isinstance(value, (str, int, float)):
+        return value
+    # bytes, complex and Ellipsis constants
+    return repr(value)
+
+
+def _dotted(node):
+    """`a.b.c` for names and attribute chains, None for anything else"""
+    if isinstance(node, ast.Name):
+        return node.id
+    if isinstance(node, ast.Attribute):
+        base = _dotted(node.value)
+        return None if base is None else f"{base}.{node.attr}"
+    return None
+
+
+def dump(code):
+    """parse code into the tree and summaries of the names, imports and calls it uses"""
+    tree = ast.parse(code)
+    names = set()
+    imports = []
+    calls = []
+    for node in ast.walk(tree):
+        if isinstance(node, ast.Name):
+            names.add(node.id)
+        elif isinstance(node, ast.Import):
+            for alias in node.names:
+                imports.append({"module": alias.name, "names": [], "line": node.lineno})
+        elif isinstance(node, ast.ImportFrom):
+            module = "." * (node.level or 0) + (node.module or "")
+            imports.append({
+                "module": module,
+                "names": [alias.name for alias in node.names],
+                "line": node.lineno,
+            })
+        elif isinstance(node, ast.Call):
+            calls.append({"target": _dotted(node.func), "line": node.lineno})
+    # ast.walk is breadth-first
+    imports.sort(key=lambda item: item["line"])
+    calls.sort(key=lambda item: item["line"])
+    return {
+        "tree": _node(tree),
+        "names": sorted(names),
+        "imports": imports,
+        "calls": calls,
+    }
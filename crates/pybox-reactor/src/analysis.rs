@@ -0,0 +1,238 @@
+//! analysis.rs 不执行代码的静态分析，使用与 pybox_exec 相同的编译器
+//!
+//! 分析不依赖局部环境，使用一个按需创建的独立解释器，不影响任何环境的状态；
+//! pybox_ast 的 python 部分在 analysis.py 中
+
+use std::cell::OnceCell;
+use std::rc::Rc;
+
+use libc::ssize_t;
+use rustpython_vm::{
+    AsObject, Interpreter, PyObjectRef, PyResult, VirtualMachine, builtins::PyBaseExceptionRef,
+    compiler::Mode,
+};
+
+use crate::ioctl;
+use crate::json;
+use crate::pybox_new_interpreter;
+
+/// analysis.py 的源码
+const ANALYSIS_SOURCE: &str = include_str!("analysis.py");
+
+thread_local! {
+    static ANALYZER: OnceCell<Rc<Interpreter>> = const { OnceCell::new() };
+    /// analysis.py 的 dump 函数，在分析解释器中创建
+    static DUMP: OnceCell<PyObjectRef> = const { OnceCell::new() };
+}
+
+/// 静态分析使用的解释器
+pub(crate) fn analyzer() -> Rc<Interpreter> {
+    ANALYZER.with(|analyzer| analyzer.get_or_init(pybox_new_interpreter).clone())
+}
+
+/// 语法检查，不执行代码
+///
+/// * `code` 待检查的代码
+/// * `result` 写入 json 列表，每项为 `{"message", "line", "column", "offset", "end_line", "end_column"}`，
+///   代码合法时为空列表；line / column 从 1 开始，offset 为从 0 开始的字符偏移，未知时为 null
+///
+/// 返回 0 表示检查完成，-1 表示参数无效
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_check(
+    code: *const ioctl::pybox_bytes,
+    result: *mut *mut ioctl::pybox_bytes,
+) -> ssize_t {
+    if code.is_null() {
+        return -1;
+    }
+    let Ok(code) = (unsafe { (*code).string() }) else {
+        return -1;
+    };
+
+    let interpreter = analyzer();
+    interpreter.enter(|vm| {
+        let diagnostics = (|| -> PyResult<String> {
+            let diagnostics = match vm.compile(code, Mode::BlockExpr, "<check>".to_owned()) {
+                Ok(_) => Vec::new(),
+                Err(err) => {
+                    let exception = vm.new_syntax_error(&err, Some(code));
+                    vec![syntax_diagnostic(vm, code, &exception)?]
+                }
+            };
+            json::dumps(vm.ctx.new_list(diagnostics).as_object(), vm)
+        })();
+        match diagnostics {
+            Ok(diagnostics) => {
+                ioctl::pybox_bytes::write_to(result, diagnostics.as_bytes());
+                0
+            }
+            Err(_) => -1,
+        }
+    })
+}
+
+/// 解析代码，返回语法树和其中的名称、import、调用，不执行代码
+///
+/// * `code` 待解析的代码
+/// * `result` 写入 json `{"tree", "names", "imports", "calls"}`：
+///   tree 中每个节点为一个对象，`_type` 为节点类型，其余为节点的字段和位置；
+///   imports 每项为 `{"module", "names", "line"}`，calls 每项为 `{"target", "line"}`，
+///   target 为被调用的名称或属性链 (`os.path.join`)，无法静态确定时为 null
+/// * `error` 解析失败时写入错误信息
+///
+/// 返回 0 表示成功，-1 表示失败
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_ast(
+    code: *const ioctl::pybox_bytes,
+    result: *mut *mut ioctl::pybox_bytes,
+    error: *mut *mut ioctl::pybox_bytes,
+) -> ssize_t {
+    if code.is_null() {
+        ioctl::pybox_bytes::write_to(error, b"Invalid arguments: code is null");
+        return -1;
+    }
+    let Ok(code) = (unsafe { (*code).string() }) else {
+        ioctl::pybox_bytes::write_to(error, b"Invalid UTF-8 encoding in code");
+        return -1;
+    };
+
+    let interpreter = analyzer();
+    interpreter.enter(|vm| {
+        let dumped = (|| -> PyResult<String> {
+            let tree = dump_function(vm)?.call((vm.ctx.new_str(code),), vm)?;
+            json::dumps(&tree, vm)
+        })();
+        match dumped {
+            Ok(dumped) => {
+                ioctl::pybox_bytes::write_to(result, dumped.as_bytes());
+                0
+            }
+            Err(exception) => {
+                let message = exception
+                    .as_object()
+                    .str(vm)
+                    .map(|message| format!("{}: {}", exception.class().name(), message))
+                    .unwrap_or_else(|_| "Pybox: Parse Code Failed!".to_string());
+                ioctl::pybox_bytes::write_to(error, message.as_bytes());
+                -1
+            }
+        }
+    })
+}
+
+/// analysis.py 的 dump 函数，首次使用时在分析解释器中执行 analysis.py
+fn dump_function(vm: &VirtualMachine) -> PyResult<PyObjectRef> {
+    if let Some(dump) = DUMP.with(|dump| dump.get().cloned()) {
+        return Ok(dump);
+    }
+    let scope = vm.new_scope_with_builtins();
+    let code = vm
+        .compile(ANALYSIS_SOURCE, Mode::Exec, "<analysis>".to_owned())
+        .map_err(|err| vm.new_syntax_error(&err, Some(ANALYSIS_SOURCE)))?;
+    vm.run_code_obj(code, scope.clone())?;
+    let dump = scope.globals.get_item("dump", vm)?;
+    DUMP.with(|cell| cell.set(dump.clone()).ok());
+    Ok(dump)
+}
+
+/// 由 SyntaxError 的属性生成一条诊断
+fn syntax_diagnostic(
+    vm: &VirtualMachine,
+    code: &str,
+    exception: &PyBaseExceptionRef,
+) -> PyResult<PyObjectRef> {
+    let attr = |name: &str| -> Option<usize> {
+        exception
+            .as_object()
+            .get_attr(name, vm)
+            .ok()?
+            .try_into_value::<usize>(vm)
+            .ok()
+    };
+    let line = attr("lineno");
+    let column = attr("offset");
+    let message = match exception.as_object().get_attr("msg", vm) {
+        Ok(msg) if !vm.is_none(&msg) => msg.str(vm)?,
+        _ => exception.as_object().str(vm)?,
+    };
+
+    let optional = |value: Option<usize>| match value {
+        Some(value) => vm.ctx.new_int(value).into(),
+        None => vm.ctx.none(),
+    };
+    let diagnostic = vm.ctx.new_dict();
+    diagnostic.set_item("message", message.into(), vm)?;
+    diagnostic.set_item("line", optional(line), vm)?;
+    diagnostic.set_item("column", optional(column), vm)?;
+    diagnostic.set_item("offset", optional(char_offset(code, line, column)), vm)?;
+    diagnostic.set_item("end_line", optional(attr("end_lineno")), vm)?;
+    diagnostic.set_item("end_column", optional(attr("end_offset")), vm)?;
+    Ok(diagnostic.into())
+}
+
+/// 从 1 开始的行、列转换为从 0 开始的字符偏移
+fn char_offset(code: &str, line: Option<usize>, column: Option<usize>) -> Option<usize> {
+    let (line, column) = (line?.checked_sub(1)?, column?.saturating_sub(1));
+    let before: usize = code
+        .split_inclusive('\n')
+        .take(line)
+        .map(|line| line.chars().count())
+        .sum();
+    Some(before + column)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check(code: &str) -> String {
+        let code = ioctl::pybox_bytes::new_bytes(code.as_bytes());
+        let mut result = std::ptr::null_mut();
+        assert_eq!(pybox_check(code, &mut result), 0);
+        unsafe { (*result).string().unwrap().to_string() }
+    }
+
+    #[test]
+    fn test_pybox_check() {
+        assert_eq!(check("x = 1\nx + 1"), "[]");
+
+        let diagnostics = check("x = 1\ndef (:\n");
+        assert!(diagnostics.contains(r#""line":2"#), "{}", diagnostics);
+        assert!(diagnostics.contains(r#""message":"#), "{}", diagnostics);
+        assert!(!diagnostics.contains(r#""offset":null"#), "{}", diagnostics);
+
+        assert_eq!(char_offset("ab\ncd", Some(2), Some(2)), Some(4));
+        assert_eq!(char_offset("ab", None, Some(1)), None);
+    }
+
+    #[test]
+    fn test_pybox_ast() {
+        let dump = |code: &str| {
+            let code = ioctl::pybox_bytes::new_bytes(code.as_bytes());
+            let (mut result, mut error) = (std::ptr::null_mut(), std::ptr::null_mut());
+            match pybox_ast(code, &mut result, &mut error) {
+                0 => Ok(unsafe { (*result).string().unwrap().to_string() }),
+                _ => Err(unsafe { (*error).string().unwrap().to_string() }),
+            }
+        };
+
+        let dumped = dump("import os\nfrom a.b import c as d\nos.path.join(d, x)\n").unwrap();
+        let dumped: serde_json::Value = serde_json::from_str(&dumped).unwrap();
+        assert_eq!(dumped["tree"]["_type"], "Module");
+        assert_eq!(dumped["tree"]["body"][0]["_type"], "Import");
+        assert_eq!(dumped["names"], serde_json::json!(["d", "os", "x"]));
+        assert_eq!(
+            dumped["imports"],
+            serde_json::json!([
+                {"module": "os", "names": [], "line": 1},
+                {"module": "a.b", "names": ["c"], "line": 2},
+            ])
+        );
+        assert_eq!(
+            dumped["calls"],
+            serde_json::json!([{"target": "os.path.join", "line": 3}])
+        );
+
+        assert!(dump("def (:").unwrap_err().starts_with("SyntaxError"));
+    }
+}
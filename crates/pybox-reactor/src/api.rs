@@ -0,0 +1,221 @@
+//! api.rs 在原生 Rust 程序中直接嵌入沙箱的安全接口
+//!
+//! `pybox_init_local`、`pybox_exec`、`pybox_call` 等 C 导出只负责解析参数和写回结果，
+//! 执行逻辑都在这里的 PyboxBuilder / PyboxEnv 上，编译到原生目标的程序得到与 wasm 中相同的沙箱行为：
+//!
+//! ```ignore
+//! use pybox_reactor::{PyboxBuilder, PyboxEnv};
+//!
+//! let env = PyboxBuilder::new().recursion_limit(200).build("agent");
+//! env.assign_json("n", "3")?;
+//! let result = env.exec("print(n * 2)");
+//! assert_eq!(result.output, "6\n");
+//! assert_eq!(PyboxEnv::open("agent").unwrap().retrieve_json("n")?, "3");
+//! ```
+//!
+//! 环境登记在当前线程的环境表中，与 C 导出共享，同 id 的环境会被替换；
+//! 解释器不是线程安全的，PyboxEnv 只能在创建它的线程上使用
+
+use std::rc::Rc;
+use std::time::Duration;
+
+use rustpython_vm::{Interpreter, PyObjectRef};
+
+use crate::exec;
+use crate::options::{FrozenModules, NativeModule, PyboxInterpreterOptions, SanitizerPolicy};
+use crate::protected::ProtectedLocals;
+use crate::{PYBOX_STATE, pybox_new_interpreter_with};
+
+/// 创建环境的构建器，每个环境使用独立的解释器
+#[derive(Clone, Debug, Default)]
+pub struct PyboxBuilder {
+    options: PyboxInterpreterOptions,
+}
+
+impl PyboxBuilder {
+    /// 默认选项，与 `pybox_init_local` 创建的环境相同
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 使用完整的解释器选项
+    pub fn options(mut self, options: PyboxInterpreterOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn sanitizer(mut self, policy: SanitizerPolicy) -> Self {
+        self.options.sanitizer = policy;
+        self
+    }
+
+    pub fn frozen_modules(mut self, frozen_modules: FrozenModules) -> Self {
+        self.options.frozen_modules = frozen_modules;
+        self
+    }
+
+    pub fn recursion_limit(mut self, limit: usize) -> Self {
+        self.options.recursion_limit = Some(limit);
+        self
+    }
+
+    pub fn hash_seed(mut self, seed: u32) -> Self {
+        self.options.hash_seed = Some(seed);
+        self
+    }
+
+    pub fn random_seed(mut self, seed: u64) -> Self {
+        self.options.random_seed = Some(seed);
+        self
+    }
+
+    /// 注册额外的原生模块
+    pub fn native_module(mut self, module: NativeModule) -> Self {
+        self.options.native_modules.push(module);
+        self
+    }
+
+    /// 创建空的环境并以 id 登记，替换同 id 的环境
+    pub fn build(&self, id: &str) -> PyboxEnv {
+        let interpreter = pybox_new_interpreter_with(&self.options);
+        let locals = interpreter.enter(|vm| {
+            vm.builtins
+                .get_attr("ProtectedLocals", vm)
+                .expect("ProtectedLocals type not registered")
+                .call((), vm)
+                .expect("Failed to create ProtectedLocals instance")
+        });
+        PYBOX_STATE.with_borrow_mut(|pybox_state| {
+            pybox_state
+                .locals
+                .insert(id.to_string(), (locals.clone(), interpreter.clone()))
+        });
+        PyboxEnv {
+            id: id.to_string(),
+            interpreter,
+            locals,
+        }
+    }
+}
+
+/// 一次 exec 的结果
+#[derive(Clone, Debug, Default)]
+pub struct PyboxExecResult {
+    /// 合并的输出 (stdout & stderr)，代码抛出异常时包括 traceback
+    pub output: String,
+    /// 代码是否抛出了异常（包括编译错误）
+    pub raised: bool,
+    /// 结构化结果，见 `pybox_exec_report`
+    pub report: Option<serde_json::Value>,
+    pub compile_time: Duration,
+    pub run_time: Duration,
+}
+
+/// 一个已登记的环境，持有其解释器和 locals 的引用
+#[derive(Clone)]
+pub struct PyboxEnv {
+    id: String,
+    interpreter: Rc<Interpreter>,
+    locals: PyObjectRef,
+}
+
+impl PyboxEnv {
+    /// 获取当前线程上以 id 登记的环境
+    pub fn open(id: &str) -> Option<Self> {
+        let (interpreter, locals) = exec::clone_local(id).ok()?;
+        Some(Self {
+            id: id.to_string(),
+            interpreter,
+            locals,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// 环境的解释器，用于这里没有覆盖的操作
+    pub fn interpreter(&self) -> &Rc<Interpreter> {
+        &self.interpreter
+    }
+
+    /// 执行代码，输出和 artifact 的限制、标志等使用 `pybox_set_exec_limits` 等设置的当前值
+    pub fn exec(&self, code: &str) -> PyboxExecResult {
+        let output = exec::exec_in(&self.id, &self.interpreter, self.locals.clone(), code);
+        let (compile_ns, run_ns) = exec::last_exec_timing();
+        PyboxExecResult {
+            output,
+            raised: exec::last_exec_raised(),
+            report: exec::last_exec_report(),
+            compile_time: Duration::from_nanos(compile_ns),
+            run_time: Duration::from_nanos(run_ns),
+        }
+    }
+
+    /// 以 json 创建变量，绕过保护检查，失败时返回 traceback
+    pub fn assign_json(&self, name: &str, json: &str) -> Result<(), String> {
+        exec::assign_in(&self.interpreter, &self.locals, name, json)
+    }
+
+    /// 以 json 读取变量，失败时返回 traceback
+    pub fn retrieve_json(&self, name: &str) -> Result<String, String> {
+        exec::retrieve_in(&self.interpreter, &self.locals, name)
+    }
+
+    /// 调用环境中的可调用对象，args 为 `{"args": [...], "kwargs": {...}}`，返回 json 序列化的返回值
+    pub fn call_json(&self, name: &str, args: &str) -> Result<String, String> {
+        exec::call_in(&self.id, &self.interpreter, self.locals.clone(), name, args)
+    }
+
+    /// 保护变量，环境中的代码不能修改或删除它
+    pub fn protect(&self, name: &str) {
+        if let Some(locals) = self.locals.downcast_ref::<ProtectedLocals>() {
+            locals.protect(name);
+        }
+    }
+
+    /// 取消登记，已有的 PyboxEnv 在释放前仍然可用
+    pub fn remove(self) -> bool {
+        PYBOX_STATE.with_borrow_mut(|pybox_state| pybox_state.locals.remove(&self.id).is_some())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_builder_env() {
+        let env = PyboxBuilder::new()
+            .recursion_limit(150)
+            .build("test_builder_env");
+        env.assign_json("n", "3").unwrap();
+        let result = env.exec("import sys\nprint(n * 2, sys.getrecursionlimit())");
+        assert_eq!(result.output, "6 150\n");
+        assert!(!result.raised);
+        assert_eq!(result.report.unwrap()["stdout"], "6 150\n");
+
+        // 与 C 导出共享同一个环境
+        let opened = PyboxEnv::open("test_builder_env").unwrap();
+        opened.exec("def double(x):\n    return x * 2\nn = 4");
+        assert_eq!(env.retrieve_json("n").unwrap(), "4");
+        assert_eq!(env.call_json("double", r#"{"args": [21]}"#).unwrap(), "42");
+        assert!(
+            env.call_json("missing", "{}")
+                .unwrap_err()
+                .contains("KeyError")
+        );
+
+        env.protect("n");
+        assert!(env.exec("n = 5").raised);
+        assert_eq!(env.retrieve_json("n").unwrap(), "4");
+
+        let result = env.exec("1 / 0");
+        assert!(result.raised);
+        assert!(result.output.contains("ZeroDivisionError"));
+
+        assert!(opened.remove());
+        assert!(PyboxEnv::open("test_builder_env").is_none());
+        assert!(!env.remove());
+    }
+}
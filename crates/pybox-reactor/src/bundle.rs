@@ -0,0 +1,15 @@
+//! bundle.rs pybox-builder 打包进 wasm 的 python 模块
+//!
+//! build.rs 根据 PYBOX_BUNDLE 目录生成模块表，pybox.py 的 `_BundleFinder` 在导入时编译执行；
+//! 标准库优先，同名的打包模块不会覆盖标准库
+
+include!(concat!(env!("OUT_DIR"), "/bundle.rs"));
+
+/// 打包的模块的源码和是否为包
+pub fn find(name: &str) -> Option<(&'static str, bool)> {
+    let index = BUNDLE
+        .binary_search_by(|(module, _, _)| (*module).cmp(name))
+        .ok()?;
+    let (_, source, package) = BUNDLE[index];
+    Some((source, package))
+}
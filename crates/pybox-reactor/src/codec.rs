@@ -0,0 +1,168 @@
+//! codec.rs transparent compression of large ioctl payloads
+//!
+//! host 通过 CAPS_HANDLE 声明支持压缩后，guest 以 `handle | COMPRESSED_HANDLE_FLAG` 发送分帧的请求：
+//! 首字节为编码 (FRAME_RAW / FRAME_ZSTD)，其后为数据，host 的响应使用相同的分帧；
+//! 响应也可以是 FRAME_STREAM + u32 stream id，guest 通过 STREAM_HANDLE 逐块拉取数据
+
+use std::cell::OnceCell;
+use std::io::Read;
+
+use crate::ioctl::ioctl_host;
+
+/// ioctl 约定的版本，与 host 的 pybox_host::protocol::PROTOCOL_VERSION 一致时才能加载
+pub const PROTOCOL_VERSION: u32 = 2;
+/// handle >= SYSTEM_HANDLE_BASE 为 pybox 保留的系统 handle
+pub const SYSTEM_HANDLE_BASE: usize = 0x7FFF_0000;
+/// 系统 handle：查询 host 能力，响应为 `key=value` 行
+pub const CAPS_HANDLE: usize = SYSTEM_HANDLE_BASE + 1;
+/// 带有该标记的 handle 使用分帧的请求和响应
+pub const COMPRESSED_HANDLE_FLAG: usize = 0x4000_0000;
+
+const FRAME_RAW: u8 = 0;
+const FRAME_ZSTD: u8 = 1;
+const FRAME_STREAM: u8 = 2;
+
+/// 解码后的响应
+#[derive(Debug, PartialEq)]
+pub enum Payload {
+    Data(Vec<u8>),
+    /// host 端的数据流，guest 端以 `pybox.Stream` 读取
+    Stream(u32),
+}
+
+thread_local! {
+    /// host 声明的压缩阈值，None 表示 host 不支持压缩
+    static COMPRESS_THRESHOLD: OnceCell<Option<usize>> = const { OnceCell::new() };
+}
+
+/// 解析 host 能力中的压缩阈值
+fn parse_threshold(caps: &[u8]) -> Option<usize> {
+    let caps = std::str::from_utf8(caps).ok()?;
+    let mut zstd = false;
+    let mut threshold = None;
+    for line in caps.lines() {
+        match line.split_once('=') {
+            Some(("compress", codecs)) => zstd = codecs.split(',').any(|codec| codec == "zstd"),
+            Some(("compress_threshold", value)) => threshold = value.parse().ok(),
+            _ => {}
+        }
+    }
+    threshold.filter(|_| zstd)
+}
+
+fn compress_threshold() -> Option<usize> {
+    COMPRESS_THRESHOLD.with(|threshold| {
+        *threshold.get_or_init(|| match ioctl_host(CAPS_HANDLE, b"") {
+            (true, caps) => parse_threshold(&caps),
+            _ => None,
+        })
+    })
+}
+
+/// 分帧编码，数据不小于 threshold 且压缩有收益时使用 zstd
+pub fn encode(data: &[u8], threshold: usize) -> Vec<u8> {
+    if data.len() >= threshold {
+        let compressed =
+            ruzstd::encoding::compress_to_vec(data, ruzstd::encoding::CompressionLevel::Fastest);
+        if compressed.len() < data.len() {
+            let mut frame = Vec::with_capacity(compressed.len() + 1);
+            frame.push(FRAME_ZSTD);
+            frame.extend_from_slice(&compressed);
+            return frame;
+        }
+    }
+    let mut frame = Vec::with_capacity(data.len() + 1);
+    frame.push(FRAME_RAW);
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// 分帧解码
+pub fn decode(frame: &[u8]) -> Result<Payload, String> {
+    match frame.split_first() {
+        Some((&FRAME_RAW, data)) => Ok(Payload::Data(data.to_vec())),
+        Some((&FRAME_ZSTD, data)) => {
+            let mut decoder =
+                ruzstd::decoding::StreamingDecoder::new(data).map_err(|e| e.to_string())?;
+            let mut data = Vec::new();
+            decoder.read_to_end(&mut data).map_err(|e| e.to_string())?;
+            Ok(Payload::Data(data))
+        }
+        Some((&FRAME_STREAM, id)) => {
+            let id: [u8; 4] = id
+                .try_into()
+                .map_err(|_| "invalid stream frame".to_string())?;
+            Ok(Payload::Stream(u32::from_le_bytes(id)))
+        }
+        Some((codec, _)) => Err(format!("unknown frame codec {}", codec)),
+        None => Err("empty frame".to_string()),
+    }
+}
+
+/// 调用 host handler，host 支持时对普通 handle 使用分帧压缩
+pub fn call_host(handle: isize, data: &[u8]) -> (bool, Payload) {
+    let raw_call = |handle: usize| {
+        let (success, data) = ioctl_host(handle, data);
+        (success, Payload::Data(data))
+    };
+    let Ok(raw_handle) = usize::try_from(handle) else {
+        return raw_call(handle as usize);
+    };
+    if raw_handle >= COMPRESSED_HANDLE_FLAG {
+        return raw_call(raw_handle);
+    }
+    let Some(threshold) = compress_threshold() else {
+        return raw_call(raw_handle);
+    };
+
+    let (success, frame) = ioctl_host(
+        raw_handle | COMPRESSED_HANDLE_FLAG,
+        &encode(data, threshold),
+    );
+    if !success {
+        return (false, Payload::Data(frame));
+    }
+    match decode(&frame) {
+        Ok(payload) => (true, payload),
+        Err(_) => (false, Payload::Data(Vec::new())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_frame_round_trip() {
+        let small = b"{\"args\": [1]}".to_vec();
+        let frame = encode(&small, 1024);
+        assert_eq!(frame[0], FRAME_RAW);
+        assert_eq!(decode(&frame).unwrap(), Payload::Data(small));
+
+        let large = "x".repeat(1 << 16).into_bytes();
+        let frame = encode(&large, 1024);
+        assert_eq!(frame[0], FRAME_ZSTD);
+        assert!(frame.len() < large.len());
+        assert_eq!(decode(&frame).unwrap(), Payload::Data(large));
+
+        let frame = [FRAME_STREAM, 7, 0, 0, 0];
+        assert_eq!(decode(&frame).unwrap(), Payload::Stream(7));
+        assert!(decode(&[FRAME_STREAM, 7]).is_err());
+
+        assert!(decode(&[]).is_err());
+        assert!(decode(&[7, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_parse_threshold() {
+        assert_eq!(
+            parse_threshold(b"compress=zstd\ncompress_threshold=4096"),
+            Some(4096)
+        );
+        assert_eq!(
+            parse_threshold(b"compress=lz4\ncompress_threshold=4096"),
+            None
+        );
+        assert_eq!(parse_threshold(b""), None);
+    }
+}
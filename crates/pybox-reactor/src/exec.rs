@@ -1,15 +1,265 @@
 //! exec crate 提供 pybox_exec 等在 locals 中执行代码的接口
 
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
+use std::time::Instant;
 
 use libc::ssize_t;
 
-use rustpython_vm::{AsObject, Interpreter, PyResult, VirtualMachine, compiler::Mode};
+use rustpython_vm::{
+    AsObject, Interpreter, PyObjectRef, PyResult, VirtualMachine,
+    builtins::{PyBaseExceptionRef, PyDict},
+    compiler::Mode,
+    function::FuncArgs,
+};
 
 use super::PYBOX_STATE;
 
+use crate::api::{PyboxBuilder, PyboxEnv};
+use crate::interrupt;
 use crate::ioctl;
+use crate::json;
+use crate::mem;
 use crate::protected::ProtectedLocals;
+use crate::trace;
+use crate::traceback;
+
+thread_local! {
+    /// 最近一次 pybox_exec 的 (编译耗时, 运行耗时)，单位纳秒
+    static LAST_EXEC_TIMING: Cell<(u64, u64)> = const { Cell::new((0, 0)) };
+    /// 最近一次 pybox_exec 的代码是否抛出了异常（包括编译错误）
+    static LAST_EXEC_RAISED: Cell<bool> = const { Cell::new(false) };
+    /// 最近一次 pybox_exec 的结构化结果 (json)
+    static LAST_EXEC_REPORT: RefCell<String> = const { RefCell::new(String::new()) };
+    /// 之后开始的 pybox_exec 是否在写入 stdout / stderr 时将内容实时发送给 host
+    static STREAM_OUTPUT: Cell<bool> = const { Cell::new(false) };
+    /// 之后开始的 pybox_exec 的输出和 artifact 限制
+    static EXEC_LIMITS: Cell<ExecLimits> = const { Cell::new(ExecLimits::UNLIMITED) };
+    /// 之后开始的 pybox_exec 的标志，见 PYBOX_EXEC_AUTO_CREATE
+    static EXEC_FLAGS: Cell<i32> = const { Cell::new(0) };
+    /// 之后开始的 pybox_exec / pybox_call 的关联 id，由 host 在 exec 前设置
+    static CORRELATION_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+    /// 正在运行的 pybox_exec / pybox_call 的 (环境 id, 关联 id)，handler 内部重入时嵌套
+    static CALLING: RefCell<Vec<(String, Option<String>)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// pybox_exec 的环境不存在时创建空的环境，而不是返回 "Local context not found"
+pub const PYBOX_EXEC_AUTO_CREATE: i32 = 1;
+/// 代码抛出异常时将环境的绑定和受保护的键恢复到 exec 之前，对象内部的修改不会回滚
+pub const PYBOX_EXEC_TRANSACTIONAL: i32 = 2;
+
+/// pybox_exec 的输出和 artifact 限制，None 表示不限制
+#[derive(Clone, Copy)]
+struct ExecLimits {
+    /// 输出的最大字节数
+    max_output: Option<usize>,
+    /// 最多附加的 artifact 数量
+    max_artifacts: Option<usize>,
+    /// artifact 数据的最大总字节数
+    max_artifact_bytes: Option<usize>,
+}
+
+impl ExecLimits {
+    const UNLIMITED: Self = Self {
+        max_output: None,
+        max_artifacts: None,
+        max_artifact_bytes: None,
+    };
+}
+
+/// 设置之后开始的 pybox_exec 的限制，由 host 在 exec 前设置，负数表示不限制
+/// 超出限制的输出和 artifact 被丢弃，结构化结果中 `truncated` 为 true
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_set_exec_limits(
+    max_output: i64,
+    max_artifacts: i64,
+    max_artifact_bytes: i64,
+) {
+    let limit = |value: i64| usize::try_from(value).ok();
+    EXEC_LIMITS.set(ExecLimits {
+        max_output: limit(max_output),
+        max_artifacts: limit(max_artifacts),
+        max_artifact_bytes: limit(max_artifact_bytes),
+    });
+}
+
+/// 将输出截断到 max_output 字节以内（按字符边界），返回是否截断
+fn truncate_output(output: &mut String, max_output: Option<usize>) -> bool {
+    let Some(max_output) = max_output.filter(|max_output| output.len() > *max_output) else {
+        return false;
+    };
+    let end = (0..=max_output)
+        .rev()
+        .find(|end| output.is_char_boundary(*end))
+        .unwrap_or(0);
+    output.truncate(end);
+    true
+}
+
+/// 设置之后开始的 pybox_exec 的标志，由 host 在 exec 前设置，未知的标志被忽略
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_set_exec_flags(flags: i32) {
+    EXEC_FLAGS.set(flags);
+}
+
+/// 设置之后开始的 pybox_exec / pybox_call 的关联 id，由 host 在 exec 前设置，null 清除
+/// 运行期间发出的 JSON-RPC 请求带上该 id，host 可以据此关联一次执行内的所有调用
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_set_correlation_id(id: *const ioctl::pybox_bytes) {
+    let id = (!id.is_null())
+        .then(|| unsafe { (*id).string().ok().map(str::to_string) })
+        .flatten();
+    CORRELATION_ID.replace(id);
+}
+
+/// 最内层正在运行的 pybox_exec / pybox_call 的 (环境 id, 关联 id)
+pub fn calling_context() -> Option<(String, Option<String>)> {
+    CALLING.with_borrow(|calling| calling.last().cloned())
+}
+
+/// 记录正在运行的环境，释放时弹出
+struct CallingGuard;
+
+impl CallingGuard {
+    fn enter(id: &str) -> Self {
+        let correlation_id = CORRELATION_ID.with_borrow(Clone::clone);
+        CALLING.with_borrow_mut(|calling| calling.push((id.to_string(), correlation_id)));
+        CallingGuard
+    }
+}
+
+impl Drop for CallingGuard {
+    fn drop(&mut self) {
+        CALLING.with_borrow_mut(|calling| calling.pop());
+    }
+}
+
+/// 设置之后开始的 pybox_exec 是否实时发送输出，由 host 在 exec 前设置
+/// 输出通过系统 handle `pybox._OUTPUT_HANDLE` 以 `stdout:<text>` / `stderr:<text>` 发送
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_set_output_stream(enabled: i32) {
+    STREAM_OUTPUT.set(enabled != 0);
+}
+
+/// 最近一次 pybox_exec 的编译耗时（纳秒）
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_exec_compile_ns() -> u64 {
+    LAST_EXEC_TIMING.get().0
+}
+
+/// 最近一次 pybox_exec 的运行耗时（纳秒）
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_exec_run_ns() -> u64 {
+    LAST_EXEC_TIMING.get().1
+}
+
+/// 最近一次 pybox_exec 的代码是否抛出了异常，异常信息写在输出中
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_exec_raised() -> i32 {
+    LAST_EXEC_RAISED.get() as i32
+}
+
+/// 最近一次 pybox_exec 的结构化结果，json 格式：
+/// `{"stdout", "stderr", "exception": null | {"type", "message", "traceback"}, "result_repr", "artifacts", "truncated", "diagnostics"}`
+/// * `report` 结果输出，由调用者释放
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_exec_report(report: *mut *mut ioctl::pybox_bytes) -> ssize_t {
+    if report.is_null() {
+        return -1;
+    }
+    LAST_EXEC_REPORT.with_borrow(|last| unsafe {
+        *report = ioctl::pybox_bytes::new_bytes(last.as_bytes());
+    });
+    0
+}
+
+pub(crate) fn last_exec_timing() -> (u64, u64) {
+    LAST_EXEC_TIMING.get()
+}
+
+pub(crate) fn last_exec_raised() -> bool {
+    LAST_EXEC_RAISED.get()
+}
+
+/// 最近一次的结构化结果，生成失败时为 None
+pub(crate) fn last_exec_report() -> Option<serde_json::Value> {
+    LAST_EXEC_REPORT.with_borrow(|last| serde_json::from_str(last).ok())
+}
+
+/// 记录 pybox_exec 的结构化结果，生成失败时记为空字符串
+fn set_exec_report(
+    vm: &VirtualMachine,
+    stdout: &str,
+    stderr: &str,
+    exception: Option<&PyBaseExceptionRef>,
+    value: Option<&PyObjectRef>,
+    artifacts: Option<PyObjectRef>,
+    truncated: bool,
+    diagnostics: Option<PyObjectRef>,
+) {
+    let report = (|| -> PyResult<String> {
+        let report = vm.ctx.new_dict();
+        report.set_item("stdout", vm.ctx.new_str(stdout).into(), vm)?;
+        report.set_item("stderr", vm.ctx.new_str(stderr).into(), vm)?;
+
+        let exception = match exception {
+            Some(exception) => {
+                let mut traceback = String::new();
+                let _ = traceback::write_exception(vm, &mut traceback, exception);
+                let info = vm.ctx.new_dict();
+                info.set_item(
+                    "type",
+                    vm.ctx.new_str(exception.class().name().to_string()).into(),
+                    vm,
+                )?;
+                info.set_item("message", exception.as_object().str(vm)?.into(), vm)?;
+                info.set_item("traceback", vm.ctx.new_str(traceback).into(), vm)?;
+                info.into()
+            }
+            None => vm.ctx.none(),
+        };
+        report.set_item("exception", exception, vm)?;
+
+        let result_repr = match value.filter(|value| !vm.is_none(value)) {
+            Some(value) => value.repr(vm)?.into(),
+            None => vm.ctx.none(),
+        };
+        report.set_item("result_repr", result_repr, vm)?;
+        report.set_item(
+            "artifacts",
+            artifacts.unwrap_or_else(|| vm.ctx.new_list(Vec::new()).into()),
+            vm,
+        )?;
+        report.set_item("truncated", vm.ctx.new_bool(truncated).into(), vm)?;
+        report.set_item(
+            "diagnostics",
+            diagnostics.unwrap_or_else(|| vm.ctx.new_list(Vec::new()).into()),
+            vm,
+        )?;
+
+        let json_module = vm.import("json", 0)?;
+        json_module
+            .get_attr("dumps", vm)?
+            .call((report,), vm)?
+            .try_into_value::<String>(vm)
+    })()
+    .unwrap_or_default();
+    LAST_EXEC_REPORT.set(report);
+}
+
+/// 运行 pybox.lint 注册的 linter，返回诊断列表，没有 linter 时返回 None
+/// linter 报告错误时返回 pybox.LintError，其 diagnostics 属性为全部诊断
+fn run_linters(vm: &VirtualMachine, code: &str) -> PyResult<Option<PyObjectRef>> {
+    let pybox_module = vm.import("pybox", 0)?;
+    let linters = pybox_module.get_attr("_linters", vm)?;
+    if linters.length(vm)? == 0 {
+        return Ok(None);
+    }
+    let diagnostics = pybox_module
+        .get_attr("_lint", vm)?
+        .call((vm.ctx.new_str(code),), vm)?;
+    Ok(Some(diagnostics))
+}
 
 /// 在指定 id 的 locals 环境上创建一个 json 描述的变量
 ///
@@ -35,78 +285,231 @@ pub extern "C" fn pybox_assign(
         }
     }
 
-    PYBOX_STATE.with_borrow(|pybox_state| {
-        let Ok((id, name, object_str)) = (|| -> Result<_, ()> {
-            unsafe {
-                let id: &str = (*id).string()?;
-                let name = (*name).string()?;
-                let object_str = (*object).string()?;
-                Ok((id, name, object_str))
-            }
-        })() else {
-            if !error.is_null() {
-                unsafe {
-                    *error = ioctl::pybox_bytes::new_bytes(
-                        b"Invalid UTF-8 encoding in id, name or object",
-                    );
-                }
+    let Ok((id, name, object_str)) = (|| -> Result<_, ()> {
+        unsafe {
+            let id: &str = (*id).string()?;
+            let name = (*name).string()?;
+            let object_str = (*object).string()?;
+            Ok((id, name, object_str))
+        }
+    })() else {
+        ioctl::pybox_bytes::write_to(error, b"Invalid UTF-8 encoding in id, name or object");
+        return -1;
+    };
+
+    let Some(env) = PyboxEnv::open(id) else {
+        let error_msg = format!("Local context '{}' not found", id);
+        ioctl::pybox_bytes::write_to(error, error_msg.as_bytes());
+        return -1;
+    };
+    match env.assign_json(name, object_str) {
+        Ok(()) => 0,
+        Err(error_string) => {
+            ioctl::pybox_bytes::write_to(error, error_string.as_bytes());
+            -1
+        }
+    }
+}
+
+/// 以 json 反序列化的对象创建变量，绕过保护检查但不绕过变量数量上限，失败时返回 traceback
+pub(crate) fn assign_in(
+    interpreter: &Interpreter,
+    locals: &PyObjectRef,
+    name: &str,
+    object_str: &str,
+) -> Result<(), String> {
+    interpreter.enter(|vm| {
+        let result = (|| -> PyResult<()> {
+            // Deserialize JSON string to Python object
+            let python_obj = json::loads(object_str.as_bytes(), vm)?;
+
+            // Get the ProtectedLocals instance
+            let protected_locals = locals.downcast_ref::<ProtectedLocals>().ok_or_else(|| {
+                vm.new_type_error("locals is not a ProtectedLocals instance".to_string())
+            })?;
+
+            // Directly set item to internal dict, bypassing protection check
+            // but not the variable limit
+            protected_locals.check_new_key(vm.ctx.new_str(name).as_object(), vm)?;
+            let dict = protected_locals.dict();
+            dict.as_object().set_item(name, python_obj, vm)?;
+
+            Ok(())
+        })();
+
+        result.map_err(|exception| {
+            let mut error_string = String::new();
+            if traceback::write_exception(vm, &mut error_string, &exception).is_err() {
+                error_string.push_str("Failed to assign object: unknown error");
             }
+            error_string
+        })
+    })
+}
+
+/// 在指定 id 的 locals 环境上创建一个 bytes 变量，数据由 host 直接写入，不经过 json
+///
+/// # Arguments
+///
+/// * `id` 指定 locals 环境 id
+/// * `name` 变量名
+/// * `buffer` pybox_buffer_alloc 分配的缓冲区，无论成功与否都会被取走
+/// * `len` 写入的字节数
+/// * `memoryview` 非 0 时绑定为 bytes 的 memoryview
+/// * `error` pybox 错误信息
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_assign_buffer(
+    id: *const ioctl::pybox_bytes,
+    name: *const ioctl::pybox_bytes,
+    buffer: *mut u8,
+    len: libc::size_t,
+    memoryview: i32,
+    error: *mut *mut ioctl::pybox_bytes,
+) -> ssize_t {
+    let Some(data) = mem::take_buffer(buffer, len) else {
+        ioctl::pybox_bytes::write_to(error, b"Invalid arguments: unknown buffer");
+        return -1;
+    };
+    if id.is_null() || name.is_null() {
+        ioctl::pybox_bytes::write_to(error, b"Invalid arguments: id or name is null");
+        return -1;
+    }
+
+    let Ok((id, name)) = (|| -> Result<_, ()> {
+        unsafe {
+            let id = (*id).string()?;
+            let name = (*name).string()?;
+            Ok((id, name))
+        }
+    })() else {
+        ioctl::pybox_bytes::write_to(error, b"Invalid UTF-8 encoding in id or name");
+        return -1;
+    };
+
+    let (interpreter, locals_ref) = match clone_local(id) {
+        Ok(values) => values,
+        Err(err_msg) => {
+            ioctl::pybox_bytes::write_to(error, err_msg.as_bytes());
             return -1;
-        };
+        }
+    };
 
-        let Some((locals, interpreter)) = pybox_state.locals.get(id) else {
-            let error_msg = format!("Local context '{}' not found", id);
-            if !error.is_null() {
-                unsafe {
-                    *error = ioctl::pybox_bytes::new_bytes(error_msg.as_bytes());
+    interpreter.enter(|vm| {
+        let result = (|| -> PyResult<()> {
+            let protected_locals =
+                locals_ref
+                    .downcast_ref::<ProtectedLocals>()
+                    .ok_or_else(|| {
+                        vm.new_type_error("locals is not a ProtectedLocals instance".to_string())
+                    })?;
+            let mut value: PyObjectRef = vm.ctx.new_bytes(data).into();
+            if memoryview != 0 {
+                value = vm.builtins.get_attr("memoryview", vm)?.call((value,), vm)?;
+            }
+            // Directly set item to internal dict, bypassing protection check
+            protected_locals
+                .dict()
+                .as_object()
+                .set_item(name, value, vm)
+        })();
+
+        match result {
+            Ok(_) => 0,
+            Err(exception) => {
+                let mut error_string = String::new();
+                if traceback::write_exception(vm, &mut error_string, &exception).is_err() {
+                    error_string.push_str("Failed to assign buffer: unknown error");
                 }
+                ioctl::pybox_bytes::write_to(error, error_string.as_bytes());
+                -1
             }
-            return -1;
+        }
+    })
+}
+
+/// 获取指定 id 的 interpreter 和 locals (引用计数拷贝)
+///
+/// 拷贝后即释放 PYBOX_STATE 的借用, 执行的 python 代码可以通过 JSON-RPC 重入 pybox 接口
+pub fn clone_local(id: &str) -> Result<(Rc<Interpreter>, PyObjectRef), &'static str> {
+    PYBOX_STATE.with_borrow(|pybox_state| {
+        let Some((locals, interpreter)) = pybox_state.locals.get(id) else {
+            return Err("Local context not found");
         };
+        Ok((interpreter.clone(), locals.clone()))
+    })
+}
 
-        // Use JSON to deserialize object string to Python object and save to locals
-        interpreter.enter(|vm| {
-            let result = (|| -> PyResult<()> {
-                // Import json module
-                let json_module = vm.import("json", 0)?;
-                let loads_func = json_module.get_attr("loads", vm)?;
+/// 读取指定 id 的 locals 环境中的变量, 以 json 序列化返回
+///
+/// # Arguments
+///
+/// * `id` 指定 locals 环境 id
+/// * `name` 变量名
+/// * `object` 序列化的 json 对象
+/// * `error` pybox 错误信息
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_retrieve(
+    id: *const ioctl::pybox_bytes,
+    name: *const ioctl::pybox_bytes,
+    object: *mut *mut ioctl::pybox_bytes,
+    error: *mut *mut ioctl::pybox_bytes,
+) -> ssize_t {
+    if id.is_null() || name.is_null() {
+        ioctl::pybox_bytes::write_to(error, b"Invalid arguments: id or name is null");
+        return -1;
+    }
+
+    let Ok((id, name)) = (|| -> Result<_, ()> {
+        unsafe {
+            let id = (*id).string()?;
+            let name = (*name).string()?;
+            Ok((id, name))
+        }
+    })() else {
+        ioctl::pybox_bytes::write_to(error, b"Invalid UTF-8 encoding in id or name");
+        return -1;
+    };
 
-                // Deserialize JSON string to Python object
-                let json_str = vm.ctx.new_str(object_str);
-                let python_obj = loads_func.call((json_str,), vm)?;
+    let Some(env) = PyboxEnv::open(id) else {
+        ioctl::pybox_bytes::write_to(error, b"Local context not found");
+        return -1;
+    };
+    match env.retrieve_json(name) {
+        Ok(json_str) => {
+            ioctl::pybox_bytes::write_to(object, json_str.as_bytes());
+            0
+        }
+        Err(error_string) => {
+            ioctl::pybox_bytes::write_to(error, error_string.as_bytes());
+            -1
+        }
+    }
+}
 
-                // Get the ProtectedLocals instance
-                let protected_locals =
-                    locals.downcast_ref::<ProtectedLocals>().ok_or_else(|| {
+/// 读取环境中的变量并以 json 序列化，失败时返回 traceback
+pub(crate) fn retrieve_in(
+    interpreter: &Interpreter,
+    locals_ref: &PyObjectRef,
+    name: &str,
+) -> Result<String, String> {
+    interpreter.enter(|vm| {
+        let result = (|| -> PyResult<String> {
+            let protected_locals =
+                locals_ref
+                    .downcast_ref::<ProtectedLocals>()
+                    .ok_or_else(|| {
                         vm.new_type_error("locals is not a ProtectedLocals instance".to_string())
                     })?;
+            let value = protected_locals.dict().get_item(name, vm)?;
+            json::dumps(&value, vm)
+        })();
 
-                // Directly set item to internal dict, bypassing protection check
-                let dict = protected_locals.dict();
-                dict.as_object().set_item(name, python_obj, vm)?;
-
-                Ok(())
-            })();
-
-            match result {
-                Ok(_) => 0,
-                Err(exception) => {
-                    // Write error to error_buf if provided
-                    let mut error_string = String::new();
-                    match vm.write_exception(&mut error_string, &exception) {
-                        Ok(_) => (),
-                        Err(_) => {
-                            error_string.push_str("Failed to assign object: unknown error");
-                        }
-                    }
-                    if !error.is_null() {
-                        unsafe {
-                            *error = ioctl::pybox_bytes::new_bytes(error_string.as_bytes());
-                        }
-                    }
-                    -1
-                }
+        result.map_err(|exception| {
+            let mut error_string = String::new();
+            if traceback::write_exception(vm, &mut error_string, &exception).is_err() {
+                error_string.push_str("Failed to retrieve object: unknown error");
             }
+            error_string
         })
     })
 }
@@ -158,6 +561,79 @@ where
     result
 }
 
+/// with_split_output 分别记录的输出
+#[derive(Default)]
+pub struct SplitOutput {
+    pub stdout: String,
+    pub stderr: String,
+    /// 输出超过 max_output 被截断
+    pub truncated: bool,
+}
+
+/// 与 with_redirect_output 相同，另外分别记录 stdout 和 stderr 的内容
+/// stdout 和 stderr 合计超过 max_output 字节的部分被丢弃
+/// 无法创建 pybox._Tee 时退化为 with_redirect_output，stdout / stderr 保持为空
+pub fn with_split_output<F, R>(
+    vm: &VirtualMachine,
+    output: &mut String,
+    split: &mut SplitOutput,
+    max_output: Option<usize>,
+    f: F,
+) -> R
+where
+    F: FnOnce() -> R,
+{
+    let Ok((sys_module, merged, stdout_tee, stderr_tee, limit)) = (|| -> PyResult<_> {
+        let merged = vm
+            .import("_io", 0)?
+            .get_attr("StringIO", vm)?
+            .call((), vm)?;
+        let pybox_module = vm.import("pybox", 0)?;
+        let limit = pybox_module
+            .get_attr("_OutputLimit", vm)?
+            .call((max_output,), vm)?;
+        let tee_class = pybox_module.get_attr("_Tee", vm)?;
+        let live = vm.ctx.new_bool(STREAM_OUTPUT.get());
+        let stdout_tee =
+            tee_class.call((merged.clone(), "stdout", live.clone(), limit.clone()), vm)?;
+        let stderr_tee = tee_class.call((merged.clone(), "stderr", live, limit.clone()), vm)?;
+        Ok((vm.import("sys", 0)?, merged, stdout_tee, stderr_tee, limit))
+    })() else {
+        return with_redirect_output(vm, output, f);
+    };
+    let (Ok(original_stdout), Ok(original_stderr)) = (
+        sys_module.get_attr("stdout", vm),
+        sys_module.get_attr("stderr", vm),
+    ) else {
+        return with_redirect_output(vm, output, f);
+    };
+
+    let _ = sys_module.set_attr("stdout", stdout_tee.clone(), vm);
+    let _ = sys_module.set_attr("stderr", stderr_tee.clone(), vm);
+
+    let result = f();
+
+    let _ = sys_module.set_attr("stdout", original_stdout, vm);
+    let _ = sys_module.set_attr("stderr", original_stderr, vm);
+
+    for (stream, buffer) in [
+        (&merged, output),
+        (&stdout_tee, &mut split.stdout),
+        (&stderr_tee, &mut split.stderr),
+    ] {
+        if let Ok(content) = vm.call_method(stream, "getvalue", ())
+            && let Ok(content) = content.try_into_value::<String>(vm)
+        {
+            buffer.push_str(&content);
+        }
+    }
+    split.truncated = limit
+        .get_attr("truncated", vm)
+        .is_ok_and(|truncated| truncated.try_to_bool(vm).unwrap_or(false));
+
+    result
+}
+
 /// 在指定 locals 环境中执行 python 代码
 /// * `id` 指定 locals id
 /// * `code` python 代码
@@ -195,51 +671,87 @@ pub extern "C" fn pybox_exec(
         return -1;
     };
 
-    // Step 1: Get interpreter and locals (with read-only borrow)
-    // Clone them so we can release the borrow before executing Python code
-    let (interpreter, locals_ref) = match PYBOX_STATE.with_borrow(
-        |pybox_state| -> Result<(Rc<Interpreter>, rustpython_vm::PyObjectRef), &'static str> {
-            let Some((locals, interpreter)) = pybox_state.locals.get(id) else {
-                return Err("Local context not found");
-            };
-
-            // Clone Rc<Interpreter> and PyObjectRef (cheap, reference-counted)
-            Ok((interpreter.clone(), locals.clone()))
-        },
-    ) {
-        Ok(values) => values,
-        Err(err_msg) => {
-            if !error.is_null() {
-                unsafe {
-                    *error = ioctl::pybox_bytes::new_bytes(err_msg.as_bytes());
-                }
-            }
-            return -1;
+    // 找不到环境时按 PYBOX_EXEC_AUTO_CREATE 创建
+    let env = match PyboxEnv::open(id) {
+        None if EXEC_FLAGS.get() & PYBOX_EXEC_AUTO_CREATE != 0 => {
+            Some(PyboxBuilder::new().build(id))
         }
+        env => env,
+    };
+    let Some(env) = env else {
+        ioctl::pybox_bytes::write_to(error, b"Local context not found");
+        return -1;
     };
 
-    // Step 2: Execute code WITHOUT holding PYBOX_STATE lock
-    // This allows Python code to call pybox functions (like init_local_from) via JSON-RPC
+    let output_string = env.exec(code).output;
+    ioctl::pybox_bytes::write_to(output, output_string.as_bytes());
+    0
+}
+
+/// 在环境中执行代码，返回合并的输出 (stdout & stderr)，
+/// 结构化结果、耗时和是否抛出异常记录在本线程，见 pybox_exec_report
+///
+/// 执行期间不持有 PYBOX_STATE 的借用，python 代码可以通过 JSON-RPC 重入 pybox 接口（如 init_local_from）
+pub(crate) fn exec_in(
+    id: &str,
+    interpreter: &Rc<Interpreter>,
+    locals_ref: PyObjectRef,
+    code: &str,
+) -> String {
+    let _calling = CallingGuard::enter(id);
+    let _span = trace::span!("exec", env = id, code_bytes = code.len());
     interpreter.enter(|vm| {
         let mut output_string = String::new();
 
-        let code_obj = match vm.compile(&code, Mode::Exec, "<string>".to_owned()) {
+        let compile_start = Instant::now();
+        // BlockExpr 模式下代码以表达式结尾时返回它的值，用于 result_repr
+        let compiled = {
+            let _span = trace::span!("compile");
+            vm.compile(&code, Mode::BlockExpr, traceback::retain(vm, &code))
+        };
+        let compile_ns = compile_start.elapsed().as_nanos() as u64;
+        let limits = EXEC_LIMITS.get();
+        LAST_EXEC_TIMING.set((compile_ns, 0));
+        LAST_EXEC_RAISED.set(compiled.is_err());
+
+        let code_obj = match compiled {
             Ok(code_obj) => code_obj,
             Err(err) => {
                 // 处理编译错误
                 let exception = vm.new_syntax_error(&err, Some(&code));
-                match vm.write_exception(&mut output_string, &exception) {
+                match traceback::write_exception(vm, &mut output_string, &exception) {
                     Ok(_) => (),
                     Err(_) => {
                         output_string.push_str("Pybox: Compile Code Failed!");
                     }
                 }
-                if !output.is_null() {
-                    unsafe {
-                        *output = ioctl::pybox_bytes::new_bytes(output_string.as_bytes());
-                    }
+                let truncated = truncate_output(&mut output_string, limits.max_output);
+                set_exec_report(vm, "", "", Some(&exception), None, None, truncated, None);
+                return output_string;
+            }
+        };
+
+        // 沙箱中通过 pybox.lint 注册的 linter 在运行前检查代码，报告错误时不运行
+        let diagnostics = match run_linters(vm, &code) {
+            Ok(diagnostics) => diagnostics,
+            Err(exception) => {
+                LAST_EXEC_RAISED.set(true);
+                if traceback::write_exception(vm, &mut output_string, &exception).is_err() {
+                    output_string.push_str("Pybox: Lint Code Failed!");
                 }
-                return 0;
+                let diagnostics = exception.as_object().get_attr("diagnostics", vm).ok();
+                let truncated = truncate_output(&mut output_string, limits.max_output);
+                set_exec_report(
+                    vm,
+                    "",
+                    "",
+                    Some(&exception),
+                    None,
+                    None,
+                    truncated,
+                    diagnostics,
+                );
+                return output_string;
             }
         };
 
@@ -256,25 +768,193 @@ pub extern "C" fn pybox_exec(
             vm,
         );
 
-        match with_redirect_output(vm, &mut output_string, || vm.run_code_obj(code_obj, scope)) {
-            Ok(_) => (),
-            Err(exception) => {
-                match vm.write_exception(&mut output_string, &exception) {
-                    Ok(_) => (),
-                    Err(_) => {
-                        output_string.push_str("Pybox: Run Code Failed!");
-                    }
-                };
+        // 本次 exec 使用独立的 artifact 列表，嵌套的 exec 结束后恢复外层的列表
+        let pybox_module = vm.import("pybox", 0).ok();
+        let outer_artifacts = pybox_module.as_ref().and_then(|module| {
+            let outer = module.get_attr("_artifacts", vm).ok()?;
+            let artifacts = module
+                .get_attr("_Artifacts", vm)
+                .ok()?
+                .call((limits.max_artifacts, limits.max_artifact_bytes), vm)
+                .ok()?;
+            module.set_attr("_artifacts", artifacts, vm).ok()?;
+            Some(outer)
+        });
+
+        // 事务 exec 抛出异常时回滚到运行前的快照
+        let snapshot =
+            (EXEC_FLAGS.get() & PYBOX_EXEC_TRANSACTIONAL != 0).then(|| protected_locals.snapshot());
+
+        let run_start = Instant::now();
+        let mut split = SplitOutput::default();
+        let result = with_split_output(
+            vm,
+            &mut output_string,
+            &mut split,
+            limits.max_output,
+            || {
+                let _span = trace::span!("run");
+                interrupt::running(interpreter, || vm.run_code_obj(code_obj, scope))
+            },
+        );
+        // 嵌套的 exec 会覆盖计时、异常标记和结构化结果，结束时重新记录本次的结果
+        LAST_EXEC_TIMING.set((compile_ns, run_start.elapsed().as_nanos() as u64));
+        LAST_EXEC_RAISED.set(result.is_err());
+
+        if let (Some(snapshot), Err(_)) = (snapshot, &result) {
+            let _ = protected_locals.restore(snapshot, vm);
+        }
+
+        let artifacts = match (&pybox_module, outer_artifacts) {
+            (Some(module), Some(outer)) => {
+                let artifacts = module.get_attr("_artifacts", vm).ok();
+                let _ = module.set_attr("_artifacts", outer, vm);
+                artifacts
             }
+            _ => None,
         };
 
-        // write output to buffer
-        if !output.is_null() {
-            unsafe {
-                *output = ioctl::pybox_bytes::new_bytes(output_string.as_bytes());
-            }
+        if let Err(exception) = &result {
+            match traceback::write_exception(vm, &mut output_string, exception) {
+                Ok(_) => (),
+                Err(_) => {
+                    output_string.push_str("Pybox: Run Code Failed!");
+                }
+            };
         }
-        0
+
+        // traceback 也计入 max_output
+        let truncated = truncate_output(&mut output_string, limits.max_output)
+            | split.truncated
+            | artifacts.as_ref().is_some_and(|artifacts| {
+                artifacts
+                    .get_attr("truncated", vm)
+                    .is_ok_and(|truncated| truncated.try_to_bool(vm).unwrap_or(false))
+            });
+        let (exception, value) = match &result {
+            Ok(value) => (None, Some(value)),
+            Err(exception) => (Some(exception), None),
+        };
+        set_exec_report(
+            vm,
+            &split.stdout,
+            &split.stderr,
+            exception,
+            value,
+            artifacts,
+            truncated,
+            diagnostics,
+        );
+
+        output_string
+    })
+}
+
+/// 调用指定 locals 环境中的可调用对象, 参数和返回值都以 json 序列化
+///
+/// # Arguments
+///
+/// * `id` 指定 locals 环境 id
+/// * `name` 可调用对象的变量名
+/// * `args` 序列化的 json 参数 `{"args": [...], "kwargs": {...}}`
+/// * `result` 序列化的 json 返回值
+/// * `error` pybox 错误信息
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_call(
+    id: *const ioctl::pybox_bytes,
+    name: *const ioctl::pybox_bytes,
+    args: *const ioctl::pybox_bytes,
+    result: *mut *mut ioctl::pybox_bytes,
+    error: *mut *mut ioctl::pybox_bytes,
+) -> ssize_t {
+    if id.is_null() || name.is_null() || args.is_null() {
+        ioctl::pybox_bytes::write_to(error, b"Invalid arguments: id, name or args is null");
+        return -1;
+    }
+
+    let Ok((id, name, args)) = (|| -> Result<_, ()> {
+        unsafe {
+            let id = (*id).string()?;
+            let name = (*name).string()?;
+            let args = (*args).string()?;
+            Ok((id, name, args))
+        }
+    })() else {
+        ioctl::pybox_bytes::write_to(error, b"Invalid UTF-8 encoding in id, name or args");
+        return -1;
+    };
+
+    let Some(env) = PyboxEnv::open(id) else {
+        ioctl::pybox_bytes::write_to(error, b"Local context not found");
+        return -1;
+    };
+    match env.call_json(name, args) {
+        Ok(json_str) => {
+            ioctl::pybox_bytes::write_to(result, json_str.as_bytes());
+            0
+        }
+        Err(error_string) => {
+            ioctl::pybox_bytes::write_to(error, error_string.as_bytes());
+            -1
+        }
+    }
+}
+
+/// 调用环境中的可调用对象，参数和返回值都以 json 序列化，失败时返回 traceback
+///
+/// 和 exec_in 一样，调用期间不持有 PYBOX_STATE 的借用
+pub(crate) fn call_in(
+    id: &str,
+    interpreter: &Rc<Interpreter>,
+    locals_ref: PyObjectRef,
+    name: &str,
+    args: &str,
+) -> Result<String, String> {
+    let _calling = CallingGuard::enter(id);
+    let _span = trace::span!("call", env = id, name);
+    interpreter.enter(|vm| {
+        let call_result = (|| -> PyResult<String> {
+            let request = json::loads(args.as_bytes(), vm)?
+                .downcast::<PyDict>()
+                .map_err(|_| vm.new_type_error("call arguments must be a dict".to_string()))?;
+
+            let protected_locals =
+                locals_ref
+                    .downcast_ref::<ProtectedLocals>()
+                    .ok_or_else(|| {
+                        vm.new_type_error("locals is not a ProtectedLocals instance".to_string())
+                    })?;
+            let func = protected_locals.dict().get_item(name, vm)?;
+
+            // {"args": [...], "kwargs": {...}} -> FuncArgs
+            let positional = match request.get_item_opt("args", vm)? {
+                Some(positional) => positional.try_into_value::<Vec<PyObjectRef>>(vm)?,
+                None => Vec::new(),
+            };
+            let mut func_args = FuncArgs::from(positional);
+            if let Some(kwargs) = request.get_item_opt("kwargs", vm)? {
+                let kwargs = kwargs
+                    .downcast::<PyDict>()
+                    .map_err(|_| vm.new_type_error("kwargs must be a dict".to_string()))?;
+                for (key, value) in kwargs.into_iter() {
+                    func_args
+                        .kwargs
+                        .insert(key.try_into_value::<String>(vm)?, value);
+                }
+            }
+
+            let ret = interrupt::running(interpreter, || func.call(func_args, vm))?;
+
+            json::dumps(&ret, vm)
+        })();
+
+        call_result.map_err(|exception| {
+            let mut error_string = String::new();
+            if traceback::write_exception(vm, &mut error_string, &exception).is_err() {
+                error_string.push_str("Pybox: Call Failed!");
+            }
+            error_string
+        })
     })
 }
 
@@ -403,6 +1083,235 @@ print(test_continue)
         }
     }
 
+    #[test]
+    fn test_pybox_exec_timing() {
+        let id = ioctl::pybox_bytes::new_bytes(b"test_pybox_exec_timing");
+        let result = pybox_init_local(id);
+        assert_eq!(result, 0, "Failed to init local");
+
+        let code = ioctl::pybox_bytes::new_bytes(b"total = sum(range(10000))");
+        let result = pybox_exec(id, code, std::ptr::null_mut(), std::ptr::null_mut());
+        assert_eq!(result, 0, "Execution failed");
+        assert!(pybox_exec_compile_ns() > 0);
+        assert!(pybox_exec_run_ns() > 0);
+
+        // a compile error leaves the run time at 0
+        let code = ioctl::pybox_bytes::new_bytes(b"def (");
+        pybox_exec(id, code, std::ptr::null_mut(), std::ptr::null_mut());
+        assert_eq!(pybox_exec_run_ns(), 0);
+    }
+
+    #[test]
+    fn test_pybox_exec_raised() {
+        let id = ioctl::pybox_bytes::new_bytes(b"test_pybox_exec_raised");
+        let result = pybox_init_local(id);
+        assert_eq!(result, 0, "Failed to init local");
+
+        for (code, raised) in [
+            (&b"x = 1"[..], 0),
+            (b"raise ValueError('x')", 1),
+            (b"def (", 1),
+            (b"print(x)", 0),
+        ] {
+            let code = ioctl::pybox_bytes::new_bytes(code);
+            let result = pybox_exec(id, code, std::ptr::null_mut(), std::ptr::null_mut());
+            assert_eq!(result, 0, "Execution failed");
+            assert_eq!(pybox_exec_raised(), raised);
+        }
+    }
+
+    #[test]
+    fn test_pybox_exec_report() {
+        let id = ioctl::pybox_bytes::new_bytes(b"test_pybox_exec_report");
+        let result = pybox_init_local(id);
+        assert_eq!(result, 0, "Failed to init local");
+
+        let report = |code: &[u8]| {
+            let code = ioctl::pybox_bytes::new_bytes(code);
+            let result = pybox_exec(id, code, std::ptr::null_mut(), std::ptr::null_mut());
+            assert_eq!(result, 0, "Execution failed");
+            let mut report = std::ptr::null_mut();
+            assert_eq!(pybox_exec_report(&mut report), 0);
+            unsafe { (*report).string().unwrap().to_string() }
+        };
+
+        let json = report(
+            b"import sys, pybox\nprint('out')\nsys.stderr.write('err')\npybox.artifact('a.txt', 'hi')\n1 + 1",
+        );
+        assert!(json.contains(r#""stdout": "out\n""#), "{}", json);
+        assert!(json.contains(r#""stderr": "err""#), "{}", json);
+        assert!(json.contains(r#""result_repr": "2""#), "{}", json);
+        assert!(json.contains(r#""name": "a.txt""#), "{}", json);
+        assert!(json.contains(r#""exception": null"#), "{}", json);
+
+        let json = report(b"raise KeyError('missing')");
+        assert!(json.contains(r#""type": "KeyError""#), "{}", json);
+        assert!(json.contains(r#""result_repr": null"#), "{}", json);
+        assert!(json.contains(r#""artifacts": []"#), "{}", json);
+    }
+
+    #[test]
+    fn test_pybox_exec_lint() {
+        let id = ioctl::pybox_bytes::new_bytes(b"test_pybox_exec_lint");
+        let result = pybox_init_local(id);
+        assert_eq!(result, 0, "Failed to init local");
+
+        let report = |code: &[u8]| {
+            let code = ioctl::pybox_bytes::new_bytes(code);
+            let result = pybox_exec(id, code, std::ptr::null_mut(), std::ptr::null_mut());
+            assert_eq!(result, 0, "Execution failed");
+            let mut report = std::ptr::null_mut();
+            assert_eq!(pybox_exec_report(&mut report), 0);
+            unsafe { (*report).string().unwrap().to_string() }
+        };
+
+        let json = report(
+            br#"
+import ast, pybox
+
+@pybox.lint
+def no_eval(tree, code):
+    for node in ast.walk(tree):
+        if isinstance(node, ast.Call) and getattr(node.func, "id", None) == "eval":
+            yield {"message": "eval is not allowed", "line": node.lineno, "severity": "error"}
+        elif isinstance(node, ast.While):
+            yield {"message": "loop", "line": node.lineno}
+"#,
+        );
+        assert!(json.contains(r#""diagnostics": []"#), "{}", json);
+
+        // 警告不阻止执行
+        let json = report(b"i = 0\nwhile i < 2:\n    i += 1\nprint(i)");
+        assert!(json.contains(r#""stdout": "2\n""#), "{}", json);
+        assert!(json.contains(r#""rule": "no_eval""#), "{}", json);
+        assert_eq!(pybox_exec_raised(), 0);
+
+        // 错误时不运行代码
+        let json = report(b"ran = True\neval('1')");
+        assert!(json.contains(r#""type": "LintError""#), "{}", json);
+        assert!(json.contains("eval is not allowed"), "{}", json);
+        assert_eq!(pybox_exec_raised(), 1);
+        let json = report(b"'ran' in dir()");
+        assert!(json.contains(r#""result_repr": "False""#), "{}", json);
+    }
+
+    #[test]
+    fn test_pybox_exec_transactional() {
+        let id = ioctl::pybox_bytes::new_bytes(b"test_pybox_exec_transactional");
+        let result = pybox_init_local(id);
+        assert_eq!(result, 0, "Failed to init local");
+
+        let report = |code: &[u8]| {
+            let code = ioctl::pybox_bytes::new_bytes(code);
+            let result = pybox_exec(id, code, std::ptr::null_mut(), std::ptr::null_mut());
+            assert_eq!(result, 0, "Execution failed");
+            let mut report = std::ptr::null_mut();
+            assert_eq!(pybox_exec_report(&mut report), 0);
+            unsafe { (*report).string().unwrap().to_string() }
+        };
+
+        report(b"x = 1\nitems = []");
+        pybox_set_exec_flags(PYBOX_EXEC_TRANSACTIONAL);
+        let json = report(b"x = 2\ny = 3\ndel items\nraise ValueError('step failed')");
+        assert!(json.contains(r#""type": "ValueError""#), "{}", json);
+        let json = report(b"(x, 'y' in dir(), 'items' in dir())");
+        assert!(
+            json.contains(r#""result_repr": "(1, False, True)""#),
+            "{}",
+            json
+        );
+
+        // 成功时保留修改
+        report(b"x = 2");
+        pybox_set_exec_flags(0);
+        let json = report(b"x = 3\nraise ValueError()");
+        assert!(json.contains(r#""type": "ValueError""#), "{}", json);
+        let json = report(b"x");
+        assert!(json.contains(r#""result_repr": "3""#), "{}", json);
+    }
+
+    #[test]
+    fn test_pybox_exec_limits() {
+        let id = ioctl::pybox_bytes::new_bytes(b"test_pybox_exec_limits");
+        let result = pybox_init_local(id);
+        assert_eq!(result, 0, "Failed to init local");
+
+        let run = |code: &[u8]| {
+            let code = ioctl::pybox_bytes::new_bytes(code);
+            let mut output = std::ptr::null_mut();
+            let result = pybox_exec(id, code, &mut output, std::ptr::null_mut());
+            assert_eq!(result, 0, "Execution failed");
+            let mut report = std::ptr::null_mut();
+            assert_eq!(pybox_exec_report(&mut report), 0);
+            unsafe {
+                (
+                    (*output).string().unwrap().to_string(),
+                    (*report).string().unwrap().to_string(),
+                )
+            }
+        };
+
+        pybox_set_exec_limits(10, 1, 4);
+        let (output, json) = run(
+            b"import pybox\nprint('x' * 100)\npybox.artifact('a', 'abc')\npybox.artifact('b', 'd')",
+        );
+        assert_eq!(output, "x".repeat(10));
+        assert!(json.contains(r#""truncated": true"#), "{}", json);
+        assert!(json.contains(r#""name": "a""#), "{}", json);
+        assert!(!json.contains(r#""name": "b""#), "{}", json);
+
+        // 截断按字符边界
+        let (output, _) = run("print('é' * 10)".as_bytes());
+        assert_eq!(output, "é".repeat(5));
+
+        pybox_set_exec_limits(-1, -1, -1);
+        let (output, json) = run(b"print('x' * 100)");
+        assert_eq!(output.len(), 101);
+        assert!(json.contains(r#""truncated": false"#), "{}", json);
+    }
+
+    #[test]
+    fn test_pybox_exec_auto_create() {
+        let id = ioctl::pybox_bytes::new_bytes(b"test_pybox_exec_auto_create");
+        let code = ioctl::pybox_bytes::new_bytes(b"print('created')");
+        let mut output = std::ptr::null_mut();
+        let mut error = std::ptr::null_mut();
+        assert_eq!(pybox_exec(id, code, &mut output, &mut error), -1);
+        assert_eq!(
+            unsafe { (*error).string().unwrap() },
+            "Local context not found"
+        );
+
+        pybox_set_exec_flags(PYBOX_EXEC_AUTO_CREATE);
+        let result = pybox_exec(id, code, &mut output, std::ptr::null_mut());
+        pybox_set_exec_flags(0);
+        assert_eq!(result, 0, "Execution failed");
+        assert_eq!(unsafe { (*output).string().unwrap() }, "created\n");
+        assert!(clone_local("test_pybox_exec_auto_create").is_ok());
+    }
+
+    #[test]
+    fn test_calling_context() {
+        assert!(calling_context().is_none());
+        let correlation_id = ioctl::pybox_bytes::new_bytes(b"req-1");
+        pybox_set_correlation_id(correlation_id);
+        {
+            let _outer = CallingGuard::enter("outer");
+            pybox_set_correlation_id(std::ptr::null());
+            let _inner = CallingGuard::enter("inner");
+            assert_eq!(calling_context(), Some(("inner".to_string(), None)));
+        }
+        assert!(calling_context().is_none());
+
+        pybox_set_correlation_id(correlation_id);
+        let _calling = CallingGuard::enter("outer");
+        pybox_set_correlation_id(std::ptr::null());
+        assert_eq!(
+            calling_context(),
+            Some(("outer".to_string(), Some("req-1".to_string())))
+        );
+    }
+
     #[test]
     fn test_pybox_assign() {
         let id = ioctl::pybox_bytes::new_bytes(b"test_pybox_assign");
@@ -474,4 +1383,80 @@ print(f"complex_obj = {complex_obj}")
             );
         }
     }
+
+    #[test]
+    fn test_pybox_assign_buffer() {
+        let id = ioctl::pybox_bytes::new_bytes(b"test_pybox_assign_buffer");
+        let result = pybox_init_local(id);
+        assert_eq!(result, 0, "Failed to init local");
+
+        for (name, memoryview) in [(&b"raw"[..], 0), (&b"view"[..], 1)] {
+            let buffer = mem::pybox_buffer_alloc(5);
+            unsafe { std::ptr::copy_nonoverlapping(b"\x00\x01abc".as_ptr(), buffer, 5) };
+            let name = ioctl::pybox_bytes::new_bytes(name);
+            let result = pybox_assign_buffer(id, name, buffer, 5, memoryview, std::ptr::null_mut());
+            assert_eq!(result, 0, "Failed to assign buffer");
+
+            // the buffer is consumed by the first assignment
+            let result = pybox_assign_buffer(id, name, buffer, 5, memoryview, std::ptr::null_mut());
+            assert_eq!(result, -1, "Should fail when the buffer is reused");
+        }
+
+        let code = ioctl::pybox_bytes::new_bytes(
+            b"print(type(raw).__name__, raw)\nprint(type(view).__name__, view.tobytes())",
+        );
+        let output_buf = pybox_alloc_mem(std::mem::size_of::<*mut ioctl::pybox_bytes>())
+            as *mut *mut ioctl::pybox_bytes;
+        let result = pybox_exec(id, code, output_buf, std::ptr::null_mut());
+        assert_eq!(result, 0, "Execution failed");
+        unsafe {
+            let output = (**output_buf).string().unwrap();
+            assert!(output.contains("bytes b'\\x00\\x01abc'"), "{}", output);
+            assert!(output.contains("memoryview b'\\x00\\x01abc'"), "{}", output);
+        }
+    }
+
+    #[test]
+    fn test_pybox_retrieve_and_call() {
+        let id = ioctl::pybox_bytes::new_bytes(b"test_pybox_retrieve_and_call");
+        let result = pybox_init_local(id);
+        assert_eq!(result, 0, "Failed to init local");
+
+        let code = ioctl::pybox_bytes::new_bytes(
+            r#"
+value = {"a": [1, 2]}
+
+def add(x, y=1):
+    return x + y
+"#
+            .as_bytes(),
+        );
+        let result = pybox_exec(id, code, std::ptr::null_mut(), std::ptr::null_mut());
+        assert_eq!(result, 0, "Execution failed");
+
+        let out_buf = pybox_alloc_mem(std::mem::size_of::<*mut ioctl::pybox_bytes>())
+            as *mut *mut ioctl::pybox_bytes;
+
+        // Test 1: retrieve a variable as json
+        let name = ioctl::pybox_bytes::new_bytes(b"value");
+        let result = pybox_retrieve(id, name, out_buf, std::ptr::null_mut());
+        assert_eq!(result, 0, "Failed to retrieve variable");
+        unsafe {
+            assert_eq!((**out_buf).string().unwrap(), r#"{"a": [1, 2]}"#);
+        }
+
+        // Test 2: call a guest function with json args
+        let name = ioctl::pybox_bytes::new_bytes(b"add");
+        let args = ioctl::pybox_bytes::new_bytes(br#"{"args": [40], "kwargs": {"y": 2}}"#);
+        let result = pybox_call(id, name, args, out_buf, std::ptr::null_mut());
+        assert_eq!(result, 0, "Failed to call guest function");
+        unsafe {
+            assert_eq!((**out_buf).string().unwrap(), "42");
+        }
+
+        // Test 3: missing variable fails
+        let name = ioctl::pybox_bytes::new_bytes(b"missing");
+        let result = pybox_retrieve(id, name, out_buf, std::ptr::null_mut());
+        assert_eq!(result, -1, "Should fail when variable doesn't exist");
+    }
 }
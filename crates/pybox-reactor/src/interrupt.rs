@@ -0,0 +1,113 @@
+//! interrupt crate 将 host 的 KeyboardInterrupt 传递给正在执行的 python 代码
+//!
+//! 每个 interpreter 在创建时注册一个 user signal channel，pybox_exec / pybox_call 执行期间
+//! 将 interpreter 压入执行栈；host 在 guest 执行中途调用 pybox_interrupt，向栈顶的 interpreter
+//! 发送信号，python 代码在下一次检查信号时抛出 KeyboardInterrupt
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use libc::ssize_t;
+
+use rustpython_vm::{Interpreter, signal::UserSignalSender};
+
+thread_local! {
+    /// interpreter 地址 -> (interpreter, 信号发送端)
+    static SENDERS: RefCell<HashMap<usize, (Weak<Interpreter>, UserSignalSender)>> =
+        RefCell::new(HashMap::new());
+    /// 正在执行代码的 interpreter 的信号发送端，嵌套的 exec 在栈顶
+    static RUNNING: RefCell<Vec<UserSignalSender>> = const { RefCell::new(Vec::new()) };
+}
+
+/// 记录 interpreter 的信号发送端，同时清理已释放的 interpreter
+pub fn register(interpreter: &Rc<Interpreter>, sender: UserSignalSender) {
+    SENDERS.with_borrow_mut(|senders| {
+        senders.retain(|_, (interpreter, _)| interpreter.strong_count() > 0);
+        senders.insert(
+            Rc::as_ptr(interpreter) as usize,
+            (Rc::downgrade(interpreter), sender),
+        );
+    });
+}
+
+/// 在 interpreter 上执行 f，执行期间 pybox_interrupt 会中断该 interpreter
+pub fn running<F, R>(interpreter: &Rc<Interpreter>, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let sender = SENDERS.with_borrow(|senders| {
+        senders
+            .get(&(Rc::as_ptr(interpreter) as usize))
+            .map(|(_, sender)| sender.clone())
+    });
+    let Some(sender) = sender else {
+        return f();
+    };
+
+    RUNNING.with_borrow_mut(|running| running.push(sender));
+    let result = f();
+    RUNNING.with_borrow_mut(|running| running.pop());
+    result
+}
+
+/// 在正在执行的 python 代码中抛出 KeyboardInterrupt
+///
+/// 由 host 在 guest 执行中途调用（epoch 中断回调），只发送信号，不进入 python 代码
+/// 返回 0 表示已发送，-1 表示没有正在执行的代码
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_interrupt() -> ssize_t {
+    let sent = RUNNING.with_borrow(|running| {
+        running.last().is_some_and(|sender| {
+            sender
+                .send(Box::new(|vm| {
+                    Err(vm.new_exception_empty(vm.ctx.exceptions.keyboard_interrupt.to_owned()))
+                }))
+                .is_ok()
+        })
+    });
+    if sent { 0 } else { -1 }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ioctl;
+    use crate::mem::pybox_alloc_mem;
+    use crate::pybox_init_local;
+
+    use super::*;
+
+    #[test]
+    fn test_pybox_interrupt() {
+        // 没有正在执行的代码
+        assert_eq!(pybox_interrupt(), -1);
+
+        let id = ioctl::pybox_bytes::new_bytes(b"test_pybox_interrupt");
+        assert_eq!(pybox_init_local(id), 0);
+
+        // host 在 epoch 中断回调中调用 pybox_interrupt，这里在执行代码前直接调用
+        let (interpreter, _) = crate::exec::clone_local("test_pybox_interrupt").unwrap();
+        let interrupted = running(&interpreter, || {
+            assert_eq!(pybox_interrupt(), 0);
+            interpreter.enter(|vm| {
+                vm.run_code_string(
+                    vm.new_scope_with_builtins(),
+                    "for i in range(1000): pass",
+                    "<test>".to_owned(),
+                )
+                .is_err_and(|err| err.fast_isinstance(vm.ctx.exceptions.keyboard_interrupt))
+            })
+        });
+        assert!(interrupted);
+
+        // 中断只作用于一次执行
+        let code = ioctl::pybox_bytes::new_bytes(b"print('after')");
+        let output_buf = pybox_alloc_mem(std::mem::size_of::<*mut ioctl::pybox_bytes>());
+        let output = output_buf as *mut *mut ioctl::pybox_bytes;
+        assert_eq!(
+            crate::exec::pybox_exec(id, code, output, std::ptr::null_mut()),
+            0
+        );
+        assert_eq!(unsafe { (**output).string().unwrap() }, "after\n");
+    }
+}
@@ -1,6 +1,6 @@
 //! ioctl.rs implement pybox communicate with host
 
-use libc::{c_void, size_t, ssize_t};
+use libc::{c_void, size_t};
 
 #[repr(C, packed)]
 pub struct pybox_bytes {
@@ -28,6 +28,15 @@ impl pybox_bytes {
         }
     }
 
+    /// 分配新的 pybox_bytes 并写入输出指针, 输出指针为空时忽略
+    pub fn write_to(out: *mut *mut Self, bytes: &[u8]) {
+        if !out.is_null() {
+            unsafe {
+                *out = Self::new_bytes(bytes);
+            }
+        }
+    }
+
     pub fn string(&self) -> Result<&str, ()> {
         unsafe {
             let slice = std::slice::from_raw_parts(self.data.as_ptr(), self.length);
@@ -45,21 +54,116 @@ pub struct pybox_ioctl_packet {
     pub buf_len: size_t,
 }
 
-#[cfg(target_arch = "wasm32")]
+#[cfg(all(target_arch = "wasm32", not(feature = "stdio")))]
 unsafe extern "C" {
     pub fn pybox_ioctl_host_req_impl(
         handle: size_t,
         req: *mut pybox_ioctl_packet,
         resp: *mut pybox_ioctl_packet,
-    ) -> ssize_t;
+    ) -> libc::ssize_t;
+}
+
+/// 系统 handle：拉取 host 暂存的响应，请求 packet 指向 guest 分配的缓冲区，host 把响应写入其中
+const RESPONSE_HANDLE: size_t = crate::codec::SYSTEM_HANDLE_BASE + 7;
+
+/// 导出 guest 使用的 ioctl 约定版本，host 加载时检查
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_protocol_version() -> u32 {
+    crate::codec::PROTOCOL_VERSION
 }
 
-#[cfg(not(target_arch = "wasm32"))]
+/// 调用 host 的 pybox_ioctl_host_req_impl 导入
+#[cfg(all(target_arch = "wasm32", not(feature = "stdio")))]
+fn ioctl_raw(
+    handle: size_t,
+    req: &mut pybox_ioctl_packet,
+    resp: &mut pybox_ioctl_packet,
+) -> libc::ssize_t {
+    unsafe { pybox_ioctl_host_req_impl(handle, req as *mut _, resp as *mut _) }
+}
+
+/// mock 和 stdio 模式下为本地实现
+#[cfg(any(not(target_arch = "wasm32"), feature = "stdio"))]
+fn ioctl_raw(
+    handle: size_t,
+    req: &mut pybox_ioctl_packet,
+    resp: &mut pybox_ioctl_packet,
+) -> libc::ssize_t {
+    pybox_ioctl_host_req_impl(handle, req as *mut _, resp as *mut _)
+}
+
+/// 调用 host ioctl，返回 (是否成功, 响应数据)
+///
+/// host 使用 pybox_alloc_mem 分配响应缓冲区，拷贝后在这里释放；
+/// host 不能在 ioctl 中调用 pybox_alloc_mem（如 Pulley 解释器）时响应暂存在 host，
+/// resp 中写入 `{NULL, len}`，由 guest 分配缓冲区，再以 RESPONSE_HANDLE 拉取响应
+pub fn ioctl_host(handle: size_t, data: &[u8]) -> (bool, Vec<u8>) {
+    use crate::mem::pybox_free_mem;
+
+    let _span = crate::trace::span!("ioctl", handle, request_bytes = data.len());
+
+    // Prepare request packet
+    let mut req = pybox_ioctl_packet {
+        buf: data.as_ptr() as *mut _,
+        buf_len: data.len(),
+    };
+
+    // Prepare response packet (host will allocate buffer)
+    let mut resp = pybox_ioctl_packet {
+        buf: std::ptr::null_mut(),
+        buf_len: 0,
+    };
+
+    // Call the host ioctl implementation
+    let success = ioctl_raw(handle, &mut req, &mut resp) == 0;
+
+    if resp.buf.is_null() {
+        if resp.buf_len == 0 {
+            return (success, Vec::new());
+        }
+        return match take_pending(resp.buf_len) {
+            Some(data) => (success, data),
+            None => (false, Vec::new()),
+        };
+    }
+
+    // Copy data from host buffer, then free the host-allocated buffer
+    let data = if resp.buf_len > 0 {
+        unsafe { std::slice::from_raw_parts(resp.buf as *const u8, resp.buf_len).to_vec() }
+    } else {
+        Vec::new()
+    };
+    pybox_free_mem(resp.buf);
+    (success, data)
+}
+
+/// 拉取 host 暂存的 len 字节响应
+fn take_pending(len: size_t) -> Option<Vec<u8>> {
+    let mut data = vec![0u8; len];
+    let mut req = pybox_ioctl_packet {
+        buf: data.as_mut_ptr() as *mut _,
+        buf_len: len,
+    };
+    let mut resp = pybox_ioctl_packet {
+        buf: std::ptr::null_mut(),
+        buf_len: 0,
+    };
+    if ioctl_raw(RESPONSE_HANDLE, &mut req, &mut resp) != 0 {
+        return None;
+    }
+    Some(data)
+}
+
+/// stdio 模式下没有 host 导入，请求经 stdin / stdout 转发
+#[cfg(feature = "stdio")]
+pub use crate::stdio::pybox_ioctl_host_req_impl;
+
+#[cfg(all(not(target_arch = "wasm32"), not(feature = "stdio")))]
 pub fn pybox_ioctl_host_req_impl(
     handle: size_t,
     req: *mut pybox_ioctl_packet,
     resp: *mut pybox_ioctl_packet,
-) -> ssize_t {
+) -> libc::ssize_t {
     // mock
     let _ = handle;
     let _ = req;
@@ -0,0 +1,462 @@
+//! json.rs JSON-RPC 请求与响应的编解码，代替每次调用都经过 json 模块的 dumps / loads
+//!
+//! 输出与 json.dumps 兼容：整数按十进制原样输出，非有限浮点数输出为 NaN / Infinity / -Infinity，
+//! dict 的 str / int / float / bool / None 键转换为字符串；解析响应使用 serde_json，
+//! serde_json 不接受的非标准 token (NaN / Infinity) 交给 json.loads
+//!
+//! host 通过 pybox_set_codec 选择非有限浮点数和大整数的编码方式，assign / retrieve / call / json_rpc
+//! 两端使用相同的选项；`{"__pybox_float__": "nan" | "inf" | "-inf"}` 始终解码为浮点数，
+//! `{"__pybox_int__": "<十进制>"}` 始终解码为整数；
+//! host 注册的自定义类型编码为 `{"__pybox_type__": "<类型名>", "value": ...}`，由 pybox._decode_type 解码
+
+use std::cell::Cell;
+
+use rustpython_vm::{
+    AsObject, PyObject, PyObjectRef, PyResult, VirtualMachine,
+    builtins::{PyDict, PyFloat, PyInt, PyList, PyStr, PyTuple},
+};
+use serde_json::Value;
+
+/// 标记非有限浮点数的键
+pub const FLOAT_TAG: &str = "__pybox_float__";
+/// 标记超出 64 位的整数的键，值为十进制字符串
+pub const INT_TAG: &str = "__pybox_int__";
+/// 标记自定义类型的键，值为类型名，"value" 为编码后的数据
+pub const TYPE_TAG: &str = "__pybox_type__";
+/// pybox_set_codec 中启用大整数标记的位
+const BIG_INT_FLAG: i32 = 0b100;
+
+/// 非有限浮点数 (nan / inf / -inf) 的编码方式
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NonFinite {
+    /// 与 json.dumps 相同，输出非标准的 NaN / Infinity / -Infinity
+    Allow,
+    /// 抛出 ValueError
+    Error,
+    /// 输出 null
+    Null,
+    /// 输出 `{"__pybox_float__": "nan"}`，另一端解码为浮点数
+    Tag,
+}
+
+thread_local! {
+    static NON_FINITE: Cell<NonFinite> = const { Cell::new(NonFinite::Allow) };
+    static TAG_BIG_INTS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// 设置编码选项，由 host 在 exec / retrieve / call 前设置
+/// * `options` 低两位为非有限浮点数的编码方式：0 Allow，1 Error，2 Null，3 Tag；
+///   第 3 位为 1 时超出 64 位的整数编码为 `{"__pybox_int__": "<十进制>"}`，
+///   避免以 f64 解析数字的 host 丢失精度
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_set_codec(options: i32) {
+    NON_FINITE.set(match options & 0b11 {
+        1 => NonFinite::Error,
+        2 => NonFinite::Null,
+        3 => NonFinite::Tag,
+        _ => NonFinite::Allow,
+    });
+    TAG_BIG_INTS.set(options & BIG_INT_FLAG != 0);
+}
+
+/// 以当前的编码选项将对象编码为 JSON
+pub fn dumps(obj: &PyObject, vm: &VirtualMachine) -> PyResult<String> {
+    let mut writer = JsonWriter::new(vm);
+    writer.value(obj)?;
+    Ok(writer.finish())
+}
+
+/// 将 Python 对象写为 JSON 文本
+pub struct JsonWriter<'vm> {
+    vm: &'vm VirtualMachine,
+    out: String,
+}
+
+impl<'vm> JsonWriter<'vm> {
+    pub fn new(vm: &'vm VirtualMachine) -> Self {
+        Self {
+            vm,
+            out: String::new(),
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+
+    /// 原样写入，调用方保证是合法的 JSON 片段
+    pub fn raw(&mut self, text: &str) {
+        self.out.push_str(text);
+    }
+
+    /// 写入转义后的字符串
+    pub fn str(&mut self, text: &str) {
+        self.out.push('"');
+        for ch in text.chars() {
+            match ch {
+                '"' => self.out.push_str("\\\""),
+                '\\' => self.out.push_str("\\\\"),
+                '\n' => self.out.push_str("\\n"),
+                '\r' => self.out.push_str("\\r"),
+                '\t' => self.out.push_str("\\t"),
+                '\u{08}' => self.out.push_str("\\b"),
+                '\u{0c}' => self.out.push_str("\\f"),
+                ch if (ch as u32) < 0x20 => {
+                    self.out.push_str(&format!("\\u{:04x}", ch as u32));
+                }
+                ch => self.out.push(ch),
+            }
+        }
+        self.out.push('"');
+    }
+
+    fn float(&mut self, value: f64) -> PyResult<()> {
+        if value.is_finite() {
+            // Debug 格式保留小数点 (1.0)，按 json 解析时仍是浮点数
+            self.out.push_str(&format!("{:?}", value));
+            return Ok(());
+        }
+        let (token, name) = match value {
+            value if value.is_nan() => ("NaN", "nan"),
+            value if value > 0.0 => ("Infinity", "inf"),
+            _ => ("-Infinity", "-inf"),
+        };
+        match NON_FINITE.get() {
+            NonFinite::Allow => self.out.push_str(token),
+            NonFinite::Error => {
+                return Err(self.vm.new_value_error(format!(
+                    "Out of range float values are not JSON compliant: {}",
+                    name
+                )));
+            }
+            NonFinite::Null => self.out.push_str("null"),
+            NonFinite::Tag => {
+                self.out.push('{');
+                self.str(FLOAT_TAG);
+                self.out.push(':');
+                self.str(name);
+                self.out.push('}');
+            }
+        }
+        Ok(())
+    }
+
+    /// 写入一个可以被 json.dumps 序列化的对象
+    pub fn value(&mut self, obj: &PyObject) -> PyResult<()> {
+        let vm = self.vm;
+        if vm.is_none(obj) {
+            self.out.push_str("null");
+        } else if obj.fast_isinstance(vm.ctx.types.bool_type) {
+            self.out.push_str(if obj.is(&vm.ctx.true_value) {
+                "true"
+            } else {
+                "false"
+            });
+        } else if let Some(int) = obj.downcast_ref::<PyInt>() {
+            let digits = int.as_bigint().to_string();
+            if TAG_BIG_INTS.get() && i64::try_from(int.as_bigint()).is_err() {
+                self.out.push('{');
+                self.str(INT_TAG);
+                self.out.push(':');
+                self.str(&digits);
+                self.out.push('}');
+            } else {
+                self.out.push_str(&digits);
+            }
+        } else if let Some(float) = obj.downcast_ref::<PyFloat>() {
+            self.float(float.to_f64())?;
+        } else if let Some(text) = obj.downcast_ref::<PyStr>() {
+            self.str(text.as_str());
+        } else if let Some(list) = obj.downcast_ref::<PyList>() {
+            let items = list.borrow_vec().to_vec();
+            self.array(&items)?;
+        } else if let Some(tuple) = obj.downcast_ref::<PyTuple>() {
+            self.array(tuple.as_slice())?;
+        } else if let Ok(dict) = obj.to_owned().downcast::<PyDict>() {
+            vm.with_recursion(" while encoding a JSON object", || {
+                self.out.push('{');
+                for (index, (key, value)) in dict.into_iter().enumerate() {
+                    if index > 0 {
+                        self.out.push(',');
+                    }
+                    self.key(&key)?;
+                    self.out.push(':');
+                    self.value(&value)?;
+                }
+                self.out.push('}');
+                Ok(())
+            })?;
+        } else {
+            return Err(vm.new_type_error(format!(
+                "Object of type {} is not JSON serializable",
+                obj.class().name()
+            )));
+        }
+        Ok(())
+    }
+
+    fn array(&mut self, items: &[PyObjectRef]) -> PyResult<()> {
+        let vm = self.vm;
+        vm.with_recursion(" while encoding a JSON object", || {
+            self.out.push('[');
+            for (index, item) in items.iter().enumerate() {
+                if index > 0 {
+                    self.out.push(',');
+                }
+                self.value(item)?;
+            }
+            self.out.push(']');
+            Ok(())
+        })
+    }
+
+    /// json.dumps 将 str 以外的基本类型键转换为字符串
+    fn key(&mut self, key: &PyObject) -> PyResult<()> {
+        let vm = self.vm;
+        if let Some(text) = key.downcast_ref::<PyStr>() {
+            self.str(text.as_str());
+            return Ok(());
+        }
+        let mut key_writer = JsonWriter::new(vm);
+        let is_scalar = vm.is_none(key)
+            || key.downcast_ref::<PyInt>().is_some()
+            || key.downcast_ref::<PyFloat>().is_some();
+        if !is_scalar {
+            return Err(vm.new_type_error(format!(
+                "keys must be str, int, float, bool or None, not {}",
+                key.class().name()
+            )));
+        }
+        key_writer.value(key)?;
+        self.str(&key_writer.finish());
+        Ok(())
+    }
+}
+
+/// 解析 JSON 文本为 Python 对象
+pub fn loads(data: &[u8], vm: &VirtualMachine) -> PyResult {
+    match serde_json::from_slice::<Value>(data) {
+        Ok(value) if !lossy(&value) => to_py(value, vm),
+        // 非标准的 NaN / Infinity 和超出 64 位的整数等由 json 模块解析
+        _ => {
+            let text = std::str::from_utf8(data)
+                .map_err(|e| vm.new_value_error(format!("Invalid UTF-8 in JSON: {}", e)))?;
+            vm.import("json", 0)?
+                .get_attr("loads", vm)?
+                .call((vm.ctx.new_str(text),), vm)
+        }
+    }
+}
+
+/// serde_json 将超出 64 位的整数解析为 f64，此时需要由 json 模块重新解析
+fn lossy(value: &Value) -> bool {
+    match value {
+        Value::Number(number) => number.as_f64().is_some_and(|value| {
+            number.is_f64() && value.fract() == 0.0 && value.abs() >= 2f64.powi(63)
+        }),
+        Value::Array(items) => items.iter().any(lossy),
+        Value::Object(entries) => entries.values().any(lossy),
+        _ => false,
+    }
+}
+
+fn to_py(value: Value, vm: &VirtualMachine) -> PyResult {
+    Ok(match value {
+        Value::Null => vm.ctx.none(),
+        Value::Bool(value) => vm.ctx.new_bool(value).into(),
+        Value::Number(number) => match (number.as_i64(), number.as_u64()) {
+            (Some(value), _) => vm.ctx.new_int(value).into(),
+            (None, Some(value)) => vm.ctx.new_int(value).into(),
+            _ => vm.ctx.new_float(number.as_f64().unwrap_or(f64::NAN)).into(),
+        },
+        Value::String(value) => vm.ctx.new_str(value).into(),
+        Value::Array(items) => {
+            let items = items
+                .into_iter()
+                .map(|item| to_py(item, vm))
+                .collect::<PyResult<Vec<_>>>()?;
+            vm.ctx.new_list(items).into()
+        }
+        Value::Object(entries) if entries.len() == 1 && entries.contains_key(FLOAT_TAG) => {
+            let value = match entries[FLOAT_TAG].as_str() {
+                Some("nan") => f64::NAN,
+                Some("inf") => f64::INFINITY,
+                Some("-inf") => f64::NEG_INFINITY,
+                _ => return Err(vm.new_value_error(format!("Invalid {} value", FLOAT_TAG))),
+            };
+            vm.ctx.new_float(value).into()
+        }
+        Value::Object(entries) if entries.len() == 1 && entries.contains_key(INT_TAG) => {
+            let digits = entries[INT_TAG]
+                .as_str()
+                .ok_or_else(|| vm.new_value_error(format!("Invalid {} value", INT_TAG)))?;
+            vm.ctx
+                .types
+                .int_type
+                .as_object()
+                .call((vm.ctx.new_str(digits),), vm)?
+        }
+        Value::Object(mut entries)
+            if entries.len() == 2
+                && entries.get(TYPE_TAG).is_some_and(Value::is_string)
+                && entries.contains_key("value") =>
+        {
+            let name = entries.remove(TYPE_TAG).unwrap_or_default();
+            let data = to_py(entries.remove("value").unwrap_or_default(), vm)?;
+            vm.import("pybox", 0)?.get_attr("_decode_type", vm)?.call(
+                (vm.ctx.new_str(name.as_str().unwrap_or_default()), data),
+                vm,
+            )?
+        }
+        Value::Object(entries) => {
+            let dict = vm.ctx.new_dict();
+            for (key, value) in entries {
+                dict.set_item(key.as_str(), to_py(value, vm)?, vm)?;
+            }
+            dict.into()
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use rustpython_vm::compiler::Mode;
+
+    use super::*;
+    use crate::pybox_new_interpreter;
+
+    #[test]
+    fn test_json_round_trip() {
+        let interpreter = pybox_new_interpreter();
+        interpreter.enter(|vm| {
+            let eval = |source: &str| {
+                let code = vm.compile(source, Mode::Eval, "<test>".to_owned()).unwrap();
+                vm.run_code_obj(code, vm.new_scope_with_builtins()).unwrap()
+            };
+            let value = eval(r#"{"s": "a\"b\né", "f": 1.0, "b": [True, None, (1, 2)], 3: {}}"#);
+            let text = dumps(&value, vm).unwrap();
+            assert_eq!(
+                text,
+                r#"{"s":"a\"b\né","f":1.0,"b":[true,null,[1,2]],"3":{}}"#
+            );
+
+            // 与 json.loads 的结果相同
+            let expected = vm
+                .import("json", 0)
+                .unwrap()
+                .get_attr("loads", vm)
+                .unwrap()
+                .call((vm.ctx.new_str(text.as_str()),), vm)
+                .unwrap();
+            let loaded = loads(text.as_bytes(), vm).unwrap();
+            assert_eq!(
+                loaded.repr(vm).unwrap().as_str(),
+                expected.repr(vm).unwrap().as_str()
+            );
+
+            // 整数按十进制原样输出
+            assert_eq!(
+                dumps(&eval("2 ** 70"), vm).unwrap(),
+                "1180591620717411303424"
+            );
+            // 非标准 token 交给 json.loads
+            let nan = loads(b"[NaN]", vm).unwrap();
+            assert_eq!(nan.repr(vm).unwrap().as_str(), "[nan]");
+            assert!(dumps(&eval("b'x'"), vm).is_err());
+        });
+    }
+
+    #[test]
+    fn test_json_non_finite() {
+        let interpreter = pybox_new_interpreter();
+        interpreter.enter(|vm| {
+            let values: PyObjectRef = vm
+                .ctx
+                .new_list(vec![
+                    vm.ctx.new_float(f64::NAN).into(),
+                    vm.ctx.new_float(f64::NEG_INFINITY).into(),
+                    vm.ctx.new_float(0.5).into(),
+                ])
+                .into();
+
+            assert_eq!(dumps(&values, vm).unwrap(), "[NaN,-Infinity,0.5]");
+            pybox_set_codec(1);
+            assert!(dumps(&values, vm).is_err());
+            pybox_set_codec(2);
+            assert_eq!(dumps(&values, vm).unwrap(), "[null,null,0.5]");
+            pybox_set_codec(3);
+            let text = dumps(&values, vm).unwrap();
+            pybox_set_codec(0);
+            assert_eq!(
+                text,
+                r#"[{"__pybox_float__":"nan"},{"__pybox_float__":"-inf"},0.5]"#
+            );
+
+            // 标记始终解码为浮点数
+            let loaded = loads(text.as_bytes(), vm).unwrap();
+            assert_eq!(loaded.repr(vm).unwrap().as_str(), "[nan, -inf, 0.5]");
+        });
+    }
+
+    #[test]
+    fn test_json_type_tag() {
+        let interpreter = pybox_new_interpreter();
+        interpreter.enter(|vm| {
+            let text = br#"[{"__pybox_type__": "decimal.Decimal", "value": "1.10"}]"#;
+            let err = loads(text, vm).unwrap_err();
+            assert!(
+                err.as_object()
+                    .str(vm)
+                    .unwrap()
+                    .as_str()
+                    .contains("no decoder")
+            );
+
+            vm.import("pybox", 0)
+                .unwrap()
+                .get_attr("_register_decoder", vm)
+                .unwrap()
+                .call(
+                    (
+                        "decimal.Decimal",
+                        "import decimal\ndef decode(data):\n    return decimal.Decimal(data)\n",
+                    ),
+                    vm,
+                )
+                .unwrap();
+            let loaded = loads(text, vm).unwrap();
+            assert_eq!(loaded.repr(vm).unwrap().as_str(), "[Decimal('1.10')]");
+            // 其他键不同的对象不是标记
+            let loaded = loads(br#"{"__pybox_type__": "x", "data": 1}"#, vm).unwrap();
+            assert!(loaded.downcast_ref::<PyDict>().is_some());
+        });
+    }
+
+    #[test]
+    fn test_json_big_int() {
+        let interpreter = pybox_new_interpreter();
+        interpreter.enter(|vm| {
+            let code = vm
+                .compile("[2 ** 70, -2 ** 63, 7]", Mode::Eval, "<test>".to_owned())
+                .unwrap();
+            let values = vm.run_code_obj(code, vm.new_scope_with_builtins()).unwrap();
+
+            pybox_set_codec(BIG_INT_FLAG);
+            let text = dumps(&values, vm).unwrap();
+            pybox_set_codec(0);
+            assert_eq!(
+                text,
+                r#"[{"__pybox_int__":"1180591620717411303424"},-9223372036854775808,7]"#
+            );
+            let loaded = loads(text.as_bytes(), vm).unwrap();
+            assert!(vm.bool_eq(&loaded, &values).unwrap());
+
+            // 未标记的大整数也不经过 f64
+            let loaded = loads(b"[1180591620717411303424, 1e30]", vm).unwrap();
+            assert_eq!(
+                loaded.repr(vm).unwrap().as_str(),
+                "[1180591620717411303424, 1e+30]"
+            );
+        });
+    }
+}
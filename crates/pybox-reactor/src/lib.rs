@@ -1,15 +1,32 @@
 //! in-process python sandbox based on rustpython and WASM
 
+mod analysis;
+mod api;
+mod bundle;
+mod codec;
 mod exec;
+mod interrupt;
 mod ioctl;
+mod json;
 mod mem;
+mod options;
 mod protected;
+mod ring;
 mod sanitizer;
+#[cfg(feature = "stdio")]
+mod stdio;
+mod trace;
+mod traceback;
+mod view;
 
 use libc::ssize_t;
 
-use rustpython_vm::{Interpreter, PyObjectRef, pymodule};
+use rustpython_vm::{
+    AsObject, Interpreter, PyObjectRef, builtins::PyDict, compiler::Mode, pymodule, scope::Scope,
+};
 
+pub use api::{PyboxBuilder, PyboxEnv, PyboxExecResult};
+pub use options::{FrozenModules, NativeModule, PyboxInterpreterOptions, SanitizerPolicy};
 use protected::ProtectedLocals;
 use std::cell::RefCell;
 use std::collections::HashMap;
@@ -21,16 +38,68 @@ struct PyboxState {
     pub locals: HashMap<String, (PyObjectRef, Rc<Interpreter>)>,
 }
 
+/// python part of the guest `pybox` module
+const PYBOX_MODULE_SOURCE: &str = include_str!("pybox.py");
+
 thread_local! {
     static PYBOX_STATE: RefCell<PyboxState> = RefCell::new(PyboxState{locals:HashMap::new()});
 }
 
 /// create a new default pybox interpreter
 pub fn pybox_new_interpreter() -> Rc<Interpreter> {
-    let builder = Interpreter::builder(Default::default())
-        .add_frozen_modules(rustpython_pylib::FROZEN_STDLIB);
+    pybox_new_interpreter_with(&PyboxInterpreterOptions::default())
+}
+
+/// 从 WASI random_get 读取 32 位随机数，失败时返回 None
+fn entropy_seed() -> Option<u32> {
+    let mut seed = [0u8; 4];
+    let result = unsafe { libc::getentropy(seed.as_mut_ptr().cast(), seed.len()) };
+    (result == 0).then(|| u32::from_ne_bytes(seed))
+}
+
+/// create a new pybox interpreter with the given options
+pub fn pybox_new_interpreter_with(options: &PyboxInterpreterOptions) -> Rc<Interpreter> {
+    let _span = trace::span!("new_interpreter");
+    let mut report = sanitizer::SanitizerReport::default();
+    let mut settings = rustpython_vm::Settings::default();
+    // 未固定时每次从 WASI random_get 读取种子，不经过 rand 的线程内生成器：
+    // wizer 预初始化后生成器的状态随内存写回 wasm，所有实例会得到相同的种子
+    settings.hash_seed = options.hash_seed.or_else(entropy_seed);
+
+    // 被排除的冻结模块按顶层包名记入 report
+    let mut excluded = std::collections::BTreeSet::new();
+    let frozen: Vec<_> = rustpython_pylib::FROZEN_STDLIB
+        .into_iter()
+        .filter(|(name, _)| {
+            let name: &'static str = *name;
+            let included = options.frozen_modules.includes(name);
+            if !included {
+                excluded.insert(name.split('.').next().unwrap_or(name));
+            }
+            included
+        })
+        .collect();
+    report.excluded_modules(&options.frozen_modules, &excluded);
+
+    // host 通过该 channel 在执行中的代码里抛出 KeyboardInterrupt
+    let (signal_tx, signal_rx) = rustpython_vm::signal::user_signal_channel();
+    let recursion_limit = options.recursion_limit;
+    let mut builder = Interpreter::builder(settings)
+        .add_frozen_modules(frozen)
+        .init_hook(move |vm| {
+            vm.set_user_signal_channel(signal_rx);
+            if let Some(limit) = recursion_limit {
+                vm.recursion_limit.set(limit);
+            }
+        });
     let def = py_pybox::module_def(&builder.ctx);
-    let interp = Rc::new(builder.add_native_module(def).build());
+    builder = builder.add_native_module(def);
+    for module_def in &options.native_modules {
+        let def = module_def(&builder.ctx);
+        builder = builder.add_native_module(def);
+    }
+    let interp = Rc::new(builder.build());
+    interrupt::register(&interp, signal_tx);
 
     // Register ProtectedDict type to Interpreter
     interp.enter(|vm| {
@@ -42,6 +111,7 @@ pub fn pybox_new_interpreter() -> Rc<Interpreter> {
                 .builtins
                 .set_attr("ProtectedLocals", protected_locals_type, vm)
                 .map_err(|_| "Failed to register ProtectedLocals")?;
+            report.added_builtin("ProtectedLocals");
 
             let pybox_module = vm
                 .import("pybox", 0)
@@ -54,6 +124,7 @@ pub fn pybox_new_interpreter() -> Rc<Interpreter> {
             vm.builtins
                 .set_attr("pybox_ioctl_host", pybox_ioctl_host, vm)
                 .map_err(|_| "Failed to register 'pybox_ioctl_host'")?;
+            report.added_builtin("pybox_ioctl_host");
 
             let pybox_json_rpc = pybox_module
                 .get_attr("pybox_json_rpc", vm)
@@ -62,9 +133,32 @@ pub fn pybox_new_interpreter() -> Rc<Interpreter> {
             vm.builtins
                 .set_attr("pybox_json_rpc", pybox_json_rpc, vm)
                 .map_err(|_| "Failed to register 'pybox_json_rpc'")?;
+            report.added_builtin("pybox_json_rpc");
+
+            // run the python part of pybox module in its namespace
+            let pybox_dict = pybox_module
+                .get_attr("__dict__", vm)
+                .ok()
+                .and_then(|dict| dict.downcast::<PyDict>().ok())
+                .ok_or("Failed to get pybox module dict")?;
+            let code_obj = vm
+                .compile(PYBOX_MODULE_SOURCE, Mode::Exec, "<pybox>".to_owned())
+                .map_err(|_| "Failed to compile pybox module source")?;
+            vm.run_code_obj(code_obj, Scope::with_builtins(None, pybox_dict, vm))
+                .map_err(|_| "Failed to run pybox module source")?;
 
             // delete unsafe builtins
-            sanitizer::builtins_sanitizer(vm)?;
+            sanitizer::builtins_sanitizer(vm, &options.sanitizer, &mut report)?;
+
+            if let Some(seed) = options.random_seed
+                && options.frozen_modules.includes("random")
+            {
+                vm.import("random", 0)
+                    .and_then(|random| random.get_attr("seed", vm))
+                    .and_then(|random_seed| random_seed.call((seed,), vm))
+                    .map_err(|_| "Failed to seed the random module")?;
+            }
+            report.save();
 
             Ok(())
         })() {
@@ -82,34 +176,16 @@ pub fn pybox_new_interpreter() -> Rc<Interpreter> {
 /// * `id` for
 #[unsafe(no_mangle)]
 pub extern "C" fn pybox_init_local(id: *const ioctl::pybox_bytes) -> ssize_t {
-    PYBOX_STATE.with_borrow_mut(|pybox_state| {
-        let Ok(id) = (unsafe { (*id).string() }) else {
-            return -1;
-        };
-
-        // allocate a new interpreter for sys modules isolation
-        let interpreter = pybox_new_interpreter();
-
-        // create ProtectedLocals for vm
-        let locals_obj = interpreter.enter(|vm| {
-            // get the type
-            let protected_locals_type = vm
-                .builtins
-                .get_attr("ProtectedLocals", vm)
-                .expect("ProtectedLocals type not registered");
-
-            // create instance
-            protected_locals_type
-                .call((), vm)
-                .expect("Failed to create ProtectedLocals instance")
-        });
-
-        pybox_state
-            .locals
-            .insert(id.to_string(), (locals_obj, interpreter));
+    let Ok(id) = (unsafe { (*id).string() }) else {
+        return -1;
+    };
+    new_local(id);
+    0
+}
 
-        0
-    })
+/// create an empty local execution enviroment, replacing the one with the same id
+pub fn new_local(id: &str) {
+    PyboxBuilder::new().build(id);
 }
 
 /// create a new local from existing local (shallow copy)
@@ -189,95 +265,207 @@ pub extern "C" fn pybox_init_local_from(
     })
 }
 
-/// delete a local enviroment
+/// set the preferred encoding of a local environment
 /// * `id` local enviroment id
+/// * `encoding` codec of the captured stdout / stderr and `locale.getpreferredencoding()`
+/// * `errors` error handler used when text does not fit the encoding ("strict", "replace", ...)
+/// * `error` set to the error message when the encoding or handler is unknown
 #[unsafe(no_mangle)]
-pub extern "C" fn pybox_del_local(id: *const pybox_bytes) -> ssize_t {
-    PYBOX_STATE.with_borrow_mut(|pybox_state| {
-        let Ok(id) = (unsafe { (*id).string() }) else {
+pub extern "C" fn pybox_local_set_encoding(
+    id: *const pybox_bytes,
+    encoding: *const pybox_bytes,
+    errors: *const pybox_bytes,
+    error: *mut *mut pybox_bytes,
+) -> ssize_t {
+    if id.is_null() || encoding.is_null() || errors.is_null() {
+        pybox_bytes::write_to(error, b"Invalid arguments: id, encoding or errors is null");
+        return -1;
+    }
+    let Ok((id, encoding, errors)) = (|| -> Result<_, ()> {
+        unsafe { Ok(((*id).string()?, (*encoding).string()?, (*errors).string()?)) }
+    })() else {
+        pybox_bytes::write_to(error, b"Invalid UTF-8 encoding in arguments");
+        return -1;
+    };
+    let interpreter = match exec::clone_local(id) {
+        Ok((interpreter, _)) => interpreter,
+        Err(message) => {
+            pybox_bytes::write_to(error, message.as_bytes());
             return -1;
-        };
+        }
+    };
 
-        // no id?
-        if !pybox_state.locals.contains_key(id) {
-            return -1;
+    interpreter.enter(|vm| {
+        let result = vm
+            .import("pybox", 0)
+            .and_then(|module| module.get_attr("_set_encoding", vm))
+            .and_then(|set_encoding| set_encoding.call((encoding, errors), vm));
+        match result {
+            Ok(_) => 0,
+            Err(exception) => {
+                let message = exception
+                    .as_object()
+                    .str(vm)
+                    .map(|message| format!("{}: {}", exception.class().name(), message))
+                    .unwrap_or_else(|_| "Pybox: Set Encoding Failed!".to_string());
+                pybox_bytes::write_to(error, message.as_bytes());
+                -1
+            }
         }
+    })
+}
 
-        // deleted
-        pybox_state.locals.remove(id);
+/// collect garbage and clear the interpreter's caches of a local environment
+/// * `id` local enviroment id
+///
+/// returns the number of unreachable objects found, -1 when the environment does not exist
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_gc(id: *const pybox_bytes) -> ssize_t {
+    if id.is_null() {
+        return -1;
+    }
+    let Ok(id) = (unsafe { (*id).string() }) else {
+        return -1;
+    };
+    let Ok((interpreter, _)) = exec::clone_local(id) else {
+        return -1;
+    };
 
-        0
+    interpreter.enter(|vm| {
+        vm.import("pybox", 0)
+            .and_then(|module| module.get_attr("_gc", vm))
+            .and_then(|gc| gc.call((), vm))
+            .and_then(|collected| collected.try_into_value::<ssize_t>(vm))
+            .unwrap_or(-1)
     })
 }
 
+/// delete a local enviroment
+/// * `id` local enviroment id
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_del_local(id: *const pybox_bytes) -> ssize_t {
+    let Ok(id) = (unsafe { (*id).string() }) else {
+        return -1;
+    };
+    match PyboxEnv::open(id) {
+        Some(env) if env.remove() => 0,
+        _ => -1,
+    }
+}
+
+/// wizer 预初始化的入口，启用 wizer feature 时导出
+///
+/// 创建一个解释器后释放，rustpython 的全局状态（内置类型、冻结的标准库）保留在内存中，
+/// wizer 将此时的内存写回 wasm。hash 种子在每次创建解释器时读取，random 模块在导入时播种，
+/// 这里不导入 random，也不使用 rand 的线程内生成器，随机状态不会随内存固定下来
+#[cfg(feature = "wizer")]
+#[unsafe(export_name = "wizer.initialize")]
+pub extern "C" fn pybox_wizer_initialize() {
+    drop(pybox_new_interpreter());
+    PREINITIALIZED.store(true, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// wizer.initialize 已经运行，随内存一起写回 wasm，预初始化的模块启动时即为 true
+static PREINITIALIZED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
 #[pymodule(name = "pybox")]
 mod py_pybox {
-    use crate::ioctl::{pybox_ioctl_host_req_impl, pybox_ioctl_packet};
-    use crate::mem::pybox_free_mem;
+    use crate::codec::Payload;
+    use crate::json::{self, JsonWriter};
     use rustpython_vm::{
-        AsObject, PyPayload, PyResult, VirtualMachine,
-        builtins::{PyBytes, PyBytesRef, PyDict, PyTuple},
+        AsObject, Py, PyObjectRef, PyPayload, PyResult, VirtualMachine,
+        builtins::{PyBaseExceptionRef, PyBytes, PyBytesRef, PyDict, PyStrRef, PyTypeRef},
         convert::IntoObject,
         function::FuncArgs,
     };
 
-    /// Python function: pybox_ioctl_host(handle, data) -> (success, result_bytes)
+    /// Python function: pybox_ioctl_host(handle, data) -> (success, result)
     ///
     /// Host allocates response buffer using pybox_alloc_mem, guest copies data and frees it.
+    /// Large payloads are compressed transparently when the host supports it.
+    /// The result is bytes, or a `pybox.Stream` when the host handler streams its response.
     #[pyfunction]
     fn pybox_ioctl_host(
         handle: isize,
         data: PyBytesRef,
         vm: &VirtualMachine,
-    ) -> PyResult<(bool, PyBytesRef)> {
-        let data_bytes = data.as_bytes();
-
-        // Prepare request packet
-        let mut req = pybox_ioctl_packet {
-            buf: data_bytes.as_ptr() as *mut _,
-            buf_len: data_bytes.len(),
-        };
-
-        // Prepare response packet (host will allocate buffer)
-        let mut resp = pybox_ioctl_packet {
-            buf: std::ptr::null_mut(),
-            buf_len: 0,
-        };
+    ) -> PyResult<(bool, PyObjectRef)> {
+        match crate::codec::call_host(handle, data.as_bytes()) {
+            (success, Payload::Data(data_vec)) => Ok((
+                success,
+                PyBytes::from(data_vec).into_ref(&vm.ctx).into_object(),
+            )),
+            (success, Payload::Stream(stream_id)) => {
+                let stream_class = vm.import("pybox", 0)?.get_attr("Stream", vm)?;
+                Ok((success, stream_class.call((stream_id,), vm)?))
+            }
+        }
+    }
 
-        // Call the host ioctl implementation
-        #[cfg(target_arch = "wasm32")]
-        let success = unsafe {
-            pybox_ioctl_host_req_impl(handle as usize, &mut req as *mut _, &mut resp as *mut _) == 0
-        };
+    /// Python function: pybox_bundle_source(name) -> (source, is_package) | None
+    ///
+    /// Source of a module baked into the reactor by pybox-builder.
+    #[pyfunction]
+    fn pybox_bundle_source(name: PyStrRef) -> Option<(String, bool)> {
+        crate::bundle::find(name.as_str()).map(|(source, package)| (source.to_string(), package))
+    }
 
-        #[cfg(not(target_arch = "wasm32"))]
-        let success =
-            pybox_ioctl_host_req_impl(handle as usize, &mut req as *mut _, &mut resp as *mut _)
-                == 0;
+    /// Python function: pybox_mem_info() -> (heap_used, memory_size)
+    ///
+    /// Bytes of heap in use by the interpreter and size of the linear memory.
+    #[pyfunction]
+    fn pybox_mem_info() -> (usize, usize) {
+        (crate::mem::heap_used(), crate::mem::memory_size())
+    }
 
-        // Create Python bytes object from host-allocated buffer
-        let result_bytes = if !resp.buf.is_null() && resp.buf_len > 0 {
-            // Copy data from host buffer to Rust Vec
-            let data_vec =
-                unsafe { std::slice::from_raw_parts(resp.buf as *const u8, resp.buf_len).to_vec() };
+    /// Python function: pybox_preinitialized() -> bool
+    ///
+    /// Whether the reactor was pre-initialized by wizer, its memory already holds the started runtime.
+    #[pyfunction]
+    fn pybox_preinitialized() -> bool {
+        crate::PREINITIALIZED.load(std::sync::atomic::Ordering::Relaxed)
+    }
 
-            // Free the host-allocated buffer
-            pybox_free_mem(resp.buf);
+    /// Python function: pybox_ring_write(name, data) -> bool
+    ///
+    /// Write a message into a ring buffer created by the host, False when it is full.
+    #[pyfunction]
+    fn pybox_ring_write(name: PyStrRef, data: PyBytesRef, vm: &VirtualMachine) -> PyResult<bool> {
+        crate::ring::with_ring(name.as_str(), |ring| ring.write(data.as_bytes()))
+            .ok_or_else(|| no_ring_error(&name, vm))
+    }
 
-            PyBytes::from(data_vec).into_ref(&vm.ctx)
-        } else {
-            // Empty response
-            PyBytes::from(Vec::new()).into_ref(&vm.ctx)
-        };
+    /// Python function: pybox_ring_read(name) -> bytes | None
+    ///
+    /// Read a message from a ring buffer created by the host, None when it is empty.
+    #[pyfunction]
+    fn pybox_ring_read(name: PyStrRef, vm: &VirtualMachine) -> PyResult<Option<PyBytesRef>> {
+        let message = crate::ring::with_ring(name.as_str(), |ring| ring.read())
+            .ok_or_else(|| no_ring_error(&name, vm))?;
+        Ok(message.map(|message| PyBytes::from(message).into_ref(&vm.ctx)))
+    }
 
-        Ok((success, result_bytes))
+    fn no_ring_error(
+        name: &PyStrRef,
+        vm: &VirtualMachine,
+    ) -> rustpython_vm::builtins::PyBaseExceptionRef {
+        vm.new_exception_msg(
+            vm.ctx.exceptions.lookup_error.to_owned(),
+            format!("no ring buffer named '{}'", name.as_str()),
+        )
     }
 
-    /// Python function: pybox_json_rpc(handler_id, *args, **kwargs) -> result
+    /// Python function: pybox_json_rpc(handler_id, *args, _timeout=None, _retries=0, **kwargs) -> result
     ///
     /// JSON-RPC wrapper around pybox_ioctl_host that handles serialization/deserialization.
+    /// The request also carries the calling env id (`env`) and the correlation id of the
+    /// running exec (`correlation_id`) when the host set one.
+    ///
+    /// `_timeout` (seconds) asks the host to give up on a handler that has not returned in
+    /// time, the call then raises TimeoutError. A call failing with TimeoutError or
+    /// ConnectionError is retried up to `_retries` times. Both are kept out of the kwargs.
     #[pyfunction]
-    fn pybox_json_rpc(args: FuncArgs, vm: &VirtualMachine) -> PyResult {
+    fn pybox_json_rpc(mut args: FuncArgs, vm: &VirtualMachine) -> PyResult {
         // 1. Parse handler_id from first argument
         if args.args.is_empty() {
             return Err(vm.new_type_error(
@@ -288,80 +476,112 @@ mod py_pybox {
         let handler_id_obj = &args.args[0];
         let handler_id: isize = handler_id_obj.try_to_value(vm)?;
 
-        // 2. Extract remaining arguments
-        let remaining_args = args.args[1..].to_vec();
-        let remaining_args_tuple = PyTuple::new_ref(remaining_args, &vm.ctx);
+        // 超时由 host 限制 handler 的执行时间，重试在 guest 端进行
+        let timeout = args
+            .take_keyword("_timeout")
+            .filter(|timeout| !vm.is_none(timeout));
+        let retries = match args.take_keyword("_retries") {
+            Some(retries) => retries.try_to_value::<usize>(vm)?,
+            None => 0,
+        };
 
-        // 3. Build request dict: {"args": [...], "kwargs": {...}}
-        let request_dict = PyDict::default().into_ref(&vm.ctx);
-        request_dict
-            .as_object()
-            .set_item("args", remaining_args_tuple.into_object(), vm)?;
+        // 2. Build request: {"args": [...], "kwargs": {...}}
+        let mut request = JsonWriter::new(vm);
+        request.raw("{\"args\":[");
+        for (index, arg) in args.args[1..].iter().enumerate() {
+            if index > 0 {
+                request.raw(",");
+            }
+            request.value(arg)?;
+        }
+        request.raw("],\"kwargs\":{");
+        for (index, (key, value)) in args.kwargs.iter().enumerate() {
+            if index > 0 {
+                request.raw(",");
+            }
+            request.str(key);
+            request.raw(":");
+            request.value(value)?;
+        }
+        request.raw("}");
+
+        // 调用方的环境，host 据此按环境授权和计算配额
+        if let Some((env, correlation_id)) = crate::exec::calling_context() {
+            request.raw(",\"env\":");
+            request.str(&env);
+            if let Some(correlation_id) = correlation_id {
+                request.raw(",\"correlation_id\":");
+                request.str(&correlation_id);
+            }
+        }
+        if let Some(timeout) = timeout {
+            request.raw(",\"timeout\":");
+            request.value(&timeout)?;
+        }
+        request.raw("}");
+
+        // 3. Encode to bytes
+        let request_bytes = vm.ctx.new_bytes(request.finish().into_bytes());
 
-        // Convert kwargs to PyDict
-        let kwargs_dict = PyDict::default().into_ref(&vm.ctx);
-        for (key, value) in args.kwargs {
-            kwargs_dict.as_object().set_item(key.as_str(), value, vm)?;
+        let mut attempts_left = retries;
+        loop {
+            match json_rpc_call(handler_id, request_bytes.clone(), vm) {
+                Err(err) if attempts_left > 0 && is_retryable(&err, vm) => attempts_left -= 1,
+                result => return result,
+            }
         }
-        request_dict
-            .as_object()
-            .set_item("kwargs", kwargs_dict.into_object(), vm)?;
-
-        // 4. Serialize to JSON
-        let json_module = vm.import("json", 0)?;
-        let dumps_func = json_module.get_attr("dumps", vm)?;
-        let request_json_str = dumps_func.call((request_dict.to_owned(),), vm)?;
-
-        // 5. Encode to bytes
-        let encode_method = request_json_str.get_attr("encode", vm)?;
-        let encode_result = encode_method.call((vm.ctx.new_str("utf-8"),), vm)?;
-        let request_bytes = encode_result
-            .downcast::<PyBytes>()
-            .map_err(|_| vm.new_type_error("encode() did not return bytes".to_string()))?;
-
-        // 6. Call pybox_ioctl_host
+    }
+
+    /// 超时和连接错误可以重试，其他错误重试也不会成功
+    fn is_retryable(err: &PyBaseExceptionRef, vm: &VirtualMachine) -> bool {
+        err.fast_isinstance(vm.ctx.exceptions.timeout_error)
+            || err.fast_isinstance(vm.ctx.exceptions.connection_error)
+    }
+
+    /// 发送一次 JSON-RPC 请求并解析响应
+    fn json_rpc_call(
+        handler_id: isize,
+        request_bytes: PyBytesRef,
+        vm: &VirtualMachine,
+    ) -> PyResult {
+        // 4. Call pybox_ioctl_host
         let (is_ok, response_data) = pybox_ioctl_host(handler_id, request_bytes, vm)?;
 
+        // host 拒绝的调用 (handler ACL) 失败时带有 {"exception": ...} 形式的错误
         if !is_ok {
-            return Err(vm.new_exception_msg(
-                vm.ctx.exceptions.exception_type.to_owned(),
-                format!(
-                    "JSON-RPC communication failed with handler_id {}!",
-                    handler_id
+            let denied = response_data
+                .downcast_ref::<PyBytes>()
+                .and_then(|bytes| json::loads(bytes.as_bytes(), vm).ok())
+                .and_then(|response| response.downcast::<PyDict>().ok())
+                .filter(|response| response.get_item("exception", vm).is_ok());
+            return Err(match denied {
+                Some(response_dict_obj) => rpc_exception(&response_dict_obj, vm)?,
+                None => vm.new_exception_msg(
+                    vm.ctx.exceptions.exception_type.to_owned(),
+                    format!(
+                        "JSON-RPC communication failed with handler_id {}!",
+                        handler_id
+                    ),
                 ),
-            ));
+            });
         }
 
-        // 7. Decode response
-        let decode_method = response_data.as_object().get_attr("decode", vm)?;
-        let decode_result = decode_method.call((vm.ctx.new_str("utf-8"),), vm)?;
-
-        // 8. Deserialize JSON
-        let loads_func = json_module.get_attr("loads", vm)?;
-        let response_dict = loads_func.call((decode_result,), vm)?;
+        // 5. Deserialize JSON, streamed responses are returned as pybox.Stream
+        let Some(response_bytes) = response_data.downcast_ref::<PyBytes>() else {
+            return Ok(response_data);
+        };
+        let response_dict = json::loads(response_bytes.as_bytes(), vm)?;
 
-        // 9. Check for exception
+        // 6. Check for exception
         let response_dict_obj = response_dict
             .downcast::<PyDict>()
             .map_err(|_| vm.new_type_error("JSON response is not a dict".to_string()))?;
 
-        if let Ok(exception) = response_dict_obj.get_item("exception", vm) {
-            let error_msg = if let Ok(traceback) = response_dict_obj.get_item("traceback", vm) {
-                format!(
-                    "JSON-RPC Error: {}\nTraceback:\n{}",
-                    exception.str(vm)?,
-                    traceback.str(vm)?
-                )
-            } else {
-                format!("JSON-RPC Error: {}", exception.str(vm)?)
-            };
-
-            return Err(
-                vm.new_exception_msg(vm.ctx.exceptions.exception_type.to_owned(), error_msg)
-            );
+        if response_dict_obj.get_item("exception", vm).is_ok() {
+            return Err(rpc_exception(&response_dict_obj, vm)?);
         }
 
-        // 10. Return result
+        // 7. Return result
         response_dict_obj.get_item("result", vm).map_err(|_| {
             vm.new_exception_msg(
                 vm.ctx.exceptions.exception_type.to_owned(),
@@ -369,6 +589,37 @@ mod py_pybox {
             )
         })
     }
+
+    /// JSON-RPC 错误响应对应的异常
+    fn rpc_exception(
+        response_dict_obj: &Py<PyDict>,
+        vm: &VirtualMachine,
+    ) -> PyResult<PyBaseExceptionRef> {
+        let exception = response_dict_obj.get_item("exception", vm)?.str(vm)?;
+        let error_msg = if let Ok(traceback) = response_dict_obj.get_item("traceback", vm) {
+            format!(
+                "JSON-RPC Error: {}\nTraceback:\n{}",
+                exception,
+                traceback.str(vm)?
+            )
+        } else {
+            format!("JSON-RPC Error: {}", exception)
+        };
+
+        // guest 代码可以分别捕获，handler 配额的错误为 pybox.QuotaError
+        let exception_type: PyTypeRef = match exception.as_str().split_once(':') {
+            Some(("TimeoutError", _)) => vm.ctx.exceptions.timeout_error.to_owned(),
+            Some(("ConnectionError", _)) => vm.ctx.exceptions.connection_error.to_owned(),
+            Some(("PermissionError", _)) => vm.ctx.exceptions.permission_error.to_owned(),
+            Some(("QuotaError", _)) => vm
+                .import("pybox", 0)?
+                .get_attr("QuotaError", vm)?
+                .downcast()
+                .map_err(|_| vm.new_type_error("pybox.QuotaError is not a type".to_string()))?,
+            _ => vm.ctx.exceptions.exception_type.to_owned(),
+        };
+        Ok(vm.new_exception_msg(exception_type, error_msg))
+    }
 }
 
 #[cfg(test)]
@@ -393,4 +644,172 @@ mod lib_tests {
         let result = pybox_init_local_from(another_id, nonexistent);
         assert_eq!(result, -1, "Should fail when source doesn't exist");
     }
+
+    #[test]
+    fn test_pybox_new_interpreter_with() {
+        let options = PyboxInterpreterOptions {
+            sanitizer: SanitizerPolicy {
+                remove_builtins: vec!["exit".to_string(), "input".to_string()],
+            },
+            frozen_modules: FrozenModules::Exclude(vec![
+                "xml".to_string(),
+                "no_such_module".to_string(),
+            ]),
+            recursion_limit: Some(123),
+            hash_seed: Some(0),
+            random_seed: Some(7),
+            ..Default::default()
+        };
+        let eval = |interpreter: &Interpreter, code: &str| {
+            interpreter.enter(|vm| {
+                let code_obj = vm.compile(code, Mode::Eval, "<test>".to_owned()).unwrap();
+                let value = vm
+                    .run_code_obj(code_obj, vm.new_scope_with_builtins())
+                    .unwrap();
+                value.str(vm).unwrap().as_str().to_string()
+            })
+        };
+
+        let first = pybox_new_interpreter_with(&options);
+        assert_eq!(eval(&first, "__import__('sys').getrecursionlimit()"), "123");
+        let removed =
+            "[hasattr(__import__('builtins'), name) for name in ('exit', 'input', 'quit')]";
+        assert_eq!(eval(&first, removed), "[False, False, True]");
+        assert!(first.enter(|vm| vm.import("xml", 0).is_err()));
+
+        // 相同的种子在每次创建时得到相同的哈希和随机数
+        let second = pybox_new_interpreter_with(&options);
+        for code in ["hash('pybox')", "__import__('random').random()"] {
+            assert_eq!(eval(&first, code), eval(&second, code));
+        }
+
+        let mut report = std::ptr::null_mut();
+        assert_eq!(sanitizer::pybox_sanitizer_report(&mut report), 0);
+        let report: serde_json::Value =
+            serde_json::from_str(unsafe { (*report).string().unwrap() }).unwrap();
+        let changes = report.as_array().unwrap();
+        for (kind, name, action) in [
+            ("module", "xml", "removed"),
+            ("module", "no_such_module", "missing"),
+            ("builtin", "input", "removed"),
+        ] {
+            let expected = serde_json::json!({"kind": kind, "name": name, "action": action});
+            assert!(changes.contains(&expected), "{}", expected);
+        }
+        assert!(!changes.iter().any(|change| change["name"] == "quit"));
+    }
+
+    #[test]
+    fn test_pybox_local_set_encoding() {
+        let id = pybox_bytes::new_bytes(b"test_pybox_local_set_encoding");
+        assert_eq!(pybox_init_local(id), 0, "Failed to init local");
+
+        let exec = |code: &[u8]| {
+            let code = pybox_bytes::new_bytes(code);
+            let mut output = std::ptr::null_mut();
+            assert_eq!(
+                exec::pybox_exec(id, code, &mut output, std::ptr::null_mut()),
+                0
+            );
+            unsafe { (*output).string().unwrap().to_string() }
+        };
+        let set_encoding = |encoding: &[u8], errors: &[u8]| {
+            let mut error = std::ptr::null_mut();
+            let result = pybox_local_set_encoding(
+                id,
+                pybox_bytes::new_bytes(encoding),
+                pybox_bytes::new_bytes(errors),
+                &mut error,
+            );
+            match result {
+                0 => Ok(()),
+                _ => Err(unsafe { (*error).string().unwrap().to_string() }),
+            }
+        };
+
+        set_encoding(b"ascii", b"replace").unwrap();
+        assert_eq!(
+            exec(b"import sys\nprint(sys.stdout.encoding, 'caf\\xe9')"),
+            "ascii caf?\n"
+        );
+        assert_eq!(
+            exec(b"import sys\nsys.stdout.buffer.write(b'ok\\n')"),
+            "ok\n"
+        );
+
+        set_encoding(b"ascii", b"strict").unwrap();
+        assert!(exec(b"print('caf\\xe9')").contains("UnicodeEncodeError"));
+
+        assert!(
+            set_encoding(b"no-such-codec", b"strict")
+                .unwrap_err()
+                .starts_with("LookupError")
+        );
+        assert!(set_encoding(b"utf-8", b"no-such-handler").is_err());
+    }
+
+    #[test]
+    fn test_pybox_gc() {
+        let id = pybox_bytes::new_bytes(b"test_pybox_gc");
+        assert_eq!(pybox_init_local(id), 0, "Failed to init local");
+
+        let code = pybox_bytes::new_bytes(
+            b"import re, sys\nre.compile('a+b')\nsys.last_value = ValueError()\nx = 1",
+        );
+        let mut output = std::ptr::null_mut();
+        assert_eq!(
+            exec::pybox_exec(id, code, &mut output, std::ptr::null_mut()),
+            0
+        );
+        assert!(pybox_gc(id) >= 0);
+
+        let code = pybox_bytes::new_bytes(b"import sys\nprint(x, hasattr(sys, 'last_value'))");
+        assert_eq!(
+            exec::pybox_exec(id, code, &mut output, std::ptr::null_mut()),
+            0
+        );
+        assert_eq!(unsafe { (*output).string().unwrap() }, "1 False\n");
+
+        let missing = pybox_bytes::new_bytes(b"test_pybox_gc_missing");
+        assert_eq!(pybox_gc(missing), -1);
+    }
+
+    #[cfg(feature = "wizer")]
+    #[test]
+    fn test_pybox_wizer_initialize() {
+        pybox_wizer_initialize();
+
+        let id = pybox_bytes::new_bytes(b"test_pybox_wizer_initialize");
+        assert_eq!(pybox_init_local(id), 0, "Failed to init local after wizer");
+
+        let code = pybox_bytes::new_bytes(b"import pybox\nprint(pybox.pybox_preinitialized())");
+        let mut output = std::ptr::null_mut();
+        assert_eq!(
+            exec::pybox_exec(id, code, &mut output, std::ptr::null_mut()),
+            0
+        );
+        assert_eq!(unsafe { (*output).string().unwrap() }, "True\n");
+    }
+
+    #[test]
+    fn test_pybox_module_source() {
+        let id = pybox_bytes::new_bytes(b"test_pybox_module_source");
+        let result = pybox_init_local(id);
+        assert_eq!(result, 0, "Failed to init local");
+
+        let code = pybox_bytes::new_bytes(
+            br#"
+import pybox
+print(pybox.RemoteObject.__name__)
+"#,
+        );
+        let output_buf =
+            mem::pybox_alloc_mem(std::mem::size_of::<*mut pybox_bytes>()) as *mut *mut pybox_bytes;
+        let result = exec::pybox_exec(id, code, output_buf, std::ptr::null_mut());
+        assert_eq!(result, 0, "Execution failed");
+        unsafe {
+            let output = (**output_buf).string().unwrap();
+            assert!(output.contains("RemoteObject"), "{}", output);
+        }
+    }
 }
@@ -1,6 +1,89 @@
 //! mem.rs for shared memory with host
+use std::cell::RefCell;
+use std::collections::HashMap;
+
 use libc::{c_void, free, malloc, size_t};
 
+thread_local! {
+    /// pybox_buffer_alloc 分配、尚未被 take_buffer 取走的缓冲区：地址 -> Vec
+    /// 每个 reactor 有自己的 wasm 实例，表只属于这个 reactor；host 失败后没有交给 guest 的缓冲区
+    /// 由 pybox_buffer_release 或下一次 pybox_buffer_alloc 释放
+    static PENDING_BUFFERS: RefCell<HashMap<usize, Vec<u8>>> = RefCell::new(HashMap::new());
+}
+
+/// 统计堆使用量的全局分配器，只在 wasm 目标上启用，
+/// 原生程序通过 rlib 嵌入时保留自己的分配器
+#[cfg(target_arch = "wasm32")]
+mod counting {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Rust 分配器中仍在使用的字节数，解释器的对象都经由它分配
+    pub(super) static HEAP_USED: AtomicUsize = AtomicUsize::new(0);
+
+    /// 统计堆使用量的分配器，pybox.mem_info() 据此报告 heap_used
+    struct CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let ptr = unsafe { System.alloc(layout) };
+            if !ptr.is_null() {
+                HEAP_USED.fetch_add(layout.size(), Ordering::Relaxed);
+            }
+            ptr
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            let ptr = unsafe { System.alloc_zeroed(layout) };
+            if !ptr.is_null() {
+                HEAP_USED.fetch_add(layout.size(), Ordering::Relaxed);
+            }
+            ptr
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe { System.dealloc(ptr, layout) };
+            HEAP_USED.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let new_ptr = unsafe { System.realloc(ptr, layout, new_size) };
+            if !new_ptr.is_null() {
+                HEAP_USED.fetch_sub(layout.size(), Ordering::Relaxed);
+                HEAP_USED.fetch_add(new_size, Ordering::Relaxed);
+            }
+            new_ptr
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+}
+
+/// 仍在使用的堆内存字节数，非 wasm 目标不统计，返回 0
+pub fn heap_used() -> usize {
+    #[cfg(target_arch = "wasm32")]
+    {
+        counting::HEAP_USED.load(std::sync::atomic::Ordering::Relaxed)
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+/// 线性内存的字节数，非 wasm 目标 (单元测试) 没有线性内存，返回 0
+pub fn memory_size() -> usize {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) * 65536
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
 /// 在 pybox 中分配 size_t 大小内存
 #[unsafe(no_mangle)]
 pub extern "C" fn pybox_alloc_mem(size: size_t) -> *mut c_void {
@@ -21,6 +104,36 @@ pub extern "C" fn pybox_free_mem(ptr: *mut c_void) {
     }
 }
 
+/// 分配一块 host 直接写入的缓冲区，之后由 take_buffer 取走，数据无需再次拷贝
+/// host 每次只有一块缓冲区在途，之前仍未取走的缓冲区来自失败的调用，在这里释放
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_buffer_alloc(size: size_t) -> *mut u8 {
+    let mut buffer = Vec::with_capacity(size);
+    let ptr = buffer.as_mut_ptr();
+    PENDING_BUFFERS.with_borrow_mut(|buffers| {
+        buffers.clear();
+        buffers.insert(ptr as usize, buffer)
+    });
+    ptr
+}
+
+/// 释放所有尚未取走的缓冲区，host 写入或交付缓冲区失败后调用
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_buffer_release() {
+    PENDING_BUFFERS.with_borrow_mut(|buffers| buffers.clear());
+}
+
+/// 取走 pybox_buffer_alloc 分配的缓冲区，len 为 host 写入的字节数
+pub fn take_buffer(ptr: *mut u8, len: usize) -> Option<Vec<u8>> {
+    let mut buffer = PENDING_BUFFERS.with_borrow_mut(|buffers| buffers.remove(&(ptr as usize)))?;
+    if len > buffer.capacity() {
+        return None;
+    }
+    // SAFETY: host 已写入 len 字节
+    unsafe { buffer.set_len(len) };
+    Some(buffer)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -42,4 +155,37 @@ mod test {
 
         pybox_free_mem(ptr);
     }
+
+    #[test]
+    fn test_pybox_buffer_alloc_and_take() {
+        let ptr = pybox_buffer_alloc(4);
+        unsafe { std::ptr::copy_nonoverlapping(b"data".as_ptr(), ptr, 4) };
+        assert_eq!(take_buffer(ptr, 5), None);
+
+        let ptr = pybox_buffer_alloc(4);
+        unsafe { std::ptr::copy_nonoverlapping(b"data".as_ptr(), ptr, 4) };
+        assert_eq!(take_buffer(ptr, 4).unwrap(), b"data");
+        assert_eq!(take_buffer(ptr, 4), None);
+    }
+
+    #[test]
+    fn test_pybox_buffer_release() {
+        // 没有交给 guest 的缓冲区由下一次分配或 release 释放
+        let abandoned = pybox_buffer_alloc(4);
+        let ptr = pybox_buffer_alloc(4);
+        assert_eq!(take_buffer(abandoned, 0), None);
+        assert_eq!(PENDING_BUFFERS.with_borrow(HashMap::len), 1);
+
+        pybox_buffer_release();
+        assert_eq!(take_buffer(ptr, 0), None);
+        assert_eq!(PENDING_BUFFERS.with_borrow(HashMap::len), 0);
+    }
+
+    #[test]
+    fn test_heap_used() {
+        // 其他测试并行分配，只检查本线程持有的大块内存
+        let buffer = vec![0u8; 16 << 20];
+        assert!(heap_used() >= buffer.len());
+        drop(buffer);
+    }
 }
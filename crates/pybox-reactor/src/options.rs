@@ -0,0 +1,73 @@
+//! options.rs 创建解释器的选项，见 pybox_new_interpreter_with
+//!
+//! 默认选项与 pybox_new_interpreter 相同：加载全部冻结的标准库，删除 REMOVED_BUILTINS，
+//! 使用 rustpython 默认的递归上限和随机的哈希种子
+
+use rustpython_vm::{Context, builtins::PyModuleDef};
+
+/// 额外的原生模块，即 `#[pymodule]` 生成的 `module_def`
+pub type NativeModule = fn(&Context) -> &'static PyModuleDef;
+
+/// 创建解释器时 sanitizer 的策略
+#[derive(Clone, Debug, PartialEq)]
+pub struct SanitizerPolicy {
+    /// 从 builtins 中删除的名称
+    pub remove_builtins: Vec<String>,
+}
+
+impl Default for SanitizerPolicy {
+    fn default() -> Self {
+        Self {
+            remove_builtins: crate::sanitizer::REMOVED_BUILTINS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// 冻结的标准库中加载哪些模块
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum FrozenModules {
+    /// 全部加载
+    #[default]
+    All,
+    /// 不加载列出的模块，包名同时排除其下的子模块
+    Exclude(Vec<String>),
+    /// 只加载列出的模块，包名同时包含其下的子模块
+    Only(Vec<String>),
+}
+
+impl FrozenModules {
+    /// 模块是否加载
+    pub fn includes(&self, module: &str) -> bool {
+        let matches = |names: &[String]| names.iter().any(|name| module_matches(name, module));
+        match self {
+            Self::All => true,
+            Self::Exclude(names) => !matches(names),
+            Self::Only(names) => matches(names),
+        }
+    }
+}
+
+/// module 是 name 本身或其下的子模块
+pub(crate) fn module_matches(name: &str, module: &str) -> bool {
+    module
+        .strip_prefix(name)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+/// pybox_new_interpreter_with 的选项
+#[derive(Clone, Debug, Default)]
+pub struct PyboxInterpreterOptions {
+    pub sanitizer: SanitizerPolicy,
+    pub frozen_modules: FrozenModules,
+    /// python 的递归上限，None 时使用 rustpython 的默认值
+    pub recursion_limit: Option<usize>,
+    /// 固定 str / bytes 的哈希种子，set 的迭代顺序在每次创建时相同
+    pub hash_seed: Option<u32>,
+    /// 创建时以该种子初始化 random 模块，没有加载 random 时忽略
+    pub random_seed: Option<u64>,
+    /// 除 pybox 模块外注册的原生模块
+    pub native_modules: Vec<NativeModule>,
+}
@@ -4,7 +4,7 @@ use libc::ssize_t;
 
 use rustpython_vm::{
     AsObject, Py, PyObject, PyObjectRef, PyResult, VirtualMachine,
-    builtins::{PyDict, PyDictRef, PyStr, PyType},
+    builtins::{PyBaseExceptionRef, PyDict, PyDictRef, PyStr, PyType},
     common::lock::PyRwLock,
     function::FuncArgs,
     object::{PyPayload, Traverse, TraverseFn},
@@ -12,7 +12,7 @@ use rustpython_vm::{
     pyclass,
     types::{AsMapping, Constructor},
 };
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 
 /// ProtectedLocals: 带保护键的字典
 /// 使用组合模式包装 PyDict，实现 AsMapping trait 来拦截操作
@@ -26,6 +26,8 @@ use std::collections::HashSet;
 pub struct ProtectedLocals {
     dict: PyDictRef,                          // 内部字典
     protected_set: PyRwLock<HashSet<String>>, // 受保护的键集合（不需要遍历）
+    blocked: PyRwLock<BTreeMap<String, u64>>, // 每个受保护的键被阻止修改或删除的次数
+    max_keys: PyRwLock<Option<usize>>,        // 键数量的上限，新增键时检查
 }
 
 // SAFETY: Traverse properly visits all owned PyObjectRefs
@@ -49,6 +51,8 @@ impl Constructor for ProtectedLocals {
         Ok(Self {
             dict: dict.into_ref(&vm.ctx),
             protected_set: PyRwLock::new(HashSet::new()),
+            blocked: PyRwLock::new(BTreeMap::new()),
+            max_keys: PyRwLock::new(None),
         })
     }
 }
@@ -66,7 +70,6 @@ impl ProtectedLocals {
     }
 
     /// 取消保护某个键
-    #[allow(unused)]
     pub fn unprotect(&self, key: &str) {
         self.protected_set.write().remove(key);
     }
@@ -78,11 +81,84 @@ impl ProtectedLocals {
     }
 
     /// 获取所有被保护的键列表
-    #[allow(unused)]
     pub fn get_protected_keys(&self) -> Vec<String> {
         self.protected_set.read().iter().cloned().collect()
     }
 
+    /// 记录当前的绑定和受保护的键，绑定的对象本身不复制
+    pub fn snapshot(&self) -> LocalsSnapshot {
+        LocalsSnapshot {
+            items: self.dict().into_iter().collect(),
+            protected_set: self.protected_set.read().clone(),
+        }
+    }
+
+    /// 恢复到 snapshot 时的绑定和受保护的键
+    pub fn restore(&self, snapshot: LocalsSnapshot, vm: &VirtualMachine) -> PyResult<()> {
+        vm.call_method(self.dict.as_object(), "clear", ())?;
+        for (key, value) in snapshot.items {
+            self.dict.set_item(&*key, value, vm)?;
+        }
+        *self.protected_set.write() = snapshot.protected_set;
+        Ok(())
+    }
+
+    /// 保护当前存在的所有键，返回这些键（已排序）
+    pub fn protect_all(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .dict()
+            .into_iter()
+            .filter_map(|(key, _)| Some(key.downcast_ref::<PyStr>()?.as_str().to_owned()))
+            .collect();
+        keys.sort();
+        self.protected_set.write().extend(keys.iter().cloned());
+        keys
+    }
+
+    /// 每个受保护的键被阻止修改或删除的次数
+    pub fn blocked_counts(&self) -> BTreeMap<String, u64> {
+        self.blocked.read().clone()
+    }
+
+    /// 设置键数量的上限，None 表示不限制，已有的键不受影响
+    pub fn set_max_keys(&self, max_keys: Option<usize>) {
+        *self.max_keys.write() = max_keys;
+    }
+
+    /// 新增键会超过上限时返回 pybox.EnvLimitError，修改已有的键不受限制
+    pub fn check_new_key(&self, key: &PyObject, vm: &VirtualMachine) -> PyResult<()> {
+        let Some(max_keys) = *self.max_keys.read() else {
+            return Ok(());
+        };
+        if self.dict.__len__() < max_keys || self.dict.contains_key(key, vm) {
+            return Ok(());
+        }
+        let message = format!(
+            "Environment variable limit reached: at most {} variables",
+            max_keys
+        );
+        // pybox 模块不可用时退回到 EnvLimitError 的基类 MemoryError
+        let limit_error = vm
+            .import("pybox", 0)
+            .and_then(|module| module.get_attr("EnvLimitError", vm))
+            .ok()
+            .and_then(|class| class.downcast::<PyType>().ok());
+        Err(match limit_error {
+            Some(class) => vm.new_exception_msg(class, message),
+            None => vm.new_memory_error(message),
+        })
+    }
+
+    /// 记录一次被阻止的修改或删除，返回对应的 KeyError
+    fn blocked(&self, action: &str, key: &str, vm: &VirtualMachine) -> PyBaseExceptionRef {
+        *self.blocked.write().entry(key.to_owned()).or_default() += 1;
+        vm.new_key_error(
+            vm.ctx
+                .new_str(format!("Cannot {} protected key: '{}'", action, key))
+                .into(),
+        )
+    }
+
     /// 检查键是否被保护（从 PyObject 转换）
     fn check_protected(&self, key: &PyObject, _vm: &VirtualMachine) -> PyResult<bool> {
         if let Some(key_str) = key.downcast_ref::<PyStr>() {
@@ -93,6 +169,12 @@ impl ProtectedLocals {
     }
 }
 
+/// ProtectedLocals 的快照，见 ProtectedLocals::snapshot
+pub struct LocalsSnapshot {
+    items: Vec<(PyObjectRef, PyObjectRef)>,
+    protected_set: HashSet<String>,
+}
+
 // 实现 AsMapping trait 技能自定义映射类型
 impl AsMapping for ProtectedLocals {
     fn as_mapping() -> &'static PyMappingMethods {
@@ -116,30 +198,17 @@ impl AsMapping for ProtectedLocals {
                     // 设置操作 - 检查是否被保护
                     if zelf.check_protected(needle, vm)? {
                         if let Some(key_str) = needle.downcast_ref::<PyStr>() {
-                            return Err(vm.new_key_error(
-                                vm.ctx
-                                    .new_str(format!(
-                                        "Cannot modify protected key: '{}'",
-                                        key_str.as_str()
-                                    ))
-                                    .into(),
-                            ));
+                            return Err(zelf.blocked("modify", key_str.as_str(), vm));
                         }
                     }
-                    // 未保护，允许设置
+                    // 未保护，检查数量上限后设置
+                    zelf.check_new_key(needle, vm)?;
                     zelf.dict.as_object().set_item(needle, value, vm)
                 } else {
                     // 删除操作 - 检查是否被保护
                     if zelf.check_protected(needle, vm)? {
                         if let Some(key_str) = needle.downcast_ref::<PyStr>() {
-                            return Err(vm.new_key_error(
-                                vm.ctx
-                                    .new_str(format!(
-                                        "Cannot delete protected key: '{}'",
-                                        key_str.as_str()
-                                    ))
-                                    .into(),
-                            ));
+                            return Err(zelf.blocked("delete", key_str.as_str(), vm));
                         }
                     }
                     // 未保护，允许删除
@@ -171,16 +240,10 @@ impl ProtectedLocals {
         // 检查保护
         if self.check_protected(&*key, vm)? {
             if let Some(key_str) = key.downcast_ref::<PyStr>() {
-                return Err(vm.new_key_error(
-                    vm.ctx
-                        .new_str(format!(
-                            "Cannot modify protected key: '{}'",
-                            key_str.as_str()
-                        ))
-                        .into(),
-                ));
+                return Err(self.blocked("modify", key_str.as_str(), vm));
             }
         }
+        self.check_new_key(&key, vm)?;
         self.dict.as_object().set_item(&*key, value, vm)
     }
 
@@ -190,14 +253,7 @@ impl ProtectedLocals {
         // 检查保护
         if self.check_protected(&*key, vm)? {
             if let Some(key_str) = key.downcast_ref::<PyStr>() {
-                return Err(vm.new_key_error(
-                    vm.ctx
-                        .new_str(format!(
-                            "Cannot delete protected key: '{}'",
-                            key_str.as_str()
-                        ))
-                        .into(),
-                ));
+                return Err(self.blocked("delete", key_str.as_str(), vm));
             }
         }
         self.dict.as_object().del_item(&*key, vm)
@@ -282,6 +338,127 @@ pub extern "C" fn pybox_local_protect(
     })
 }
 
+/// 保护环境中当前存在的所有键，之后新增的键不受影响
+/// * `id` 环境 id
+/// * `result` 写入被保护的键的 json 列表（已排序）
+///
+/// 返回 0 表示成功，-1 表示环境不存在或参数无效
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_local_protect_all(
+    id: *const ioctl::pybox_bytes,
+    result: *mut *mut ioctl::pybox_bytes,
+) -> ssize_t {
+    let keys = with_locals(id, |locals| {
+        serde_json::to_string(&locals.protect_all()).ok()
+    });
+    let Some(Some(keys)) = keys else {
+        return -1;
+    };
+    ioctl::pybox_bytes::write_to(result, keys.as_bytes());
+    0
+}
+
+/// 取消保护环境中的多个键，未被保护的键被忽略
+/// * `id` 环境 id
+/// * `names` 键名的 json 列表
+///
+/// 返回 0 表示成功，-1 表示环境不存在或参数无效
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_local_unprotect(
+    id: *const ioctl::pybox_bytes,
+    names: *const ioctl::pybox_bytes,
+) -> ssize_t {
+    if names.is_null() {
+        return -1;
+    }
+    let Some(names) = unsafe { (*names).string() }
+        .ok()
+        .and_then(|names| serde_json::from_str::<Vec<String>>(names).ok())
+    else {
+        return -1;
+    };
+    match with_locals(id, |locals| {
+        names.iter().for_each(|name| locals.unprotect(name))
+    }) {
+        Some(()) => 0,
+        None => -1,
+    }
+}
+
+/// 环境中受保护的键，包括尚未定义的键
+/// * `id` 环境 id
+/// * `result` 写入键的 json 列表（已排序）
+///
+/// 返回 0 表示成功，-1 表示环境不存在或参数无效
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_local_protected_keys(
+    id: *const ioctl::pybox_bytes,
+    result: *mut *mut ioctl::pybox_bytes,
+) -> ssize_t {
+    let keys = with_locals(id, |locals| {
+        let mut keys = locals.get_protected_keys();
+        keys.sort();
+        serde_json::to_string(&keys).ok()
+    });
+    let Some(Some(keys)) = keys else {
+        return -1;
+    };
+    ioctl::pybox_bytes::write_to(result, keys.as_bytes());
+    0
+}
+
+/// 环境中每个受保护的键被阻止修改或删除的次数
+/// * `id` 环境 id
+/// * `result` 写入 json 对象 `{key: count}`，没有被阻止的操作时为空对象
+///
+/// 返回 0 表示成功，-1 表示环境不存在或参数无效
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_protection_stats(
+    id: *const ioctl::pybox_bytes,
+    result: *mut *mut ioctl::pybox_bytes,
+) -> ssize_t {
+    let stats = with_locals(id, |locals| {
+        serde_json::to_string(&locals.blocked_counts()).ok()
+    });
+    let Some(Some(stats)) = stats else {
+        return -1;
+    };
+    ioctl::pybox_bytes::write_to(result, stats.as_bytes());
+    0
+}
+
+/// 设置环境中变量数量的上限，超过上限的新变量抛出 pybox.EnvLimitError
+/// * `id` 环境 id
+/// * `max_variables` 变量数量的上限，负数表示不限制
+///
+/// 返回 0 表示成功，-1 表示环境不存在或参数无效
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_local_set_max_variables(
+    id: *const ioctl::pybox_bytes,
+    max_variables: i64,
+) -> ssize_t {
+    let max_variables = usize::try_from(max_variables).ok();
+    match with_locals(id, |locals| locals.set_max_keys(max_variables)) {
+        Some(()) => 0,
+        None => -1,
+    }
+}
+
+/// 在 id 对应环境的 ProtectedLocals 上调用 f，环境不存在或 id 无效时返回 None
+fn with_locals<R>(
+    id: *const ioctl::pybox_bytes,
+    f: impl FnOnce(&ProtectedLocals) -> R,
+) -> Option<R> {
+    if id.is_null() {
+        return None;
+    }
+    let id = unsafe { (*id).string() }.ok()?;
+    PYBOX_STATE.with_borrow(|pybox_state| {
+        let (locals, _) = pybox_state.locals.get(id)?;
+        Some(f(locals.downcast_ref::<ProtectedLocals>()?))
+    })
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -295,5 +472,148 @@ mod test {
         assert_eq!(result, 0);
         let result = pybox_local_protect(id, name);
         assert_eq!(result, 0);
+
+        let keys = || {
+            let mut result = std::ptr::null_mut();
+            assert_eq!(pybox_local_protected_keys(id, &mut result), 0);
+            unsafe { (*result).string().unwrap().to_string() }
+        };
+        assert_eq!(keys(), r#"["my_var"]"#);
+        let names = ioctl::pybox_bytes::new_bytes(br#"["my_var"]"#);
+        assert_eq!(pybox_local_unprotect(id, names), 0);
+        assert_eq!(keys(), "[]");
+    }
+
+    #[test]
+    pub fn test_protect_all() {
+        let id = ioctl::pybox_bytes::new_bytes(b"test_protect_all");
+        assert_eq!(pybox_init_local(id), 0);
+        let exec = |code: &[u8]| {
+            let code = ioctl::pybox_bytes::new_bytes(code);
+            let mut output = std::ptr::null_mut();
+            assert_eq!(
+                exec::pybox_exec(id, code, &mut output, std::ptr::null_mut()),
+                0
+            );
+            unsafe { (*output).string().unwrap().to_string() }
+        };
+        exec(b"b = 1\na = 2");
+
+        let mut result = std::ptr::null_mut();
+        assert_eq!(pybox_local_protect_all(id, &mut result), 0);
+        let keys = unsafe { (*result).string().unwrap().to_string() };
+        assert!(keys.contains(r#""a","b""#), "{}", keys);
+        assert!(exec(b"a = 3").contains("Cannot modify protected key"));
+        // 之后新增的键不受保护
+        assert_eq!(exec(b"c = 1\nc = 2"), "");
+
+        let names = ioctl::pybox_bytes::new_bytes(br#"["a", "missing"]"#);
+        assert_eq!(pybox_local_unprotect(id, names), 0);
+        assert_eq!(exec(b"a = 3"), "");
+        assert!(exec(b"b = 3").contains("Cannot modify protected key"));
+
+        let invalid = ioctl::pybox_bytes::new_bytes(b"a");
+        assert_eq!(pybox_local_unprotect(id, invalid), -1);
+    }
+
+    #[test]
+    pub fn test_protection_stats() {
+        let id = ioctl::pybox_bytes::new_bytes(b"test_protection_stats");
+        let name = ioctl::pybox_bytes::new_bytes(b"secret");
+        assert_eq!(pybox_init_local(id), 0);
+        assert_eq!(pybox_local_protect(id, name), 0);
+
+        let stats = || {
+            let mut result = std::ptr::null_mut();
+            assert_eq!(pybox_protection_stats(id, &mut result), 0);
+            unsafe { (*result).string().unwrap().to_string() }
+        };
+        assert_eq!(stats(), "{}");
+
+        for code in [
+            &b"secret = 1"[..],
+            b"del secret",
+            b"secret = 2",
+            b"other = 1",
+        ] {
+            let code = ioctl::pybox_bytes::new_bytes(code);
+            assert_eq!(
+                exec::pybox_exec(id, code, std::ptr::null_mut(), std::ptr::null_mut()),
+                0
+            );
+        }
+        assert_eq!(stats(), r#"{"secret":3}"#);
+
+        let missing = ioctl::pybox_bytes::new_bytes(b"test_protection_stats_missing");
+        assert_eq!(
+            pybox_protection_stats(missing, &mut std::ptr::null_mut()),
+            -1
+        );
+    }
+
+    #[test]
+    pub fn test_max_variables() {
+        let id = ioctl::pybox_bytes::new_bytes(b"test_max_variables");
+        assert_eq!(pybox_init_local(id), 0);
+        let exec = |code: &[u8]| {
+            let code = ioctl::pybox_bytes::new_bytes(code);
+            let mut output = std::ptr::null_mut();
+            assert_eq!(
+                exec::pybox_exec(id, code, &mut output, std::ptr::null_mut()),
+                0
+            );
+            unsafe { (*output).string().unwrap().to_string() }
+        };
+        exec(b"a = 1");
+        assert_eq!(pybox_local_set_max_variables(id, 2), 0);
+        assert_eq!(exec(b"b = 2\na = 3\nb = 4"), "");
+        assert!(exec(b"c = 5").contains("EnvLimitError"));
+        // 删除后可以新增
+        assert_eq!(exec(b"del a\nc = 5"), "");
+
+        let object = ioctl::pybox_bytes::new_bytes(b"1");
+        let name = ioctl::pybox_bytes::new_bytes(b"d");
+        let mut error = std::ptr::null_mut();
+        assert_eq!(exec::pybox_assign(id, name, object, &mut error), -1);
+        assert!(unsafe { (*error).string().unwrap() }.contains("EnvLimitError"));
+
+        assert_eq!(pybox_local_set_max_variables(id, -1), 0);
+        assert_eq!(exec(b"d = 6"), "");
+
+        let missing = ioctl::pybox_bytes::new_bytes(b"test_max_variables_missing");
+        assert_eq!(pybox_local_set_max_variables(missing, 1), -1);
+    }
+
+    #[test]
+    pub fn test_protected_snapshot() {
+        let interpreter = pybox_new_interpreter();
+        interpreter.enter(|vm| {
+            let locals = vm
+                .builtins
+                .get_attr("ProtectedLocals", vm)
+                .unwrap()
+                .call((), vm)
+                .unwrap();
+            let locals = locals.downcast_ref::<ProtectedLocals>().unwrap();
+            locals
+                .dict()
+                .set_item("x", vm.ctx.new_int(1).into(), vm)
+                .unwrap();
+            locals.protect("x");
+
+            let snapshot = locals.snapshot();
+            locals
+                .dict()
+                .set_item("y", vm.ctx.new_int(2).into(), vm)
+                .unwrap();
+            locals.unprotect("x");
+            locals.protect("y");
+
+            locals.restore(snapshot, vm).unwrap();
+            assert!(locals.dict().get_item_opt("y", vm).unwrap().is_none());
+            assert!(locals.dict().get_item_opt("x", vm).unwrap().is_some());
+            assert!(locals.is_protected("x"));
+            assert!(!locals.is_protected("y"));
+        });
     }
 }
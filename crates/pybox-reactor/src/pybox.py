@@ -0,0 +1,619 @@
+"""python part of the guest `pybox` module
+
+executed in the namespace of the native `pybox` module when an interpreter is created,
+so everything defined here is reachable as `pybox.<name>` inside the sandbox
+"""
+
+# reserved ioctl handle, the host resolves a handler name to its handle id
+_RESOLVE_HANDLE = 0x7FFF0000
+# reserved ioctl handle, pulls the chunks of a streamed host response
+_STREAM_HANDLE = 0x7FFF0002
+# reserved ioctl handle, forwards the output of an exec to the host while it is written
+_OUTPUT_HANDLE = 0x7FFF0004
+# reserved ioctl handle, fuel left to the running exec or call
+_BUDGET_HANDLE = 0x7FFF0005
+# reserved ioctl handle, the limit of the linear memory set by the host
+_MEMORY_HANDLE = 0x7FFF0006
+
+
+def resolve(name):
+    """resolve the handle id of a named host handler"""
+    ok, data = pybox_ioctl_host(_RESOLVE_HANDLE, name.encode("utf-8"))
+    if not ok:
+        raise LookupError(f"no host handler named '{name}'")
+    return int(data)
+
+
+def remaining_budget():
+    """fuel left before the host stops the running exec or call, None when it has no budget,
+    long-running code can check it to save its progress and return in time
+    """
+    ok, data = pybox_ioctl_host(_BUDGET_HANDLE, b"")
+    if not ok or not data:
+        return None
+    return int(data)
+
+
+def mem_info():
+    """memory of the sandbox in bytes: `heap_used` by the interpreter, `memory_size` of the linear memory
+    and the host's `memory_limit` (None without one), data-processing code can check it to chunk its work
+    instead of being stopped when the memory grows past the limit
+    """
+    heap_used, memory_size = pybox_mem_info()
+    ok, data = pybox_ioctl_host(_MEMORY_HANDLE, b"")
+    return {
+        "heap_used": heap_used,
+        "memory_size": memory_size,
+        "memory_limit": int(data) if ok and data else None,
+    }
+
+
+class RpcProxy:
+    """calls named host handlers by attribute,
+    `rpc_proxy("tools").search(q="x")` calls the handler named `tools.search`,
+    `_timeout=` and `_retries=` bound the call as in pybox_json_rpc.
+    Attributes nest, `host.db.query(...)` calls the handler named `db.query`
+    """
+
+    def __init__(self, namespace, handles=None):
+        object.__setattr__(self, "_namespace", namespace)
+        # resolved handles, shared by the proxies derived from the same root
+        object.__setattr__(self, "_handles", {} if handles is None else handles)
+
+    def __getattr__(self, name):
+        if name.startswith("__"):
+            raise AttributeError(name)
+        full_name = f"{self._namespace}.{name}" if self._namespace else name
+        return RpcProxy(full_name, self._handles)
+
+    def __call__(self, *args, **kwargs):
+        full_name = self._namespace
+        if not full_name:
+            raise TypeError("call a handler of the proxy, not the proxy itself")
+        handle = self._handles.get(full_name)
+        if handle is None:
+            handle = self._handles[full_name] = resolve(full_name)
+        return pybox_json_rpc(handle, *args, **kwargs)
+
+    @property
+    def __name__(self):
+        return self._namespace.rpartition(".")[2]
+
+    def __setattr__(self, name, value):
+        raise AttributeError("rpc proxy is read-only")
+
+    def __repr__(self):
+        return f"<pybox.RpcProxy namespace={self._namespace!r}>"
+
+
+def rpc_proxy(namespace=""):
+    """proxy calling the host handlers registered under `namespace`"""
+    return RpcProxy(namespace)
+
+
+# named host handlers by attribute: `pybox.host.search("query")`, `pybox.host.db.query(sql)`
+host = RpcProxy("")
+
+
+class Stream:
+    """streamed response of a host handler, iterate it to pull the chunks (bytes)
+    one by one instead of receiving the whole payload at once
+    """
+
+    def __init__(self, stream_id):
+        self._id = stream_id
+        self._done = False
+
+    def __iter__(self):
+        return self
+
+    def __next__(self):
+        if self._done:
+            raise StopIteration
+        ok, chunk = pybox_ioctl_host(_STREAM_HANDLE, f"next:{self._id}".encode())
+        if not ok:
+            self._done = True
+            raise IOError(f"stream {self._id} failed")
+        if not chunk:
+            self._done = True
+            raise StopIteration
+        return chunk
+
+    def read(self):
+        """read the remaining chunks at once"""
+        return b"".join(self)
+
+    def close(self):
+        """release the host side of the stream"""
+        if not self._done:
+            self._done = True
+            pybox_ioctl_host(_STREAM_HANDLE, f"close:{self._id}".encode())
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, *exc):
+        self.close()
+
+    def __del__(self):
+        self.close()
+
+    def __repr__(self):
+        return f"<pybox.Stream id={self._id}>"
+
+
+class Ring:
+    """ring buffer created by the host with `reactor.ring_create(name, capacity)`
+
+    messages are copied straight into shared memory without an ioctl call,
+    cheap enough for frequent small messages such as progress updates
+    """
+
+    def __init__(self, name):
+        self.name = name
+
+    def write(self, data):
+        """write a message (bytes or str), returns False when the ring is full"""
+        if isinstance(data, str):
+            data = data.encode("utf-8")
+        return pybox_ring_write(self.name, data)
+
+    def read(self):
+        """read a message, returns None when the ring is empty"""
+        return pybox_ring_read(self.name)
+
+    def __iter__(self):
+        """read the pending messages"""
+        while True:
+            message = self.read()
+            if message is None:
+                return
+            yield message
+
+    def __repr__(self):
+        return f"<pybox.Ring name={self.name!r}>"
+
+
+def ring(name):
+    """ring buffer shared with the host"""
+    return Ring(name)
+
+
+class _OutputLimit:
+    """byte budget shared by the stdout and stderr of an exec, None for no limit"""
+
+    def __init__(self, limit=None):
+        self.remaining = limit
+        self.truncated = False
+
+    def take(self, data):
+        """the part of data that still fits in the budget"""
+        if self.remaining is None:
+            return data
+        encoded = data.encode("utf-8")
+        if len(encoded) <= self.remaining:
+            self.remaining -= len(encoded)
+            return data
+        self.truncated = True
+        data = encoded[:self.remaining].decode("utf-8", "ignore")
+        self.remaining = 0
+        return data
+
+
+# preferred encoding of this environment and its error handler, see _set_encoding
+_encoding = "utf-8"
+_errors = "strict"
+
+
+def _set_encoding(encoding, errors):
+    """set the encoding of the captured stdout / stderr and of locale.getpreferredencoding()"""
+    import codecs
+
+    encoding = codecs.lookup(encoding).name
+    codecs.lookup_error(errors)
+    global _encoding, _errors
+    _encoding, _errors = encoding, errors
+    try:
+        import locale
+    except ImportError:
+        return
+    locale.getpreferredencoding = lambda do_setlocale=True: encoding
+    locale.getencoding = lambda: encoding
+
+
+def _gc():
+    """collect garbage and clear the interpreter's caches, returns the number of unreachable objects found"""
+    import gc
+    import sys
+
+    # the last uncaught exception keeps its traceback and every frame in it alive
+    for name in ("last_type", "last_value", "last_traceback", "last_exc"):
+        if hasattr(sys, name):
+            delattr(sys, name)
+    # only clear the caches of modules the code has imported
+    for module, clear in (("re", "purge"), ("linecache", "clearcache"), ("importlib", "invalidate_caches")):
+        if module in sys.modules:
+            getattr(sys.modules[module], clear, lambda: None)()
+    getattr(sys, "_clear_type_cache", lambda: None)()
+    return gc.collect()
+
+
+class _TeeBuffer:
+    """binary `buffer` of a _Tee, bytes are decoded with the environment's encoding"""
+
+    def __init__(self, tee):
+        self._tee = tee
+
+    def write(self, data):
+        self._tee.write(bytes(data).decode(_encoding, _errors))
+        return len(data)
+
+    def flush(self):
+        pass
+
+
+class _Tee:
+    """stdout / stderr of an exec, keeps its own copy of what goes to the merged output,
+    `live` tees also forward every write to the host, writes over the limit are dropped
+    """
+
+    def __init__(self, merged, name="stdout", live=False, limit=None):
+        self._merged = merged
+        self._chunks = []
+        self._name = name
+        self._live = live
+        self._limit = limit
+        self.buffer = _TeeBuffer(self)
+
+    @property
+    def encoding(self):
+        return _encoding
+
+    @property
+    def errors(self):
+        return _errors
+
+    def write(self, data):
+        size = len(data)
+        # text that does not fit the configured encoding fails or is replaced as it would on a real stream
+        if _encoding != "utf-8" or _errors != "strict":
+            data = data.encode(_encoding, _errors).decode(_encoding, "replace")
+        if self._limit is not None:
+            data = self._limit.take(data)
+        if data:
+            self._merged.write(data)
+            self._chunks.append(data)
+            if self._live:
+                pybox_ioctl_host(_OUTPUT_HANDLE, f"{self._name}:{data}".encode("utf-8"))
+        return size
+
+    def flush(self):
+        pass
+
+    def getvalue(self):
+        return "".join(self._chunks)
+
+
+class _Artifacts(list):
+    """artifacts of an exec, the ones over the count or size limit are dropped"""
+
+    def __init__(self, max_count=None, max_bytes=None):
+        super().__init__()
+        self.max_count = max_count
+        self.remaining_bytes = max_bytes
+        self.truncated = False
+
+    def add(self, artifact, size):
+        if (self.max_count is not None and len(self) >= self.max_count) or (
+            self.remaining_bytes is not None and size > self.remaining_bytes
+        ):
+            self.truncated = True
+            return
+        if self.remaining_bytes is not None:
+            self.remaining_bytes -= size
+        self.append(artifact)
+
+
+# artifacts attached by the running exec, swapped by the host side of every exec
+_artifacts = _Artifacts()
+
+
+def artifact(name, data, mime=None):
+    """attach an artifact (str or bytes) to the result of the running exec"""
+    if not isinstance(data, (str, bytes, bytearray)):
+        raise TypeError("artifact data must be str or bytes")
+    binary = not isinstance(data, str)
+    size = len(data) if binary else len(data.encode("utf-8"))
+    _artifacts.add({
+        "name": str(name),
+        "mime": mime,
+        "binary": binary,
+        # no base64 in the sandbox, binary data travels as hex
+        "data": bytes(data).hex() if binary else data,
+    }, size)
+
+
+class QuotaError(Exception):
+    """raised when a handler call exceeds the request size, response size or call count limit of the handler"""
+
+
+class EnvLimitError(MemoryError):
+    """raised when a new variable would exceed the environment's variable limit"""
+
+
+class LintError(Exception):
+    """raised instead of running code that a linter rejected, `diagnostics` holds every finding"""
+
+    def __init__(self, diagnostics):
+        self.diagnostics = diagnostics
+        errors = [d for d in diagnostics if d["severity"] == "error"]
+        super().__init__("; ".join(
+            d["message"] if d["line"] is None else f"line {d['line']}: {d['message']}" for d in errors
+        ))
+
+
+# linters of this environment, run on the parsed code before every exec
+_linters = []
+
+
+def lint(func):
+    """register func(tree, code) to check the code of every later exec before it runs,
+    tree is the ast.Module of the code. func returns diagnostics as strings or dicts with
+    `message`, `line`, `severity` ("warning" or "error") and `rule`; any error blocks the
+    execution with LintError. The findings are returned as the exec result's diagnostics
+    """
+    _linters.append(func)
+    return func
+
+
+def _diagnostic(item, rule):
+    if isinstance(item, str):
+        item = {"message": item}
+    severity = item.get("severity", "warning")
+    return {
+        "message": str(item.get("message", "")),
+        "line": item.get("line"),
+        "severity": "error" if severity == "error" else "warning",
+        "rule": item.get("rule", rule),
+    }
+
+
+def _lint(code):
+    """run the linters on code, returns the diagnostics or raises LintError on an error"""
+    import ast
+
+    tree = ast.parse(code)
+    diagnostics = []
+    for linter in list(_linters):
+        rule = getattr(linter, "__name__", None)
+        try:
+            diagnostics.extend(_diagnostic(item, rule) for item in linter(tree, code) or ())
+        except Exception as e:
+            # a broken rule must not let the code through
+            diagnostics.append(_diagnostic({"message": f"linter failed: {e!r}", "severity": "error"}, rule))
+    if any(d["severity"] == "error" for d in diagnostics):
+        raise LintError(diagnostics)
+    return diagnostics
+
+
+# decoders of values the host tags with a type name, name -> (source, decode)
+_decoders = {}
+
+
+def _register_decoder(name, source):
+    """define the decoder of values tagged with the type name, source defines `decode(data)`"""
+    if name in _decoders and _decoders[name][0] == source:
+        return
+    namespace = {}
+    exec(source, namespace)
+    _decoders[name] = (source, namespace["decode"])
+
+
+def _decode_type(name, data):
+    """rebuild a value tagged `{"__pybox_type__": name, "value": data}`"""
+    try:
+        decode = _decoders[name][1]
+    except KeyError:
+        raise ValueError(f"no decoder registered for type {name!r}") from None
+    return decode(data)
+
+
+class RemoteObject:
+    """proxy of a host object exposed through a JSON-RPC handler
+
+    attribute access and method calls are routed to the host,
+    only the methods and attributes allowed by the host are reachable
+    """
+
+    def __init__(self, handle):
+        spec = pybox_json_rpc(handle, "spec")
+        object.__setattr__(self, "_handle", handle)
+        object.__setattr__(self, "_methods", frozenset(spec["methods"]))
+        object.__setattr__(self, "_attributes", frozenset(spec["attributes"]))
+
+    def __getattr__(self, name):
+        if name in self._methods:
+            handle = self._handle
+
+            def method(*args, **kwargs):
+                return pybox_json_rpc(handle, "call", name, *args, **kwargs)
+
+            method.__name__ = name
+            return method
+        if name in self._attributes:
+            return pybox_json_rpc(self._handle, "getattr", name)
+        raise AttributeError(f"remote object has no attribute '{name}'")
+
+    def __setattr__(self, name, value):
+        raise AttributeError("remote object is read-only")
+
+    def __dir__(self):
+        return sorted(self._methods | self._attributes)
+
+    def __repr__(self):
+        return f"<pybox.RemoteObject handle={self._handle}>"
+
+
+class KV:
+    """client of the host key/value store registered as `pybox.kv`,
+    values are JSON round-tripped and scoped to the calling environment
+    """
+
+    def __init__(self):
+        self._handle = None
+
+    def _call(self, op, *args):
+        if self._handle is None:
+            self._handle = resolve("pybox.kv")
+        return pybox_json_rpc(self._handle, op, *args)
+
+    def get(self, key, default=None):
+        return self._call("get", key, default)
+
+    def set(self, key, value):
+        self._call("set", key, value)
+
+    def delete(self, key):
+        return self._call("delete", key)
+
+    def keys(self, prefix=""):
+        return self._call("keys", prefix)
+
+    def __repr__(self):
+        return "<pybox.kv>"
+
+
+kv = KV()
+
+
+class HostFS:
+    """client of the host filesystem handler registered as `pybox.hostfs`,
+    paths look like `/<mount>/<relative path>`, `list("/")` lists the mounts
+    """
+
+    def __init__(self):
+        self._handle = None
+
+    def _call(self, op, *args):
+        if self._handle is None:
+            self._handle = resolve("pybox.hostfs")
+        return pybox_json_rpc(self._handle, op, *args)
+
+    def read(self, path):
+        return self._call("read", path)
+
+    def read_bytes(self, path):
+        return bytes.fromhex(self._call("read", path, True))
+
+    def write(self, path, data):
+        if isinstance(data, (bytes, bytearray)):
+            self._call("write", path, data.hex(), True)
+        else:
+            self._call("write", path, data)
+
+    def list(self, path="/"):
+        return self._call("list", path)
+
+    def stat(self, path):
+        return self._call("stat", path)
+
+    def __repr__(self):
+        return "<pybox.hostfs>"
+
+
+hostfs = HostFS()
+
+
+class HTTP:
+    """client of the host http handler registered as `pybox.http`,
+    responses are dicts with `status`, `url`, `headers` and `body`
+    """
+
+    def __init__(self):
+        self._handle = None
+
+    def request(self, method, url, headers=None, body=None):
+        if self._handle is None:
+            self._handle = resolve("pybox.http")
+        return pybox_json_rpc(self._handle, method, url, headers, body)
+
+    def get(self, url, headers=None):
+        return self.request("GET", url, headers)
+
+    def post(self, url, body=None, headers=None):
+        return self.request("POST", url, headers, body)
+
+    def __repr__(self):
+        return "<pybox.http>"
+
+
+http = HTTP()
+
+
+class SQL:
+    """client of the host SQLite handler registered as `pybox.sql`,
+    every environment has its own database, params are a list or a dict
+    """
+
+    def __init__(self):
+        self._handle = None
+
+    def _call(self, op, *args):
+        if self._handle is None:
+            self._handle = resolve("pybox.sql")
+        return pybox_json_rpc(self._handle, op, *args)
+
+    def execute(self, sql, params=None):
+        """run a statement, returns {"rowcount": ..., "lastrowid": ...}"""
+        return self._call("execute", sql, params)
+
+    def query(self, sql, params=None):
+        """run a query, returns the rows as dicts"""
+        result = self._call("query", sql, params)
+        columns = result["columns"]
+        return [dict(zip(columns, row)) for row in result["rows"]]
+
+    def executescript(self, sql):
+        self._call("executescript", sql)
+
+    def __repr__(self):
+        return "<pybox.sql>"
+
+
+sql = SQL()
+
+
+class _BundleFinder:
+    """imports the modules baked into the reactor by pybox-builder,
+    appended to sys.meta_path so the standard library takes precedence
+    """
+
+    @classmethod
+    def find_spec(cls, name, path=None, target=None):
+        found = pybox_bundle_source(name)
+        if found is None:
+            return None
+        from importlib.machinery import ModuleSpec
+
+        is_package = found[1]
+        origin = name.replace(".", "/") + ("/__init__.py" if is_package else ".py")
+        return ModuleSpec(name, cls, origin=f"<bundle>/{origin}", is_package=is_package)
+
+    @staticmethod
+    def create_module(spec):
+        return None
+
+    @staticmethod
+    def exec_module(module):
+        source, _ = pybox_bundle_source(module.__spec__.name)
+        code = compile(source, module.__spec__.origin, "exec")
+        exec(code, module.__dict__)
+
+
+def _install_bundle_finder():
+    import sys
+
+    sys.meta_path.append(_BundleFinder)
+
+
+_install_bundle_finder()
@@ -0,0 +1,154 @@
+//! ring.rs shared-memory ring buffer between host and guest
+//!
+//! 环形缓冲区由 host 在 guest 内存中分配，布局（小端 u32）：
+//! `capacity | head | tail | data[capacity]`，head / tail 为累计写入 / 读取的字节数，
+//! 每条消息为 `u32 长度 + 数据`。每个方向只允许一个写者和一个读者
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::codec::SYSTEM_HANDLE_BASE;
+use crate::ioctl::ioctl_host;
+
+/// 系统 handle：按名称查询环形缓冲区的地址，响应为十进制地址
+pub const RING_HANDLE: usize = SYSTEM_HANDLE_BASE + 3;
+/// 头部大小：capacity + head + tail
+pub const RING_HEADER_SIZE: usize = 12;
+
+pub struct Ring<'a> {
+    mem: &'a mut [u8],
+}
+
+impl<'a> Ring<'a> {
+    /// mem 为整个环形缓冲区（头部 + 数据区）
+    pub fn new(mem: &'a mut [u8]) -> Self {
+        Self { mem }
+    }
+
+    fn load(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.mem[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn store(&mut self, offset: usize, value: u32) {
+        self.mem[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.load(0)
+    }
+
+    /// 已写入未读取的字节数
+    pub fn used(&self) -> u32 {
+        self.load(4).wrapping_sub(self.load(8))
+    }
+
+    fn copy_in(&mut self, pos: u32, data: &[u8]) {
+        let capacity = self.capacity() as usize;
+        let start = pos as usize % capacity;
+        let first = data.len().min(capacity - start);
+        let base = RING_HEADER_SIZE;
+        self.mem[base + start..base + start + first].copy_from_slice(&data[..first]);
+        self.mem[base..base + data.len() - first].copy_from_slice(&data[first..]);
+    }
+
+    fn copy_out(&self, pos: u32, data: &mut [u8]) {
+        let capacity = self.capacity() as usize;
+        let start = pos as usize % capacity;
+        let first = data.len().min(capacity - start);
+        let base = RING_HEADER_SIZE;
+        let len = data.len();
+        data[..first].copy_from_slice(&self.mem[base + start..base + start + first]);
+        data[first..].copy_from_slice(&self.mem[base..base + len - first]);
+    }
+
+    /// 写入一条消息，空间不足时返回 false
+    pub fn write(&mut self, data: &[u8]) -> bool {
+        let needed = 4 + data.len() as u64;
+        if needed > (self.capacity() - self.used()) as u64 {
+            return false;
+        }
+        let head = self.load(4);
+        self.copy_in(head, &(data.len() as u32).to_le_bytes());
+        self.copy_in(head.wrapping_add(4), data);
+        self.store(4, head.wrapping_add(needed as u32));
+        true
+    }
+
+    /// 读取一条消息，没有消息时返回 None
+    pub fn read(&mut self) -> Option<Vec<u8>> {
+        if self.used() < 4 {
+            return None;
+        }
+        let tail = self.load(8);
+        let mut len = [0u8; 4];
+        self.copy_out(tail, &mut len);
+        let mut data = vec![0u8; u32::from_le_bytes(len) as usize];
+        self.copy_out(tail.wrapping_add(4), &mut data);
+        self.store(8, tail.wrapping_add(4 + data.len() as u32));
+        Some(data)
+    }
+}
+
+thread_local! {
+    /// 环形缓冲区名称 -> guest 内存地址
+    static RINGS: RefCell<HashMap<String, usize>> = RefCell::new(HashMap::new());
+}
+
+/// 按名称获取 host 创建的环形缓冲区，地址只从 host 查询，guest 代码无法指定
+pub fn with_ring<R>(name: &str, f: impl FnOnce(&mut Ring<'static>) -> R) -> Option<R> {
+    let ptr = RINGS.with(|rings| rings.borrow().get(name).copied());
+    let ptr = match ptr {
+        Some(ptr) => ptr,
+        None => {
+            let (success, data) = ioctl_host(RING_HANDLE, name.as_bytes());
+            if !success {
+                return None;
+            }
+            let ptr: usize = std::str::from_utf8(&data).ok()?.parse().ok()?;
+            RINGS.with(|rings| rings.borrow_mut().insert(name.to_string(), ptr));
+            ptr
+        }
+    };
+    let mem = unsafe {
+        let capacity = u32::from_le_bytes(*(ptr as *const [u8; 4])) as usize;
+        std::slice::from_raw_parts_mut(ptr as *mut u8, RING_HEADER_SIZE + capacity)
+    };
+    Some(f(&mut Ring::new(mem)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_ring(capacity: u32) -> Vec<u8> {
+        let mut mem = vec![0u8; RING_HEADER_SIZE + capacity as usize];
+        mem[..4].copy_from_slice(&capacity.to_le_bytes());
+        mem
+    }
+
+    #[test]
+    fn test_ring_write_and_read() {
+        let mut mem = new_ring(32);
+        let mut ring = Ring::new(&mut mem);
+        assert_eq!(ring.read(), None);
+        assert!(ring.write(b"hello"));
+        assert!(ring.write(b""));
+        assert_eq!(ring.read().unwrap(), b"hello");
+        assert_eq!(ring.read().unwrap(), b"");
+        assert_eq!(ring.read(), None);
+    }
+
+    #[test]
+    fn test_ring_wrap_and_full() {
+        let mut mem = new_ring(16);
+        let mut ring = Ring::new(&mut mem);
+        for i in 0..100u8 {
+            assert!(ring.write(&[i; 7]));
+            // 4 + 7 + 4 + 7 > 16
+            assert!(!ring.write(&[i; 7]));
+            assert_eq!(ring.read().unwrap(), vec![i; 7]);
+        }
+        assert!(!ring.write(&[0; 13]));
+        assert!(ring.write(&[0; 12]));
+    }
+}
@@ -1,22 +1,159 @@
+//! sanitizer.rs 创建解释器时调整 builtins，并记录调整的内容
+//!
+//! 每次创建解释器时的调整相同，pybox_sanitizer_report 导出最近一次的记录，
+//! host 据此确认沙箱实际生效的策略
+
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+use libc::ssize_t;
 use rustpython_vm::{self, AsObject, VirtualMachine};
 
-pub(crate) fn builtins_sanitizer(vm: &VirtualMachine) -> Result<(), String> {
-    // try to delete quit and exit
-    let _ = vm
-        .builtins
-        .as_object()
-        .del_item(&*vm.ctx.new_str("threading"), vm);
-    let _ = vm
-        .builtins
-        .as_object()
-        .del_item(&*vm.ctx.new_str("_thread"), vm);
-    let _ = vm
-        .builtins
-        .as_object()
-        .del_item(&*vm.ctx.new_str("quit"), vm);
-    let _ = vm
-        .builtins
-        .as_object()
-        .del_item(&*vm.ctx.new_str("exit"), vm);
+use crate::ioctl;
+use crate::options::{FrozenModules, SanitizerPolicy, module_matches};
+
+/// 默认策略删除的 builtins
+pub(crate) const REMOVED_BUILTINS: [&str; 4] = ["threading", "_thread", "quit", "exit"];
+
+thread_local! {
+    /// 最近一次创建解释器时的记录 (json)
+    static LAST_REPORT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// 创建解释器时对 builtins 和模块的一项修改
+struct Change {
+    /// "builtin" 或 "module"
+    kind: &'static str,
+    name: String,
+    /// "added"、"removed"，或要删除的名称本来就不存在时为 "missing"
+    action: &'static str,
+}
+
+/// 创建解释器过程中的修改记录
+#[derive(Default)]
+pub(crate) struct SanitizerReport {
+    changes: Vec<Change>,
+}
+
+impl SanitizerReport {
+    pub(crate) fn added_builtin(&mut self, name: &str) {
+        self.changes.push(Change {
+            kind: "builtin",
+            name: name.to_string(),
+            action: "added",
+        });
+    }
+
+    /// 没有加载的冻结模块，excluded 为被排除的顶层包名；
+    /// Exclude 列出的名称没有匹配任何冻结模块时记为 "missing"
+    pub(crate) fn excluded_modules(
+        &mut self,
+        selection: &FrozenModules,
+        excluded: &BTreeSet<&str>,
+    ) {
+        for name in excluded {
+            self.changes.push(Change {
+                kind: "module",
+                name: name.to_string(),
+                action: "removed",
+            });
+        }
+        if let FrozenModules::Exclude(names) = selection {
+            for name in names {
+                let frozen = rustpython_pylib::FROZEN_STDLIB
+                    .into_iter()
+                    .any(|(module, _)| module_matches(name, module));
+                if !frozen {
+                    self.changes.push(Change {
+                        kind: "module",
+                        name: name.clone(),
+                        action: "missing",
+                    });
+                }
+            }
+        }
+    }
+
+    /// 保存为最近一次的记录
+    pub(crate) fn save(self) {
+        let changes: Vec<_> = self
+            .changes
+            .iter()
+            .map(|change| {
+                serde_json::json!({
+                    "kind": change.kind,
+                    "name": change.name,
+                    "action": change.action,
+                })
+            })
+            .collect();
+        let report = serde_json::Value::from(changes).to_string();
+        LAST_REPORT.set(Some(report));
+    }
+}
+
+pub(crate) fn builtins_sanitizer(
+    vm: &VirtualMachine,
+    policy: &SanitizerPolicy,
+    report: &mut SanitizerReport,
+) -> Result<(), String> {
+    for name in &policy.remove_builtins {
+        let removed = vm
+            .builtins
+            .as_object()
+            .del_item(&*vm.ctx.new_str(name.as_str()), vm)
+            .is_ok();
+        report.changes.push(Change {
+            kind: "builtin",
+            name: name.clone(),
+            action: if removed { "removed" } else { "missing" },
+        });
+    }
     Ok(())
 }
+
+/// 创建解释器时对 builtins 和模块的修改
+/// * `report` 写入 json 列表，每项为 `{"kind": "builtin" | "module", "name", "action": "added" | "removed" | "missing"}`，
+///   按修改的顺序排列；"missing" 表示要删除的名称本来就不存在
+///
+/// 还没有创建过解释器时先创建分析用的解释器；返回 0 表示成功，-1 表示参数无效
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_sanitizer_report(report: *mut *mut ioctl::pybox_bytes) -> ssize_t {
+    if report.is_null() {
+        return -1;
+    }
+    if LAST_REPORT.with_borrow(Option::is_none) {
+        crate::analysis::analyzer();
+    }
+    let last = LAST_REPORT.with_borrow(|last| last.clone().unwrap_or_default());
+    ioctl::pybox_bytes::write_to(report, last.as_bytes());
+    0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sanitizer_report() {
+        crate::pybox_new_interpreter();
+        let mut result = std::ptr::null_mut();
+        assert_eq!(pybox_sanitizer_report(&mut result), 0);
+        let report = unsafe { (*result).string().unwrap().to_string() };
+        let report: serde_json::Value = serde_json::from_str(&report).unwrap();
+        let find = |name: &str| {
+            report
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|change| change["name"] == name)
+                .map(|change| change["action"].as_str().unwrap().to_string())
+        };
+        assert_eq!(find("exit").as_deref(), Some("removed"));
+        assert_eq!(find("quit").as_deref(), Some("removed"));
+        assert_eq!(find("pybox_ioctl_host").as_deref(), Some("added"));
+        assert_eq!(find("print"), None);
+
+        assert_eq!(pybox_sanitizer_report(std::ptr::null_mut()), -1);
+    }
+}
@@ -0,0 +1,241 @@
+//! stdio.rs 分帧的 stdio 命令协议，启用 stdio feature 时导出 _start
+//!
+//! 通用的 WASI 运行时（wasmtime CLI、wasmer、WasmEdge）不需要实现导出的 ABI，
+//! 运行模块并通过 stdin / stdout 交换消息即可驱动 pybox：
+//!
+//! - 消息：4 字节小端字段数，随后每个字段为 4 字节小端长度 + 数据
+//! - 命令：第一个字段为命令名，`init_local <id>`、`init_local_from <id> <from>`、`del_local <id>`、
+//!   `exec <id> <code>`、`assign <id> <name> <json>`、`retrieve <id> <name>`、`protect <id> <name>`
+//! - 响应：`ok <data>` 或 `error <message>`；exec 的 data 为 pybox_exec_report 的 json，
+//!   retrieve 的 data 为变量的 json，其余命令的 data 为空
+//! - guest 代码调用 host 时模块不再导入 pybox_ioctl_host_req_impl，而是在 stdout 发送
+//!   `ioctl <handle> <data>`（handle 为十进制），host 以 `ok <data>` 或 `error` 响应后继续执行
+//! - stdin 结束时 _start 返回
+
+use std::io::{self, Read, Write};
+
+use libc::{c_void, size_t, ssize_t};
+
+use crate::exec::{pybox_assign, pybox_exec, pybox_exec_report, pybox_retrieve};
+use crate::ioctl::{pybox_bytes, pybox_ioctl_packet};
+use crate::mem::{pybox_alloc_mem, pybox_free_mem};
+use crate::protected::pybox_local_protect;
+use crate::{pybox_del_local, pybox_init_local, pybox_init_local_from};
+
+/// 读取一条消息，stdin 在消息开始前结束时返回 None
+pub fn read_message(input: &mut impl Read) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let mut count = [0; 4];
+    match input.read_exact(&mut count) {
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let mut fields = Vec::new();
+    for _ in 0..u32::from_le_bytes(count) {
+        let mut len = [0; 4];
+        input.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as u64;
+        // 不按声明的长度预先分配，错误的长度不会耗尽内存
+        let mut field = Vec::new();
+        if input.take(len).read_to_end(&mut field)? as u64 != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        fields.push(field);
+    }
+    Ok(Some(fields))
+}
+
+pub fn write_message(output: &mut impl Write, fields: &[&[u8]]) -> io::Result<()> {
+    let mut message = (fields.len() as u32).to_le_bytes().to_vec();
+    for field in fields {
+        message.extend_from_slice(&(field.len() as u32).to_le_bytes());
+        message.extend_from_slice(field);
+    }
+    output.write_all(&message)?;
+    output.flush()
+}
+
+/// 处理命令直到 input 结束
+pub fn serve(input: &mut impl Read, output: &mut impl Write) -> io::Result<()> {
+    while let Some(command) = read_message(input)? {
+        match dispatch(&command) {
+            Ok(data) => write_message(output, &[b"ok", &data])?,
+            Err(message) => write_message(output, &[b"error", message.as_bytes()])?,
+        }
+    }
+    Ok(())
+}
+
+/// 通用 WASI 运行时的入口
+#[cfg(target_arch = "wasm32")]
+#[unsafe(export_name = "_start")]
+pub extern "C" fn pybox_stdio_start() {
+    if let Err(err) = serve(&mut io::stdin(), &mut io::stdout()) {
+        eprintln!("pybox: {}", err);
+    }
+}
+
+fn dispatch(command: &[Vec<u8>]) -> Result<Vec<u8>, String> {
+    let Some((name, args)) = command.split_first() else {
+        return Err("Empty command".to_string());
+    };
+    let name = String::from_utf8_lossy(name);
+    let arity = match name.as_ref() {
+        "init_local" | "del_local" => 1,
+        "init_local_from" | "exec" | "retrieve" | "protect" => 2,
+        "assign" => 3,
+        _ => return Err(format!("Unknown command '{}'", name)),
+    };
+    if args.len() != arity {
+        return Err(format!("'{}' takes {} arguments", name, arity));
+    }
+
+    with_bytes(args, |ptrs| {
+        let mut first = std::ptr::null_mut();
+        let mut error = std::ptr::null_mut();
+        let result = match name.as_ref() {
+            "init_local" => pybox_init_local(ptrs[0]),
+            "init_local_from" => pybox_init_local_from(ptrs[0], ptrs[1]),
+            "del_local" => pybox_del_local(ptrs[0]),
+            "exec" => pybox_exec(ptrs[0], ptrs[1], &mut first, &mut error),
+            "assign" => pybox_assign(ptrs[0], ptrs[1], ptrs[2], &mut error),
+            "retrieve" => pybox_retrieve(ptrs[0], ptrs[1], &mut first, &mut error),
+            _ => pybox_local_protect(ptrs[0], ptrs[1]),
+        };
+        let first = take(first);
+        let error = take(error);
+        if result != 0 {
+            return Err(match error.is_empty() {
+                true => format!("'{}' failed", name),
+                false => String::from_utf8_lossy(&error).into_owned(),
+            });
+        }
+        match name.as_ref() {
+            "exec" => {
+                let mut report = std::ptr::null_mut();
+                if pybox_exec_report(&mut report) != 0 {
+                    return Err("Failed to build the exec report".to_string());
+                }
+                Ok(take(report))
+            }
+            "retrieve" => Ok(first),
+            _ => Ok(Vec::new()),
+        }
+    })
+}
+
+/// 为每个参数分配 pybox_bytes，调用 f 后释放
+fn with_bytes<T>(args: &[Vec<u8>], f: impl FnOnce(&[*mut pybox_bytes]) -> T) -> T {
+    let ptrs: Vec<_> = args.iter().map(|arg| pybox_bytes::new_bytes(arg)).collect();
+    let result = f(&ptrs);
+    for ptr in ptrs {
+        pybox_free_mem(ptr as *mut c_void);
+    }
+    result
+}
+
+/// 读取并释放输出的 pybox_bytes
+fn take(ptr: *mut pybox_bytes) -> Vec<u8> {
+    if ptr.is_null() {
+        return Vec::new();
+    }
+    let data = unsafe {
+        let data = std::ptr::addr_of!((*ptr).data) as *const u8;
+        std::slice::from_raw_parts(data, (*ptr).length).to_vec()
+    };
+    pybox_free_mem(ptr as *mut c_void);
+    data
+}
+
+/// stdio 模式下 host 调用经 stdout / stdin 转发，响应与导入的实现一样由 pybox_alloc_mem 分配
+pub fn pybox_ioctl_host_req_impl(
+    handle: size_t,
+    req: *mut pybox_ioctl_packet,
+    resp: *mut pybox_ioctl_packet,
+) -> ssize_t {
+    let request = unsafe { std::slice::from_raw_parts((*req).buf as *const u8, (*req).buf_len) };
+    let handle = handle.to_string();
+    if write_message(&mut io::stdout(), &[b"ioctl", handle.as_bytes(), request]).is_err() {
+        return -1;
+    }
+    let Ok(Some(response)) = read_message(&mut io::stdin()) else {
+        return -1;
+    };
+    let [status, data] = response.as_slice() else {
+        return -1;
+    };
+    if status != b"ok" {
+        return -1;
+    }
+    let buf = pybox_alloc_mem(data.len());
+    unsafe {
+        std::ptr::copy_nonoverlapping(data.as_ptr(), buf as *mut u8, data.len());
+        (*resp).buf = buf;
+        (*resp).buf_len = data.len();
+    }
+    0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(fields: &[&str]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let fields: Vec<&[u8]> = fields.iter().map(|field| field.as_bytes()).collect();
+        write_message(&mut data, &fields).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_message() {
+        let data = message(&["exec", "env", ""]);
+        let mut input = data.as_slice();
+        let fields = read_message(&mut input).unwrap().unwrap();
+        assert_eq!(fields, vec![b"exec".to_vec(), b"env".to_vec(), Vec::new()]);
+        assert!(read_message(&mut input).unwrap().is_none());
+
+        // 消息中途结束
+        let mut input = &data[..data.len() - 4];
+        read_message(&mut input).unwrap_err();
+    }
+
+    #[test]
+    fn test_serve() {
+        let mut input = Vec::new();
+        for command in [
+            &["init_local", "test_stdio_serve"][..],
+            &["assign", "test_stdio_serve", "x", "[1, 2]"],
+            &["exec", "test_stdio_serve", "print(len(x))\nx.append(3)"],
+            &["retrieve", "test_stdio_serve", "x"],
+            &["exec", "test_stdio_serve"],
+            &["quit"],
+        ] {
+            input.extend(message(command));
+        }
+        let mut output = Vec::new();
+        serve(&mut input.as_slice(), &mut output).unwrap();
+
+        let mut output = output.as_slice();
+        let mut responses = Vec::new();
+        while let Some(fields) = read_message(&mut output).unwrap() {
+            let fields: Vec<String> = fields
+                .iter()
+                .map(|field| String::from_utf8_lossy(field).into_owned())
+                .collect();
+            responses.push(fields);
+        }
+        assert_eq!(responses.len(), 6);
+        assert_eq!(responses[0], ["ok", ""]);
+        assert_eq!(responses[1], ["ok", ""]);
+        assert_eq!(responses[2][0], "ok");
+        assert!(
+            responses[2][1].contains(r#""stdout": "2\n""#),
+            "{:?}",
+            responses[2]
+        );
+        assert_eq!(responses[3][0], "ok");
+        assert_eq!(responses[3][1].replace(' ', ""), "[1,2,3]");
+        assert_eq!(responses[4], ["error", "'exec' takes 2 arguments"]);
+        assert_eq!(responses[5], ["error", "Unknown command 'quit'"]);
+    }
+}
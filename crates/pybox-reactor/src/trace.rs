@@ -0,0 +1,152 @@
+//! trace.rs 在 interpreter 创建、编译、执行和 ioctl 调用处记录 tracing span
+//!
+//! 启用 tracing feature 时 span 退出后以一行文本写入 WASI stderr：
+//! `pybox-trace: <按深度缩进><name>{fields} <耗时>us`，host 需要转发 guest 的 stderr
+//! （pybox-host、pybox-node 和 `wasmtime run` 都会转发）；未启用时 span! 不做任何事
+
+/// 进入一个 span，返回的 guard 离开作用域时退出
+macro_rules! span {
+    ($name:literal $(, $($fields:tt)*)?) => {{
+        #[cfg(feature = "tracing")]
+        let guard = {
+            $crate::trace::init();
+            ::tracing::info_span!($name $(, $($fields)*)?).entered()
+        };
+        #[cfg(not(feature = "tracing"))]
+        let guard = $crate::trace::NoSpan;
+        guard
+    }};
+}
+
+pub(crate) use span;
+
+/// 未启用 tracing feature 时 span! 返回的空 guard
+#[cfg(not(feature = "tracing"))]
+pub struct NoSpan;
+
+#[cfg(feature = "tracing")]
+pub use subscriber::init;
+
+#[cfg(feature = "tracing")]
+mod subscriber {
+    use std::collections::HashMap;
+    use std::fmt::Write as _;
+    use std::io::Write as _;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Instant;
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    struct Span {
+        name: &'static str,
+        fields: String,
+        entered: Option<Instant>,
+    }
+
+    /// 将 span 的耗时和事件写入 stderr
+    struct StderrSubscriber {
+        spans: Mutex<HashMap<u64, Span>>,
+        /// 当前进入的 span 的层数，用于缩进
+        depth: AtomicU64,
+        /// span id 从 1 开始，0 不是合法的 Id
+        next_id: AtomicU64,
+    }
+
+    /// 以 `name=value` 的形式追加字段
+    struct Fields<'a>(&'a mut String);
+
+    impl Visit for Fields<'_> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.record_debug(field, &format_args!("{}", value));
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            let separator = if self.0.is_empty() { "" } else { " " };
+            let _ = write!(self.0, "{}{}={:?}", separator, field.name(), value);
+        }
+    }
+
+    impl StderrSubscriber {
+        fn write_line(&self, depth: u64, text: &str) {
+            let indent = "  ".repeat(depth as usize);
+            let _ = writeln!(std::io::stderr(), "pybox-trace: {}{}", indent, text);
+        }
+    }
+
+    impl Subscriber for StderrSubscriber {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            let mut fields = String::new();
+            attributes.record(&mut Fields(&mut fields));
+            self.spans.lock().unwrap().insert(
+                id,
+                Span {
+                    name: attributes.metadata().name(),
+                    fields,
+                    entered: None,
+                },
+            );
+            Id::from_u64(id)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+                values.record(&mut Fields(&mut span.fields));
+            }
+        }
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = String::new();
+            event.record(&mut Fields(&mut fields));
+            self.write_line(self.depth.load(Ordering::Relaxed), &fields);
+        }
+
+        fn enter(&self, span: &Id) {
+            if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+                span.entered = Some(Instant::now());
+            }
+            self.depth.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn exit(&self, span: &Id) {
+            let depth = self.depth.fetch_sub(1, Ordering::Relaxed).saturating_sub(1);
+            let spans = self.spans.lock().unwrap();
+            let Some(span) = spans.get(&span.into_u64()) else {
+                return;
+            };
+            let elapsed = span
+                .entered
+                .map_or(0, |entered| entered.elapsed().as_micros());
+            self.write_line(
+                depth,
+                &format!("{}{{{}}} {}us", span.name, span.fields, elapsed),
+            );
+        }
+
+        fn try_close(&self, id: Id) -> bool {
+            self.spans.lock().unwrap().remove(&id.into_u64());
+            true
+        }
+    }
+
+    /// 第一次记录 span 时安装 subscriber，其他 subscriber 已经安装时不替换
+    pub fn init() {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            let _ = tracing::subscriber::set_global_default(StderrSubscriber {
+                spans: Mutex::new(HashMap::new()),
+                depth: AtomicU64::new(0),
+                next_id: AtomicU64::new(1),
+            });
+        });
+    }
+}
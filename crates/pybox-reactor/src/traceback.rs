@@ -0,0 +1,135 @@
+//! traceback.rs 保留 pybox_exec 提交的源码，traceback 中显示出错的源码行
+//!
+//! 每次 exec 以 `<exec-N>` 为文件名编译，之前 exec 定义的函数出错时也能找到对应的源码；
+//! 源码同时登记到 linecache，沙箱内的 traceback 模块同样可以显示源码行。
+//! 只保留最近 MAX_RETAINED 次 exec 的源码
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use rustpython_vm::{PyObjectRef, PyResult, VirtualMachine, builtins::PyBaseExceptionRef};
+
+/// 保留源码的 exec 次数
+const MAX_RETAINED: usize = 64;
+
+thread_local! {
+    /// (文件名, 源码)，按提交顺序排列
+    static SOURCES: RefCell<VecDeque<(String, Rc<str>)>> = const { RefCell::new(VecDeque::new()) };
+    static NEXT_ID: Cell<u64> = const { Cell::new(1) };
+}
+
+/// 保留一次 exec 的源码，返回编译时使用的文件名
+pub fn retain(vm: &VirtualMachine, code: &str) -> String {
+    let id = NEXT_ID.replace(NEXT_ID.get() + 1);
+    let filename = format!("<exec-{}>", id);
+    let evicted = SOURCES.with_borrow_mut(|sources| {
+        sources.push_back((filename.clone(), Rc::from(code)));
+        if sources.len() > MAX_RETAINED {
+            sources.pop_front()
+        } else {
+            None
+        }
+    });
+
+    // linecache 不可用时只影响沙箱内的 traceback 模块
+    let _ = (|| -> PyResult<()> {
+        let cache = vm.import("linecache", 0)?.get_attr("cache", vm)?;
+        if let Some((evicted, _)) = evicted {
+            let _ = cache.del_item(evicted.as_str(), vm);
+        }
+        let lines: Vec<PyObjectRef> = code
+            .split_inclusive('\n')
+            .map(|line| vm.ctx.new_str(line).into())
+            .collect();
+        // mtime 为 None 的条目不会被 checkcache 清除
+        let entry = vm.ctx.new_tuple(vec![
+            vm.ctx.new_int(code.len()).into(),
+            vm.ctx.none(),
+            vm.ctx.new_list(lines).into(),
+            vm.ctx.new_str(filename.as_str()).into(),
+        ]);
+        cache.set_item(filename.as_str(), entry.into(), vm)
+    })();
+    filename
+}
+
+/// 保留的源码中的一行，lineno 从 1 开始
+fn source_line(filename: &str, lineno: usize) -> Option<String> {
+    SOURCES.with_borrow(|sources| {
+        let (_, code) = sources.iter().find(|(name, _)| name == filename)?;
+        let line = code.lines().nth(lineno.checked_sub(1)?)?.trim();
+        (!line.is_empty()).then(|| line.to_string())
+    })
+}
+
+/// 与 vm.write_exception 相同，并在每个 exec 帧之后写入对应的源码行
+pub fn write_exception(
+    vm: &VirtualMachine,
+    output: &mut String,
+    exception: &PyBaseExceptionRef,
+) -> std::fmt::Result {
+    let mut traceback = String::new();
+    vm.write_exception(&mut traceback, exception)
+        .map_err(|_| std::fmt::Error)?;
+    for line in traceback.split_inclusive('\n') {
+        output.push_str(line);
+        if let Some(source) = frame_source(line) {
+            if !output.ends_with('\n') {
+                output.push('\n');
+            }
+            output.push_str("    ");
+            output.push_str(&source);
+            output.push('\n');
+        }
+    }
+    Ok(())
+}
+
+/// `  File "<exec-N>", line L, in f` 对应的源码行
+fn frame_source(line: &str) -> Option<String> {
+    let rest = line.trim_end().strip_prefix("  File \"")?;
+    let (filename, rest) = rest.split_once("\", line ")?;
+    let lineno = rest.split(',').next()?.parse().ok()?;
+    source_line(filename, lineno)
+}
+
+#[cfg(test)]
+mod test {
+    use rustpython_vm::compiler::Mode;
+
+    use super::*;
+    use crate::pybox_new_interpreter;
+
+    #[test]
+    fn test_traceback_source() {
+        let interpreter = pybox_new_interpreter();
+        interpreter.enter(|vm| {
+            let define = "def f(x):\n    return 1 / x\n";
+            let filename = retain(vm, define);
+            let code = vm.compile(define, Mode::Exec, filename.clone()).unwrap();
+            let scope = vm.new_scope_with_builtins();
+            vm.run_code_obj(code, scope.clone()).unwrap();
+
+            // 之前 exec 定义的函数也显示自己的源码行
+            let call = "y = 0\nf(y)\n";
+            let code = vm.compile(call, Mode::Exec, retain(vm, call)).unwrap();
+            let exception = vm.run_code_obj(code, scope).unwrap_err();
+            let mut output = String::new();
+            write_exception(vm, &mut output, &exception).unwrap();
+            assert!(
+                output.contains("line 2, in <module>\n    f(y)\n"),
+                "{}",
+                output
+            );
+            assert!(
+                output.contains(&format!(
+                    "File \"{}\", line 2, in f\n    return 1 / x\n",
+                    filename
+                )),
+                "{}",
+                output
+            );
+        });
+    }
+}
@@ -0,0 +1,162 @@
+//! view.rs 将环境中 bytes / bytearray 变量的内存区域暴露给 host，host 直接读取而不拷贝
+//!
+//! 导出期间 guest 持有变量的 buffer 引用：对象不会被释放，bytearray 也无法改变大小
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use libc::ssize_t;
+use rustpython_vm::{
+    PyResult,
+    builtins::{PyByteArray, PyBytes},
+    protocol::PyBuffer,
+};
+
+use crate::exec::clone_local;
+use crate::ioctl;
+use crate::protected::ProtectedLocals;
+
+thread_local! {
+    /// view id -> 导出中的 buffer
+    static VIEWS: RefCell<HashMap<u32, PyBuffer>> = RefCell::new(HashMap::new());
+    static NEXT_VIEW_ID: RefCell<u32> = const { RefCell::new(1) };
+}
+
+/// 导出指定环境中 bytes / bytearray 变量的内存区域，直到 pybox_view_release
+///
+/// # Arguments
+///
+/// * `id` 指定 locals 环境 id
+/// * `name` 变量名
+/// * `info` 输出 3 个 u32：view id、数据地址、数据长度
+/// * `error` pybox 错误信息
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_view_acquire(
+    id: *const ioctl::pybox_bytes,
+    name: *const ioctl::pybox_bytes,
+    info: *mut u32,
+    error: *mut *mut ioctl::pybox_bytes,
+) -> ssize_t {
+    if id.is_null() || name.is_null() || info.is_null() {
+        ioctl::pybox_bytes::write_to(error, b"Invalid arguments: id, name or info is null");
+        return -1;
+    }
+
+    let Ok((id, name)) = (|| -> Result<_, ()> {
+        unsafe {
+            let id = (*id).string()?;
+            let name = (*name).string()?;
+            Ok((id, name))
+        }
+    })() else {
+        ioctl::pybox_bytes::write_to(error, b"Invalid UTF-8 encoding in id or name");
+        return -1;
+    };
+
+    let (interpreter, locals_ref) = match clone_local(id) {
+        Ok(values) => values,
+        Err(err_msg) => {
+            ioctl::pybox_bytes::write_to(error, err_msg.as_bytes());
+            return -1;
+        }
+    };
+
+    interpreter.enter(|vm| {
+        let result = (|| -> PyResult<PyBuffer> {
+            let protected_locals =
+                locals_ref
+                    .downcast_ref::<ProtectedLocals>()
+                    .ok_or_else(|| {
+                        vm.new_type_error("locals is not a ProtectedLocals instance".to_string())
+                    })?;
+            let value = protected_locals.dict().get_item(name, vm)?;
+            if value.downcast_ref::<PyBytes>().is_none()
+                && value.downcast_ref::<PyByteArray>().is_none()
+            {
+                return Err(vm.new_type_error(format!(
+                    "'{}' is not bytes or bytearray",
+                    value.class().name()
+                )));
+            }
+            PyBuffer::try_from_borrowed_object(vm, &value)
+        })();
+
+        match result {
+            Ok(buffer) => {
+                let (ptr, len) = {
+                    let Some(data) = buffer.as_contiguous() else {
+                        ioctl::pybox_bytes::write_to(error, b"Buffer is not contiguous");
+                        return -1;
+                    };
+                    (data.as_ptr() as u32, data.len() as u32)
+                };
+                let view_id = NEXT_VIEW_ID.with_borrow_mut(|next| {
+                    let view_id = *next;
+                    *next = next.wrapping_add(1).max(1);
+                    view_id
+                });
+                VIEWS.with_borrow_mut(|views| views.insert(view_id, buffer));
+                unsafe {
+                    // info 由 host 分配，不保证对齐
+                    info.write_unaligned(view_id);
+                    info.add(1).write_unaligned(ptr);
+                    info.add(2).write_unaligned(len);
+                }
+                0
+            }
+            Err(exception) => {
+                let mut error_string = String::new();
+                if vm.write_exception(&mut error_string, &exception).is_err() {
+                    error_string.push_str("Failed to view object: unknown error");
+                }
+                ioctl::pybox_bytes::write_to(error, error_string.as_bytes());
+                -1
+            }
+        }
+    })
+}
+
+/// 释放 pybox_view_acquire 导出的内存区域
+/// * `view_id` pybox_view_acquire 返回的 view id
+#[unsafe(no_mangle)]
+pub extern "C" fn pybox_view_release(view_id: u32) -> ssize_t {
+    match VIEWS.with_borrow_mut(|views| views.remove(&view_id)) {
+        Some(_) => 0,
+        None => -1,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{exec, mem, pybox_init_local};
+
+    #[test]
+    fn test_view_acquire_and_release() {
+        let id = ioctl::pybox_bytes::new_bytes(b"test_view");
+        assert_eq!(pybox_init_local(id), 0);
+        let code = ioctl::pybox_bytes::new_bytes(b"data = bytearray(b'hello view')\ntext = 'str'");
+        let output = mem::pybox_alloc_mem(std::mem::size_of::<*mut ioctl::pybox_bytes>())
+            as *mut *mut ioctl::pybox_bytes;
+        assert_eq!(exec::pybox_exec(id, code, output, std::ptr::null_mut()), 0);
+
+        let mut info = [0u32; 3];
+        let error = mem::pybox_alloc_mem(std::mem::size_of::<*mut ioctl::pybox_bytes>())
+            as *mut *mut ioctl::pybox_bytes;
+        let name = ioctl::pybox_bytes::new_bytes(b"data");
+        assert_eq!(pybox_view_acquire(id, name, info.as_mut_ptr(), error), 0);
+        let data =
+            unsafe { std::slice::from_raw_parts(info[1] as usize as *const u8, info[2] as usize) };
+        assert_eq!(data, b"hello view");
+
+        // 导出期间 bytearray 不能改变大小
+        let code = ioctl::pybox_bytes::new_bytes(b"data.extend(b'!')");
+        assert_eq!(exec::pybox_exec(id, code, output, error), -1);
+        assert_eq!(pybox_view_release(info[0]), 0);
+        assert_eq!(pybox_view_release(info[0]), -1);
+        assert_eq!(exec::pybox_exec(id, code, output, error), 0);
+
+        let name = ioctl::pybox_bytes::new_bytes(b"text");
+        assert_eq!(pybox_view_acquire(id, name, info.as_mut_ptr(), error), -1);
+    }
+}
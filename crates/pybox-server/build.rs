@@ -0,0 +1,9 @@
+//! 启用 grpc feature 时从 proto/pybox.proto 生成 tonic 的服务端代码，需要安装 protoc
+
+fn main() {
+    #[cfg(feature = "grpc")]
+    tonic_build::configure()
+        .build_client(false)
+        .compile_protos(&["proto/pybox.proto"], &["proto"])
+        .expect("Failed to compile proto/pybox.proto");
+}
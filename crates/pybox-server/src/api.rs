@@ -0,0 +1,340 @@
+//! api.rs HTTP JSON 接口
+//!
+//! 除 `GET /health` 外的请求需要 `Authorization: Bearer <key>` 或 `X-API-Key: <key>`，
+//! 没有配置 API key 时不验证；环境和快照属于创建它们的 API key，其他 key 访问时返回 404；
+//! 超过每个 key 的数量上限返回 429；错误以 `{"error": "..."}` 返回；
+//! `GET /v1/envs/{env_id}/ws` 升级为 WebSocket 连接，见 websocket.rs
+
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, HeaderMap};
+use hyper::{Method, Request, Response, StatusCode};
+use serde_json::{Value, json};
+
+use crate::pool::{LimitExceeded, NotFound, ReactorPool};
+use crate::sandbox::GuestError;
+use crate::websocket;
+
+pub struct ServerConfig {
+    /// 允许的 API key，为空时不验证
+    pub api_keys: Vec<String>,
+    /// 单次 exec 的默认超时，也是请求可以指定的上限
+    pub max_timeout: Duration,
+    /// 请求体的上限（字节）
+    pub max_body: usize,
+}
+
+pub struct Server {
+    pub pool: Arc<ReactorPool>,
+    pub config: ServerConfig,
+}
+
+#[derive(Debug, PartialEq)]
+enum Route {
+    Health,
+    CreateEnv,
+    DeleteEnv(String),
+    Exec(String),
+    Assign(String),
+    Retrieve(String, String),
+    Snapshot(String),
+    DeleteSnapshot(String),
+    Stream(String),
+}
+
+fn route(method: &Method, path: &str) -> Option<Route> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let owned = |segment: &str| segment.to_string();
+    Some(match (method, segments.as_slice()) {
+        (&Method::GET, ["health"]) => Route::Health,
+        (&Method::POST, ["v1", "envs"]) => Route::CreateEnv,
+        (&Method::DELETE, ["v1", "envs", env_id]) => Route::DeleteEnv(owned(env_id)),
+        (&Method::POST, ["v1", "envs", env_id, "exec"]) => Route::Exec(owned(env_id)),
+        (&Method::POST, ["v1", "envs", env_id, "assign"]) => Route::Assign(owned(env_id)),
+        (&Method::GET, ["v1", "envs", env_id, "vars", name]) => {
+            Route::Retrieve(owned(env_id), owned(name))
+        }
+        (&Method::POST, ["v1", "envs", env_id, "snapshots"]) => Route::Snapshot(owned(env_id)),
+        (&Method::DELETE, ["v1", "snapshots", snapshot_id]) => {
+            Route::DeleteSnapshot(owned(snapshot_id))
+        }
+        (&Method::GET, ["v1", "envs", env_id, "ws"]) => Route::Stream(owned(env_id)),
+        _ => return None,
+    })
+}
+
+/// 请求携带的 API key 在允许的列表中时返回该 key，作为环境和快照的所有者；
+/// 没有配置 API key 时所有请求属于同一个所有者 ""。query 中的 `api_key` 只用于 WebSocket
+pub fn authenticate(
+    api_keys: &[String],
+    headers: &HeaderMap,
+    query: Option<&str>,
+) -> Option<String> {
+    if api_keys.is_empty() {
+        return Some(String::new());
+    }
+    let bearer = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let header = headers
+        .get("x-api-key")
+        .and_then(|value| value.to_str().ok());
+    let query = query.and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("api_key="))
+    });
+    let key = bearer.or(header).or(query)?.trim();
+    api_keys
+        .iter()
+        .find(|allowed| constant_time_eq(allowed.as_bytes(), key.as_bytes()))
+        .cloned()
+}
+
+/// 比较时间与内容无关，避免按响应时间猜测 key
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        let status = if err.is::<NotFound>() {
+            StatusCode::NOT_FOUND
+        } else if err.is::<LimitExceeded>() {
+            StatusCode::TOO_MANY_REQUESTS
+        } else if err.is::<GuestError>() {
+            StatusCode::BAD_REQUEST
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        Self::new(status, format!("{:#}", err))
+    }
+}
+
+fn json_response(status: StatusCode, body: &Value) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(body.to_string())));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    response
+}
+
+pub async fn handle(
+    server: Arc<Server>,
+    request: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let response = match dispatch(&server, request).await {
+        Ok(response) => response,
+        Err(err) => json_response(err.status, &json!({ "error": err.message })),
+    };
+    Ok(response)
+}
+
+async fn dispatch(
+    server: &Arc<Server>,
+    request: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, ApiError> {
+    let route = route(request.method(), request.uri().path())
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "Unknown endpoint"))?;
+    if route == Route::Health {
+        let health = json!({
+            "status": "ok",
+            "reactors": server.pool.size(),
+            "envs": server.pool.env_count(),
+        });
+        return Ok(json_response(StatusCode::OK, &health));
+    }
+    let query = match route {
+        Route::Stream(_) => request.uri().query(),
+        _ => None,
+    };
+    let tenant = authenticate(&server.config.api_keys, request.headers(), query)
+        .ok_or_else(|| ApiError::new(StatusCode::UNAUTHORIZED, "Missing or invalid API key"))?;
+    if let Route::Stream(env_id) = route {
+        return websocket::upgrade(Arc::clone(server), request, tenant, env_id);
+    }
+    let body = read_body(request, server.config.max_body).await?;
+    let (status, body) = call(server, tenant, route, body).await?;
+    Ok(json_response(status, &body))
+}
+
+/// 执行 JSON 接口的请求，返回状态码和响应体
+async fn call(
+    server: &Arc<Server>,
+    tenant: String,
+    route: Route,
+    body: Value,
+) -> Result<(StatusCode, Value), ApiError> {
+    let text = |key: &str| body[key].as_str().map(str::to_string);
+    let pool = Arc::clone(&server.pool);
+    match route {
+        Route::Health | Route::Stream(_) => unreachable!(),
+        Route::CreateEnv => {
+            let from_snapshot = text("from_snapshot");
+            let env_id =
+                blocking(move || pool.create_env(&tenant, from_snapshot.as_deref())).await?;
+            Ok((StatusCode::CREATED, json!({ "env_id": env_id })))
+        }
+        Route::DeleteEnv(env_id) => {
+            blocking(move || pool.delete_env(&tenant, &env_id)).await?;
+            Ok((StatusCode::OK, json!({})))
+        }
+        Route::Exec(env_id) => {
+            let code = text("code")
+                .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "Missing 'code'"))?;
+            let timeout = request_timeout(&body["timeout"], server.config.max_timeout)?;
+            let (outcome, restarted) =
+                blocking(move || pool.exec(&tenant, &env_id, &code, timeout, None)).await?;
+            let mut result = outcome.to_json();
+            result["reactor_restarted"] = restarted.into();
+            Ok((StatusCode::OK, result))
+        }
+        Route::Assign(env_id) => {
+            let name = text("name")
+                .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "Missing 'name'"))?;
+            let value = body.get("value").cloned().unwrap_or(Value::Null);
+            blocking(move || pool.assign(&tenant, &env_id, &name, &value)).await?;
+            Ok((StatusCode::OK, json!({})))
+        }
+        Route::Retrieve(env_id, name) => {
+            let value = blocking(move || pool.retrieve(&tenant, &env_id, &name)).await?;
+            Ok((StatusCode::OK, json!({ "value": value })))
+        }
+        Route::Snapshot(env_id) => {
+            let snapshot_id = blocking(move || pool.snapshot(&tenant, &env_id)).await?;
+            Ok((StatusCode::CREATED, json!({ "snapshot_id": snapshot_id })))
+        }
+        Route::DeleteSnapshot(snapshot_id) => {
+            blocking(move || pool.delete_snapshot(&tenant, &snapshot_id)).await?;
+            Ok((StatusCode::OK, json!({})))
+        }
+    }
+}
+
+/// 读取 json 请求体，空请求体视为 `{}`
+async fn read_body(request: Request<Incoming>, max_body: usize) -> Result<Value, ApiError> {
+    let body = Limited::new(request.into_body(), max_body)
+        .collect()
+        .await
+        .map_err(|_| ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "Request body too large"))?
+        .to_bytes();
+    if body.is_empty() {
+        return Ok(json!({}));
+    }
+    match serde_json::from_slice(&body) {
+        Ok(value @ Value::Object(_)) => Ok(value),
+        Ok(_) => Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "Request body must be a JSON object",
+        )),
+        Err(e) => Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Invalid JSON: {}", e),
+        )),
+    }
+}
+
+/// 请求指定的超时秒数，不能超过服务端的上限
+pub fn request_timeout(timeout: &Value, max_timeout: Duration) -> Result<Duration, ApiError> {
+    if timeout.is_null() {
+        return Ok(max_timeout);
+    }
+    timeout
+        .as_f64()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .map(|timeout| timeout.min(max_timeout))
+        .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "Invalid 'timeout'"))
+}
+
+/// reactor 的调用会阻塞，放到阻塞线程池中执行
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+) -> Result<T, ApiError> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(ApiError::from)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_route() {
+        assert_eq!(route(&Method::GET, "/health"), Some(Route::Health));
+        assert_eq!(route(&Method::POST, "/v1/envs"), Some(Route::CreateEnv));
+        assert_eq!(
+            route(&Method::POST, "/v1/envs/env_1/exec"),
+            Some(Route::Exec("env_1".to_string()))
+        );
+        assert_eq!(
+            route(&Method::GET, "/v1/envs/env_1/vars/x"),
+            Some(Route::Retrieve("env_1".to_string(), "x".to_string()))
+        );
+        assert_eq!(
+            route(&Method::DELETE, "/v1/snapshots/snap_1/"),
+            Some(Route::DeleteSnapshot("snap_1".to_string()))
+        );
+        assert_eq!(
+            route(&Method::GET, "/v1/envs/env_1/ws"),
+            Some(Route::Stream("env_1".to_string()))
+        );
+        assert_eq!(route(&Method::GET, "/v1/envs/env_1/exec"), None);
+        assert_eq!(route(&Method::GET, "/v1/envs"), None);
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let keys = vec!["secret".to_string(), "other".to_string()];
+        let tenant = |tenant: &str| Some(tenant.to_string());
+        let mut headers = HeaderMap::new();
+        assert_eq!(authenticate(&[], &headers, None), tenant(""));
+        assert_eq!(authenticate(&keys, &headers, None), None);
+        assert_eq!(
+            authenticate(&keys, &headers, Some("a=1&api_key=secret")),
+            tenant("secret")
+        );
+        assert_eq!(authenticate(&keys, &headers, Some("api_key=wrong")), None);
+        headers.insert(AUTHORIZATION, "Bearer wrong".parse().unwrap());
+        assert_eq!(authenticate(&keys, &headers, None), None);
+        headers.insert(AUTHORIZATION, "Bearer secret".parse().unwrap());
+        assert_eq!(authenticate(&keys, &headers, None), tenant("secret"));
+        headers.remove(AUTHORIZATION);
+        headers.insert("x-api-key", "other".parse().unwrap());
+        assert_eq!(authenticate(&keys, &headers, None), tenant("other"));
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        let max = Duration::from_secs(10);
+        assert_eq!(request_timeout(&Value::Null, max).ok(), Some(max));
+        assert_eq!(
+            request_timeout(&json!(2.5), max).ok(),
+            Some(Duration::from_millis(2500))
+        );
+        assert_eq!(request_timeout(&json!(60), max).ok(), Some(max));
+        assert!(request_timeout(&json!(-1), max).is_err());
+        assert!(request_timeout(&json!("1"), max).is_err());
+    }
+}
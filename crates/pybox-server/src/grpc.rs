@@ -0,0 +1,364 @@
+//! grpc.rs gRPC 接口，定义见 proto/pybox.proto
+//!
+//! 与 HTTP 接口共享 reactor 池和 API key，key 通过 `authorization: Bearer <key>`
+//! 或 `x-api-key` metadata 传递，环境和快照同样属于创建它们的 key；
+//! 客户端的 deadline (`grpc-timeout`) 映射为 exec 的超时
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use serde_json::Value;
+use tokio_stream::Stream;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tonic::{Request, Response, Status};
+
+use crate::api::{Server, authenticate};
+use crate::pool::{LimitExceeded, NotFound};
+use crate::sandbox::{ExecOutcome, GuestError, OutputSink};
+
+pub mod proto {
+    tonic::include_proto!("pybox.v1");
+}
+
+use proto::exec_event::Event;
+use proto::pybox_server::{Pybox, PyboxServer};
+use proto::{
+    Artifact, AssignRequest, CreateEnvRequest, CreateEnvResponse, Empty, End, EnvRequest,
+    Exception, ExecEvent, ExecRequest, ExecResult, ExecStatus, Output, RetrieveRequest,
+    RetrieveResponse, SnapshotRequest, SnapshotResponse,
+};
+
+pub async fn serve(server: Arc<Server>, listen: SocketAddr) -> anyhow::Result<()> {
+    let api_keys = server.config.api_keys.clone();
+    let service =
+        PyboxServer::with_interceptor(GrpcService { server }, move |request: Request<()>| {
+            let headers = request.metadata().clone().into_headers();
+            let Some(tenant) = authenticate(&api_keys, &headers, None) else {
+                return Err(Status::unauthenticated("Missing or invalid API key"));
+            };
+            let mut request = request;
+            request.extensions_mut().insert(Tenant(tenant));
+            Ok(request)
+        });
+    eprintln!("pybox-server: gRPC listening on {}", listen);
+    tonic::transport::Server::builder()
+        .add_service(service)
+        .serve(listen)
+        .await?;
+    Ok(())
+}
+
+struct GrpcService {
+    server: Arc<Server>,
+}
+
+/// 拦截器验证的 API key，见 api::authenticate
+#[derive(Clone)]
+struct Tenant(String);
+
+/// 请求所属的 API key
+fn tenant<T>(request: &Request<T>) -> Result<String, Status> {
+    request
+        .extensions()
+        .get::<Tenant>()
+        .map(|tenant| tenant.0.clone())
+        .ok_or_else(|| Status::unauthenticated("Missing or invalid API key"))
+}
+
+#[tonic::async_trait]
+impl Pybox for GrpcService {
+    type ExecStreamStream = Pin<Box<dyn Stream<Item = Result<ExecEvent, Status>> + Send>>;
+
+    async fn create_env(
+        &self,
+        request: Request<CreateEnvRequest>,
+    ) -> Result<Response<CreateEnvResponse>, Status> {
+        let tenant = tenant(&request)?;
+        let from_snapshot = request.into_inner().from_snapshot;
+        let pool = Arc::clone(&self.server.pool);
+        let env_id = blocking(move || pool.create_env(&tenant, from_snapshot.as_deref())).await?;
+        Ok(Response::new(CreateEnvResponse { env_id }))
+    }
+
+    async fn delete_env(&self, request: Request<EnvRequest>) -> Result<Response<Empty>, Status> {
+        let tenant = tenant(&request)?;
+        let env_id = request.into_inner().env_id;
+        let pool = Arc::clone(&self.server.pool);
+        blocking(move || pool.delete_env(&tenant, &env_id)).await?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn exec_stream(
+        &self,
+        request: Request<ExecRequest>,
+    ) -> Result<Response<Self::ExecStreamStream>, Status> {
+        let deadline = request
+            .metadata()
+            .get("grpc-timeout")
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_grpc_timeout);
+        let tenant = tenant(&request)?;
+        let request = request.into_inner();
+        let mut timeout = self.server.config.max_timeout;
+        if let Some(secs) = request.timeout {
+            let requested = Duration::try_from_secs_f64(secs)
+                .map_err(|_| Status::invalid_argument("Invalid 'timeout'"))?;
+            timeout = timeout.min(requested);
+        }
+        if let Some(deadline) = deadline {
+            timeout = timeout.min(deadline);
+        }
+
+        // 输出和结束事件在同一个阻塞线程上依次放入同一个通道，按发送顺序到达
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let output_sender = sender.clone();
+        let seq = Arc::new(AtomicU64::new(0));
+        let output_seq = Arc::clone(&seq);
+        let output: OutputSink = Box::new(move |stream, text| {
+            let output = Output {
+                stream: stream.to_string(),
+                text: text.to_string(),
+                seq: output_seq.fetch_add(1, Ordering::SeqCst) + 1,
+            };
+            let _ = output_sender.send(Ok(event(Event::Output(output))));
+        });
+        let pool = Arc::clone(&self.server.pool);
+        tokio::task::spawn_blocking(move || {
+            match pool.exec(
+                &tenant,
+                &request.env_id,
+                &request.code,
+                timeout,
+                Some(output),
+            ) {
+                Ok((outcome, restarted)) => {
+                    for event in final_events(outcome, restarted, &seq) {
+                        let _ = sender.send(Ok(event));
+                    }
+                }
+                Err(err) => {
+                    let end = end_event(ExecStatus::Error, &seq);
+                    let _ = sender.send(Ok(end));
+                    let _ = sender.send(Err(status(err)));
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(UnboundedReceiverStream::new(
+            receiver,
+        ))))
+    }
+
+    async fn assign(&self, request: Request<AssignRequest>) -> Result<Response<Empty>, Status> {
+        let tenant = tenant(&request)?;
+        let request = request.into_inner();
+        let value: Value = serde_json::from_str(&request.value_json)
+            .map_err(|e| Status::invalid_argument(format!("Invalid value_json: {}", e)))?;
+        let pool = Arc::clone(&self.server.pool);
+        blocking(move || pool.assign(&tenant, &request.env_id, &request.name, &value)).await?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn retrieve(
+        &self,
+        request: Request<RetrieveRequest>,
+    ) -> Result<Response<RetrieveResponse>, Status> {
+        let tenant = tenant(&request)?;
+        let request = request.into_inner();
+        let pool = Arc::clone(&self.server.pool);
+        let value =
+            blocking(move || pool.retrieve(&tenant, &request.env_id, &request.name)).await?;
+        Ok(Response::new(RetrieveResponse {
+            value_json: value.to_string(),
+        }))
+    }
+
+    async fn snapshot(
+        &self,
+        request: Request<EnvRequest>,
+    ) -> Result<Response<SnapshotResponse>, Status> {
+        let tenant = tenant(&request)?;
+        let env_id = request.into_inner().env_id;
+        let pool = Arc::clone(&self.server.pool);
+        let snapshot_id = blocking(move || pool.snapshot(&tenant, &env_id)).await?;
+        Ok(Response::new(SnapshotResponse { snapshot_id }))
+    }
+
+    async fn delete_snapshot(
+        &self,
+        request: Request<SnapshotRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let tenant = tenant(&request)?;
+        let snapshot_id = request.into_inner().snapshot_id;
+        let pool = Arc::clone(&self.server.pool);
+        blocking(move || pool.delete_snapshot(&tenant, &snapshot_id)).await?;
+        Ok(Response::new(Empty {}))
+    }
+}
+
+fn event(event: Event) -> ExecEvent {
+    ExecEvent { event: Some(event) }
+}
+
+/// 执行结束后发送的事件：未实时发送的输出、artifact、结果和 End，
+/// seq 为已实时发送的输出数量
+fn final_events(outcome: ExecOutcome, restarted: bool, seq: &AtomicU64) -> Vec<ExecEvent> {
+    let mut events = Vec::new();
+    if !outcome.streamed {
+        for (stream, text) in [("stdout", &outcome.stdout), ("stderr", &outcome.stderr)] {
+            if !text.is_empty() {
+                events.push(event(Event::Output(Output {
+                    stream: stream.to_string(),
+                    text: text.clone(),
+                    seq: seq.fetch_add(1, Ordering::SeqCst) + 1,
+                })));
+            }
+        }
+    }
+    let exec_status = match outcome.status() {
+        "timed_out" => ExecStatus::TimedOut,
+        "exception" => ExecStatus::Exception,
+        _ => ExecStatus::Ok,
+    };
+    for artifact in &outcome.artifacts {
+        let binary = artifact["binary"].as_bool().unwrap_or(false);
+        let data = artifact["data"].as_str().unwrap_or_default();
+        events.push(event(Event::Artifact(Artifact {
+            name: artifact["name"].as_str().unwrap_or_default().to_string(),
+            mime: artifact["mime"].as_str().map(str::to_string),
+            // guest 中没有 base64，二进制数据以 hex 传递
+            data: if binary {
+                hex_decode(data).unwrap_or_default()
+            } else {
+                data.as_bytes().to_vec()
+            },
+            binary,
+        })));
+    }
+    let text = |value: &Value| value.as_str().unwrap_or_default().to_string();
+    events.push(event(Event::Result(ExecResult {
+        exception: outcome.exception.as_ref().map(|exception| Exception {
+            r#type: text(&exception["type"]),
+            message: text(&exception["message"]),
+            traceback: text(&exception["traceback"]),
+        }),
+        stdout: outcome.stdout,
+        stderr: outcome.stderr,
+        result_repr: outcome.result_repr,
+        truncated: outcome.truncated,
+        timed_out: outcome.timed_out,
+        reactor_restarted: restarted,
+    })));
+    events.push(end_event(exec_status, seq));
+    events
+}
+
+fn end_event(status: ExecStatus, seq: &AtomicU64) -> ExecEvent {
+    event(Event::End(End {
+        status: status as i32,
+        output_frames: seq.load(Ordering::SeqCst),
+    }))
+}
+
+fn status(err: anyhow::Error) -> Status {
+    let message = format!("{:#}", err);
+    if err.is::<NotFound>() {
+        Status::not_found(message)
+    } else if err.is::<LimitExceeded>() {
+        Status::resource_exhausted(message)
+    } else if err.is::<GuestError>() {
+        Status::invalid_argument(message)
+    } else {
+        Status::internal(message)
+    }
+}
+
+/// reactor 的调用会阻塞，放到阻塞线程池中执行
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+) -> Result<T, Status> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(status)
+}
+
+/// 解析 `grpc-timeout`：最多 8 位数字加单位 H / M / S / m / u / n
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    let (digits, unit) = value.split_at(value.len().checked_sub(1)?);
+    if digits.is_empty() || digits.len() > 8 || !digits.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = digits.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(amount * 3600),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    })
+}
+
+fn hex_decode(data: &str) -> Option<Vec<u8>> {
+    if data.len() % 2 != 0 {
+        return None;
+    }
+    (0..data.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(data.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_grpc_timeout() {
+        assert_eq!(parse_grpc_timeout("5S"), Some(Duration::from_secs(5)));
+        assert_eq!(
+            parse_grpc_timeout("1500m"),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(parse_grpc_timeout("2M"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_grpc_timeout("S"), None);
+        assert_eq!(parse_grpc_timeout("123456789S"), None);
+        assert_eq!(parse_grpc_timeout("+5S"), None);
+        assert_eq!(parse_grpc_timeout("5x"), None);
+        assert_eq!(parse_grpc_timeout(""), None);
+    }
+
+    #[test]
+    fn test_final_events_order() {
+        let outcome = ExecOutcome {
+            stdout: "out\n".to_string(),
+            stderr: String::new(),
+            exception: None,
+            result_repr: None,
+            artifacts: Vec::new(),
+            truncated: false,
+            timed_out: true,
+            streamed: false,
+        };
+        // 已经实时发送了两个输出
+        let seq = AtomicU64::new(2);
+        let events: Vec<_> = final_events(outcome, true, &seq)
+            .into_iter()
+            .map(|event| event.event.unwrap())
+            .collect();
+        assert_eq!(events.len(), 3);
+        assert!(matches!(&events[0], Event::Output(output) if output.seq == 3));
+        assert!(matches!(&events[1], Event::Result(result) if result.timed_out));
+        assert_eq!(
+            events[2],
+            Event::End(End {
+                status: ExecStatus::TimedOut as i32,
+                output_frames: 3,
+            })
+        );
+    }
+}
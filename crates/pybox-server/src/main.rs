@@ -0,0 +1,184 @@
+//! pybox-server 通过 HTTP JSON 接口提供沙箱执行
+//!
+//! ```text
+//! POST   /v1/envs                     {"from_snapshot"?}        -> {"env_id"}
+//! DELETE /v1/envs/{env_id}
+//! POST   /v1/envs/{env_id}/exec       {"code", "timeout"?}      -> {"stdout", "stderr", "exception", ...}
+//! POST   /v1/envs/{env_id}/assign     {"name", "value"}
+//! GET    /v1/envs/{env_id}/vars/{name}                          -> {"value"}
+//! POST   /v1/envs/{env_id}/snapshots                            -> {"snapshot_id"}
+//! DELETE /v1/snapshots/{snapshot_id}
+//! GET    /health
+//! GET    /v1/envs/{env_id}/ws         WebSocket，实时返回输出
+//! ```
+//!
+//! `--metrics-listen` 在单独的地址上以 Prometheus 文本格式提供 `GET /metrics`，见 metrics.rs。
+//! 启用 grpc feature 时 `--grpc-listen` 同时提供 proto/pybox.proto 定义的 gRPC 接口。
+//! 环境分布在固定数量的 reactor 上，每个 reactor 只放同一个 API key 的环境和快照；
+//! 执行超时的 reactor 会重新创建，其上该 key 的环境全部丢失，此时 exec 的结果中 `reactor_restarted` 为 true
+
+mod api;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod metrics;
+mod pool;
+mod sandbox;
+mod websocket;
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Parser;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+
+use api::{Server, ServerConfig};
+use pool::{PoolLimits, ReactorPool};
+use sandbox::SandboxModule;
+
+#[derive(Parser)]
+#[command(version, about = "Serve the pybox sandbox over an HTTP JSON API")]
+struct Args {
+    /// Reactor WASM file
+    #[arg(long, env = "PYBOX_WASM")]
+    wasm: PathBuf,
+
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    listen: SocketAddr,
+
+    /// Address to serve the gRPC API on
+    #[cfg(feature = "grpc")]
+    #[arg(long)]
+    grpc_listen: Option<SocketAddr>,
+
+    /// Address to serve Prometheus metrics on (GET /metrics, unauthenticated)
+    #[arg(long)]
+    metrics_listen: Option<SocketAddr>,
+
+    /// Number of reactors, executions in different reactors run in parallel
+    #[arg(long, default_value_t = 4)]
+    reactors: usize,
+
+    /// Accepted API keys, comma separated. Authentication is disabled when empty
+    #[arg(long = "api-key", env = "PYBOX_API_KEYS", value_delimiter = ',')]
+    api_keys: Vec<String>,
+
+    /// Default and maximum execution time of a request, in seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 30.0)]
+    timeout: f64,
+
+    /// Maximum guest memory of each reactor in bytes, with an optional K, M or G suffix
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_memory: Option<usize>,
+
+    /// Maximum number of environments per API key
+    #[arg(long, default_value_t = 64)]
+    max_envs: usize,
+
+    /// Maximum number of snapshots per API key
+    #[arg(long, default_value_t = 64)]
+    max_snapshots: usize,
+
+    /// Maximum request body size, with an optional K, M or G suffix
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "1M")]
+    max_body: usize,
+}
+
+/// 解析带 K / M / G 后缀的字节数
+fn parse_size(size: &str) -> Result<usize, String> {
+    let (digits, unit) = match size.char_indices().last() {
+        Some((index, suffix)) if suffix.is_ascii_alphabetic() => {
+            let unit = match suffix.to_ascii_uppercase() {
+                'K' => 1 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                _ => return Err(format!("unknown size suffix '{}'", suffix)),
+            };
+            (&size[..index], unit)
+        }
+        _ => (size, 1),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|size| size.checked_mul(unit))
+        .ok_or_else(|| format!("invalid size '{}'", size))
+}
+
+async fn serve(server: Arc<Server>, listen: SocketAddr) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(listen).await?;
+    eprintln!(
+        "pybox-server: listening on http://{}",
+        listener.local_addr()?
+    );
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            let service = service_fn(move |request| api::handle(Arc::clone(&server), request));
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades()
+                .await
+            {
+                eprintln!("pybox-server: connection error: {}", err);
+            }
+        });
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let max_timeout = Duration::try_from_secs_f64(args.timeout)
+        .map_err(|e| anyhow::anyhow!("invalid timeout: {}", e))?;
+    let api_keys: Vec<String> = args
+        .api_keys
+        .into_iter()
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty())
+        .collect();
+    if api_keys.is_empty() {
+        eprintln!("pybox-server: no API key configured, authentication is disabled");
+    }
+
+    let module = SandboxModule::load(&args.wasm, args.max_memory, max_timeout)?;
+    let limits = PoolLimits {
+        max_envs: args.max_envs,
+        max_snapshots: args.max_snapshots,
+    };
+    let server = Arc::new(Server {
+        pool: Arc::new(ReactorPool::new(module, args.reactors, limits)?),
+        config: ServerConfig {
+            api_keys,
+            max_timeout,
+            max_body: args.max_body,
+        },
+    });
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(async move {
+            #[cfg(feature = "grpc")]
+            if let Some(grpc_listen) = args.grpc_listen {
+                let server = Arc::clone(&server);
+                tokio::spawn(async move {
+                    if let Err(err) = grpc::serve(server, grpc_listen).await {
+                        eprintln!("pybox-server: gRPC server error: {:#}", err);
+                    }
+                });
+            }
+            if let Some(metrics_listen) = args.metrics_listen {
+                let pool = Arc::clone(&server.pool);
+                tokio::spawn(async move {
+                    if let Err(err) = metrics::serve(pool, metrics_listen).await {
+                        eprintln!("pybox-server: metrics server error: {:#}", err);
+                    }
+                });
+            }
+            serve(server, args.listen).await
+        })
+}
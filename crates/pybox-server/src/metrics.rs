@@ -0,0 +1,195 @@
+//! metrics.rs 以 Prometheus 文本格式导出 reactor 池的计数
+//!
+//! `--metrics-listen` 指定地址时在该地址上提供 `GET /metrics`，不验证 API key，
+//! 应当只监听内部网络的地址
+
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+
+use crate::pool::ReactorPool;
+
+const CONTENT_TYPE_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// 某一时刻池的计数
+#[derive(Debug, Default, PartialEq)]
+pub struct PoolMetrics {
+    pub uptime: f64,
+    pub reactors: usize,
+    pub envs: usize,
+    pub snapshots: usize,
+    pub execs: u64,
+    pub failures: u64,
+    pub timeouts: u64,
+    pub restarts: u64,
+    pub exec_time: f64,
+}
+
+impl PoolMetrics {
+    pub fn collect(pool: &ReactorPool, started: Instant) -> Self {
+        let counters = pool.counters();
+        let load = |counter: &std::sync::atomic::AtomicU64| counter.load(Ordering::Relaxed);
+        Self {
+            uptime: started.elapsed().as_secs_f64(),
+            reactors: pool.size(),
+            envs: pool.env_count(),
+            snapshots: pool.snapshot_count(),
+            execs: load(&counters.execs),
+            failures: load(&counters.failures),
+            timeouts: load(&counters.timeouts),
+            restarts: load(&counters.restarts),
+            exec_time: load(&counters.exec_time_ns) as f64 / 1e9,
+        }
+    }
+
+    /// Prometheus 文本格式，每个指标带 HELP 和 TYPE
+    pub fn render(&self) -> String {
+        let metrics: [(&str, &str, &str, String); 9] = [
+            (
+                "pybox_uptime_seconds",
+                "gauge",
+                "Seconds since the server started",
+                self.uptime.to_string(),
+            ),
+            (
+                "pybox_reactors",
+                "gauge",
+                "Number of reactors",
+                self.reactors.to_string(),
+            ),
+            (
+                "pybox_envs",
+                "gauge",
+                "Number of live environments",
+                self.envs.to_string(),
+            ),
+            (
+                "pybox_snapshots",
+                "gauge",
+                "Number of live snapshots",
+                self.snapshots.to_string(),
+            ),
+            (
+                "pybox_execs_total",
+                "counter",
+                "Executions run in a reactor",
+                self.execs.to_string(),
+            ),
+            (
+                "pybox_exec_failures_total",
+                "counter",
+                "Executions that raised, timed out or trapped",
+                self.failures.to_string(),
+            ),
+            (
+                "pybox_exec_timeouts_total",
+                "counter",
+                "Executions that timed out",
+                self.timeouts.to_string(),
+            ),
+            (
+                "pybox_reactor_restarts_total",
+                "counter",
+                "Reactors recreated after a timeout or a guest trap",
+                self.restarts.to_string(),
+            ),
+            (
+                "pybox_exec_seconds_total",
+                "counter",
+                "Seconds spent running executions",
+                self.exec_time.to_string(),
+            ),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            let _ = writeln!(text, "{} {}", name, value);
+        }
+        text
+    }
+}
+
+async fn handle(
+    pool: Arc<ReactorPool>,
+    started: Instant,
+    request: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let path = request.uri().path().trim_end_matches('/');
+    if request.method() != Method::GET || path != "/metrics" {
+        let mut response = Response::new(Full::new(Bytes::from_static(b"Not found\n")));
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+    let text = PoolMetrics::collect(&pool, started).render();
+    let mut response = Response::new(Full::new(Bytes::from(text)));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, CONTENT_TYPE_TEXT.parse().unwrap());
+    Ok(response)
+}
+
+pub async fn serve(pool: Arc<ReactorPool>, listen: SocketAddr) -> anyhow::Result<()> {
+    let started = Instant::now();
+    let listener = tokio::net::TcpListener::bind(listen).await?;
+    eprintln!(
+        "pybox-server: serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let pool = Arc::clone(&pool);
+        tokio::spawn(async move {
+            let service = service_fn(move |request| handle(Arc::clone(&pool), started, request));
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                eprintln!("pybox-server: metrics connection error: {}", err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = PoolMetrics {
+            uptime: 1.5,
+            reactors: 4,
+            envs: 2,
+            execs: 10,
+            failures: 3,
+            timeouts: 1,
+            restarts: 1,
+            exec_time: 0.25,
+            ..PoolMetrics::default()
+        };
+        let text = metrics.render();
+        assert!(text.ends_with('\n'));
+        assert!(text.contains("# TYPE pybox_execs_total counter\npybox_execs_total 10\n"));
+        assert!(text.contains("# TYPE pybox_envs gauge\npybox_envs 2\n"));
+        assert!(text.contains("\npybox_snapshots 0\n"));
+        assert!(text.contains("\npybox_uptime_seconds 1.5\n"));
+        assert!(text.contains("\npybox_exec_seconds_total 0.25\n"));
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            let (name, value) = line.split_once(' ').unwrap();
+            assert!(name.starts_with("pybox_"));
+            assert!(value.parse::<f64>().is_ok(), "{}", line);
+        }
+    }
+}
@@ -0,0 +1,425 @@
+//! pool.rs 固定数量的 reactor，环境创建后固定在所在的 reactor 上
+//!
+//! 每个 reactor 同时只执行一个请求，不同 reactor 上的请求并行执行；
+//! 环境和快照属于创建它们的 API key，其他 key 访问时视为不存在。
+//! 同一个 reactor 上只有一个 key 的环境和快照，reactor 上的环境和快照全部删除后才能分配给其他 key，
+//! 超时或 guest trap 后重新创建 reactor 只影响该 key 自己的环境和快照。
+//! 快照是环境的深拷贝，与源环境位于同一个 reactor，从快照创建的环境同样是快照的深拷贝
+
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use crate::sandbox::{ExecOutcome, GuestError, OutputSink, Sandbox, SandboxModule};
+
+/// 环境或快照不存在
+#[derive(Debug)]
+pub struct NotFound(pub String);
+
+impl std::fmt::Display for NotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} not found", self.0)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+/// 超过 API key 的环境或快照数量上限，或者没有可以分配给该 key 的 reactor
+#[derive(Debug)]
+pub struct LimitExceeded(pub String);
+
+impl std::fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// 每个 API key 的数量上限
+#[derive(Clone, Copy)]
+pub struct PoolLimits {
+    pub max_envs: usize,
+    pub max_snapshots: usize,
+}
+
+/// 环境或快照所在的 reactor 和所属的 API key
+struct Entry {
+    reactor: usize,
+    tenant: String,
+}
+
+#[derive(Default)]
+struct Ids {
+    envs: HashMap<String, Entry>,
+    snapshots: HashMap<String, Entry>,
+}
+
+impl Ids {
+    fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.envs.values().chain(self.snapshots.values())
+    }
+
+    /// 分配给 tenant 的 reactor 中环境和快照最少的一个，没有时分配一个空闲的 reactor
+    fn place(&self, tenant: &str, size: usize) -> Option<usize> {
+        let mut counts = vec![0usize; size];
+        let mut owners = vec![None; size];
+        for entry in self.entries() {
+            counts[entry.reactor] += 1;
+            owners[entry.reactor] = Some(entry.tenant.as_str());
+        }
+        (0..size)
+            .filter(|&index| owners[index].is_none_or(|owner| owner == tenant))
+            .min_by_key(|&index| counts[index])
+    }
+}
+
+/// 启动以来的累计计数，见 metrics.rs
+#[derive(Default)]
+pub struct PoolCounters {
+    pub execs: AtomicU64,
+    /// 代码抛出异常或超时
+    pub failures: AtomicU64,
+    pub timeouts: AtomicU64,
+    /// 超时或 guest trap 后重新创建 reactor 的次数
+    pub restarts: AtomicU64,
+    /// exec 在 guest 中执行的总时间（纳秒）
+    pub exec_time_ns: AtomicU64,
+}
+
+pub struct ReactorPool {
+    module: Arc<SandboxModule>,
+    reactors: Vec<Mutex<Sandbox>>,
+    ids: Mutex<Ids>,
+    limits: PoolLimits,
+    counters: PoolCounters,
+    /// 生成 ID 的计数器，与随机的哈希种子一起生成不可预测的 ID
+    counter: AtomicU64,
+    hasher: std::hash::RandomState,
+}
+
+impl ReactorPool {
+    pub fn new(
+        module: Arc<SandboxModule>,
+        size: usize,
+        limits: PoolLimits,
+    ) -> anyhow::Result<Self> {
+        let reactors = (0..size.max(1))
+            .map(|_| Sandbox::new(&module).map(Mutex::new))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            module,
+            reactors,
+            ids: Mutex::default(),
+            limits,
+            counters: PoolCounters::default(),
+            counter: AtomicU64::new(0),
+            hasher: std::hash::RandomState::new(),
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.reactors.len()
+    }
+
+    pub fn env_count(&self) -> usize {
+        self.ids.lock().unwrap().envs.len()
+    }
+
+    pub fn snapshot_count(&self) -> usize {
+        self.ids.lock().unwrap().snapshots.len()
+    }
+
+    pub fn counters(&self) -> &PoolCounters {
+        &self.counters
+    }
+
+    /// 环境存在并且属于 tenant
+    pub fn has_env(&self, tenant: &str, env_id: &str) -> bool {
+        self.env_reactor(tenant, env_id).is_ok()
+    }
+
+    /// 为 tenant 创建环境，指定快照时从快照深拷贝
+    pub fn create_env(&self, tenant: &str, from_snapshot: Option<&str>) -> anyhow::Result<String> {
+        let env_id = self.new_id("env");
+        let index = {
+            let mut ids = self.ids.lock().unwrap();
+            let count = ids
+                .envs
+                .values()
+                .filter(|entry| entry.tenant == tenant)
+                .count();
+            if count >= self.limits.max_envs {
+                return Err(LimitExceeded(format!(
+                    "Too many environments, at most {} per API key",
+                    self.limits.max_envs
+                ))
+                .into());
+            }
+            let index = match from_snapshot {
+                Some(snapshot) => lookup(&ids.snapshots, tenant, snapshot, "Snapshot")?,
+                None => ids.place(tenant, self.reactors.len()).ok_or_else(|| {
+                    LimitExceeded("No reactor available, try again later".to_string())
+                })?,
+            };
+            // 先登记环境，等待 reactor 的锁期间 reactor 不会分配给其他 key
+            ids.envs.insert(
+                env_id.clone(),
+                Entry {
+                    reactor: index,
+                    tenant: tenant.to_string(),
+                },
+            );
+            index
+        };
+        let mut reactor = self.reactors[index].lock().unwrap();
+        // 等待锁期间 reactor 可能已经重新创建，登记的环境和快照已经删除
+        let result = self
+            .env_reactor(tenant, &env_id)
+            .and_then(|_| match from_snapshot {
+                Some(snapshot) => {
+                    self.snapshot_reactor(tenant, snapshot)?;
+                    reactor.copy_local(&env_id, snapshot)
+                }
+                None => reactor.init_local(&env_id),
+            });
+        if let Err(err) = self.recover(index, &mut reactor, result) {
+            self.ids.lock().unwrap().envs.remove(&env_id);
+            return Err(err);
+        }
+        Ok(env_id)
+    }
+
+    pub fn delete_env(&self, tenant: &str, env_id: &str) -> anyhow::Result<()> {
+        self.with_env(tenant, env_id, |reactor| reactor.del_local(env_id))?;
+        self.ids.lock().unwrap().envs.remove(env_id);
+        Ok(())
+    }
+
+    /// 执行代码，超时后重新创建环境所在的 reactor，返回结果和 reactor 是否重新创建
+    pub fn exec(
+        &self,
+        tenant: &str,
+        env_id: &str,
+        code: &str,
+        timeout: Duration,
+        output: Option<OutputSink>,
+    ) -> anyhow::Result<(ExecOutcome, bool)> {
+        let index = self.env_reactor(tenant, env_id)?;
+        let mut reactor = self.reactors[index].lock().unwrap();
+        self.env_reactor(tenant, env_id)?;
+        let started = Instant::now();
+        let result = reactor.exec(env_id, code, timeout, output);
+        let counters = &self.counters;
+        counters.execs.fetch_add(1, Ordering::Relaxed);
+        counters.exec_time_ns.fetch_add(
+            u64::try_from(started.elapsed().as_nanos()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+        match &result {
+            Ok(outcome) if outcome.timed_out || outcome.exception.is_some() => {
+                counters.failures.fetch_add(1, Ordering::Relaxed);
+                if outcome.timed_out {
+                    counters.timeouts.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Ok(_) => {}
+            Err(_) => {
+                counters.failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        match self.recover(index, &mut reactor, result)? {
+            outcome if outcome.timed_out => {
+                self.restart(index, &mut reactor)?;
+                Ok((outcome, true))
+            }
+            outcome => Ok((outcome, false)),
+        }
+    }
+
+    pub fn assign(
+        &self,
+        tenant: &str,
+        env_id: &str,
+        name: &str,
+        value: &Value,
+    ) -> anyhow::Result<()> {
+        self.with_env(tenant, env_id, |reactor| {
+            reactor.assign(env_id, name, value)
+        })
+    }
+
+    pub fn retrieve(&self, tenant: &str, env_id: &str, name: &str) -> anyhow::Result<Value> {
+        self.with_env(tenant, env_id, |reactor| reactor.retrieve(env_id, name))
+    }
+
+    /// 深拷贝环境保存为快照，返回快照 ID
+    pub fn snapshot(&self, tenant: &str, env_id: &str) -> anyhow::Result<String> {
+        let snapshot_id = self.new_id("snap");
+        let index = {
+            let mut ids = self.ids.lock().unwrap();
+            let count = ids
+                .snapshots
+                .values()
+                .filter(|entry| entry.tenant == tenant)
+                .count();
+            if count >= self.limits.max_snapshots {
+                return Err(LimitExceeded(format!(
+                    "Too many snapshots, at most {} per API key",
+                    self.limits.max_snapshots
+                ))
+                .into());
+            }
+            let index = lookup(&ids.envs, tenant, env_id, "Environment")?;
+            ids.snapshots.insert(
+                snapshot_id.clone(),
+                Entry {
+                    reactor: index,
+                    tenant: tenant.to_string(),
+                },
+            );
+            index
+        };
+        let mut reactor = self.reactors[index].lock().unwrap();
+        let result = self
+            .snapshot_reactor(tenant, &snapshot_id)
+            .and_then(|_| self.env_reactor(tenant, env_id))
+            .and_then(|_| reactor.copy_local(&snapshot_id, env_id));
+        if let Err(err) = self.recover(index, &mut reactor, result) {
+            self.ids.lock().unwrap().snapshots.remove(&snapshot_id);
+            return Err(err);
+        }
+        Ok(snapshot_id)
+    }
+
+    pub fn delete_snapshot(&self, tenant: &str, snapshot_id: &str) -> anyhow::Result<()> {
+        let index = self.snapshot_reactor(tenant, snapshot_id)?;
+        let mut reactor = self.reactors[index].lock().unwrap();
+        self.snapshot_reactor(tenant, snapshot_id)?;
+        reactor.del_local(snapshot_id)?;
+        self.ids.lock().unwrap().snapshots.remove(snapshot_id);
+        Ok(())
+    }
+
+    /// 锁定环境所在的 reactor 执行 f
+    fn with_env<T>(
+        &self,
+        tenant: &str,
+        env_id: &str,
+        f: impl FnOnce(&mut Sandbox) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let index = self.env_reactor(tenant, env_id)?;
+        let mut reactor = self.reactors[index].lock().unwrap();
+        // 等待锁期间 reactor 可能已经重新创建，环境已经不存在
+        self.env_reactor(tenant, env_id)?;
+        f(&mut reactor)
+    }
+
+    fn env_reactor(&self, tenant: &str, env_id: &str) -> anyhow::Result<usize> {
+        lookup(
+            &self.ids.lock().unwrap().envs,
+            tenant,
+            env_id,
+            "Environment",
+        )
+    }
+
+    fn snapshot_reactor(&self, tenant: &str, snapshot_id: &str) -> anyhow::Result<usize> {
+        lookup(
+            &self.ids.lock().unwrap().snapshots,
+            tenant,
+            snapshot_id,
+            "Snapshot",
+        )
+    }
+
+    /// guest trap 等 host 错误后 reactor 的状态不可信，重新创建 reactor；guest 拒绝的请求原样返回
+    fn recover<T>(
+        &self,
+        index: usize,
+        reactor: &mut Sandbox,
+        result: anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        match result {
+            Err(err) if !err.is::<GuestError>() && !err.is::<NotFound>() => {
+                self.restart(index, reactor)?;
+                Err(err)
+            }
+            result => result,
+        }
+    }
+
+    /// 重新创建 reactor，调用方持有该 reactor 的锁；reactor 上只有同一个 key 的环境和快照
+    fn restart(&self, index: usize, reactor: &mut Sandbox) -> anyhow::Result<()> {
+        let mut ids = self.ids.lock().unwrap();
+        ids.envs.retain(|_, entry| entry.reactor != index);
+        ids.snapshots.retain(|_, entry| entry.reactor != index);
+        drop(ids);
+        self.counters.restarts.fetch_add(1, Ordering::Relaxed);
+        *reactor = Sandbox::new(&self.module)?;
+        Ok(())
+    }
+
+    fn new_id(&self, prefix: &str) -> String {
+        let count = self.counter.fetch_add(1, Ordering::Relaxed);
+        format!("{}_{:016x}", prefix, self.hasher.hash_one(count))
+    }
+}
+
+/// 属于 tenant 的环境或快照所在的 reactor，其他 key 的环境和快照视为不存在
+fn lookup(
+    entries: &HashMap<String, Entry>,
+    tenant: &str,
+    id: &str,
+    kind: &str,
+) -> anyhow::Result<usize> {
+    entries
+        .get(id)
+        .filter(|entry| entry.tenant == tenant)
+        .map(|entry| entry.reactor)
+        .ok_or_else(|| NotFound(format!("{} '{}'", kind, id)).into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(reactor: usize, tenant: &str) -> Entry {
+        Entry {
+            reactor,
+            tenant: tenant.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_place() {
+        let mut ids = Ids::default();
+        assert_eq!(ids.place("a", 2), Some(0));
+        ids.envs.insert("env_1".to_string(), entry(0, "a"));
+        // 空闲的 reactor 负载更低
+        assert_eq!(ids.place("a", 2), Some(1));
+        assert_eq!(ids.place("b", 2), Some(1));
+        ids.snapshots.insert("snap_1".to_string(), entry(1, "b"));
+        assert_eq!(ids.place("a", 2), Some(0));
+        assert_eq!(ids.place("b", 2), Some(1));
+        // reactor 不会分配给其他 key
+        assert_eq!(ids.place("c", 2), None);
+        ids.snapshots.remove("snap_1");
+        assert_eq!(ids.place("c", 2), Some(1));
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut envs = HashMap::new();
+        envs.insert("env_1".to_string(), entry(3, "a"));
+        assert_eq!(lookup(&envs, "a", "env_1", "Environment").unwrap(), 3);
+        let err = lookup(&envs, "b", "env_1", "Environment").unwrap_err();
+        assert!(err.is::<NotFound>());
+        assert_eq!(err.to_string(), "Environment 'env_1' not found");
+        assert!(lookup(&envs, "a", "env_2", "Environment").is_err());
+    }
+}
@@ -0,0 +1,167 @@
+//! sandbox.rs 一个 reactor 实例，在其中管理局部环境和执行代码
+//!
+//! reactor 由 pybox-host 实现：server 不注册 handler，guest 发起的 ioctl 请求除名称解析和实时输出外
+//! 全部返回失败；超时由 epoch 中断实现，内存上限由 store 的 limiter 实现
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use pybox_host::{COPY_ENV_CODE, Reactor, ReactorOptions};
+use serde_json::{Value, json};
+
+pub use pybox_host::GuestError;
+
+/// 接收实时输出的回调：(stdout 或 stderr, 文本)
+pub type OutputSink = Box<dyn FnMut(&str, &str) + Send>;
+
+/// 所有 reactor 共享的 wasm 文件和限制，模块由 pybox-host 按路径缓存，只编译一次
+pub struct SandboxModule {
+    wasm: PathBuf,
+    options: ReactorOptions,
+}
+
+impl SandboxModule {
+    /// 记录 wasm 和限制，wasm 在创建第一个 reactor 时编译；timeout 限制复制环境的时间
+    pub fn load(
+        wasm: &Path,
+        max_memory: Option<usize>,
+        timeout: Duration,
+    ) -> anyhow::Result<Arc<Self>> {
+        Ok(Arc::new(Self {
+            wasm: wasm.to_path_buf(),
+            options: ReactorOptions {
+                max_memory,
+                timeout: Some(timeout),
+                ..ReactorOptions::default()
+            },
+        }))
+    }
+}
+
+/// exec 的结果，字段与 `pybox_exec_report` 的 json 结果对应
+pub struct ExecOutcome {
+    pub stdout: String,
+    pub stderr: String,
+    /// `{"type", "message", "traceback"}`
+    pub exception: Option<Value>,
+    pub result_repr: Option<String>,
+    /// `pybox.artifact` 附加的数据 `{"name", "mime", "binary", "data"}`
+    pub artifacts: Vec<Value>,
+    /// 输出或 artifact 超过限制而被截断
+    pub truncated: bool,
+    pub timed_out: bool,
+    /// 输出已经通过 OutputSink 实时发送，旧版 guest 不支持实时输出
+    pub streamed: bool,
+}
+
+impl From<pybox_host::ExecOutcome> for ExecOutcome {
+    fn from(outcome: pybox_host::ExecOutcome) -> Self {
+        Self {
+            stdout: outcome.stdout,
+            stderr: outcome.stderr,
+            exception: outcome.exception,
+            result_repr: outcome.result_repr,
+            artifacts: outcome.artifacts,
+            truncated: outcome.truncated,
+            // server 不设置中断，中断与超时一样需要重新创建 reactor
+            timed_out: outcome.timed_out || outcome.interrupted,
+            streamed: outcome.streamed,
+        }
+    }
+}
+
+impl ExecOutcome {
+    /// 流式接口结束帧中的执行状态："ok"、"exception" 或 "timed_out"，host 错误为 "error"
+    pub fn status(&self) -> &'static str {
+        if self.timed_out {
+            "timed_out"
+        } else if self.exception.is_some() {
+            "exception"
+        } else {
+            "ok"
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "stdout": self.stdout,
+            "stderr": self.stderr,
+            "exception": self.exception,
+            "result_repr": self.result_repr,
+            "artifacts": self.artifacts,
+            "truncated": self.truncated,
+            "timed_out": self.timed_out,
+        })
+    }
+}
+
+pub struct Sandbox {
+    reactor: Reactor,
+}
+
+impl Sandbox {
+    pub fn new(module: &SandboxModule) -> anyhow::Result<Self> {
+        Ok(Self {
+            reactor: Reactor::new(&module.wasm, &module.options)?,
+        })
+    }
+
+    /// 创建空的局部环境
+    pub fn init_local(&mut self, id: &str) -> anyhow::Result<()> {
+        self.reactor.init_local(id)?;
+        Ok(())
+    }
+
+    /// 创建局部环境并深拷贝 from 环境中的变量；变量无法复制时不创建环境，返回 GuestError，
+    /// 复制超时后与 exec 超时一样不应继续使用该 reactor
+    pub fn copy_local(&mut self, id: &str, from: &str) -> anyhow::Result<()> {
+        self.reactor.init_local_from(id, from)?;
+        let outcome = self.reactor.exec(id, COPY_ENV_CODE, None)?;
+        if outcome.timed_out || outcome.interrupted {
+            anyhow::bail!("Timed out copying the environment '{}'", from);
+        }
+        if let Some(exception) = outcome.exception {
+            self.reactor.del_local(id)?;
+            return Err(GuestError(format!(
+                "Failed to copy the environment '{}': {}: {}",
+                from,
+                exception["type"].as_str().unwrap_or_default(),
+                exception["message"].as_str().unwrap_or_default()
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    pub fn del_local(&mut self, id: &str) -> anyhow::Result<()> {
+        self.reactor.del_local(id)?;
+        Ok(())
+    }
+
+    /// 执行代码，guest 代码抛出的异常记录在结果中，guest 支持时输出实时发送到 output；
+    /// 超时的执行可能停在任意位置，之后不应继续使用该 reactor
+    pub fn exec(
+        &mut self,
+        id: &str,
+        code: &str,
+        timeout: Duration,
+        output: Option<OutputSink>,
+    ) -> anyhow::Result<ExecOutcome> {
+        self.reactor
+            .set_output(output.map(|output| Box::new(output) as Box<dyn pybox_host::OutputSink>));
+        let result = self.reactor.exec(id, code, Some(timeout));
+        self.reactor.set_output(None);
+        Ok(result?.into())
+    }
+
+    /// 以 json 值创建变量
+    pub fn assign(&mut self, id: &str, name: &str, value: &Value) -> anyhow::Result<()> {
+        self.reactor.assign(id, name, value)
+    }
+
+    /// 读取变量的 json 值
+    pub fn retrieve(&mut self, id: &str, name: &str) -> anyhow::Result<Value> {
+        self.reactor.retrieve(id, name)
+    }
+}
@@ -0,0 +1,532 @@
+//! websocket.rs 通过 WebSocket 执行代码并实时返回输出
+//!
+//! `GET /v1/envs/{env_id}/ws` 升级为 WebSocket 连接，客户端发送文本消息
+//! `{"type": "exec", "id"?, "code", "timeout"?}`，服务端按顺序返回：
+//!
+//! ```text
+//! {"type": "output", "id", "seq", "stream": "stdout" | "stderr", "text"}  执行期间的每次输出
+//! {"type": "artifact", "id", "artifact": {"name", "mime", "binary", "data"}}
+//! {"type": "result", "id", "stdout", "stderr", "exception", ...}     与 HTTP exec 的结果相同
+//! {"type": "error", "id", "error"}                                   请求无效或 host 错误
+//! {"type": "end", "id", "status", "output_frames"}                   exec 的最后一帧
+//! ```
+//!
+//! guest 的每次输出在写入时同步交给连接，exec 返回前的输出全部排在 artifact 和 result 之前；
+//! output 的 seq 从 1 开始连续编号。执行过的请求最后总是发送 end 帧，status 为
+//! "ok"、"exception"、"timed_out" 或 host 出错时的 "error"，output_frames 为已发送的 output 帧数，
+//! 客户端据此确认没有遗漏输出；无效的请求只返回 error。
+//! 同一连接上的 exec 依次执行；浏览器无法设置请求头，API key 也可以通过 `?api_key=` 传递。
+//! 只实现服务端需要的部分 RFC 6455：不支持扩展和二进制消息
+
+use std::sync::Arc;
+
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{CONNECTION, HeaderValue, SEC_WEBSOCKET_ACCEPT, UPGRADE};
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde_json::{Value, json};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::api::{ApiError, Server, request_timeout};
+use crate::sandbox::{ExecOutcome, OutputSink};
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// 握手时与客户端 key 拼接的固定 GUID
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// 校验握手请求，返回 101 响应，连接升级后在后台运行会话
+pub fn upgrade(
+    server: Arc<Server>,
+    request: Request<Incoming>,
+    tenant: String,
+    env_id: String,
+) -> Result<Response<Full<Bytes>>, ApiError> {
+    let headers = request.headers();
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+    };
+    if !header("upgrade").eq_ignore_ascii_case("websocket")
+        || header("sec-websocket-version") != "13"
+    {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "Expected a WebSocket upgrade request",
+        ));
+    }
+    let key = header("sec-websocket-key").trim().to_string();
+    if key.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "Missing Sec-WebSocket-Key",
+        ));
+    }
+    if !server.pool.has_env(&tenant, &env_id) {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("Environment '{}' not found", env_id),
+        ));
+    }
+
+    let on_upgrade = hyper::upgrade::on(request);
+    tokio::spawn(async move {
+        let result = match on_upgrade.await {
+            Ok(upgraded) => session(server, TokioIo::new(upgraded), tenant, env_id).await,
+            Err(err) => Err(std::io::Error::other(err)),
+        };
+        if let Err(err) = result {
+            eprintln!("pybox-server: websocket error: {}", err);
+        }
+    });
+
+    let mut response = Response::new(Full::new(Bytes::new()));
+    *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    let headers = response.headers_mut();
+    headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+    headers.insert(
+        SEC_WEBSOCKET_ACCEPT,
+        HeaderValue::from_str(&accept_key(&key))
+            .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?,
+    );
+    Ok(response)
+}
+
+/// exec 执行期间从阻塞线程发往连接的事件
+enum Event {
+    Output(String, String),
+    Done(anyhow::Result<(ExecOutcome, bool)>),
+}
+
+async fn session<S: AsyncRead + AsyncWrite + Unpin>(
+    server: Arc<Server>,
+    mut io: S,
+    tenant: String,
+    env_id: String,
+) -> std::io::Result<()> {
+    while let Some(message) = read_message(&mut io, server.config.max_body).await? {
+        let request: Value = serde_json::from_str(&message).unwrap_or_default();
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let code = request["code"].as_str().map(str::to_string);
+        let timeout = request_timeout(&request["timeout"], server.config.max_timeout);
+        let (code, timeout) = match (request["type"].as_str(), code, timeout) {
+            (Some("exec"), Some(code), Ok(timeout)) => (code, timeout),
+            (Some("exec"), _, Err(err)) => {
+                send_error(&mut io, &id, &err.message).await?;
+                continue;
+            }
+            _ => {
+                let error = "Expected {\"type\": \"exec\", \"code\": ...}";
+                send_error(&mut io, &id, error).await?;
+                continue;
+            }
+        };
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let output_sender = sender.clone();
+        let output: OutputSink = Box::new(move |stream, text| {
+            let _ = output_sender.send(Event::Output(stream.to_string(), text.to_string()));
+        });
+        let pool = Arc::clone(&server.pool);
+        let (tenant, env_id) = (tenant.clone(), env_id.clone());
+        tokio::task::spawn_blocking(move || {
+            let result = pool.exec(&tenant, &env_id, &code, timeout, Some(output));
+            let _ = sender.send(Event::Done(result));
+        });
+
+        // 输出和结束事件经过同一个通道，按发送顺序到达
+        let mut seq = 0;
+        while let Some(event) = receiver.recv().await {
+            match event {
+                Event::Output(stream, text) => {
+                    send_output(&mut io, &id, &mut seq, &stream, &text).await?;
+                }
+                Event::Done(Ok((outcome, restarted))) => {
+                    send_result(&mut io, &id, &mut seq, &outcome, restarted).await?;
+                    send_end(&mut io, &id, outcome.status(), seq).await?;
+                    break;
+                }
+                Event::Done(Err(err)) => {
+                    send_error(&mut io, &id, &format!("{:#}", err)).await?;
+                    send_end(&mut io, &id, "error", seq).await?;
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 发送一帧输出，seq 为本次 exec 已发送的 output 帧数
+async fn send_output<S: AsyncWrite + Unpin>(
+    io: &mut S,
+    id: &Value,
+    seq: &mut u64,
+    stream: &str,
+    text: &str,
+) -> std::io::Result<()> {
+    *seq += 1;
+    let frame = json!({ "type": "output", "id": id, "seq": *seq, "stream": stream, "text": text });
+    send(io, &frame).await
+}
+
+async fn send_result<S: AsyncWrite + Unpin>(
+    io: &mut S,
+    id: &Value,
+    seq: &mut u64,
+    outcome: &ExecOutcome,
+    restarted: bool,
+) -> std::io::Result<()> {
+    // 旧版 guest 不支持实时输出，结束后一次发送
+    if !outcome.streamed {
+        for (stream, text) in [("stdout", &outcome.stdout), ("stderr", &outcome.stderr)] {
+            if !text.is_empty() {
+                send_output(io, id, seq, stream, text).await?;
+            }
+        }
+    }
+    for artifact in &outcome.artifacts {
+        send(
+            io,
+            &json!({ "type": "artifact", "id": id, "artifact": artifact }),
+        )
+        .await?;
+    }
+    let mut result = outcome.to_json();
+    result["type"] = "result".into();
+    result["id"] = id.clone();
+    result["reactor_restarted"] = restarted.into();
+    send(io, &result).await
+}
+
+async fn send_error<S: AsyncWrite + Unpin>(
+    io: &mut S,
+    id: &Value,
+    error: &str,
+) -> std::io::Result<()> {
+    send(io, &json!({ "type": "error", "id": id, "error": error })).await
+}
+
+async fn send_end<S: AsyncWrite + Unpin>(
+    io: &mut S,
+    id: &Value,
+    status: &str,
+    output_frames: u64,
+) -> std::io::Result<()> {
+    let frame =
+        json!({ "type": "end", "id": id, "status": status, "output_frames": output_frames });
+    send(io, &frame).await
+}
+
+async fn send<S: AsyncWrite + Unpin>(io: &mut S, message: &Value) -> std::io::Result<()> {
+    io.write_all(&encode_frame(OPCODE_TEXT, message.to_string().as_bytes()))
+        .await?;
+    io.flush().await
+}
+
+/// 读取下一条文本消息，处理 ping 和分片，连接关闭时返回 None
+async fn read_message<S: AsyncRead + AsyncWrite + Unpin>(
+    io: &mut S,
+    max_len: usize,
+) -> std::io::Result<Option<String>> {
+    let mut message = Vec::new();
+    let mut started = false;
+    loop {
+        let Some((fin, opcode, payload)) = read_frame(io, max_len).await? else {
+            return Ok(None);
+        };
+        match opcode {
+            OPCODE_PING => {
+                io.write_all(&encode_frame(OPCODE_PONG, &payload)).await?;
+                io.flush().await?;
+                continue;
+            }
+            OPCODE_PONG => continue,
+            OPCODE_CLOSE => {
+                // 回应关闭帧，带回客户端的状态码
+                let code = payload.get(..2).unwrap_or_default();
+                io.write_all(&encode_frame(OPCODE_CLOSE, code)).await?;
+                io.flush().await?;
+                return Ok(None);
+            }
+            OPCODE_BINARY => return Err(invalid_data("binary messages are not supported")),
+            OPCODE_TEXT | OPCODE_CONTINUATION => {}
+            _ => return Err(invalid_data("unknown opcode")),
+        }
+        // 分片消息以文本帧开始，之后只能是后续帧
+        if (opcode == OPCODE_TEXT) == started {
+            return Err(invalid_data("unexpected continuation frame"));
+        }
+        started = true;
+        if message.len() + payload.len() > max_len {
+            return Err(invalid_data("message too large"));
+        }
+        message.extend_from_slice(&payload);
+        if fin {
+            return String::from_utf8(message)
+                .map(Some)
+                .map_err(|_| invalid_data("text message is not valid utf-8"));
+        }
+    }
+}
+
+/// 读取一帧：(FIN, opcode, 去掉掩码的数据)，连接在帧之间关闭时返回 None
+async fn read_frame<S: AsyncRead + Unpin>(
+    io: &mut S,
+    max_len: usize,
+) -> std::io::Result<Option<(bool, u8, Vec<u8>)>> {
+    let mut header = [0u8; 2];
+    match io.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let fin = header[0] & 0x80 != 0;
+    let opcode = header[0] & 0x0F;
+    if header[0] & 0x70 != 0 {
+        return Err(invalid_data("reserved bits set without an extension"));
+    }
+    // 客户端发送的帧必须带掩码
+    if header[1] & 0x80 == 0 {
+        return Err(invalid_data("client frames must be masked"));
+    }
+    let len = match header[1] & 0x7F {
+        126 => io.read_u16().await? as u64,
+        127 => io.read_u64().await?,
+        len => len as u64,
+    };
+    if len > max_len as u64 {
+        return Err(invalid_data("frame too large"));
+    }
+    let mut mask = [0u8; 4];
+    io.read_exact(&mut mask).await?;
+    let mut payload = vec![0u8; len as usize];
+    io.read_exact(&mut payload).await?;
+    for (index, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[index % 4];
+    }
+    Ok(Some((fin, opcode, payload)))
+}
+
+/// 服务端发送的帧不带掩码，不分片
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+/// Sec-WebSocket-Accept：base64(sha1(key + GUID))
+fn accept_key(key: &str) -> String {
+    base64_encode(&sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for index in 0..4 {
+            if index <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * index) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// 握手只需要对短字符串计算一次 SHA-1，不为此引入依赖
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (index, word) in block.chunks(4).enumerate() {
+            w[index] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for index in 16..80 {
+            w[index] = (w[index - 3] ^ w[index - 8] ^ w[index - 14] ^ w[index - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (index, word) in w.iter().enumerate() {
+            let (f, k) = match index {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (value, add) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(add);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (index, value) in state.iter().enumerate() {
+        digest[index * 4..index * 4 + 4].copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // RFC 6455 1.3 中的示例
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert_eq!(base64_encode(b"ab"), "YWI=");
+        assert_eq!(base64_encode(b"a"), "YQ==");
+    }
+
+    #[test]
+    fn test_read_frame() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        // 客户端发送的 "Hello"，RFC 6455 5.7 中的示例
+        let frame: &[u8] = &[
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let mut io = frame;
+        let result = runtime.block_on(read_frame(&mut io, 1024)).unwrap();
+        assert_eq!(result, Some((true, OPCODE_TEXT, b"Hello".to_vec())));
+        assert!(
+            runtime
+                .block_on(read_frame(&mut io, 1024))
+                .unwrap()
+                .is_none()
+        );
+
+        // 未带掩码的帧和超过上限的帧
+        let mut io: &[u8] = &[0x81, 0x05, b'H', b'e', b'l', b'l', b'o'];
+        assert!(runtime.block_on(read_frame(&mut io, 1024)).is_err());
+        let mut io = frame;
+        assert!(runtime.block_on(read_frame(&mut io, 4)).is_err());
+
+        assert_eq!(encode_frame(OPCODE_TEXT, b"Hello"), b"\x81\x05Hello");
+        assert_eq!(
+            &encode_frame(OPCODE_TEXT, &[0; 200])[..4],
+            &[0x81, 126, 0, 200]
+        );
+    }
+
+    /// 解析服务端发送的文本帧
+    fn decode_frames(mut data: &[u8]) -> Vec<Value> {
+        let mut frames = Vec::new();
+        while !data.is_empty() {
+            let (len, start) = match data[1] {
+                126 => (u16::from_be_bytes([data[2], data[3]]) as usize, 4),
+                127 => (
+                    u64::from_be_bytes(data[2..10].try_into().unwrap()) as usize,
+                    10,
+                ),
+                len => (len as usize, 2),
+            };
+            frames.push(serde_json::from_slice(&data[start..start + len]).unwrap());
+            data = &data[start + len..];
+        }
+        frames
+    }
+
+    #[test]
+    fn test_send_result_order() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        // 旧版 guest 没有实时输出，输出在结束后补发，编号接在已发送的帧之后
+        let outcome = ExecOutcome {
+            stdout: "out\n".to_string(),
+            stderr: "err\n".to_string(),
+            exception: Some(json!({ "type": "ValueError", "message": "x", "traceback": "" })),
+            result_repr: None,
+            artifacts: vec![json!({ "name": "a", "mime": null, "binary": false, "data": "" })],
+            truncated: false,
+            timed_out: false,
+            streamed: false,
+        };
+        let id = json!(7);
+        let mut io = Vec::new();
+        let mut seq = 0;
+        runtime.block_on(async {
+            send_output(&mut io, &id, &mut seq, "stdout", "live\n")
+                .await
+                .unwrap();
+            send_result(&mut io, &id, &mut seq, &outcome, false)
+                .await
+                .unwrap();
+            send_end(&mut io, &id, outcome.status(), seq).await.unwrap();
+        });
+
+        let frames = decode_frames(&io);
+        let kinds: Vec<_> = frames
+            .iter()
+            .map(|frame| frame["type"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            kinds,
+            ["output", "output", "output", "artifact", "result", "end"]
+        );
+        let seqs: Vec<_> = frames[..3]
+            .iter()
+            .map(|frame| frame["seq"].as_u64().unwrap())
+            .collect();
+        assert_eq!(seqs, [1, 2, 3]);
+        assert_eq!(frames[2]["stream"], "stderr");
+        assert_eq!(
+            frames[5],
+            json!({ "type": "end", "id": 7, "status": "exception", "output_frames": 3 })
+        );
+    }
+}
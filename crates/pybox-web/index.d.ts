@@ -0,0 +1,70 @@
+export interface LoadOptions {
+  /** Receives what the guest prints outside of an execution, console.log by default */
+  stdout?: (text: string) => void
+  /** Receives what the guest prints to stderr outside of an execution, console.error by default */
+  stderr?: (text: string) => void
+}
+
+export interface ExecOptions {
+  /** Receives the output while the code runs */
+  onOutput?: (stream: 'stdout' | 'stderr', text: string) => void
+}
+
+export interface ExecException {
+  type: string
+  message: string
+  traceback: string
+}
+
+export interface Artifact {
+  name: string
+  mime: string | null
+  binary: boolean
+  /** Text, or hex encoded data when binary is true */
+  data: string
+}
+
+export interface ExecResult {
+  stdout: string
+  stderr: string
+  /** Uncaught exception of the code */
+  exception: ExecException | null
+  /** repr of a trailing expression */
+  resultRepr: string | null
+  artifacts: Artifact[]
+  /** Output or artifacts were cut at the guest limits */
+  truncated: boolean
+  /** The output was already delivered through onOutput */
+  streamed: boolean
+}
+
+export type Handler = (request: Uint8Array) => Uint8Array | string | null
+
+export type JsonValue = null | boolean | number | string | JsonValue[] | { [key: string]: JsonValue }
+
+/** The guest called proc_exit, the reactor can not be used afterwards */
+export class WasiExit extends Error {
+  readonly code: number
+}
+
+export class Reactor {
+  static load (
+    source: string | URL | Response | BufferSource | WebAssembly.Module,
+    options?: LoadOptions
+  ): Promise<Reactor>
+
+  registerHandler (handle: number, func: Handler, name?: string): void
+  unregisterHandler (handle: number): boolean
+
+  initLocal (envId: string): boolean
+  initLocalFrom (envId: string, fromEnvId: string): boolean
+  delLocal (envId: string): boolean
+
+  exec (code: string, envId: string, options?: ExecOptions): ExecResult
+  assign (name: string, value: JsonValue, envId: string): void
+  retrieve (name: string, envId: string): JsonValue
+  protect (name: string, envId: string): void
+
+  snapshot (): Uint8Array
+  restore (snapshot: Uint8Array): void
+}
@@ -0,0 +1,398 @@
+// The pybox reactor in browsers and Node.js, driven by the WebAssembly API without wasmtime
+
+import { Wasi, WasiExit } from './wasi.js'
+
+// Handles at or above this value are reserved by pybox
+const RESERVED_HANDLE_BASE = 0x40000000
+// System handle: resolve a handler by name, the response is the decimal handle
+const RESOLVE_HANDLE = 0x7fff0000
+// System handle: output while the code runs, `stdout:<text>` or `stderr:<text>`
+const OUTPUT_HANDLE = 0x7fff0004
+
+const encoder = new TextEncoder()
+const decoder = new TextDecoder()
+
+async function compile (source) {
+  if (source instanceof WebAssembly.Module) {
+    return source
+  }
+  if (typeof source === 'string' || source instanceof URL) {
+    source = await fetch(source)
+  }
+  if (typeof Response !== 'undefined' && source instanceof Response) {
+    // compileStreaming requires the application/wasm content type
+    if (source.headers.get('content-type') === 'application/wasm') {
+      return WebAssembly.compileStreaming(source)
+    }
+    source = await source.arrayBuffer()
+  }
+  return WebAssembly.compile(source)
+}
+
+// Parse the exception from a traceback at the end of the output, for guests without pybox_exec_report
+function trailingException (output) {
+  const start = output.lastIndexOf('Traceback (most recent call last):\n')
+  if (start < 0) {
+    return null
+  }
+  const traceback = output.slice(start)
+  const last = traceback.trimEnd().split('\n').pop()
+  const colon = last.indexOf(': ')
+  const type = colon < 0 ? last : last.slice(0, colon)
+  if (last.startsWith(' ') || !/^[\w.]+$/.test(type)) {
+    return null
+  }
+  return { type, message: colon < 0 ? '' : last.slice(colon + 2), traceback }
+}
+
+/**
+ * A sandboxed Python interpreter running on the WebAssembly engine of the browser or Node.js.
+ *
+ * Code runs synchronously on the calling thread, run the reactor in a Web Worker
+ * to keep the page responsive; terminating the worker is the only way to stop
+ * a runaway execution.
+ */
+export class Reactor {
+  /**
+   * Load the reactor module and instantiate it
+   *
+   * @param {string | URL | Response | BufferSource | WebAssembly.Module} source
+   *     The reactor WASM file, a URL is fetched
+   * @param {{stdout?: (text: string) => void, stderr?: (text: string) => void}} [options]
+   *     Receive what the guest prints outside of an execution
+   */
+  static async load (source, options = {}) {
+    const module = await compile(source)
+    const wasi = new Wasi(options)
+    const reactor = new Reactor()
+    const instance = await WebAssembly.instantiate(module, {
+      wasi_snapshot_preview1: wasi.imports,
+      env: {
+        pybox_ioctl_host_req_impl: (handle, reqPtr, respPtr) => reactor._ioctl(handle, reqPtr, respPtr)
+      }
+    })
+    wasi.memory = instance.exports.memory
+    reactor._exports = instance.exports
+    if (typeof instance.exports._initialize === 'function') {
+      instance.exports._initialize()
+    }
+    return reactor
+  }
+
+  constructor () {
+    this._exports = null
+    this._handlers = new Map()
+    this._names = new Map()
+    this._onOutput = null
+    // The first exception thrown by a handler, rethrown after the guest returns
+    this._error = null
+    this._busy = false
+  }
+
+  /**
+   * Register a handler for ioctl requests
+   *
+   * @param {number} handle Handler ID
+   * @param {(request: Uint8Array) => Uint8Array | string | null} func Returns the response,
+   *     null makes the guest call fail
+   * @param {string} [name] Guest code can resolve it with pybox.resolve(name)
+   */
+  registerHandler (handle, func, name) {
+    if (!Number.isInteger(handle) || handle < 0 || handle >= RESERVED_HANDLE_BASE) {
+      throw new RangeError(`Handler ID ${handle} is reserved by pybox`)
+    }
+    if (typeof func !== 'function') {
+      throw new TypeError('A handler must be a function')
+    }
+    this._unname(handle)
+    this._handlers.set(handle, func)
+    if (name !== undefined) {
+      this._names.set(name, handle)
+    }
+  }
+
+  /**
+   * Unregister a handler
+   *
+   * @returns {boolean} Whether the handler was registered
+   */
+  unregisterHandler (handle) {
+    this._unname(handle)
+    return this._handlers.delete(handle)
+  }
+
+  _unname (handle) {
+    for (const [name, named] of this._names) {
+      if (named === handle) {
+        this._names.delete(name)
+      }
+    }
+  }
+
+  initLocal (envId) {
+    return this._call(() => this._withBytes([envId], ([id]) => this._exports.pybox_init_local(id)) === 0)
+  }
+
+  initLocalFrom (envId, fromEnvId) {
+    return this._call(() =>
+      this._withBytes([envId, fromEnvId], ([id, from]) => this._exports.pybox_init_local_from(id, from)) === 0
+    )
+  }
+
+  delLocal (envId) {
+    return this._call(() => this._withBytes([envId], ([id]) => this._exports.pybox_del_local(id)) === 0)
+  }
+
+  /**
+   * Execute code in an environment
+   *
+   * Exceptions raised by the code are reported in the result, exceptions thrown
+   * by handlers or onOutput make the guest call fail and are rethrown afterwards.
+   *
+   * @param {string} code
+   * @param {string} envId Local environment created with initLocal
+   * @param {{onOutput?: (stream: string, text: string) => void}} [options]
+   * @returns {ExecResult}
+   */
+  exec (code, envId, options = {}) {
+    return this._call(() => {
+      const exports = this._exports
+      const streamed = options.onOutput !== undefined && typeof exports.pybox_set_output_stream === 'function'
+      if (streamed) {
+        this._onOutput = options.onOutput
+        exports.pybox_set_output_stream(1)
+      }
+      let output, error, result
+      try {
+        [result, output, error] = this._withBytes([envId, code], ([id, source]) => {
+          const outputPtr = this._newPtr()
+          const errorPtr = this._newPtr()
+          const result = exports.pybox_exec(id, source, outputPtr, errorPtr)
+          return [result, this._takePtr(outputPtr), this._takePtr(errorPtr)]
+        })
+      } finally {
+        if (streamed) {
+          exports.pybox_set_output_stream(0)
+          this._onOutput = null
+        }
+      }
+      this._rethrow()
+      if (result !== 0) {
+        throw new Error(decoder.decode(error))
+      }
+
+      let report = this._readReport()
+      if (report === null) {
+        // Older guests merge the output into stdout
+        const stdout = decoder.decode(output)
+        report = { stdout, exception: trailingException(stdout) }
+      }
+      return {
+        stdout: report.stdout ?? '',
+        stderr: report.stderr ?? '',
+        exception: report.exception ?? null,
+        resultRepr: report.result_repr ?? null,
+        artifacts: report.artifacts ?? [],
+        truncated: report.truncated ?? false,
+        streamed
+      }
+    })
+  }
+
+  /** Create a variable from a JSON-compatible value */
+  assign (name, value, envId) {
+    this._call(() => {
+      const [result, error] = this._withBytes([envId, name, JSON.stringify(value)], ([id, key, json]) => {
+        const errorPtr = this._newPtr()
+        const result = this._exports.pybox_assign(id, key, json, errorPtr)
+        return [result, this._takePtr(errorPtr)]
+      })
+      if (result !== 0) {
+        throw new Error(decoder.decode(error))
+      }
+    })
+  }
+
+  /** Read a variable as a JSON-compatible value */
+  retrieve (name, envId) {
+    return this._call(() => {
+      if (typeof this._exports.pybox_retrieve !== 'function') {
+        throw new Error('The reactor does not export pybox_retrieve')
+      }
+      const [result, object, error] = this._withBytes([envId, name], ([id, key]) => {
+        const objectPtr = this._newPtr()
+        const errorPtr = this._newPtr()
+        const result = this._exports.pybox_retrieve(id, key, objectPtr, errorPtr)
+        return [result, this._takePtr(objectPtr), this._takePtr(errorPtr)]
+      })
+      if (result !== 0) {
+        throw new Error(decoder.decode(error))
+      }
+      return JSON.parse(decoder.decode(object))
+    })
+  }
+
+  /** Protect a variable from being reassigned or deleted by guest code */
+  protect (name, envId) {
+    this._call(() => {
+      const result = this._withBytes([envId, name], ([id, key]) => this._exports.pybox_local_protect(id, key))
+      if (result !== 0) {
+        throw new Error(`Failed to protect '${name}' in the environment '${envId}'`)
+      }
+    })
+  }
+
+  /**
+   * Save the memory of the reactor
+   *
+   * The WebAssembly API can not read globals that are not exported, snapshots are
+   * only consistent between calls into the guest, which is the only time this runs.
+   *
+   * @returns {Uint8Array}
+   */
+  snapshot () {
+    return this._call(() => new Uint8Array(this._exports.memory.buffer).slice())
+  }
+
+  /** Restore a snapshot taken from this reactor or one loaded from the same WASM file */
+  restore (snapshot) {
+    this._call(() => {
+      const memory = this._exports.memory
+      const missing = snapshot.length - memory.buffer.byteLength
+      if (missing > 0) {
+        memory.grow(Math.ceil(missing / 65536))
+      }
+      const data = new Uint8Array(memory.buffer)
+      data.set(snapshot)
+      data.fill(0, snapshot.length)
+    })
+  }
+
+  // The reactor can not be reentered from a handler
+  _call (f) {
+    if (this._busy) {
+      throw new Error('The reactor is busy, it can not be used from its own handlers')
+    }
+    this._busy = true
+    this._error = null
+    try {
+      return f()
+    } catch (err) {
+      // proc_exit leaves the instance in an unknown state
+      if (err instanceof WasiExit) {
+        this._exports = null
+      }
+      throw err
+    } finally {
+      this._busy = false
+    }
+  }
+
+  _rethrow () {
+    const error = this._error
+    this._error = null
+    if (error !== null) {
+      throw error
+    }
+  }
+
+  _ioctl (handle, reqPtr, respPtr) {
+    const memory = this._exports.memory
+    const view = new DataView(memory.buffer)
+    const buf = view.getUint32(reqPtr, true)
+    const len = view.getUint32(reqPtr + 4, true)
+    const request = new Uint8Array(memory.buffer, buf, len).slice()
+
+    let response
+    try {
+      if (handle === RESOLVE_HANDLE) {
+        const resolved = this._names.get(decoder.decode(request))
+        response = resolved === undefined ? null : String(resolved)
+      } else if (handle === OUTPUT_HANDLE) {
+        const text = decoder.decode(request)
+        const colon = text.indexOf(':')
+        if (this._onOutput !== null && colon >= 0) {
+          this._onOutput(text.slice(0, colon), text.slice(colon + 1))
+        }
+        response = ''
+      } else {
+        const handler = this._handlers.get(handle)
+        response = handler === undefined ? null : handler(request)
+      }
+    } catch (err) {
+      this._error ??= err
+      return -1
+    }
+    if (response === null || response === undefined) {
+      return -1
+    }
+    const data = typeof response === 'string' ? encoder.encode(response) : new Uint8Array(response)
+    const ptr = this._exports.pybox_alloc_mem(data.length)
+    if (ptr === 0) {
+      return -1
+    }
+    new Uint8Array(this._exports.memory.buffer, ptr, data.length).set(data)
+    const packet = new DataView(this._exports.memory.buffer)
+    packet.setUint32(respPtr, ptr, true)
+    packet.setUint32(respPtr + 4, data.length, true)
+    return 0
+  }
+
+  _readReport () {
+    if (typeof this._exports.pybox_exec_report !== 'function') {
+      return null
+    }
+    const reportPtr = this._newPtr()
+    const result = this._exports.pybox_exec_report(reportPtr)
+    const report = this._takePtr(reportPtr)
+    if (result !== 0 || report.length === 0) {
+      return null
+    }
+    return JSON.parse(decoder.decode(report))
+  }
+
+  // Create pybox_bytes (4 byte length + data) for each string, call f with the pointers and free them
+  _withBytes (strings, f) {
+    if (this._exports === null) {
+      throw new Error('The guest exited, load a new reactor')
+    }
+    const ptrs = strings.map((text) => {
+      const data = encoder.encode(text)
+      const ptr = this._exports.pybox_alloc_mem(4 + data.length)
+      const bytes = new Uint8Array(this._exports.memory.buffer, ptr, 4 + data.length)
+      new DataView(bytes.buffer).setUint32(ptr, data.length, true)
+      bytes.set(data, 4)
+      return ptr
+    })
+    try {
+      return f(ptrs)
+    } finally {
+      for (const ptr of ptrs) {
+        this._exports.pybox_free_mem(ptr)
+      }
+    }
+  }
+
+  // Allocate a *mut pybox_bytes initialized to NULL
+  _newPtr () {
+    const ptr = this._exports.pybox_alloc_mem(4)
+    new DataView(this._exports.memory.buffer).setUint32(ptr, 0, true)
+    return ptr
+  }
+
+  // Read the pybox_bytes a *mut pybox_bytes points to, free the data and the pointer itself
+  _takePtr (ptrPtr) {
+    const view = new DataView(this._exports.memory.buffer)
+    const ptr = view.getUint32(ptrPtr, true)
+    let data = new Uint8Array(0)
+    if (ptr !== 0) {
+      const len = view.getUint32(ptr, true)
+      data = new Uint8Array(this._exports.memory.buffer, ptr + 4, len).slice()
+      this._exports.pybox_free_mem(ptr)
+    }
+    this._exports.pybox_free_mem(ptrPtr)
+    return data
+  }
+}
+
+export { WasiExit }
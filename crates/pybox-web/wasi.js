@@ -0,0 +1,147 @@
+// A minimal wasi_snapshot_preview1 for the pybox reactor
+//
+// The reactor only needs stdio, clocks and randomness. There is no file system:
+// no directories are preopened, so guest file access fails with EBADF.
+
+const ESUCCESS = 0
+const EBADF = 8
+const ENOSYS = 52
+const ESPIPE = 70
+
+const FILETYPE_CHARACTER_DEVICE = 2
+const CLOCK_REALTIME = 0
+
+// crypto.getRandomValues fills at most 65536 bytes per call
+const RANDOM_CHUNK = 65536
+
+export class WasiExit extends Error {
+  constructor (code) {
+    super(`The guest exited with code ${code}`)
+    this.code = code
+  }
+}
+
+export class Wasi {
+  /**
+   * @param {{stdout?: (text: string) => void, stderr?: (text: string) => void}} [options]
+   *     Receive what the guest writes to fd 1 and 2 outside of an exec,
+   *     console.log / console.error by default
+   */
+  constructor (options = {}) {
+    this.memory = null
+    this._writers = {
+      1: options.stdout ?? ((text) => console.log(text)),
+      2: options.stderr ?? ((text) => console.error(text))
+    }
+    // Multi-byte characters may be split across fd_write calls
+    this._decoders = { 1: new TextDecoder(), 2: new TextDecoder() }
+  }
+
+  _view () {
+    return new DataView(this.memory.buffer)
+  }
+
+  _bytes (ptr, len) {
+    return new Uint8Array(this.memory.buffer, ptr, len)
+  }
+
+  /** The import object of the wasi_snapshot_preview1 module */
+  get imports () {
+    const unsupported = () => ENOSYS
+    const noFiles = () => EBADF
+    const empty = (countPtr, sizePtr) => {
+      const view = this._view()
+      view.setUint32(countPtr, 0, true)
+      view.setUint32(sizePtr, 0, true)
+      return ESUCCESS
+    }
+    return {
+      args_get: () => ESUCCESS,
+      args_sizes_get: empty,
+      environ_get: () => ESUCCESS,
+      environ_sizes_get: empty,
+      clock_time_get: (id, precision, timePtr) => {
+        const nanos = id === CLOCK_REALTIME
+          ? BigInt(Date.now()) * 1000000n
+          : BigInt(Math.round(performance.now() * 1000000))
+        this._view().setBigUint64(timePtr, nanos, true)
+        return ESUCCESS
+      },
+      random_get: (ptr, len) => {
+        for (let offset = 0; offset < len; offset += RANDOM_CHUNK) {
+          crypto.getRandomValues(this._bytes(ptr + offset, Math.min(RANDOM_CHUNK, len - offset)))
+        }
+        return ESUCCESS
+      },
+      fd_write: (fd, iovs, iovsLen, nwrittenPtr) => {
+        const writer = this._writers[fd]
+        if (writer === undefined) {
+          return EBADF
+        }
+        const view = this._view()
+        let written = 0
+        let text = ''
+        for (let i = 0; i < iovsLen; i++) {
+          const buf = view.getUint32(iovs + i * 8, true)
+          const len = view.getUint32(iovs + i * 8 + 4, true)
+          text += this._decoders[fd].decode(this._bytes(buf, len), { stream: true })
+          written += len
+        }
+        if (text !== '') {
+          writer(text)
+        }
+        view.setUint32(nwrittenPtr, written, true)
+        return ESUCCESS
+      },
+      // stdin is always at EOF
+      fd_read: (fd, iovs, iovsLen, nreadPtr) => {
+        if (fd !== 0) {
+          return EBADF
+        }
+        this._view().setUint32(nreadPtr, 0, true)
+        return ESUCCESS
+      },
+      fd_fdstat_get: (fd, ptr) => {
+        if (fd > 2) {
+          return EBADF
+        }
+        const view = this._view()
+        view.setUint8(ptr, FILETYPE_CHARACTER_DEVICE)
+        view.setUint16(ptr + 2, 0, true)
+        view.setBigUint64(ptr + 8, 0xffffffffffffffffn, true)
+        view.setBigUint64(ptr + 16, 0xffffffffffffffffn, true)
+        return ESUCCESS
+      },
+      // RustPython checks the standard streams when it starts
+      fd_filestat_get: (fd, ptr) => {
+        if (fd > 2) {
+          return EBADF
+        }
+        this._bytes(ptr, 64).fill(0)
+        this._view().setUint8(ptr + 16, FILETYPE_CHARACTER_DEVICE)
+        return ESUCCESS
+      },
+      fd_close: (fd) => (fd <= 2 ? ESUCCESS : EBADF),
+      fd_seek: (fd) => (fd <= 2 ? ESPIPE : EBADF),
+      fd_sync: (fd) => (fd <= 2 ? ESUCCESS : EBADF),
+      fd_prestat_get: noFiles,
+      fd_prestat_dir_name: noFiles,
+      fd_filestat_set_size: noFiles,
+      fd_readdir: noFiles,
+      path_create_directory: noFiles,
+      path_filestat_get: noFiles,
+      path_filestat_set_times: noFiles,
+      path_link: noFiles,
+      path_open: noFiles,
+      path_readlink: noFiles,
+      path_remove_directory: noFiles,
+      path_rename: noFiles,
+      path_unlink_file: noFiles,
+      poll_oneoff: unsupported,
+      sched_yield: () => ESUCCESS,
+      proc_exit: (code) => {
+        throw new WasiExit(code)
+      }
+    }
+  }
+}
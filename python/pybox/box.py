@@ -1,36 +1,152 @@
 
 import os
 import json
-from typing import Callable, Dict, Any
+import asyncio
+import threading
+import contextvars
+import inspect
+from typing import Callable, Dict, Any, Iterable
 
 from .exception import PyboxException
-from .pyboxcore import PyBoxReactor
-from .tool import PyboxPTCTool
+from .pyboxcore import PyBoxEngine, PyBoxReactor, PyBoxKVStore, PyBoxHostFS, PyBoxHttp, PyBoxSQLite, PyBoxQuotaError, PyBoxRateLimitError, PyBoxEnvLimitError, PyBoxGuestError, PyBoxTimeoutError, PyBoxReplayError, PyBoxLintError, PyBoxExecResult, PyBoxHandlerContext, encode_json, decode_json
+from .tool import PyboxPTCTool, PyboxRemoteObject
+
+
+def _accepts_ctx(func: Callable) -> bool:
+    try:
+        return "ctx" in inspect.signature(func).parameters
+    except (TypeError, ValueError):
+        return False
 
 
 class PyBoxHandler:
+    """
+    A raw handler, func receives the request bytes and returns the response bytes.
+    A func with a `ctx` parameter is also given the PyBoxHandlerContext of the call
+    """
 
-    def __init__(self, handle: int, func: Callable[[bytes], bytes]):
+    def __init__(self, handle: int, func: Callable[..., bytes]):
         self.handle = handle
         self.func = func
+        self._func_ctx = _accepts_ctx(func)
 
-    def __call__(self, data:bytes):
+    def __call__(self, data: bytes, ctx: PyBoxHandlerContext = None):
+        if self._func_ctx:
+            return self.func(data, ctx=ctx)
         return self.func(data)
 
 
+class PyBoxStream:
+    """
+    Return it from a tool to stream the result chunk by chunk,
+    sandboxed code receives a pybox.Stream and pulls the chunks on demand
+    """
+
+    def __init__(self, chunks: Iterable):
+        self._chunks = iter(chunks)
+
+    def __iter__(self):
+        return self
+
+    def __next__(self) -> bytes:
+        chunk = next(self._chunks)
+        return chunk.encode('utf-8') if isinstance(chunk, str) else bytes(chunk)
+
+
+def _json_default(obj):
+    # exec results used to be plain strings, keep encoding them that way
+    if isinstance(obj, PyBoxExecResult):
+        return str(obj)
+    raise TypeError(f"Object of type {type(obj).__name__} is not JSON serializable")
+
+
+class PyBoxRPCContext:
+    """
+    Who made the JSON-RPC request being handled, see rpc_context()
+    """
+
+    def __init__(self, env_id: str = None, correlation_id: str = None, handler: PyBoxHandlerContext = None):
+        # None when the guest is too old to send it
+        self.env_id = env_id
+        # the correlation_id passed to exec, None if there was none
+        self.correlation_id = correlation_id
+        # handler name and call sequence number, None when called outside a reactor
+        self.handler = handler
+
+    def __repr__(self):
+        return f"PyBoxRPCContext(env_id={self.env_id!r}, correlation_id={self.correlation_id!r}, handler={self.handler!r})"
+
+
+_rpc_context: contextvars.ContextVar = contextvars.ContextVar("pybox_rpc_context", default=None)
+
+
+def rpc_context() -> PyBoxRPCContext:
+    """
+    The calling environment of the JSON-RPC request being handled,
+    None outside of a JSON-RPC handler. Use it for per-env authorization and quotas:
+
+        @box.tool
+        def search(query):
+            if rpc_context().env_id not in allowed_envs:
+                raise PermissionError("search is not allowed")
+    """
+    return _rpc_context.get()
+
+
+def _call_with_timeout(func: Callable[[], Any], timeout: float) -> Any:
+    """
+    Run func in a daemon thread and give up after timeout seconds, the abandoned call keeps running
+    but its result is dropped. func runs outside the reactor thread and cannot re-enter the sandbox
+    """
+    context = contextvars.copy_context()
+    outcome = {}
+
+    def run():
+        try:
+            outcome["result"] = context.run(func)
+        except BaseException as e:
+            outcome["error"] = e
+
+    worker = threading.Thread(target=run, name="pybox-rpc-timeout", daemon=True)
+    worker.start()
+    worker.join(timeout)
+    if worker.is_alive():
+        raise TimeoutError(f"handler did not return within {timeout} seconds")
+    if "error" in outcome:
+        raise outcome["error"]
+    return outcome["result"]
+
+
 class PyBoxJSONRPCHandler(PyBoxHandler):
 
     def __init__(self, handle: int, callback: Callable[..., Any]):
         self.callback = callback
         super().__init__(handle, self._handler_impl)
 
-    def _handler_impl(self, data: bytes) -> bytes:
+    def _handler_impl(self, data: bytes, ctx: PyBoxHandlerContext = None) -> bytes:
         try:
-            request = json.loads(data.decode('utf-8'))
+            request = decode_json(data)
             args = request.get("args", [])
             kwargs = request.get("kwargs", {})
-            result = self.callback(*args, **kwargs)
-            response_data = json.dumps({"result": result}).encode('utf-8')
+            # guests too old to send the env are still known to the reactor
+            env_id = request.get("env", ctx.env_id if ctx is not None else None)
+            context = PyBoxRPCContext(env_id, request.get("correlation_id"), ctx)
+            token = _rpc_context.set(context)
+            try:
+                timeout = request.get("timeout")
+                if timeout is None:
+                    result = self.callback(*args, **kwargs)
+                else:
+                    result = _call_with_timeout(lambda: self.callback(*args, **kwargs), timeout)
+            finally:
+                _rpc_context.reset(token)
+            if isinstance(result, PyBoxStream):
+                return result
+            if ctx is not None:
+                response = encode_json({"result": result}, ctx.non_finite, _json_default, ctx.big_ints)
+            else:
+                response = encode_json({"result": result}, default=_json_default)
+            response_data = response.encode('utf-8')
             return response_data
         except PyboxException:
             # use this Exception to escape from sandbox
@@ -48,6 +164,34 @@ class PyBoxJSONRPCHandler(PyBoxHandler):
         #     raise PyboxException("WASM internal exeception",e) from e
 
 
+class PyBoxObjectHandler(PyBoxJSONRPCHandler):
+    """
+    Expose a host object to the guest (see pybox.RemoteObject),
+    only the allowlisted methods and attributes are reachable
+    """
+
+    def __init__(self, handle: int, obj: Any, methods: Iterable[str], attributes: Iterable[str] = ()):
+        self.obj = obj
+        self.methods = frozenset(methods)
+        self.attributes = frozenset(attributes)
+        super().__init__(handle, self._dispatch)
+
+    def _dispatch(self, *args, **kwargs):
+        # (op, [name], *args) positional, keep kwargs free for the remote method
+        op, *args = args
+        if op == "spec":
+            return {
+                "methods": sorted(self.methods),
+                "attributes": sorted(self.attributes),
+            }
+        name, *args = args
+        if op == "getattr" and name in self.attributes:
+            return getattr(self.obj, name)
+        if op == "call" and name in self.methods:
+            return getattr(self.obj, name)(*args, **kwargs)
+        raise PermissionError(f"'{name}' is not exposed by the host")
+
+
 
 
 class PyBox(PyBoxReactor):
@@ -55,38 +199,199 @@ class PyBox(PyBoxReactor):
     Python wrapper for PyBoxReactor with automatic WASM file loading
     """
 
-    def __init__(self, preopen_dirs={}):
+    def __init__(self, preopen_dirs={}, max_call_depth=16, compress_threshold=64 * 1024, wasm_file=None):
         """
-        Initialize PyBox with optional preopen directories and environment variables
+        Initialize PyBox with optional preopen directories and environment variables,
+        further options are set with PyBoxReactorBuilder
 
         Args:
             preopen_dirs: Dictionary mapping guest paths to host paths (GUEST:HOST)
             env_vars: Dictionary of environment variables (currently not used)
+            max_call_depth: Maximum depth of guest calls (exec/retrieve/call) nested inside tools
+            compress_threshold: Tool payloads at least this large are zstd-compressed, None disables it
+            wasm_file: Path of the reactor WASM file, the bundled image if None
         """
-        image_dir = os.path.join(os.path.dirname(__file__), "image")
-        # Find the WASM file
-        wasm_file = os.path.join(image_dir, "pybox_reactor.wasm")
+        self._start_box(preopen_dirs, max_call_depth, compress_threshold, wasm_file)
+
 
-        # Call parent __init__ to initialize the reactor
-        super().__init__(wasm_file, preopen_dirs)
+    def _start_box(self, preopen_dirs, max_call_depth, compress_threshold, wasm_file, prewarm=False, lazy=False, engine: PyBoxEngine = None):
+        if wasm_file is None:
+            image_dir = os.path.join(os.path.dirname(__file__), "image")
+            # Find the WASM file
+            wasm_file = os.path.join(image_dir, "pybox_reactor.wasm")
+
+        self._start(wasm_file, preopen_dirs, max_call_depth, compress_threshold, prewarm, lazy, engine)
 
         self._handlers: Dict[int, PyBoxHandler] = {}
 
 
-    def tool(self,func:Callable):
+    def _register(self, handler: PyBoxHandler, name: str = None, **quotas):
+        self._handlers[handler.handle] = handler
+        self.register_handler(handler.handle, handler, name, **quotas)
+
+
+    def tool(self, func: Callable = None, *, namespace: str = None, max_request_bytes: int = None, max_response_bytes: int = None, max_calls_per_exec: int = None):
+        """
+        Register func as a tool, usable as `@box.tool` or `@box.tool(namespace="tools")`
+
+        The tool is named `namespace.func_name` (or `func_name`), sandboxed code can call it
+        through `pybox.rpc_proxy(namespace).func_name(...)` as well as through its stub.
+        Calls beyond the quotas raise `pybox.QuotaError` in the sandbox, see PyBoxReactor.register_handler
+        """
+        quotas = dict(max_request_bytes=max_request_bytes, max_response_bytes=max_response_bytes, max_calls_per_exec=max_calls_per_exec)
+        if func is None:
+            return lambda func: self.tool(func, namespace=namespace, **quotas)
+
         handler = PyBoxJSONRPCHandler(
                 len(self._handlers),
                 func
             )
-        self.register_handler(handler.handle,handler)
+        name = f"{namespace}.{func.__name__}" if namespace else func.__name__
+        self._register(handler, name, **quotas)
         return PyboxPTCTool(
             handler.handle,
             func
         )
 
 
+    def expose(self, obj: Any, methods: Iterable[str], attributes: Iterable[str] = ()):
+        handler = PyBoxObjectHandler(
+                len(self._handlers),
+                obj,
+                methods,
+                attributes
+            )
+        self._register(handler)
+        return PyboxRemoteObject(
+            handler.handle,
+            obj
+        )
+
+
+    def enable_kv(self, store: PyBoxKVStore = None):
+        """
+        Register the built-in key/value store, sandboxed code reaches it as `pybox.kv`
+
+        Args:
+            store: Store to register, a new in-memory store if None.
+                   A store can be shared by several boxes, data is scoped per environment
+        """
+        if store is None:
+            store = PyBoxKVStore()
+        self._register_builtin(store, "pybox.kv")
+        return store
+
+
+    def enable_hostfs(self, mounts: Dict[str, str], max_file_size: int = None, extensions: Iterable[str] = None, read_only: bool = False):
+        """
+        Register the built-in host filesystem handler, sandboxed code reaches it as `pybox.hostfs`
+
+        Args:
+            mounts: Dictionary mapping mount names to host directories, seen by the guest as `/<name>/...`
+            max_file_size: Optional limit of a single read or write in bytes
+            extensions: Optional allowed file extensions
+            read_only: Reject every write if True
+        """
+        fs = PyBoxHostFS(
+            mounts,
+            max_file_size,
+            list(extensions) if extensions is not None else None,
+            read_only
+        )
+        self._register_builtin(fs, "pybox.hostfs")
+        return fs
+
+
+    def enable_http(self, allowed_hosts: Iterable[str], methods: Iterable[str] = None, max_response_bytes: int = None, max_requests: int = None, timeout: float = None):
+        """
+        Register the built-in http handler, sandboxed code reaches it as `pybox.http`
+
+        Args:
+            allowed_hosts: Hosts the guest may reach, `*.example.com` matches subdomains
+            methods: Allowed methods, GET and HEAD by default
+            max_response_bytes: Limit of a response body, 1 MiB by default
+            max_requests: Optional limit of requests per exec
+            timeout: Request timeout in seconds, 30 by default
+        """
+        http = PyBoxHttp(
+            list(allowed_hosts),
+            list(methods) if methods is not None else None,
+            max_response_bytes,
+            max_requests,
+            timeout
+        )
+        self._register_builtin(http, "pybox.http")
+        return http
+
+
+    def enable_sql(self, directory: str = None, max_rows: int = None):
+        """
+        Register the built-in SQLite handler, sandboxed code reaches it as `pybox.sql`
+
+        Args:
+            directory: Optional directory holding one database file per environment,
+                       in-memory databases are used if None
+            max_rows: Limit of rows returned by a query, 1000 by default
+        """
+        sql = PyBoxSQLite(directory, max_rows)
+        self._register_builtin(sql, "pybox.sql")
+        return sql
+
+
+    async def exec_async(self, code: str, env_id: str = None, cancel_grace: float = 1.0, **kwargs) -> PyBoxExecResult:
+        """
+        Execute code in a worker thread without blocking the event loop, takes the keyword arguments of exec
+
+        Cancelling the task, e.g. through `asyncio.wait_for(box.exec_async(code, id), 5)`, stops the sandbox:
+        KeyboardInterrupt is raised inside the guest, a guest that has not unwound after cancel_grace seconds
+        is stopped, and CancelledError is re-raised once the reactor is idle again.
+        Callbacks such as on_output run in the worker thread.
+        """
+        loop = asyncio.get_running_loop()
+        cancelled = threading.Event()
+
+        def run():
+            # cancelled before the worker thread picked it up
+            if cancelled.is_set():
+                return None
+            return self.exec(code, env_id, **kwargs)
+
+        future = loop.run_in_executor(None, run)
+        try:
+            return await asyncio.shield(future)
+        except asyncio.CancelledError:
+            cancelled.set()
+            # cancel again until the execution returned, the second cancel stops the guest
+            while not future.done():
+                self.cancel()
+                await asyncio.wait({future}, timeout=cancel_grace)
+            if not future.cancelled():
+                future.exception()
+            raise
+
+
+    def _register_builtin(self, handler, name: str):
+        handle = len(self._handlers)
+        self._handlers[handle] = handler
+        self.register_handler(handle, handler, name)
+
+
 __all__ = [
     PyBoxHandler.__name__,
+    PyBoxHandlerContext.__name__,
     PyBoxJSONRPCHandler.__name__,
+    PyBoxObjectHandler.__name__,
+    PyBoxStream.__name__,
+    PyBoxRPCContext.__name__,
+    rpc_context.__name__,
+    PyBoxQuotaError.__name__,
+    PyBoxRateLimitError.__name__,
+    PyBoxEnvLimitError.__name__,
+    PyBoxGuestError.__name__,
+    PyBoxTimeoutError.__name__,
+    PyBoxReplayError.__name__,
+    PyBoxLintError.__name__,
+    PyBoxExecResult.__name__,
+    PyBoxEngine.__name__,
     PyBox.__name__
 ]
\ No newline at end of file
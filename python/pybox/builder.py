@@ -0,0 +1,202 @@
+from typing import Callable, Dict, List, Tuple
+
+from .box import PyBox, PyBoxEngine
+from .pyboxcore import PyBoxReactorSnapshot
+
+
+class PyBoxReactorBuilder:
+    """
+    Fluent configuration of a PyBox, the same builder can build any number of boxes
+
+        box = (PyBoxReactorBuilder()
+               .memory_limit(256 * 1024 * 1024)
+               .timeout(5)
+               .preopen("/data", "./data")
+               .tool(search)
+               .build())
+    """
+
+    def __init__(self):
+        self._wasm_file: str = None
+        self._prewarm: bool = False
+        self._lazy: bool = False
+        self._engine: PyBoxEngine = None
+        self._fuel: bool = False
+        self._snapshot: PyBoxReactorSnapshot = None
+        self._preopen_dirs: Dict[str, str] = {}
+        self._max_call_depth: int = 16
+        self._compress_threshold: int = 64 * 1024
+        self._memory_limit: int = None
+        self._timeout: float = None
+        self._checkpoint: bool = False
+        self._handlers: List[Tuple[int, Callable[[bytes], bytes], str]] = []
+        self._tools: List[Tuple[Callable, str]] = []
+        self._prelude: Tuple[str, bool] = None
+        self._envs: List[str] = []
+
+
+    def wasm(self, path: str) -> "PyBoxReactorBuilder":
+        """
+        Use a reactor WASM file other than the bundled image
+        """
+        self._wasm_file = path
+        return self
+
+
+    def prewarm(self, enabled: bool = True) -> "PyBoxReactorBuilder":
+        """
+        Start from the pre-initialized snapshot shipped with the WASM file, disabled by default.
+        Boxes with preopen directories always start normally
+        """
+        self._prewarm = enabled
+        return self
+
+
+    def lazy(self, enabled: bool = True) -> "PyBoxReactorBuilder":
+        """
+        Defer starting the sandbox until it is first used, boxes that are never used cost little
+        """
+        self._lazy = enabled
+        return self
+
+
+    def engine(self, engine: PyBoxEngine) -> "PyBoxReactorBuilder":
+        """
+        Run the boxes on a shared engine, they share its compiled modules and configuration
+        """
+        self._engine = engine
+        return self
+
+
+    def fuel(self, enabled: bool = True) -> "PyBoxReactorBuilder":
+        """
+        Run on the default engine that counts fuel, needed by fuel budgets and slower, disabled by default.
+        A box with an explicit engine counts fuel when the engine was created with fuel=True
+        """
+        self._fuel = enabled
+        return self
+
+
+    def snapshot(self, snapshot: PyBoxReactorSnapshot) -> "PyBoxReactorBuilder":
+        """
+        Start the boxes from a snapshot, recycle() resets them to it, see PyBoxReactor.from_snapshot
+        """
+        self._snapshot = snapshot
+        return self
+
+
+    def preopen(self, guest_path: str, host_path: str) -> "PyBoxReactorBuilder":
+        """
+        Map a host directory into the sandbox filesystem
+        """
+        self._preopen_dirs[guest_path] = host_path
+        return self
+
+
+    def max_call_depth(self, depth: int) -> "PyBoxReactorBuilder":
+        """
+        Maximum depth of guest calls nested inside tools
+        """
+        self._max_call_depth = depth
+        return self
+
+
+    def compress_threshold(self, threshold: int) -> "PyBoxReactorBuilder":
+        """
+        Tool payloads at least this large are zstd-compressed, None disables it
+        """
+        self._compress_threshold = threshold
+        return self
+
+
+    def memory_limit(self, limit: int) -> "PyBoxReactorBuilder":
+        """
+        Maximum size of the guest memory in bytes, see PyBoxReactor.set_memory_limit
+        """
+        self._memory_limit = limit
+        return self
+
+
+    def timeout(self, seconds: float, checkpoint: bool = False) -> "PyBoxReactorBuilder":
+        """
+        Stop executions running longer than seconds and restore the box,
+        see PyBoxReactor.set_watchdog
+        """
+        self._timeout = seconds
+        self._checkpoint = checkpoint
+        return self
+
+
+    def handler(self, handle: int, func: Callable[[bytes], bytes], name: str = None) -> "PyBoxReactorBuilder":
+        """
+        Register a raw ioctl handler or a built-in handler such as PyBoxKVStore
+        """
+        self._handlers.append((handle, func, name))
+        return self
+
+
+    def tool(self, func: Callable, namespace: str = None) -> "PyBoxReactorBuilder":
+        """
+        Register func as a tool, see PyBox.tool
+        """
+        self._tools.append((func, namespace))
+        return self
+
+
+    def prelude(self, code: str, per_exec: bool = False) -> "PyBoxReactorBuilder":
+        """
+        Code run in every new environment, or before every exec if per_exec
+        """
+        self._prelude = (code, per_exec)
+        return self
+
+
+    def env(self, env_id: str) -> "PyBoxReactorBuilder":
+        """
+        Create a local environment in every built box
+        """
+        self._envs.append(env_id)
+        return self
+
+
+    def build(self) -> PyBox:
+        """
+        Create a PyBox with the current configuration
+        """
+        # PyBox.__init__ only takes the baseline parameters, the other options start the box directly
+        box = PyBox.__new__(PyBox)
+        engine = self._engine
+        if engine is None and self._fuel:
+            engine = PyBoxEngine.default(fuel=True)
+        box._start_box(
+            dict(self._preopen_dirs),
+            self._max_call_depth,
+            self._compress_threshold,
+            self._wasm_file,
+            self._prewarm,
+            self._lazy,
+            engine
+        )
+        if self._snapshot is not None:
+            box._restore_baseline(self._snapshot)
+        for handle, func, name in self._handlers:
+            box._handlers[handle] = func
+            box.register_handler(handle, func, name)
+        for func, namespace in self._tools:
+            box.tool(func, namespace=namespace)
+        if self._prelude is not None:
+            box.set_prelude(*self._prelude)
+        if self._memory_limit is not None:
+            box.set_memory_limit(self._memory_limit)
+        for env_id in self._envs:
+            if not box.init_local(env_id):
+                raise RuntimeError(f"Failed to create environment '{env_id}'")
+        # the watchdog snapshot is taken last, so a timeout restores the configured state
+        if self._timeout is not None:
+            box.set_watchdog(self._timeout, checkpoint=self._checkpoint)
+        return box
+
+
+__all__ = [
+    PyBoxReactorBuilder.__name__
+]
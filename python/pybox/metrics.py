@@ -0,0 +1,102 @@
+import math
+from typing import Any, Dict, List, Mapping, Tuple
+
+# (stats key, metric name, type, help)
+_REACTOR_METRICS = [
+    ("uptime", "pybox_uptime_seconds", "gauge", "Seconds since the reactor was created"),
+    ("execs", "pybox_execs_total", "counter", "Executions that reached the guest"),
+    ("failures", "pybox_exec_failures_total", "counter", "Executions that raised or timed out"),
+    ("timeouts", "pybox_exec_timeouts_total", "counter", "Executions that timed out"),
+    ("traps", "pybox_traps_total", "counter", "Executions aborted by a guest trap or an exhausted fuel budget"),
+    ("guest_time", "pybox_exec_seconds_total", "counter", "Seconds spent running executions"),
+    ("fuel_consumed", "pybox_fuel_consumed_total", "counter", "Fuel consumed by executions"),
+    ("ioctl_calls", "pybox_ioctl_calls_total", "counter", "Requests from the guest to the host"),
+    ("handler_calls", "pybox_handler_calls_total", "counter", "Calls of each registered handler"),
+    ("memory_bytes", "pybox_memory_bytes", "gauge", "Guest memory size after the last execution"),
+]
+
+_POOL_METRICS = [
+    ("target", "pybox_pool_target_boxes", "gauge", "Number of idle boxes the pool keeps ready"),
+    ("available", "pybox_pool_available_boxes", "gauge", "Idle boxes ready to be leased"),
+    ("busy", "pybox_pool_busy_boxes", "gauge", "Boxes currently leased"),
+    ("created", "pybox_pool_created_total", "counter", "Boxes created by the pool"),
+    ("replaced", "pybox_pool_replaced_total", "counter", "Leased boxes discarded instead of reused"),
+]
+
+
+def _escape(value: str) -> str:
+    return value.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n")
+
+
+def _format_value(value) -> str:
+    if isinstance(value, float):
+        if math.isnan(value):
+            return "NaN"
+        if math.isinf(value):
+            return "+Inf" if value > 0 else "-Inf"
+        return repr(value)
+    return str(int(value))
+
+
+def _format_labels(labels: Mapping[str, str]) -> str:
+    if not labels:
+        return ""
+    return "{" + ",".join(f'{name}="{_escape(str(value))}"' for name, value in labels.items()) + "}"
+
+
+def render_prometheus(
+    reactors: Mapping[str, Any] = None,
+    pools: Mapping[str, Any] = None,
+    labels: Mapping[str, str] = None
+) -> str:
+    """
+    Render reactor and pool counters in the Prometheus text exposition format
+
+        text = render_prometheus(reactors={"main": box}, pools={"warm": pool}, labels={"host": "a"})
+
+    Every sample of a reactor is labelled with reactor="<name>" and every sample of a
+    pool with pool="<name>", so several of them can be exported from one endpoint.
+
+    Args:
+        reactors: Name -> reactor (anything with the stats() of PyBoxReactor)
+        pools: Name -> pool (anything with the stats() of PyBoxWarmPool)
+        labels: Extra labels added to every sample
+
+    Returns:
+        str: The metrics, each with its HELP and TYPE lines
+    """
+    labels = dict(labels or {})
+    samples: Dict[str, List[Tuple[Dict[str, str], Any]]] = {}
+
+    def collect(definitions, kind: str, objects: Mapping[str, Any]):
+        for name, obj in (objects or {}).items():
+            stats = obj.stats()
+            for key, metric, _, _ in definitions:
+                if key not in stats:
+                    continue
+                sample_labels = {**labels, kind: name}
+                value = stats[key]
+                if isinstance(value, Mapping):
+                    # handler_calls: handler name -> count
+                    for handler, count in sorted(value.items()):
+                        samples.setdefault(metric, []).append(({**sample_labels, "handler": handler}, count))
+                else:
+                    samples.setdefault(metric, []).append((sample_labels, value))
+
+    collect(_REACTOR_METRICS, "reactor", reactors)
+    collect(_POOL_METRICS, "pool", pools)
+
+    lines = []
+    for _, metric, metric_type, description in _REACTOR_METRICS + _POOL_METRICS:
+        if metric not in samples:
+            continue
+        lines.append(f"# HELP {metric} {description}")
+        lines.append(f"# TYPE {metric} {metric_type}")
+        for sample_labels, value in samples[metric]:
+            lines.append(f"{metric}{_format_labels(sample_labels)} {_format_value(value)}")
+    return "\n".join(lines) + "\n" if lines else ""
+
+
+__all__ = [
+    render_prometheus.__name__
+]
@@ -0,0 +1,589 @@
+import contextlib
+import multiprocessing
+import os
+import threading
+import time
+from collections import deque
+from concurrent.futures import Future, ThreadPoolExecutor
+from typing import Any, Callable, Deque, Dict, Iterator, List, Optional, Tuple, Union
+
+from .box import PyBox
+from .exception import PyboxException
+from .pyboxcore import PyBoxReactorSnapshot
+
+
+class PyBoxWorkerError(PyboxException):
+    """
+    A worker process exited while serving a call, its environments are lost
+    """
+    pass
+
+
+def _worker_main(conn, wasm_file: str, preopen_dirs: Dict[str, str], snapshot: bytes):
+    """
+    Entry point of a worker process: serve (method, args, kwargs) requests on conn
+    until the pool closes it
+    """
+    try:
+        box = PyBox(preopen_dirs, wasm_file=wasm_file)
+        if snapshot is not None:
+            PyBoxReactorSnapshot.from_bytes(snapshot).restore(box)
+    except BaseException as e:
+        conn.send(("error", PyboxException(f"Worker failed to start: {type(e).__name__}: {e}")))
+        return
+    conn.send(("ok", os.getpid()))
+
+    while True:
+        try:
+            request = conn.recv()
+        except EOFError:
+            break
+        if request is None:
+            break
+        method, args, kwargs = request
+        try:
+            reply = ("ok", getattr(box, method)(*args, **kwargs))
+        except BaseException as e:
+            reply = ("error", e)
+        try:
+            conn.send(reply)
+        except Exception as e:
+            # the reply is pickled before anything is written, the pipe is still usable
+            conn.send(("error", PyboxException(f"Cannot send the result of {method}: {type(e).__name__}: {e}")))
+
+
+class _Worker:
+    """
+    One reactor in its own process, calls are serialized by a lock
+    """
+
+    def __init__(self, context, wasm_file: str, preopen_dirs: Dict[str, str], snapshot: bytes):
+        self._conn, child = context.Pipe()
+        self.process = context.Process(target=_worker_main, args=(child, wasm_file, preopen_dirs, snapshot), daemon=True)
+        self.process.start()
+        child.close()
+        self._lock = threading.Lock()
+        self.envs = set()
+        with self._lock:
+            self._receive()
+
+
+    def call(self, method: str, *args, **kwargs) -> Any:
+        with self._lock:
+            try:
+                self._conn.send((method, args, kwargs))
+            except (BrokenPipeError, OSError):
+                raise self._exited() from None
+            return self._receive()
+
+
+    def _receive(self) -> Any:
+        try:
+            status, value = self._conn.recv()
+        except (EOFError, OSError):
+            raise self._exited() from None
+        if status == "error":
+            raise value
+        return value
+
+
+    def _exited(self) -> PyBoxWorkerError:
+        self.process.join(1)
+        return PyBoxWorkerError(f"Worker process {self.process.pid} exited with code {self.process.exitcode}")
+
+
+    def alive(self) -> bool:
+        return self.process.is_alive()
+
+
+    def close(self, timeout: float = 5):
+        try:
+            self._conn.send(None)
+        except (BrokenPipeError, OSError):
+            pass
+        self.process.join(timeout)
+        if self.process.is_alive():
+            self.process.kill()
+            self.process.join()
+        self._conn.close()
+
+
+class PyBoxProcessPool:
+    """
+    Reactors in separate worker processes, for parallelism beyond one process and crash isolation
+
+    Every environment lives on one worker, chosen when it is created. Calls on environments
+    of different workers run in parallel when they come from different threads, or use submit.
+    Arguments and results cross the process boundary with pickle.
+
+        with PyBoxProcessPool(workers=4, snapshot=box.snapshot()) as pool:
+            pool.init_local("a")
+            pool.init_local("b")
+            futures = [pool.submit(code, "a"), pool.submit(code, "b")]
+            print([future.result().stdout for future in futures])
+    """
+
+    def __init__(self, workers: int = None, wasm_file: str = None, preopen_dirs: Dict[str, str] = {}, snapshot: Union[PyBoxReactorSnapshot, bytes] = None, start_method: str = "spawn"):
+        """
+        Args:
+            workers: Number of worker processes, os.cpu_count() if None
+            wasm_file: Path of the reactor WASM file, the bundled image if None
+            preopen_dirs: Dictionary mapping guest paths to host paths (GUEST:HOST)
+            snapshot: Snapshot every worker starts from (and restarts from after a crash),
+                taken from a PyBox with the same WASM file
+            start_method: multiprocessing start method, "spawn" does not inherit the state of this process
+        """
+        if isinstance(snapshot, PyBoxReactorSnapshot):
+            snapshot = snapshot.to_bytes()
+        self._context = multiprocessing.get_context(start_method)
+        self._args = (wasm_file, preopen_dirs, snapshot)
+        self._lock = threading.Lock()
+        self._setup: List[Tuple[str, tuple, dict]] = []
+        self._envs: Dict[str, _Worker] = {}
+        self._workers: List[_Worker] = [_Worker(self._context, *self._args) for _ in range(workers or os.cpu_count() or 1)]
+        self._executor = ThreadPoolExecutor(len(self._workers))
+        self._closed = False
+
+
+    def _new_worker(self) -> _Worker:
+        worker = _Worker(self._context, *self._args)
+        for method, args, kwargs in self._setup:
+            worker.call(method, *args, **kwargs)
+        return worker
+
+
+    def _worker(self, env_id: str) -> _Worker:
+        with self._lock:
+            if self._closed:
+                raise PyboxException("The pool is closed")
+            try:
+                return self._envs[env_id]
+            except KeyError:
+                raise KeyError(f"No environment '{env_id}' in the pool") from None
+
+
+    def _call(self, worker: _Worker, method: str, *args, **kwargs) -> Any:
+        """
+        Call a method of the worker's box, a worker that exits is replaced and its environments are dropped
+        """
+        try:
+            return worker.call(method, *args, **kwargs)
+        except PyBoxWorkerError:
+            self._replace(worker)
+            raise
+
+
+    def _replace(self, worker: _Worker):
+        with self._lock:
+            if self._closed or worker not in self._workers:
+                return
+            for env_id in worker.envs:
+                self._envs.pop(env_id, None)
+            self._workers.remove(worker)
+        worker.close()
+        replacement = self._new_worker()
+        with self._lock:
+            self._workers.append(replacement)
+
+
+    def init_local(self, env_id: str, encoding: Optional[str] = None, errors: Optional[str] = None) -> bool:
+        """
+        Create an environment on the worker with the fewest environments, see PyBoxReactor.init_local for encoding and errors
+
+        Returns:
+            bool: False if the environment already exists
+        """
+        with self._lock:
+            if self._closed:
+                raise PyboxException("The pool is closed")
+            if env_id in self._envs:
+                return False
+            worker = min(self._workers, key=lambda worker: len(worker.envs))
+            # reserve the id so that concurrent calls pick the same worker
+            self._envs[env_id] = worker
+            worker.envs.add(env_id)
+        try:
+            created = self._call(worker, "init_local", env_id, encoding=encoding, errors=errors)
+        except BaseException:
+            self._forget(env_id)
+            raise
+        if not created:
+            self._forget(env_id)
+        return created
+
+
+    def init_local_from(self, env_id: str, from_env_id: str) -> bool:
+        """
+        Create an environment inheriting from another one, on the worker of the other one
+        """
+        worker = self._worker(from_env_id)
+        with self._lock:
+            if env_id in self._envs:
+                return False
+            self._envs[env_id] = worker
+            worker.envs.add(env_id)
+        try:
+            created = self._call(worker, "init_local_from", env_id, from_env_id)
+        except BaseException:
+            self._forget(env_id)
+            raise
+        if not created:
+            self._forget(env_id)
+        return created
+
+
+    def del_local(self, env_id: str) -> bool:
+        """
+        Delete an environment, returns whether it existed
+        """
+        with self._lock:
+            worker = self._envs.get(env_id)
+        if worker is None:
+            return False
+        deleted = self._call(worker, "del_local", env_id)
+        self._forget(env_id)
+        return deleted
+
+
+    def _forget(self, env_id: str):
+        with self._lock:
+            worker = self._envs.pop(env_id, None)
+            if worker is not None:
+                worker.envs.discard(env_id)
+
+
+    def exec(self, code: str, env_id: str, **kwargs) -> Any:
+        """
+        Execute code in an environment, takes the keyword arguments of PyBox.exec
+        except on_output, which cannot cross the process boundary
+
+        Raises:
+            PyBoxWorkerError: The worker exited, it is restarted without its environments
+        """
+        return self._call(self._worker(env_id), "exec", code, env_id, **kwargs)
+
+
+    def submit(self, code: str, env_id: str, **kwargs) -> Future:
+        """
+        Execute code in the background, returns a Future of the exec result
+        """
+        return self._executor.submit(self.exec, code, env_id, **kwargs)
+
+
+    def assign(self, env_id: str, name: str, value: Any):
+        """
+        Assign a JSON-serializable value to a variable in an environment
+        """
+        return self._call(self._worker(env_id), "assign", env_id, name, value)
+
+
+    def retrieve(self, env_id: str, name: str) -> Any:
+        """
+        Retrieve the value of a variable in an environment
+        """
+        return self._call(self._worker(env_id), "retrieve", env_id, name)
+
+
+    def protect(self, env_id: str, name: str):
+        """
+        Protect a variable in an environment from modification
+        """
+        return self._call(self._worker(env_id), "protect", env_id, name)
+
+
+    def protect_all(self, env_id: str) -> List[str]:
+        """
+        Protect every variable currently defined in an environment
+        """
+        return self._call(self._worker(env_id), "protect_all", env_id)
+
+
+    def unprotect(self, env_id: str, names: List[str]):
+        """
+        Stop protecting variables in an environment
+        """
+        return self._call(self._worker(env_id), "unprotect", env_id, names)
+
+
+    def protected_keys(self, env_id: str) -> List[str]:
+        """
+        List the protected variables of an environment
+        """
+        return self._call(self._worker(env_id), "protected_keys", env_id)
+
+
+    def call(self, env_id: str, name: str, *args, **kwargs) -> Any:
+        """
+        Call a function defined in an environment
+        """
+        return self._call(self._worker(env_id), "call", env_id, name, *args, **kwargs)
+
+
+    def broadcast(self, method: str, *args, **kwargs) -> List[Any]:
+        """
+        Call a PyBox method on every worker, and on the workers started later to replace crashed ones,
+        e.g. pool.broadcast("set_watchdog", 5) or pool.broadcast("register_handler", 1, module_level_func)
+
+        Returns:
+            list: The result of every worker
+        """
+        with self._lock:
+            self._setup.append((method, args, kwargs))
+            workers = list(self._workers)
+        return [self._call(worker, method, *args, **kwargs) for worker in workers]
+
+
+    def envs(self) -> List[str]:
+        """
+        IDs of the environments in the pool
+        """
+        with self._lock:
+            return list(self._envs)
+
+
+    def pids(self) -> List[int]:
+        """
+        Process IDs of the workers
+        """
+        with self._lock:
+            return [worker.process.pid for worker in self._workers]
+
+
+    def close(self):
+        """
+        Stop the worker processes, the environments are lost
+        """
+        with self._lock:
+            if self._closed:
+                return
+            self._closed = True
+            workers, self._workers = self._workers, []
+            self._envs.clear()
+        self._executor.shutdown(wait=True)
+        for worker in workers:
+            worker.close()
+
+
+    def __enter__(self) -> "PyBoxProcessPool":
+        return self
+
+
+    def __exit__(self, *exc):
+        self.close()
+
+
+    def __len__(self) -> int:
+        return len(self._workers)
+
+
+
+class PyBoxWarmPool:
+    """
+    Pristine reactors kept warm by a background thread, so taking a box never pays the cold start
+
+    Boxes start from the golden snapshot. A released box is restored to it in the background and
+    handed out again, boxes that fail to restore, are too old or were used too often are replaced.
+
+        pool = PyBoxWarmPool(8, setup=register_tools, max_age=600)
+        with pool.lease() as box:
+            box.init_local("agent")
+            box.exec(code, "agent")
+    """
+
+    def __init__(self, size: int, snapshot: PyBoxReactorSnapshot = None, setup: Callable[[PyBox], None] = None, max_uses: int = None, max_age: float = None, interval: float = 1.0, **box_kwargs):
+        """
+        Args:
+            size: Number of boxes kept available
+            snapshot: Golden snapshot the boxes start from, taken from the first box after setup if None
+            setup: Called with every new box before it is restored, e.g. to register handlers
+            max_uses: Replace a box after this many leases instead of restoring it, None for no limit
+            max_age: Replace a box this many seconds after it was created, None for no limit
+            interval: Seconds between the maintenance rounds that replace aged idle boxes
+            box_kwargs: Arguments of PyBox
+        """
+        self.size = size
+        self.max_uses = max_uses
+        self.max_age = max_age
+        self.interval = interval
+        self._setup = setup
+        self._box_kwargs = box_kwargs
+        self._snapshot = snapshot
+        self._cond = threading.Condition()
+        self._available: Deque[PyBox] = deque()
+        self._released: Deque[Tuple[PyBox, bool]] = deque()
+        # id(box) -> [creation time, leases]
+        self._info: Dict[int, List[float]] = {}
+        self._busy = 0
+        self._created = 0
+        self._replaced = 0
+        self._closed = False
+        self._error: BaseException = None
+        self._thread = threading.Thread(target=self._maintain, name="pybox-warm-pool", daemon=True)
+        self._thread.start()
+
+
+    def _new_box(self) -> PyBox:
+        box = PyBox(**self._box_kwargs)
+        if self._setup is not None:
+            self._setup(box)
+        if self._snapshot is None:
+            self._snapshot = PyBoxReactorSnapshot(box)
+        else:
+            self._snapshot.restore(box)
+        return box
+
+
+    def _expired(self, box: PyBox) -> bool:
+        created, uses = self._info[id(box)]
+        if self.max_uses is not None and uses >= self.max_uses:
+            return True
+        return self.max_age is not None and time.monotonic() - created >= self.max_age
+
+
+    def _discard(self, box: PyBox):
+        with self._cond:
+            self._info.pop(id(box), None)
+            self._replaced += 1
+
+
+    def _maintain(self):
+        while True:
+            with self._cond:
+                self._cond.wait_for(lambda: self._closed or self._released or len(self._available) < self.size, self.interval)
+                if self._closed:
+                    return
+                released = list(self._released)
+                self._released.clear()
+                aged = [box for box in self._available if self._expired(box)]
+                for box in aged:
+                    self._available.remove(box)
+            for box in aged:
+                self._discard(box)
+
+            for box, discard in released:
+                if discard or self._expired(box):
+                    self._discard(box)
+                    continue
+                try:
+                    self._snapshot.restore(box)
+                except Exception:
+                    # a box that cannot be restored is broken
+                    self._discard(box)
+                    continue
+                with self._cond:
+                    self._available.append(box)
+                    self._cond.notify_all()
+
+            while True:
+                with self._cond:
+                    if self._closed or len(self._available) >= self.size:
+                        break
+                try:
+                    box = self._new_box()
+                except Exception as e:
+                    with self._cond:
+                        self._error = e
+                        self._cond.notify_all()
+                    time.sleep(self.interval)
+                    break
+                with self._cond:
+                    self._info[id(box)] = [time.monotonic(), 0]
+                    self._created += 1
+                    self._error = None
+                    self._available.append(box)
+                    self._cond.notify_all()
+
+
+    def acquire(self, timeout: float = None) -> PyBox:
+        """
+        Take a pristine box, waiting for the maintainer when none is available
+
+        Raises:
+            TimeoutError: No box became available within timeout seconds
+        """
+        with self._cond:
+            if not self._cond.wait_for(lambda: self._closed or self._available, timeout):
+                message = f"No warm box available within {timeout}s"
+                if self._error is not None:
+                    message += f", creating boxes fails with {type(self._error).__name__}: {self._error}"
+                raise TimeoutError(message)
+            if self._closed:
+                raise PyboxException("The pool is closed")
+            box = self._available.popleft()
+            self._info[id(box)][1] += 1
+            self._busy += 1
+            self._cond.notify_all()
+            return box
+
+
+    def release(self, box: PyBox, discard: bool = False):
+        """
+        Give a box back, it is restored to the golden snapshot in the background
+
+        Args:
+            discard: Replace the box instead of restoring it, e.g. after it misbehaved
+        """
+        with self._cond:
+            if id(box) not in self._info:
+                raise ValueError("The box does not belong to this pool")
+            self._busy -= 1
+            if self._closed:
+                self._info.pop(id(box), None)
+                return
+            self._released.append((box, discard))
+            self._cond.notify_all()
+
+
+    @contextlib.contextmanager
+    def lease(self, timeout: float = None) -> Iterator[PyBox]:
+        """
+        Acquire a box for a with block, it is discarded when the block raises
+        """
+        box = self.acquire(timeout)
+        try:
+            yield box
+        except BaseException:
+            self.release(box, discard=True)
+            raise
+        self.release(box)
+
+
+    def stats(self) -> Dict[str, int]:
+        """
+        Gauges of the pool: target size, available and busy boxes, boxes created and replaced so far
+        """
+        with self._cond:
+            return {
+                "target": self.size,
+                "available": len(self._available),
+                "busy": self._busy,
+                "created": self._created,
+                "replaced": self._replaced,
+            }
+
+
+    def close(self):
+        """
+        Stop the maintainer and drop the idle boxes, leased boxes stay usable
+        """
+        with self._cond:
+            self._closed = True
+            self._available.clear()
+            self._released.clear()
+            self._cond.notify_all()
+        self._thread.join()
+
+
+    def __enter__(self) -> "PyBoxWarmPool":
+        return self
+
+
+    def __exit__(self, *exc):
+        self.close()
+
+
+__all__ = [
+    PyBoxProcessPool.__name__,
+    PyBoxWarmPool.__name__,
+    PyBoxWorkerError.__name__
+]
@@ -1,14 +1,137 @@
 
-from .box import PyBox
-from .pyboxcore import PyBoxReactorSnapshot
+from typing import Dict, List
+
+from .box import PyBox, PyBoxQuotaError
+from .pyboxcore import PyBoxReactorSnapshot, PyBoxSnapshotGuard
 
 
 class PyBoxSnapshot(PyBoxReactorSnapshot):
-    def __init__(self,box:PyBox):
-        super().__init__(box)
+    def __init__(self,box:PyBox,base:"PyBoxSnapshot"=None):
+        super().__init__(box,base)
+
+
+class PyBoxSnapshotStore:
+    """
+    Named snapshots of one box, for checkpointing workflows
+
+        store = PyBoxSnapshotStore(box, max_bytes=512 * 1024 * 1024)
+        store.save("clean")
+        box.exec(setup_code, env_id)
+        store.save("after-setup", base="clean")
+        ...
+        store.restore("after-setup")
+    """
+
+    def __init__(self, box: PyBox, max_bytes: int = None, max_snapshots: int = None):
+        """
+        Args:
+            box: Box the snapshots are taken from and restored to
+            max_bytes: Limit of the total size of the stored snapshots, None for no limit
+            max_snapshots: Limit of the number of stored snapshots, None for no limit
+        """
+        self.box = box
+        self.max_bytes = max_bytes
+        self.max_snapshots = max_snapshots
+        self._snapshots: Dict[str, PyBoxSnapshot] = {}
+
+
+    def save(self, name: str, base: str = None) -> PyBoxSnapshot:
+        """
+        Snapshot the box under name, replacing a snapshot with the same name
+
+        Args:
+            name: Name of the snapshot
+            base: Name of a stored snapshot, only the pages that changed since it are kept
+
+        Raises:
+            PyBoxQuotaError: The snapshot does not fit in the limits, nothing is stored
+        """
+        if base == name:
+            raise ValueError(f"Snapshot '{name}' cannot be its own base")
+        base_snapshot = self[base] if base is not None else None
+        if name in self._snapshots and self._dependents(name):
+            raise ValueError(f"Snapshot '{name}' is the base of {', '.join(self._dependents(name))}")
+        snapshot = PyBoxSnapshot(self.box, base_snapshot)
+
+        replaced = self._snapshots.get(name)
+        count = len(self._snapshots) + (replaced is None)
+        if self.max_snapshots is not None and count > self.max_snapshots:
+            raise PyBoxQuotaError(f"Snapshot store is limited to {self.max_snapshots} snapshots")
+        size = self.size() - (replaced.size() if replaced is not None else 0) + snapshot.size()
+        if self.max_bytes is not None and size > self.max_bytes:
+            raise PyBoxQuotaError(f"Snapshot store is limited to {self.max_bytes} bytes, '{name}' would need {size}")
+
+        self._snapshots.pop(name, None)
+        self._snapshots[name] = snapshot
+        return snapshot
+
+
+    def restore(self, name: str):
+        """
+        Restore the box to the snapshot stored under name
+        """
+        self[name].restore(self.box)
+
+
+    def guard(self, name: str, on_error: bool = False) -> PyBoxSnapshotGuard:
+        """
+        Restore the box to the snapshot stored under name when a with block exits
+        """
+        return self[name].guard(self.box, on_error)
+
+
+    def delete(self, name: str) -> bool:
+        """
+        Delete the snapshot stored under name, returns whether it existed
+
+        Raises:
+            ValueError: Another stored snapshot was taken with it as base
+        """
+        if name not in self._snapshots:
+            return False
+        dependents = self._dependents(name)
+        if dependents:
+            raise ValueError(f"Snapshot '{name}' is the base of {', '.join(dependents)}")
+        del self._snapshots[name]
+        return True
+
+
+    def list(self) -> List[str]:
+        """
+        Names of the stored snapshots, oldest first
+        """
+        return list(self._snapshots)
+
+
+    def size(self) -> int:
+        """
+        Total size of the stored snapshots in bytes
+        """
+        return sum(snapshot.size() for snapshot in self._snapshots.values())
+
+
+    def _dependents(self, name: str) -> List[str]:
+        snapshot = self._snapshots[name]
+        return [other for other, stored in self._snapshots.items() if stored.base is snapshot]
+
+
+    def __getitem__(self, name: str) -> PyBoxSnapshot:
+        try:
+            return self._snapshots[name]
+        except KeyError:
+            raise KeyError(f"No snapshot named '{name}'") from None
+
+
+    def __contains__(self, name: str) -> bool:
+        return name in self._snapshots
+
+
+    def __len__(self) -> int:
+        return len(self._snapshots)
 
 
 
 __all__ = [
-    PyBoxSnapshot.__name__
-]
\ No newline at end of file
+    PyBoxSnapshot.__name__,
+    PyBoxSnapshotStore.__name__
+]
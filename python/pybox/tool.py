@@ -73,6 +73,29 @@ class PyboxPTCTool:
         )
     
 
+class PyboxRemoteObject:
+    def __init__(
+        self,
+        handle,
+        obj
+    ):
+        self._handle = handle
+        self._object = obj
+
+    @property
+    def handle(self):
+        return self._handle
+
+    @property
+    def object(self):
+        return self._object
+
+    def stub(self, name):
+        """生成在沙箱中绑定代理对象的代码"""
+        return f'{name} = __import__("pybox").RemoteObject({self._handle})'
+
+
 __all__ = [
-    PyboxPTCTool.__name__
+    PyboxPTCTool.__name__,
+    PyboxRemoteObject.__name__
 ]
\ No newline at end of file